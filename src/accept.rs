@@ -0,0 +1,238 @@
+use crate::logging;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpListener;
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+pub const DEFAULT_MAX_CONNECTIONS: usize = 1000;
+
+// Running out of file descriptors is usually temporary, so the server shouldn't die
+pub const ACCEPT_ERROR_SLEEP: Duration = Duration::from_millis(100);
+
+pub const RAW_TCP_FULL_MESSAGE: &[u8] = b"The server is full, try again soon.\r\n";
+pub const WEBSOCKET_FULL_MESSAGE: &[u8] =
+    b"HTTP/1.1 503 Service Unavailable\r\nConnection: close\r\n\r\nThe server is full, try again soon.\n";
+
+// Totals over both listeners, shown by the admin "stats" command
+pub static ACCEPTED_COUNT: AtomicU64 = AtomicU64::new(0);
+pub static ACTIVE_COUNT: AtomicU64 = AtomicU64::new(0);
+
+// Makes it possible to test the accept loop without real listening sockets
+pub trait Acceptor {
+    async fn accept(&mut self) -> io::Result<(TcpStream, SocketAddr)>;
+}
+
+impl Acceptor for TcpListener {
+    async fn accept(&mut self) -> io::Result<(TcpStream, SocketAddr)> {
+        TcpListener::accept(self).await
+    }
+}
+
+// Counts connections of all kinds, unlike IpTracker which only sees one IP at a time
+pub struct ConnectionLimit {
+    count: Mutex<usize>,
+    max: usize,
+}
+
+pub struct ConnectionSlot {
+    limit: Arc<ConnectionLimit>,
+}
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        *self.limit.count.lock().unwrap() -= 1;
+        ACTIVE_COUNT.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl ConnectionLimit {
+    pub fn new(max: usize) -> Self {
+        Self {
+            count: Mutex::new(0),
+            max,
+        }
+    }
+
+    pub fn try_acquire(limit: Arc<ConnectionLimit>) -> Option<ConnectionSlot> {
+        {
+            let mut count = limit.count.lock().unwrap();
+            if *count >= limit.max {
+                return None;
+            }
+            *count += 1;
+        }
+        ACCEPTED_COUNT.fetch_add(1, Ordering::SeqCst);
+        ACTIVE_COUNT.fetch_add(1, Ordering::SeqCst);
+        Some(ConnectionSlot { limit })
+    }
+}
+
+async fn reject(mut socket: TcpStream, message: &'static [u8]) {
+    // A client that doesn't read shouldn't keep the socket open for long
+    let _ = timeout(Duration::from_secs(1), socket.write_all(message)).await;
+}
+
+/*
+Calls handle_connection for every connection until the cap is reached.
+After that, connections get a short message and are closed right away,
+so that a bot or a traffic spike can't use up all file descriptors.
+*/
+pub async fn accept_forever<F>(
+    mut acceptor: impl Acceptor,
+    limit: Arc<ConnectionLimit>,
+    full_message: &'static [u8],
+    mut handle_connection: F,
+) where
+    F: FnMut(TcpStream, SocketAddr, ConnectionSlot),
+{
+    loop {
+        match acceptor.accept().await {
+            Ok((socket, addr)) => match ConnectionLimit::try_acquire(limit.clone()) {
+                Some(slot) => handle_connection(socket, addr, slot),
+                None => {
+                    logging::log(
+                        "accept",
+                        &format!("Server is full, rejecting connection from {}", addr),
+                    );
+                    tokio::spawn(reject(socket, full_message));
+                }
+            },
+            Err(e) => {
+                // e.g. EMFILE or ENFILE (too many open files)
+                logging::log_error("accept", &format!("Accepting a connection failed: {}", e));
+                tokio::time::sleep(ACCEPT_ERROR_SLEEP).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::admin::ClientHandle;
+    use crate::admin::ClientRegistry;
+    use crate::escapes::TerminalType;
+    use crate::render::SharedRenderData;
+    use std::collections::VecDeque;
+    use tokio::io::AsyncReadExt;
+
+    // Gives out errors and sockets in a predefined order, then waits forever
+    struct MockAcceptor {
+        results: VecDeque<io::Result<(TcpStream, SocketAddr)>>,
+    }
+
+    impl Acceptor for MockAcceptor {
+        async fn accept(&mut self) -> io::Result<(TcpStream, SocketAddr)> {
+            match self.results.pop_front() {
+                Some(result) => result,
+                None => std::future::pending().await,
+            }
+        }
+    }
+
+    // Returns server side and client side
+    async fn socket_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (server, client)
+    }
+
+    #[tokio::test]
+    async fn test_accept_errors_dont_stop_the_loop() {
+        let (server, _client) = socket_pair().await;
+        let addr = server.peer_addr().unwrap();
+        let acceptor = MockAcceptor {
+            results: VecDeque::from([
+                Err(io::Error::from_raw_os_error(24)), // EMFILE
+                Err(io::Error::from_raw_os_error(23)), // ENFILE
+                Err(io::Error::from(io::ErrorKind::ConnectionAborted)),
+                Ok((server, addr)),
+            ]),
+        };
+
+        let accepted = Arc::new(Mutex::new(vec![]));
+        let accepted2 = accepted.clone();
+        let limit = Arc::new(ConnectionLimit::new(10));
+        let _ = timeout(
+            Duration::from_secs(1),
+            accept_forever(acceptor, limit, RAW_TCP_FULL_MESSAGE, move |_, addr, _| {
+                accepted2.lock().unwrap().push(addr)
+            }),
+        )
+        .await;
+        assert_eq!(*accepted.lock().unwrap(), [addr]);
+    }
+
+    #[tokio::test]
+    async fn test_connections_past_the_cap() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let listen_addr = listener.local_addr().unwrap();
+
+        let registry = Arc::new(ClientRegistry::new());
+        let registry2 = registry.clone();
+        let mut next_id = 1;
+        let mut slots_and_sockets = vec![];
+        let accept_task = tokio::spawn(accept_forever(
+            listener,
+            Arc::new(ConnectionLimit::new(2)),
+            RAW_TCP_FULL_MESSAGE,
+            move |socket, _, slot| {
+                let render_data = Arc::new(SharedRenderData::new(TerminalType::Ansi));
+                let (handle, _) = ClientHandle::new(render_data);
+                let unregister = ClientRegistry::register(registry2.clone(), next_id, handle);
+                next_id += 1;
+                slots_and_sockets.push((slot, socket, unregister));
+            },
+        ));
+
+        let mut clients = vec![];
+        for _ in 0..2 {
+            clients.push(TcpStream::connect(listen_addr).await.unwrap());
+        }
+        while registry.client_ids().len() < 2 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(registry.client_ids(), [1, 2]);
+
+        // The extra client gets the message, and then the server closes the connection
+        let mut extra_client = TcpStream::connect(listen_addr).await.unwrap();
+        let mut text = String::new();
+        timeout(
+            Duration::from_secs(5),
+            extra_client.read_to_string(&mut text),
+        )
+        .await
+        .expect("connection wasn't closed")
+        .unwrap();
+        assert_eq!(text.as_bytes(), RAW_TCP_FULL_MESSAGE);
+        assert_eq!(registry.client_ids(), [1, 2]);
+
+        // Clients that got in stay connected
+        for client in &mut clients {
+            let mut buf = [0u8; 1];
+            assert!(timeout(Duration::from_millis(50), client.read(&mut buf))
+                .await
+                .is_err());
+        }
+        accept_task.abort();
+    }
+
+    #[test]
+    fn test_slots_are_given_back() {
+        let limit = Arc::new(ConnectionLimit::new(1));
+        let slot = ConnectionLimit::try_acquire(limit.clone());
+        assert!(slot.is_some());
+        assert!(ConnectionLimit::try_acquire(limit.clone()).is_none());
+        drop(slot);
+        assert!(ConnectionLimit::try_acquire(limit).is_some());
+    }
+}
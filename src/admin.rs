@@ -0,0 +1,423 @@
+// Local-only command interface for the server operator, enabled with --admin-port.
+// Connect with e.g. "nc localhost 12346" and type "list".
+
+use crate::accept;
+use crate::client::log_for_client;
+use crate::connection;
+use crate::game_logic::game::Mode;
+use crate::logging;
+use crate::render::SharedRenderData;
+use crate::views;
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::io::AsyncBufReadExt;
+use tokio::io::AsyncWriteExt;
+use tokio::io::BufReader;
+use tokio::net::TcpListener;
+use tokio::net::TcpStream;
+use tokio::sync::watch;
+
+const BANNER_DURATION: Duration = Duration::from_secs(10);
+
+// Players already in lobbies and games see the first line of a changed motd
+const MOTD_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+const MOTD_BANNER_DURATION: Duration = Duration::from_secs(60);
+
+#[derive(Debug, PartialEq)]
+pub enum AdminCommand {
+    List,
+    Kick { client_id: u64, reason: String },
+    Broadcast(String),
+    ReloadMotd,
+    Stats,
+}
+
+pub fn parse_command(line: &str) -> Result<AdminCommand, String> {
+    let line = line.trim();
+    let (command, rest) = line.split_once(' ').unwrap_or((line, ""));
+    let rest = rest.trim();
+
+    match command {
+        "list" if rest.is_empty() => Ok(AdminCommand::List),
+        "kick" => {
+            let (id, reason) = rest.split_once(' ').unwrap_or((rest, ""));
+            match id.parse() {
+                Ok(client_id) => Ok(AdminCommand::Kick {
+                    client_id,
+                    reason: reason.trim().to_string(),
+                }),
+                Err(_) => Err("usage: kick <client_id> [reason]".to_string()),
+            }
+        }
+        "broadcast" if !rest.is_empty() => Ok(AdminCommand::Broadcast(rest.to_string())),
+        "broadcast" => Err("usage: broadcast <text>".to_string()),
+        "motd" if rest == "reload" => Ok(AdminCommand::ReloadMotd),
+        "stats" if rest.is_empty() => Ok(AdminCommand::Stats),
+        _ => Err(format!(
+            "unknown command: {:?} (try list, kick, broadcast, motd reload or stats)",
+            line
+        )),
+    }
+}
+
+// What "list" shows about a client
+#[derive(Default)]
+pub struct ClientStatus {
+    pub name: Option<String>,
+    pub lobby_id: Option<String>,
+    pub mode: Option<Mode>,
+}
+
+// Each client has one of these, and the registry has them for connected clients
+pub struct ClientHandle {
+    pub status: Mutex<ClientStatus>,
+    render_data: Arc<SharedRenderData>,
+    kick_sender: watch::Sender<Option<String>>,
+}
+
+impl ClientHandle {
+    pub fn new(
+        render_data: Arc<SharedRenderData>,
+    ) -> (Arc<ClientHandle>, watch::Receiver<Option<String>>) {
+        let (kick_sender, kick_receiver) = watch::channel(None);
+        let handle = ClientHandle {
+            status: Mutex::new(ClientStatus::default()),
+            render_data,
+            kick_sender,
+        };
+        (Arc::new(handle), kick_receiver)
+    }
+
+    pub fn get_kick_reason(&self) -> Option<String> {
+        self.kick_sender.borrow().clone()
+    }
+}
+
+pub struct ClientRegistry {
+    clients: Mutex<HashMap<u64, Arc<ClientHandle>>>,
+    last_motd: Mutex<Option<Vec<String>>>, // None until the motd file has been checked
+}
+
+pub struct UnregisterOnDrop {
+    client_id: u64,
+    registry: Arc<ClientRegistry>,
+}
+impl Drop for UnregisterOnDrop {
+    fn drop(&mut self) {
+        self.registry
+            .clients
+            .lock()
+            .unwrap()
+            .remove(&self.client_id);
+    }
+}
+
+impl ClientRegistry {
+    pub fn new() -> Self {
+        Self {
+            clients: Mutex::new(HashMap::new()),
+            last_motd: Mutex::new(None),
+        }
+    }
+
+    pub fn register(
+        registry: Arc<ClientRegistry>,
+        client_id: u64,
+        handle: Arc<ClientHandle>,
+    ) -> UnregisterOnDrop {
+        registry.clients.lock().unwrap().insert(client_id, handle);
+        UnregisterOnDrop {
+            client_id,
+            registry,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn client_ids(&self) -> Vec<u64> {
+        let mut ids: Vec<u64> = self.clients.lock().unwrap().keys().copied().collect();
+        ids.sort();
+        ids
+    }
+
+    fn list(&self) -> String {
+        let clients = self.clients.lock().unwrap();
+        let mut ids: Vec<&u64> = clients.keys().collect();
+        ids.sort();
+
+        let mut result = format!("{} clients\n", ids.len());
+        for id in ids {
+            let status = clients[id].status.lock().unwrap();
+            result.push_str(&format!(
+                "{} name={} lobby={} mode={}\n",
+                id,
+                status.name.as_deref().unwrap_or("-"),
+                status.lobby_id.as_deref().unwrap_or("-"),
+                status.mode.map(|m| m.name()).unwrap_or("-"),
+            ));
+        }
+        result
+    }
+
+    // The kicked client notices this when it waits for the next key press
+    pub fn kick(&self, client_id: u64, reason: &str) -> bool {
+        match self.clients.lock().unwrap().get(&client_id) {
+            Some(handle) => {
+                log_for_client(client_id, &format!("Kicked by admin: {}", reason));
+                handle.kick_sender.send_replace(Some(reason.to_string()));
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn broadcast(registry: Arc<ClientRegistry>, text: &str) {
+        logging::log("admin", &format!("Broadcasting: {}", text));
+        Self::show_temporary_banner(registry, "admin", text, BANNER_DURATION);
+    }
+
+    // Banner goes away after a while, unless there's a newer one from the same source
+    fn show_temporary_banner(
+        registry: Arc<ClientRegistry>,
+        source: &'static str,
+        text: &str,
+        duration: Duration,
+    ) {
+        let handles: Vec<Arc<ClientHandle>> =
+            registry.clients.lock().unwrap().values().cloned().collect();
+        for handle in &handles {
+            let mut render_data = handle.render_data.lock().unwrap();
+            render_data.set_banner(source, Some(text.to_string()));
+        }
+
+        let text = text.to_string();
+        tokio::spawn(async move {
+            tokio::time::sleep(duration).await;
+            for handle in handles {
+                let mut render_data = handle.render_data.lock().unwrap();
+                if render_data
+                    .banners
+                    .iter()
+                    .any(|(s, t)| *s == source && *t == text)
+                {
+                    render_data.set_banner(source, None);
+                }
+            }
+        });
+    }
+
+    /*
+    Shows the first line of the motd as a banner, if the motd changed since
+    the previous check. The first check only remembers the motd, because
+    clients see it in the menu anyway. Returns the current motd lines.
+    */
+    pub async fn check_motd(
+        registry: Arc<ClientRegistry>,
+        filename: &str,
+    ) -> Result<Vec<String>, io::Error> {
+        let lines = match views::read_motd_file(filename).await {
+            Ok(lines) => lines,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => vec![],
+            Err(e) => return Err(e),
+        };
+        let old_lines = registry.last_motd.lock().unwrap().replace(lines.clone());
+        if matches!(&old_lines, Some(old) if *old != lines) {
+            if let Some(first_line) = lines.first().filter(|line| !line.trim().is_empty()) {
+                logging::log("admin", &format!("Showing changed motd: {}", first_line));
+                Self::show_temporary_banner(registry, "motd", first_line, MOTD_BANNER_DURATION);
+            }
+        }
+        Ok(lines)
+    }
+}
+
+pub async fn check_motd_periodically(registry: Arc<ClientRegistry>) {
+    loop {
+        if let Err(e) = ClientRegistry::check_motd(registry.clone(), views::MOTD_FILENAME).await {
+            logging::log_error("admin", &format!("Reading motd file failed: {}", e));
+        }
+        tokio::time::sleep(MOTD_CHECK_INTERVAL).await;
+    }
+}
+
+// One line, so that load tests can poll it and compare with what their clients saw
+fn format_stats() -> String {
+    format!(
+        "accepted={} active={} frames_sent={}\n",
+        accept::ACCEPTED_COUNT.load(Ordering::SeqCst),
+        accept::ACTIVE_COUNT.load(Ordering::SeqCst),
+        connection::FRAMES_SENT.load(Ordering::SeqCst),
+    )
+}
+
+async fn run_command(registry: Arc<ClientRegistry>, line: &str) -> String {
+    match parse_command(line) {
+        Ok(AdminCommand::List) => registry.list(),
+        Ok(AdminCommand::Kick { client_id, reason }) => {
+            if registry.kick(client_id, &reason) {
+                "ok\n".to_string()
+            } else {
+                format!("no client with id {}\n", client_id)
+            }
+        }
+        Ok(AdminCommand::Broadcast(text)) => {
+            ClientRegistry::broadcast(registry, &text);
+            "ok\n".to_string()
+        }
+        // The menu reads the motd file every time, but others only see it in a banner
+        Ok(AdminCommand::ReloadMotd) => {
+            match ClientRegistry::check_motd(registry, views::MOTD_FILENAME).await {
+                Ok(lines) => format!("ok, motd has {} lines\n", lines.len()),
+                Err(e) => format!("reading motd failed: {}\n", e),
+            }
+        }
+        Ok(AdminCommand::Stats) => format_stats(),
+        Err(message) => message + "\n",
+    }
+}
+
+async fn handle_admin_connection(
+    registry: Arc<ClientRegistry>,
+    socket: TcpStream,
+) -> Result<(), io::Error> {
+    let (read_half, mut write_half) = socket.into_split();
+    let mut lines = BufReader::new(read_half).lines();
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        logging::log("admin", &format!("Command: {}", line.trim()));
+        let response = run_command(registry.clone(), &line).await;
+        write_half.write_all(response.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+pub async fn listen_for_admin_connections(registry: Arc<ClientRegistry>, port: u16) {
+    // Only from localhost, there's no authentication
+    let listener = TcpListener::bind(("127.0.0.1", port)).await.unwrap();
+    // Port 0 picks a free port, so print the one we actually got
+    println!(
+        "Listening for admin connections on 127.0.0.1 port {}...",
+        listener.local_addr().unwrap().port()
+    );
+    loop {
+        let socket = match listener.accept().await {
+            Ok((socket, _)) => socket,
+            Err(e) => {
+                // Same as in accept_forever(), e.g. too many open files
+                logging::log_error("admin", &format!("Accepting a connection failed: {}", e));
+                tokio::time::sleep(accept::ACCEPT_ERROR_SLEEP).await;
+                continue;
+            }
+        };
+        let registry = registry.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_admin_connection(registry, socket).await {
+                logging::log_error("admin", &format!("Connection failed: {}", e));
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::client::Client;
+    use crate::connection::Receiver;
+    use crate::escapes::TerminalType;
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(parse_command("list"), Ok(AdminCommand::List));
+        assert_eq!(parse_command("  list \n"), Ok(AdminCommand::List));
+        assert_eq!(
+            parse_command("kick 12 stop spamming"),
+            Ok(AdminCommand::Kick {
+                client_id: 12,
+                reason: "stop spamming".to_string()
+            })
+        );
+        assert_eq!(
+            parse_command("kick 12"),
+            Ok(AdminCommand::Kick {
+                client_id: 12,
+                reason: "".to_string()
+            })
+        );
+        assert!(parse_command("kick").is_err());
+        assert!(parse_command("kick abc").is_err());
+        assert_eq!(
+            parse_command("broadcast restarting in 5 minutes"),
+            Ok(AdminCommand::Broadcast(
+                "restarting in 5 minutes".to_string()
+            ))
+        );
+        assert!(parse_command("broadcast").is_err());
+        assert_eq!(parse_command("motd reload"), Ok(AdminCommand::ReloadMotd));
+        assert!(parse_command("motd").is_err());
+        assert_eq!(parse_command("stats"), Ok(AdminCommand::Stats));
+        assert!(parse_command("stats please").is_err());
+        assert!(parse_command("list all").is_err());
+        assert!(parse_command("hello").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_kick() {
+        let registry = Arc::new(ClientRegistry::new());
+        // "BLOCK" means that the client never presses a key
+        let mut client = Client::new(123, Receiver::Test("BLOCK".to_string()), TerminalType::Ansi);
+        let _unregister = ClientRegistry::register(registry.clone(), 123, client.handle.clone());
+        assert!(registry.list().contains("123 name=- lobby=- mode=-"));
+
+        assert!(!registry.kick(456, "wrong client"));
+        assert!(registry.kick(123, "spamming"));
+        let error = client.receive_key_press().await.unwrap_err();
+        assert!(error.to_string().contains("spamming"));
+        assert_eq!(
+            client.handle.get_kick_reason(),
+            Some("spamming".to_string())
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_changed_motd_is_shown_as_banner() {
+        let dir = tempfile::tempdir().unwrap();
+        let filename = dir.path().join("catris_motd.txt");
+        let filename = filename.to_str().unwrap();
+        std::fs::write(filename, "Welcome\n").unwrap();
+
+        let registry = Arc::new(ClientRegistry::new());
+        let client = Client::new(123, Receiver::Test("BLOCK".to_string()), TerminalType::Ansi);
+        let _unregister = ClientRegistry::register(registry.clone(), 123, client.handle.clone());
+        let banners = || client.render_data.lock().unwrap().banners.clone();
+
+        // Clients see the initial motd in the menu
+        ClientRegistry::check_motd(registry.clone(), filename)
+            .await
+            .unwrap();
+        assert!(banners().is_empty());
+
+        std::fs::write(filename, "Restarting at 20:00\nSorry\n").unwrap();
+        let lines = ClientRegistry::check_motd(registry.clone(), filename)
+            .await
+            .unwrap();
+        assert_eq!(lines, ["Restarting at 20:00", "Sorry"]);
+        assert_eq!(banners(), [("motd", "Restarting at 20:00".to_string())]);
+
+        tokio::time::sleep(MOTD_BANNER_DURATION - Duration::from_secs(1)).await;
+        assert!(!banners().is_empty());
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert!(banners().is_empty());
+
+        // Emptying the motd doesn't show an empty banner
+        std::fs::write(filename, "").unwrap();
+        ClientRegistry::check_motd(registry.clone(), filename)
+            .await
+            .unwrap();
+        assert!(banners().is_empty());
+    }
+}
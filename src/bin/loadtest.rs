@@ -0,0 +1,365 @@
+/*
+Load tester: connects many fake players to a catris server over raw TCP.
+Each fake player answers the terminal detection, enters a generated name,
+makes or joins a lobby, starts a traditional game and presses random keys.
+
+    cargo run --release --bin loadtest -- --host example.com:12345 --clients 100
+
+The server lets only 5 connections come from the same IP by default, so
+start it with e.g. --max-connections-per-ip 1000 if you load test it from
+one machine. The server's admin "stats" command shows the same things from
+the server's point of view.
+*/
+use catris::escapes;
+use rand::rngs::StdRng;
+use rand::Rng;
+use rand::SeedableRng;
+use std::io;
+use std::io::ErrorKind;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWriteExt;
+use tokio::net::tcp::OwnedReadHalf;
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::TcpStream;
+use tokio::sync::watch;
+use tokio::time::timeout;
+
+// Getting through one step of connecting shouldn't take longer than this
+const STEP_TIME_LIMIT: Duration = Duration::from_secs(10);
+
+// The server disconnects clients that send more than 100 keys per second
+const MAX_KEYS_PER_SECOND: f32 = 50.0;
+
+const RANDOM_KEYS: &[&[u8]] = &[
+    escapes::ANSI_UP,
+    escapes::ANSI_DOWN,
+    escapes::ANSI_LEFT,
+    escapes::ANSI_RIGHT,
+];
+
+struct Args {
+    host: String,
+    clients: usize,
+    group_size: usize,
+    keys_per_second: f32,
+    seconds: f32,
+}
+
+fn parse_args() -> Args {
+    let mut result = Args {
+        host: "localhost:12345".to_string(),
+        clients: 10,
+        group_size: 2,
+        keys_per_second: 5.0,
+        seconds: 30.0,
+    };
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let value = args.next().unwrap_or_default();
+        let ok = match arg.as_str() {
+            "--host" => {
+                result.host = value.clone();
+                true
+            }
+            "--clients" => value.parse().map(|n| result.clients = n).is_ok(),
+            // Traditional games have room for 6 players
+            "--group-size" => match value.parse() {
+                Ok(n @ 1..=6) => {
+                    result.group_size = n;
+                    true
+                }
+                _ => false,
+            },
+            "--keys-per-second" => match value.parse() {
+                Ok(n) if n > 0.0 && n <= MAX_KEYS_PER_SECOND => {
+                    result.keys_per_second = n;
+                    true
+                }
+                _ => false,
+            },
+            "--seconds" => match value.parse() {
+                Ok(n) if n >= 0.0 => {
+                    result.seconds = n;
+                    true
+                }
+                _ => false,
+            },
+            _ => {
+                eprintln!("unknown argument: {}", arg);
+                std::process::exit(2);
+            }
+        };
+        if !ok {
+            eprintln!("bad value for {}: {:?}", arg, value);
+            std::process::exit(2);
+        }
+    }
+    result
+}
+
+#[derive(Default)]
+struct Stats {
+    connect_failures: usize,
+    setup_failures: usize, // connected, but didn't get to play
+    disconnects: usize,
+    bytes_received: u64,
+    times_to_first_frame: Vec<Duration>,
+}
+
+impl Stats {
+    fn p95_time_to_first_frame(&self) -> Option<Duration> {
+        let mut times = self.times_to_first_frame.clone();
+        times.sort();
+        let index = ((times.len() as f32) * 0.95).ceil() as usize;
+        times.get(index.checked_sub(1)?).copied()
+    }
+
+    fn summary_lines(&self, client_count: usize) -> Vec<String> {
+        let p95 = match self.p95_time_to_first_frame() {
+            Some(time) => format!("{}ms", time.as_millis()),
+            None => "-".to_string(),
+        };
+        vec![
+            format!("clients: {}", client_count),
+            format!("connect failures: {}", self.connect_failures),
+            format!("setup failures: {}", self.setup_failures),
+            format!("disconnects: {}", self.disconnects),
+            format!("bytes received: {}", self.bytes_received),
+            format!("time to first frame (p95): {}", p95),
+        ]
+    }
+
+    fn all_ok(&self) -> bool {
+        self.connect_failures == 0 && self.setup_failures == 0 && self.disconnects == 0
+    }
+}
+
+struct Connection {
+    read_half: OwnedReadHalf,
+    write_half: OwnedWriteHalf,
+    stats: Arc<Mutex<Stats>>,
+}
+
+impl Connection {
+    async fn read_some(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        let n = self.read_half.read(buf).await?;
+        if n == 0 {
+            return Err(io::Error::new(
+                ErrorKind::ConnectionAborted,
+                "server closed the connection",
+            ));
+        }
+        self.stats.lock().unwrap().bytes_received += n as u64;
+        Ok(n)
+    }
+
+    // Discards everything until the pattern has been received
+    async fn read_until(&mut self, pattern: &[u8]) -> Result<(), io::Error> {
+        let mut received: Vec<u8> = vec![];
+        let mut buf = [0u8; 4096];
+        let deadline = Instant::now() + STEP_TIME_LIMIT;
+        loop {
+            if received.windows(pattern.len()).any(|w| w == pattern) {
+                return Ok(());
+            }
+            // The pattern may be split between two reads
+            let keep = received.len().min(pattern.len() - 1);
+            received.drain(..received.len() - keep);
+
+            let time_left = deadline.saturating_duration_since(Instant::now());
+            let n = timeout(time_left, self.read_some(&mut buf)).await??;
+            received.extend(&buf[..n]);
+        }
+    }
+
+    async fn send(&mut self, data: &[u8]) -> Result<(), io::Error> {
+        self.write_half.write_all(data).await
+    }
+
+    /*
+    Frames only contain what changed, so text we wait for can be split by
+    escape sequences. Ctrl+R makes the server send the whole screen, and
+    because keys are handled in order, it shows what the earlier keys did.
+    */
+    async fn wait_for_screen_text(&mut self, text: &str) -> Result<(), io::Error> {
+        self.send(&[escapes::CTRL_R]).await?;
+        self.read_until(text.as_bytes()).await
+    }
+}
+
+enum Role {
+    Leader(watch::Sender<bool>),
+    Follower {
+        leader_name: String,
+        lobby_ready: watch::Receiver<bool>,
+    },
+}
+
+// Returns false if the leader of the group failed before making the lobby
+async fn wait_until_ready(lobby_ready: &mut watch::Receiver<bool>) -> bool {
+    while !*lobby_ready.borrow() {
+        if lobby_ready.changed().await.is_err() {
+            return false;
+        }
+    }
+    true
+}
+
+// Returns when the client is in the mode menu of its lobby
+async fn set_up(conn: &mut Connection, name: &str, role: &mut Role) -> Result<(), io::Error> {
+    conn.read_until(escapes::DEVICE_STATUS_REPORT).await?;
+    conn.send(b"\x1b[24;80R").await?;
+
+    let mut keys = name.as_bytes().to_vec();
+    keys.push(escapes::ENTER);
+    match role {
+        Role::Leader(_) => keys.push(escapes::ENTER), // "New lobby" is selected by default
+        Role::Follower {
+            leader_name,
+            lobby_ready,
+        } => {
+            if !timeout(STEP_TIME_LIMIT, wait_until_ready(lobby_ready)).await? {
+                return Err(io::Error::other(
+                    "the leader of the group didn't get to make a lobby",
+                ));
+            }
+            // "Join by player name" is the third item in the menu
+            keys.extend(escapes::ANSI_DOWN);
+            keys.extend(escapes::ANSI_DOWN);
+            keys.push(escapes::ENTER);
+            keys.extend(leader_name.as_bytes());
+            keys.push(escapes::ENTER);
+        }
+    }
+    conn.send(&keys).await?;
+    conn.wait_for_screen_text("Lobby ID: ").await?;
+
+    if let Role::Leader(sender) = role {
+        sender.send_replace(true);
+    }
+    Ok(())
+}
+
+/*
+Starts the first game in the mode menu and presses random keys. The time
+to first frame is from pressing Enter in the menu to receiving something
+back, so it's a bit off if the menu happens to change at the same time.
+*/
+async fn play(conn: &mut Connection, keys_per_second: f32, seconds: f32) -> Result<(), io::Error> {
+    let mut rng = StdRng::from_entropy();
+    let mut buf = [0u8; 4096];
+
+    let start = Instant::now();
+    conn.send(&[escapes::ENTER]).await?;
+    timeout(STEP_TIME_LIMIT, conn.read_some(&mut buf)).await??;
+    conn.stats
+        .lock()
+        .unwrap()
+        .times_to_first_frame
+        .push(start.elapsed());
+
+    let end = tokio::time::Instant::now() + Duration::from_secs_f32(seconds);
+    let mut next_key = tokio::time::Instant::now();
+    loop {
+        tokio::select! {
+            result = conn.read_some(&mut buf) => { result?; }
+            _ = tokio::time::sleep_until(next_key) => {
+                let key = RANDOM_KEYS[rng.gen_range(0..RANDOM_KEYS.len())];
+                conn.send(key).await?;
+                // Random spacing, so that clients don't all press keys at once
+                next_key += Duration::from_secs_f32(rng.gen_range(0.0..2.0) / keys_per_second);
+            }
+            _ = tokio::time::sleep_until(end) => return Ok(()),
+        }
+    }
+}
+
+async fn run_client(
+    index: usize,
+    name: String,
+    mut role: Role,
+    args: Arc<Args>,
+    stats: Arc<Mutex<Stats>>,
+) {
+    let socket = match timeout(STEP_TIME_LIMIT, TcpStream::connect(&args.host)).await {
+        Ok(Ok(socket)) => socket,
+        Ok(Err(e)) => {
+            println!("client {}: connecting failed: {}", index, e);
+            stats.lock().unwrap().connect_failures += 1;
+            return;
+        }
+        Err(_) => {
+            println!("client {}: connecting timed out", index);
+            stats.lock().unwrap().connect_failures += 1;
+            return;
+        }
+    };
+    let (read_half, write_half) = socket.into_split();
+    let mut conn = Connection {
+        read_half,
+        write_half,
+        stats: stats.clone(),
+    };
+
+    if let Err(e) = set_up(&mut conn, &name, &mut role).await {
+        println!("client {}: setting up failed: {}", index, e);
+        let mut stats = stats.lock().unwrap();
+        stats.setup_failures += 1;
+        if e.kind() == ErrorKind::ConnectionAborted {
+            stats.disconnects += 1;
+        }
+        return;
+    }
+    if let Err(e) = play(&mut conn, args.keys_per_second, args.seconds).await {
+        println!("client {}: playing failed: {}", index, e);
+        stats.lock().unwrap().disconnects += 1;
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let args = Arc::new(parse_args());
+    let stats = Arc::new(Mutex::new(Stats::default()));
+
+    // Names must be unique on the server, even if another load test is running
+    let run_id: u16 = rand::thread_rng().gen_range(1000..10000);
+
+    let mut tasks = vec![];
+    let mut lobby_ready = None;
+    for index in 0..args.clients {
+        let name = format!("load{}_{}", run_id, index);
+        let leader_index = index - (index % args.group_size);
+        let role = if index == leader_index {
+            let (sender, receiver) = watch::channel(false);
+            lobby_ready = Some(receiver);
+            Role::Leader(sender)
+        } else {
+            Role::Follower {
+                leader_name: format!("load{}_{}", run_id, leader_index),
+                lobby_ready: lobby_ready.clone().unwrap(),
+            }
+        };
+        tasks.push(tokio::spawn(run_client(
+            index,
+            name,
+            role,
+            args.clone(),
+            stats.clone(),
+        )));
+    }
+    for task in tasks {
+        task.await.unwrap();
+    }
+
+    let stats = stats.lock().unwrap();
+    for line in stats.summary_lines(args.clients) {
+        println!("{}", line);
+    }
+    if !stats.all_ok() {
+        std::process::exit(1);
+    }
+}
@@ -1,49 +1,78 @@
+use crate::admin::ClientHandle;
 use crate::connection::Receiver;
 use crate::escapes::KeyPress;
 use crate::escapes::TerminalType;
+use crate::idle::IdleTracker;
 use crate::lobby;
 use crate::lobby::Lobbies;
 use crate::lobby::Lobby;
-use crate::render::RenderBuffer;
-use crate::render::RenderData;
+use crate::logging;
+use crate::logging::ClientLogger;
+use crate::logging::EventKind;
+use crate::prefs::Prefs;
+use crate::render::SharedRenderData;
 use std::collections::HashSet;
 use std::io;
 use std::io::ErrorKind;
+use std::net::IpAddr;
 use std::sync::Arc;
 use std::sync::Mutex;
-use tokio::sync::Notify;
+use std::time::Instant;
+use tokio::sync::watch;
 
 #[cfg(test)]
 use crate::escapes::Color;
 
 pub fn log_for_client(client_id: u64, message: &str) {
-    println!("[client {}] {}", client_id, message);
+    logging::log_without_context(client_id, message);
 }
 
 pub struct Client {
     pub id: u64,
-    pub render_data: Arc<Mutex<RenderData>>,
+    pub render_data: Arc<SharedRenderData>,
     receiver: Receiver,
     pub lobby: Option<Arc<Mutex<Lobby>>>,
     pub lobby_id_hidden: bool,
     pub prefer_rotating_counter_clockwise: bool,
+    pub other_keys_cancel_soft_drop: bool,
+    pub bell_enabled: bool,         // rings when the client's block lands
+    pub auto_shift_enabled: bool,   // server repeats sideways moves, see AutoShift
+    pub reduced_flashing: bool,     // for photosensitive players, see render_blocks()
+    pub sound_events_enabled: bool, // websocket client asked for them when connecting
+    pub settings_passphrase: Option<String>, // settings get saved with it, see prefs.rs
     remove_name_on_disconnect_data: Option<(String, Arc<Mutex<HashSet<String>>>)>,
+    local_player_name: Option<String>, // reserved in the same set of used names
+    pub handle: Arc<ClientHandle>,     // for the admin interface
+    kick_receiver: watch::Receiver<Option<String>>,
+    idle_tracker: IdleTracker,
+    pub logger: ClientLogger, // knows the name and lobby, unlike log_for_client()
+    pub ip: Option<IpAddr>,   // None in tests
 }
 impl Client {
     pub fn new(id: u64, receiver: Receiver, terminal_type: TerminalType) -> Client {
+        let render_data = Arc::new(SharedRenderData::new(terminal_type));
+        let (handle, kick_receiver) = ClientHandle::new(render_data.clone());
+        let prefs = Prefs::default();
         Client {
             id,
-            render_data: Arc::new(Mutex::new(RenderData {
-                buffer: RenderBuffer::new(terminal_type),
-                cursor_pos: None,
-                changed: Arc::new(Notify::new()),
-                force_redraw: false,
-            })),
+            render_data,
             receiver,
             lobby: None,
             lobby_id_hidden: false,
-            prefer_rotating_counter_clockwise: false,
+            prefer_rotating_counter_clockwise: prefs.prefer_rotating_counter_clockwise,
+            other_keys_cancel_soft_drop: prefs.other_keys_cancel_soft_drop,
+            bell_enabled: prefs.bell_enabled,
+            auto_shift_enabled: prefs.auto_shift_enabled,
+            reduced_flashing: prefs.reduced_flashing,
+            sound_events_enabled: false,
+            settings_passphrase: None,
             remove_name_on_disconnect_data: None,
+            local_player_name: None,
+            handle,
+            kick_receiver,
+            idle_tracker: IdleTracker::new(Instant::now()),
+            logger: ClientLogger::new(id),
+            ip: None,
         }
     }
 
@@ -85,7 +114,7 @@ impl Client {
     pub fn get_name(&self) -> Option<&str> {
         self.remove_name_on_disconnect_data
             .as_ref()
-            .map(|(name, _)| -> &str { &*name })
+            .map(|(name, _)| -> &str { name })
     }
 
     // returns false if name is in use already
@@ -101,23 +130,94 @@ impl Client {
 
         assert!(self.remove_name_on_disconnect_data.is_none());
         self.remove_name_on_disconnect_data = Some((name.to_string(), used_names));
+        self.handle.status.lock().unwrap().name = Some(name.to_string());
+        self.logger.set_name(name);
+        self.logger
+            .log_event(EventKind::NameSet, &format!("Name set: {}", name));
         true
     }
 
+    pub fn get_local_player_name(&self) -> Option<&str> {
+        self.local_player_name.as_deref()
+    }
+
+    // returns false if name is in use already
+    pub fn add_local_player(&mut self, name: &str) -> bool {
+        assert!(self.local_player_name.is_none());
+        {
+            let (_, used_names) = self.remove_name_on_disconnect_data.as_ref().unwrap();
+            let lowercase_name = name.to_lowercase();
+            let mut used_names = used_names.lock().unwrap();
+            if used_names.contains(&lowercase_name) {
+                return false;
+            }
+            used_names.insert(lowercase_name);
+        }
+
+        self.lobby
+            .as_ref()
+            .unwrap()
+            .lock()
+            .unwrap()
+            .add_local_player(self.id, name);
+        self.local_player_name = Some(name.to_string());
+        true
+    }
+
+    pub fn remove_local_player(&mut self) {
+        if let Some(name) = self.local_player_name.take() {
+            self.lobby
+                .as_ref()
+                .unwrap()
+                .lock()
+                .unwrap()
+                .remove_local_player(self.id);
+            let (_, used_names) = self.remove_name_on_disconnect_data.as_ref().unwrap();
+            used_names.lock().unwrap().remove(&name.to_lowercase());
+        }
+    }
+
     pub async fn receive_key_press(&mut self) -> Result<KeyPress, io::Error> {
         loop {
-            match self.receiver.receive_key_press().await? {
+            let now = Instant::now();
+            if self.idle_tracker.should_disconnect(now) {
+                return Err(io::Error::new(
+                    ErrorKind::TimedOut,
+                    "disconnected due to inactivity",
+                ));
+            }
+            let warning = self.idle_tracker.get_warning(now);
+            self.render_data.lock().unwrap().set_banner("idle", warning);
+
+            // This function is often cancelled and called again, so use a deadline and not a duration
+            let next_check = tokio::time::Instant::from_std(self.idle_tracker.next_check_time(now));
+            let key = tokio::select! {
+                key = self.receiver.receive_key_press() => key?,
+                _ = tokio::time::sleep_until(next_check) => continue,
+                _ = self.kick_receiver.changed() => {
+                    let reason = self.kick_receiver.borrow().clone().unwrap_or_default();
+                    return Err(io::Error::new(
+                        ErrorKind::ConnectionAborted,
+                        format!("kicked by admin: {}", reason),
+                    ));
+                }
+            };
+            self.idle_tracker.key_pressed(Instant::now());
+            self.render_data.lock().unwrap().set_banner("idle", None);
+
+            match key {
                 KeyPress::Quit => {
                     return Err(io::Error::new(
                         ErrorKind::ConnectionAborted,
                         "received quit key press",
                     ));
                 }
-                KeyPress::RefreshRequest => {
-                    let mut render_data = self.render_data.lock().unwrap();
-                    render_data.force_redraw = true;
-                    render_data.changed.notify_one();
+                // After resizing, the screen may be garbage until everything is redrawn
+                KeyPress::RefreshRequest | KeyPress::CursorPositionReport => {
+                    self.render_data.lock().unwrap().request_redraw();
                 }
+                // Ignored, so that they don't do something unexpected
+                KeyPress::FunctionKey(_) => {}
                 key => {
                     return Ok(key);
                 }
@@ -127,16 +227,19 @@ impl Client {
 
     pub fn make_lobby(&mut self, lobbies: Lobbies) {
         let mut lobbies = lobbies.lock().unwrap();
-        let id = lobby::generate_unused_id(&*lobbies);
+        let id = lobby::generate_unused_id(&lobbies);
         let mut lobby = Lobby::new(&id);
-        log_for_client(self.id, &format!("Created lobby: {}", id));
-        lobby.add_client(self.id, self.get_name().unwrap());
+        self.logger.set_lobby_id(Some(&id));
+        self.logger
+            .log_event(EventKind::LobbyJoin, &format!("Created lobby: {}", id));
+        lobby.add_client(self.id, self.get_name().unwrap(), self.ip);
 
         let lobby = Arc::new(Mutex::new(lobby));
-        lobbies.insert(id, lobby.clone());
+        lobbies.insert(id.clone(), lobby.clone());
 
         assert!(self.lobby.is_none());
         self.lobby = Some(lobby);
+        self.handle.status.lock().unwrap().lobby_id = Some(id);
     }
 
     pub fn join_lobby(&mut self, lobby: Arc<Mutex<Lobby>>) -> bool {
@@ -145,7 +248,11 @@ impl Client {
             if lobby.lobby_is_full() {
                 return false;
             }
-            lobby.add_client(self.id, self.get_name().unwrap());
+            lobby.add_client(self.id, self.get_name().unwrap(), self.ip);
+            self.handle.status.lock().unwrap().lobby_id = Some(lobby.id.clone());
+            self.logger.set_lobby_id(Some(&lobby.id));
+            self.logger
+                .log_event(EventKind::LobbyJoin, &format!("Joined lobby: {}", lobby.id));
         }
         assert!(self.lobby.is_none());
         self.lobby = Some(lobby);
@@ -156,10 +263,14 @@ impl Client {
 impl Drop for Client {
     fn drop(&mut self) {
         if let Some(lobby) = &self.lobby {
-            lobby.lock().unwrap().remove_client(self.id);
+            lobby::leave_lobby(lobby, self.id);
         }
         if let Some((name, name_set)) = &self.remove_name_on_disconnect_data {
-            name_set.lock().unwrap().remove(&name.to_lowercase());
+            let mut name_set = name_set.lock().unwrap();
+            name_set.remove(&name.to_lowercase());
+            if let Some(local_name) = &self.local_player_name {
+                name_set.remove(&local_name.to_lowercase());
+            }
         }
     }
 }
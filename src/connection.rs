@@ -1,3 +1,5 @@
+use crate::deflate;
+use crate::deflate::DeflateStream;
 use crate::escapes::parse_key_press;
 use crate::escapes::KeyPress;
 use crate::ip_tracker::ForgetClientOnDrop;
@@ -11,8 +13,11 @@ use std::env;
 use std::io;
 use std::io::ErrorKind;
 use std::net::IpAddr;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::sync::OnceLock;
 use std::time::Duration;
 use std::time::Instant;
 use tokio::io::AsyncReadExt;
@@ -20,6 +25,7 @@ use tokio::io::AsyncWriteExt;
 use tokio::net::tcp::OwnedReadHalf;
 use tokio::net::tcp::OwnedWriteHalf;
 use tokio::net::TcpStream;
+use tokio::sync::Notify;
 use tokio::time::timeout;
 use tokio_tungstenite::tungstenite;
 use tokio_tungstenite::tungstenite::handshake::server::Callback;
@@ -28,6 +34,8 @@ use tokio_tungstenite::tungstenite::handshake::server::Request;
 use tokio_tungstenite::tungstenite::handshake::server::Response;
 use tokio_tungstenite::tungstenite::http;
 use tokio_tungstenite::tungstenite::http::StatusCode;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
 use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
 use tokio_tungstenite::tungstenite::Message;
 use tokio_tungstenite::WebSocketStream;
@@ -40,12 +48,141 @@ pub fn get_websocket_proxy_ip() -> Option<IpAddr> {
     }
 }
 
+/*
+Browsers send an Origin header telling which webpage opened the websocket.
+Without checking it, any webpage could connect its visitors to this server.
+Other clients (e.g. netcat with a handshake) usually don't send it, and
+they are allowed, because they could just as well use raw TCP.
+
+An allowed origin without a port allows any port, e.g. "http://localhost"
+allows "http://localhost:8000".
+*/
+pub const DEFAULT_ALLOWED_ORIGINS: &[&str] = &[
+    "https://catris.net",
+    "https://akuli.github.io",
+    "http://localhost",
+    "http://127.0.0.1",
+    "http://[::1]",
+];
+pub static ALLOWED_ORIGINS: OnceLock<Vec<String>> = OnceLock::new();
+
+// Rejected origins are logged, and they can be anything
+const MAX_LOGGED_ORIGIN_LENGTH: usize = 100;
+
+// Returns (scheme, host, port) for origins like "http://localhost:8000"
+fn split_origin(origin: &str) -> Option<(&str, &str, Option<u16>)> {
+    let (scheme, rest) = origin.split_once("://")?;
+    if scheme != "http" && scheme != "https" {
+        return None;
+    }
+    if rest.is_empty()
+        || rest
+            .chars()
+            .any(|c| "/?#@".contains(c) || c.is_whitespace() || c.is_control())
+    {
+        return None;
+    }
+
+    let (host, port) = if let Some(ipv6_and_rest) = rest.strip_prefix('[') {
+        // IPv6 address, e.g. "http://[::1]:8000"
+        let (address, after) = ipv6_and_rest.split_once(']')?;
+        (&rest[..address.len() + 2], after)
+    } else {
+        rest.split_at(rest.find(':').unwrap_or(rest.len()))
+    };
+
+    let port = match port {
+        "" => None,
+        _ => Some(port.strip_prefix(':')?.parse().ok()?),
+    };
+    if host.is_empty() || host == "[]" {
+        return None;
+    }
+    Some((scheme, host, port))
+}
+
+fn origin_is_allowed<S: AsRef<str>>(origin: &str, allowed_origins: &[S]) -> bool {
+    let Some((scheme, host, port)) = split_origin(origin) else {
+        return false;
+    };
+    allowed_origins.iter().any(|allowed| {
+        split_origin(allowed.as_ref()).is_some_and(|(a_scheme, a_host, a_port)| {
+            a_scheme == scheme
+                && a_host.eq_ignore_ascii_case(host)
+                && (a_port.is_none() || a_port == port)
+        })
+    })
+}
+
+// Comma-separated, given with --allowed-origins or CATRIS_ALLOWED_ORIGINS
+pub fn parse_allowed_origins(value: &str) -> Result<Vec<String>, String> {
+    let origins: Vec<String> = value
+        .split(',')
+        .map(|origin| origin.trim())
+        .filter(|origin| !origin.is_empty())
+        .map(|origin| origin.to_string())
+        .collect();
+    if origins.is_empty() {
+        return Err("no origins given".to_string());
+    }
+    if let Some(bad) = origins.iter().find(|origin| split_origin(origin).is_none()) {
+        return Err(format!(
+            "invalid origin {:?} (should be like https://example.com)",
+            bad
+        ));
+    }
+    Ok(origins)
+}
+
+fn format_rejected_origin(value: &[u8]) -> String {
+    let origin = String::from_utf8_lossy(value);
+    let mut result: String = origin.chars().take(MAX_LOGGED_ORIGIN_LENGTH).collect();
+    if origin.chars().count() > MAX_LOGGED_ORIGIN_LENGTH {
+        result.push_str("...");
+    }
+    format!("{:?}", result)
+}
+
+/*
+The client broke the websocket protocol, e.g. sent reserved bits, an unknown
+opcode, a continuation frame without a start, or a message that is too big.
+Frames are decoded by tungstenite, which buffers partial frames and joins
+fragmented messages, but it doesn't tell the client why it gets disconnected.
+This goes inside an io::Error so that the close code can be sent later.
+*/
+#[derive(Debug)]
+struct ProtocolViolation {
+    code: CloseCode,
+    reason: String,
+}
+impl std::fmt::Display for ProtocolViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "websocket protocol violation: {}", self.reason)
+    }
+}
+impl std::error::Error for ProtocolViolation {}
+
+fn protocol_violation(code: CloseCode, reason: String) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, ProtocolViolation { code, reason })
+}
+
 // Errors can be io::Error or tungstenite::Error.
 // I can't box them because boxes aren't Send i.e. can't be held across await.
 fn convert_error(e: tungstenite::Error) -> io::Error {
-    io::Error::new(ErrorKind::Other, format!("websocket error: {:?}", e))
+    match e {
+        tungstenite::Error::Capacity(e) => protocol_violation(CloseCode::Size, e.to_string()),
+        tungstenite::Error::Protocol(e) => protocol_violation(CloseCode::Protocol, e.to_string()),
+        e => io::Error::other(format!("websocket error: {:?}", e)),
+    }
 }
 
+/*
+Escape sequences (e.g. arrow keys) start with the same byte as the Escape key.
+Terminals send the whole sequence at once, so if nothing comes after the
+escape byte for a while, the user pressed Escape.
+*/
+const LONE_ESCAPE_TIMEOUT: Duration = Duration::from_millis(100);
+
 fn connection_closed_error() -> io::Error {
     io::Error::new(ErrorKind::ConnectionAborted, "connection closed")
 }
@@ -66,8 +203,10 @@ impl ReceiveState {
         self.last_recv = Instant::now();
     }
 
+    // Client disconnects idle players with a warning before this, see idle.rs.
+    // This is a fallback for when nothing is waiting for key presses.
     fn get_timeout(&self) -> Duration {
-        let deadline = self.last_recv + Duration::from_secs(10 * 60);
+        let deadline = self.last_recv + Duration::from_secs(11 * 60);
         deadline.saturating_duration_since(Instant::now())
     }
 
@@ -89,9 +228,11 @@ impl ReceiveState {
     }
 }
 
+type WebSocket = WebSocketStream<DeflateStream<TcpStream>>;
+
 pub enum Receiver {
     WebSocket {
-        ws_reader: SplitStream<WebSocketStream<TcpStream>>,
+        ws_reader: SplitStream<WebSocket>,
         recv_state: ReceiveState,
     },
     RawTcp {
@@ -114,16 +255,14 @@ impl Receiver {
                     .map_err(convert_error)?; // error if receiving failed
 
                 match item {
+                    // Empty messages are valid websocket, but spamming them is not ok
+                    Message::Binary(bytes) if bytes.is_empty() => {
+                        recv_state.check_key_press_frequency()?;
+                        Ok(())
+                    }
                     Message::Binary(bytes) => {
-                        if bytes.is_empty() {
-                            Err(io::Error::new(
-                                ErrorKind::Other,
-                                "received empty bytes from websocket message",
-                            ))
-                        } else {
-                            recv_state.add_received_bytes(&bytes);
-                            Ok(())
-                        }
+                        recv_state.add_received_bytes(&bytes);
+                        Ok(())
                     }
                     Message::Close(_) => Err(connection_closed_error()),
                     /*
@@ -137,12 +276,12 @@ impl Receiver {
                     We don't have to send pongs, because tungstenite does it
                     automatically.
                     */
-                    Message::Ping(_) => {
+                    Message::Ping(_) | Message::Pong(_) => {
                         recv_state.check_key_press_frequency()?;
                         Ok(())
                     }
-                    other => Err(io::Error::new(
-                        ErrorKind::Other,
+                    other => Err(protocol_violation(
+                        CloseCode::Unsupported,
                         format!("unexpected websocket frame: {:?}", other),
                     )),
                 }
@@ -177,6 +316,11 @@ impl Receiver {
                     *string = string[bytes_used..].to_string();
                     Ok(key)
                 }
+                // Nothing will come after it, so it's the Escape key
+                None if string == "\x1b" => {
+                    string.clear();
+                    Ok(KeyPress::Escape)
+                }
                 None => Err(connection_closed_error()),
             };
         }
@@ -189,8 +333,21 @@ impl Receiver {
                     recv_state.buffer.as_slices().0
                 }
             };
+            let lone_escape = received_so_far == b"\x1b";
+
+            let parsed = match parse_key_press(received_so_far) {
+                None if lone_escape => {
+                    if let Ok(result) = timeout(LONE_ESCAPE_TIMEOUT, self.receive_more_data()).await
+                    {
+                        result?;
+                        continue;
+                    }
+                    Some((KeyPress::Escape, 1))
+                }
+                parsed => parsed,
+            };
 
-            match parse_key_press(received_so_far) {
+            match parsed {
                 Some((key, bytes_used)) => {
                     let recv_state = match self {
                         Self::Test(_) => panic!(),
@@ -210,7 +367,9 @@ impl Receiver {
 
 pub enum Sender {
     WebSocket {
-        ws_writer: SplitSink<WebSocketStream<tokio::net::TcpStream>, Message>,
+        ws_writer: SplitSink<WebSocket, Message>,
+        sound_events: bool, // client asked for them when connecting
+        compression: bool,  // see deflate.rs
     },
     RawTcp {
         write_half: OwnedWriteHalf,
@@ -219,13 +378,267 @@ pub enum Sender {
 impl Sender {
     pub async fn send(&mut self, data: &[u8]) -> Result<(), io::Error> {
         match self {
-            Self::WebSocket { ws_writer } => ws_writer
+            Self::WebSocket { ws_writer, .. } => ws_writer
                 .send(Message::binary(data.to_vec()))
                 .await
                 .map_err(convert_error),
             Self::RawTcp { write_half } => write_half.write_all(data).await,
         }
     }
+
+    // Tells a websocket client why it gets disconnected, if it broke the protocol
+    pub async fn close_after_error(&mut self, error: &io::Error) -> Result<(), io::Error> {
+        let violation = error
+            .get_ref()
+            .and_then(|e| e.downcast_ref::<ProtocolViolation>());
+        match (self, violation) {
+            (Self::WebSocket { ws_writer, .. }, Some(violation)) => {
+                let frame = CloseFrame {
+                    code: violation.code,
+                    reason: violation.reason.clone().into(),
+                };
+                ws_writer
+                    .send(Message::Close(Some(frame)))
+                    .await
+                    .map_err(convert_error)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn compression_enabled(&self) -> bool {
+        matches!(
+            self,
+            Self::WebSocket {
+                compression: true,
+                ..
+            }
+        )
+    }
+
+    pub fn wants_sound_events(&self) -> bool {
+        matches!(
+            self,
+            Self::WebSocket {
+                sound_events: true,
+                ..
+            }
+        )
+    }
+
+    /*
+    Sound events go in text frames, so that they never mix with the escape
+    sequences in binary frames. Raw TCP has nowhere to put them, and clients
+    that didn't ask for them might not know what to do with text frames.
+    */
+    pub async fn send_sound_event(&mut self, message: &str) -> Result<(), io::Error> {
+        match self {
+            Self::WebSocket {
+                ws_writer,
+                sound_events: true,
+                ..
+            } => ws_writer
+                .send(Message::text(message))
+                .await
+                .map_err(convert_error),
+            _ => Ok(()),
+        }
+    }
+
+    pub async fn send_queued_frames(&mut self, queue: &FrameQueue) -> Result<(), io::Error> {
+        loop {
+            match queue.pop().await {
+                Queued::Frame(frame) => {
+                    self.send(&frame).await?;
+                    FRAMES_SENT.fetch_add(1, Ordering::SeqCst);
+                }
+                Queued::SoundEvent(message) => self.send_sound_event(&message).await?,
+            }
+        }
+    }
+}
+
+pub const FRAME_QUEUE_CAPACITY: usize = 4;
+
+// For all clients together, shown by the admin "stats" command
+pub static FRAMES_SENT: AtomicU64 = AtomicU64::new(0);
+const SOUND_EVENT_QUEUE_CAPACITY: usize = 16;
+
+pub enum Queued {
+    Frame(Vec<u8>),
+    SoundEvent(String),
+}
+
+/*
+Rendered frames waiting to be sent to a client. Rendering doesn't wait for
+slow clients. If the queue fills up, the frames that haven't been sent yet
+are thrown away, and the renderer must push a frame that redraws the whole
+screen, because the thrown away frames only contained changes.
+
+Sound events are queued separately and sent before frames. They are tiny,
+so there's no need to drop them unless a client stops receiving entirely.
+*/
+pub struct FrameQueue {
+    frames: Mutex<VecDeque<Vec<u8>>>,
+    sound_events: Mutex<VecDeque<String>>,
+    notify: Notify,
+    dropped_count: Mutex<usize>,
+}
+
+impl FrameQueue {
+    pub fn new() -> Self {
+        Self {
+            frames: Mutex::new(VecDeque::new()),
+            sound_events: Mutex::new(VecDeque::new()),
+            notify: Notify::new(),
+            dropped_count: Mutex::new(0),
+        }
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.frames.lock().unwrap().len() >= FRAME_QUEUE_CAPACITY
+    }
+
+    pub fn drop_unsent_frames(&self) {
+        let mut frames = self.frames.lock().unwrap();
+        *self.dropped_count.lock().unwrap() += frames.len();
+        frames.clear();
+    }
+
+    pub fn push(&self, frame: Vec<u8>) {
+        let mut frames = self.frames.lock().unwrap();
+        assert!(frames.len() < FRAME_QUEUE_CAPACITY);
+        frames.push_back(frame);
+        self.notify.notify_one();
+    }
+
+    pub fn push_sound_event(&self, message: String) {
+        let mut sound_events = self.sound_events.lock().unwrap();
+        if sound_events.len() >= SOUND_EVENT_QUEUE_CAPACITY {
+            sound_events.pop_front();
+        }
+        sound_events.push_back(message);
+        self.notify.notify_one();
+    }
+
+    pub async fn pop(&self) -> Queued {
+        loop {
+            if let Some(message) = self.sound_events.lock().unwrap().pop_front() {
+                return Queued::SoundEvent(message);
+            }
+            if let Some(frame) = self.frames.lock().unwrap().pop_front() {
+                return Queued::Frame(frame);
+            }
+            self.notify.notified().await;
+        }
+    }
+
+    #[cfg(test)]
+    pub async fn pop_frame(&self) -> Vec<u8> {
+        loop {
+            if let Queued::Frame(frame) = self.pop().await {
+                return frame;
+            }
+        }
+    }
+
+    pub fn get_dropped_count(&self) -> usize {
+        *self.dropped_count.lock().unwrap()
+    }
+}
+
+/*
+Sizes of the frames sent to a client, to see how well the diffing in
+get_updates_as_escape_codes() works in practice. Recording a frame is a
+few arithmetic operations, so this is always on.
+
+Frame sizes go to histogram buckets: the first bucket is frames of at most
+64 bytes, the next one is at most 128 bytes, and so on, doubling each time.
+The last bucket is for everything bigger. Percentiles are approximated with
+the upper limit of the bucket they fall into.
+*/
+const FRAME_SIZE_BUCKET_COUNT: usize = 12;
+const SMALLEST_BUCKET_LIMIT_LOG2: u32 = 6;
+
+fn frame_size_bucket(size: usize) -> usize {
+    if size <= 1 << SMALLEST_BUCKET_LIMIT_LOG2 {
+        return 0;
+    }
+    // Number of bits needed for size-1 is log2(size) rounded up
+    let log2 = usize::BITS - (size - 1).leading_zeros();
+    ((log2 - SMALLEST_BUCKET_LIMIT_LOG2) as usize).min(FRAME_SIZE_BUCKET_COUNT - 1)
+}
+
+// None for the last bucket, which has no limit
+fn frame_size_bucket_limit(bucket: usize) -> Option<usize> {
+    if bucket == FRAME_SIZE_BUCKET_COUNT - 1 {
+        None
+    } else {
+        Some(1 << (bucket as u32 + SMALLEST_BUCKET_LIMIT_LOG2))
+    }
+}
+
+#[derive(Default)]
+pub struct FrameStats {
+    count: u64,
+    full_redraws: u64,
+    total_bytes: u64,
+    buckets: [u64; FRAME_SIZE_BUCKET_COUNT],
+}
+
+impl FrameStats {
+    pub fn record(&mut self, size: usize, full_redraw: bool) {
+        self.count += 1;
+        self.full_redraws += full_redraw as u64;
+        self.total_bytes += size as u64;
+        self.buckets[frame_size_bucket(size)] += 1;
+    }
+
+    fn mean(&self) -> Option<u64> {
+        self.total_bytes.checked_div(self.count)
+    }
+
+    // Index of the bucket containing the given percentile
+    fn percentile_bucket(&self, percent: u64) -> Option<usize> {
+        if self.count == 0 {
+            return None;
+        }
+        // Smallest n such that n/count >= percent/100
+        let target = (self.count * percent).div_ceil(100);
+        let mut seen = 0;
+        for (i, n) in self.buckets.iter().enumerate() {
+            seen += n;
+            if seen >= target {
+                return Some(i);
+            }
+        }
+        unreachable!()
+    }
+
+    pub fn summary(&self) -> String {
+        let (mean, p95) = match (self.mean(), self.percentile_bucket(95)) {
+            (Some(mean), Some(p95)) => (mean, p95),
+            _ => return "no frames sent".to_string(),
+        };
+        let p95_text = match frame_size_bucket_limit(p95) {
+            Some(limit) => format!("<= {}", limit),
+            None => format!("> {}", frame_size_bucket_limit(p95 - 1).unwrap()),
+        };
+        let plural = |n: u64, word: &str| {
+            if n == 1 {
+                format!("1 {}", word)
+            } else {
+                format!("{} {}s", n, word)
+            }
+        };
+        format!(
+            "{} ({}), mean {} bytes, p95 {} bytes",
+            plural(self.count, "frame"),
+            plural(self.full_redraws, "full redraw"),
+            mean,
+            p95_text
+        )
+    }
 }
 
 /*
@@ -258,14 +671,64 @@ In another terminal:
     $ nc localhost 54321 < req.txt
 
 You should see the dummy IP 12.34.56.78 printed.
+
+The callback is also used without a proxy, because it's the only way to see
+the URL and other headers. Clients opt in to sound events by connecting to e.g.
+/websocket?sound-events, and compression is used if the client offers it.
 */
-struct CheckRealIpCallback {
+struct HandshakeCallback {
     client_id: u64,
     ip_tracker: Arc<Mutex<IpTracker>>,
+    check_real_ip: bool,
     decrementers: Vec<ForgetClientOnDrop>,
+    sound_events: bool,
+    compression: bool,
+    rejected_origin: Option<String>,
 }
-impl Callback for &mut CheckRealIpCallback {
-    fn on_request(self, request: &Request, response: Response) -> Result<Response, ErrorResponse> {
+impl Callback for &mut HandshakeCallback {
+    fn on_request(
+        self,
+        request: &Request,
+        mut response: Response,
+    ) -> Result<Response, ErrorResponse> {
+        // Checked first, so that a rejected connection isn't counted for its IP
+        if let Some(origin) = request.headers().get("Origin") {
+            let allowed_origins = match ALLOWED_ORIGINS.get() {
+                Some(origins) => origins.iter().map(|o| o.as_str()).collect(),
+                None => DEFAULT_ALLOWED_ORIGINS.to_vec(),
+            };
+            let allowed = origin
+                .to_str()
+                .is_ok_and(|origin| origin_is_allowed(origin, &allowed_origins));
+            if !allowed {
+                self.rejected_origin = Some(format_rejected_origin(origin.as_bytes()));
+                return Err(http::Response::builder()
+                    .status(StatusCode::FORBIDDEN)
+                    .body(None)
+                    .unwrap());
+            }
+        }
+
+        self.sound_events = request
+            .uri()
+            .query()
+            .is_some_and(|query| query.split('&').any(|param| param == "sound-events"));
+        self.compression = request
+            .headers()
+            .get_all("Sec-WebSocket-Extensions")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .any(deflate::accepts_offer);
+        if self.compression {
+            response.headers_mut().insert(
+                "Sec-WebSocket-Extensions",
+                http::HeaderValue::from_static(deflate::EXTENSION_RESPONSE),
+            );
+        }
+        if !self.check_real_ip {
+            return Ok(response);
+        }
+
         let ip: IpAddr = request
             .headers()
             .get("X-Real-IP")
@@ -298,6 +761,9 @@ impl Callback for &mut CheckRealIpCallback {
     }
 }
 
+// Key presses are tiny, bigger messages are probably an attack
+const MAX_WEBSOCKET_MESSAGE_SIZE: usize = 1024;
+
 pub async fn initialize_connection(
     ip_tracker: Arc<Mutex<IpTracker>>,
     client_id: u64,
@@ -342,34 +808,50 @@ pub async fn initialize_connection(
             // Prevent various denial-of-service attacks that fill up server's memory.
             // Most defaults are reasonable, but unnecessarily huge for this program.
             max_send_queue: Some(10), // TODO: can be 1? https://github.com/snapview/tungstenite-rs/issues/285
-            max_message_size: Some(1024),
-            max_frame_size: Some(1024),
+            max_message_size: Some(MAX_WEBSOCKET_MESSAGE_SIZE),
+            max_frame_size: Some(MAX_WEBSOCKET_MESSAGE_SIZE),
             ..Default::default()
         };
 
-        let ws;
-        if get_websocket_proxy_ip().is_some() {
-            let mut cb = CheckRealIpCallback {
-                decrementers: vec![],
-                ip_tracker,
-                client_id,
-            };
-            ws = tokio_tungstenite::accept_hdr_async_with_config(socket, &mut cb, Some(config))
-                .await
-                .map_err(convert_error)?;
+        // Without a proxy, clients connect directly to server and source ip is usable
+        let mut cb = HandshakeCallback {
+            client_id,
+            ip_tracker,
+            check_real_ip: decrementer.is_none(),
+            decrementers: vec![],
+            sound_events: false,
+            compression: false,
+            rejected_origin: None,
+        };
+        let handshake_result = tokio_tungstenite::accept_hdr_async_with_config(
+            DeflateStream::new(socket),
+            &mut cb,
+            Some(config),
+        )
+        .await;
+        if let Some(origin) = cb.rejected_origin {
+            return Err(io::Error::new(
+                ErrorKind::PermissionDenied,
+                format!("websocket origin not allowed: {}", origin),
+            ));
+        }
+        let mut ws = handshake_result.map_err(convert_error)?;
+        if cb.compression {
+            ws.get_mut().enable_compression(MAX_WEBSOCKET_MESSAGE_SIZE);
+        }
+        if cb.check_real_ip {
             assert!(cb.decrementers.len() == 1);
             decrementer = cb.decrementers.pop();
-        } else {
-            // Clients connect directly to server, source ip is usable
-            ws = tokio_tungstenite::accept_async_with_config(socket, Some(config))
-                .await
-                .map_err(convert_error)?;
         }
 
         assert!(decrementer.is_some());
 
         let (ws_writer, ws_reader) = ws.split();
-        sender = Sender::WebSocket { ws_writer };
+        sender = Sender::WebSocket {
+            ws_writer,
+            sound_events: cb.sound_events,
+            compression: cb.compression,
+        };
         receiver = Receiver::WebSocket {
             ws_reader,
             recv_state,
@@ -385,3 +867,706 @@ pub async fn initialize_connection(
 
     Ok((sender, receiver, decrementer.unwrap()))
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::escapes::TerminalType;
+    use crate::game_wrapper::SoundEvent;
+
+    // Like what handle_sending() in main.rs does
+    fn push_frame(queue: &FrameQueue, state: usize) {
+        if queue.is_full() {
+            queue.drop_unsent_frames();
+            queue.push(format!("full {}", state).into_bytes());
+        } else {
+            queue.push(format!("changes {}", state).into_bytes());
+        }
+    }
+
+    #[test]
+    fn test_frame_size_buckets() {
+        assert_eq!(frame_size_bucket(0), 0);
+        assert_eq!(frame_size_bucket(64), 0);
+        assert_eq!(frame_size_bucket(65), 1);
+        assert_eq!(frame_size_bucket(128), 1);
+        assert_eq!(frame_size_bucket(129), 2);
+        assert_eq!(frame_size_bucket(1 << 16), FRAME_SIZE_BUCKET_COUNT - 2);
+        assert_eq!(
+            frame_size_bucket((1 << 16) + 1),
+            FRAME_SIZE_BUCKET_COUNT - 1
+        );
+        assert_eq!(frame_size_bucket(usize::MAX), FRAME_SIZE_BUCKET_COUNT - 1);
+
+        for bucket in 0..(FRAME_SIZE_BUCKET_COUNT - 1) {
+            let limit = frame_size_bucket_limit(bucket).unwrap();
+            assert_eq!(frame_size_bucket(limit), bucket);
+            assert_eq!(frame_size_bucket(limit + 1), bucket + 1);
+        }
+        assert_eq!(frame_size_bucket_limit(FRAME_SIZE_BUCKET_COUNT - 1), None);
+    }
+
+    #[test]
+    fn test_frame_stats() {
+        let mut stats = FrameStats::default();
+        assert_eq!(stats.summary(), "no frames sent");
+
+        // 95 small diffs and 5 full redraws
+        for _ in 0..95 {
+            stats.record(50, false);
+        }
+        for _ in 0..5 {
+            stats.record(3000, true);
+        }
+        assert_eq!(stats.mean(), Some((95 * 50 + 5 * 3000) / 100));
+        assert_eq!(stats.percentile_bucket(95), Some(0));
+        assert_eq!(stats.percentile_bucket(96), Some(frame_size_bucket(3000)));
+        assert_eq!(
+            stats.summary(),
+            "100 frames (5 full redraws), mean 197 bytes, p95 <= 64 bytes"
+        );
+
+        // One more big frame pushes the 95th percentile into the big bucket
+        stats.record(3000, true);
+        assert_eq!(
+            stats.summary(),
+            "101 frames (6 full redraws), mean 225 bytes, p95 <= 4096 bytes"
+        );
+
+        let mut stats = FrameStats::default();
+        stats.record(1 << 20, true);
+        assert_eq!(
+            stats.summary(),
+            "1 frame (1 full redraw), mean 1048576 bytes, p95 > 65536 bytes"
+        );
+    }
+
+    // Runs the handshake callback with the default allowed origins
+    fn check_origin(origin: Option<&[u8]>) -> (Result<(), StatusCode>, Option<String>) {
+        let mut request = http::Request::builder().uri("/websocket");
+        if let Some(origin) = origin {
+            request = request.header("Origin", http::HeaderValue::from_bytes(origin).unwrap());
+        }
+        let mut cb = HandshakeCallback {
+            client_id: 1,
+            ip_tracker: Arc::new(Mutex::new(IpTracker::new())),
+            check_real_ip: false,
+            decrementers: vec![],
+            sound_events: false,
+            compression: false,
+            rejected_origin: None,
+        };
+        let result = (&mut cb)
+            .on_request(&request.body(()).unwrap(), Response::default())
+            .map(|_| ())
+            .map_err(|response| response.status());
+        (result, cb.rejected_origin)
+    }
+
+    #[test]
+    fn test_allowed_origins() {
+        for origin in [
+            "https://catris.net",
+            "https://akuli.github.io",
+            "https://AKULI.github.io",
+            "http://localhost:8000",
+            "http://127.0.0.1:8000",
+            "http://[::1]:8000",
+        ] {
+            assert_eq!(check_origin(Some(origin.as_bytes())), (Ok(()), None));
+        }
+        // Non-browser clients don't send it
+        assert_eq!(check_origin(None), (Ok(()), None));
+    }
+
+    #[test]
+    fn test_disallowed_origins() {
+        for origin in [
+            "https://evil.example.com",
+            "http://akuli.github.io", // wrong scheme
+            "https://akuli.github.io.evil.example.com",
+            "http://localhost.evil.example.com",
+        ] {
+            let (result, rejected) = check_origin(Some(origin.as_bytes()));
+            assert_eq!(result, Err(StatusCode::FORBIDDEN));
+            assert_eq!(rejected, Some(format!("{:?}", origin)));
+        }
+    }
+
+    #[test]
+    fn test_malformed_origins() {
+        for origin in [
+            "null",
+            "",
+            "localhost",
+            "https://",
+            "https://akuli.github.io/catris",
+            "https://akuli.github.io:notaport",
+            "https://akuli.github.io:99999",
+            "https://user@akuli.github.io",
+            "ftp://localhost",
+            "http://[::1",
+        ] {
+            let (result, _) = check_origin(Some(origin.as_bytes()));
+            assert_eq!(result, Err(StatusCode::FORBIDDEN), "{:?}", origin);
+        }
+
+        let (result, rejected) = check_origin(Some(b"https://\xff"));
+        assert_eq!(result, Err(StatusCode::FORBIDDEN));
+        assert_eq!(rejected.unwrap(), "\"https://\u{fffd}\"");
+
+        let long = format!("https://{}.com", "a".repeat(200));
+        let (_, rejected) = check_origin(Some(long.as_bytes()));
+        let expected = format!("https://{}...", "a".repeat(MAX_LOGGED_ORIGIN_LENGTH - 8));
+        assert_eq!(rejected.unwrap(), format!("{:?}", expected));
+    }
+
+    #[test]
+    fn test_parse_allowed_origins() {
+        assert_eq!(
+            parse_allowed_origins("https://example.com, http://localhost:8000,"),
+            Ok(vec![
+                "https://example.com".to_string(),
+                "http://localhost:8000".to_string()
+            ])
+        );
+        assert_eq!(
+            parse_allowed_origins(" , "),
+            Err("no origins given".to_string())
+        );
+        assert_eq!(
+            parse_allowed_origins("https://example.com,example.org"),
+            Err("invalid origin \"example.org\" (should be like https://example.com)".to_string())
+        );
+
+        // Port is optional in the allowed origin, but must match if given
+        let allowed = parse_allowed_origins("http://192.168.1.3,https://example.com:8443").unwrap();
+        assert!(origin_is_allowed("http://192.168.1.3:8000", &allowed));
+        assert!(origin_is_allowed("http://192.168.1.3", &allowed));
+        assert!(origin_is_allowed("https://example.com:8443", &allowed));
+        assert!(!origin_is_allowed("https://example.com", &allowed));
+        assert!(!origin_is_allowed("https://example.com:8444", &allowed));
+    }
+
+    #[tokio::test]
+    async fn test_stalled_writer_doesnt_block_rendering() {
+        let queue = Arc::new(FrameQueue::new());
+        let stall = Arc::new(Notify::new());
+
+        // Writer receives one frame and then gets stuck, like a slow client
+        let writer = {
+            let queue = queue.clone();
+            let stall = stall.clone();
+            tokio::spawn(async move {
+                let mut received = vec![queue.pop_frame().await];
+                stall.notified().await;
+                while received.last().unwrap() != b"changes 10" {
+                    received.push(queue.pop_frame().await);
+                }
+                received
+            })
+        };
+
+        push_frame(&queue, 0);
+        tokio::task::yield_now().await;
+        for state in 1..=10 {
+            push_frame(&queue, state);
+        }
+        assert_eq!(queue.get_dropped_count(), 8);
+
+        stall.notify_one();
+        let received = timeout(Duration::from_secs(1), writer)
+            .await
+            .unwrap()
+            .unwrap();
+        // Only the full redraw and what came after it are left
+        assert_eq!(
+            received,
+            [&b"changes 0"[..], &b"full 9"[..], &b"changes 10"[..]]
+        );
+    }
+
+    // Returns the server side of a raw TCP connection and the socket of the client
+    async fn connect_raw_tcp() -> (Sender, Receiver, TcpStream) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (socket, _) = listener.accept().await.unwrap();
+        let (read_half, write_half) = socket.into_split();
+        let recv_state = ReceiveState {
+            buffer: VecDeque::new(),
+            key_press_times: VecDeque::new(),
+            last_recv: Instant::now(),
+        };
+        (
+            Sender::RawTcp { write_half },
+            Receiver::RawTcp {
+                read_half,
+                recv_state,
+            },
+            client,
+        )
+    }
+
+    // Returns the server side of a websocket connection and the client's websocket
+    async fn connect_websocket(
+        path: &str,
+    ) -> (
+        Sender,
+        Receiver,
+        WebSocketStream<tokio_tungstenite::MaybeTlsStream<TcpStream>>,
+    ) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}{}", listener.local_addr().unwrap(), path);
+        let client = tokio::spawn(async move {
+            let (ws, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+            ws
+        });
+        let (socket, addr) = listener.accept().await.unwrap();
+        let ip_tracker = Arc::new(Mutex::new(IpTracker::new()));
+        let (sender, receiver, _) = initialize_connection(ip_tracker, 1, socket, addr.ip(), true)
+            .await
+            .unwrap();
+        (sender, receiver, client.await.unwrap())
+    }
+
+    // Returns the server side of a websocket connection and a raw socket for sending frames by hand
+    async fn connect_websocket_raw() -> (Sender, Receiver, TcpStream) {
+        let (sender, receiver, client, _) = connect_websocket_raw_with_headers("").await;
+        (sender, receiver, client)
+    }
+
+    // Also returns the response headers
+    async fn connect_websocket_raw_with_headers(
+        extra_headers: &str,
+    ) -> (Sender, Receiver, TcpStream, String) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let request = format!(
+            "GET / HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+             Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+             Sec-WebSocket-Version: 13\r\n{}\r\n",
+            extra_headers
+        );
+        client.write_all(request.as_bytes()).await.unwrap();
+        let (socket, addr) = listener.accept().await.unwrap();
+        let ip_tracker = Arc::new(Mutex::new(IpTracker::new()));
+        let (sender, receiver, _) = initialize_connection(ip_tracker, 1, socket, addr.ip(), true)
+            .await
+            .unwrap();
+
+        let mut response = vec![];
+        while !response.ends_with(b"\r\n\r\n") {
+            response.push(client.read_u8().await.unwrap());
+        }
+        assert!(response.starts_with(b"HTTP/1.1 101"));
+        (
+            sender,
+            receiver,
+            client,
+            String::from_utf8(response).unwrap(),
+        )
+    }
+
+    // Clients must mask their frames
+    fn masked_frame(first_byte: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [1, 2, 3, 4];
+        let mut frame = vec![first_byte];
+        if payload.len() < 126 {
+            frame.push(0x80 | payload.len() as u8);
+        } else {
+            frame.push(0x80 | 126);
+            frame.extend((payload.len() as u16).to_be_bytes());
+        }
+        frame.extend(mask);
+        frame.extend(payload.iter().zip(mask.iter().cycle()).map(|(b, m)| b ^ m));
+        frame
+    }
+
+    // Fragmented message, empty frames, a ping and an arrow key split across messages
+    fn tricky_frames() -> Vec<u8> {
+        let mut bytes = vec![];
+        bytes.extend(masked_frame(0x02, b"a")); // binary, not final
+        bytes.extend(masked_frame(0x89, b"ping")); // pings can be between fragments
+        bytes.extend(masked_frame(0x00, b"")); // continuation, not final
+        bytes.extend(masked_frame(0x80, b"b\x1b[")); // continuation, final
+        bytes.extend(masked_frame(0x82, b"")); // empty binary
+        bytes.extend(masked_frame(0x82, b"Ac"));
+        bytes
+    }
+
+    async fn receive_tricky_frames(mut receiver: Receiver) {
+        for expected in [
+            KeyPress::Character('a'),
+            KeyPress::Character('b'),
+            KeyPress::Up,
+            KeyPress::Character('c'),
+        ] {
+            assert_eq!(receiver.receive_key_press().await.unwrap(), expected);
+        }
+    }
+
+    // Returns close code and reason
+    async fn read_close_frame(client: &mut TcpStream) -> (u16, String) {
+        assert_eq!(client.read_u8().await.unwrap(), 0x88);
+        let len = client.read_u8().await.unwrap() as usize; // server frames aren't masked
+        let mut payload = vec![0; len];
+        client.read_exact(&mut payload).await.unwrap();
+        let code = u16::from_be_bytes([payload[0], payload[1]]);
+        (code, String::from_utf8(payload[2..].to_vec()).unwrap())
+    }
+
+    // Sound events from a short game, and a frame in between
+    fn queue_game_events(queue: &FrameQueue) {
+        queue.push(b"frame".to_vec());
+        for event in [
+            SoundEvent::BlockLanded,
+            SoundEvent::RowsCleared(2),
+            SoundEvent::BombExploded,
+            SoundEvent::GameOver,
+        ] {
+            queue.push_sound_event(event.to_message());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sound_events_only_sent_when_requested() {
+        for (path, expect_sounds) in [
+            ("/websocket?sound-events", true),
+            ("/websocket?foo=bar&sound-events", true),
+            ("/websocket", false),
+        ] {
+            let (mut sender, _receiver, mut client) = connect_websocket(path).await;
+            assert_eq!(sender.wants_sound_events(), expect_sounds);
+
+            let queue = FrameQueue::new();
+            queue_game_events(&queue);
+            queue.push(b"last frame".to_vec());
+            tokio::select! {
+                result = sender.send_queued_frames(&queue) => panic!("{:?}", result.err()),
+                _ = async {
+                    let mut texts = vec![];
+                    loop {
+                        match client.next().await.unwrap().unwrap() {
+                            Message::Text(text) => texts.push(text),
+                            Message::Binary(bytes) if bytes == b"last frame" => break,
+                            Message::Binary(_) => {}
+                            other => panic!("unexpected message: {:?}", other),
+                        }
+                    }
+                    if expect_sounds {
+                        assert_eq!(
+                            texts,
+                            [
+                                "sound block_landed",
+                                "sound rows_cleared 2",
+                                "sound bomb_exploded",
+                                "sound game_over",
+                            ]
+                        );
+                    } else {
+                        assert!(texts.is_empty());
+                    }
+                } => {}
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sound_events_not_sent_with_raw_tcp() {
+        let (mut sender, _receiver, mut client) = connect_raw_tcp().await;
+        assert!(!sender.wants_sound_events());
+
+        let queue = FrameQueue::new();
+        queue_game_events(&queue);
+        let _ = timeout(
+            Duration::from_millis(100),
+            sender.send_queued_frames(&queue),
+        )
+        .await;
+        drop(sender);
+
+        let mut output = vec![];
+        client.read_to_end(&mut output).await.unwrap();
+        assert_eq!(output, b"frame");
+    }
+
+    #[tokio::test]
+    async fn test_terminal_detection_with_slow_response() {
+        let (mut sender, mut receiver, mut client) = connect_raw_tcp().await;
+
+        let detecting = tokio::spawn(async move {
+            let result =
+                crate::detect_terminal_type(&mut sender, &mut receiver, Duration::from_secs(10))
+                    .await;
+            drop(sender); // lets the client read everything that was sent
+            result
+        });
+
+        // Response to DSR arrives in two parts, with countdown ticks in between
+        tokio::time::sleep(Duration::from_millis(2500)).await;
+        client.write_all(b"\x1b[").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(1000)).await;
+        client.write_all(b"12;34R").await.unwrap();
+
+        let terminal_type = detecting.await.unwrap().unwrap();
+        assert_eq!(terminal_type, TerminalType::Ansi);
+
+        let mut output = String::new();
+        client.read_to_string(&mut output).await.unwrap();
+        for seconds in [10, 9, 8, 7] {
+            assert!(output.contains(&format!(
+                "\rAuto-detecting... {}s left - press a for ANSI, v for VT52",
+                seconds
+            )));
+        }
+        assert!(!output.contains("6s left"));
+    }
+
+    #[tokio::test]
+    async fn test_terminal_detection_without_response() {
+        let (mut sender, mut receiver, _client) = connect_raw_tcp().await;
+
+        let start = Instant::now();
+        let error = crate::detect_terminal_type(&mut sender, &mut receiver, Duration::from_secs(2))
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::TimedOut);
+        assert!(start.elapsed() >= Duration::from_secs(2));
+        assert!(start.elapsed() < Duration::from_secs(3));
+    }
+
+    #[tokio::test]
+    async fn test_websocket_frames_one_byte_at_a_time() {
+        let (_sender, receiver, mut client) = connect_websocket_raw().await;
+        client.set_nodelay(true).unwrap();
+        let writing = tokio::spawn(async move {
+            for byte in tricky_frames() {
+                client.write_all(&[byte]).await.unwrap();
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+            client
+        });
+        receive_tricky_frames(receiver).await;
+        writing.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_websocket_frames_in_random_chunks() {
+        use rand::Rng;
+        use rand::SeedableRng;
+
+        let (_sender, receiver, mut client) = connect_websocket_raw().await;
+        client.set_nodelay(true).unwrap();
+        let writing = tokio::spawn(async move {
+            let mut rng = rand::rngs::StdRng::seed_from_u64(123);
+            let bytes = tricky_frames();
+            let mut rest: &[u8] = &bytes;
+            while !rest.is_empty() {
+                let (chunk, after) = rest.split_at(rng.gen_range(1..=rest.len().min(7)));
+                client.write_all(chunk).await.unwrap();
+                tokio::time::sleep(Duration::from_millis(1)).await;
+                rest = after;
+            }
+            client
+        });
+        receive_tricky_frames(receiver).await;
+        writing.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_websocket_oversized_frame() {
+        let (mut sender, mut receiver, mut client) = connect_websocket_raw().await;
+        client
+            .write_all(&masked_frame(0x82, &[b'x'; 2000]))
+            .await
+            .unwrap();
+
+        let error = receiver.receive_key_press().await.unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        sender.close_after_error(&error).await.unwrap();
+        let (code, reason) = read_close_frame(&mut client).await;
+        assert_eq!(code, 1009);
+        assert!(reason.contains("2000"));
+    }
+
+    #[tokio::test]
+    async fn test_websocket_reserved_bits_and_unknown_opcodes() {
+        for first_byte in [0xC2, 0xA2, 0x92, 0x83, 0x8B, 0x80] {
+            let (mut sender, mut receiver, mut client) = connect_websocket_raw().await;
+            client
+                .write_all(&masked_frame(first_byte, b"x"))
+                .await
+                .unwrap();
+
+            let error = receiver.receive_key_press().await.unwrap_err();
+            sender.close_after_error(&error).await.unwrap();
+            assert_eq!(read_close_frame(&mut client).await.0, 1002);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_lone_escape() {
+        let (_sender, mut receiver, mut client) = connect_websocket_raw().await;
+
+        // Rest of an escape sequence arrives a bit later
+        client
+            .write_all(&masked_frame(0x82, b"\x1b"))
+            .await
+            .unwrap();
+        let (key, _) = tokio::join!(receiver.receive_key_press(), async {
+            tokio::time::sleep(Duration::from_millis(30)).await;
+            client.write_all(&masked_frame(0x82, b"OA")).await.unwrap();
+        });
+        assert_eq!(key.unwrap(), KeyPress::Up);
+
+        let start = Instant::now();
+        client
+            .write_all(&masked_frame(0x82, b"\x1b"))
+            .await
+            .unwrap();
+        assert_eq!(
+            receiver.receive_key_press().await.unwrap(),
+            KeyPress::Escape
+        );
+        assert!(start.elapsed() >= LONE_ESCAPE_TIMEOUT);
+
+        client
+            .write_all(&masked_frame(0x82, b"\x1b[A"))
+            .await
+            .unwrap();
+        assert_eq!(receiver.receive_key_press().await.unwrap(), KeyPress::Up);
+    }
+
+    // Like what a browser does, with the other end's compressor
+    fn compress_like_client(data: &[u8]) -> Vec<u8> {
+        let mut encoder =
+            flate2::write::DeflateEncoder::new(vec![], flate2::Compression::default());
+        std::io::Write::write_all(&mut encoder, data).unwrap();
+        std::io::Write::flush(&mut encoder).unwrap(); // sync flush, ends with 00 00 ff ff
+        let compressed = encoder.finish().unwrap();
+        let end = compressed
+            .windows(4)
+            .rposition(|w| w == [0x00, 0x00, 0xff, 0xff])
+            .unwrap();
+        compressed[..end].to_vec()
+    }
+
+    // Returns the first byte of the frame and the decompressed payload
+    async fn read_compressed_frame(client: &mut TcpStream) -> (u8, Vec<u8>) {
+        let first_byte = client.read_u8().await.unwrap();
+        let len = match client.read_u8().await.unwrap() {
+            126 => client.read_u16().await.unwrap() as usize,
+            n => n as usize,
+        };
+        let mut payload = vec![0; len];
+        client.read_exact(&mut payload).await.unwrap();
+        payload.extend([0x00, 0x00, 0xff, 0xff]);
+
+        let mut decompressed = vec![];
+        let mut decoder = flate2::read::DeflateDecoder::new(&payload[..]);
+        // The stream doesn't end, so reading fails after all data is read
+        let _ = std::io::Read::read_to_end(&mut decoder, &mut decompressed);
+        (first_byte, decompressed)
+    }
+
+    #[tokio::test]
+    async fn test_websocket_compression() {
+        let (mut sender, mut receiver, mut client, response) = connect_websocket_raw_with_headers(
+            "Sec-WebSocket-Extensions: permessage-deflate; client_max_window_bits\r\n",
+        )
+        .await;
+        assert!(response.to_lowercase().contains(
+            "sec-websocket-extensions: permessage-deflate; \
+             server_no_context_takeover; client_no_context_takeover\r\n"
+        ));
+        assert!(sender.compression_enabled());
+
+        // Compressed frames have the RSV1 bit set
+        let screen = b"\x1b[1;1H\x1b[31m[]\x1b[0m    ".repeat(50);
+        sender.send(&screen).await.unwrap();
+        let (first_byte, payload) = read_compressed_frame(&mut client).await;
+        assert_eq!(first_byte, 0xC2);
+        assert_eq!(payload, screen);
+
+        // Compressing a tiny frame would make it bigger
+        sender.send(b"x").await.unwrap();
+        assert_eq!(client.read_u8().await.unwrap(), 0x82);
+        assert_eq!(client.read_u8().await.unwrap(), 1);
+        assert_eq!(client.read_u8().await.unwrap(), b'x');
+
+        // Compressed message, also fragmented with a ping in between, and uncompressed frames
+        let compressed = compress_like_client(b"abc\x1b[A");
+        let (start, end) = compressed.split_at(2);
+        client
+            .write_all(&masked_frame(0xC2, &compress_like_client(b"xy")))
+            .await
+            .unwrap();
+        client.write_all(&masked_frame(0x42, start)).await.unwrap();
+        client
+            .write_all(&masked_frame(0x89, b"ping"))
+            .await
+            .unwrap();
+        client.write_all(&masked_frame(0x80, end)).await.unwrap();
+        client.write_all(&tricky_frames()).await.unwrap();
+        for expected in [
+            KeyPress::Character('x'),
+            KeyPress::Character('y'),
+            KeyPress::Character('a'),
+            KeyPress::Character('b'),
+            KeyPress::Character('c'),
+            KeyPress::Up,
+        ] {
+            assert_eq!(receiver.receive_key_press().await.unwrap(), expected);
+        }
+        receive_tricky_frames(receiver).await;
+    }
+
+    #[tokio::test]
+    async fn test_websocket_compression_not_offered() {
+        for extra_headers in ["", "Sec-WebSocket-Extensions: x-webkit-deflate-frame\r\n"] {
+            let (mut sender, _receiver, mut client, response) =
+                connect_websocket_raw_with_headers(extra_headers).await;
+            assert!(!response.to_lowercase().contains("sec-websocket-extensions"));
+            assert!(!sender.compression_enabled());
+
+            let screen = b"\x1b[1;1H\x1b[31m[]\x1b[0m    ".repeat(50);
+            sender.send(&screen).await.unwrap();
+            assert_eq!(client.read_u8().await.unwrap(), 0x82);
+            assert_eq!(client.read_u8().await.unwrap(), 126);
+            assert_eq!(client.read_u16().await.unwrap() as usize, screen.len());
+            let mut payload = vec![0; screen.len()];
+            client.read_exact(&mut payload).await.unwrap();
+            assert_eq!(payload, screen);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_websocket_compressed_message_too_big() {
+        let (mut sender, mut receiver, mut client, _) =
+            connect_websocket_raw_with_headers("Sec-WebSocket-Extensions: permessage-deflate\r\n")
+                .await;
+        client
+            .write_all(&masked_frame(0xC2, &compress_like_client(&[b'x'; 100_000])))
+            .await
+            .unwrap();
+
+        let error = receiver.receive_key_press().await.unwrap_err();
+        sender.close_after_error(&error).await.unwrap();
+        assert_eq!(read_close_frame(&mut client).await.0, 1009);
+    }
+
+    #[tokio::test]
+    async fn test_close_after_error_ignores_other_errors() {
+        let (mut sender, _receiver, mut client) = connect_websocket_raw().await;
+        sender
+            .close_after_error(&connection_closed_error())
+            .await
+            .unwrap();
+        sender.send(b"hello").await.unwrap();
+        assert_eq!(client.read_u8().await.unwrap(), 0x82);
+    }
+}
@@ -0,0 +1,412 @@
+/*
+permessage-deflate websocket extension (RFC 7692). The escape sequences sent
+to clients repeat a lot, and the web client is often used on mobile data.
+
+tungstenite doesn't support the extension, so DeflateStream sits between the
+socket and tungstenite. It compresses data frames written by tungstenite and
+decompresses compressed data frames before tungstenite sees them. Other frames
+pass through unchanged, so tungstenite still handles pings, close frames and
+protocol violations.
+
+Context takeover is disabled in both directions. Each message is compressed
+on its own, so that the compressor doesn't keep a growing history per client.
+*/
+use flate2::Compress;
+use flate2::Compression;
+use flate2::Decompress;
+use flate2::FlushCompress;
+use flate2::FlushDecompress;
+use std::io;
+use std::io::Cursor;
+use std::pin::Pin;
+use std::task::ready;
+use std::task::Context;
+use std::task::Poll;
+use tokio::io::AsyncRead;
+use tokio::io::AsyncWrite;
+use tokio::io::ReadBuf;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::Data;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::OpCode;
+use tokio_tungstenite::tungstenite::protocol::frame::Frame;
+use tokio_tungstenite::tungstenite::protocol::frame::FrameHeader;
+
+pub const EXTENSION_RESPONSE: &str =
+    "permessage-deflate; server_no_context_takeover; client_no_context_takeover";
+
+// Every compressed message ends with these bytes, and they are left out when sending
+const MESSAGE_TAIL: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+// Don't buffer without limit when the client doesn't receive
+const MAX_UNSENT_BYTES: usize = 64 * 1024;
+
+/*
+The value of a Sec-WebSocket-Extensions header can contain several offers,
+e.g. "permessage-deflate; client_max_window_bits, x-webkit-deflate-frame".
+The compressor always uses a 32KB window, so an offer that needs a smaller
+window from the server is skipped.
+*/
+pub fn accepts_offer(header_value: &str) -> bool {
+    header_value.split(',').any(|offer| {
+        let mut parts = offer.split(';').map(|part| part.trim());
+        parts.next() == Some("permessage-deflate")
+            && parts.all(|param| {
+                let (name, value) = match param.split_once('=') {
+                    Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+                    None => (param, None),
+                };
+                match name {
+                    "server_no_context_takeover" | "client_no_context_takeover" => value.is_none(),
+                    "client_max_window_bits" => true,
+                    "server_max_window_bits" => value == Some("15"),
+                    _ => false,
+                }
+            })
+    })
+}
+
+struct Codec {
+    compress: Compress,
+    decompress: Decompress,
+    max_message_size: usize,
+}
+
+impl Codec {
+    // Returns None if compressing doesn't make the message smaller
+    fn compress(&mut self, data: &[u8]) -> Option<Vec<u8>> {
+        self.compress.reset();
+        let mut output = Vec::with_capacity(data.len() + 64);
+        loop {
+            let consumed = self.compress.total_in() as usize;
+            self.compress
+                .compress_vec(&data[consumed..], &mut output, FlushCompress::Sync)
+                .unwrap();
+            if self.compress.total_in() as usize == data.len() && output.len() < output.capacity() {
+                break;
+            }
+            output.reserve(data.len() + 64);
+        }
+        assert!(output.ends_with(&MESSAGE_TAIL));
+        output.truncate(output.len() - MESSAGE_TAIL.len());
+        (output.len() < data.len()).then_some(output)
+    }
+
+    // Output longer than the max size gets truncated, and tungstenite rejects it as too big
+    fn decompress(&mut self, mut data: Vec<u8>) -> Option<Vec<u8>> {
+        data.extend(MESSAGE_TAIL);
+        self.decompress.reset(false);
+        let mut output = Vec::with_capacity(self.max_message_size + 1);
+        self.decompress
+            .decompress_vec(&data, &mut output, FlushDecompress::Sync)
+            .ok()?;
+        Some(output)
+    }
+}
+
+fn unmask(header: &FrameHeader, payload: &mut [u8]) {
+    if let Some(mask) = header.mask {
+        for (byte, mask_byte) in payload.iter_mut().zip(mask.iter().cycle()) {
+            *byte ^= mask_byte;
+        }
+    }
+}
+
+struct SplitFrame {
+    header: FrameHeader,
+    raw: Vec<u8>, // the whole frame as it was
+    payload: Vec<u8>,
+}
+
+enum Split {
+    Frame(SplitFrame),
+    Incomplete,
+    Invalid, // e.g. unknown opcode or too big, left for tungstenite to complain about
+}
+
+fn split_frame(bytes: &mut Vec<u8>, max_payload_len: usize) -> Split {
+    let mut cursor = Cursor::new(&bytes[..]);
+    let (header, payload_len) = match FrameHeader::parse(&mut cursor) {
+        Ok(Some(result)) => result,
+        Ok(None) => return Split::Incomplete,
+        Err(_) => return Split::Invalid,
+    };
+    if payload_len > max_payload_len as u64 {
+        return Split::Invalid;
+    }
+    let header_len = cursor.position() as usize;
+    let frame_len = header_len + payload_len as usize;
+    if bytes.len() < frame_len {
+        return Split::Incomplete;
+    }
+    let raw: Vec<u8> = bytes.drain(..frame_len).collect();
+    let mut payload = raw[header_len..].to_vec();
+    unmask(&header, &mut payload);
+    Split::Frame(SplitFrame {
+        header,
+        raw,
+        payload,
+    })
+}
+
+// A fragmented compressed message that is being received
+struct Fragments {
+    opcode: OpCode,
+    payload: Vec<u8>,
+    raw: Vec<u8>,
+}
+
+fn format_frame(header: FrameHeader, payload: Vec<u8>, output: &mut Vec<u8>) {
+    Frame::from_payload(header, payload).format(output).unwrap();
+}
+
+pub struct DeflateStream<S> {
+    inner: S,
+    codec: Option<Codec>, // None until compression is negotiated
+    received: Vec<u8>,    // from the client, not converted yet
+    converted: Vec<u8>,   // for tungstenite to read
+    fragments: Option<Fragments>,
+    written: Vec<u8>, // from tungstenite, not converted yet
+    unsent: Vec<u8>,
+    // If the client sends something that can't be converted, the rest goes to tungstenite as is
+    passthrough: bool,
+}
+
+impl<S> DeflateStream<S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            codec: None,
+            received: vec![],
+            converted: vec![],
+            fragments: None,
+            written: vec![],
+            unsent: vec![],
+            passthrough: false,
+        }
+    }
+
+    // Call this after the handshake, so that the handshake isn't treated as frames
+    pub fn enable_compression(&mut self, max_message_size: usize) {
+        self.codec = Some(Codec {
+            compress: Compress::new(Compression::default(), false),
+            decompress: Decompress::new(false),
+            max_message_size,
+        });
+    }
+
+    fn convert_received(&mut self) {
+        let codec = self.codec.as_mut().unwrap();
+        while !self.passthrough {
+            let frame = match split_frame(&mut self.received, codec.max_message_size) {
+                Split::Frame(frame) => frame,
+                Split::Incomplete => break,
+                Split::Invalid => {
+                    self.passthrough = true;
+                    if let Some(fragments) = self.fragments.take() {
+                        self.converted.extend(fragments.raw);
+                    }
+                    break;
+                }
+            };
+
+            let header = &frame.header;
+            let message = match (header.opcode, header.rsv1, &mut self.fragments) {
+                (OpCode::Data(Data::Binary | Data::Text), true, None) if header.is_final => {
+                    Some((header.opcode, frame.payload, frame.raw))
+                }
+                (OpCode::Data(Data::Binary | Data::Text), true, None) => {
+                    self.fragments = Some(Fragments {
+                        opcode: header.opcode,
+                        payload: frame.payload,
+                        raw: frame.raw,
+                    });
+                    None
+                }
+                (OpCode::Data(Data::Continue), false, Some(fragments))
+                    if fragments.payload.len() + frame.payload.len() <= codec.max_message_size =>
+                {
+                    fragments.payload.extend(frame.payload);
+                    fragments.raw.extend(frame.raw);
+                    if header.is_final {
+                        let fragments = self.fragments.take().unwrap();
+                        Some((fragments.opcode, fragments.payload, fragments.raw))
+                    } else {
+                        None
+                    }
+                }
+                // Something that tungstenite should complain about, e.g. a new message
+                // in the middle of a fragmented message
+                (OpCode::Data(_), _, Some(fragments)) => {
+                    self.passthrough = true;
+                    self.converted.append(&mut fragments.raw);
+                    self.converted.extend(frame.raw);
+                    None
+                }
+                // Uncompressed messages and control frames, e.g. pings
+                _ => {
+                    self.converted.extend(frame.raw);
+                    None
+                }
+            };
+
+            if let Some((opcode, compressed, raw)) = message {
+                match codec.decompress(compressed) {
+                    Some(data) => {
+                        let header = FrameHeader {
+                            opcode,
+                            // tungstenite requires clients to mask their frames, zeros change nothing
+                            mask: Some([0; 4]),
+                            ..FrameHeader::default()
+                        };
+                        format_frame(header, data, &mut self.converted);
+                    }
+                    None => {
+                        self.passthrough = true;
+                        self.converted.extend(raw);
+                    }
+                }
+            }
+        }
+
+        if self.passthrough {
+            self.converted.append(&mut self.received);
+        }
+    }
+
+    fn convert_written(&mut self) {
+        let codec = self.codec.as_mut().unwrap();
+        // Frames written by tungstenite are valid, and it doesn't fragment messages it sends
+        while let Split::Frame(frame) = split_frame(&mut self.written, usize::MAX) {
+            let compressed = match frame.header.opcode {
+                OpCode::Data(Data::Binary | Data::Text) => codec.compress(&frame.payload),
+                _ => None,
+            };
+            match compressed {
+                Some(compressed) => {
+                    let header = FrameHeader {
+                        rsv1: true,
+                        ..frame.header
+                    };
+                    format_frame(header, compressed, &mut self.unsent);
+                }
+                None => self.unsent.extend(frame.raw),
+            }
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> DeflateStream<S> {
+    fn poll_send_unsent(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.unsent.is_empty() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.unsent))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.unsent.drain(..n);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for DeflateStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if this.codec.is_none() {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        }
+
+        while this.converted.is_empty() {
+            let mut chunk = [0u8; 1024];
+            let mut chunk_buf = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut chunk_buf))?;
+            if chunk_buf.filled().is_empty() {
+                // Connection closed, tungstenite decides what to do with a partial frame
+                this.converted.append(&mut this.received);
+                break;
+            }
+            this.received.extend(chunk_buf.filled());
+            this.convert_received();
+        }
+
+        let n = this.converted.len().min(buf.remaining());
+        buf.put_slice(&this.converted[..n]);
+        this.converted.drain(..n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for DeflateStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        if this.codec.is_none() {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+        if this.unsent.len() > MAX_UNSENT_BYTES {
+            ready!(this.poll_send_unsent(cx))?;
+        }
+        this.written.extend_from_slice(buf);
+        this.convert_written();
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        ready!(this.poll_send_unsent(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        ready!(this.poll_send_unsent(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_accepts_offer() {
+        assert!(accepts_offer("permessage-deflate"));
+        assert!(accepts_offer("permessage-deflate; client_max_window_bits"));
+        assert!(accepts_offer(
+            "x-webkit-deflate-frame, permessage-deflate; client_no_context_takeover"
+        ));
+        assert!(accepts_offer(
+            "permessage-deflate; server_max_window_bits=15"
+        ));
+        assert!(!accepts_offer(
+            "permessage-deflate; server_max_window_bits=10"
+        ));
+        assert!(!accepts_offer("permessage-deflate; foo"));
+        assert!(!accepts_offer("x-webkit-deflate-frame"));
+        assert!(!accepts_offer(""));
+    }
+
+    #[test]
+    fn test_compress_and_decompress() {
+        let mut stream = DeflateStream::new(());
+        stream.enable_compression(1024);
+        let codec = stream.codec.as_mut().unwrap();
+
+        let data = b"\x1b[1;1H\x1b[31m[]\x1b[0m".repeat(20);
+        let compressed = codec.compress(&data).unwrap();
+        assert!(compressed.len() < data.len() / 4);
+        assert_eq!(codec.decompress(compressed).unwrap(), data);
+
+        // Too short to get any smaller
+        assert_eq!(codec.compress(b"a"), None);
+
+        // Decompressing stops at the size limit
+        let huge = codec.compress(&[b'x'; 100_000]).unwrap();
+        assert_eq!(codec.decompress(huge).unwrap().len(), 1025);
+    }
+}
@@ -77,6 +77,14 @@ impl TerminalType {
         }
     }
 
+    // VT52 terminals only display ascii characters
+    pub fn supports_unicode(&self) -> bool {
+        match self {
+            Self::Ansi => true,
+            Self::VT52 => false,
+        }
+    }
+
     pub fn reset_colors(&self) -> &str {
         match self {
             Self::Ansi => "\x1b[0m",
@@ -91,7 +99,15 @@ impl TerminalType {
                 if color.fg != 0 {
                     let _ = write!(result, "\x1b[1;{}m", color.fg);
                 }
-                if color.bg != 0 {
+                // The 16 basic colors don't include dark versions, but the 256-color palette does.
+                // Other backgrounds, e.g. bright gray, are shown as is.
+                let dim_palette_index = match color.bg {
+                    40..=47 if color.dim => Some(DIM_BACKGROUNDS[(color.bg - 40) as usize]),
+                    _ => None,
+                };
+                if let Some(palette_index) = dim_palette_index {
+                    let _ = write!(result, "\x1b[48;5;{}m", palette_index);
+                } else if color.bg != 0 {
                     let _ = write!(result, "\x1b[1;{}m", color.bg);
                 }
                 result
@@ -101,32 +117,47 @@ impl TerminalType {
     }
 }
 
+// 256-color palette indexes for dim black, red, green, yellow, blue, magenta, cyan and white
+const DIM_BACKGROUNDS: [u8; 8] = [16, 52, 22, 58, 17, 53, 23, 59];
+
 #[derive(Clone, Copy, PartialEq, Debug)]
 pub struct Color {
     pub fg: u8,
     pub bg: u8,
+    // Darker version of bg (40-47). Terminals without 256 colors show no background.
+    pub dim: bool,
 }
 impl Color {
-    pub const DEFAULT: Color = Color { fg: 0, bg: 0 };
-    pub const BLACK_ON_WHITE: Color = Color { fg: 30, bg: 47 };
-    pub const GRAY_FOREGROUND: Color = Color { fg: 90, bg: 0 };
-    pub const GRAY_BACKGROUND: Color = Color { fg: 0, bg: 100 };
-
-    pub const RED_FOREGROUND: Color = Color { fg: 31, bg: 0 };
-    pub const GREEN_FOREGROUND: Color = Color { fg: 32, bg: 0 };
-    pub const YELLOW_FOREGROUND: Color = Color { fg: 33, bg: 0 };
-    pub const BLUE_FOREGROUND: Color = Color { fg: 34, bg: 0 };
-    pub const MAGENTA_FOREGROUND: Color = Color { fg: 35, bg: 0 };
-    pub const CYAN_FOREGROUND: Color = Color { fg: 36, bg: 0 };
-    //pub const WHITE_FOREGROUND: Color = Color { fg: 37, bg: 0 };
-
-    pub const RED_BACKGROUND: Color = Color { fg: 0, bg: 41 };
-    pub const GREEN_BACKGROUND: Color = Color { fg: 0, bg: 42 };
-    pub const YELLOW_BACKGROUND: Color = Color { fg: 0, bg: 43 };
-    pub const BLUE_BACKGROUND: Color = Color { fg: 0, bg: 44 };
-    pub const MAGENTA_BACKGROUND: Color = Color { fg: 0, bg: 45 };
-    pub const CYAN_BACKGROUND: Color = Color { fg: 0, bg: 46 };
-    pub const WHITE_BACKGROUND: Color = Color { fg: 0, bg: 47 };
+    pub const fn new(fg: u8, bg: u8) -> Color {
+        Color { fg, bg, dim: false }
+    }
+    pub const fn dimmed(self) -> Color {
+        Color { dim: true, ..self }
+    }
+
+    pub const DEFAULT: Color = Color::new(0, 0);
+    pub const BLACK_ON_WHITE: Color = Color::new(30, 47);
+    pub const GRAY_FOREGROUND: Color = Color::new(90, 0);
+    pub const GRAY_BACKGROUND: Color = Color::new(0, 100);
+
+    pub const RED_FOREGROUND: Color = Color::new(31, 0);
+    pub const GREEN_FOREGROUND: Color = Color::new(32, 0);
+    pub const YELLOW_FOREGROUND: Color = Color::new(33, 0);
+    pub const BLUE_FOREGROUND: Color = Color::new(34, 0);
+    pub const MAGENTA_FOREGROUND: Color = Color::new(35, 0);
+    pub const CYAN_FOREGROUND: Color = Color::new(36, 0);
+    //pub const WHITE_FOREGROUND: Color = Color::new(37, 0);
+
+    pub const RED_BACKGROUND: Color = Color::new(0, 41);
+    pub const GREEN_BACKGROUND: Color = Color::new(0, 42);
+    pub const YELLOW_BACKGROUND: Color = Color::new(0, 43);
+    pub const BLUE_BACKGROUND: Color = Color::new(0, 44);
+    pub const MAGENTA_BACKGROUND: Color = Color::new(0, 45);
+    pub const CYAN_BACKGROUND: Color = Color::new(0, 46);
+    pub const WHITE_BACKGROUND: Color = Color::new(0, 47);
+
+    pub const DIM_RED_BACKGROUND: Color = Color::RED_BACKGROUND.dimmed();
+    pub const DIM_BLACK_ON_WHITE: Color = Color::BLACK_ON_WHITE.dimmed();
 }
 
 #[derive(Debug, PartialEq)]
@@ -135,24 +166,43 @@ pub enum KeyPress {
     Down,
     Right,
     Left,
+    PageUp,
+    PageDown,
+    Home,
+    End,
     BackSpace,
     Enter,
+    Escape, // only when not followed by the rest of an escape sequence
     Quit,
     RefreshRequest,
+    CursorPositionReport, // some terminals send these after resizing
+    FunctionKey(u8),      // F1 to F12, not used for anything
     Character(char),
 }
 
+const MAX_CSI_PARAMS_LEN: usize = 20;
+
+// Public so that the load tester sends exactly what we parse
+pub const ANSI_UP: &[u8] = b"\x1b[A";
+pub const ANSI_DOWN: &[u8] = b"\x1b[B";
+pub const ANSI_RIGHT: &[u8] = b"\x1b[C";
+pub const ANSI_LEFT: &[u8] = b"\x1b[D";
+pub const ENTER: u8 = b'\r';
+
+// Asks an ANSI terminal to send a cursor position report
+pub const DEVICE_STATUS_REPORT: &[u8] = b"\x1b[6n";
+
 const NORMAL_BACKSPACE: u8 = b'\x7f';
 const WINDOWS_BACKSPACE: u8 = b'\x08';
 
 const CTRL_C: u8 = b'\x03';
 const CTRL_D: u8 = b'\x04';
 const CTRL_Q: u8 = b'\x11';
-const CTRL_R: u8 = b'\x12';
+pub const CTRL_R: u8 = b'\x12';
 
 // The usize is how many bytes were consumed.
 pub fn parse_key_press(data: &[u8]) -> Option<(KeyPress, usize)> {
-    if data == b"" || data == b"\x1b" || data == b"\x1b[" {
+    if data == b"" || data == b"\x1b" || data == b"\x1b[" || data == b"\x1bO" {
         // Incomplete data: need to receive more
         return None;
     }
@@ -171,17 +221,85 @@ pub fn parse_key_press(data: &[u8]) -> Option<(KeyPress, usize)> {
     // ANSI arrow keys: 3 bytes each
     if data.len() >= 3 {
         match &data[..3] {
-            b"\x1b[A" => return Some((KeyPress::Up, 3)),
-            b"\x1b[B" => return Some((KeyPress::Down, 3)),
-            b"\x1b[C" => return Some((KeyPress::Right, 3)),
-            b"\x1b[D" => return Some((KeyPress::Left, 3)),
+            ANSI_UP => return Some((KeyPress::Up, 3)),
+            ANSI_DOWN => return Some((KeyPress::Down, 3)),
+            ANSI_RIGHT => return Some((KeyPress::Right, 3)),
+            ANSI_LEFT => return Some((KeyPress::Left, 3)),
+            // Application mode arrow keys, e.g. PuTTY sends these
+            b"\x1bOA" => return Some((KeyPress::Up, 3)),
+            b"\x1bOB" => return Some((KeyPress::Down, 3)),
+            b"\x1bOC" => return Some((KeyPress::Right, 3)),
+            b"\x1bOD" => return Some((KeyPress::Left, 3)),
+            // Home and End, in both normal and application mode
+            b"\x1b[H" | b"\x1bOH" => return Some((KeyPress::Home, 3)),
+            b"\x1b[F" | b"\x1bOF" => return Some((KeyPress::End, 3)),
+            // Numpad in application keypad mode: 8, 2, 6, 4
+            b"\x1bOx" => return Some((KeyPress::Up, 3)),
+            b"\x1bOr" => return Some((KeyPress::Down, 3)),
+            b"\x1bOv" => return Some((KeyPress::Right, 3)),
+            b"\x1bOt" => return Some((KeyPress::Left, 3)),
+            // F1 to F4
+            b"\x1bOP" => return Some((KeyPress::FunctionKey(1), 3)),
+            b"\x1bOQ" => return Some((KeyPress::FunctionKey(2), 3)),
+            b"\x1bOR" => return Some((KeyPress::FunctionKey(3), 3)),
+            b"\x1bOS" => return Some((KeyPress::FunctionKey(4), 3)),
             _ => {}
         }
     }
 
+    // ANSI cursor position report: ESC [ row ; column R
+    if data.starts_with(b"\x1b[") {
+        let rest = &data[2..];
+        let n = rest
+            .iter()
+            .take_while(|b| b.is_ascii_digit() || **b == b';')
+            .count();
+        /*
+        Length limit so that garbage doesn't make us wait forever. Longer
+        sequences are garbage even if they end properly, so that the result
+        doesn't depend on how the bytes happen to arrive.
+        */
+        if n < MAX_CSI_PARAMS_LEN {
+            if n == rest.len() {
+                return None; // need more data
+            }
+            if n > 0 && rest.get(n) == Some(&b'R') && rest[..n].contains(&b';') {
+                return Some((KeyPress::CursorPositionReport, 2 + n + 1));
+            }
+
+            // ESC [ number ~
+            if rest.get(n) == Some(&b'~') {
+                let key = match &rest[..n] {
+                    b"5" => Some(KeyPress::PageUp),
+                    b"6" => Some(KeyPress::PageDown),
+                    // 1 and 4 come from e.g. linux console, 7 and 8 from rxvt
+                    b"1" | b"7" => Some(KeyPress::Home),
+                    b"4" | b"8" => Some(KeyPress::End),
+                    b"11" => Some(KeyPress::FunctionKey(1)),
+                    b"12" => Some(KeyPress::FunctionKey(2)),
+                    b"13" => Some(KeyPress::FunctionKey(3)),
+                    b"14" => Some(KeyPress::FunctionKey(4)),
+                    b"15" => Some(KeyPress::FunctionKey(5)),
+                    // 16 and 22 are skipped for historical reasons
+                    b"17" => Some(KeyPress::FunctionKey(6)),
+                    b"18" => Some(KeyPress::FunctionKey(7)),
+                    b"19" => Some(KeyPress::FunctionKey(8)),
+                    b"20" => Some(KeyPress::FunctionKey(9)),
+                    b"21" => Some(KeyPress::FunctionKey(10)),
+                    b"23" => Some(KeyPress::FunctionKey(11)),
+                    b"24" => Some(KeyPress::FunctionKey(12)),
+                    _ => None,
+                };
+                if let Some(key) = key {
+                    return Some((key, 2 + n + 1));
+                }
+            }
+        }
+    }
+
     // Other special things are 1 byte each
     match data[0] {
-        b'\r' => return Some((KeyPress::Enter, 1)),
+        ENTER => return Some((KeyPress::Enter, 1)),
         NORMAL_BACKSPACE | WINDOWS_BACKSPACE => return Some((KeyPress::BackSpace, 1)),
         CTRL_C | CTRL_D | CTRL_Q => return Some((KeyPress::Quit, 1)),
         CTRL_R => return Some((KeyPress::RefreshRequest, 1)),
@@ -193,7 +311,7 @@ pub fn parse_key_press(data: &[u8]) -> Option<(KeyPress, usize)> {
             let ch = s.chars().next().unwrap();
             Some((KeyPress::Character(ch), ch.len_utf8()))
         }
-        Err(e) if e.valid_up_to() == 0 && e.error_len() == None => {
+        Err(e) if e.valid_up_to() == 0 && e.error_len().is_none() => {
             // unexpected end of input, need more data to get valid utf-8
             None
         }
@@ -260,5 +378,103 @@ mod tests {
             Some((KeyPress::Character('Ö'), 2))
         );
         assert_eq!(parse_key_press(b"\r"), Some((KeyPress::Enter, 1)));
+
+        // cursor position reports
+        assert_eq!(
+            parse_key_press(b"\x1b[24;80Rxx"),
+            Some((KeyPress::CursorPositionReport, 8))
+        );
+        assert_eq!(parse_key_press(b"\x1b[24;8"), None);
+        assert_eq!(
+            parse_key_press(b"\x1b[24x"),
+            Some((KeyPress::Character('\x1b'), 1))
+        );
+        assert_eq!(
+            parse_key_press(b"\x1b[1234567890;1234567890R"),
+            Some((KeyPress::Character('\x1b'), 1))
+        );
+    }
+
+    #[test]
+    fn test_dim_colors() {
+        assert_eq!(
+            TerminalType::Ansi.format_color(Color::DIM_RED_BACKGROUND),
+            "\x1b[0m\x1b[48;5;52m"
+        );
+        // No dark version in the palette, so shown as is
+        assert_eq!(
+            TerminalType::Ansi.format_color(Color::GRAY_BACKGROUND.dimmed()),
+            TerminalType::Ansi.format_color(Color::GRAY_BACKGROUND)
+        );
+        assert_eq!(
+            TerminalType::Ansi.format_color(Color::DEFAULT.dimmed()),
+            TerminalType::Ansi.format_color(Color::DEFAULT)
+        );
+    }
+
+    // Returns None if more data is needed
+    fn parse_in_pieces(data: &[u8]) -> Option<KeyPress> {
+        // Each prefix is what we would have after receiving some of the bytes
+        for end in 1..data.len() {
+            assert_eq!(parse_key_press(&data[..end]), None, "{:?}", &data[..end]);
+        }
+        let (key, len) = parse_key_press(data)?;
+        assert_eq!(len, data.len());
+        Some(key)
+    }
+
+    #[test]
+    fn test_more_special_keys() {
+        assert_eq!(parse_in_pieces(b"\x1bOA"), Some(KeyPress::Up));
+        assert_eq!(parse_in_pieces(b"\x1bOB"), Some(KeyPress::Down));
+        assert_eq!(parse_in_pieces(b"\x1bOC"), Some(KeyPress::Right));
+        assert_eq!(parse_in_pieces(b"\x1bOD"), Some(KeyPress::Left));
+        assert_eq!(parse_in_pieces(b"\x1bOx"), Some(KeyPress::Up));
+        assert_eq!(parse_in_pieces(b"\x1bOr"), Some(KeyPress::Down));
+        assert_eq!(parse_in_pieces(b"\x1bOv"), Some(KeyPress::Right));
+        assert_eq!(parse_in_pieces(b"\x1bOt"), Some(KeyPress::Left));
+        assert_eq!(parse_in_pieces(b"\x1b[5~"), Some(KeyPress::PageUp));
+        assert_eq!(parse_in_pieces(b"\x1b[6~"), Some(KeyPress::PageDown));
+        for bytes in [b"\x1b[H" as &[u8], b"\x1bOH", b"\x1b[1~", b"\x1b[7~"] {
+            assert_eq!(parse_in_pieces(bytes), Some(KeyPress::Home));
+        }
+        for bytes in [b"\x1b[F" as &[u8], b"\x1bOF", b"\x1b[4~", b"\x1b[8~"] {
+            assert_eq!(parse_in_pieces(bytes), Some(KeyPress::End));
+        }
+
+        let f_keys: [&[u8]; 12] = [
+            b"\x1bOP",
+            b"\x1bOQ",
+            b"\x1bOR",
+            b"\x1bOS",
+            b"\x1b[15~",
+            b"\x1b[17~",
+            b"\x1b[18~",
+            b"\x1b[19~",
+            b"\x1b[20~",
+            b"\x1b[21~",
+            b"\x1b[23~",
+            b"\x1b[24~",
+        ];
+        for (n, bytes) in (1..).zip(f_keys) {
+            assert_eq!(parse_in_pieces(bytes), Some(KeyPress::FunctionKey(n)));
+        }
+        assert_eq!(parse_in_pieces(b"\x1b[11~"), Some(KeyPress::FunctionKey(1)));
+
+        assert_eq!(
+            parse_key_press(b"\x1b[6~\x1bOAx"),
+            Some((KeyPress::PageDown, 4))
+        );
+        assert_eq!(parse_key_press(b"\x1bOAx"), Some((KeyPress::Up, 3)));
+
+        // Not special
+        assert_eq!(
+            parse_key_press(b"\x1b[16~"),
+            Some((KeyPress::Character('\x1b'), 1))
+        );
+        assert_eq!(
+            parse_key_press(b"\x1bOz"),
+            Some((KeyPress::Character('\x1b'), 1))
+        );
     }
 }
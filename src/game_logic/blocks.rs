@@ -1,9 +1,9 @@
 use crate::escapes::Color;
 use crate::game_logic::BlockRelativeCoords;
 use crate::game_logic::PlayerPoint;
-use crate::render::RenderBuffer;
 use rand::distributions::Distribution;
 use rand::distributions::WeightedIndex;
+use rand::rngs::StdRng;
 use rand::seq::SliceRandom;
 use rand::Rng;
 
@@ -162,9 +162,24 @@ fn get_drill_text(
     &DRILL_PICTURES[p_index][a_index][y_index][x_index..(x_index + 2)]
 }
 
+// Something that squares can be drawn on, such as a client's RenderBuffer
+pub trait SquareCanvas {
+    fn has_color(&self) -> bool;
+    fn low_bandwidth(&self) -> bool; // animations are skipped to send less data
+    fn set_char_with_color(&mut self, x: usize, y: usize, ch: char, color: Color);
+
+    fn add_text_with_color(&mut self, x: usize, y: usize, text: &str, color: Color) {
+        for (i, ch) in text.chars().enumerate() {
+            self.set_char_with_color(x + i, y, ch, color);
+        }
+    }
+}
+
 #[derive(Copy, Clone, Debug)]
 pub enum SquareContent {
-    Normal([(char, Color); 2]),
+    // The client ID is of the player who landed the square, if it has landed.
+    // The bool is true for reinforced squares, see Game::set_reinforced_squares().
+    Normal([(char, Color); 2], Option<u64>, bool),
     Bomb {
         timer: u8,
         id: Option<u64>,
@@ -175,16 +190,22 @@ pub enum SquareContent {
     LandedDrill {
         texts_by_viewer_direction: [&'static str; 4], // indexed by direction_to_0123()
     },
+    // Clears its rows (or rings) when it lands, whether they are full or not
+    LineBomb,
 }
 impl SquareContent {
     pub fn with_color(color: Color) -> Self {
-        Self::Normal([(' ', color), (' ', color)])
+        Self::Normal([(' ', color), (' ', color)], None, false)
     }
 
     pub fn is_bomb(&self) -> bool {
         matches!(self, Self::Bomb { .. })
     }
 
+    pub fn is_line_bomb(&self) -> bool {
+        matches!(self, Self::LineBomb)
+    }
+
     pub fn is_drill(&self) -> bool {
         matches!(self, Self::FallingDrill { .. } | Self::LandedDrill { .. })
     }
@@ -193,6 +214,18 @@ impl SquareContent {
         self.is_drill() && !other.is_drill()
     }
 
+    // Client ID of the player who landed the square
+    pub fn landed_by(&self) -> Option<u64> {
+        match self {
+            Self::Normal(_, client_id, _) => *client_id,
+            _ => None,
+        }
+    }
+
+    pub fn is_reinforced(&self) -> bool {
+        matches!(self, Self::Normal(_, _, true))
+    }
+
     pub fn animate(&mut self) -> bool {
         match self {
             Self::FallingDrill { animation_counter } => {
@@ -208,8 +241,13 @@ impl SquareContent {
         &self,
         relative_coords: BlockRelativeCoords,
         player_direction: (i8, i8),
+        client_id: u64,
+        reinforced: bool,
     ) -> Self {
         match self {
+            Self::Normal(chars_and_colors, _, _) => {
+                Self::Normal(*chars_and_colors, Some(client_id), reinforced)
+            }
             Self::FallingDrill { animation_counter } => {
                 let mut texts_by_viewer_direction = ["", "", "", ""];
                 for viewer_dir in [(-1, 0), (1, 0), (0, -1), (0, 1)] {
@@ -236,13 +274,14 @@ impl SquareContent {
                     Color::RED_FOREGROUND
                 }
             }
+            Self::LineBomb => Color::RED_FOREGROUND,
             _ => Color::DEFAULT,
         }
     }
 
     pub fn render(
         &self,
-        buffer: &mut RenderBuffer,
+        buffer: &mut impl SquareCanvas,
         x: usize,
         y: usize,
         /*
@@ -254,19 +293,31 @@ impl SquareContent {
         */
         falling_block_data: Option<(BlockRelativeCoords, (i8, i8))>,
         viewer_direction: (i8, i8),
+        // Color of the player who landed the square, shown as a small marker
+        owner_tint: Option<u8>,
     ) {
         match self {
-            Self::Normal(chars_and_colors) => {
-                let (char1, color1) = chars_and_colors[0];
-                let (char2, color2) = chars_and_colors[1];
-                if char1 == ' ' && char2 == ' ' && !buffer.terminal_type.has_color() {
+            Self::Normal(chars_and_colors, _, reinforced) => {
+                let (mut char1, color1) = chars_and_colors[0];
+                let (mut char2, mut color2) = chars_and_colors[1];
+                if *reinforced && char1 == ' ' && buffer.has_color() {
+                    char1 = '+';
+                }
+                if let Some(fg) = owner_tint {
+                    if char2 == ' ' && buffer.has_color() {
+                        char2 = '·';
+                        color2 = Color { fg, ..color2 };
+                    }
+                }
+                if char1 == ' ' && char2 == ' ' && !buffer.has_color() {
                     // Display blocks with "()" instead of colored spaces.
                     //
                     // Blocks cannot be created with different texts, because the same
                     // block can be rendered on different types of terminals that various
                     // players have.
-                    buffer.set_char(x, y, '(');
-                    buffer.set_char(x + 1, y, ')');
+                    let (left, right) = if *reinforced { ('[', ']') } else { ('(', ')') };
+                    buffer.set_char_with_color(x, y, left, Color::DEFAULT);
+                    buffer.set_char_with_color(x + 1, y, right, Color::DEFAULT);
                 } else {
                     buffer.set_char_with_color(x, y, char1, color1);
                     buffer.set_char_with_color(x + 1, y, char2, color2);
@@ -279,8 +330,14 @@ impl SquareContent {
             Self::FallingDrill { animation_counter } => {
                 let (relative_coords, driller_direction) = falling_block_data.unwrap();
                 let direction = choose_drill_direction(viewer_direction, driller_direction);
-                let text = get_drill_text(*animation_counter, direction, relative_coords);
-                buffer.add_text(x, y, text);
+                // Animating would send a lot of data to clients with slow connections
+                let counter = if buffer.low_bandwidth() {
+                    0
+                } else {
+                    *animation_counter
+                };
+                let text = get_drill_text(counter, direction, relative_coords);
+                buffer.add_text_with_color(x, y, text, Color::DEFAULT);
             }
             Self::LandedDrill {
                 texts_by_viewer_direction,
@@ -288,11 +345,14 @@ impl SquareContent {
                 let text = texts_by_viewer_direction[direction_to_0123(viewer_direction)];
                 buffer.add_text_with_color(x, y, text, Color::GRAY_BACKGROUND);
             }
+            Self::LineBomb => {
+                buffer.add_text_with_color(x, y, "==", Color::RED_FOREGROUND);
+            }
         };
     }
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum Shape {
     L,
     I,
@@ -353,38 +413,190 @@ const DRILL_COORDS: &[BlockRelativeCoords] = &[
     (0, 2),
 ];
 
+// For block statistics. Cursed blocks count as cursed, not as their shape.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum BlockKind {
+    Normal(Shape),
+    Cursed,
+    Drill,
+    Bomb,
+    LineBomb,
+}
+
+impl BlockKind {
+    // in the order they are shown to users
+    pub const ALL_KINDS: &'static [BlockKind] = &[
+        BlockKind::Normal(Shape::L),
+        BlockKind::Normal(Shape::I),
+        BlockKind::Normal(Shape::J),
+        BlockKind::Normal(Shape::O),
+        BlockKind::Normal(Shape::T),
+        BlockKind::Normal(Shape::S),
+        BlockKind::Normal(Shape::Z),
+        BlockKind::Cursed,
+        BlockKind::Drill,
+        BlockKind::Bomb,
+        BlockKind::LineBomb,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            BlockKind::Normal(Shape::L) => "L",
+            BlockKind::Normal(Shape::I) => "I",
+            BlockKind::Normal(Shape::J) => "J",
+            BlockKind::Normal(Shape::O) => "O",
+            BlockKind::Normal(Shape::T) => "T",
+            BlockKind::Normal(Shape::Z) => "Z",
+            BlockKind::Normal(Shape::S) => "S",
+            BlockKind::Cursed => "cursed",
+            BlockKind::Drill => "drill",
+            BlockKind::Bomb => "bomb",
+            BlockKind::LineBomb => "linebomb",
+        }
+    }
+}
+
+// Bomb timers count seconds. At higher scores, bombs explode sooner.
+const BOMB_TIMER_AT_ZERO_SCORE: u8 = 15;
+const BOMB_TIMER_MIN: u8 = 5; // reached at score 20000
+const BOMB_TIMER_POINTS_PER_SECOND: usize = 2000;
+// Some bombs have a short fuse regardless of score, to keep things interesting
+const SHORT_BOMB_TIMER: u8 = 3;
+const SHORT_BOMB_PROBABILITY: f64 = 0.2;
+
+pub fn bomb_timer_for_score(score: usize) -> u8 {
+    let decrease = (score / BOMB_TIMER_POINTS_PER_SECOND)
+        .min((BOMB_TIMER_AT_ZERO_SCORE - BOMB_TIMER_MIN) as usize);
+    BOMB_TIMER_AT_ZERO_SCORE - (decrease as u8)
+}
+
+fn choose_bomb_timer(score: usize, rng: &mut impl Rng) -> u8 {
+    if rng.gen_bool(SHORT_BOMB_PROBABILITY) {
+        SHORT_BOMB_TIMER
+    } else {
+        bomb_timer_for_score(score)
+    }
+}
+
+// Lobby setting, because not everyone likes cursed blocks
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum CursedBlocks {
+    Off,
+    #[default]
+    Late, // only after 500 points, surprises new players
+    On, // from the start, but not as common as they get later
+}
+
+impl CursedBlocks {
+    pub fn name(self) -> &'static str {
+        match self {
+            CursedBlocks::Off => "off",
+            CursedBlocks::Late => "late",
+            CursedBlocks::On => "on",
+        }
+    }
+
+    pub fn next(self) -> Self {
+        match self {
+            CursedBlocks::Off => CursedBlocks::Late,
+            CursedBlocks::Late => CursedBlocks::On,
+            CursedBlocks::On => CursedBlocks::Off,
+        }
+    }
+
+    fn weight(self, score_kilos: f32) -> f32 {
+        match self {
+            CursedBlocks::Off => 0.0,
+            // Cursed blocks only appear at score>500 and then become very common.
+            // The intent is to surprise new players.
+            CursedBlocks::Late => (score_kilos - 0.5).max(0.0) / 20.0,
+            CursedBlocks::On => 0.05 + score_kilos / 20.0,
+        }
+    }
+}
+
 #[derive(Copy, Clone)]
 pub enum BlockType {
     Normal,
     Cursed,
     Drill,
-    Bomb,
+    Bomb { initial_timer: u8 },
+    LineBomb,
 }
 
 impl BlockType {
-    pub fn from_score(score: usize) -> Self {
+    pub fn from_score(score: usize, cursed_blocks: CursedBlocks, rng: &mut impl Rng) -> Self {
         let score_kilos = score as f32 / 1000.0;
 
         let items = [
             // Weight x means it's x times as likely as normal block.
             (BlockType::Normal, 1.0),
-            // Cursed blocks only appear at score>500 and then become very common.
-            // The intent is to surprise new players.
-            (BlockType::Cursed, (score_kilos - 0.5).max(0.0) / 20.0),
+            (BlockType::Cursed, cursed_blocks.weight(score_kilos)),
             // Drills are rare, but always possible.
             // They're also very powerful when you happen to get one.
             (BlockType::Drill, score_kilos / 200.0),
             // Bombs are initially just 1% of normal squares.
             // But they get much more common as you get more points.
-            (BlockType::Bomb, score_kilos / 80.0 + 0.01),
+            (
+                BlockType::Bomb {
+                    initial_timer: choose_bomb_timer(score, rng),
+                },
+                score_kilos / 80.0 + 0.01,
+            ),
+            // Line bombs only appear at score>2000, and they stay rare.
+            (BlockType::LineBomb, (score_kilos - 2.0).max(0.0) / 300.0),
         ];
         let distribution = WeightedIndex::new(items.iter().map(|(_, weight)| weight)).unwrap();
-        let index = distribution.sample(&mut rand::thread_rng());
+        let index = distribution.sample(rng);
         let (result, _) = items[index];
         result
     }
 }
 
+/*
+Chooses the normal blocks of one player. A shape that hasn't appeared in a
+while gets more likely, so that waiting for e.g. an I block doesn't last
+forever, but short-term randomness stays.
+
+Special blocks and spawn points use a separate random number generator.
+They depend on how the game goes, so players racing with the same seed
+would otherwise get different blocks after the first special block.
+*/
+#[derive(Debug)]
+pub struct BlockGenerator {
+    pub shape_rng: StdRng, // only for choosing the shapes of normal blocks
+    pub rng: StdRng,       // for other randomness that belongs to the player
+    blocks_since_shape: [usize; ALL_SHAPES.len()],
+}
+
+impl BlockGenerator {
+    pub fn new(shape_rng: StdRng, rng: StdRng) -> Self {
+        Self {
+            shape_rng,
+            rng,
+            blocks_since_shape: [0; ALL_SHAPES.len()],
+        }
+    }
+
+    pub fn next_normal_block(&mut self) -> FallingBlock {
+        let weights = shape_weights(&self.blocks_since_shape);
+        let index = WeightedIndex::new(weights)
+            .unwrap()
+            .sample(&mut self.shape_rng);
+        for (i, count) in self.blocks_since_shape.iter_mut().enumerate() {
+            *count = if i == index { 0 } else { *count + 1 };
+        }
+        FallingBlock::normal_from_shape(ALL_SHAPES[index])
+    }
+}
+
+// Each block without the shape adds 1/12 to its weight, up to 3x
+pub(crate) fn shape_weights(
+    blocks_since_shape: &[usize; ALL_SHAPES.len()],
+) -> [f32; ALL_SHAPES.len()] {
+    blocks_since_shape.map(|count| (1.0 + count as f32 / 12.0).min(3.0))
+}
+
 #[derive(Copy, Clone, Debug)]
 enum RotateMode {
     NoRotating,
@@ -405,7 +617,7 @@ fn shapes_match(a: &[BlockRelativeCoords], b: &[BlockRelativeCoords]) -> bool {
     let vx = b.iter().map(|(x, _)| x).min().unwrap() - a.iter().map(|(x, _)| x).min().unwrap();
     let vy = b.iter().map(|(_, y)| y).min().unwrap() - a.iter().map(|(_, y)| y).min().unwrap();
     let shifted_a: Vec<BlockRelativeCoords> = a.iter().map(|(ax, ay)| (ax + vx, ay + vy)).collect();
-    return b.iter().all(|p| shifted_a.contains(p));
+    b.iter().all(|p| shifted_a.contains(p))
 }
 
 fn choose_initial_rotate_mode(
@@ -431,12 +643,10 @@ fn choose_initial_rotate_mode(
     RotateMode::FullRotating
 }
 
-fn add_extra_square(coords: &mut Vec<BlockRelativeCoords>) {
+fn add_extra_square(coords: &mut Vec<BlockRelativeCoords>, rng: &mut impl Rng) {
     loop {
-        let existing = coords.choose(&mut rand::thread_rng()).unwrap();
-        let diff: BlockRelativeCoords = *[(-1, 0), (1, 0), (0, -1), (0, 1)]
-            .choose(&mut rand::thread_rng())
-            .unwrap();
+        let existing = coords.choose(rng).unwrap();
+        let diff: BlockRelativeCoords = *[(-1, 0), (1, 0), (0, -1), (0, 1)].choose(rng).unwrap();
         let (ex, ey) = existing;
         let (dx, dy) = diff;
         let shifted_point = (ex + dx, ey + dy);
@@ -447,6 +657,23 @@ fn add_extra_square(coords: &mut Vec<BlockRelativeCoords>) {
     }
 }
 
+/*
+In big mode, each square of a normal block becomes 2x2 squares. The square at
+(x,y) becomes (2x,2y), (2x+1,2y), (2x,2y+1) and (2x+1,2y+1), so the old
+rotation center (0,0) is now the corner point between the four squares of
+(0,0). Rotating around that corner keeps the 2x2 groups aligned, see
+get_rotated_relative_coords().
+*/
+fn scale_to_big(coords: &[BlockRelativeCoords]) -> Vec<BlockRelativeCoords> {
+    coords
+        .iter()
+        .flat_map(|(x, y)| {
+            let (x, y) = (2 * x, 2 * y);
+            [(x, y), (x + 1, y), (x, y + 1), (x + 1, y + 1)]
+        })
+        .collect()
+}
+
 fn fix_rotation_center(coords: &mut [BlockRelativeCoords]) {
     let x_sum: i8 = coords.iter().map(|(x, _)| *x).sum();
     let y_sum: i8 = coords.iter().map(|(_, y)| *y).sum();
@@ -460,49 +687,58 @@ fn fix_rotation_center(coords: &mut [BlockRelativeCoords]) {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct FallingBlock {
     pub square_content: SquareContent,
     pub has_been_in_hold: bool,
+    pub kind: BlockKind,
     pub center: PlayerPoint,
     relative_coords: Vec<BlockRelativeCoords>,
     rotate_mode: RotateMode,
+    big: bool,
+    pub idle_ticks: usize, // ticks since spawning, counted only with Game::set_stall_decay()
 }
 impl FallingBlock {
-    pub fn new(block_type: BlockType) -> FallingBlock {
+    pub fn new(block_type: BlockType, rng: &mut impl Rng) -> FallingBlock {
         let content;
         let mut coords;
+        let kind;
 
         match block_type {
             BlockType::Normal => {
-                let shape = ALL_SHAPES.choose(&mut rand::thread_rng()).unwrap();
+                let shape = ALL_SHAPES.choose(rng).unwrap();
                 content = SquareContent::with_color(shape.color());
                 coords = shape.coords().to_vec();
+                kind = BlockKind::Normal(*shape);
             }
             BlockType::Cursed => {
-                let shape = ALL_SHAPES.choose(&mut rand::thread_rng()).unwrap();
+                let shape = ALL_SHAPES.choose(rng).unwrap();
                 content = SquareContent::with_color(shape.color());
                 coords = shape.coords().to_vec();
-                add_extra_square(&mut coords);
+                add_extra_square(&mut coords, rng);
                 fix_rotation_center(&mut coords);
+                kind = BlockKind::Cursed;
             }
             BlockType::Drill => {
                 content = SquareContent::FallingDrill {
                     animation_counter: 0,
                 };
                 coords = DRILL_COORDS.to_vec();
+                kind = BlockKind::Drill;
             }
-            BlockType::Bomb => {
-                let initial_timer_value = if rand::thread_rng().gen_range(0..5) == 0 {
-                    3
-                } else {
-                    15
-                };
+            BlockType::Bomb { initial_timer } => {
+                // The timer starts ticking when the block spawns, not in the next block preview
                 content = SquareContent::Bomb {
-                    timer: initial_timer_value,
+                    timer: initial_timer,
                     id: None,
                 };
                 coords = Shape::O.coords().to_vec();
+                kind = BlockKind::Bomb;
+            }
+            BlockType::LineBomb => {
+                content = SquareContent::LineBomb;
+                coords = Shape::I.coords().to_vec();
+                kind = BlockKind::LineBomb;
             }
         }
 
@@ -512,10 +748,12 @@ impl FallingBlock {
             rotate_mode: choose_initial_rotate_mode(&coords, &content),
             relative_coords: coords,
             has_been_in_hold: false,
+            kind,
+            big: false,
+            idle_ticks: 0,
         }
     }
 
-    #[cfg(test)]
     pub fn normal_from_shape(shape: Shape) -> FallingBlock {
         let content = SquareContent::with_color(shape.color());
         let coords = shape.coords().to_vec();
@@ -525,7 +763,33 @@ impl FallingBlock {
             rotate_mode: choose_initial_rotate_mode(&coords, &content),
             relative_coords: coords,
             has_been_in_hold: false,
+            kind: BlockKind::Normal(shape),
+            big: false,
+            idle_ticks: 0,
+        }
+    }
+
+    // Must be called before the block spawns, only makes sense for normal blocks
+    pub fn make_big(&mut self) {
+        assert!(!self.big);
+        assert!(matches!(self.kind, BlockKind::Normal(_)));
+        self.relative_coords = scale_to_big(&self.relative_coords);
+        self.big = true;
+    }
+
+    // Big blocks are shown in normal size in the next block and hold previews
+    pub fn get_preview_coords(&self) -> Vec<BlockRelativeCoords> {
+        if !self.big {
+            return self.relative_coords.clone();
         }
+        let mut result: Vec<BlockRelativeCoords> = vec![];
+        for (x, y) in &self.relative_coords {
+            let point = (x.div_euclid(2), y.div_euclid(2));
+            if !result.contains(&point) {
+                result.push(point);
+            }
+        }
+        result
     }
 
     pub fn spawn_at(&mut self, spawn_point: PlayerPoint) {
@@ -534,6 +798,7 @@ impl FallingBlock {
         let lowest_relative_y = *self.relative_coords.iter().map(|(_, y)| y).max().unwrap();
         let bottom_edge = (lowest_relative_y as i32) + 1;
         self.center = (spawn_x, spawn_y - bottom_edge);
+        self.idle_ticks = 0;
 
         // spawned bombs get a new tick counter
         if let SquareContent::Bomb { id, .. } = &mut self.square_content {
@@ -584,10 +849,18 @@ impl FallingBlock {
             RotateMode::NextCounterClockwiseThenBack => true,
             RotateMode::FullRotating => prefer_counter_clockwise,
         };
+        // Big blocks rotate around the point between (0,0), (1,0), (0,1) and (1,1)
+        let shift = if self.big { 1 } else { 0 };
         if counter_clockwise {
-            self.relative_coords.iter().map(|(x, y)| (*y, -x)).collect()
+            self.relative_coords
+                .iter()
+                .map(|(x, y)| (*y, shift - x))
+                .collect()
         } else {
-            self.relative_coords.iter().map(|(x, y)| (-y, *x)).collect()
+            self.relative_coords
+                .iter()
+                .map(|(x, y)| (shift - y, *x))
+                .collect()
         }
     }
 
@@ -618,14 +891,97 @@ impl FallingBlock {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use rand::SeedableRng;
     use std::collections::HashSet;
 
+    #[test]
+    fn test_bomb_timer_for_score() {
+        assert_eq!(bomb_timer_for_score(0), 15);
+        assert_eq!(bomb_timer_for_score(1999), 15);
+        assert_eq!(bomb_timer_for_score(2000), 14);
+        assert_eq!(bomb_timer_for_score(19999), 6);
+        assert_eq!(bomb_timer_for_score(20000), BOMB_TIMER_MIN);
+        assert_eq!(bomb_timer_for_score(usize::MAX), BOMB_TIMER_MIN);
+    }
+
+    fn count_cursed_blocks(score: usize, cursed_blocks: CursedBlocks) -> usize {
+        let mut rng = StdRng::seed_from_u64(0);
+        (0..5000)
+            .filter(|_| {
+                matches!(
+                    BlockType::from_score(score, cursed_blocks, &mut rng),
+                    BlockType::Cursed
+                )
+            })
+            .count()
+    }
+
+    #[test]
+    fn test_cursed_blocks_setting() {
+        assert_eq!(count_cursed_blocks(0, CursedBlocks::Off), 0);
+        assert_eq!(count_cursed_blocks(10_000, CursedBlocks::Off), 0);
+        assert_eq!(count_cursed_blocks(0, CursedBlocks::Late), 0);
+        assert!(count_cursed_blocks(10_000, CursedBlocks::Late) > 0);
+        assert!(count_cursed_blocks(0, CursedBlocks::On) > 0);
+    }
+
+    fn coord_set(block: &FallingBlock) -> HashSet<BlockRelativeCoords> {
+        block.get_relative_coords().iter().copied().collect()
+    }
+
+    #[test]
+    fn test_big_block_coords() {
+        let mut block = FallingBlock::normal_from_shape(Shape::O);
+        block.make_big();
+        let expected: HashSet<BlockRelativeCoords> =
+            (-2..2).flat_map(|x| (-2..2).map(move |y| (x, y))).collect();
+        assert_eq!(coord_set(&block), expected);
+
+        let mut block = FallingBlock::normal_from_shape(Shape::I);
+        block.make_big();
+        let expected: HashSet<BlockRelativeCoords> =
+            (-4..4).flat_map(|x| [(x, 0), (x, 1)]).collect();
+        assert_eq!(coord_set(&block), expected);
+
+        // Previews show the block in normal size
+        for shape in ALL_SHAPES {
+            let mut block = FallingBlock::normal_from_shape(*shape);
+            block.make_big();
+            assert_eq!(block.get_relative_coords().len(), 4 * shape.coords().len());
+            assert_eq!(block.get_preview_coords(), shape.coords());
+        }
+    }
+
+    #[test]
+    fn test_big_block_rotation_stays_on_lattice() {
+        for shape in ALL_SHAPES {
+            for counter_clockwise in [false, true] {
+                let mut normal = FallingBlock::normal_from_shape(*shape);
+                let mut big = FallingBlock::normal_from_shape(*shape);
+                big.make_big();
+                let original = coord_set(&big);
+
+                for _ in 0..4 {
+                    normal.rotate(counter_clockwise);
+                    big.rotate(counter_clockwise);
+                    // Same as scaling the rotated normal block, so the 2x2 groups stay aligned
+                    let expected: HashSet<BlockRelativeCoords> =
+                        scale_to_big(normal.get_relative_coords())
+                            .into_iter()
+                            .collect();
+                    assert_eq!(coord_set(&big), expected);
+                }
+                assert_eq!(coord_set(&big), original);
+            }
+        }
+    }
+
     #[test]
     fn test_rotation_center_of_cursed_blocks() {
         for _ in 0..50 {
             // Random-generate a long cursed I-block. Repeat a few times, in case only some of them are good.
             let block = loop {
-                let block = FallingBlock::new(BlockType::Cursed);
+                let block = FallingBlock::new(BlockType::Cursed, &mut rand::thread_rng());
                 if block.get_relative_coords().iter().all(|(_, y)| *y == 0) {
                     break block;
                 }
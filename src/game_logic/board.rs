@@ -0,0 +1,317 @@
+use crate::game_logic::blocks::FallingBlock;
+use crate::game_logic::blocks::SquareContent;
+use crate::game_logic::bottle::BottleNecks;
+use crate::game_logic::player::BlockOrTimer;
+use crate::game_logic::player::Player;
+use crate::game_logic::PlayerPoint;
+use crate::game_logic::WorldPoint;
+use rand::rngs::StdRng;
+use std::cell::RefCell;
+
+pub type LandedRows = Vec<Vec<Option<SquareContent>>>;
+
+// Result of looking for full rows, or full rings in ring mode
+#[derive(Default)]
+pub struct FullGroups {
+    pub points: Vec<WorldPoint>,
+    pub single_player_count: usize, // filled by one player, e.g. rows in a bottle neck
+    pub everyone_count: usize,      // filled by all players together
+}
+
+// Lobby settings that change the board, see Game::set_board_options()
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BoardOptions {
+    pub two_bottle_basins: bool, // with 4 or more players, see bottle::get_basin_split()
+    pub bottle_necks: BottleNecks,
+    pub big_blocks: bool, // normal blocks scaled 2x, and no special blocks
+}
+
+/*
+How player coordinates map to world coordinates, see Player::player_to_world().
+The player's coordinates are rotated around the center. If lap_radius is set,
+blocks can fall all the way around the board, and the y coordinates wrap around.
+*/
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PlayerGeometry {
+    pub center: WorldPoint,
+    pub lap_radius: Option<i32>,
+}
+
+impl PlayerGeometry {
+    pub fn wrap_around(&self, y: &mut i32) {
+        if let Some(r) = self.lap_radius {
+            if *y > 0 {
+                *y += r;
+                *y %= 2 * r + 1;
+                *y -= r;
+            }
+        }
+    }
+}
+
+// What Game needs to do after the board made room for a new player
+#[derive(Default)]
+pub struct AddPlayerEffects {
+    pub need_new_block: Vec<usize>, // indexes of players whose block got removed
+    pub flash_points: Vec<WorldPoint>, // where landed squares were deleted
+}
+
+/*
+Everything that depends on the shape of the board: its size, where blocks
+spawn, what counts as a full row and what happens when it's cleared.
+Game delegates to this, so adding a new board shape means implementing this
+trait in a new file and adding a variant to Mode.
+
+The landed squares and players belong to the Game, and they are passed in
+as arguments. Data that only one mode needs, such as the wall between the
+two basins of a bottle game, goes to the struct implementing this trait.
+*/
+pub(crate) trait BoardBehavior: Send {
+    // Rows of the board before anyone joins
+    fn initial_rows(&self) -> LandedRows;
+
+    // Can't always return rows[0].len(), because this is called during resizing
+    fn width(&self, player_count: usize, rows: &LandedRows) -> usize;
+
+    // Only for boards where each player has their own columns
+    fn width_per_player(&self, _player_count: usize) -> Option<usize> {
+        None
+    }
+
+    // Is the point in the area that the player's blocks fall through? Uses player coordinates.
+    fn is_in_player_region(
+        &self,
+        point: PlayerPoint,
+        player_idx: usize,
+        player_count: usize,
+    ) -> bool {
+        let w = self.width_per_player(player_count).unwrap() as i32;
+        point.0.div_euclid(w) == player_idx as i32
+    }
+
+    // For the ui, returns (x_min, x_max+1, y_min, y_max+1)
+    fn bounds(&self, width: usize, height: usize) -> (i32, i32, i32, i32) {
+        (0, width as i32, 0, height as i32)
+    }
+
+    // Falling blocks can be partially above this y coordinate, where they are not yet visible
+    fn top_y(&self) -> i32 {
+        0
+    }
+
+    fn is_valid_landed_coords(&self, point: WorldPoint, width: usize, height: usize) -> bool;
+
+    fn player_geometry(&self) -> PlayerGeometry {
+        PlayerGeometry::default()
+    }
+
+    // Called after a falling block moves, e.g. to keep its coordinates small
+    fn wrap_falling_block(&self, _block: &mut FallingBlock) {}
+
+    // Boards where players fall in different directions return None when all are used
+    fn down_direction_for_new_player(&self, _used: &[WorldPoint]) -> Option<WorldPoint> {
+        Some((0, 1))
+    }
+
+    // Called when the number of players changes
+    fn spawn_point_for(&self, player_idx: usize, player_count: usize) -> PlayerPoint;
+
+    // Where a block spawns, if not always at the player's spawn point
+    fn spawn_point_for_block(
+        &self,
+        _player_idx: usize,
+        spawn_point: PlayerPoint,
+        _block: &FallingBlock,
+        _rng: &mut StdRng,
+    ) -> PlayerPoint {
+        spawn_point
+    }
+
+    // Makes room for a player that was just inserted to the players list
+    fn add_player(
+        &mut self,
+        rows: &mut LandedRows,
+        players: &[RefCell<Player>],
+        player_idx: usize,
+        handicap: u8,
+        rng: &mut StdRng,
+    ) -> AddPlayerEffects;
+
+    // Called after the player was removed from the players list.
+    // Returns indexes of players that need a new block, because theirs was removed.
+    fn remove_player(
+        &mut self,
+        rows: &mut LandedRows,
+        players: &[RefCell<Player>],
+        player_idx: usize,
+    ) -> Vec<usize>;
+
+    // When a player's playing area fills up, it is cleared after a waiting time
+    fn clear_region_for_player(
+        &self,
+        rows: &mut LandedRows,
+        players: &[RefCell<Player>],
+        player_idx: usize,
+    );
+
+    fn find_full_groups(&self, rows: &LandedRows, player_count: usize) -> FullGroups;
+
+    // Deletes full squares and moves other squares where they were
+    fn shift_after_clear(&self, rows: &mut LandedRows, full: &[WorldPoint], player_count: usize);
+
+    // Called when a player fills their playing area. Returns false if the player
+    // has to wait instead, which is what happens in most modes.
+    fn clear_filled_area(&self, _rows: &mut LandedRows) -> bool {
+        false
+    }
+
+    // Can a player whose stack is much higher than others clear it? See Game::offer_rescues_as_needed()
+    fn offers_rescues(&self) -> bool {
+        false
+    }
+
+    // Can blocks stay in the air as long as the player wants? See Game::set_stall_decay()
+    fn blocks_can_stall(&self) -> bool {
+        false
+    }
+
+    // Can a single player turn the board upside down?
+    fn can_flip_view(&self) -> bool {
+        false
+    }
+
+    // Should the game remember whose squares were cleared? See Game::get_last_ring_clear()
+    fn tracks_cleared_squares(&self) -> bool {
+        false
+    }
+}
+
+// Makes room for a player between other players, in modes where each player has columns
+pub(crate) fn insert_columns(
+    rows: &mut LandedRows,
+    players: &[RefCell<Player>],
+    left: usize,
+    width: usize,
+) {
+    for row in rows.iter_mut() {
+        row.splice(left..left, vec![None; width]);
+    }
+
+    // Blocks move as a whole, so that they keep their shape
+    let left = left as i32;
+    let width = width as i32;
+    for player in players {
+        if let BlockOrTimer::Block(block) = &mut player.borrow_mut().block_or_timer {
+            let (center_x, center_y) = block.center;
+            if center_x >= left {
+                let new_points: Vec<PlayerPoint> = block
+                    .get_coords()
+                    .iter()
+                    .map(|(x, y)| (x + width, *y))
+                    .collect();
+                block.set_player_coords(&new_points, (center_x + width, center_y));
+            }
+        }
+    }
+}
+
+// Opposite of insert_columns(), returns indexes of players whose block got removed entirely
+pub(crate) fn remove_columns(
+    rows: &mut LandedRows,
+    players: &[RefCell<Player>],
+    left: usize,
+    width: usize,
+) -> Vec<usize> {
+    // In these modes, player points and world points are the same.
+    // So it doesn't matter whether "left" is in world or player points.
+    let right = left + width;
+    for row in rows.iter_mut() {
+        row.splice(left..right, vec![]);
+    }
+
+    let left = left as i32;
+    let width = width as i32;
+    let right = right as i32;
+
+    let mut need_new_block = vec![];
+
+    for (player_idx, player) in players.iter().enumerate() {
+        if let BlockOrTimer::Block(block) = &mut player.borrow_mut().block_or_timer {
+            let old_points = block.get_coords();
+            let mut new_points = vec![];
+            for (x, y) in old_points {
+                // Remove points in (left..right), move points on right side
+                if (..left).contains(&x) {
+                    new_points.push((x, y));
+                } else if (right..).contains(&x) {
+                    new_points.push((x - width, y));
+                }
+            }
+
+            // Move center just like other points, except that it can't be removed
+            let (mut center_x, center_y) = block.center;
+            if (right..).contains(&center_x) {
+                center_x -= width;
+            } else if (left..right).contains(&center_x) {
+                center_x = left;
+            }
+
+            if new_points.is_empty() {
+                // Cannot call Game::new_block() here because player is borrowed
+                need_new_block.push(player_idx);
+            } else {
+                block.set_player_coords(&new_points, (center_x, center_y));
+            }
+        }
+    }
+
+    need_new_block
+}
+
+pub(crate) fn landed_rows_contain(rows: &LandedRows, point: WorldPoint) -> bool {
+    let (x, y) = point;
+    (0..rows.len() as i16).contains(&y) && (0..rows[y as usize].len() as i16).contains(&x)
+}
+
+// Returns None if the point is outside landed_rows, Some(None) if it's an empty square
+pub(crate) fn try_get_square(
+    rows: &LandedRows,
+    point: WorldPoint,
+) -> Option<Option<SquareContent>> {
+    let (x, y) = point;
+    landed_rows_contain(rows, point).then(|| rows[y as usize][x as usize])
+}
+
+// Returns false and does nothing if the point is outside landed_rows
+pub(crate) fn try_set_square(
+    rows: &mut LandedRows,
+    point: WorldPoint,
+    value: Option<SquareContent>,
+) -> bool {
+    let (x, y) = point;
+    if landed_rows_contain(rows, point) {
+        rows[y as usize][x as usize] = value;
+        true
+    } else {
+        false
+    }
+}
+
+pub(crate) fn clear_columns(rows: &mut LandedRows, left: usize, right: usize) {
+    for row in rows.iter_mut() {
+        for square_ref in row[left..right].iter_mut() {
+            *square_ref = None;
+        }
+    }
+}
+
+// Deletes the given row between left and right, and moves everything above it down
+pub(crate) fn delete_row_part(rows: &mut LandedRows, y: usize, left: usize, right: usize) {
+    for source_y in (0..y).rev() {
+        let source_row = rows[source_y][left..right].to_vec();
+        rows[source_y + 1].splice(left..right, source_row);
+    }
+    for cell in &mut rows[0][left..right] {
+        *cell = None;
+    }
+}
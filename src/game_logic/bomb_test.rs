@@ -0,0 +1,180 @@
+// Bomb chain reactions, driven through the same calls that game_wrapper.rs makes
+use crate::game_logic::blocks::BlockGenerator;
+use crate::game_logic::blocks::BlockType;
+use crate::game_logic::blocks::FallingBlock;
+use crate::game_logic::blocks::Shape;
+use crate::game_logic::blocks::SquareContent;
+use crate::game_logic::game::Game;
+use crate::game_logic::game::Mode;
+use crate::game_logic::player::BlockOrTimer;
+use crate::game_logic::test::create_game_with;
+use crate::game_logic::PlayerPoint;
+use crate::game_logic::WorldPoint;
+
+const FALLING_BOMB_TIMER: u8 = 3;
+
+fn create_game(mode: Mode, player_count: usize, bombs: bool) -> Game {
+    create_game_with(mode, player_count, |game| {
+        game.set_block_seed(123, false);
+        game.set_special_blocks_enabled(false);
+        game.set_normal_block_factory(if bombs {
+            |generator: &mut BlockGenerator| {
+                let initial_timer = FALLING_BOMB_TIMER;
+                FallingBlock::new(BlockType::Bomb { initial_timer }, &mut generator.rng)
+            }
+        } else {
+            |_: &mut BlockGenerator| FallingBlock::normal_from_shape(Shape::L)
+        });
+    })
+}
+
+// Use ids that start_ticking_new_bombs() doesn't give out, e.g. 100 and more
+fn place_landed_bomb(game: &mut Game, point: PlayerPoint, id: u64, timer: u8) -> WorldPoint {
+    let world_point = game.players[0].borrow().player_to_world(point);
+    assert!(game.is_valid_landed_block_coords(world_point));
+    let content = SquareContent::Bomb {
+        timer,
+        id: Some(id),
+    };
+    game.set_landed_square(world_point, Some(content));
+    world_point
+}
+
+fn landed_bomb_timer(game: &Game, point: WorldPoint) -> Option<u8> {
+    match game.get_landed_square(point) {
+        Some(SquareContent::Bomb { timer, .. }) => Some(timer),
+        _ => None,
+    }
+}
+
+fn falling_bomb_timer(game: &Game, player_idx: usize) -> Option<u8> {
+    match &game.players[player_idx].borrow().block_or_timer {
+        BlockOrTimer::Block(block) => match block.square_content {
+            SquareContent::Bomb { timer, .. } => Some(timer),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// Flashes and explodes until nothing new explodes, like tick_bombs() in game_wrapper.rs.
+// Returns the explosion centers of each step.
+fn run_explosions(game: &mut Game, mut explosion_centers: Vec<WorldPoint>) -> Vec<Vec<WorldPoint>> {
+    let mut steps = vec![];
+    while !explosion_centers.is_empty() {
+        let flashing = game.get_points_to_flash(&explosion_centers);
+        explosion_centers.sort();
+        steps.push(explosion_centers.clone());
+        explosion_centers = game.finish_explosion(&explosion_centers, &flashing);
+        assert!(steps.len() < 10, "explosions don't stop");
+    }
+    steps
+}
+
+fn check_chain_of_three(mode: Mode, chain: [PlayerPoint; 3], far_away: PlayerPoint) {
+    let mut game = create_game(mode, 1, false);
+    let a = place_landed_bomb(&mut game, chain[0], 100, 1);
+    let b = place_landed_bomb(&mut game, chain[1], 101, 10);
+    let c = place_landed_bomb(&mut game, chain[2], 102, 10);
+    let far = place_landed_bomb(&mut game, far_away, 103, 10);
+
+    // Other bombs don't tick when bomb 100 ticks
+    let explosion_centers = game.tick_bombs_by_id(100).unwrap();
+    assert_eq!(explosion_centers, [a]);
+    assert_eq!(landed_bomb_timer(&game, b), Some(10));
+
+    assert_eq!(
+        run_explosions(&mut game, explosion_centers),
+        [vec![a], vec![b], vec![c]]
+    );
+    for point in [a, b, c] {
+        assert!(game.get_landed_square(point).is_none());
+    }
+    assert_eq!(landed_bomb_timer(&game, far), Some(10));
+
+    // The tasks that tick the exploded bombs will notice that they are gone
+    assert_eq!(game.tick_bombs_by_id(101), None);
+    assert_eq!(game.tick_bombs_by_id(102), None);
+    assert_eq!(game.tick_bombs_by_id(103), Some(vec![]));
+    assert_eq!(landed_bomb_timer(&game, far), Some(9));
+}
+
+#[test]
+fn test_chain_of_three_traditional() {
+    check_chain_of_three(Mode::Traditional, [(2, 8), (2, 11), (2, 14)], (8, 14));
+}
+
+#[test]
+fn test_chain_of_three_ring() {
+    check_chain_of_three(Mode::Ring, [(-6, -8), (-3, -8), (0, -8)], (8, -8));
+}
+
+#[test]
+fn test_falling_bomb_ticks_once_per_tick() {
+    for mode in [Mode::Traditional, Mode::Ring] {
+        let mut game = create_game(mode, 2, true);
+        let bomb_ids = game.start_ticking_new_bombs();
+        assert_eq!(bomb_ids, [0, 1]);
+        // Already ticking bombs don't get new ids
+        assert_eq!(game.start_ticking_new_bombs(), Vec::<u64>::new());
+
+        for expected_timer in (1..FALLING_BOMB_TIMER).rev() {
+            assert_eq!(game.tick_bombs_by_id(bomb_ids[0]), Some(vec![]));
+            assert_eq!(falling_bomb_timer(&game, 0), Some(expected_timer));
+            assert_eq!(falling_bomb_timer(&game, 1), Some(FALLING_BOMB_TIMER));
+        }
+
+        // All 4 squares explode
+        let mut explosion_centers = game.tick_bombs_by_id(bomb_ids[0]).unwrap();
+        explosion_centers.sort();
+        let mut expected: Vec<WorldPoint> = {
+            let player = game.players[0].borrow();
+            player
+                .block_or_timer
+                .get_coords()
+                .iter()
+                .map(|p| player.player_to_world(*p))
+                .collect()
+        };
+        expected.sort();
+        assert_eq!(explosion_centers, expected);
+        assert_eq!(falling_bomb_timer(&game, 0), Some(0));
+        assert_eq!(falling_bomb_timer(&game, 1), Some(FALLING_BOMB_TIMER));
+    }
+}
+
+#[test]
+fn test_falling_bomb_of_another_player_joins_the_chain() {
+    let mut game = create_game(Mode::Traditional, 2, true);
+    let bomb_ids = game.start_ticking_new_bombs();
+    // Explosions don't reach above the top of the board
+    for _ in 0..4 {
+        game.move_blocks_down(false);
+    }
+
+    // Player 1's falling bomb is right next to a landed bomb that explodes
+    let falling_points: Vec<WorldPoint> = {
+        let player = game.players[1].borrow();
+        player
+            .block_or_timer
+            .get_coords()
+            .iter()
+            .map(|p| player.player_to_world(*p))
+            .collect()
+    };
+    let (x, y) = *falling_points.iter().max().unwrap();
+    let landed = place_landed_bomb(&mut game, (x as i32, y as i32 + 1), 100, 1);
+
+    let explosion_centers = game.tick_bombs_by_id(100).unwrap();
+    let steps = run_explosions(&mut game, explosion_centers);
+    assert_eq!(steps.len(), 2);
+    assert_eq!(steps[0], [landed]);
+    let mut expected = falling_points.clone();
+    expected.sort();
+    assert_eq!(steps[1], expected);
+
+    // The exploded bomb was replaced with a new block, and player 0's bomb is untouched
+    assert_ne!(falling_bomb_timer(&game, 1), Some(0));
+    assert_eq!(game.tick_bombs_by_id(bomb_ids[1]), None);
+    assert_eq!(falling_bomb_timer(&game, 0), Some(FALLING_BOMB_TIMER));
+}
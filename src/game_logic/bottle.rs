@@ -0,0 +1,379 @@
+use crate::escapes::Color;
+use crate::game_logic::blocks::FallingBlock;
+use crate::game_logic::blocks::SquareContent;
+use crate::game_logic::board::clear_columns;
+use crate::game_logic::board::delete_row_part;
+use crate::game_logic::board::insert_columns;
+use crate::game_logic::board::remove_columns;
+use crate::game_logic::board::AddPlayerEffects;
+use crate::game_logic::board::BoardBehavior;
+use crate::game_logic::board::BoardOptions;
+use crate::game_logic::board::FullGroups;
+use crate::game_logic::board::LandedRows;
+use crate::game_logic::player::Player;
+use crate::game_logic::PlayerPoint;
+use crate::game_logic::WorldPoint;
+use rand::rngs::StdRng;
+use rand::Rng;
+use std::cell::RefCell;
+use std::cmp::max;
+
+pub const BOTTLE_MAP: &[&str] = &[
+    r"    |xxxxxxxxxx|    ",
+    r"    |xxxxxxxxxx|    ",
+    r"    |xxxxxxxxxx|    ",
+    r"    |xxxxxxxxxx|    ",
+    r"    /xxxxxxxxxx\    ",
+    r"   /.xxxxxxxxxx.\   ",
+    r"  /xxxxxxxxxxxxxx\  ",
+    r" /.xxxxxxxxxxxxxx.\ ",
+    r"/xxxxxxxxxxxxxxxxxx\",
+    r"|xxxxxxxxxxxxxxxxxx|",
+    r"|xxxxxxxxxxxxxxxxxx|",
+    r"|xxxxxxxxxxxxxxxxxx|",
+    r"|xxxxxxxxxxxxxxxxxx|",
+    r"|xxxxxxxxxxxxxxxxxx|",
+    r"|xxxxxxxxxxxxxxxxxx|",
+    r"|xxxxxxxxxxxxxxxxxx|",
+    r"|xxxxxxxxxxxxxxxxxx|",
+    r"|xxxxxxxxxxxxxxxxxx|",
+    r"|xxxxxxxxxxxxxxxxxx|",
+    r"|xxxxxxxxxxxxxxxxxx|",
+    r"|xxxxxxxxxxxxxxxxxx|",
+];
+const BOTTLE_INNER_WIDTH: usize = 9;
+const BOTTLE_OUTER_WIDTH: usize = 10;
+
+fn is_inside_bottle_map(x: usize, y: usize) -> bool {
+    BOTTLE_MAP[y].as_bytes()[2 * (x % BOTTLE_OUTER_WIDTH) + 1] == b'x'
+}
+
+// Lobby setting, because the best split depends on how many people play
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum BottleNecks {
+    #[default]
+    Classic, // personal areas end where the "|" walls begin
+    Deep,    // less shared space, for games with many players
+    Shallow, // the widest row of the neck is shared, for games with few players
+}
+
+impl BottleNecks {
+    pub fn name(self) -> &'static str {
+        match self {
+            BottleNecks::Classic => "classic",
+            BottleNecks::Deep => "deep necks",
+            BottleNecks::Shallow => "shallow necks",
+        }
+    }
+
+    pub fn next(self) -> Self {
+        match self {
+            BottleNecks::Classic => BottleNecks::Deep,
+            BottleNecks::Deep => BottleNecks::Shallow,
+            BottleNecks::Shallow => BottleNecks::Classic,
+        }
+    }
+
+    // Rows above this are cleared separately by each player, rows below are shared
+    pub fn personal_space_height(self) -> usize {
+        match self {
+            BottleNecks::Classic => 9,
+            BottleNecks::Deep => 12,
+            BottleNecks::Shallow => 8,
+        }
+    }
+
+    // Shared rows must span the whole bottle, or they could never become full.
+    // BOTTLE_MAP gets narrower above row 8, so the necks can't be much shallower.
+    fn fits_bottle_map(self) -> bool {
+        let height = self.personal_space_height();
+        height < BOTTLE_MAP.len()
+            && (height..BOTTLE_MAP.len())
+                .all(|y| (0..BOTTLE_INNER_WIDTH).all(|x| is_inside_bottle_map(x, y)))
+    }
+}
+
+// Returns (left, right) so that blocks can spawn in columns left..right of each player's bottle
+fn get_bottle_neck_columns() -> (usize, usize) {
+    let left = BOTTLE_MAP[0].chars().position(|c| c == 'x').unwrap() / 2;
+    let right = left + BOTTLE_MAP[0].matches("xx").count();
+    (left, right)
+}
+
+// Spawning blocks always in the middle would leave the sides of the bottle neck underused
+pub fn choose_bottle_spawn_x(player_idx: usize, block: &FallingBlock, rng: &mut impl Rng) -> i32 {
+    let (left, right) = get_bottle_neck_columns();
+    let dxs = block.get_relative_coords().iter().map(|(dx, _)| *dx as i32);
+    let min_dx = dxs.clone().min().unwrap();
+    let max_dx = dxs.max().unwrap();
+
+    // Block must fit within the neck, but clamp in case it's wider than the neck
+    let min_x = (left as i32) - min_dx;
+    let max_x = max(min_x, (right as i32) - 1 - max_dx);
+    (player_idx * BOTTLE_OUTER_WIDTH) as i32 + rng.gen_range(min_x..=max_x)
+}
+
+// Clearable wall squares between the bottles of two players, on the left side of the given player
+fn create_bottle_wall(players: &[RefCell<Player>], player_idx: usize) -> SquareContent {
+    let left_color = Color::new(players[player_idx - 1].borrow().color, 0);
+    let right_color = Color::new(players[player_idx].borrow().color, 0);
+    SquareContent::Normal([('|', left_color), ('|', right_color)], None, false)
+}
+
+// With 4 or more players, the bottom of a bottle game can be split into two basins.
+// Returns index of the first player above the right basin.
+pub fn get_basin_split(two_basins: bool, player_count: usize) -> Option<usize> {
+    if two_basins && player_count >= 4 {
+        Some(player_count.div_ceil(2))
+    } else {
+        None
+    }
+}
+
+pub struct BottleBoard {
+    two_basins: bool,
+    necks: BottleNecks,
+    basin_wall_x: Option<usize>, // wall that players can't clear, between the two basins
+}
+
+impl BottleBoard {
+    pub fn new(options: &BoardOptions) -> Self {
+        assert!(
+            options.bottle_necks.fits_bottle_map(),
+            "{:?}",
+            options.bottle_necks
+        );
+        Self {
+            two_basins: options.two_bottle_basins,
+            necks: options.bottle_necks,
+            basin_wall_x: None,
+        }
+    }
+
+    fn personal_height(&self) -> usize {
+        self.necks.personal_space_height()
+    }
+
+    // Returns x ranges of the areas below the bottle necks, rows are full only within one range
+    fn get_basins(&self, player_count: usize) -> Vec<(usize, usize)> {
+        let width = BOTTLE_OUTER_WIDTH * player_count - 1;
+        match self.basin_wall_x {
+            Some(wall_x) => vec![(0, wall_x), (wall_x + 1, width)],
+            None => vec![(0, width)],
+        }
+    }
+
+    // Call this when the number of players changes
+    fn update_basin_wall(&mut self, rows: &mut LandedRows, players: &[RefCell<Player>]) {
+        let new_wall_x = get_basin_split(self.two_basins, players.len())
+            .map(|split| split * BOTTLE_OUTER_WIDTH - 1);
+        if new_wall_x == self.basin_wall_x {
+            return;
+        }
+
+        // Old wall becomes a wall between two players, like in the beginning of the game
+        if let Some(x) = self.basin_wall_x {
+            let right_player_idx = (x + 1) / BOTTLE_OUTER_WIDTH;
+            let wall = create_bottle_wall(players, right_player_idx);
+            for row in &mut rows[self.personal_height()..] {
+                row[x] = Some(wall);
+            }
+        }
+        // Blocks that landed where the new wall goes just disappear
+        if let Some(x) = new_wall_x {
+            for row in &mut rows[self.personal_height()..] {
+                row[x] = None;
+            }
+        }
+        self.basin_wall_x = new_wall_x;
+    }
+}
+
+impl BoardBehavior for BottleBoard {
+    fn initial_rows(&self) -> LandedRows {
+        vec![vec![]; 21]
+    }
+
+    fn width(&self, player_count: usize, _rows: &LandedRows) -> usize {
+        BOTTLE_OUTER_WIDTH * player_count - 1
+    }
+
+    fn is_valid_landed_coords(&self, point: WorldPoint, width: usize, height: usize) -> bool {
+        let (x, y) = point;
+        if !(0..width as i16).contains(&x) || !(0..height as i16).contains(&y) {
+            false
+        } else if (x as usize) % BOTTLE_OUTER_WIDTH == BOTTLE_INNER_WIDTH {
+            // on wall between two players, not allowed near top or between basins
+            (self.personal_height()..).contains(&(y as usize))
+                && Some(x as usize) != self.basin_wall_x
+        } else {
+            is_inside_bottle_map(x as usize, y as usize)
+        }
+    }
+
+    // x is chosen separately for each block
+    fn spawn_point_for(&self, _player_idx: usize, _player_count: usize) -> PlayerPoint {
+        (0, 0)
+    }
+
+    fn is_in_player_region(&self, point: PlayerPoint, player_idx: usize, _count: usize) -> bool {
+        point.0.div_euclid(BOTTLE_OUTER_WIDTH as i32) == player_idx as i32
+    }
+
+    fn spawn_point_for_block(
+        &self,
+        player_idx: usize,
+        _spawn_point: PlayerPoint,
+        block: &FallingBlock,
+        rng: &mut StdRng,
+    ) -> PlayerPoint {
+        (choose_bottle_spawn_x(player_idx, block, rng), 0)
+    }
+
+    fn add_player(
+        &mut self,
+        rows: &mut LandedRows,
+        players: &[RefCell<Player>],
+        player_idx: usize,
+        _handicap: u8,
+        _rng: &mut StdRng,
+    ) -> AddPlayerEffects {
+        if player_idx != players.len() - 1 {
+            // Room for the new bottle and the wall on its right side
+            let left = player_idx * BOTTLE_OUTER_WIDTH;
+            insert_columns(rows, players, left, BOTTLE_OUTER_WIDTH);
+            self.basin_wall_x = match self.basin_wall_x {
+                Some(x) if x >= left => Some(x + BOTTLE_OUTER_WIDTH),
+                other => other,
+            };
+
+            let right_wall = create_bottle_wall(players, player_idx + 1);
+            let left_wall = (player_idx >= 1).then(|| create_bottle_wall(players, player_idx));
+            for row in &mut rows[self.personal_height()..] {
+                row[left + BOTTLE_INNER_WIDTH] = Some(right_wall);
+                // Wall on the left side now separates a different pair of players
+                if let Some(wall) = left_wall {
+                    let square = &mut row[left - 1];
+                    if matches!(
+                        square,
+                        Some(SquareContent::Normal([('|', _), ('|', _)], _, _))
+                    ) {
+                        *square = Some(wall);
+                    }
+                }
+            }
+        } else {
+            let w = self.width(players.len(), rows);
+            let wall = (player_idx >= 1).then(|| create_bottle_wall(players, player_idx));
+            for (y, row) in rows.iter_mut().enumerate() {
+                row.resize(w, None);
+                if (self.personal_height()..).contains(&y) {
+                    if let Some(wall) = &wall {
+                        row[player_idx * BOTTLE_OUTER_WIDTH - 1] = Some(*wall);
+                    }
+                }
+            }
+        }
+        self.update_basin_wall(rows, players);
+        AddPlayerEffects::default()
+    }
+
+    fn remove_player(
+        &mut self,
+        rows: &mut LandedRows,
+        players: &[RefCell<Player>],
+        player_idx: usize,
+    ) -> Vec<usize> {
+        let i = player_idx;
+        let (slice_x, slice_width) = if players.is_empty() {
+            (0, BOTTLE_INNER_WIDTH)
+        } else if i == 0 {
+            (0, BOTTLE_OUTER_WIDTH)
+        } else if i == players.len() {
+            (i * BOTTLE_OUTER_WIDTH, BOTTLE_INNER_WIDTH)
+        } else {
+            (i * BOTTLE_OUTER_WIDTH, BOTTLE_OUTER_WIDTH)
+        };
+
+        let need_new_block = remove_columns(rows, players, slice_x, slice_width);
+        self.basin_wall_x = match self.basin_wall_x {
+            Some(x) if (slice_x..slice_x + slice_width).contains(&x) => None,
+            Some(x) if x >= slice_x + slice_width => Some(x - slice_width),
+            other => other,
+        };
+        self.update_basin_wall(rows, players);
+        need_new_block
+    }
+
+    fn clear_region_for_player(
+        &self,
+        rows: &mut LandedRows,
+        _players: &[RefCell<Player>],
+        player_idx: usize,
+    ) {
+        let left = BOTTLE_OUTER_WIDTH * player_idx;
+        clear_columns(rows, left, left + BOTTLE_INNER_WIDTH);
+    }
+
+    fn find_full_groups(&self, rows: &LandedRows, player_count: usize) -> FullGroups {
+        let mut result = FullGroups::default();
+        for (y, row) in rows.iter().enumerate() {
+            if (0..self.personal_height()).contains(&y) {
+                for i in 0..player_count {
+                    let left = BOTTLE_OUTER_WIDTH * i
+                        + BOTTLE_MAP[y].chars().position(|c| c == 'x').unwrap() / 2;
+                    let right = left + BOTTLE_MAP[y].matches("xx").count();
+                    if !row[left..right].iter().any(|cell| cell.is_none()) {
+                        result.single_player_count += 1;
+                        for x in left..right {
+                            result.points.push((x as i16, y as i16));
+                        }
+                    }
+                }
+            } else {
+                for (left, right) in self.get_basins(player_count) {
+                    if !row[left..right].iter().any(|cell| cell.is_none()) {
+                        result.everyone_count += 1;
+                        for x in left..right {
+                            result.points.push((x as i16, y as i16));
+                        }
+                    }
+                }
+            }
+        }
+        result
+    }
+
+    fn shift_after_clear(&self, rows: &mut LandedRows, full: &[WorldPoint], player_count: usize) {
+        for i in 0..player_count {
+            for y in 0..self.personal_height() {
+                let x_left = i * BOTTLE_OUTER_WIDTH;
+                let x_right = x_left + BOTTLE_INNER_WIDTH;
+                if full.contains(&(((x_left + x_right) / 2) as i16, y as i16)) {
+                    // Blocks fall down only on this player's personal area
+                    delete_row_part(rows, y, x_left, x_right);
+
+                    // The neck only gets wider downwards, so squares always move to valid places.
+                    // If the map ever changes, squares moving into the walls are discarded,
+                    // because they would be invisible and make rows impossible to clear.
+                    for (shifted_y, row) in rows.iter_mut().enumerate().take(y + 1) {
+                        for (x, cell) in row.iter_mut().enumerate().take(x_right).skip(x_left) {
+                            if !is_inside_bottle_map(x, shifted_y) {
+                                *cell = None;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        // Blocks fall down only in the basin where the row was full
+        for (x_left, x_right) in self.get_basins(player_count) {
+            for y in self.personal_height()..rows.len() {
+                if full.contains(&(x_left as i16, y as i16)) {
+                    delete_row_part(rows, y, x_left, x_right);
+                }
+            }
+        }
+    }
+}
@@ -1,43 +1,112 @@
 use crate::escapes::Color;
 use crate::escapes::KeyPress;
+use crate::game_logic::blocks::BlockGenerator;
+use crate::game_logic::blocks::BlockKind;
 use crate::game_logic::blocks::BlockType;
+use crate::game_logic::blocks::CursedBlocks;
 use crate::game_logic::blocks::FallingBlock;
+use crate::game_logic::blocks::Shape;
+use crate::game_logic::blocks::SquareCanvas;
 use crate::game_logic::blocks::SquareContent;
+use crate::game_logic::board::landed_rows_contain;
+use crate::game_logic::board::try_set_square;
+use crate::game_logic::board::BoardBehavior;
+use crate::game_logic::board::BoardOptions;
+use crate::game_logic::board::LandedRows;
+use crate::game_logic::bottle::BottleBoard;
 use crate::game_logic::player::BlockOrTimer;
 use crate::game_logic::player::Player;
+use crate::game_logic::player::Rescue;
+use crate::game_logic::ring::RingBoard;
+use crate::game_logic::traditional::TraditionalBoard;
 use crate::game_logic::BlockRelativeCoords;
 use crate::game_logic::PlayerPoint;
+use crate::game_logic::PlayerSpec;
 use crate::game_logic::WorldPoint;
-use crate::lobby::ClientInfo;
-use crate::lobby::MAX_CLIENTS_PER_LOBBY;
+use rand::rngs::StdRng;
 use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use std::cell::Cell;
 use std::cell::RefCell;
-use std::cmp::max;
+use std::cmp::Reverse;
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::time::Duration;
+use std::time::Instant;
+
+pub const MAX_PLAYERS: usize = 6;
+pub const SCORE_HISTORY_LENGTH: usize = 60; // old samples are forgotten
+
+// Receives a client id and a message, e.g. to print it to the server's log
+pub type Logger = Box<dyn Fn(u64, &str) + Send>;
+
+// Traditional mode rescues, see offer_rescues_as_needed()
+const RESCUE_HEIGHT_DIFFERENCE: usize = 6;
+pub const RESCUE_ROWS: usize = 4;
+pub const RESCUE_COST: usize = 50;
+
+// Blocks don't fall during the countdown, so that friends have time to join.
+// If a second player joins soon after the game started, the countdown starts again once.
+pub const COUNTDOWN_DURATION: Duration = Duration::from_secs(5);
+const COUNTDOWN_RESTART_WINDOW: Duration = Duration::from_secs(15);
+
+// Blocks landing this close to the spawn point become reinforced, see set_reinforced_squares()
+pub const REINFORCED_ROWS: usize = 5;
+
+// Falling blocks cost points after this many ticks without landing, see set_stall_decay()
+pub const STALL_TICKS: usize = 120;
+
+// Flashing normally stops much sooner, this cleans up if it doesn't
+pub const FLASHING_POINT_EXPIRY: Duration = Duration::from_secs(1);
+
+// A bug that happens on every tick shouldn't fill the log with board dumps
+const MIN_TIME_BETWEEN_STATE_DUMPS: Duration = Duration::from_secs(10);
 
 #[derive(Clone, Copy, Eq, PartialEq, Hash, Debug)]
 pub enum Mode {
     Traditional,
     Bottle,
     Ring,
+    Zen, // single player traditional game that never ends
 }
 
 impl Mode {
-    pub const ALL_MODES: &'static [Mode] = &[Mode::Traditional, Mode::Bottle, Mode::Ring];
+    pub const ALL_MODES: &'static [Mode] =
+        &[Mode::Traditional, Mode::Bottle, Mode::Ring, Mode::Zen];
 
     pub fn name(self) -> &'static str {
         match self {
             Mode::Traditional => "Traditional game",
             Mode::Bottle => "Bottle game",
             Mode::Ring => "Ring game",
+            Mode::Zen => "Zen game",
         }
     }
 
     pub fn max_players(self) -> usize {
         match self {
-            Mode::Traditional | Mode::Bottle => MAX_CLIENTS_PER_LOBBY,
+            Mode::Traditional | Mode::Bottle => MAX_PLAYERS,
             Mode::Ring => 4,
+            Mode::Zen => 1,
+        }
+    }
+
+    // Zen games are for relaxing, so their results don't go to high scores
+    pub fn has_high_scores(self) -> bool {
+        self != Mode::Zen
+    }
+
+    // A big I block is 8 wide, so it doesn't fit through a bottle neck or into ring mode's spawn area
+    pub fn supports_big_blocks(self) -> bool {
+        matches!(self, Mode::Traditional | Mode::Zen)
+    }
+
+    fn create_board(self, options: &BoardOptions) -> Box<dyn BoardBehavior> {
+        match self {
+            Mode::Traditional => Box::new(TraditionalBoard::new(options, false)),
+            Mode::Zen => Box::new(TraditionalBoard::new(options, true)),
+            Mode::Bottle => Box::new(BottleBoard::new(options)),
+            Mode::Ring => Box::new(RingBoard),
         }
     }
 }
@@ -55,404 +124,671 @@ fn circle(center: WorldPoint, radius: f32) -> Vec<WorldPoint> {
     result
 }
 
-// The square is centered around the middle of ring game, and in world coordinates
-fn square(r: i16) -> Vec<WorldPoint> {
-    let mut result = vec![(-r, -r), (-r, r), (r, -r), (r, r)];
-    for i in (-r + 1)..r {
-        result.push((-r, i));
-        result.push((r, i));
-        result.push((i, -r));
-        result.push((i, r));
+// Points from clearing rows together are multiplied by this, see add_score()
+pub fn score_multiplier(player_count: usize) -> usize {
+    2usize.saturating_pow(player_count.saturating_sub(1) as u32)
+}
+
+// Scores stop growing here, so that nothing overflows and high scores stay readable
+pub const MAX_SCORE: usize = 999_999_999;
+
+// Shows "999999999+" for scores that reached the cap
+pub fn format_score(score: usize) -> String {
+    if score >= MAX_SCORE {
+        format!("{}+", MAX_SCORE)
+    } else {
+        score.to_string()
     }
-    result
-        .iter()
-        .map(|(x, y)| (RING_OUTER_RADIUS + x, RING_OUTER_RADIUS + y))
-        .collect()
 }
 
-// Idea: Move towards the center of ring mode game, at most one unit in x and one in y.
-// Only corner points (at 45deg from center) move in both x and y directions.
-fn towards_ring_mode_center(point: WorldPoint) -> WorldPoint {
-    let (mut x, mut y) = point;
-    let rx = x - RING_OUTER_RADIUS;
-    let ry = y - RING_OUTER_RADIUS;
-    if rx.abs() >= ry.abs() {
-        x -= rx.signum();
+// Returns the 2 characters that are displayed for a square
+pub fn square_content_to_string(
+    content: SquareContent,
+    falling_block_data: Option<(BlockRelativeCoords, (i8, i8))>,
+) -> String {
+    let mut canvas = TwoCharCanvas([' ', ' ']);
+    content.render(&mut canvas, 0, 0, falling_block_data, (0, 1), None);
+    canvas.0.iter().collect::<String>()
+}
+
+struct TwoCharCanvas([char; 2]);
+impl SquareCanvas for TwoCharCanvas {
+    fn has_color(&self) -> bool {
+        true
     }
-    if ry.abs() >= rx.abs() {
-        y -= ry.signum();
+    fn low_bandwidth(&self) -> bool {
+        false
+    }
+    fn set_char_with_color(&mut self, x: usize, _y: usize, ch: char, _color: Color) {
+        self.0[x] = ch;
     }
-    (x, y)
 }
 
-pub const BOTTLE_MAP: &[&str] = &[
-    r"    |xxxxxxxxxx|    ",
-    r"    |xxxxxxxxxx|    ",
-    r"    |xxxxxxxxxx|    ",
-    r"    |xxxxxxxxxx|    ",
-    r"    /xxxxxxxxxx\    ",
-    r"   /.xxxxxxxxxx.\   ",
-    r"  /xxxxxxxxxxxxxx\  ",
-    r" /.xxxxxxxxxxxxxx.\ ",
-    r"/xxxxxxxxxxxxxxxxxx\",
-    r"|xxxxxxxxxxxxxxxxxx|",
-    r"|xxxxxxxxxxxxxxxxxx|",
-    r"|xxxxxxxxxxxxxxxxxx|",
-    r"|xxxxxxxxxxxxxxxxxx|",
-    r"|xxxxxxxxxxxxxxxxxx|",
-    r"|xxxxxxxxxxxxxxxxxx|",
-    r"|xxxxxxxxxxxxxxxxxx|",
-    r"|xxxxxxxxxxxxxxxxxx|",
-    r"|xxxxxxxxxxxxxxxxxx|",
-    r"|xxxxxxxxxxxxxxxxxx|",
-    r"|xxxxxxxxxxxxxxxxxx|",
-    r"|xxxxxxxxxxxxxxxxxx|",
-];
-const BOTTLE_INNER_WIDTH: usize = 9;
-const BOTTLE_OUTER_WIDTH: usize = 10;
-const BOTTLE_PERSONAL_SPACE_HEIGHT: usize = 9; // rows above the wide "|" area
-
-pub const RING_MAP: &[&str] = &[
-    "               .o------------------------------------------o.               ",
-    "             .'xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx'.             ",
-    "           .'xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx'.           ",
-    "         .'xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx'.         ",
-    "       .'xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx'.       ",
-    "     .'xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx'.     ",
-    "   .'xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx'.   ",
-    " .'xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx'. ",
-    "oxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxo",
-    "|xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx|",
-    "|xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx|",
-    "|xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx|",
-    "|xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx|",
-    "|xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx|",
-    "|xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx|",
-    "|xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx|",
-    "|xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxo============oxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx|",
-    "|xxxxxxxxxxxxxxxxxxxxxxxxxxxxxx|wwwwwwwwwwww|xxxxxxxxxxxxxxxxxxxxxxxxxxxxxx|",
-    "|xxxxxxxxxxxxxxxxxxxxxxxxxxxxxx|aaaaaadddddd|xxxxxxxxxxxxxxxxxxxxxxxxxxxxxx|",
-    "|xxxxxxxxxxxxxxxxxxxxxxxxxxxxxx|aaaaaadddddd|xxxxxxxxxxxxxxxxxxxxxxxxxxxxxx|",
-    "|xxxxxxxxxxxxxxxxxxxxxxxxxxxxxx|aaaaaadddddd|xxxxxxxxxxxxxxxxxxxxxxxxxxxxxx|",
-    "|xxxxxxxxxxxxxxxxxxxxxxxxxxxxxx|ssssssssssss|xxxxxxxxxxxxxxxxxxxxxxxxxxxxxx|",
-    "|xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxo------------oxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx|",
-    "|xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx|",
-    "|xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx|",
-    "|xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx|",
-    "|xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx|",
-    "|xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx|",
-    "|xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx|",
-    "|xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx|",
-    "oxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxo",
-    " '.xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx.' ",
-    "   '.xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx.'   ",
-    "     '.xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx.'     ",
-    "       '.xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx.'       ",
-    "         '.xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx.'         ",
-    "           '.xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx.'           ",
-    "             '.xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx.'             ",
-    "               'o------------------------------------------o'               ",
-];
-pub const RING_OUTER_RADIUS: i16 = 18;
-
-pub fn wrap_around(mode: Mode, y: &mut i32) {
-    if mode == Mode::Ring && *y > 0 {
-        *y += RING_OUTER_RADIUS as i32;
-        *y %= (2 * RING_OUTER_RADIUS + 1) as i32;
-        *y -= RING_OUTER_RADIUS as i32;
-    }
+// Everything that landing a block changes, see Game::set_undo_enabled()
+struct UndoSnapshot {
+    landed_rows: LandedRows,
+    block: FallingBlock,
+    next_block_queue: Vec<FallingBlock>,
+    score: usize,
+    landed_block_count: usize,
+    cleared_row_count: usize,
+    block_counts: HashMap<BlockKind, usize>,
+}
+
+// Why a player was removed from the game, shown to the remaining players
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LeaveReason {
+    Quit,
+    Disconnected,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Departure {
+    pub name: String,
+    pub color: u8,
+    pub reason: LeaveReason,
+}
+
+// Games can have a maximum duration, see game_wrapper.rs
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum TimeLimit {
+    NotReached,
+    WindingDown, // blocks that are already falling can still land
+    Reached,
 }
 
 pub struct Game {
     pub players: Vec<RefCell<Player>>,
-    pub flashing_points: HashMap<WorldPoint, u8>,
+    pub flashing_points: HashMap<WorldPoint, (u8, Instant)>, // background color, expiry time
     pub mode: Mode,
-    landed_rows: Vec<Vec<Option<SquareContent>>>,
+    landed_rows: LandedRows,
+    board: Box<dyn BoardBehavior>,
     score: usize,
+    score_history: Vec<usize>, // sampled periodically, oldest first
+    landed_block_count: usize,
+    cleared_row_count: usize,
+    block_counts: HashMap<BlockKind, usize>, // blocks taken from next block queues
+    last_ring_clear: Vec<(String, u8, usize)>, // see get_last_ring_clear()
+    last_departure: Option<Departure>,       // most recent player who left
+    departure_count: usize,
+    line_bomb_groups: Vec<(Vec<WorldPoint>, bool)>, // see find_group_containing()
     bomb_id_counter: u64,
-    normal_block_factory: fn() -> FallingBlock,
+    normal_block_factory: fn(&mut BlockGenerator) -> FallingBlock,
+    rng: StdRng, // for randomness that doesn't belong to any one player
+    block_seed: Option<u64>,
+    shared_block_sequence: bool, // if true, all players get the same blocks from the seed
+    special_blocks_enabled: bool,
+    cursed_blocks: CursedBlocks,
+    board_options: BoardOptions,
+    ownership_tint: bool,
+    spawn_delay: bool,
+    reinforced_squares: bool,
+    stall_decay: bool,
+    waiting_ends_game: bool,
+    countdown_end: Option<Instant>,
+    countdown_restart_deadline: Option<Instant>, // None after restarting once
+    undo_enabled: bool,
+    undo_snapshot: Option<UndoSnapshot>,
+    time_limit: TimeLimit,
+    logger: Logger,
+    last_state_dump: Cell<Option<Instant>>,
 }
 impl Game {
     pub fn new(mode: Mode) -> Self {
-        let landed_rows = match mode {
-            Mode::Traditional => vec![vec![]; 25],
-            Mode::Bottle => vec![vec![]; 21],
-            Mode::Ring => {
-                let size = (2 * RING_OUTER_RADIUS + 1) as usize;
-                let mut rows = vec![];
-                for _ in 0..size {
-                    let mut row = vec![];
-                    row.resize(size, None);
-                    rows.push(row);
-                }
-                rows
-            }
-        };
+        Self::new_with_board(mode, mode.create_board(&BoardOptions::default()))
+    }
+
+    // The mode is still needed for things that don't depend on the board, e.g. high scores
+    pub(crate) fn new_with_board(mode: Mode, board: Box<dyn BoardBehavior>) -> Self {
         Self {
             players: vec![],
             flashing_points: HashMap::new(),
             mode,
-            landed_rows,
+            landed_rows: board.initial_rows(),
+            board,
             score: 0,
+            score_history: vec![],
+            landed_block_count: 0,
+            cleared_row_count: 0,
+            block_counts: HashMap::new(),
+            last_ring_clear: vec![],
+            last_departure: None,
+            departure_count: 0,
+            line_bomb_groups: vec![],
             bomb_id_counter: 0,
-            normal_block_factory: || FallingBlock::new(BlockType::Normal),
+            normal_block_factory: BlockGenerator::next_normal_block,
+            rng: StdRng::from_entropy(),
+            block_seed: None,
+            shared_block_sequence: false,
+            special_blocks_enabled: true,
+            cursed_blocks: CursedBlocks::default(),
+            board_options: BoardOptions::default(),
+            ownership_tint: false,
+            spawn_delay: false,
+            reinforced_squares: false,
+            stall_decay: false,
+            waiting_ends_game: true,
+            countdown_end: None,
+            countdown_restart_deadline: None,
+            undo_enabled: false,
+            undo_snapshot: None,
+            time_limit: TimeLimit::NotReached,
+            logger: Box::new(|_, _| {}),
+            last_state_dump: Cell::new(None),
+        }
+    }
+
+    // Messages about a client are ignored unless a logger is set
+    pub fn set_logger(&mut self, logger: Logger) {
+        self.logger = logger;
+    }
+
+    // Without special blocks, all blocks come from the normal block factory
+    pub fn set_special_blocks_enabled(&mut self, enabled: bool) {
+        self.special_blocks_enabled = enabled;
+    }
+
+    pub fn set_cursed_blocks(&mut self, cursed_blocks: CursedBlocks) {
+        self.cursed_blocks = cursed_blocks;
+    }
+
+    // If disabled, the please wait timer is shown even when nobody else is playing
+    pub fn set_waiting_ends_game(&mut self, value: bool) {
+        self.waiting_ends_game = value;
+    }
+
+    // For practicing, must not be enabled when results go to high scores.
+    // Only single player games can undo, so that nobody undoes someone else's landing.
+    pub fn set_undo_enabled(&mut self, enabled: bool) {
+        self.undo_enabled = enabled;
+        self.undo_snapshot = None;
+    }
+
+    // After this, players get no new blocks
+    pub fn start_wind_down(&mut self) {
+        if self.time_limit == TimeLimit::NotReached {
+            self.time_limit = TimeLimit::WindingDown;
+            self.undo_snapshot = None;
+        }
+    }
+
+    // Ends the game, see start_pending_please_wait_counters()
+    pub fn finish_wind_down(&mut self) {
+        self.time_limit = TimeLimit::Reached;
+    }
+
+    pub fn is_winding_down(&self) -> bool {
+        self.time_limit == TimeLimit::WindingDown
+    }
+
+    pub fn time_limit_reached(&self) -> bool {
+        self.time_limit != TimeLimit::NotReached
+    }
+
+    // Must be called before adding players, because it creates a new board
+    // Big blocks are ignored in modes that don't support them
+    pub fn set_board_options(&mut self, options: BoardOptions) {
+        assert!(self.players.is_empty());
+        self.board_options = BoardOptions {
+            big_blocks: options.big_blocks && self.mode.supports_big_blocks(),
+            ..options
+        };
+        self.board = self.mode.create_board(&self.board_options);
+        self.landed_rows = self.board.initial_rows();
+    }
+
+    pub fn get_board_options(&self) -> BoardOptions {
+        self.board_options
+    }
+
+    /*
+    If enabled, a new block appears on the next tick (slow move_blocks_down)
+    after the previous block is gone, no matter how it went away. Otherwise
+    new blocks appear immediately.
+    */
+    pub fn set_spawn_delay(&mut self, enabled: bool) {
+        self.spawn_delay = enabled;
+    }
+
+    /*
+    Blocks that land on the last few rows of space before the player's spawn
+    point become reinforced. When a bomb explosion reaches a reinforced
+    square, it becomes a normal square instead of disappearing. Drills and
+    clearing rows don't care about reinforcing.
+    */
+    pub fn set_reinforced_squares(&mut self, enabled: bool) {
+        self.reinforced_squares = enabled;
+    }
+
+    /*
+    Ring mode blocks can fall around the ring for a long time without
+    landing. With stall decay, each tick after STALL_TICKS ticks without
+    landing costs 1 point from the shared score, and the player's name
+    shows that they are stalling. Other modes ignore this, because their
+    blocks always land soon.
+    */
+    pub fn set_stall_decay(&mut self, enabled: bool) {
+        self.stall_decay = enabled;
+    }
+
+    // Landed squares show who landed them, see get_ownership_tint()
+    pub fn set_ownership_tint(&mut self, enabled: bool) {
+        self.ownership_tint = enabled;
+    }
+
+    // Color of the player who landed the square, if they are still in the game
+    pub fn get_ownership_tint(&self, content: &SquareContent) -> Option<u8> {
+        if !self.ownership_tint {
+            return None;
+        }
+        let client_id = content.landed_by()?;
+        self.players
+            .iter()
+            .map(|p| p.borrow())
+            .find(|p| p.client_id == client_id)
+            .map(|p| p.color)
+    }
+
+    // Must be called before adding players.
+    // Players in different games get the same blocks when they use the same seed.
+    pub fn set_block_seed(&mut self, seed: u64, shared_block_sequence: bool) {
+        assert!(self.players.is_empty());
+        self.rng = StdRng::seed_from_u64(seed);
+        self.block_seed = Some(seed);
+        self.shared_block_sequence = shared_block_sequence;
+    }
+
+    pub fn get_block_seed(&self) -> Option<u64> {
+        self.block_seed
+    }
+
+    // Games without a countdown, such as the tutorial, start immediately
+    pub fn start_countdown(&mut self, now: Instant) {
+        self.countdown_end = Some(now + COUNTDOWN_DURATION);
+        // Restarting is for friends who join a game that someone started alone
+        self.countdown_restart_deadline = if self.players.len() < 2 {
+            Some(now + COUNTDOWN_RESTART_WINDOW)
+        } else {
+            None
+        };
+    }
+
+    // Call this after players joined. Restarts the countdown, but only once.
+    pub fn restart_countdown_once(&mut self, now: Instant) {
+        if self.players.len() >= 2
+            && matches!(self.countdown_restart_deadline, Some(deadline) if now < deadline)
+        {
+            self.countdown_end = Some(now + COUNTDOWN_DURATION);
+            self.countdown_restart_deadline = None;
         }
     }
 
+    // The countdown doesn't run while the game is paused
+    pub fn postpone_countdown(&mut self, pause_duration: Duration) {
+        if let Some(end) = &mut self.countdown_end {
+            *end += pause_duration;
+        }
+        if let Some(deadline) = &mut self.countdown_restart_deadline {
+            *deadline += pause_duration;
+        }
+    }
+
+    // Call this before each tick. Returns true if blocks shouldn't move down yet.
+    pub fn update_countdown(&mut self, now: Instant) -> bool {
+        if self.get_countdown_remaining(now).is_none() {
+            self.countdown_end = None;
+        }
+        self.is_counting_down()
+    }
+
+    pub fn set_flashing_point(&mut self, point: WorldPoint, bg_color: u8, now: Instant) {
+        self.flashing_points
+            .insert(point, (bg_color, now + FLASHING_POINT_EXPIRY));
+    }
+
+    // If a flash is abandoned midway, e.g. its task gets cancelled, the points must not flash forever.
+    // Returns true if something was removed.
+    pub fn prune_expired_flashing_points(&mut self, now: Instant) -> bool {
+        let old_len = self.flashing_points.len();
+        self.flashing_points.retain(|_, (_, expiry)| *expiry > now);
+        self.flashing_points.len() != old_len
+    }
+
+    // Returns None when the countdown is over
+    pub fn get_countdown_remaining(&self, now: Instant) -> Option<Duration> {
+        self.countdown_end
+            .and_then(|end| end.checked_duration_since(now))
+            .filter(|remaining| !remaining.is_zero())
+    }
+
+    // Changes only in update_countdown(), so that the game state doesn't depend on the clock
+    pub fn is_counting_down(&self) -> bool {
+        self.countdown_end.is_some()
+    }
+
+    fn create_block_generator(&self, player_idx: usize) -> BlockGenerator {
+        let seed = match self.block_seed {
+            Some(seed) if self.shared_block_sequence => seed,
+            Some(seed) => seed.wrapping_add(player_idx as u64),
+            None => return BlockGenerator::new(StdRng::from_entropy(), StdRng::from_entropy()),
+        };
+        // Flipping bits keeps the other seed away from the shape seeds of other players
+        BlockGenerator::new(StdRng::seed_from_u64(seed), StdRng::seed_from_u64(!seed))
+    }
+
     #[cfg(test)]
     pub fn truncate_height(&mut self, new_height: usize) {
         self.landed_rows.truncate(new_height);
     }
 
-    #[cfg(test)]
-    pub fn set_normal_block_factory(&mut self, factory: fn() -> FallingBlock) {
+    // Scripted games, such as the tutorial, use this to choose the blocks
+    pub fn set_normal_block_factory(&mut self, factory: fn(&mut BlockGenerator) -> FallingBlock) {
         self.normal_block_factory = factory;
     }
 
+    fn create_normal_block(&self, block_generator: &mut BlockGenerator) -> FallingBlock {
+        let mut block = (self.normal_block_factory)(block_generator);
+        if self.board_options.big_blocks {
+            block.make_big();
+        }
+        block
+    }
+
     pub fn get_score(&self) -> usize {
         self.score
     }
 
-    pub fn get_width_per_player(&self) -> Option<usize> {
-        match self.mode {
-            Mode::Traditional if self.players.len() >= 2 => Some(7),
-            Mode::Traditional => Some(10),
-            Mode::Bottle | Mode::Ring => None,
-        }
+    /*
+    Players who haven't pressed any keys don't count in the score multiplier
+    or high scores, so that opening an idle second connection doesn't double
+    the score. If nobody has pressed a key yet, everyone counts.
+    */
+    pub fn is_counted(&self, player_idx: usize) -> bool {
+        self.players[player_idx].borrow().made_input
+            || !self.players.iter().any(|p| p.borrow().made_input)
     }
 
-    pub fn get_width(&self) -> usize {
-        // can't always return self.landed_rows[0].len(), because this is called during resizing
-        match self.mode {
-            Mode::Traditional => self.get_width_per_player().unwrap() * self.players.len(),
-            Mode::Bottle => BOTTLE_OUTER_WIDTH * self.players.len() - 1,
-            Mode::Ring => self.landed_rows[0].len(),
-        }
+    pub fn get_counted_player_count(&self) -> usize {
+        (0..self.players.len())
+            .filter(|i| self.is_counted(*i))
+            .count()
     }
 
-    pub fn get_height(&self) -> usize {
-        self.landed_rows.len()
+    pub fn get_score_multiplier(&self) -> usize {
+        score_multiplier(self.get_counted_player_count())
     }
 
-    // for the ui, returns (x_min, x_max+1, y_min, y_max+1)
-    pub fn get_bounds_in_player_coords(&self) -> (i32, i32, i32, i32) {
-        match self.mode {
-            Mode::Traditional | Mode::Bottle => {
-                (0, self.get_width() as i32, 0, self.get_height() as i32)
-            }
-            Mode::Ring => {
-                let r = RING_OUTER_RADIUS as i32;
-                (-r, r + 1, -r, r + 1)
-            }
+    // The caller decides how often to sample, e.g. with a timer
+    pub fn sample_score(&mut self) {
+        if self.score_history.len() == SCORE_HISTORY_LENGTH {
+            self.score_history.remove(0);
         }
+        self.score_history.push(self.score);
     }
 
-    fn update_spawn_points(&self) {
-        match self.mode {
-            Mode::Traditional => {
-                let w = self.get_width_per_player().unwrap() as i32;
-                for (player_idx, player) in self.players.iter().enumerate() {
-                    let i = player_idx as i32;
-                    player.borrow_mut().spawn_point = ((i * w) + (w / 2), 0);
-                }
-            }
-            Mode::Bottle => {
-                for (player_idx, player) in self.players.iter().enumerate() {
-                    let x = (player_idx * BOTTLE_OUTER_WIDTH) + (BOTTLE_INNER_WIDTH / 2);
-                    player.borrow_mut().spawn_point = (x as i32, 0);
-                }
+    pub fn get_score_history(&self) -> &[usize] {
+        &self.score_history
+    }
+
+    pub fn get_landed_block_count(&self) -> usize {
+        self.landed_block_count
+    }
+
+    // In ring mode, this is the number of cleared rings
+    pub fn get_cleared_row_count(&self) -> usize {
+        self.cleared_row_count
+    }
+
+    pub fn get_block_counts(&self) -> &HashMap<BlockKind, usize> {
+        &self.block_counts
+    }
+
+    // e.g. "L:12 I:9 J:10 O:11 T:8 S:9 Z:10 cursed:2 drill:1 bomb:3"
+    pub fn format_block_counts(&self) -> String {
+        BlockKind::ALL_KINDS
+            .iter()
+            .map(|kind| {
+                let count = self.get_block_counts().get(kind).copied().unwrap_or(0);
+                format!("{}:{}", kind.name(), count)
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    // Name, color and square count of each player whose squares were in the
+    // rings that were cleared most recently. Most squares first.
+    pub fn get_last_ring_clear(&self) -> &[(String, u8, usize)] {
+        &self.last_ring_clear
+    }
+
+    // Players who were removed from the game while others kept playing
+    pub fn get_last_departure(&self) -> Option<&Departure> {
+        self.last_departure.as_ref()
+    }
+    pub fn get_departure_count(&self) -> usize {
+        self.departure_count
+    }
+
+    // Squares of players who left the game are not counted
+    pub fn count_squares_by_player(&self, points: &[WorldPoint]) -> Vec<(String, u8, usize)> {
+        let mut result = vec![];
+        for player in &self.players {
+            let player = player.borrow();
+            let count = points
+                .iter()
+                .filter(|p| {
+                    matches!(
+                        self.get_landed_square(**p),
+                        Some(SquareContent::Normal(_, Some(id), _)) if id == player.client_id
+                    )
+                })
+                .count();
+            if count > 0 {
+                result.push((player.name.clone(), player.color, count));
             }
-            Mode::Ring => {}
         }
+        result.sort_by_key(|(_, _, count)| Reverse(*count));
+        result
     }
 
-    fn wipe_vertical_slice(&mut self, left: usize, width: usize) {
-        // In these modes, player points and world points are the same.
-        // So it doesn't matter whether "left" is in world or player points.
-        assert!(self.mode == Mode::Traditional || self.mode == Mode::Bottle);
+    pub fn get_width_per_player(&self) -> Option<usize> {
+        self.board.width_per_player(self.players.len())
+    }
 
-        let right = left + width;
-        for row in &mut self.landed_rows {
-            row.splice(left..right, vec![]);
-        }
+    pub fn get_width(&self) -> usize {
+        self.board.width(self.players.len(), &self.landed_rows)
+    }
 
-        let left = left as i32;
-        let width = width as i32;
-        let right = right as i32;
+    pub fn get_height(&self) -> usize {
+        self.landed_rows.len()
+    }
 
-        let mut need_new_block = vec![];
+    // for the ui, returns (x_min, x_max+1, y_min, y_max+1)
+    pub fn get_bounds_in_player_coords(&self) -> (i32, i32, i32, i32) {
+        self.board.bounds(self.get_width(), self.get_height())
+    }
 
-        for (player_idx, player) in self.players.iter().enumerate() {
-            if let BlockOrTimer::Block(block) = &mut player.borrow_mut().block_or_timer {
-                let old_points = block.get_coords();
-                let mut new_points = vec![];
-                for (x, y) in old_points {
-                    // Remove points in (left..right), move points on right side
-                    if (..left).contains(&x) {
-                        new_points.push((x, y));
-                    } else if (right..).contains(&x) {
-                        new_points.push((x - width, y));
-                    }
-                }
+    pub fn is_in_player_region(&self, player_idx: usize, point: PlayerPoint) -> bool {
+        self.board
+            .is_in_player_region(point, player_idx, self.players.len())
+    }
 
-                // Move center just like other points, except that it can't be removed
-                let (mut center_x, center_y) = block.center;
-                if (right..).contains(&center_x) {
-                    center_x -= width;
-                } else if (left..right).contains(&center_x) {
-                    center_x = left;
+    pub fn get_player_region(&self, player_idx: usize) -> Vec<WorldPoint> {
+        let player = self.players[player_idx].borrow();
+        let (x_start, x_end, y_start, y_end) = self.get_bounds_in_player_coords();
+        let mut result = vec![];
+        for x in x_start..x_end {
+            for y in y_start..y_end {
+                let point = player.player_to_world((x, y));
+                if self.is_in_player_region(player_idx, (x, y))
+                    && self.is_valid_landed_block_coords(point)
+                {
+                    result.push(point);
                 }
+            }
+        }
+        result
+    }
 
-                if new_points.is_empty() {
-                    // Cannot call new_block() here because player is borrowed
-                    need_new_block.push(player_idx);
-                } else {
-                    block.set_player_coords(&new_points, (center_x, center_y));
+    // Counts rows between the player's spawn point and the highest landed square in the
+    // player's region. Returns the number of rows in the region if nothing has landed there.
+    pub fn get_free_rows_above_stack(&self, player_idx: usize) -> usize {
+        let player = self.players[player_idx].borrow();
+        let (x_start, x_end, _, y_end) = self.get_bounds_in_player_coords();
+        let spawn_y = player.spawn_point.1;
+        for y in spawn_y..y_end {
+            for x in x_start..x_end {
+                let point = player.player_to_world((x, y));
+                if self.is_in_player_region(player_idx, (x, y))
+                    && self.is_valid_landed_block_coords(point)
+                    && self.get_landed_square(point).is_some()
+                {
+                    return (y - spawn_y) as usize;
                 }
             }
         }
+        (y_end - spawn_y) as usize
+    }
+
+    // How many rows of the player's region contain landed squares, counting from spawn point
+    pub fn get_stack_height(&self, player_idx: usize) -> usize {
+        let (_, _, _, y_end) = self.get_bounds_in_player_coords();
+        let spawn_y = self.players[player_idx].borrow().spawn_point.1;
+        (y_end - spawn_y) as usize - self.get_free_rows_above_stack(player_idx)
+    }
 
-        for i in need_new_block {
-            self.new_block(i);
+    fn update_spawn_points(&self) {
+        let player_count = self.players.len();
+        for (player_idx, player) in self.players.iter().enumerate() {
+            player.borrow_mut().spawn_point = self.board.spawn_point_for(player_idx, player_count);
         }
     }
 
-    pub fn add_player(&mut self, client_info: &ClientInfo) -> bool {
+    pub fn add_player(&mut self, spec: &PlayerSpec) -> bool {
         if self.players.len() == self.mode.max_players() {
             return false;
         }
 
-        let player_idx = self.players.len();
-        let down_direction = match self.mode {
-            Mode::Traditional | Mode::Bottle => (0, 1),
-            Mode::Ring => {
-                /*
-                prefer opposite directions of existing players
-                never choose a direction that is already in use
-                choose consistently, not randomly or depending on hashing
-                */
-                let used: Vec<WorldPoint> = self
-                    .players
-                    .iter()
-                    .map(|p| p.borrow().down_direction)
-                    .collect();
-                let opposites: Vec<WorldPoint> = used.iter().map(|(x, y)| (-x, -y)).collect();
-                let all: &[WorldPoint] = &[(0, 1), (0, -1), (1, 0), (-1, 0)];
-
-                *opposites
-                    .iter()
-                    .chain(all.iter())
-                    .find(|dir| !used.contains(dir))
-                    .unwrap()
-            }
-        };
-        let spawn_point = match self.mode {
-            Mode::Traditional | Mode::Bottle => (0, 0), // dummy value to be changed soon
-            Mode::Ring => (0, -(RING_OUTER_RADIUS as i32)),
+        // Players are in the same order as in the lobby, even if they join in a different order
+        let player_idx = self
+            .players
+            .iter()
+            .position(|p| p.borrow().lobby_order > spec.lobby_order)
+            .unwrap_or(self.players.len());
+        let used_directions: Vec<WorldPoint> = self
+            .players
+            .iter()
+            .map(|p| p.borrow().down_direction)
+            .collect();
+        let Some(down_direction) = self.board.down_direction_for_new_player(&used_directions)
+        else {
+            // max_players() should prevent this
+            self.report_client_invariant_violation(spec.client_id, "no free down direction");
+            return false;
         };
-        self.players.push(RefCell::new(Player::new(
-            spawn_point,
-            client_info,
-            down_direction,
-            self.mode,
-            (self.normal_block_factory)(),
-            (self.normal_block_factory)(),
-        )));
+        let spawn_point = self
+            .board
+            .spawn_point_for(player_idx, self.players.len() + 1);
+        let mut block_generator = self.create_block_generator(player_idx);
+        let first_block = self.create_normal_block(&mut block_generator);
+        let second_block = self.create_normal_block(&mut block_generator);
+        self.players.insert(
+            player_idx,
+            RefCell::new(Player::new(
+                spawn_point,
+                spec,
+                down_direction,
+                self.board.player_geometry(),
+                first_block,
+                second_block,
+                block_generator,
+            )),
+        );
         self.update_spawn_points();
+        let mut effects = self.board.add_player(
+            &mut self.landed_rows,
+            &self.players,
+            player_idx,
+            spec.handicap,
+            &mut self.rng,
+        );
 
-        let w = self.get_width();
-        match self.mode {
-            Mode::Traditional => {
-                for row in &mut self.landed_rows {
-                    row.resize(w, None);
-                }
+        let now = Instant::now();
+        for point in effects.flash_points {
+            self.set_flashing_point(point, Color::RED_BACKGROUND.bg, now);
+        }
+        self.new_block(player_idx);
+        effects.need_new_block.sort();
+        effects.need_new_block.dedup();
+        for idx in effects.need_new_block {
+            if idx != player_idx {
+                self.new_block(idx);
             }
-            Mode::Bottle => {
-                for (y, row) in self.landed_rows.iter_mut().enumerate() {
-                    row.resize(w, None);
-                    if player_idx >= 1 && (BOTTLE_PERSONAL_SPACE_HEIGHT..).contains(&y) {
-                        let left_color = Color {
-                            fg: self.players[player_idx - 1].borrow().color,
-                            bg: 0,
-                        };
-                        let right_color = Color {
-                            fg: client_info.color,
-                            bg: 0,
-                        };
-                        row[player_idx * BOTTLE_OUTER_WIDTH - 1] = Some(SquareContent::Normal([
-                            ('|', left_color),
-                            ('|', right_color),
-                        ]));
-                    }
-                }
+        }
+        true
+    }
+
+    pub fn remove_player_if_exists(&mut self, client_id: u64, reason: LeaveReason) {
+        if let Some(player) = self.remove_player(client_id) {
+            if !self.players.is_empty() {
+                self.last_departure = Some(Departure {
+                    name: player.name,
+                    color: player.color,
+                    reason,
+                });
+                self.departure_count += 1;
             }
-            Mode::Ring => self.clear_playing_area(player_idx),
         }
+    }
 
-        self.new_block(player_idx);
-        true
+    // For undoing add_player(), so that others don't see the player leaving
+    pub fn remove_player_silently(&mut self, client_id: u64) {
+        self.remove_player(client_id);
     }
 
-    pub fn remove_player_if_exists(&mut self, client_id: u64) {
+    fn remove_player(&mut self, client_id: u64) -> Option<Player> {
         let i = self
             .players
             .iter()
-            .position(|info| info.borrow().client_id == client_id);
-        if i.is_none() {
-            return;
-        }
-        let i = i.unwrap();
-
-        match self.mode {
-            Mode::Traditional => {
-                let slice_x = self.get_width_per_player().unwrap() * i;
-                let old_width = self.get_width();
-                self.players.remove(i);
-                let new_width = self.get_width();
-
-                let slice_width = old_width - new_width;
-                self.wipe_vertical_slice(slice_x, slice_width);
-            }
-            Mode::Bottle => {
-                let (slice_x, slice_width) = if self.players.len() == 1 {
-                    (0, BOTTLE_INNER_WIDTH)
-                } else if i == 0 {
-                    (0, BOTTLE_OUTER_WIDTH)
-                } else if i == self.players.len() - 1 {
-                    (i * BOTTLE_OUTER_WIDTH, BOTTLE_INNER_WIDTH)
-                } else {
-                    (i * BOTTLE_OUTER_WIDTH, BOTTLE_OUTER_WIDTH)
-                };
+            .position(|info| info.borrow().client_id == client_id)?;
 
-                self.players.remove(i);
-                self.wipe_vertical_slice(slice_x, slice_width);
-            }
-            Mode::Ring => {
-                self.players.remove(i);
-            }
+        let player = self.players.remove(i).into_inner();
+        let need_new_block = self
+            .board
+            .remove_player(&mut self.landed_rows, &self.players, i);
+        for player_idx in need_new_block {
+            self.new_block(player_idx);
         }
 
         self.update_spawn_points();
+        Some(player)
     }
 
-    fn maybe_add_special_block_to_random_player(&self) {
-        let queue = &mut self
-            .players
-            .choose(&mut rand::thread_rng())
-            .unwrap()
-            .borrow_mut()
-            .next_block_queue;
+    fn maybe_add_special_block_to_random_player(&mut self) {
+        let mut player = self.players.choose(&mut self.rng).unwrap().borrow_mut();
+        let player = &mut *player;
+        let queue = &mut player.next_block_queue;
 
         // Do not add a special block:
         //  - when running tests (special blocks are unpredictable)
+        //  - if they are disabled
+        //  - with big blocks (drills and bombs don't scale, and cursed blocks would be too much)
         //  - if there's already ridiculously many (prevent a hypothetical out-of-memory attack)
-        if cfg!(test) || queue.len() > 10 {
+        if cfg!(test)
+            || !self.special_blocks_enabled
+            || self.board_options.big_blocks
+            || queue.len() > 10
+        {
             return;
         }
 
-        match BlockType::from_score(self.score) {
+        match BlockType::from_score(
+            self.score,
+            self.cursed_blocks,
+            &mut player.block_generator.rng,
+        ) {
             BlockType::Normal => {}
-            special => queue.push(FallingBlock::new(special)),
+            special => queue.push(FallingBlock::new(special, &mut player.block_generator.rng)),
         }
     }
 
@@ -470,60 +806,72 @@ impl Game {
             The scores also feel quite different for single player and multiplayer.
             That's why they are shown separately in the high scores view.
             */
-            add *= 2usize.pow((self.players.len() as u32) - 1);
+            add = add.saturating_mul(self.get_score_multiplier());
         }
-        self.score += add;
+        self.score = self.score.saturating_add(add).min(MAX_SCORE);
     }
 
-    pub fn find_full_rows_and_increment_score(&mut self) -> Vec<WorldPoint> {
-        let mut full_points = vec![];
-        let mut full_count_everyone = 0;
-        let mut full_count_single_player = 0;
-
-        match self.mode {
-            Mode::Traditional => {
-                for (y, row) in self.landed_rows.iter().enumerate() {
-                    if !row.iter().any(|cell| cell.is_none()) {
-                        full_count_everyone += 1;
-                        for (x, _) in row.iter().enumerate() {
-                            full_points.push((x as i16, y as i16));
-                        }
-                    }
-                }
+    // The score never goes negative
+    fn subtract_score(&mut self, amount: usize) {
+        self.score = self.score.saturating_sub(amount);
+    }
+
+    #[cfg(test)]
+    pub(crate) fn add_score_for_test(&mut self, add: usize, multi_player_compensate: bool) {
+        self.add_score(add, multi_player_compensate);
+    }
+
+    /*
+    Returns the points that would be cleared together with the given point
+    if they were all full, e.g. the row or ring containing the point. The
+    bool is true if the group is filled by all players together (see
+    FullGroups). Each board decides what is cleared together, so this
+    compares what's full when everything is filled, with and without the
+    given point.
+    */
+    fn find_group_containing(&self, point: WorldPoint) -> (Vec<WorldPoint>, bool) {
+        let mut rows = self.landed_rows.clone();
+        for row in &mut rows {
+            for cell in row {
+                cell.get_or_insert(SquareContent::with_color(Color::DEFAULT));
             }
-            Mode::Bottle => {
-                for (y, row) in self.landed_rows.iter().enumerate() {
-                    if (0..BOTTLE_PERSONAL_SPACE_HEIGHT).contains(&y) {
-                        for i in 0..self.players.len() {
-                            let left = BOTTLE_OUTER_WIDTH * i
-                                + BOTTLE_MAP[y].chars().position(|c| c == 'x').unwrap() / 2;
-                            let right = left + BOTTLE_MAP[y].matches("xx").count();
-                            if !row[left..right].iter().any(|cell| cell.is_none()) {
-                                full_count_single_player += 1;
-                                for x in left..right {
-                                    full_points.push((x as i16, y as i16));
-                                }
-                            }
-                        }
-                    } else if !row.iter().any(|cell| cell.is_none()) {
-                        full_count_everyone += 1;
-                        for (x, _) in row.iter().enumerate() {
-                            full_points.push((x as i16, y as i16));
-                        }
-                    }
-                }
+        }
+        let with_point = self.board.find_full_groups(&rows, self.players.len());
+        try_set_square(&mut rows, point, None);
+        let without_point = self.board.find_full_groups(&rows, self.players.len());
+
+        let remaining: HashSet<WorldPoint> = without_point.points.into_iter().collect();
+        let group = with_point
+            .points
+            .into_iter()
+            .filter(|p| !remaining.contains(p))
+            .collect();
+        (
+            group,
+            with_point.everyone_count > without_point.everyone_count,
+        )
+    }
+
+    pub fn find_full_rows_and_increment_score(&mut self) -> Vec<WorldPoint> {
+        let mut full = self
+            .board
+            .find_full_groups(&self.landed_rows, self.players.len());
+        let full_count_single_player = full.single_player_count;
+        let full_count_everyone = full.everyone_count;
+
+        // Rows cleared by line bombs, unless they are full anyway
+        let mut bomb_count_single_player = 0;
+        let mut bomb_count_everyone = 0;
+        for (points, everyone) in std::mem::take(&mut self.line_bomb_groups) {
+            if points.iter().all(|p| full.points.contains(p)) {
+                continue;
             }
-            Mode::Ring => {
-                for r in 1..=RING_OUTER_RADIUS {
-                    if square(r)
-                        .iter()
-                        .all(|p| self.get_landed_square(*p).is_some())
-                    {
-                        full_count_everyone += 1;
-                        full_points.extend(square(r));
-                    }
-                }
+            if everyone {
+                bomb_count_everyone += 1;
+            } else {
+                bomb_count_single_player += 1;
             }
+            full.points.extend(points);
         }
 
         /*
@@ -534,94 +882,89 @@ impl Game {
             3 full rows:  +60
             etc
         */
+        self.cleared_row_count += full_count_single_player + full_count_everyone;
+        if self.board.tracks_cleared_squares() && !full.points.is_empty() {
+            self.last_ring_clear = self.count_squares_by_player(&full.points);
+        }
         self.add_score(
             5 * full_count_single_player * (full_count_single_player + 1),
             false,
         );
         self.add_score(5 * full_count_everyone * (full_count_everyone + 1), true);
-        full_points
-    }
 
-    pub fn remove_full_rows(&mut self, full: &[WorldPoint]) {
-        match self.mode {
-            Mode::Traditional => {
-                for y in 0..self.landed_rows.len() {
-                    if full.contains(&(0, y as i16)) {
-                        self.landed_rows[..(y + 1)].rotate_right(1);
-                        for cell in &mut self.landed_rows[0] {
-                            *cell = None;
-                        }
-                    }
-                }
-            }
-            Mode::Bottle => {
-                for (i, _) in self.players.iter().enumerate() {
-                    for y in 0..BOTTLE_PERSONAL_SPACE_HEIGHT {
-                        let x_left = i * BOTTLE_OUTER_WIDTH;
-                        let x_right = x_left + BOTTLE_INNER_WIDTH;
-                        if full.contains(&(((x_left + x_right) / 2) as i16, y as i16)) {
-                            // Blocks fall down only on this player's personal area
-                            for source_y in (0..y).rev() {
-                                let source_row =
-                                    self.landed_rows[source_y][x_left..x_right].to_vec();
-                                self.landed_rows[source_y + 1].splice(x_left..x_right, source_row);
-                            }
-                            for cell in &mut self.landed_rows[0][x_left..x_right] {
-                                *cell = None;
-                            }
-                        }
-                    }
-                }
+        // Line bombs give half of what the same rows would give if they were full
+        self.cleared_row_count += bomb_count_single_player + bomb_count_everyone;
+        self.add_score(
+            5 * bomb_count_single_player * (bomb_count_single_player + 1) / 2,
+            false,
+        );
+        self.add_score(
+            5 * bomb_count_everyone * (bomb_count_everyone + 1) / 2,
+            true,
+        );
+        full.points
+    }
 
-                for y in BOTTLE_PERSONAL_SPACE_HEIGHT..self.landed_rows.len() {
-                    if full.contains(&(0, y as i16)) {
-                        self.landed_rows[..(y + 1)].rotate_right(1);
-                        for cell in &mut self.landed_rows[0] {
-                            *cell = None;
-                        }
-                    }
-                }
+    /*
+    In traditional mode, a player whose stack grows much higher than everyone
+    else's makes the game worse for everyone, because full rows need squares
+    from all players. That player gets offered a chance to clear the top of
+    their stack, paid with points from the shared score.
+    */
+    pub fn offer_rescues_as_needed(&mut self) {
+        if !self.board.offers_rescues() || self.players.len() < 2 {
+            return;
+        }
+        let heights: Vec<usize> = (0..self.players.len())
+            .map(|i| self.get_stack_height(i))
+            .collect();
+        for (player_idx, player) in self.players.iter().enumerate() {
+            let highest_other = (0..heights.len())
+                .filter(|i| *i != player_idx)
+                .map(|i| heights[i])
+                .max()
+                .unwrap();
+            let mut player = player.borrow_mut();
+            if player.rescue == Rescue::NotOffered
+                && heights[player_idx] > highest_other + RESCUE_HEIGHT_DIFFERENCE
+            {
+                player.rescue = Rescue::Offered;
             }
-            Mode::Ring => {
-                for &point in full {
-                    self.set_landed_square(point, None);
-                }
-
-                let mut full_radiuses = vec![false; RING_OUTER_RADIUS as usize + 1];
-                for (x, y) in full {
-                    let x_offset = (x - RING_OUTER_RADIUS).abs();
-                    let y_offset = (y - RING_OUTER_RADIUS).abs();
-                    full_radiuses[max(x_offset, y_offset) as usize] = true;
-                }
+        }
+    }
 
-                // Removing a ring shifts outer radiuses, so remove outermost rings first.
-                for (r, is_full) in full_radiuses.iter().enumerate().rev() {
-                    if !is_full {
-                        continue;
-                    }
-                    let r = r as i16;
+    fn has_rescue_offer(&self, player_idx: usize) -> bool {
+        self.players[player_idx].borrow().rescue == Rescue::Offered
+    }
 
-                    // Delete the squares that flashed
-                    for point in square(r) {
-                        self.set_landed_square(point, None)
-                    }
+    fn answer_rescue(&mut self, player_idx: usize, accept: bool) -> bool {
+        self.players[player_idx].borrow_mut().rescue = Rescue::Answered;
+        if !accept {
+            return true;
+        }
 
-                    // Shift outer squares inwards
-                    for source_r in (r + 1)..=RING_OUTER_RADIUS {
-                        for source in square(source_r) {
-                            let dest = towards_ring_mode_center(source);
-                            let square = self.get_landed_square(source);
-                            // Don't replace already moved non-blanks with blanks.
-                            // This makes a difference in corners where multiple places merge.
-                            if square.is_some() {
-                                self.set_landed_square(source, None);
-                                self.set_landed_square(dest, square);
-                            }
-                        }
-                    }
+        let (x_start, x_end, _, y_end) = self.get_bounds_in_player_coords();
+        let top_y = {
+            let spawn_y = self.players[player_idx].borrow().spawn_point.1;
+            spawn_y + self.get_free_rows_above_stack(player_idx) as i32
+        };
+        for y in top_y..y_end.min(top_y + RESCUE_ROWS as i32) {
+            for x in x_start..x_end {
+                let point = self.players[player_idx].borrow().player_to_world((x, y));
+                if self.is_in_player_region(player_idx, (x, y))
+                    && self.is_valid_landed_block_coords(point)
+                {
+                    self.set_landed_square(point, None);
                 }
             }
         }
+        self.subtract_score(RESCUE_COST);
+        true
+    }
+
+    pub fn remove_full_rows(&mut self, full: &[WorldPoint]) {
+        self.board
+            .shift_after_clear(&mut self.landed_rows, full, self.players.len());
 
         // Moving landed squares can cause them to overlap falling squares
         let mut potential_overlaps: Vec<WorldPoint> = vec![];
@@ -642,45 +985,16 @@ impl Game {
 
     fn is_valid_falling_block_coords(&self, player_idx: usize, point: PlayerPoint) -> bool {
         let (x, mut y) = point;
-        let top_y = match self.mode {
-            Mode::Traditional | Mode::Bottle => 0,
-            Mode::Ring => -(RING_OUTER_RADIUS as i32),
-        };
+        let top_y = self.board.top_y();
         if y < top_y {
             y = top_y;
         }
-        wrap_around(self.mode, &mut y);
         self.is_valid_landed_block_coords(self.players[player_idx].borrow().player_to_world((x, y)))
     }
 
     pub fn is_valid_landed_block_coords(&self, point: WorldPoint) -> bool {
-        let (x, y) = point;
-        match self.mode {
-            Mode::Traditional => {
-                let w = self.get_width() as i16;
-                let h = self.get_height() as i16;
-                (0..w).contains(&x) && (0..h).contains(&y)
-            }
-            Mode::Bottle => {
-                let w = self.get_width() as i16;
-                let h = self.get_height() as i16;
-                if !(0..w).contains(&x) || !(0..h).contains(&y) {
-                    false
-                } else if (x as usize) % BOTTLE_OUTER_WIDTH == BOTTLE_INNER_WIDTH {
-                    // on wall between two players, not allowed near top
-                    (BOTTLE_PERSONAL_SPACE_HEIGHT..).contains(&(y as usize))
-                } else {
-                    let line = BOTTLE_MAP[y as usize].as_bytes();
-                    line[2 * ((x as usize) % BOTTLE_OUTER_WIDTH) + 1] == b'x'
-                }
-            }
-            Mode::Ring => {
-                let size = 2 * RING_OUTER_RADIUS + 1;
-                (0..size).contains(&x)
-                    && (0..size).contains(&y)
-                    && RING_MAP[y as usize + 1].as_bytes()[2 * x as usize + 1] == b'x'
-            }
-        }
+        self.board
+            .is_valid_landed_coords(point, self.get_width(), self.get_height())
     }
 
     pub fn get_falling_square(
@@ -703,12 +1017,138 @@ impl Game {
         None
     }
 
+    /*
+    Returns the whole game area in world coordinates, one string per row.
+    Falling squares are FF and landed squares are LL, unless they would be
+    displayed differently anyway (e.g. bombs). Points outside the playing
+    area (e.g. outside the bottle) are shown as "..".
+    */
+    pub fn debug_dump(&self) -> Vec<String> {
+        let mut result = vec![];
+        for y in 0..(self.get_height() as i16) {
+            let mut row = "".to_string();
+            for x in 0..(self.get_width() as i16) {
+                let point = (x, y);
+                if !self.is_valid_landed_block_coords(point) {
+                    row.push_str("..");
+                } else if let Some((content, relative_coords, player_idx)) =
+                    self.get_falling_square(point)
+                {
+                    let (down_x, down_y) = self.players[player_idx].borrow().down_direction;
+                    let text = square_content_to_string(
+                        content,
+                        Some((relative_coords, (down_x as i8, down_y as i8))),
+                    );
+                    row.push_str(if text == "  " { "FF" } else { &text });
+                } else if let Some(content) = self.get_landed_square(point) {
+                    let text = square_content_to_string(content, None);
+                    row.push_str(if text == "  " { "LL" } else { &text });
+                } else {
+                    row.push_str("  ");
+                }
+            }
+            result.push(row);
+        }
+        result
+    }
+
+    // One line for each player, goes to the log together with debug_dump()
+    pub fn debug_dump_players(&self) -> Vec<String> {
+        self.players
+            .iter()
+            .map(|player| {
+                let player = player.borrow();
+                let state = match &player.block_or_timer {
+                    BlockOrTimer::Block(block) => format!("Block (center {:?})", block.center),
+                    BlockOrTimer::SpawnPending(_) => "SpawnPending".to_string(),
+                    BlockOrTimer::TimerPending => "TimerPending".to_string(),
+                    BlockOrTimer::Timer(n) => format!("Timer({})", n),
+                    BlockOrTimer::WindingDown => "WindingDown".to_string(),
+                };
+                format!(
+                    "player {:?} (client {}): {}, fast_down={}",
+                    player.name, player.client_id, state, player.fast_down
+                )
+            })
+            .collect()
+    }
+
+    // Everything needed to figure out what the game looked like when something went wrong
+    pub fn debug_state(&self) -> Vec<String> {
+        let mut result = vec![format!("{:?} game, score {}", self.mode, self.score)];
+        result.extend(self.debug_dump_players());
+        result.extend(self.debug_dump().iter().map(|row| format!("|{}|", row)));
+        result
+    }
+
+    /*
+    Call this when something that should never happen happens, and then
+    either panic or continue in a way that keeps the game playable. Must not
+    be called while a player is mutably borrowed.
+    */
+    fn report_invariant_violation(&self, player_idx: usize, what: &str) {
+        let client_id = self.players[player_idx].borrow().client_id;
+        self.report_client_invariant_violation(client_id, what);
+    }
+
+    // Also works for clients that aren't in the game yet
+    fn report_client_invariant_violation(&self, client_id: u64, what: &str) {
+        (self.logger)(client_id, &format!("Invariant violated: {}", what));
+
+        let now = Instant::now();
+        if let Some(last) = self.last_state_dump.get() {
+            if now.duration_since(last) < MIN_TIME_BETWEEN_STATE_DUMPS {
+                return;
+            }
+        }
+        self.last_state_dump.set(Some(now));
+        for line in self.debug_state() {
+            (self.logger)(client_id, &format!("  {}", line));
+        }
+    }
+
+    // Returns false and logs the game state if the player doesn't have a falling block
+    pub(crate) fn change_falling_block(
+        &self,
+        player_idx: usize,
+        what: &str,
+        f: impl FnOnce(&mut FallingBlock),
+    ) -> bool {
+        let changed = match &mut self.players[player_idx].borrow_mut().block_or_timer {
+            BlockOrTimer::Block(block) => {
+                f(block);
+                true
+            }
+            _ => false,
+        };
+        if !changed {
+            self.report_invariant_violation(player_idx, &format!("no block to {}", what));
+        }
+        changed
+    }
+
+    fn landed_rows_contain(&self, point: WorldPoint) -> bool {
+        landed_rows_contain(&self.landed_rows, point)
+    }
+
+    // Panics if the point is outside landed_rows. Check with is_valid_landed_block_coords()
+    // when the coordinates are computed and might end up outside the playing area.
     pub fn get_landed_square(&self, point: WorldPoint) -> Option<SquareContent> {
+        debug_assert!(
+            self.landed_rows_contain(point),
+            "landed square out of range: {:?}",
+            point
+        );
         let (x, y) = point;
         self.landed_rows[y as usize][x as usize]
     }
 
     pub fn set_landed_square(&mut self, point: WorldPoint, value: Option<SquareContent>) {
+        debug_assert!(
+            self.landed_rows_contain(point),
+            "landed square out of range: {:?}",
+            point
+        );
         let (x, y) = point;
         self.landed_rows[y as usize][x as usize] = value;
     }
@@ -733,6 +1173,41 @@ impl Game {
         None
     }
 
+    /*
+    Checks whether the current player's block can go to the given coordinates.
+    Returns the points that the block would drill through, or None if the block
+    doesn't fit. Drilling only makes sense for the player's current block.
+    */
+    fn check_block_placement(
+        &self,
+        player_idx: usize,
+        coords: &[PlayerPoint],
+        allow_drilling: bool,
+    ) -> Option<HashSet<WorldPoint>> {
+        let player = self.players[player_idx].borrow();
+        let content = match &player.block_or_timer {
+            BlockOrTimer::Block(block) if allow_drilling => Some(block.square_content),
+            _ => None,
+        };
+
+        let mut gonna_drill = HashSet::new();
+        for p in coords {
+            if !self.is_valid_falling_block_coords(player_idx, *p) {
+                return None;
+            }
+            let world_point = player.player_to_world(*p);
+            if let Some(goes_on_top_of) = self.get_any_square(world_point, Some(player_idx)) {
+                match content {
+                    Some(content) if content.can_drill(&goes_on_top_of) => {
+                        gonna_drill.insert(world_point);
+                    }
+                    _ => return None,
+                }
+            }
+        }
+        Some(gonna_drill)
+    }
+
     fn rotate_if_possible(&self, player_idx: usize, prefer_counter_clockwise: bool) -> bool {
         let player = &self.players[player_idx];
         let coords = match &player.borrow().block_or_timer {
@@ -740,21 +1215,14 @@ impl Game {
             _ => return false,
         };
 
-        let can_rotate = coords.iter().all(|p| {
-            let stays_in_bounds = self.is_valid_falling_block_coords(player_idx, *p);
-            let goes_on_top_of_something = self
-                .get_any_square(player.borrow().player_to_world(*p), Some(player_idx))
-                .is_some();
-            stays_in_bounds && !goes_on_top_of_something
-        });
-        if can_rotate {
-            let mut player = player.borrow_mut();
-            match &mut player.block_or_timer {
-                BlockOrTimer::Block(block) => block.rotate(prefer_counter_clockwise),
-                _ => panic!(),
-            }
-        }
+        // Drills don't rotate, so there's nothing to drill
+        let can_rotate = self
+            .check_block_placement(player_idx, &coords, false)
+            .is_some();
         can_rotate
+            && self.change_falling_block(player_idx, "rotate", |block| {
+                block.rotate(prefer_counter_clockwise)
+            })
     }
 
     fn move_if_possible(
@@ -764,74 +1232,45 @@ impl Game {
         dy: i8,
         enable_drilling: bool,
     ) -> bool {
-        let player = &self.players[player_idx];
-        let mut gonna_drill: HashSet<WorldPoint> = HashSet::new();
-        let can_move = {
-            let (content, coords) = match &player.borrow().block_or_timer {
-                BlockOrTimer::Block(block) => {
-                    (block.square_content, block.get_moved_coords(dx, dy))
-                }
-                _ => return false,
-            };
-
-            coords.iter().all(|p| {
-                let stays_in_bounds = self.is_valid_falling_block_coords(player_idx, *p);
-                stays_in_bounds && {
-                    let p = player.borrow().player_to_world(*p);
-                    if let Some(goes_on_top_of) = self.get_any_square(p, Some(player_idx)) {
-                        if enable_drilling && content.can_drill(&goes_on_top_of) {
-                            gonna_drill.insert(p);
-                            true
-                        } else {
-                            false
-                        }
-                    } else {
-                        true
-                    }
-                }
-            })
+        let coords = match &self.players[player_idx].borrow().block_or_timer {
+            BlockOrTimer::Block(block) => block.get_moved_coords(dx, dy),
+            _ => return false,
         };
 
-        if can_move {
-            match &mut player.borrow_mut().block_or_timer {
-                BlockOrTimer::Block(block) => block.m0v3(dx, dy),
-                _ => panic!(),
+        match self.check_block_placement(player_idx, &coords, enable_drilling) {
+            Some(gonna_drill) => {
+                if !self.change_falling_block(player_idx, "move", |block| {
+                    block.m0v3(dx, dy);
+                    self.board.wrap_falling_block(block);
+                }) {
+                    return false;
+                }
+                self.filter_and_mutate_all_squares_in_place(|point, _, i| {
+                    i == Some(player_idx) || !gonna_drill.contains(&point)
+                });
+                true
             }
-            self.filter_and_mutate_all_squares_in_place(|point, _, i| {
-                i == Some(player_idx) || !gonna_drill.contains(&point)
-            });
+            None => false,
         }
-        can_move
     }
 
+    // Drills drill when they move down, so the prediction takes drilling into account
     pub fn predict_landing_place(&self, player_idx: usize) -> Vec<WorldPoint> {
         let player = &self.players[player_idx];
-        let (content, mut working_coords) = match &player.borrow().block_or_timer {
-            BlockOrTimer::Block(block) => (block.square_content, block.get_coords()),
+        let mut working_coords = match &player.borrow().block_or_timer {
+            BlockOrTimer::Block(block) => block.get_coords(),
             _ => return vec![],
         };
 
         // 40 is enough even in ring mode
         for _ in 0..40 {
-            let can_move = working_coords.iter().all(|p| {
-                let (x, mut y) = *p;
-                y += 1;
-
-                let stays_in_bounds = self.is_valid_falling_block_coords(player_idx, (x, y));
-                stays_in_bounds && {
-                    let world_point = player.borrow().player_to_world((x, y));
-                    if let Some(goes_on_top_of) = self.get_any_square(world_point, Some(player_idx))
-                    {
-                        content.can_drill(&goes_on_top_of)
-                    } else {
-                        true
-                    }
-                }
-            });
-            if can_move {
-                for point in working_coords.iter_mut() {
-                    point.1 += 1;
-                }
+            let moved_coords: Vec<PlayerPoint> =
+                working_coords.iter().map(|(x, y)| (*x, y + 1)).collect();
+            if self
+                .check_block_placement(player_idx, &moved_coords, true)
+                .is_some()
+            {
+                working_coords = moved_coords;
             } else {
                 return working_coords
                     .iter()
@@ -841,14 +1280,44 @@ impl Game {
         }
 
         // Block won't land if it moves down. Happens a lot in ring mode.
-        return vec![];
+        vec![]
+    }
+
+    // See set_stall_decay()
+    fn count_idle_ticks(&mut self, just_spawned: &[usize]) {
+        if !self.stall_decay || !self.board.blocks_can_stall() {
+            return;
+        }
+        let mut stalling_count = 0;
+        for (player_idx, player) in self.players.iter().enumerate() {
+            if let BlockOrTimer::Block(block) = &mut player.borrow_mut().block_or_timer {
+                if !just_spawned.contains(&player_idx) {
+                    block.idle_ticks += 1;
+                }
+                if block.idle_ticks > STALL_TICKS {
+                    stalling_count += 1;
+                }
+            }
+        }
+        self.subtract_score(stalling_count);
     }
 
     pub fn move_blocks_down(&mut self, fast: bool) -> bool {
+        // New blocks stay at the spawn point until the next tick
+        let just_spawned = if fast || !self.spawn_delay {
+            vec![]
+        } else {
+            self.spawn_pending_blocks()
+        };
+
+        if !fast {
+            self.count_idle_ticks(&just_spawned);
+        }
+
         let mut drill_indexes = vec![];
         let mut other_indexes = vec![];
         for (player_idx, player) in self.players.iter().enumerate() {
-            if player.borrow().fast_down == fast {
+            if player.borrow().fast_down == fast && !just_spawned.contains(&player_idx) {
                 if let BlockOrTimer::Block(b) = &player.borrow().block_or_timer {
                     if b.square_content.is_drill() {
                         drill_indexes.push(player_idx);
@@ -859,7 +1328,7 @@ impl Game {
             }
         }
 
-        let mut need_render = false;
+        let mut need_render = !just_spawned.is_empty();
         loop {
             let old_total_len = drill_indexes.len() + other_indexes.len();
             // Move drills last, gives other blocks a chance to go in front of a drill and get drilled
@@ -877,16 +1346,20 @@ impl Game {
             if fast {
                 player.borrow_mut().fast_down = false;
             } else {
-                let (player_coords, relative_coords, square_content) =
-                    if let BlockOrTimer::Block(b) = &player.borrow().block_or_timer {
-                        (
-                            b.get_coords(),
-                            b.get_relative_coords().to_vec(),
-                            b.square_content,
-                        )
-                    } else {
-                        panic!()
-                    };
+                let block_info = match &player.borrow().block_or_timer {
+                    BlockOrTimer::Block(b) => Some((
+                        b.get_coords(),
+                        b.get_relative_coords().to_vec(),
+                        b.square_content,
+                    )),
+                    // Drilled away during this tick, the new block comes later
+                    BlockOrTimer::SpawnPending(_) => continue,
+                    _ => None,
+                };
+                let Some((player_coords, relative_coords, square_content)) = block_info else {
+                    self.report_invariant_violation(*player_idx, "no block to land");
+                    continue;
+                };
 
                 let world_coords: Vec<WorldPoint> = player_coords
                     .iter()
@@ -897,12 +1370,39 @@ impl Game {
                     .all(|p| self.is_valid_landed_block_coords(*p))
                 {
                     // land the block
+                    if self.undo_enabled && self.players.len() == 1 {
+                        self.undo_snapshot = self.create_undo_snapshot(*player_idx);
+                    }
                     let (down_x, down_y) = player.borrow().down_direction;
+                    let client_id = player.borrow().client_id;
+                    // Player coordinates make this the same calculation in every mode
+                    let spawn_y = player.borrow().spawn_point.1;
+                    let reinforced = self.reinforced_squares
+                        && player_coords
+                            .iter()
+                            .any(|(_, y)| y - spawn_y < REINFORCED_ROWS as i32);
                     for (w, r) in world_coords.iter().zip(relative_coords.iter()) {
-                        let landed_content =
-                            square_content.get_landed_content(*r, (down_x as i8, down_y as i8));
+                        let landed_content = square_content.get_landed_content(
+                            *r,
+                            (down_x as i8, down_y as i8),
+                            client_id,
+                            reinforced,
+                        );
                         self.set_landed_square(*w, Some(landed_content));
                     }
+                    if square_content.is_line_bomb() {
+                        // The rows get cleared in find_full_rows_and_increment_score()
+                        for w in &world_coords {
+                            if !self.line_bomb_groups.iter().any(|(g, _)| g.contains(w)) {
+                                let group = self.find_group_containing(*w);
+                                self.line_bomb_groups.push(group);
+                            }
+                        }
+                        // Undoing while the rows are being cleared would mess things up
+                        self.undo_snapshot = None;
+                    }
+                    self.landed_block_count += 1;
+                    self.players[*player_idx].borrow_mut().landed_count += 1;
                     self.new_block(*player_idx);
                 } else {
                     // no room to land
@@ -912,11 +1412,74 @@ impl Game {
             }
         }
 
+        if !fast {
+            self.offer_rescues_as_needed();
+        }
         need_render
     }
 
+    fn create_undo_snapshot(&self, player_idx: usize) -> Option<UndoSnapshot> {
+        let player = self.players[player_idx].borrow();
+        let BlockOrTimer::Block(block) = &player.block_or_timer else {
+            return None;
+        };
+        Some(UndoSnapshot {
+            landed_rows: self.landed_rows.clone(),
+            block: block.clone(),
+            next_block_queue: player.next_block_queue.clone(),
+            score: self.score,
+            landed_block_count: self.landed_block_count,
+            cleared_row_count: self.cleared_row_count,
+            block_counts: self.block_counts.clone(),
+        })
+    }
+
+    pub fn can_undo(&self) -> bool {
+        // Full rows are being removed right now, undoing would mess that up
+        self.undo_snapshot.is_some()
+            && self
+                .board
+                .find_full_groups(&self.landed_rows, self.players.len())
+                .points
+                .is_empty()
+    }
+
+    // Puts the block that landed last back to where blocks spawn
+    fn undo_landing(&mut self, player_idx: usize) -> bool {
+        if !self.can_undo() {
+            return false;
+        }
+        let snapshot = self.undo_snapshot.take().unwrap();
+        self.landed_rows = snapshot.landed_rows;
+        self.score = snapshot.score;
+        self.landed_block_count = snapshot.landed_block_count;
+        self.cleared_row_count = snapshot.cleared_row_count;
+        self.block_counts = snapshot.block_counts;
+
+        // Player's landed_count is not restored, it only tells clients that something landed
+        let mut player = self.players[player_idx].borrow_mut();
+        let mut block = snapshot.block;
+        let spawn_point = player.spawn_point;
+        let spawn_point = self.board.spawn_point_for_block(
+            player_idx,
+            spawn_point,
+            &block,
+            &mut player.block_generator.rng,
+        );
+        block.spawn_at(spawn_point);
+        player.block_or_timer = BlockOrTimer::Block(block);
+        player.next_block_queue = snapshot.next_block_queue;
+        player.fast_down = false;
+        true
+    }
+
+    // The F key, also shown in the help of the game
+    pub fn can_flip_view(&self) -> bool {
+        self.board.can_flip_view() && self.players.len() == 1
+    }
+
     fn flip_view(&mut self) -> bool {
-        if self.mode != Mode::Ring || self.players.len() != 1 {
+        if !self.can_flip_view() {
             return false;
         }
 
@@ -965,16 +1528,28 @@ impl Game {
         &mut self,
         client_id: u64,
         client_prefers_rotating_counter_clockwise: bool,
+        other_keys_cancel_soft_drop: bool,
         key: KeyPress,
     ) -> bool {
-        let player_idx = self
+        // Key presses can arrive just after the player left the game
+        let player_idx = match self
             .players
             .iter()
             .position(|cell| cell.borrow().client_id == client_id)
-            .unwrap();
+        {
+            Some(i) => i,
+            None => {
+                (self.logger)(client_id, "Ignoring key press, not playing anymore");
+                return false;
+            }
+        };
+        self.players[player_idx].borrow_mut().made_input = true;
 
         let need_render = match key {
             KeyPress::Down | KeyPress::Character('S') | KeyPress::Character('s') => {
+                if self.is_counting_down() {
+                    return false;
+                }
                 let mut player = self.players[player_idx].borrow_mut();
                 player.fast_down = true;
                 return false;
@@ -990,40 +1565,80 @@ impl Game {
             }
             KeyPress::Character('F') | KeyPress::Character('f') => self.flip_view(),
             KeyPress::Character('H') | KeyPress::Character('h') => self.hold_block(player_idx),
+            KeyPress::Character('U') | KeyPress::Character('u') => self.undo_landing(player_idx),
+            KeyPress::Character('Y') | KeyPress::Character('y')
+                if self.has_rescue_offer(player_idx) =>
+            {
+                self.answer_rescue(player_idx, true)
+            }
+            KeyPress::Character('N') | KeyPress::Character('n')
+                if self.has_rescue_offer(player_idx) =>
+            {
+                self.answer_rescue(player_idx, false)
+            }
             _ => false,
         };
 
-        self.players[player_idx].borrow_mut().fast_down = false;
+        // Pressing e.g. rotate while moving down fast stops the fast moving by default.
+        // Some players like it, others don't, so it's a setting (C key).
+        if other_keys_cancel_soft_drop {
+            self.players[player_idx].borrow_mut().fast_down = false;
+        }
         need_render
     }
 
+    // Moves without a key press, see AutoShift in game_wrapper.rs
+    pub fn move_sideways(&mut self, client_id: u64, dx: i8) -> bool {
+        match self
+            .players
+            .iter()
+            .position(|cell| cell.borrow().client_id == client_id)
+        {
+            Some(player_idx) => self.move_if_possible(player_idx, dx, 0, false),
+            None => false,
+        }
+    }
+
+    // New blocks never drill, even if they are drills
     fn can_add_block(&self, player_idx: usize, block: &FallingBlock) -> bool {
-        let overlaps = block.get_coords().iter().any(|p| {
-            self.get_any_square(
-                self.players[player_idx].borrow().player_to_world(*p),
-                Some(player_idx),
-            )
+        self.check_block_placement(player_idx, &block.get_coords(), false)
             .is_some()
-        });
-        !overlaps
     }
 
-    fn new_block_possibly_from_hold(&self, player_idx: usize, from_hold_if_possible: bool) {
-        use std::mem::replace;
+    fn new_block_possibly_from_hold(&mut self, player_idx: usize, from_hold_if_possible: bool) {
+        if self.time_limit_reached() {
+            let mut player = self.players[player_idx].borrow_mut();
+            // Swapping with the held block is fine, it doesn't bring in new blocks
+            if !(from_hold_if_possible && player.block_in_hold.is_some()) {
+                player.block_or_timer = BlockOrTimer::WindingDown;
+                player.fast_down = false;
+                return;
+            }
+        }
 
-        let block = {
+        let (block, came_from_hold) = {
             let mut player = self.players[player_idx].borrow_mut();
-            let mut block = if from_hold_if_possible && player.block_in_hold.is_some() {
-                replace(&mut player.block_in_hold, None).unwrap()
+            let came_from_hold = from_hold_if_possible && player.block_in_hold.is_some();
+            let mut block = if came_from_hold {
+                player.block_in_hold.take().unwrap()
             } else {
                 let block = player.next_block_queue.remove(0);
+                *self.block_counts.entry(block.kind).or_insert(0) += 1;
                 if player.next_block_queue.is_empty() {
-                    player.next_block_queue.push((self.normal_block_factory)());
+                    let block = self.create_normal_block(&mut player.block_generator);
+                    player.next_block_queue.push(block);
                 }
                 block
             };
-            block.spawn_at(player.spawn_point);
-            block
+            let spawn_point = player.spawn_point;
+            let spawn_point = self.board.spawn_point_for_block(
+                player_idx,
+                spawn_point,
+                &block,
+                &mut player.block_generator.rng,
+            );
+            block.spawn_at(spawn_point);
+            (block, came_from_hold)
         };
 
         let can_add = self.can_add_block(player_idx, &block);
@@ -1031,30 +1646,88 @@ impl Game {
         if can_add {
             player.block_or_timer = BlockOrTimer::Block(block)
         } else {
+            // Topping out must not lose the block that the player saved for later
+            if came_from_hold {
+                player.block_in_hold = Some(block);
+            }
             player.block_or_timer = BlockOrTimer::TimerPending
         }
         player.fast_down = false;
     }
 
-    fn new_block(&self, player_idx: usize) {
-        self.new_block_possibly_from_hold(player_idx, false);
-        self.maybe_add_special_block_to_random_player();
+    /*
+    After the playing area is cleared, the player continues with the blocks they
+    had: the next block queue and the held block stay as is. The held block can
+    be swapped in again, even if the player already used it before topping out.
+    */
+    fn start_new_life(&mut self, player_idx: usize) {
+        if let Some(block) = &mut self.players[player_idx].borrow_mut().block_in_hold {
+            block.has_been_in_hold = false;
+        }
+        self.new_block(player_idx);
+    }
+
+    fn new_block(&mut self, player_idx: usize) {
+        self.request_new_block(player_idx, None);
     }
 
-    fn hold_block(&self, player_idx: usize) -> bool {
+    // All ways to lose the falling block end up here, so that the next block always comes after the same delay
+    fn request_new_block(&mut self, player_idx: usize, to_hold: Option<FallingBlock>) {
+        {
+            let mut player = self.players[player_idx].borrow_mut();
+            player.block_or_timer = BlockOrTimer::SpawnPending(to_hold);
+            // Soft drop must not continue with the next block
+            player.fast_down = false;
+        }
+        if !self.spawn_delay {
+            self.spawn_pending_block(player_idx);
+        }
+    }
+
+    fn spawn_pending_block(&mut self, player_idx: usize) -> bool {
+        let to_hold = match &mut self.players[player_idx].borrow_mut().block_or_timer {
+            BlockOrTimer::SpawnPending(to_hold) => to_hold.take(),
+            _ => return false,
+        };
+        match to_hold {
+            Some(to_hold) => {
+                self.new_block_possibly_from_hold(player_idx, true);
+                let mut player = self.players[player_idx].borrow_mut();
+                // If the held block didn't fit, it went back to hold and this block is lost
+                if player.block_in_hold.is_none() {
+                    player.block_in_hold = Some(to_hold);
+                }
+            }
+            None => {
+                self.new_block_possibly_from_hold(player_idx, false);
+                self.maybe_add_special_block_to_random_player();
+            }
+        }
+        true
+    }
+
+    // Returns indexes of players who got a new block
+    fn spawn_pending_blocks(&mut self) -> Vec<usize> {
+        (0..self.players.len())
+            .filter(|i| self.spawn_pending_block(*i))
+            .collect()
+    }
+
+    fn hold_block(&mut self, player_idx: usize) -> bool {
         use std::mem::replace;
 
         let mut to_hold = match &mut self.players[player_idx].borrow_mut().block_or_timer {
             BlockOrTimer::Block(b) if !b.has_been_in_hold => {
                 // Replace the block with a dummy value.
                 // It will be overwritten soon anyway.
-                replace(b, (self.normal_block_factory)())
+                replace(b, FallingBlock::normal_from_shape(Shape::O))
             }
             _ => return false,
         };
-        self.new_block_possibly_from_hold(player_idx, true);
+        // Undoing would bring back a block that is now in hold
+        self.undo_snapshot = None;
         to_hold.has_been_in_hold = true;
-        self.players[player_idx].borrow_mut().block_in_hold = Some(to_hold);
+        self.request_new_block(player_idx, Some(to_hold));
         true
     }
 
@@ -1095,18 +1768,20 @@ impl Game {
             }
 
             if let BlockOrTimer::Block(falling_block) = &mut player.block_or_timer {
+                // Zipping instead of indexing, so that nothing can go out of range
                 let old_len = player_coords.len();
-                assert!(old_len != 0);
-
-                // see example in retain docs
-                let mut world_coord_iter = world_coords.iter();
-                player_coords.retain(|_| {
-                    f(
-                        *world_coord_iter.next().unwrap(),
-                        &mut falling_block.square_content,
-                        Some(player_idx),
-                    )
-                });
+                player_coords = player_coords
+                    .iter()
+                    .zip(&world_coords)
+                    .filter(|(_, world_point)| {
+                        f(
+                            **world_point,
+                            &mut falling_block.square_content,
+                            Some(player_idx),
+                        )
+                    })
+                    .map(|(player_point, _)| *player_point)
+                    .collect();
 
                 if player_coords.is_empty() {
                     // can't call new_block() here, because player is already borrowed
@@ -1143,6 +1818,8 @@ impl Game {
         old_flashing_points: &[WorldPoint],
     ) -> Vec<WorldPoint> {
         let mut bomb_locations = vec![];
+        // Undoing would bring back what exploded
+        self.undo_snapshot = None;
 
         self.filter_and_mutate_all_squares_in_place(|point, content, _| {
             if content.is_bomb()
@@ -1151,7 +1828,15 @@ impl Game {
             {
                 bomb_locations.push(point);
             }
-            !old_flashing_points.contains(&point)
+            if !old_flashing_points.contains(&point) {
+                return true;
+            }
+            // Reinforced squares survive one explosion
+            if let SquareContent::Normal(_, _, reinforced @ true) = content {
+                *reinforced = false;
+                return true;
+            }
+            false
         });
 
         bomb_locations
@@ -1212,6 +1897,25 @@ impl Game {
         }
     }
 
+    // In zen mode, there's no waiting time when you fill your playing area.
+    // Instead, the board clears some of it, see BoardBehavior::clear_filled_area().
+    // Returns true if something was cleared.
+    pub fn handle_pending_zen_clears(&mut self) -> bool {
+        let mut cleared = false;
+        for player_idx in 0..self.players.len() {
+            let pending = matches!(
+                self.players[player_idx].borrow().block_or_timer,
+                BlockOrTimer::TimerPending
+            );
+            if pending && self.board.clear_filled_area(&mut self.landed_rows) {
+                self.undo_snapshot = None;
+                self.start_new_life(player_idx);
+                cleared = true;
+            }
+        }
+        cleared
+    }
+
     // returns None if everyone end up waiting, i.e. if game is over
     pub fn start_pending_please_wait_counters(&mut self) -> Option<Vec<u64>> {
         let mut client_ids = vec![];
@@ -1223,10 +1927,14 @@ impl Game {
             }
         }
 
-        if self
-            .players
-            .iter()
-            .all(|p| matches!(p.borrow().block_or_timer, BlockOrTimer::Timer(_)))
+        if self.time_limit == TimeLimit::Reached {
+            return None;
+        }
+        if self.waiting_ends_game
+            && self
+                .players
+                .iter()
+                .all(|p| matches!(p.borrow().block_or_timer, BlockOrTimer::Timer(_)))
         {
             None
         } else {
@@ -1244,7 +1952,11 @@ impl Game {
             let need_reset = {
                 let mut player = self.players[i].borrow_mut();
                 match player.block_or_timer {
-                    BlockOrTimer::Timer(0) => panic!(),
+                    BlockOrTimer::Timer(0) => {
+                        drop(player);
+                        self.report_invariant_violation(i, "please wait counter is already 0");
+                        true // reset, so that the player can continue playing
+                    }
                     BlockOrTimer::Timer(1) => true, // need reset
                     BlockOrTimer::Timer(n) => {
                         player.block_or_timer = BlockOrTimer::Timer(n - 1);
@@ -1255,45 +1967,14 @@ impl Game {
             };
             if need_reset {
                 self.clear_playing_area(i);
-                self.new_block(i);
+                self.start_new_life(i);
             }
         }
         false
     }
 
     fn clear_playing_area(&mut self, player_idx: usize) {
-        match self.mode {
-            Mode::Traditional => {
-                let w = self.get_width_per_player().unwrap();
-                let left = w * player_idx;
-                let right = w * (player_idx + 1);
-                for row in self.landed_rows.iter_mut() {
-                    for square_ref in row[left..right].iter_mut() {
-                        *square_ref = None;
-                    }
-                }
-            }
-            Mode::Bottle => {
-                let left = BOTTLE_OUTER_WIDTH * player_idx;
-                let right = left + BOTTLE_INNER_WIDTH;
-                for row in self.landed_rows.iter_mut() {
-                    for square_ref in row[left..right].iter_mut() {
-                        *square_ref = None;
-                    }
-                }
-            }
-            Mode::Ring => {
-                for y_abs in 0..=(RING_OUTER_RADIUS as i32) {
-                    for x in (-y_abs)..=y_abs {
-                        let point = self.players[player_idx]
-                            .borrow()
-                            .player_to_world((x, -y_abs));
-                        if self.is_valid_landed_block_coords(point) {
-                            self.set_landed_square(point, None);
-                        }
-                    }
-                }
-            }
-        }
+        self.board
+            .clear_region_for_player(&mut self.landed_rows, &self.players, player_idx);
     }
 }
@@ -1,12 +1,28 @@
 // This module contains pure game logic. IO and async are done elsewhere.
 pub mod blocks;
+pub mod board;
+pub mod bottle;
 pub mod game;
 pub mod player;
+pub mod ring;
+pub mod traditional;
 
+#[cfg(test)]
+mod bomb_test;
 #[cfg(test)]
 mod test;
 
-// PlayerPoint numbers must be big, they don't wrap around in ring mode
+// In ring mode, PlayerPoint y is outside the ring above the top edge and briefly after
+// crossing the bottom edge, see RingBoard::wrap_falling_block()
 pub type PlayerPoint = (i32, i32); // player-specific in ring mode, (0,1) = downwards
 pub type WorldPoint = (i16, i16); // the same for all players, differs from PlayerPoint only in ring mode
 pub type BlockRelativeCoords = (i8, i8); // (0,0) = center of falling block
+
+// Everything the game needs to know about a player when they join
+pub struct PlayerSpec {
+    pub client_id: u64,
+    pub lobby_order: u64, // Game::players is sorted by this
+    pub name: String,
+    pub color: u8,
+    pub handicap: u8, // number of garbage rows given to the player in traditional games
+}
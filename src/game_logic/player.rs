@@ -1,16 +1,20 @@
+use crate::game_logic::blocks::BlockGenerator;
 use crate::game_logic::blocks::FallingBlock;
-use crate::game_logic::game::wrap_around;
-use crate::game_logic::game::Mode;
-use crate::game_logic::game::RING_OUTER_RADIUS;
+use crate::game_logic::board::PlayerGeometry;
+use crate::game_logic::game::STALL_TICKS;
 use crate::game_logic::PlayerPoint;
+use crate::game_logic::PlayerSpec;
 use crate::game_logic::WorldPoint;
-use crate::lobby::ClientInfo;
 
 #[derive(Debug)]
 pub enum BlockOrTimer {
     Block(FallingBlock),
+    // The falling block is gone, see Game::spawn_pending_block().
+    // Contains the block that goes to hold when the next block spawns, if any.
+    SpawnPending(Option<FallingBlock>),
     TimerPending,
     Timer(u8),
+    WindingDown, // no more blocks, waiting for the game to end, see Game::start_wind_down()
 }
 impl BlockOrTimer {
     pub fn get_coords(&self) -> Vec<PlayerPoint> {
@@ -21,9 +25,18 @@ impl BlockOrTimer {
     }
 }
 
+// A player whose stack is much higher than others' can clear a part of it, once per game
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Rescue {
+    NotOffered,
+    Offered,
+    Answered, // accepted or declined
+}
+
 #[derive(Debug)]
 pub struct Player {
     pub client_id: u64,
+    pub lobby_order: u64, // Game::players is sorted by this
     pub name: String,
     pub color: u8,
     pub spawn_point: PlayerPoint,
@@ -32,37 +45,50 @@ pub struct Player {
     pub block_in_hold: Option<FallingBlock>,
     pub fast_down: bool,
     pub down_direction: WorldPoint, // this vector always has length 1
-    game_mode: Mode,
+    pub landed_count: usize,        // clients ring a bell when this changes
+    pub block_generator: BlockGenerator, // seeded if the game has a seed
+    pub rescue: Rescue,
+    pub made_input: bool, // see Game::get_counted_player_count()
+    geometry: PlayerGeometry,
 }
 
 impl Player {
     pub fn new(
         spawn_point: PlayerPoint,
-        client_info: &ClientInfo,
+        spec: &PlayerSpec,
         down_direction: WorldPoint,
-        game_mode: Mode,
+        geometry: PlayerGeometry,
         first_block: FallingBlock,
         second_block: FallingBlock,
+        block_generator: BlockGenerator,
     ) -> Self {
         Self {
-            client_id: client_info.client_id,
-            name: client_info.name.to_string(),
-            color: client_info.color,
+            client_id: spec.client_id,
+            lobby_order: spec.lobby_order,
+            name: spec.name.to_string(),
+            color: spec.color,
             spawn_point,
             block_or_timer: BlockOrTimer::Block(first_block),
             next_block_queue: vec![second_block],
             block_in_hold: None,
             fast_down: false,
             down_direction,
-            game_mode,
+            landed_count: 0,
+            block_generator,
+            rescue: Rescue::NotOffered,
+            made_input: false,
+            geometry,
         }
     }
 
     pub fn get_name_string(&self, max_len: usize) -> String {
         let mut name = self.name.clone();
         loop {
-            let formatted: String = match self.block_or_timer {
+            let formatted: String = match &self.block_or_timer {
                 BlockOrTimer::Timer(n) => format!("[{}] {}", name, n),
+                BlockOrTimer::Block(b) if b.idle_ticks > STALL_TICKS => {
+                    format!("{} (stalling)", name)
+                }
                 _ => name.clone(),
             };
             if formatted.chars().count() <= max_len {
@@ -76,14 +102,11 @@ impl Player {
 
     pub fn player_to_world(&self, point: PlayerPoint) -> WorldPoint {
         let (x, mut y) = point;
-        wrap_around(self.game_mode, &mut y);
+        self.geometry.wrap_around(&mut y);
         let x = x as i16;
         let y = y as i16;
         let (down_x, down_y) = self.down_direction;
-        let (offset_x, offset_y) = match self.game_mode {
-            Mode::Traditional | Mode::Bottle => (0, 0),
-            Mode::Ring => (RING_OUTER_RADIUS, RING_OUTER_RADIUS),
-        };
+        let (offset_x, offset_y) = self.geometry.center;
 
         // a couple ways to derive this: complex number multiplication, rotation matrices
         // to check, it should return the point unchanged when down_direction is the usual (0,1)
@@ -0,0 +1,290 @@
+use crate::game_logic::blocks::FallingBlock;
+use crate::game_logic::board::try_get_square;
+use crate::game_logic::board::try_set_square;
+use crate::game_logic::board::AddPlayerEffects;
+use crate::game_logic::board::BoardBehavior;
+use crate::game_logic::board::FullGroups;
+use crate::game_logic::board::LandedRows;
+use crate::game_logic::board::PlayerGeometry;
+use crate::game_logic::game::Game;
+use crate::game_logic::player::Player;
+use crate::game_logic::PlayerPoint;
+use crate::game_logic::WorldPoint;
+use rand::rngs::StdRng;
+use std::cell::RefCell;
+use std::cmp::max;
+
+pub const RING_MAP: &[&str] = &[
+    "               .o------------------------------------------o.               ",
+    "             .'xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx'.             ",
+    "           .'xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx'.           ",
+    "         .'xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx'.         ",
+    "       .'xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx'.       ",
+    "     .'xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx'.     ",
+    "   .'xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx'.   ",
+    " .'xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx'. ",
+    "oxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxo",
+    "|xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx|",
+    "|xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx|",
+    "|xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx|",
+    "|xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx|",
+    "|xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx|",
+    "|xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx|",
+    "|xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx|",
+    "|xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxo============oxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx|",
+    "|xxxxxxxxxxxxxxxxxxxxxxxxxxxxxx|wwwwwwwwwwww|xxxxxxxxxxxxxxxxxxxxxxxxxxxxxx|",
+    "|xxxxxxxxxxxxxxxxxxxxxxxxxxxxxx|aaaaaadddddd|xxxxxxxxxxxxxxxxxxxxxxxxxxxxxx|",
+    "|xxxxxxxxxxxxxxxxxxxxxxxxxxxxxx|aaaaaadddddd|xxxxxxxxxxxxxxxxxxxxxxxxxxxxxx|",
+    "|xxxxxxxxxxxxxxxxxxxxxxxxxxxxxx|aaaaaadddddd|xxxxxxxxxxxxxxxxxxxxxxxxxxxxxx|",
+    "|xxxxxxxxxxxxxxxxxxxxxxxxxxxxxx|ssssssssssss|xxxxxxxxxxxxxxxxxxxxxxxxxxxxxx|",
+    "|xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxo------------oxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx|",
+    "|xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx|",
+    "|xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx|",
+    "|xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx|",
+    "|xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx|",
+    "|xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx|",
+    "|xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx|",
+    "|xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx|",
+    "oxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxo",
+    " '.xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx.' ",
+    "   '.xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx.'   ",
+    "     '.xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx.'     ",
+    "       '.xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx.'       ",
+    "         '.xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx.'         ",
+    "           '.xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx.'           ",
+    "             '.xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx.'             ",
+    "               'o------------------------------------------o'               ",
+];
+pub const RING_OUTER_RADIUS: i16 = 18;
+pub const RING_INNER_RADIUS: i16 = 3; // the square in the middle, where names are shown
+
+fn is_on_ring_map(point: WorldPoint) -> bool {
+    let (x, y) = point;
+    let size = 2 * RING_OUTER_RADIUS + 1;
+    (0..size).contains(&x)
+        && (0..size).contains(&y)
+        && RING_MAP[y as usize + 1].as_bytes()[2 * x as usize + 1] == b'x'
+}
+
+/*
+The points with max(|x|,|y|) == r, with x and y relative to the middle of ring game.
+Returned in world coordinates. Each corner appears once, so there are 8r points,
+except that the outer rings have fewer, because the corners of RING_MAP are rounded.
+A ring is full when all of these points are full.
+*/
+pub fn ring_points(r: i16) -> Vec<WorldPoint> {
+    let mut result = vec![(-r, -r), (-r, r), (r, -r), (r, r)];
+    for i in (-r + 1)..r {
+        result.push((-r, i));
+        result.push((r, i));
+        result.push((i, -r));
+        result.push((i, r));
+    }
+    debug_assert_eq!(result.len(), 8 * r as usize);
+    result
+        .iter()
+        .map(|(x, y)| (RING_OUTER_RADIUS + x, RING_OUTER_RADIUS + y))
+        .filter(|p| is_on_ring_map(*p))
+        .collect()
+}
+
+fn ring_radius(point: WorldPoint) -> i16 {
+    let (x, y) = point;
+    max((x - RING_OUTER_RADIUS).abs(), (y - RING_OUTER_RADIUS).abs())
+}
+
+// Idea: Move towards the center of ring mode game, at most one unit in x and one in y.
+// Only corner points (at 45deg from center) move in both x and y directions.
+fn towards_ring_mode_center(point: WorldPoint) -> WorldPoint {
+    let (mut x, mut y) = point;
+    let rx = x - RING_OUTER_RADIUS;
+    let ry = y - RING_OUTER_RADIUS;
+    if rx.abs() >= ry.abs() {
+        x -= rx.signum();
+    }
+    if ry.abs() >= rx.abs() {
+        y -= ry.signum();
+    }
+    (x, y)
+}
+
+// Counts rows between the middle square and the highest landed square in the
+// player's quadrant, i.e. the area that the player's blocks fall through.
+pub fn get_ring_stack_height(game: &Game, player_idx: usize) -> usize {
+    let quadrant_height = (RING_OUTER_RADIUS - RING_INNER_RADIUS) as usize;
+    quadrant_height.saturating_sub(game.get_free_rows_above_stack(player_idx))
+}
+
+// Players come from all four sides and blocks fall towards the middle
+pub struct RingBoard;
+
+impl BoardBehavior for RingBoard {
+    fn initial_rows(&self) -> LandedRows {
+        let size = (2 * RING_OUTER_RADIUS + 1) as usize;
+        vec![vec![None; size]; size]
+    }
+
+    fn width(&self, _player_count: usize, rows: &LandedRows) -> usize {
+        rows[0].len()
+    }
+
+    fn bounds(&self, _width: usize, _height: usize) -> (i32, i32, i32, i32) {
+        let r = RING_OUTER_RADIUS as i32;
+        (-r, r + 1, -r, r + 1)
+    }
+
+    fn top_y(&self) -> i32 {
+        -(RING_OUTER_RADIUS as i32)
+    }
+
+    fn is_valid_landed_coords(&self, point: WorldPoint, _width: usize, _height: usize) -> bool {
+        is_on_ring_map(point)
+    }
+
+    fn player_geometry(&self) -> PlayerGeometry {
+        PlayerGeometry {
+            center: (RING_OUTER_RADIUS, RING_OUTER_RADIUS),
+            lap_radius: Some(RING_OUTER_RADIUS as i32),
+        }
+    }
+
+    // A falling block that went all the way around the ring is moved back by one lap,
+    // so that its coordinates stay small. This is done only after the whole block has
+    // crossed the seam, because squares above the top edge don't wrap around.
+    fn wrap_falling_block(&self, block: &mut FallingBlock) {
+        let r = RING_OUTER_RADIUS as i32;
+        if block.get_coords().iter().all(|(_, y)| *y > r) {
+            block.m0v3(0, -((2 * RING_OUTER_RADIUS + 1) as i8));
+        }
+    }
+
+    fn blocks_can_stall(&self) -> bool {
+        true
+    }
+
+    fn can_flip_view(&self) -> bool {
+        true
+    }
+
+    fn tracks_cleared_squares(&self) -> bool {
+        true
+    }
+
+    fn down_direction_for_new_player(&self, used: &[WorldPoint]) -> Option<WorldPoint> {
+        /*
+        prefer opposite directions of existing players
+        never choose a direction that is already in use
+        choose consistently, not randomly or depending on hashing
+        */
+        let opposites: Vec<WorldPoint> = used.iter().map(|(x, y)| (-x, -y)).collect();
+        let all: &[WorldPoint] = &[(0, 1), (0, -1), (1, 0), (-1, 0)];
+
+        opposites
+            .iter()
+            .chain(all.iter())
+            .find(|dir| !used.contains(dir))
+            .copied()
+    }
+
+    fn spawn_point_for(&self, _player_idx: usize, _player_count: usize) -> PlayerPoint {
+        (0, -(RING_OUTER_RADIUS as i32))
+    }
+
+    // The quadrant between the player's edge and the middle square
+    fn is_in_player_region(&self, point: PlayerPoint, _idx: usize, _count: usize) -> bool {
+        let (x, y) = point;
+        -y > RING_INNER_RADIUS as i32 && x.abs() <= -y
+    }
+
+    fn add_player(
+        &mut self,
+        rows: &mut LandedRows,
+        players: &[RefCell<Player>],
+        player_idx: usize,
+        _handicap: u8,
+        _rng: &mut StdRng,
+    ) -> AddPlayerEffects {
+        self.clear_region_for_player(rows, players, player_idx);
+        AddPlayerEffects::default()
+    }
+
+    fn remove_player(
+        &mut self,
+        _rows: &mut LandedRows,
+        _players: &[RefCell<Player>],
+        _player_idx: usize,
+    ) -> Vec<usize> {
+        vec![]
+    }
+
+    fn clear_region_for_player(
+        &self,
+        rows: &mut LandedRows,
+        players: &[RefCell<Player>],
+        player_idx: usize,
+    ) {
+        let player = players[player_idx].borrow();
+        for y_abs in 0..=(RING_OUTER_RADIUS as i32) {
+            for x in (-y_abs)..=y_abs {
+                let point = player.player_to_world((x, -y_abs));
+                if is_on_ring_map(point) {
+                    let (x, y) = point;
+                    rows[y as usize][x as usize] = None;
+                }
+            }
+        }
+    }
+
+    fn find_full_groups(&self, rows: &LandedRows, _player_count: usize) -> FullGroups {
+        let mut result = FullGroups::default();
+        for r in (RING_INNER_RADIUS + 1)..=RING_OUTER_RADIUS {
+            let points = ring_points(r);
+            if points
+                .iter()
+                .all(|p| try_get_square(rows, *p).flatten().is_some())
+            {
+                result.everyone_count += 1;
+                result.points.extend(points);
+            }
+        }
+        result
+    }
+
+    fn shift_after_clear(&self, rows: &mut LandedRows, full: &[WorldPoint], _player_count: usize) {
+        for &point in full {
+            try_set_square(rows, point, None);
+        }
+
+        // A ring is removed only if all of its points were full, not just some of them
+        let mut counts = vec![0; RING_OUTER_RADIUS as usize + 1];
+        for point in full {
+            counts[ring_radius(*point) as usize] += 1;
+        }
+
+        // Removing a ring shifts outer radiuses, so remove outermost rings first.
+        for (r, count) in counts.iter().enumerate().rev() {
+            if *count == 0 || *count != ring_points(r as i16).len() {
+                continue;
+            }
+            let r = r as i16;
+
+            // Delete the squares that flashed
+            for point in ring_points(r) {
+                try_set_square(rows, point, None);
+            }
+
+            // Shift outer squares inwards
+            for source_r in (r + 1)..=RING_OUTER_RADIUS {
+                for source in ring_points(source_r) {
+                    let dest = towards_ring_mode_center(source);
+                    let square = try_get_square(rows, source).flatten();
+                    // Don't replace already moved non-blanks with blanks.
+                    // This makes a difference in corners where multiple places merge.
+                    if square.is_some() && try_set_square(rows, dest, square) {
+                        try_set_square(rows, source, None);
+                    }
+                }
+            }
+        }
+    }
+}
@@ -1,31 +1,52 @@
 use crate::escapes::Color;
 use crate::escapes::KeyPress;
-use crate::escapes::TerminalType;
+use crate::game_logic::blocks::shape_weights;
+use crate::game_logic::blocks::BlockGenerator;
+use crate::game_logic::blocks::BlockKind;
 use crate::game_logic::blocks::BlockType;
 use crate::game_logic::blocks::FallingBlock;
 use crate::game_logic::blocks::Shape;
 use crate::game_logic::blocks::SquareContent;
+use crate::game_logic::board::AddPlayerEffects;
+use crate::game_logic::board::BoardBehavior;
+use crate::game_logic::board::BoardOptions;
+use crate::game_logic::board::FullGroups;
+use crate::game_logic::board::LandedRows;
+use crate::game_logic::bottle::choose_bottle_spawn_x;
+use crate::game_logic::bottle::get_basin_split;
+use crate::game_logic::bottle::BottleNecks;
+use crate::game_logic::game::format_score;
+use crate::game_logic::game::score_multiplier;
+use crate::game_logic::game::square_content_to_string;
 use crate::game_logic::game::Game;
+use crate::game_logic::game::LeaveReason;
 use crate::game_logic::game::Mode;
-use crate::game_logic::game::RING_OUTER_RADIUS;
+use crate::game_logic::game::COUNTDOWN_DURATION;
+use crate::game_logic::game::FLASHING_POINT_EXPIRY;
+use crate::game_logic::game::MAX_SCORE;
+use crate::game_logic::game::REINFORCED_ROWS;
+use crate::game_logic::game::STALL_TICKS;
 use crate::game_logic::player::BlockOrTimer;
-use crate::game_logic::BlockRelativeCoords;
+use crate::game_logic::player::Player;
+use crate::game_logic::player::Rescue;
+use crate::game_logic::ring::get_ring_stack_height;
+use crate::game_logic::ring::ring_points;
+use crate::game_logic::ring::RingBoard;
+use crate::game_logic::ring::RING_INNER_RADIUS;
+use crate::game_logic::ring::RING_OUTER_RADIUS;
+use crate::game_logic::PlayerPoint;
+use crate::game_logic::PlayerSpec;
 use crate::game_logic::WorldPoint;
-use crate::lobby::ClientInfo;
-use crate::RenderBuffer;
+use rand::rngs::StdRng;
 use rand::Rng;
+use rand::SeedableRng;
+use std::cell::RefCell;
+use std::cmp::max;
+use std::collections::HashMap;
 use std::collections::HashSet;
-
-fn square_content_to_string(
-    content: SquareContent,
-    falling_block_data: Option<(BlockRelativeCoords, (i8, i8))>,
-) -> String {
-    let mut buffer = RenderBuffer::new(TerminalType::Ansi);
-    buffer.resize(80, 24); // smallest size allowed
-    content.render(&mut buffer, 0, 0, falling_block_data, (0, 1));
-    let chars = [buffer.get_char(0, 0), buffer.get_char(1, 0)];
-    chars.iter().collect::<String>()
-}
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Instant;
 
 fn dump_game_state(game: &Game) -> Vec<String> {
     let mut result: Vec<String> = vec![];
@@ -35,7 +56,7 @@ fn dump_game_state(game: &Game) -> Vec<String> {
     let mut y_coords: Vec<Option<i32>> = vec![];
 
     match game.mode {
-        Mode::Traditional => {
+        Mode::Traditional | Mode::Zen => {
             x_coords.append(&mut (x_top..x_bottom).map(Some).collect());
             y_coords.append(&mut (0..(game.get_height() as i32)).map(Some).collect());
         }
@@ -102,23 +123,66 @@ fn dump_game_state(game: &Game) -> Vec<String> {
     result
 }
 
-fn create_game(mode: Mode, player_count: usize, shape: Shape) -> Game {
+// Game::debug_dump() shows everything, dump_game_state() only the interesting parts
+fn assert_debug_dump_matches(game: &Game) {
+    let full = game.debug_dump();
+    let partial = dump_game_state(game);
+    match game.mode {
+        Mode::Traditional | Mode::Zen => assert_eq!(full, partial),
+        Mode::Bottle => {
+            assert_eq!(full[..4], partial[..4]);
+            assert_eq!(full[(full.len() - 4)..], partial[5..]);
+        }
+        Mode::Ring => {
+            // Compare the middle part, with x and y between -7 and 7
+            let r = RING_OUTER_RADIUS as usize;
+            for i in 0..15 {
+                let full_row = &full[r - 7 + i];
+                assert_eq!(
+                    full_row[(2 * (r - 7))..(2 * (r + 8))],
+                    partial[4 + i][7..37]
+                );
+            }
+        }
+    }
+}
+
+// Tests don't care about anything else than the client ID
+pub(super) fn player_spec(client_id: u64) -> PlayerSpec {
+    PlayerSpec {
+        name: format!("Player {}", client_id),
+        client_id,
+        lobby_order: client_id,
+        color: Color::RED_FOREGROUND.fg,
+        handicap: 0,
+    }
+}
+
+// Players get client IDs 0, 1, 2, ... and setup runs before they join,
+// because e.g. the board can't change after that
+pub(super) fn create_game_with(
+    mode: Mode,
+    player_count: usize,
+    setup: impl FnOnce(&mut Game),
+) -> Game {
     let mut game = Game::new(mode);
-    game.set_normal_block_factory(match shape {
-        Shape::L => || FallingBlock::normal_from_shape(Shape::L),
-        Shape::S => || FallingBlock::normal_from_shape(Shape::S),
-        _ => unimplemented!(),
-    });
-    for i in 0..player_count {
-        game.add_player(&ClientInfo {
-            name: format!("Player {}", i),
-            client_id: i as u64,
-            color: Color::RED_FOREGROUND.fg,
-        });
+    setup(&mut game);
+    for client_id in 0..(player_count as u64) {
+        game.add_player(&player_spec(client_id));
     }
     game
 }
 
+fn create_game(mode: Mode, player_count: usize, shape: Shape) -> Game {
+    create_game_with(mode, player_count, |game| {
+        game.set_normal_block_factory(match shape {
+            Shape::L => |_| FallingBlock::normal_from_shape(Shape::L),
+            Shape::S => |_| FallingBlock::normal_from_shape(Shape::S),
+            _ => unimplemented!(),
+        })
+    })
+}
+
 #[test]
 fn test_spawning_and_landing_and_game_over() {
     let mut game = create_game(Mode::Traditional, 1, Shape::L);
@@ -154,9 +218,11 @@ fn test_spawning_and_landing_and_game_over() {
             "        FFFFFF      ",
         ]
     );
+    assert_eq!(game.get_landed_block_count(), 0);
 
     // This move lands the blocks and prepares a new block that is initially off-screen.
     game.move_blocks_down(false);
+    assert_eq!(game.get_landed_block_count(), 1);
     assert_eq!(
         dump_game_state(&game),
         [
@@ -195,11 +261,160 @@ fn test_spawning_and_landing_and_game_over() {
     assert!(game.start_pending_please_wait_counters().is_none());
 }
 
+#[test]
+fn test_zen_mode_clears_instead_of_waiting() {
+    let mut game = create_game(Mode::Zen, 1, Shape::L);
+    game.truncate_height(4);
+
+    while !matches!(
+        game.players[0].borrow().block_or_timer,
+        BlockOrTimer::TimerPending
+    ) {
+        game.move_blocks_down(false);
+    }
+    assert_eq!(
+        dump_game_state(&game),
+        [
+            "            LL      ",
+            "        LLLLLL      ",
+            "            LL      ",
+            "        LLLLLL      ",
+        ]
+    );
+
+    // Bottom half is cleared, rest moves down, and the player gets a new block
+    assert!(game.handle_pending_zen_clears());
+    assert_eq!(
+        dump_game_state(&game),
+        [
+            "                    ",
+            "                    ",
+            "            LL      ",
+            "        LLLLLL      ",
+        ]
+    );
+    assert!(matches!(
+        game.players[0].borrow().block_or_timer,
+        BlockOrTimer::Block(_)
+    ));
+    assert_eq!(game.start_pending_please_wait_counters(), Some(vec![]));
+    assert!(!game.handle_pending_zen_clears());
+}
+
+#[test]
+fn test_block_counts() {
+    let mut game = create_game(Mode::Traditional, 1, Shape::L);
+    let l_count = |game: &Game| game.get_block_counts()[&BlockKind::Normal(Shape::L)];
+    assert_eq!(l_count(&game), 1);
+
+    // First hold takes a new block from the queue, second hold only swaps
+    game.handle_key_press(0, false, true, KeyPress::Character('h'));
+    assert_eq!(l_count(&game), 2);
+    game.handle_key_press(0, false, true, KeyPress::Character('h'));
+    assert_eq!(l_count(&game), 2);
+
+    for _ in 0..5 {
+        game.handle_key_press(0, false, true, KeyPress::Down);
+        while game.move_blocks_down(true) {}
+        game.move_blocks_down(false);
+    }
+    assert_eq!(l_count(&game), 7);
+    assert_eq!(game.get_block_counts().len(), 1);
+    assert_eq!(
+        game.format_block_counts(),
+        "L:7 I:0 J:0 O:0 T:0 S:0 Z:0 cursed:0 drill:0 bomb:0 linebomb:0"
+    );
+}
+
+// With other_randomness, something else uses the players' random number generators between blocks
+fn get_seeded_block_kinds(
+    seed: u64,
+    shared_block_sequence: bool,
+    other_randomness: bool,
+) -> Vec<Vec<BlockKind>> {
+    let mut game = create_game_with(Mode::Traditional, 2, |game| {
+        game.set_block_seed(seed, shared_block_sequence)
+    });
+
+    // Holding takes a new block from the queue when nothing is in hold yet
+    (0..2)
+        .map(|player_idx| {
+            (0..20)
+                .map(|_| {
+                    let kind = match &game.players[player_idx].borrow().block_or_timer {
+                        BlockOrTimer::Block(block) => block.kind,
+                        _ => panic!(),
+                    };
+                    game.handle_key_press(player_idx as u64, false, true, KeyPress::Character('h'));
+                    game.players[player_idx].borrow_mut().block_in_hold = None;
+                    if other_randomness {
+                        let mut player = game.players[player_idx].borrow_mut();
+                        let _ = FallingBlock::new(
+                            BlockType::Bomb { initial_timer: 5 },
+                            &mut player.block_generator.rng,
+                        );
+                    }
+                    kind
+                })
+                .collect()
+        })
+        .collect()
+}
+
+#[test]
+fn test_block_seed() {
+    let kinds = get_seeded_block_kinds(123, false, false);
+    assert_eq!(kinds, get_seeded_block_kinds(123, false, false));
+    assert_ne!(kinds, get_seeded_block_kinds(456, false, false));
+    assert_ne!(kinds[0], kinds[1]);
+
+    let shared = get_seeded_block_kinds(123, true, false);
+    assert_eq!(shared[0], shared[1]);
+
+    // Special blocks and spawn points don't change the sequence
+    assert_eq!(kinds, get_seeded_block_kinds(123, false, true));
+    assert_eq!(shared, get_seeded_block_kinds(123, true, true));
+}
+
+#[test]
+fn test_handicap() {
+    let mut game = Game::new(Mode::Traditional);
+    game.truncate_height(5);
+    for (client_id, handicap) in [(0, 0), (1, 3)] {
+        game.add_player(&PlayerSpec {
+            handicap,
+            ..player_spec(client_id)
+        });
+    }
+
+    // Player 1 gets 3 garbage rows with one random hole in each
+    let dump = dump_game_state(&game);
+    assert_eq!(dump[0], " ".repeat(40));
+    assert_eq!(dump[1], " ".repeat(40));
+    for row in &dump[2..] {
+        let (player0_part, player1_part) = row.split_at(20);
+        assert_eq!(player0_part, " ".repeat(20));
+        assert_eq!(player1_part.matches("LL").count(), 9);
+        assert_eq!(player1_part.matches("  ").count(), 1);
+    }
+
+    // Garbage disappears for good when the playing area is cleared
+    game.players[1].borrow_mut().block_or_timer = BlockOrTimer::Timer(1);
+    game.tick_please_wait_counter(1);
+    assert!(dump_game_state(&game)
+        .iter()
+        .all(|row| row.trim().is_empty()));
+}
+
 #[test]
 fn test_wait_counters() {
     let mut game = create_game(Mode::Traditional, 2, Shape::L);
     game.truncate_height(3);
 
+    // Leave room for player 0's next block
+    game.handle_key_press(0, false, true, KeyPress::Right);
+    game.handle_key_press(0, false, true, KeyPress::Right);
+
     game.move_blocks_down(false);
     game.move_blocks_down(false);
     game.move_blocks_down(false);
@@ -208,9 +423,9 @@ fn test_wait_counters() {
     assert_eq!(
         dump_game_state(&game),
         [
-            "    FFFFFF        FFFFFF    ",
-            "            LL        LL    ",
-            "        LLLLLL    LLLLLL    ",
+            "        FFFFFF              FFFFFF      ",
+            "                LL              LL      ",
+            "            LLLLLL          LLLLLL      ",
         ]
     );
     assert_eq!(game.start_pending_please_wait_counters(), Some(vec![]));
@@ -220,9 +435,9 @@ fn test_wait_counters() {
     assert_eq!(
         dump_game_state(&game),
         [
-            "        FF                  ",
-            "    FFFFFF  LL        LL    ",
-            "        LLLLLL    LLLLLL    ",
+            "            FF                          ",
+            "        FFFFFF  LL              LL      ",
+            "            LLLLLL          LLLLLL      ",
         ]
     );
     assert!(matches!(
@@ -250,6 +465,63 @@ fn test_wait_counters() {
     ));
 }
 
+fn falling_block_kind(game: &Game, player_idx: usize) -> BlockKind {
+    match &game.players[player_idx].borrow().block_or_timer {
+        BlockOrTimer::Block(block) => block.kind,
+        other => panic!("no falling block: {:?}", other),
+    }
+}
+
+#[test]
+fn test_hold_is_kept_after_top_out() {
+    let mut game = create_game(Mode::Traditional, 1, Shape::L);
+    game.set_waiting_ends_game(false);
+    game.truncate_height(3);
+
+    // Hold the L, then swap it with the I that comes next, so that the I is in hold
+    game.players[0].borrow_mut().next_block_queue[0] = FallingBlock::normal_from_shape(Shape::I);
+    assert!(game.handle_key_press(0, false, true, KeyPress::Character('h')));
+    assert!(game.handle_key_press(0, false, true, KeyPress::Character('h')));
+    assert_eq!(falling_block_kind(&game, 0), BlockKind::Normal(Shape::L));
+
+    while !matches!(
+        game.players[0].borrow().block_or_timer,
+        BlockOrTimer::TimerPending
+    ) {
+        game.move_blocks_down(false);
+    }
+    game.players[0].borrow_mut().next_block_queue[0] = FallingBlock::normal_from_shape(Shape::S);
+    assert_eq!(game.start_pending_please_wait_counters(), Some(vec![0]));
+    for _ in 0..29 {
+        assert!(game.tick_please_wait_counter(0));
+    }
+    assert!(!game.tick_please_wait_counter(0));
+
+    // The next block comes as usual, and the held I block is still there
+    assert_eq!(falling_block_kind(&game, 0), BlockKind::Normal(Shape::S));
+    {
+        let player = game.players[0].borrow();
+        let held = player.block_in_hold.as_ref().unwrap();
+        assert_eq!(held.kind, BlockKind::Normal(Shape::I));
+        assert!(!held.has_been_in_hold);
+    }
+
+    // The I can be swapped in, and then held again, because it's a new life
+    assert!(game.handle_key_press(0, false, true, KeyPress::Character('h')));
+    assert_eq!(falling_block_kind(&game, 0), BlockKind::Normal(Shape::I));
+    assert!(game.handle_key_press(0, false, true, KeyPress::Character('h')));
+    assert_eq!(falling_block_kind(&game, 0), BlockKind::Normal(Shape::S));
+    assert_eq!(
+        game.players[0]
+            .borrow()
+            .block_in_hold
+            .as_ref()
+            .unwrap()
+            .kind,
+        BlockKind::Normal(Shape::I)
+    );
+}
+
 #[test]
 fn test_traditional_clearing() {
     let mut game = create_game(Mode::Traditional, 2, Shape::L);
@@ -265,26 +537,29 @@ fn test_traditional_clearing() {
         }
     }
     let before_clear = vec![
-        "                            ",
-        "LLLLLLLLLLLLLLLLLLLLLLLLLLLL",
-        "LLLLLLLLLL  LLLLLLLLLLLLLLLL",
-        "LLLLLLLLLLLLLLLLLLLLLLLLLLLL",
-        "LLLLLLLLLLLLLLLL  LLLLLL  LL",
+        "                                        ",
+        "LLLLLLLLLLLLLLLLLLLLLLLLLLLLLLLLLLLLLLLL",
+        "LLLLLLLLLL  LLLLLLLLLLLLLLLLLLLLLLLLLLLL",
+        "LLLLLLLLLLLLLLLLLLLLLLLLLLLLLLLLLLLLLLLL",
+        "LLLLLLLLLLLLLLLL  LLLLLL  LLLLLLLLLLLLLL",
     ];
     let after_clear = vec![
-        "                            ",
-        "                            ",
-        "                            ",
-        "LLLLLLLLLL  LLLLLLLLLLLLLLLL",
-        "LLLLLLLLLLLLLLLL  LLLLLL  LL",
+        "                                        ",
+        "                                        ",
+        "                                        ",
+        "LLLLLLLLLL  LLLLLLLLLLLLLLLLLLLLLLLLLLLL",
+        "LLLLLLLLLLLLLLLL  LLLLLL  LLLLLLLLLLLLLL",
     ];
     assert_eq!(dump_game_state(&game), before_clear);
+    assert_debug_dump_matches(&game);
 
     assert_eq!(game.get_score(), 0);
+    assert_eq!(game.get_cleared_row_count(), 0);
     let full = game.find_full_rows_and_increment_score();
     // two full rows --> 10 for first + 20 for second
     // two players --> double score
     assert_eq!(game.get_score(), 60);
+    assert_eq!(game.get_cleared_row_count(), 2);
 
     let mut expected_full: HashSet<WorldPoint> = HashSet::new();
     for y in [1, 3] {
@@ -299,9 +574,228 @@ fn test_traditional_clearing() {
     assert_eq!(dump_game_state(&game), after_clear);
 }
 
+fn add_traditional_player(game: &mut Game, client_id: u64) {
+    game.add_player(&player_spec(client_id));
+}
+
 #[test]
-fn test_bottle_clearing() {
-    let mut game = create_game(Mode::Bottle, 2, Shape::L);
+fn test_traditional_width_changes() {
+    let mut game = create_game(Mode::Traditional, 1, Shape::L);
+    game.truncate_height(3);
+    for point in [(0, 2), (5, 1), (8, 2)] {
+        game.set_landed_square(
+            point,
+            Some(SquareContent::with_color(Color::YELLOW_FOREGROUND)),
+        );
+    }
+
+    // 1 --> 2: player 0 keeps all 10 columns
+    add_traditional_player(&mut game, 1);
+    assert_eq!(
+        dump_game_state(&game),
+        [
+            "                                        ",
+            "          LL                            ",
+            "LL              LL                      ",
+        ]
+    );
+    assert!(game.flashing_points.is_empty());
+
+    // 2 --> 3: 3 columns on the right side of each player are deleted
+    for point in [(12, 2), (19, 2)] {
+        game.set_landed_square(
+            point,
+            Some(SquareContent::with_color(Color::YELLOW_FOREGROUND)),
+        );
+    }
+    for client_id in [0, 1] {
+        for _ in 0..3 {
+            game.handle_key_press(client_id, false, true, KeyPress::Right);
+        }
+    }
+    game.move_blocks_down(false);
+    game.move_blocks_down(false);
+    assert_eq!(
+        dump_game_state(&game),
+        [
+            "                  FF                  FF",
+            "          LL  FFFFFF              FFFFFF",
+            "LL              LL      LL            LL",
+        ]
+    );
+    add_traditional_player(&mut game, 2);
+
+    // Player 1's block moved left to stay in their area. Player 0's block
+    // couldn't move because of a landed square, so it was replaced.
+    assert_eq!(
+        dump_game_state(&game),
+        [
+            "                          FF              ",
+            "          LL          FFFFFF              ",
+            "LL                LL                      ",
+        ]
+    );
+    let mut flashing: Vec<WorldPoint> = game.flashing_points.keys().copied().collect();
+    flashing.sort();
+    assert_eq!(flashing, [(6, 2), (13, 2)]);
+
+    // 3 --> 2: empty columns appear on the right side of each player
+    game.set_landed_square(
+        (20, 2),
+        Some(SquareContent::with_color(Color::YELLOW_FOREGROUND)),
+    );
+    game.remove_player_if_exists(1, LeaveReason::Quit);
+    assert_eq!(
+        dump_game_state(&game),
+        [
+            "                                        ",
+            "          LL                            ",
+            "LL                              LL      ",
+        ]
+    );
+
+    // 2 --> 1
+    game.remove_player_if_exists(2, LeaveReason::Quit);
+    assert_eq!(
+        dump_game_state(&game),
+        [
+            "                    ",
+            "          LL        ",
+            "LL                  "
+        ]
+    );
+    assert_debug_dump_matches(&game);
+}
+
+#[test]
+fn test_departures() {
+    let mut game = Game::new(Mode::Traditional);
+    for client_id in 0..3 {
+        add_traditional_player(&mut game, client_id);
+    }
+    let last_departure = |game: &Game| {
+        let departure = game.get_last_departure().unwrap();
+        (departure.name.clone(), departure.reason)
+    };
+
+    game.remove_player_if_exists(1, LeaveReason::Disconnected);
+    assert_eq!(
+        last_departure(&game),
+        ("Player 1".to_string(), LeaveReason::Disconnected)
+    );
+    game.remove_player_if_exists(123, LeaveReason::Quit); // not playing
+    game.remove_player_if_exists(0, LeaveReason::Quit);
+    // Nobody is left to see the last player leaving
+    game.remove_player_if_exists(2, LeaveReason::Quit);
+
+    assert_eq!(
+        last_departure(&game),
+        ("Player 0".to_string(), LeaveReason::Quit)
+    );
+    assert_eq!(game.get_departure_count(), 2);
+}
+
+#[test]
+fn test_silent_removal_is_not_a_departure() {
+    let mut game = Game::new(Mode::Traditional);
+    for client_id in 0..2 {
+        add_traditional_player(&mut game, client_id);
+    }
+    game.remove_player_silently(1);
+    assert_eq!(game.players.len(), 1);
+    assert!(game.get_last_departure().is_none());
+    assert_eq!(game.get_departure_count(), 0);
+}
+
+#[test]
+fn test_big_blocks() {
+    let mut game = Game::new(Mode::Traditional);
+    game.set_normal_block_factory(|_| FallingBlock::normal_from_shape(Shape::I));
+    game.set_board_options(BoardOptions {
+        big_blocks: true,
+        ..BoardOptions::default()
+    });
+    add_traditional_player(&mut game, 0);
+    game.truncate_height(5);
+    for y in 3..5 {
+        for x in [0, 9] {
+            game.set_landed_square(
+                (x, y),
+                Some(SquareContent::with_color(Color::YELLOW_FOREGROUND)),
+            );
+        }
+    }
+
+    for _ in 0..5 {
+        game.move_blocks_down(false);
+    }
+    assert_eq!(
+        dump_game_state(&game),
+        [
+            "                    ",
+            "                    ",
+            "                    ",
+            "LLFFFFFFFFFFFFFFFFLL",
+            "LLFFFFFFFFFFFFFFFFLL",
+        ]
+    );
+
+    // Landing the big I block fills two rows at once
+    game.move_blocks_down(false);
+    let full = game.find_full_rows_and_increment_score();
+    assert_eq!(game.get_cleared_row_count(), 2);
+    assert_eq!(full.len(), 20);
+    game.remove_full_rows(&full);
+    assert_eq!(dump_game_state(&game), ["                    "; 5]);
+
+    // Big blocks need 10 columns per player, even with many players
+    add_traditional_player(&mut game, 1);
+    add_traditional_player(&mut game, 2);
+    assert_eq!(game.get_width_per_player(), Some(10));
+    assert_eq!(game.get_width(), 30);
+}
+
+#[test]
+fn test_big_blocks_in_each_mode() {
+    for mode in Mode::ALL_MODES {
+        let game = create_game_with(*mode, 1, |game| {
+            game.set_normal_block_factory(|_| FallingBlock::normal_from_shape(Shape::I));
+            game.set_board_options(BoardOptions {
+                big_blocks: true,
+                ..BoardOptions::default()
+            });
+        });
+        assert_eq!(
+            game.get_board_options().big_blocks,
+            mode.supports_big_blocks()
+        );
+
+        // The first block fits, instead of the player topping out right away
+        let coords = game.players[0].borrow().block_or_timer.get_coords();
+        let expected_len = if mode.supports_big_blocks() { 16 } else { 4 };
+        assert_eq!(coords.len(), expected_len, "{:?}", mode);
+    }
+}
+
+const ALL_BOTTLE_NECKS: [BottleNecks; 3] = [
+    BottleNecks::Classic,
+    BottleNecks::Deep,
+    BottleNecks::Shallow,
+];
+
+fn create_bottle_game(necks: BottleNecks, player_count: usize) -> Game {
+    create_game_with(Mode::Bottle, player_count, |game| {
+        game.set_board_options(BoardOptions {
+            bottle_necks: necks,
+            ..BoardOptions::default()
+        });
+        game.set_normal_block_factory(|_| FallingBlock::normal_from_shape(Shape::L));
+    })
+}
+
+// Rows near the top are personal and rows at the bottom are shared with every setting
+fn check_bottle_clearing(necks: BottleNecks) {
+    let mut game = create_bottle_game(necks, 2);
     for y in 0..3 {
         for x in 2..7 {
             if (x, y) != (3, 0) && (x, y) != (5, 2) {
@@ -358,6 +852,7 @@ fn test_bottle_clearing() {
     ];
 
     assert_eq!(dump_game_state(&game), before_clear);
+    assert_debug_dump_matches(&game);
 
     assert_eq!(game.get_score(), 0);
     let full = game.find_full_rows_and_increment_score();
@@ -370,46 +865,328 @@ fn test_bottle_clearing() {
 }
 
 #[test]
-fn test_ring_mode_clearing() {
-    let mut game = create_game(Mode::Ring, 2, Shape::L);
-    for x in -6..=6 {
-        for y in -6..=6 {
-            let point = (x + RING_OUTER_RADIUS, y + RING_OUTER_RADIUS);
-            if game.is_valid_landed_block_coords(point) && (x, y) != (5, -2) {
-                game.set_landed_square(
-                    point,
-                    Some(SquareContent::with_color(Color::YELLOW_FOREGROUND)),
-                );
+fn test_bottle_clearing() {
+    for necks in ALL_BOTTLE_NECKS {
+        check_bottle_clearing(necks);
+    }
+}
+
+#[test]
+fn test_bottle_necks_move_personal_rows() {
+    for necks in ALL_BOTTLE_NECKS {
+        let mut game = create_bottle_game(necks, 2);
+        let h = necks.personal_space_height() as i16;
+        let square = Some(SquareContent::with_color(Color::YELLOW_FOREGROUND));
+
+        // Wall between the players begins where their personal areas end
+        assert!(!game.is_valid_landed_block_coords((9, h - 1)));
+        assert!(game.is_valid_landed_block_coords((9, h)));
+        assert!(game.get_landed_square((9, h - 1)).is_none());
+        assert!(game.get_landed_square((9, h)).is_some());
+
+        // Last personal row of the first player, and the first shared row
+        for x in 0..9 {
+            game.set_landed_square((x, h - 1), square);
+        }
+        for x in (0..19).filter(|x| *x != 9) {
+            game.set_landed_square((x, h), square);
+        }
+        // Half of the next row is not enough, because it's shared
+        for x in 0..9 {
+            game.set_landed_square((x, h + 1), square);
+        }
+        assert_debug_dump_matches(&game);
+
+        let full = game.find_full_rows_and_increment_score();
+        // 10 points for player-specific row, 2*10 for a row shared with two players
+        assert_eq!(game.get_score(), 30, "{:?}", necks);
+        game.remove_full_rows(&full);
+        for x in 0..9 {
+            assert!(game.get_landed_square((x, h - 1)).is_none());
+            assert!(game.get_landed_square((x, h)).is_none());
+            assert!(game.get_landed_square((x, h + 1)).is_some());
+        }
+    }
+}
+
+#[test]
+fn test_bottle_neck_clear_keeps_squares_inside_bottle() {
+    let mut game = create_game(Mode::Bottle, 2, Shape::L);
+    let square = Some(SquareContent::with_color(Color::YELLOW_FOREGROUND));
+    // Widening shoulder rows of the first player, with a hole in each row except row 6
+    for y in 4..9 {
+        for x in 0..9 {
+            if game.is_valid_landed_block_coords((x, y)) && (y == 6 || x != 4) {
+                game.set_landed_square((x, y), square);
             }
         }
     }
 
-    // These unrelated squares would be deleted if the clears were done in the wrong order
-    game.set_landed_square(
-        (RING_OUTER_RADIUS + 6, RING_OUTER_RADIUS - 7),
-        Some(SquareContent::with_color(Color::YELLOW_FOREGROUND)),
+    let before_clear = game.debug_dump();
+    assert_eq!(
+        before_clear[3..9],
+        [
+            "....          ..........          ....",
+            "....LLLL  LLLL..........          ....",
+            "....LLLL  LLLL..........          ....",
+            "..LLLLLLLLLLLLLL......              ..",
+            "..LLLLLL  LLLLLL......              ..",
+            "LLLLLLLL  LLLLLLLL..                  ",
+        ]
     );
-    game.set_landed_square(
-        (RING_OUTER_RADIUS, RING_OUTER_RADIUS - 7),
-        Some(SquareContent::with_color(Color::YELLOW_FOREGROUND)),
+    let full = game.find_full_rows_and_increment_score();
+    game.remove_full_rows(&full);
+
+    // Rows above the cleared row move down, and the wider row gets empty edges
+    let after_clear = game.debug_dump();
+    assert_eq!(after_clear[..4], before_clear[..4]);
+    assert_eq!(
+        after_clear[3..9],
+        [
+            "....          ..........          ....",
+            "....          ..........          ....",
+            "....LLLL  LLLL..........          ....",
+            "..  LLLL  LLLL  ......              ..",
+            "..LLLLLL  LLLLLL......              ..",
+            "LLLLLLLL  LLLLLLLL..                  ",
+        ]
     );
+    assert_eq!(after_clear[9..], before_clear[9..]);
 
-    let before_clear = vec![
-        "......~                              ~......",
-        "......~                              ~......",
-        "......~                              ~......",
-        "~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~",
-        "      ~              LL          LL  ~      ",
-        "      ~  LLLLLLLLLLLLLLLLLLLLLLLLLL  ~      ",
-        "      ~  LLLLLLLLLLLLLLLLLLLLLLLLLL  ~      ",
-        "      ~  LLLLLLLLLLLLLLLLLLLLLLLLLL  ~      ",
-        "      ~  LLLLLL..............LLLLLL  ~      ",
-        "      ~  LLLLLL..............LL  LL  ~      ",
-        "      ~  LLLLLL..............LLLLLL  ~      ",
-        "      ~  LLLLLL..............LLLLLL  ~      ",
-        "      ~  LLLLLL..............LLLLLL  ~      ",
-        "      ~  LLLLLL..............LLLLLL  ~      ",
-        "      ~  LLLLLL..............LLLLLL  ~      ",
+    for y in 0..(game.get_height() as i16) {
+        for x in 0..(game.get_width() as i16) {
+            if !game.is_valid_landed_block_coords((x, y)) {
+                assert!(game.get_landed_square((x, y)).is_none(), "{:?}", (x, y));
+            }
+        }
+    }
+}
+
+fn create_two_basin_bottle_game(player_count: usize) -> Game {
+    create_game_with(Mode::Bottle, player_count, |game| {
+        game.set_board_options(BoardOptions {
+            two_bottle_basins: true,
+            ..BoardOptions::default()
+        });
+        game.set_normal_block_factory(|_| FallingBlock::normal_from_shape(Shape::L));
+    })
+}
+
+fn basin_split(game: &Game) -> Option<usize> {
+    get_basin_split(
+        game.get_board_options().two_bottle_basins,
+        game.players.len(),
+    )
+}
+
+#[test]
+fn test_bottle_basins_clear_separately() {
+    let mut game = create_two_basin_bottle_game(4);
+    assert_eq!(basin_split(&game), Some(2));
+    let h = game.get_height() as i16;
+
+    for y in (h - 2)..h {
+        for x in 0..(game.get_width() as i16) {
+            if (x, y) != (25, h - 1)
+                && game.is_valid_landed_block_coords((x, y))
+                && game.get_landed_square((x, y)).is_none()
+            {
+                game.set_landed_square(
+                    (x, y),
+                    Some(SquareContent::with_color(Color::YELLOW_FOREGROUND)),
+                );
+            }
+        }
+    }
+    for x in [5, 30] {
+        game.set_landed_square(
+            (x, h - 3),
+            Some(SquareContent::with_color(Color::YELLOW_FOREGROUND)),
+        );
+    }
+
+    let top = "....          ..........          ..........          ..........          ....";
+    let before_clear = vec![
+        top,
+        top,
+        top,
+        top,
+        "~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~",
+        "                  ||                  ..                  ||                  ",
+        "          LL      ||                  ..                  ||LL                ",
+        "LLLLLLLLLLLLLLLLLL||LLLLLLLLLLLLLLLLLL..LLLLLLLLLLLLLLLLLL||LLLLLLLLLLLLLLLLLL",
+        "LLLLLLLLLLLLLLLLLL||LLLLLLLLLLLLLLLLLL..LLLLLLLLLL  LLLLLL||LLLLLLLLLLLLLLLLLL",
+    ];
+    // Left basin clears two rows, right basin only one
+    let after_clear = vec![
+        top,
+        top,
+        top,
+        top,
+        "~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~",
+        "                  ||                  ..                  ||                  ",
+        "                  ||                  ..                  ||                  ",
+        "                  ||                  ..                  ||LL                ",
+        "          LL      ||                  ..LLLLLLLLLL  LLLLLL||LLLLLLLLLLLLLLLLLL",
+    ];
+
+    assert_eq!(dump_game_state(&game), before_clear);
+    assert_debug_dump_matches(&game);
+
+    let full = game.find_full_rows_and_increment_score();
+    // 3 full rows in total, 60 points doubled for each additional player
+    assert_eq!(game.get_score(), 60 * 8);
+    game.remove_full_rows(&full);
+    assert_eq!(dump_game_state(&game), after_clear);
+}
+
+#[test]
+fn test_bottle_basin_wall_moves() {
+    let mut game = create_two_basin_bottle_game(3);
+    let h = game.get_height() as i16;
+    assert_eq!(basin_split(&game), None);
+    assert!(game.is_valid_landed_block_coords((19, h - 1)));
+
+    game.add_player(&player_spec(3));
+    assert_eq!(basin_split(&game), Some(2));
+    assert!(!game.is_valid_landed_block_coords((19, h - 1)));
+    assert!(game.is_valid_landed_block_coords((29, h - 1)));
+
+    game.add_player(&player_spec(4));
+    assert_eq!(basin_split(&game), Some(3));
+    assert!(game.is_valid_landed_block_coords((19, h - 1)));
+    assert!(game.get_landed_square((19, h - 1)).is_some()); // wall between players is back
+    assert!(!game.is_valid_landed_block_coords((29, h - 1)));
+
+    // Leaving player's bottle contained the basin wall
+    game.remove_player_if_exists(2, LeaveReason::Quit);
+    assert_eq!(basin_split(&game), Some(2));
+    assert!(!game.is_valid_landed_block_coords((19, h - 1)));
+    assert!(game.is_valid_landed_block_coords((29, h - 1)));
+    assert_debug_dump_matches(&game);
+
+    game.remove_player_if_exists(0, LeaveReason::Quit);
+    assert_eq!(basin_split(&game), None);
+    for x in [9, 19] {
+        assert!(game.is_valid_landed_block_coords((x, h - 1)));
+        assert!(game.get_landed_square((x, h - 1)).is_some());
+    }
+}
+
+#[test]
+fn test_late_joiner_goes_to_lobby_position() {
+    let mut game = Game::new(Mode::Bottle);
+    game.set_normal_block_factory(|_| FallingBlock::normal_from_shape(Shape::L));
+    for (client_id, lobby_order) in [(0, 0), (1, 4)] {
+        game.add_player(&PlayerSpec {
+            lobby_order,
+            ..player_spec(client_id)
+        });
+    }
+    let h = game.get_height() as i16;
+    for x in [0, 18] {
+        game.set_landed_square(
+            (x, h - 1),
+            Some(SquareContent::with_color(Color::YELLOW_FOREGROUND)),
+        );
+    }
+
+    game.add_player(&player_spec(2));
+    let names: Vec<String> = game
+        .players
+        .iter()
+        .map(|p| p.borrow().name.clone())
+        .collect();
+    assert_eq!(names, ["Player 0", "Player 2", "Player 1"]);
+
+    // The second player's bottle and its contents moved right to make room
+    let top = "....          ..........          ..........          ....";
+    let expected = vec![
+        top,
+        top,
+        top,
+        top,
+        "~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~",
+        "                  ||                  ||                  ",
+        "                  ||                  ||                  ",
+        "                  ||                  ||                  ",
+        "LL                ||                  ||                LL",
+    ];
+    assert_eq!(dump_game_state(&game), expected);
+    assert_debug_dump_matches(&game);
+}
+
+#[test]
+fn test_bottle_spawn_x() {
+    let game = create_game(Mode::Bottle, 2, Shape::L);
+    let mut rng = StdRng::seed_from_u64(123);
+
+    for player_idx in 0..2 {
+        let mut used_xs = HashSet::new();
+        for _ in 0..100 {
+            for block_type in [
+                BlockType::Normal,
+                BlockType::Cursed,
+                BlockType::Drill,
+                BlockType::Bomb { initial_timer: 15 },
+                BlockType::LineBomb,
+            ] {
+                let mut block = FallingBlock::new(block_type, &mut rng);
+                let x = choose_bottle_spawn_x(player_idx, &block, &mut rng);
+                used_xs.insert(x);
+                block.spawn_at((x, 0));
+                // The whole block must be above the neck of this player's bottle
+                for (x, _) in block.get_coords() {
+                    assert!(game.is_valid_landed_block_coords((x as i16, 0)));
+                    assert_eq!(x as usize / 10, player_idx);
+                }
+            }
+        }
+        assert!(used_xs.len() > 1);
+    }
+}
+
+#[test]
+fn test_ring_mode_clearing() {
+    let mut game = create_game(Mode::Ring, 2, Shape::L);
+    for x in -6..=6 {
+        for y in -6..=6 {
+            let point = (x + RING_OUTER_RADIUS, y + RING_OUTER_RADIUS);
+            if game.is_valid_landed_block_coords(point) && (x, y) != (5, -2) {
+                game.set_landed_square(
+                    point,
+                    Some(SquareContent::with_color(Color::YELLOW_FOREGROUND)),
+                );
+            }
+        }
+    }
+
+    // These unrelated squares would be deleted if the clears were done in the wrong order
+    game.set_landed_square(
+        (RING_OUTER_RADIUS + 6, RING_OUTER_RADIUS - 7),
+        Some(SquareContent::with_color(Color::YELLOW_FOREGROUND)),
+    );
+    game.set_landed_square(
+        (RING_OUTER_RADIUS, RING_OUTER_RADIUS - 7),
+        Some(SquareContent::with_color(Color::YELLOW_FOREGROUND)),
+    );
+
+    let before_clear = vec![
+        "......~                              ~......",
+        "......~                              ~......",
+        "......~                              ~......",
+        "~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~",
+        "      ~              LL          LL  ~      ",
+        "      ~  LLLLLLLLLLLLLLLLLLLLLLLLLL  ~      ",
+        "      ~  LLLLLLLLLLLLLLLLLLLLLLLLLL  ~      ",
+        "      ~  LLLLLLLLLLLLLLLLLLLLLLLLLL  ~      ",
+        "      ~  LLLLLL..............LLLLLL  ~      ",
+        "      ~  LLLLLL..............LL  LL  ~      ",
+        "      ~  LLLLLL..............LLLLLL  ~      ",
+        "      ~  LLLLLL..............LLLLLL  ~      ",
+        "      ~  LLLLLL..............LLLLLL  ~      ",
+        "      ~  LLLLLL..............LLLLLL  ~      ",
+        "      ~  LLLLLL..............LLLLLL  ~      ",
         "      ~  LLLLLLLLLLLLLLLLLLLLLLLLLL  ~      ",
         "      ~  LLLLLLLLLLLLLLLLLLLLLLLLLL  ~      ",
         "      ~  LLLLLLLLLLLLLLLLLLLLLLLLLL  ~      ",
@@ -445,6 +1222,7 @@ fn test_ring_mode_clearing() {
         "......~                              ~......",
     ];
     assert_eq!(dump_game_state(&game), before_clear);
+    assert_debug_dump_matches(&game);
 
     assert_eq!(game.get_score(), 0);
     let full = game.find_full_rows_and_increment_score();
@@ -455,6 +1233,91 @@ fn test_ring_mode_clearing() {
     assert_eq!(dump_game_state(&game), after_clear);
 }
 
+#[test]
+fn test_ring_mode_every_radius() {
+    let yellow = Some(SquareContent::with_color(Color::YELLOW_FOREGROUND));
+    let landed_points = |game: &Game| {
+        let size = 2 * RING_OUTER_RADIUS + 1;
+        let mut result = vec![];
+        for x in 0..size {
+            for y in 0..size {
+                if game.get_landed_square((x, y)).is_some() {
+                    result.push((x, y));
+                }
+            }
+        }
+        result
+    };
+
+    for r in (RING_INNER_RADIUS + 1)..=RING_OUTER_RADIUS {
+        let mut game = create_game(Mode::Ring, 1, Shape::L);
+        let points = ring_points(r);
+        assert_eq!(points.iter().collect::<HashSet<_>>().len(), points.len());
+        assert!(points.iter().all(|p| game.is_valid_landed_block_coords(*p)));
+        assert!(points.len() <= 8 * r as usize);
+        // Squares that can't be filled must not prevent a clear
+        let size = 2 * RING_OUTER_RADIUS + 1;
+        for x in 0..size {
+            for y in 0..size {
+                let dx = (x - RING_OUTER_RADIUS).abs();
+                let dy = (y - RING_OUTER_RADIUS).abs();
+                if max(dx, dy) == r && !points.contains(&(x, y)) {
+                    assert!(!game.is_valid_landed_block_coords((x, y)));
+                }
+            }
+        }
+        // Farthest from the middle, so in the corner even if the corner is rounded
+        let corner = *points
+            .iter()
+            .filter(|(x, y)| *x > RING_OUTER_RADIUS && *y < RING_OUTER_RADIUS)
+            .max_by_key(|(x, y)| {
+                let dx = x - RING_OUTER_RADIUS;
+                let dy = y - RING_OUTER_RADIUS;
+                dx * dx + dy * dy
+            })
+            .unwrap();
+
+        // Complete ring
+        for point in &points {
+            game.set_landed_square(*point, yellow);
+        }
+        let full = game.find_full_rows_and_increment_score();
+        assert_eq!(
+            full.iter().collect::<HashSet<_>>(),
+            points.iter().collect::<HashSet<_>>()
+        );
+        assert_eq!(game.get_cleared_row_count(), 1);
+        game.remove_full_rows(&full);
+        assert_eq!(landed_points(&game), []);
+
+        // Same ring with a missing corner
+        let mut game = create_game(Mode::Ring, 1, Shape::L);
+        for point in &points {
+            if *point != corner {
+                game.set_landed_square(*point, yellow);
+            }
+        }
+        let full = game.find_full_rows_and_increment_score();
+        assert!(full.is_empty());
+        assert_eq!(game.get_cleared_row_count(), 0);
+        game.remove_full_rows(&full);
+        assert_eq!(landed_points(&game).len(), points.len() - 1);
+
+        // A partial list of points (e.g. from before an explosion) doesn't remove the ring
+        let incomplete: Vec<WorldPoint> = points.iter().copied().filter(|p| *p != corner).collect();
+        game.set_landed_square(corner, yellow);
+        game.remove_full_rows(&incomplete);
+        assert_eq!(landed_points(&game), [corner]);
+    }
+
+    // Outermost rings have rounded corners
+    assert!(ring_points(RING_OUTER_RADIUS).len() < 8 * RING_OUTER_RADIUS as usize);
+    assert_eq!(
+        ring_points(RING_INNER_RADIUS + 1).len(),
+        8 * (RING_INNER_RADIUS + 1) as usize
+    );
+}
+
 // Sometimes, a clear in ring mode causes another clear to trigger.
 // This is because inner rings are smaller, and shoving squares into smaller space can get rid of gaps.
 #[test]
@@ -576,128 +1439,669 @@ fn test_ring_mode_double_clear() {
 }
 
 #[test]
-fn test_rotating_and_bumping_to_walls() {
-    let mut game = create_game(Mode::Traditional, 1, Shape::L);
-    game.truncate_height(5);
-    game.move_blocks_down(false);
-    game.move_blocks_down(false);
-    assert_eq!(
-        dump_game_state(&game),
-        vec![
-            "            FF      ",
-            "        FFFFFF      ",
-            "                    ",
-            "                    ",
-            "                    "
-        ]
-    );
+fn test_ring_block_crossing_the_seam() {
+    let lap = (2 * RING_OUTER_RADIUS + 1) as usize;
+    let move_down_and_rotate = |count: usize| {
+        let mut game = create_game(Mode::Ring, 1, Shape::L);
+        for _ in 0..8 {
+            game.handle_key_press(0, false, true, KeyPress::Left);
+        }
+        for _ in 0..count {
+            assert!(game.move_blocks_down(false));
+        }
 
-    game.handle_key_press(0, false, KeyPress::Up);
-    assert_eq!(
-        dump_game_state(&game),
-        vec![
-            "          FF        ",
-            "          FF        ",
-            "          FFFF      ",
-            "                    ",
-            "                    "
-        ]
-    );
+        let player_coords = |game: &Game| match &game.players[0].borrow().block_or_timer {
+            BlockOrTimer::Block(block) => block.get_coords(),
+            _ => panic!(),
+        };
+        let world_coords = |game: &Game| -> Vec<WorldPoint> {
+            player_coords(game)
+                .iter()
+                .map(|p| game.players[0].borrow().player_to_world(*p))
+                .collect()
+        };
+        let r = RING_OUTER_RADIUS as i32;
+        assert!(player_coords(&game).iter().all(|(_, y)| y.abs() <= r + 3));
+
+        // Something to land on, so that there is a trace
+        for x in -10..=-6 {
+            let point = game.players[0].borrow().player_to_world((x, 0));
+            game.set_landed_square(
+                point,
+                Some(SquareContent::with_color(Color::RED_FOREGROUND)),
+            );
+        }
+        let before_rotating = world_coords(&game);
+        let trace = game.predict_landing_place(0);
+        assert!(!trace.is_empty());
+        assert!(game.handle_key_press(0, false, true, KeyPress::Up));
+        (
+            player_coords(&game),
+            before_rotating,
+            trace,
+            world_coords(&game),
+        )
+    };
+    assert_eq!(move_down_and_rotate(2 * lap + 3), move_down_and_rotate(3));
+}
 
-    // Move block all the way to left, shouldn't rotate when against wall
-    for _ in 0..100 {
-        game.handle_key_press(0, false, KeyPress::Left);
+#[test]
+fn test_ring_clear_counts_squares_by_player() {
+    let mut game = create_game(Mode::Ring, 2, Shape::L);
+    let tagged = |client_id| {
+        Some(SquareContent::Normal(
+            [(' ', Color::RED_BACKGROUND); 2],
+            Some(client_id),
+            false,
+        ))
+    };
+    let landed_by = |game: &Game, point| match game.get_landed_square(point) {
+        Some(SquareContent::Normal(_, id, _)) => id,
+        _ => None,
+    };
+
+    let ring = ring_points(5);
+    for (i, point) in ring.iter().enumerate() {
+        let client_id = match i {
+            0..=2 => 1,
+            3 => 123, // player who already left the game
+            _ => 0,
+        };
+        game.set_landed_square(*point, tagged(client_id));
     }
-    let all_the_way_to_left = vec![
-        "FF                  ",
-        "FF                  ",
-        "FFFF                ",
-        "                    ",
-        "                    ",
-    ];
-    assert_eq!(dump_game_state(&game), all_the_way_to_left);
-    game.handle_key_press(0, false, KeyPress::Up);
-    assert_eq!(dump_game_state(&game), all_the_way_to_left);
+    let outside = (RING_OUTER_RADIUS + 6, RING_OUTER_RADIUS);
+    game.set_landed_square(outside, tagged(1));
+    assert!(game.get_last_ring_clear().is_empty());
 
-    // Move away from wall
-    game.handle_key_press(0, false, KeyPress::Right);
-    game.handle_key_press(0, false, KeyPress::Up);
+    let full = game.find_full_rows_and_increment_score();
+    assert_eq!(full.len(), ring.len());
+    let color = Color::RED_FOREGROUND.fg;
     assert_eq!(
-        dump_game_state(&game),
-        vec![
-            "                    ",
-            "FFFFFF              ",
-            "FF                  ",
-            "                    ",
-            "                    "
+        game.get_last_ring_clear(),
+        [
+            ("Player 0".to_string(), color, ring.len() - 4),
+            ("Player 1".to_string(), color, 3),
         ]
     );
 
-    for _ in 0..6 {
+    // The square outside moves inwards with its tag, the cleared squares are gone
+    game.remove_full_rows(&full);
+    assert_eq!(
+        landed_by(&game, (RING_OUTER_RADIUS + 5, RING_OUTER_RADIUS)),
+        Some(1)
+    );
+    assert!(game.get_landed_square(outside).is_none());
+    assert!(ring.iter().all(
+        |p| *p == (RING_OUTER_RADIUS + 5, RING_OUTER_RADIUS) || landed_by(&game, *p).is_none()
+    ));
+
+    // Landing a block tags its squares
+    while game.players[0].borrow().landed_count == 0 {
         game.move_blocks_down(false);
     }
-    game.handle_key_press(0, false, KeyPress::Left);
-    game.handle_key_press(0, false, KeyPress::Left);
-    game.handle_key_press(0, false, KeyPress::Left);
-    let landed_block_prevents_rotation = vec![
-        "                    ",
-        "      FF            ",
-        "  FFFFFF            ",
-        "LLLLLL              ",
-        "LL                  ",
-    ];
-    assert_eq!(dump_game_state(&game), landed_block_prevents_rotation);
-    game.handle_key_press(0, false, KeyPress::Up);
-    assert_eq!(dump_game_state(&game), landed_block_prevents_rotation);
+    let size = 2 * RING_OUTER_RADIUS + 1;
+    let count_tagged = |client_id| {
+        (0..size)
+            .flat_map(|y| (0..size).map(move |x| (x, y)))
+            .filter(|p| game.is_valid_landed_block_coords(*p))
+            .filter(|p| landed_by(&game, *p) == Some(client_id))
+            .count()
+    };
+    assert_eq!(count_tagged(0), 4);
+}
 
-    // Move falling block to the right side of landed block, so it can't move left
-    game.handle_key_press(0, false, KeyPress::Right);
-    game.handle_key_press(0, false, KeyPress::Right);
-    game.move_blocks_down(false);
-    for _ in 0..10 {
-        game.handle_key_press(0, false, KeyPress::Left);
+#[test]
+fn test_ring_stack_height() {
+    let r = RING_INNER_RADIUS as i32;
+    for player_idx in 0..4 {
+        let mut game = create_game(Mode::Ring, 4, Shape::L);
+        let player_to_world =
+            |game: &Game, point| game.players[player_idx].borrow().player_to_world(point);
+        assert!(!game.is_valid_landed_block_coords(player_to_world(&game, (0, -r))));
+        assert!(game.is_valid_landed_block_coords(player_to_world(&game, (0, -r - 1))));
+
+        let heights = |game: &Game| {
+            (0..4)
+                .map(|i| get_ring_stack_height(game, i))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(heights(&game), [0, 0, 0, 0]);
+
+        let point = player_to_world(&game, (2, -10));
+        game.set_landed_square(
+            point,
+            Some(SquareContent::with_color(Color::YELLOW_FOREGROUND)),
+        );
+        let mut expected = vec![0, 0, 0, 0];
+        expected[player_idx] = 10 - RING_INNER_RADIUS as usize;
+        assert_eq!(heights(&game), expected);
+
+        // Goes to the quadrant of the player on the left side
+        let point = player_to_world(&game, (-12, -5));
+        game.set_landed_square(
+            point,
+            Some(SquareContent::with_color(Color::YELLOW_FOREGROUND)),
+        );
+        let new_heights = heights(&game);
+        assert_eq!(new_heights[player_idx], 10 - RING_INNER_RADIUS as usize);
+        assert_eq!(
+            new_heights
+                .iter()
+                .filter(|h| **h == 12 - RING_INNER_RADIUS as usize)
+                .count(),
+            1
+        );
     }
-    assert_eq!(
-        dump_game_state(&game),
-        vec![
-            "                    ",
-            "                    ",
-            "          FF        ",
-            "LLLLLLFFFFFF        ",
-            "LL                  ",
-        ]
-    );
+}
 
-    // Should be possible to slide block under another landed block before it lands
-    game.move_blocks_down(false);
-    for _ in 0..10 {
-        game.handle_key_press(0, false, KeyPress::Left);
+#[test]
+fn test_player_regions() {
+    let game = create_game(Mode::Traditional, 2, Shape::L);
+    assert!(game.is_in_player_region(0, (9, 20)));
+    assert!(!game.is_in_player_region(0, (10, 20)));
+    assert!(game.is_in_player_region(1, (10, 0)));
+    assert!(game.is_in_player_region(1, (19, 0)));
+    assert_eq!(game.get_player_region(0).len(), 10 * game.get_height());
+
+    let game = create_game(Mode::Traditional, 3, Shape::L);
+    assert!(game.is_in_player_region(0, (6, 20)));
+    assert!(!game.is_in_player_region(0, (7, 20)));
+    assert!(game.is_in_player_region(1, (7, 0)));
+    assert!(game.is_in_player_region(1, (13, 0)));
+    assert_eq!(game.get_player_region(0).len(), 7 * game.get_height());
+
+    let game = create_game(Mode::Bottle, 2, Shape::L);
+    assert!(game.is_in_player_region(0, (0, 5)));
+    assert!(game.is_in_player_region(0, (8, 5)));
+    assert!(!game.is_in_player_region(0, (10, 5)));
+    assert!(game.is_in_player_region(1, (10, 5)));
+    assert!(game.get_player_region(1).iter().all(|(x, _)| *x >= 10));
+
+    // Each square belongs to exactly one player, except that diagonals of ring are shared
+    for mode in [Mode::Traditional, Mode::Bottle, Mode::Ring] {
+        let game = create_game(mode, mode.max_players(), Shape::L);
+        let mut all_points: Vec<WorldPoint> = vec![];
+        for i in 0..game.players.len() {
+            all_points.extend(game.get_player_region(i));
+        }
+        let unique: HashSet<WorldPoint> = all_points.iter().copied().collect();
+        if mode == Mode::Ring {
+            let diagonal_count = unique
+                .iter()
+                .filter(|(x, y)| (x - RING_OUTER_RADIUS).abs() == (y - RING_OUTER_RADIUS).abs())
+                .count();
+            assert!(diagonal_count > 0);
+            assert_eq!(all_points.len(), unique.len() + diagonal_count);
+        } else {
+            assert_eq!(all_points.len(), unique.len());
+        }
     }
-    assert_eq!(
-        dump_game_state(&game),
-        vec![
-            "                    ",
-            "                    ",
-            "                    ",
-            "LLLLLLFF            ",
-            "LLFFFFFF            ",
-        ]
+
+    // Ring quadrant doesn't include the middle square
+    let game = create_game(Mode::Ring, 4, Shape::L);
+    let r = RING_INNER_RADIUS as i32;
+    assert!(game.is_in_player_region(0, (0, -r - 1)));
+    assert!(!game.is_in_player_region(0, (0, -r)));
+    assert!(game.is_in_player_region(0, (-10, -10)));
+    assert!(!game.is_in_player_region(0, (-10, -9)));
+}
+
+#[test]
+fn test_free_rows_above_stack() {
+    let mut game = create_game(Mode::Traditional, 2, Shape::L);
+    let h = game.get_height();
+    assert_eq!(game.get_free_rows_above_stack(0), h);
+    game.set_landed_square(
+        (12, 10),
+        Some(SquareContent::with_color(Color::YELLOW_FOREGROUND)),
     );
+    assert_eq!(game.get_free_rows_above_stack(0), h);
+    assert_eq!(game.get_free_rows_above_stack(1), 10);
 
-    game.move_blocks_down(false);
+    let mut game = create_game(Mode::Ring, 2, Shape::L);
+    let point = game.players[1].borrow().player_to_world((0, -15));
+    game.set_landed_square(
+        point,
+        Some(SquareContent::with_color(Color::YELLOW_FOREGROUND)),
+    );
     assert_eq!(
-        dump_game_state(&game),
-        vec![
-            "                    ",
-            "                    ",
-            "                    ",
-            "LLLLLLLL            ",
-            "LLLLLLLL            ",
-        ]
+        game.get_free_rows_above_stack(1),
+        (RING_OUTER_RADIUS - 15) as usize
     );
 }
 
-// Z blocks aren't tested because they are very similar (mirror image)
+// Lands the block of player 0 on squares that are the given number of rows below the
+// spawn point, or on whatever is below it. Returns the number of reinforced squares.
+fn land_and_count_reinforced(game: &mut Game, floor_rows_below_spawn: Option<i32>) -> usize {
+    if let Some(rows) = floor_rows_below_spawn {
+        let floor: Vec<WorldPoint> = {
+            let player = game.players[0].borrow();
+            let spawn_y = player.spawn_point.1;
+            player
+                .block_or_timer
+                .get_coords()
+                .iter()
+                .map(|(x, _)| player.player_to_world((*x, spawn_y + rows)))
+                .collect()
+        };
+        for point in floor {
+            game.set_landed_square(
+                point,
+                Some(SquareContent::with_color(Color::YELLOW_FOREGROUND)),
+            );
+        }
+    }
+
+    let old_landed_count = game.players[0].borrow().landed_count;
+    for _ in 0..100 {
+        if game.players[0].borrow().landed_count != old_landed_count {
+            break;
+        }
+        game.move_blocks_down(false);
+    }
+    assert_ne!(game.players[0].borrow().landed_count, old_landed_count);
+
+    game.get_player_region(0)
+        .iter()
+        .filter(|p| matches!(game.get_landed_square(**p), Some(c) if c.is_reinforced()))
+        .count()
+}
+
+#[test]
+fn test_reinforced_squares_landing() {
+    let near_spawn = Some(REINFORCED_ROWS as i32 + 1);
+    for mode in [Mode::Traditional, Mode::Bottle, Mode::Ring] {
+        let mut game = create_game(mode, 1, Shape::L);
+        assert_eq!(land_and_count_reinforced(&mut game, near_spawn), 0);
+
+        let mut game = create_game(mode, 1, Shape::L);
+        game.set_reinforced_squares(true);
+        assert_eq!(land_and_count_reinforced(&mut game, None), 0);
+        assert_eq!(land_and_count_reinforced(&mut game, near_spawn), 4);
+    }
+}
+
+#[test]
+fn test_explosion_downgrades_reinforced_squares() {
+    let mut game = create_game(Mode::Traditional, 1, Shape::L);
+    let reinforced = SquareContent::Normal([(' ', Color::RED_BACKGROUND); 2], Some(0), true);
+    assert_eq!(square_content_to_string(reinforced, None), "+ ");
+    game.set_landed_square((1, 10), Some(reinforced));
+    game.set_landed_square(
+        (2, 10),
+        Some(SquareContent::with_color(Color::RED_BACKGROUND)),
+    );
+
+    let flashing = [(1, 10), (2, 10)];
+    game.finish_explosion(&[], &flashing);
+    let survivor = game.get_landed_square((1, 10)).unwrap();
+    assert!(!survivor.is_reinforced());
+    assert_eq!(survivor.landed_by(), Some(0));
+    assert!(game.get_landed_square((2, 10)).is_none());
+
+    game.finish_explosion(&[], &flashing);
+    assert!(game.get_landed_square((1, 10)).is_none());
+}
+
+// Moves the block of player 0 up before each tick, so that it never lands
+fn tick_without_landing(game: &mut Game, ticks: usize) {
+    for _ in 0..ticks {
+        if let BlockOrTimer::Block(block) = &mut game.players[0].borrow_mut().block_or_timer {
+            block.m0v3(0, -1);
+        }
+        game.move_blocks_down(false);
+    }
+}
+
+#[test]
+fn test_stall_decay() {
+    let mut game = create_game(Mode::Ring, 1, Shape::L);
+    game.set_stall_decay(true);
+    game.add_score_for_test(100, false);
+    let name = |game: &Game| game.players[0].borrow().get_name_string(30);
+
+    tick_without_landing(&mut game, STALL_TICKS);
+    assert_eq!(game.get_score(), 100);
+    assert_eq!(name(&game), "Player 0");
+
+    tick_without_landing(&mut game, 3);
+    assert_eq!(game.get_score(), 97);
+    assert_eq!(name(&game), "Player 0 (stalling)");
+
+    // Stalling costs points until the block lands
+    drop_until_landed(&mut game, 0);
+    let score = game.get_score();
+    assert!(score < 97);
+    assert_eq!(name(&game), "Player 0");
+    tick_without_landing(&mut game, 10);
+    assert_eq!(game.get_score(), score);
+
+    // Decaying stops at zero
+    tick_without_landing(&mut game, STALL_TICKS + 200);
+    assert_eq!(game.get_score(), 0);
+    assert_eq!(name(&game), "Player 0 (stalling)");
+}
+
+#[test]
+fn test_stall_decay_only_in_ring_mode() {
+    for (mode, stall_decay) in [(Mode::Ring, false), (Mode::Traditional, true)] {
+        let mut game = create_game(mode, 1, Shape::L);
+        game.set_stall_decay(stall_decay);
+        game.add_score_for_test(100, false);
+        tick_without_landing(&mut game, STALL_TICKS + 10);
+        assert_eq!(game.get_score(), 100);
+        assert_eq!(game.players[0].borrow().get_name_string(30), "Player 0");
+    }
+}
+
+#[test]
+fn test_score_multiplier() {
+    for (player_count, multiplier) in [(1, 1), (2, 2), (3, 4), (4, 8), (5, 16), (6, 32)] {
+        let game = create_game(Mode::Traditional, player_count, Shape::L);
+        assert_eq!(game.get_score_multiplier(), multiplier);
+    }
+}
+
+#[test]
+fn test_inactive_players_dont_multiply_score() {
+    let mut game = create_game(Mode::Traditional, 2, Shape::L);
+    assert!(game.is_counted(0) && game.is_counted(1));
+    game.add_score_for_test(10, true);
+    assert_eq!(game.get_score(), 20);
+
+    game.handle_key_press(0, false, true, KeyPress::Left);
+    assert!(game.is_counted(0));
+    assert!(!game.is_counted(1));
+    assert_eq!(game.get_counted_player_count(), 1);
+    assert_eq!(game.get_score_multiplier(), 1);
+    game.add_score_for_test(10, true);
+    assert_eq!(game.get_score(), 30);
+
+    game.handle_key_press(1, false, true, KeyPress::Left);
+    assert_eq!(game.get_score_multiplier(), 2);
+    game.add_score_for_test(10, true);
+    assert_eq!(game.get_score(), 50);
+}
+
+#[test]
+fn test_score_cap() {
+    assert_eq!(score_multiplier(1000), usize::MAX);
+
+    let mut game = create_game(Mode::Traditional, 6, Shape::L);
+    game.add_score_for_test(MAX_SCORE - 100, false);
+    assert_eq!(format_score(game.get_score()), "999999899");
+    game.add_score_for_test(10, true);
+    assert_eq!(game.get_score(), MAX_SCORE);
+    assert_eq!(format_score(game.get_score()), "999999999+");
+
+    // Would overflow without saturating
+    game.add_score_for_test(usize::MAX, true);
+    game.add_score_for_test(usize::MAX, false);
+    assert_eq!(game.get_score(), MAX_SCORE);
+}
+
+// Fills bottom rows of a player's columns, except the leftmost column so that rows aren't full
+fn build_stack(game: &mut Game, player_idx: usize, rows: usize) {
+    let w = (game.get_width() / game.players.len()) as i16;
+    let h = game.get_height() as i16;
+    for y in (h - rows as i16)..h {
+        for x in (player_idx as i16 * w + 1)..((player_idx as i16 + 1) * w) {
+            game.set_landed_square(
+                (x, y),
+                Some(SquareContent::with_color(Color::YELLOW_FOREGROUND)),
+            );
+        }
+    }
+}
+
+fn get_rescue(game: &Game, player_idx: usize) -> Rescue {
+    game.players[player_idx].borrow().rescue
+}
+
+#[test]
+fn test_rescue_offer() {
+    let mut game = create_game(Mode::Traditional, 2, Shape::L);
+    build_stack(&mut game, 0, 1);
+    build_stack(&mut game, 1, 7);
+    assert_eq!(game.get_stack_height(0), 1);
+    assert_eq!(game.get_stack_height(1), 7);
+    game.offer_rescues_as_needed();
+    assert_eq!(get_rescue(&game, 1), Rescue::NotOffered);
+
+    build_stack(&mut game, 1, 8);
+    game.offer_rescues_as_needed();
+    assert_eq!(get_rescue(&game, 0), Rescue::NotOffered);
+    assert_eq!(get_rescue(&game, 1), Rescue::Offered);
+
+    // Only offered in shared traditional games
+    for (mode, player_count) in [(Mode::Traditional, 1), (Mode::Bottle, 2)] {
+        let mut game = create_game(mode, player_count, Shape::L);
+        build_stack(&mut game, player_count - 1, 10);
+        game.offer_rescues_as_needed();
+        assert_eq!(get_rescue(&game, player_count - 1), Rescue::NotOffered);
+    }
+}
+
+#[test]
+fn test_rescue_accept() {
+    let mut game = create_game(Mode::Traditional, 2, Shape::L);
+    let h = game.get_height() as i16;
+    for y in (h - 3)..h {
+        for x in 0..(game.get_width() as i16) {
+            game.set_landed_square(
+                (x, y),
+                Some(SquareContent::with_color(Color::YELLOW_FOREGROUND)),
+            );
+        }
+    }
+    let full = game.find_full_rows_and_increment_score();
+    game.remove_full_rows(&full);
+    assert_eq!(game.get_score(), 120);
+
+    build_stack(&mut game, 0, 1);
+    build_stack(&mut game, 1, 8);
+    game.offer_rescues_as_needed();
+    assert!(!game.handle_key_press(0, false, true, KeyPress::Character('y')));
+    assert!(game.handle_key_press(1, false, true, KeyPress::Character('Y')));
+    assert_eq!(get_rescue(&game, 1), Rescue::Answered);
+    assert_eq!(game.get_stack_height(0), 1);
+    assert_eq!(game.get_stack_height(1), 4);
+    assert_eq!(game.get_score(), 70);
+
+    // Once per game
+    build_stack(&mut game, 1, 12);
+    game.offer_rescues_as_needed();
+    assert_eq!(get_rescue(&game, 1), Rescue::Answered);
+    assert!(!game.handle_key_press(1, false, true, KeyPress::Character('y')));
+    assert_eq!(game.get_stack_height(1), 12);
+}
+
+#[test]
+fn test_rescue_score_doesnt_go_negative() {
+    let mut game = create_game(Mode::Traditional, 2, Shape::L);
+    build_stack(&mut game, 0, 10);
+    game.offer_rescues_as_needed();
+    assert!(game.handle_key_press(0, false, true, KeyPress::Character('y')));
+    assert_eq!(game.get_stack_height(0), 6);
+    assert_eq!(game.get_score(), 0);
+}
+
+#[test]
+fn test_rescue_decline() {
+    let mut game = create_game(Mode::Traditional, 2, Shape::L);
+    build_stack(&mut game, 0, 10);
+    game.offer_rescues_as_needed();
+    assert!(game.handle_key_press(0, false, true, KeyPress::Character('n')));
+    assert_eq!(get_rescue(&game, 0), Rescue::Answered);
+    assert_eq!(game.get_stack_height(0), 10);
+
+    build_stack(&mut game, 0, 15);
+    game.offer_rescues_as_needed();
+    assert_eq!(get_rescue(&game, 0), Rescue::Answered);
+}
+
+#[test]
+fn test_block_pushed_far_below_playing_area() {
+    let mut game = create_game(Mode::Traditional, 2, Shape::L);
+    game.truncate_height(5);
+    game.move_blocks_down(false);
+
+    // Push player 1's block way below the rows that exist
+    if let BlockOrTimer::Block(block) = &mut game.players[1].borrow_mut().block_or_timer {
+        let (center_x, center_y) = block.center;
+        let coords: Vec<(i32, i32)> = block
+            .get_coords()
+            .iter()
+            .map(|(x, y)| (*x, y + 50))
+            .collect();
+        block.set_player_coords(&coords, (center_x, center_y + 50));
+    }
+    let far_below: Vec<WorldPoint> = current_block_coords(&game, 1)
+        .iter()
+        .map(|p| game.players[1].borrow().player_to_world(*p))
+        .collect();
+
+    for point in &far_below {
+        assert!(!game.is_valid_landed_block_coords(*point));
+        assert!(game.get_any_square(*point, Some(0)).is_some());
+    }
+
+    // Exploding near the block must not touch anything outside the landed rows
+    let flashing = game.get_points_to_flash(&far_below);
+    assert!(flashing.is_empty());
+
+    // The block can't land outside the playing area
+    game.move_blocks_down(false);
+    assert!(matches!(
+        game.players[1].borrow().block_or_timer,
+        BlockOrTimer::TimerPending
+    ));
+    assert!(game.debug_dump().iter().all(|row| !row.contains("LL")));
+}
+
+#[test]
+fn test_rotating_and_bumping_to_walls() {
+    let mut game = create_game(Mode::Traditional, 1, Shape::L);
+    game.truncate_height(5);
+    game.move_blocks_down(false);
+    game.move_blocks_down(false);
+    assert_eq!(
+        dump_game_state(&game),
+        vec![
+            "            FF      ",
+            "        FFFFFF      ",
+            "                    ",
+            "                    ",
+            "                    "
+        ]
+    );
+
+    game.handle_key_press(0, false, true, KeyPress::Up);
+    assert_eq!(
+        dump_game_state(&game),
+        vec![
+            "          FF        ",
+            "          FF        ",
+            "          FFFF      ",
+            "                    ",
+            "                    "
+        ]
+    );
+
+    // Move block all the way to left, shouldn't rotate when against wall
+    for _ in 0..100 {
+        game.handle_key_press(0, false, true, KeyPress::Left);
+    }
+    let all_the_way_to_left = vec![
+        "FF                  ",
+        "FF                  ",
+        "FFFF                ",
+        "                    ",
+        "                    ",
+    ];
+    assert_eq!(dump_game_state(&game), all_the_way_to_left);
+    game.handle_key_press(0, false, true, KeyPress::Up);
+    assert_eq!(dump_game_state(&game), all_the_way_to_left);
+
+    // Move away from wall
+    game.handle_key_press(0, false, true, KeyPress::Right);
+    game.handle_key_press(0, false, true, KeyPress::Up);
+    assert_eq!(
+        dump_game_state(&game),
+        vec![
+            "                    ",
+            "FFFFFF              ",
+            "FF                  ",
+            "                    ",
+            "                    "
+        ]
+    );
+
+    for _ in 0..6 {
+        game.move_blocks_down(false);
+    }
+    game.handle_key_press(0, false, true, KeyPress::Left);
+    game.handle_key_press(0, false, true, KeyPress::Left);
+    game.handle_key_press(0, false, true, KeyPress::Left);
+    let landed_block_prevents_rotation = vec![
+        "                    ",
+        "      FF            ",
+        "  FFFFFF            ",
+        "LLLLLL              ",
+        "LL                  ",
+    ];
+    assert_eq!(dump_game_state(&game), landed_block_prevents_rotation);
+    game.handle_key_press(0, false, true, KeyPress::Up);
+    assert_eq!(dump_game_state(&game), landed_block_prevents_rotation);
+
+    // Move falling block to the right side of landed block, so it can't move left
+    game.handle_key_press(0, false, true, KeyPress::Right);
+    game.handle_key_press(0, false, true, KeyPress::Right);
+    game.move_blocks_down(false);
+    for _ in 0..10 {
+        game.handle_key_press(0, false, true, KeyPress::Left);
+    }
+    assert_eq!(
+        dump_game_state(&game),
+        vec![
+            "                    ",
+            "                    ",
+            "          FF        ",
+            "LLLLLLFFFFFF        ",
+            "LL                  ",
+        ]
+    );
+
+    // Should be possible to slide block under another landed block before it lands
+    game.move_blocks_down(false);
+    for _ in 0..10 {
+        game.handle_key_press(0, false, true, KeyPress::Left);
+    }
+    assert_eq!(
+        dump_game_state(&game),
+        vec![
+            "                    ",
+            "                    ",
+            "                    ",
+            "LLLLLLFF            ",
+            "LLFFFFFF            ",
+        ]
+    );
+
+    game.move_blocks_down(false);
+    assert_eq!(
+        dump_game_state(&game),
+        vec![
+            "                    ",
+            "                    ",
+            "                    ",
+            "LLLLLLLL            ",
+            "LLLLLLLL            ",
+        ]
+    );
+}
+
+// Z blocks aren't tested because they are very similar (mirror image)
 #[test]
 fn test_rotating_s_blocks() {
     let mut game = create_game(Mode::Traditional, 1, Shape::S);
@@ -726,24 +2130,310 @@ fn test_rotating_s_blocks() {
     // S and Z blocks should go back to their original state after two rotations.
     // The rotations should be the same regardless of whether user prefers clockwise or counter-clockwise.
     for _ in 0..10 {
-        game.handle_key_press(0, rand::thread_rng().gen::<bool>(), KeyPress::Up);
+        game.handle_key_press(0, rand::thread_rng().gen::<bool>(), true, KeyPress::Up);
         assert_eq!(dump_game_state(&game), state2);
-        game.handle_key_press(0, rand::thread_rng().gen::<bool>(), KeyPress::Up);
+        game.handle_key_press(0, rand::thread_rng().gen::<bool>(), true, KeyPress::Up);
         assert_eq!(dump_game_state(&game), state1);
     }
 }
 
-fn create_ring_game_with_drills() -> Game {
-    let mut game = Game::new(Mode::Ring);
-    game.set_normal_block_factory(|| FallingBlock::new(BlockType::Drill));
-    for i in 0..3 {
-        game.add_player(&ClientInfo {
-            name: format!("Player {}", i),
-            client_id: i as u64,
-            color: Color::RED_FOREGROUND.fg,
-        });
+fn current_block_coords(game: &Game, player_idx: usize) -> Vec<(i32, i32)> {
+    match &game.players[player_idx].borrow().block_or_timer {
+        BlockOrTimer::Block(block) => block.get_coords(),
+        _ => panic!(),
     }
-    game
+}
+
+fn set_falling_block(
+    game: &mut Game,
+    player_idx: usize,
+    block_type: BlockType,
+    center: PlayerPoint,
+) {
+    let mut player = game.players[player_idx].borrow_mut();
+    let player = &mut *player;
+    let mut block = FallingBlock::new(block_type, &mut player.block_generator.rng);
+    block.spawn_at(center);
+    player.block_or_timer = BlockOrTimer::Block(block);
+}
+
+fn drop_until_landed(game: &mut Game, player_idx: usize) {
+    let old_count = game.players[player_idx].borrow().landed_count;
+    while game.players[player_idx].borrow().landed_count == old_count {
+        game.move_blocks_down(false);
+    }
+}
+
+#[test]
+fn test_line_bomb_traditional() {
+    let mut game = create_game(Mode::Traditional, 1, Shape::L);
+    game.truncate_height(6);
+    let w = game.get_width() as i16;
+    let square = Some(SquareContent::with_color(Color::YELLOW_FOREGROUND));
+    // Bottom row has a hole, and there's a bit of stuff above it
+    for x in 1..w {
+        game.set_landed_square((x, 5), square);
+    }
+    game.set_landed_square((0, 4), square);
+    game.set_landed_square((0, 3), square);
+
+    let spawn_point = game.players[0].borrow().spawn_point;
+    set_falling_block(&mut game, 0, BlockType::LineBomb, spawn_point);
+    game.move_blocks_down(false);
+    // Doesn't do anything while falling
+    assert!(game.find_full_rows_and_increment_score().is_empty());
+
+    drop_until_landed(&mut game, 0);
+    let full = game.find_full_rows_and_increment_score();
+    let mut expected: Vec<WorldPoint> = (0..w).map(|x| (x, 4)).collect();
+    let mut full_sorted = full.clone();
+    full_sorted.sort();
+    expected.sort();
+    assert_eq!(full_sorted, expected);
+    // Half of the 10 points that a full row would give
+    assert_eq!(game.get_score(), 5);
+    assert_eq!(game.get_cleared_row_count(), 1);
+    // Nothing is cleared twice
+    assert!(game.find_full_rows_and_increment_score().is_empty());
+
+    game.remove_full_rows(&full);
+    assert!(game.get_landed_square((0, 4)).is_some()); // moved down from y=3
+    assert!(game.get_landed_square((0, 3)).is_none());
+    assert!(game.get_landed_square((0, 5)).is_none());
+    assert!((1..w).all(|x| game.get_landed_square((x, 5)).is_some()));
+    assert!((1..w).all(|x| game.get_landed_square((x, 4)).is_none()));
+}
+
+#[test]
+fn test_line_bomb_ring() {
+    let mut game = create_game(Mode::Ring, 2, Shape::L);
+    let square = Some(SquareContent::with_color(Color::YELLOW_FOREGROUND));
+    let outer_point = (RING_OUTER_RADIUS + 7, RING_OUTER_RADIUS);
+    game.set_landed_square(outer_point, square);
+
+    // Player 1 waits while player 0's line bomb lands on the innermost ring
+    game.players[1].borrow_mut().fast_down = true;
+    let spawn_point = game.players[0].borrow().spawn_point;
+    set_falling_block(&mut game, 0, BlockType::LineBomb, spawn_point);
+    drop_until_landed(&mut game, 0);
+
+    let full = game.find_full_rows_and_increment_score();
+    let ring = ring_points(RING_INNER_RADIUS + 1);
+    assert_eq!(full.len(), ring.len());
+    assert!(ring.iter().all(|p| full.contains(p)));
+    // Half of 10 points, doubled for two players
+    assert_eq!(game.get_score(), 10);
+
+    game.remove_full_rows(&full);
+    assert!(ring.iter().all(|p| game.get_landed_square(*p).is_none()));
+    assert!(game.get_landed_square(outer_point).is_none());
+    assert!(game
+        .get_landed_square((RING_OUTER_RADIUS + 6, RING_OUTER_RADIUS))
+        .is_some());
+}
+
+#[test]
+fn test_drill_drills_falling_line_bomb() {
+    let mut game = create_game(Mode::Traditional, 2, Shape::L);
+    let (x, y) = game.players[0].borrow().spawn_point;
+    set_falling_block(&mut game, 0, BlockType::Drill, (x, y));
+    set_falling_block(&mut game, 1, BlockType::LineBomb, (x, y + 5));
+
+    // Only the drill moves
+    game.players[1].borrow_mut().fast_down = true;
+    for _ in 0..5 {
+        game.move_blocks_down(false);
+    }
+    let bomb_xs: Vec<i32> = current_block_coords(&game, 1)
+        .iter()
+        .map(|(x, _)| *x)
+        .collect();
+    assert_eq!(bomb_xs.len(), 2);
+    assert!(!bomb_xs.contains(&(x - 1)) && !bomb_xs.contains(&x));
+
+    // What's left of the line bomb still clears the bottom row when it lands
+    game.players[0].borrow_mut().fast_down = true;
+    game.players[1].borrow_mut().fast_down = false;
+    drop_until_landed(&mut game, 1);
+    let bottom = game.get_height() as i16 - 1;
+    let full = game.find_full_rows_and_increment_score();
+    assert_eq!(full.len(), game.get_width());
+    assert!(full.iter().all(|(_, y)| *y == bottom));
+}
+
+#[test]
+fn test_rotating_never_overlaps() {
+    let mut game = create_game(Mode::Traditional, 1, Shape::L);
+    game.truncate_height(6);
+    game.move_blocks_down(false);
+    game.move_blocks_down(false);
+    game.move_blocks_down(false);
+
+    let old_coords = current_block_coords(&game, 0);
+    let rotated_coords = match &game.players[0].borrow().block_or_timer {
+        BlockOrTimer::Block(block) => block.get_rotated_coords(false),
+        _ => panic!(),
+    };
+    let (x, y) = *rotated_coords
+        .iter()
+        .find(|p| !old_coords.contains(p))
+        .unwrap();
+    let blocking_point = (x as i16, y as i16);
+
+    game.set_landed_square(
+        blocking_point,
+        Some(SquareContent::with_color(Color::RED_BACKGROUND)),
+    );
+    assert!(!game.handle_key_press(0, false, true, KeyPress::Up));
+    assert_eq!(current_block_coords(&game, 0), old_coords);
+
+    game.set_landed_square(blocking_point, None);
+    assert!(game.handle_key_press(0, false, true, KeyPress::Up));
+    assert_eq!(current_block_coords(&game, 0), rotated_coords);
+}
+
+#[test]
+fn test_drills_drill_only_when_moving_down() {
+    let mut game = Game::new(Mode::Traditional);
+    game.set_normal_block_factory(|generator| {
+        FallingBlock::new(BlockType::Drill, &mut generator.rng)
+    });
+    game.add_player(&player_spec(0));
+    game.truncate_height(8);
+    for _ in 0..4 {
+        game.move_blocks_down(false);
+    }
+
+    // Fill the column to the left of the drill, and the bottom row
+    let min_x = current_block_coords(&game, 0)
+        .iter()
+        .map(|(x, _)| *x)
+        .min()
+        .unwrap();
+    let full = Some(SquareContent::with_color(Color::RED_BACKGROUND));
+    for y in 0..8 {
+        game.set_landed_square(((min_x - 1) as i16, y), full);
+    }
+    for x in 0..(game.get_width() as i16) {
+        game.set_landed_square((x, 7), full);
+    }
+
+    // Moving sideways doesn't drill
+    let old_coords = current_block_coords(&game, 0);
+    assert!(!game.handle_key_press(0, false, true, KeyPress::Left));
+    assert_eq!(current_block_coords(&game, 0), old_coords);
+    assert!(game.get_landed_square(((min_x - 1) as i16, 3)).is_some());
+
+    // Landing place prediction knows that the drill will go through the bottom row
+    let predicted = game.predict_landing_place(0);
+    assert_eq!(predicted.iter().map(|(_, y)| *y).max(), Some(7));
+}
+
+#[test]
+fn test_new_drills_dont_drill() {
+    let mut game = create_game_with(Mode::Traditional, 2, |game| {
+        game.set_normal_block_factory(|generator| {
+            FallingBlock::new(BlockType::Drill, &mut generator.rng)
+        })
+    });
+
+    // Move player 0's drill out of the way, then put player 1's drill where player 0's blocks spawn
+    let (spawn_x, _) = game.players[0].borrow().spawn_point;
+    game.handle_key_press(0, false, true, KeyPress::Down);
+    for _ in 0..5 {
+        game.move_blocks_down(true);
+    }
+    while current_block_coords(&game, 1)
+        .iter()
+        .all(|(x, _)| *x != spawn_x)
+    {
+        assert!(game.handle_key_press(1, false, true, KeyPress::Left));
+    }
+
+    // Holding gets a new drill, but it can't drill its way in
+    game.handle_key_press(0, false, true, KeyPress::Character('h'));
+    assert!(matches!(
+        game.players[0].borrow().block_or_timer,
+        BlockOrTimer::TimerPending
+    ));
+}
+
+#[test]
+fn test_other_keys_cancel_soft_drop_setting() {
+    let mut game = create_game(Mode::Traditional, 1, Shape::L);
+
+    game.handle_key_press(0, false, false, KeyPress::Down);
+    assert!(game.players[0].borrow().fast_down);
+    game.handle_key_press(0, false, false, KeyPress::Up);
+    assert!(game.players[0].borrow().fast_down);
+
+    // This is the default
+    game.handle_key_press(0, false, true, KeyPress::Up);
+    assert!(!game.players[0].borrow().fast_down);
+}
+
+#[test]
+fn test_countdown() {
+    let mut game = create_game(Mode::Traditional, 1, Shape::L);
+    assert!(!game.is_counting_down());
+    let start = Instant::now();
+    let d = COUNTDOWN_DURATION;
+    game.start_countdown(start);
+    assert!(game.is_counting_down());
+
+    // Can move and rotate, but not move down
+    let coords = current_block_coords(&game, 0);
+    assert!(game.handle_key_press(0, false, true, KeyPress::Left));
+    assert!(game.handle_key_press(0, false, true, KeyPress::Up));
+    assert_ne!(current_block_coords(&game, 0), coords);
+    game.handle_key_press(0, false, true, KeyPress::Down);
+    assert!(!game.players[0].borrow().fast_down);
+
+    assert!(game.update_countdown(start + d / 2));
+    let spec = player_spec(1);
+    game.add_player(&spec);
+    game.restart_countdown_once(start + d / 2);
+    assert_eq!(game.get_countdown_remaining(start + d / 2), Some(d));
+
+    // Restarts only once
+    game.remove_player_if_exists(1, LeaveReason::Quit);
+    game.add_player(&spec);
+    game.restart_countdown_once(start + d);
+    assert_eq!(game.get_countdown_remaining(start + d), Some(d / 2));
+
+    // Time spent in the pause menu doesn't count
+    game.postpone_countdown(d);
+    assert_eq!(game.get_countdown_remaining(start + d), Some(d * 3 / 2));
+    assert!(game.update_countdown(start + d * 2));
+    assert!(game.is_counting_down());
+    assert!(!game.update_countdown(start + d * 5 / 2));
+    assert!(!game.is_counting_down());
+
+    game.handle_key_press(0, false, true, KeyPress::Down);
+    assert!(game.players[0].borrow().fast_down);
+}
+
+#[test]
+fn test_flashing_point_expiry() {
+    let mut game = create_game(Mode::Traditional, 1, Shape::L);
+    let start = Instant::now();
+    game.set_flashing_point((1, 2), 41, start);
+    game.set_flashing_point((3, 4), 41, start + FLASHING_POINT_EXPIRY / 2);
+
+    assert!(!game.prune_expired_flashing_points(start));
+    assert!(game.prune_expired_flashing_points(start + FLASHING_POINT_EXPIRY));
+    assert_eq!(game.flashing_points.keys().collect::<Vec<_>>(), [&(3, 4)]);
+    assert!(game.prune_expired_flashing_points(start + FLASHING_POINT_EXPIRY * 2));
+    assert!(game.flashing_points.is_empty());
+    assert!(!game.prune_expired_flashing_points(start + FLASHING_POINT_EXPIRY * 3));
+}
+
+fn create_ring_game_with_drills() -> Game {
+    create_game_with(Mode::Ring, 3, |game| {
+        game.set_normal_block_factory(|generator| {
+            FallingBlock::new(BlockType::Drill, &mut generator.rng)
+        })
+    })
 }
 
 #[test]
@@ -826,15 +2516,11 @@ fn test_displaying_and_animating_falling_drills() {
 
 #[test]
 fn test_displaying_landed_drills() {
-    let mut game = Game::new(Mode::Ring);
-    game.set_normal_block_factory(|| FallingBlock::new(BlockType::Drill));
-    for i in 0..3 {
-        game.add_player(&ClientInfo {
-            name: format!("Player {}", i),
-            client_id: i as u64,
-            color: Color::RED_FOREGROUND.fg,
-        });
-    }
+    let mut game = create_game_with(Mode::Ring, 3, |game| {
+        game.set_normal_block_factory(|generator| {
+            FallingBlock::new(BlockType::Drill, &mut generator.rng)
+        })
+    });
 
     // Make sure that drills show up correctly once landed
     let has_landed_squares = |game: &Game| {
@@ -884,3 +2570,512 @@ fn test_displaying_landed_drills() {
     game.animate_drills();
     assert_eq!(dump_game_state(&game), dump_before_land);
 }
+
+// Doesn't change when players join or leave, and full rows disappear like in traditional mode
+struct TinyBoard;
+
+impl BoardBehavior for TinyBoard {
+    fn initial_rows(&self) -> LandedRows {
+        vec![vec![None; 4]; 4]
+    }
+
+    fn width(&self, _player_count: usize, rows: &LandedRows) -> usize {
+        rows[0].len()
+    }
+
+    fn is_valid_landed_coords(&self, point: WorldPoint, width: usize, height: usize) -> bool {
+        let (x, y) = point;
+        (0..width as i16).contains(&x) && (0..height as i16).contains(&y)
+    }
+
+    fn spawn_point_for(&self, _player_idx: usize, _player_count: usize) -> PlayerPoint {
+        (1, 0)
+    }
+
+    // Only one player fits
+    fn down_direction_for_new_player(&self, used: &[WorldPoint]) -> Option<WorldPoint> {
+        if used.is_empty() {
+            Some((0, 1))
+        } else {
+            None
+        }
+    }
+
+    fn add_player(
+        &mut self,
+        _rows: &mut LandedRows,
+        _players: &[RefCell<Player>],
+        _player_idx: usize,
+        _handicap: u8,
+        _rng: &mut StdRng,
+    ) -> AddPlayerEffects {
+        AddPlayerEffects::default()
+    }
+
+    fn remove_player(
+        &mut self,
+        _rows: &mut LandedRows,
+        _players: &[RefCell<Player>],
+        _player_idx: usize,
+    ) -> Vec<usize> {
+        vec![]
+    }
+
+    fn clear_region_for_player(
+        &self,
+        rows: &mut LandedRows,
+        _players: &[RefCell<Player>],
+        _player_idx: usize,
+    ) {
+        *rows = self.initial_rows();
+    }
+
+    fn find_full_groups(&self, rows: &LandedRows, _player_count: usize) -> FullGroups {
+        let mut result = FullGroups::default();
+        for (y, row) in rows.iter().enumerate() {
+            if row.iter().all(|cell| cell.is_some()) {
+                result.everyone_count += 1;
+                result
+                    .points
+                    .extend((0..row.len()).map(|x| (x as i16, y as i16)));
+            }
+        }
+        result
+    }
+
+    fn shift_after_clear(&self, rows: &mut LandedRows, full: &[WorldPoint], _player_count: usize) {
+        for y in 0..rows.len() {
+            if full.contains(&(0, y as i16)) {
+                rows[..(y + 1)].rotate_right(1);
+                rows[0] = vec![None; 4];
+            }
+        }
+    }
+}
+
+#[test]
+fn test_custom_board() {
+    let mut game = Game::new_with_board(Mode::Traditional, Box::new(TinyBoard));
+    game.set_normal_block_factory(|_| FallingBlock::normal_from_shape(Shape::L));
+    game.add_player(&player_spec(0));
+    assert_eq!(game.get_width(), 4);
+    assert_eq!(game.get_bounds_in_player_coords(), (0, 4, 0, 4));
+    assert_eq!(game.players[0].borrow().spawn_point, (1, 0));
+    assert!(!game.is_valid_landed_block_coords((4, 0)));
+
+    let garbage = SquareContent::with_color(Color::GRAY_BACKGROUND);
+    game.set_landed_square((0, 2), Some(garbage));
+    for x in 0..4 {
+        game.set_landed_square((x, 3), Some(garbage));
+    }
+    let full = game.find_full_rows_and_increment_score();
+    assert_eq!(full, vec![(0, 3), (1, 3), (2, 3), (3, 3)]);
+    assert_eq!(game.get_score(), 10);
+
+    game.remove_full_rows(&full);
+    assert!(game.get_landed_square((0, 3)).is_some());
+    assert!(game.get_landed_square((1, 3)).is_none());
+    assert!(game.get_landed_square((0, 2)).is_none());
+}
+
+fn capture_logs(game: &mut Game) -> Arc<Mutex<Vec<String>>> {
+    let lines = Arc::new(Mutex::new(vec![]));
+    let lines2 = lines.clone();
+    game.set_logger(Box::new(move |client_id, message| {
+        lines2
+            .lock()
+            .unwrap()
+            .push(format!("[client {}] {}", client_id, message))
+    }));
+    lines
+}
+
+#[test]
+fn test_invariant_violation_in_please_wait_counter() {
+    let mut game = create_game(Mode::Traditional, 2, Shape::L);
+    let logs = capture_logs(&mut game);
+    game.set_landed_square(
+        (3, 10),
+        Some(SquareContent::with_color(Color::YELLOW_FOREGROUND)),
+    );
+    game.players[0].borrow_mut().block_or_timer = BlockOrTimer::Timer(0);
+
+    assert!(!game.tick_please_wait_counter(0));
+    assert!(matches!(
+        game.players[0].borrow().block_or_timer,
+        BlockOrTimer::Block(_)
+    ));
+    assert!(game.get_landed_square((3, 10)).is_none());
+
+    let logs = logs.lock().unwrap();
+    assert_eq!(
+        logs[0],
+        "[client 0] Invariant violated: please wait counter is already 0"
+    );
+    assert_eq!(logs[1], "[client 0]   Traditional game, score 0");
+    assert_eq!(
+        logs[2],
+        "[client 0]   player \"Player 0\" (client 0): Timer(0), fast_down=false"
+    );
+    assert!(logs[3].starts_with("[client 0]   player \"Player 1\" (client 1): Block"));
+    assert_eq!(
+        logs[14],
+        format!("[client 0]   |      LL{}|", " ".repeat(32))
+    );
+    assert_eq!(logs.len(), 4 + game.get_height());
+}
+
+#[test]
+fn test_invariant_violation_without_falling_block() {
+    let mut game = create_game(Mode::Traditional, 1, Shape::L);
+    let logs = capture_logs(&mut game);
+    game.players[0].borrow_mut().block_or_timer = BlockOrTimer::TimerPending;
+
+    assert!(!game.change_falling_block(0, "rotate", |block| block.rotate(false)));
+    assert!(!game.change_falling_block(0, "move", |block| block.m0v3(1, 0)));
+
+    // Second violation doesn't dump the whole state again
+    let logs = logs.lock().unwrap();
+    let violations: Vec<&String> = logs
+        .iter()
+        .filter(|line| line.contains("Invariant violated"))
+        .collect();
+    assert_eq!(
+        violations,
+        [
+            "[client 0] Invariant violated: no block to rotate",
+            "[client 0] Invariant violated: no block to move"
+        ]
+    );
+    assert_eq!(logs.len(), 2 + 2 + game.get_height());
+    assert_eq!(logs.last().unwrap(), violations[1]);
+}
+
+#[test]
+fn test_invariant_violation_without_down_direction() {
+    let mut game = Game::new_with_board(Mode::Traditional, Box::new(TinyBoard));
+    let logs = capture_logs(&mut game);
+    assert!(game.add_player(&player_spec(0)));
+    assert!(!game.add_player(&player_spec(1)));
+    assert_eq!(game.players.len(), 1);
+    assert_eq!(
+        logs.lock().unwrap()[0],
+        "[client 1] Invariant violated: no free down direction"
+    );
+
+    let ring = RingBoard;
+    let all = [(0, 1), (0, -1), (1, 0), (-1, 0)];
+    assert_eq!(ring.down_direction_for_new_player(&all[..3]), Some((-1, 0)));
+    assert_eq!(ring.down_direction_for_new_player(&all), None);
+}
+
+#[test]
+fn test_invariant_violation_without_block_to_land() {
+    let mut game = create_game(Mode::Traditional, 2, Shape::L);
+    let logs = capture_logs(&mut game);
+    let (x, y) = game.players[1].borrow().spawn_point;
+    for floor_x in [x - 1, x] {
+        game.set_landed_square(
+            (floor_x as i16, (y + 8) as i16),
+            Some(SquareContent::with_color(Color::YELLOW_FOREGROUND)),
+        );
+    }
+    set_falling_block(&mut game, 0, BlockType::Drill, (x, y + 6));
+    set_falling_block(
+        &mut game,
+        1,
+        BlockType::Bomb { initial_timer: 15 },
+        (x, y + 8),
+    );
+
+    // The drill takes the top half of the bomb, which doesn't move
+    game.players[1].borrow_mut().fast_down = true;
+    game.move_blocks_down(false);
+    assert_eq!(current_block_coords(&game, 1).len(), 2);
+    assert!(logs.lock().unwrap().is_empty());
+
+    // The bomb can't fall and is about to land, but gets drilled away.
+    // During wind down, there's no new block to land instead.
+    game.start_wind_down();
+    game.players[1].borrow_mut().fast_down = false;
+    game.move_blocks_down(false);
+    assert!(matches!(
+        game.players[1].borrow().block_or_timer,
+        BlockOrTimer::WindingDown
+    ));
+    assert_eq!(
+        logs.lock().unwrap()[0],
+        "[client 1] Invariant violated: no block to land"
+    );
+}
+
+fn drop_block(game: &mut Game, client_id: u64) {
+    game.handle_key_press(client_id, false, true, KeyPress::Down);
+    while game.move_blocks_down(true) {}
+    game.move_blocks_down(false);
+}
+
+fn press_u(game: &mut Game, client_id: u64) -> bool {
+    game.handle_key_press(client_id, false, true, KeyPress::Character('u'))
+}
+
+#[test]
+fn test_undo() {
+    let mut game = create_game(Mode::Zen, 1, Shape::L);
+    game.set_undo_enabled(true);
+    game.truncate_height(4);
+    let l_count = |game: &Game| game.get_block_counts()[&BlockKind::Normal(Shape::L)];
+
+    drop_block(&mut game, 0);
+    let spawn_coords = current_block_coords(&game, 0);
+    let before_landing = dump_game_state(&game);
+    assert!(!before_landing.iter().all(|row| row.trim().is_empty()));
+    assert!(game.can_undo());
+
+    game.move_blocks_down(false);
+    drop_block(&mut game, 0);
+    assert_ne!(dump_game_state(&game), before_landing);
+    assert_eq!(game.get_landed_block_count(), 2);
+    assert_eq!(l_count(&game), 3);
+
+    // The block goes back to where blocks spawn, not to where it was when landing
+    assert!(press_u(&mut game, 0));
+    assert_eq!(dump_game_state(&game), before_landing);
+    assert_eq!(game.get_landed_block_count(), 1);
+    assert_eq!(l_count(&game), 2);
+    assert_eq!(current_block_coords(&game, 0), spawn_coords);
+
+    // Only the latest landing can be undone
+    assert!(!game.can_undo());
+    assert!(!press_u(&mut game, 0));
+}
+
+#[test]
+fn test_undo_after_clearing_rows() {
+    let mut game = create_game(Mode::Zen, 1, Shape::L);
+    game.set_undo_enabled(true);
+    game.truncate_height(4);
+
+    // Fill the bottom row except where the first block will land
+    for x in 0..(game.get_width() as i16) {
+        if !(4..7).contains(&x) {
+            game.set_landed_square(
+                (x, 3),
+                Some(SquareContent::with_color(Color::YELLOW_FOREGROUND)),
+            );
+        }
+    }
+    let before_landing = dump_game_state(&game);
+    drop_block(&mut game, 0);
+
+    // Can't undo while the full row is waiting to be removed
+    assert!(!game.can_undo());
+    let full = game.find_full_rows_and_increment_score();
+    assert_eq!(game.get_score(), 10);
+    assert_eq!(game.get_cleared_row_count(), 1);
+    game.remove_full_rows(&full);
+    assert_eq!(
+        dump_game_state(&game),
+        [
+            "                    ",
+            "                    ",
+            "                    ",
+            "            LL      ",
+        ]
+    );
+
+    // The block goes back above the visible area
+    assert!(press_u(&mut game, 0));
+    assert_eq!(dump_game_state(&game), before_landing);
+    assert_eq!(game.get_score(), 0);
+    assert_eq!(game.get_cleared_row_count(), 0);
+}
+
+#[test]
+fn test_no_undo_after_hold() {
+    let mut game = create_game(Mode::Zen, 1, Shape::L);
+    game.set_undo_enabled(true);
+    drop_block(&mut game, 0);
+    assert!(game.can_undo());
+    game.handle_key_press(0, false, true, KeyPress::Character('h'));
+    assert!(!game.can_undo());
+    assert!(!press_u(&mut game, 0));
+}
+
+#[test]
+fn test_undo_only_when_enabled_and_alone() {
+    let mut game = create_game(Mode::Zen, 1, Shape::L);
+    drop_block(&mut game, 0);
+    assert!(!game.can_undo());
+    assert!(!press_u(&mut game, 0));
+
+    let mut game = create_game(Mode::Traditional, 2, Shape::L);
+    game.set_undo_enabled(true);
+    drop_block(&mut game, 0);
+    assert!(!game.can_undo());
+    assert!(!press_u(&mut game, 0));
+}
+
+#[test]
+fn test_wind_down() {
+    let mut game = create_game(Mode::Traditional, 1, Shape::L);
+    game.truncate_height(5);
+    game.handle_key_press(0, false, true, KeyPress::Character('h'));
+    game.start_wind_down();
+    assert!(game.is_winding_down());
+    assert!(game.time_limit_reached());
+
+    // Swapping with the held block is fine
+    assert!(game.handle_key_press(0, false, true, KeyPress::Character('h')));
+    assert!(matches!(
+        game.players[0].borrow().block_or_timer,
+        BlockOrTimer::Block(_)
+    ));
+
+    drop_block(&mut game, 0);
+    assert_eq!(game.get_landed_block_count(), 1);
+    assert!(matches!(
+        game.players[0].borrow().block_or_timer,
+        BlockOrTimer::WindingDown
+    ));
+    assert!(!game.move_blocks_down(false));
+    assert_eq!(game.start_pending_please_wait_counters(), Some(vec![]));
+
+    game.finish_wind_down();
+    assert!(!game.is_winding_down());
+    assert!(game.time_limit_reached());
+    assert_eq!(game.start_pending_please_wait_counters(), None);
+}
+
+// Longest number of blocks between two blocks of the same shape
+fn max_drought(mut next_kind: impl FnMut() -> BlockKind) -> usize {
+    let mut last_seen: HashMap<BlockKind, usize> = HashMap::new();
+    let mut result = 0;
+    for i in 0..10_000 {
+        let previous = last_seen.insert(next_kind(), i).unwrap_or(0);
+        result = max(result, i - previous);
+    }
+    result
+}
+
+// The seed is fixed, so this gives the same result every time with the same version of rand
+#[test]
+fn test_droughts_are_short() {
+    let seed = 123;
+    let mut generator =
+        BlockGenerator::new(StdRng::seed_from_u64(seed), StdRng::seed_from_u64(seed));
+    let biased = max_drought(|| generator.next_normal_block().kind);
+
+    let mut rng = StdRng::seed_from_u64(seed);
+    let unbiased = max_drought(|| FallingBlock::new(BlockType::Normal, &mut rng).kind);
+
+    assert!(biased < 50, "{}", biased);
+    assert!(unbiased > 50, "{}", unbiased);
+}
+
+// Doesn't sample anything, so that the result doesn't depend on the random number generator
+#[test]
+fn test_missing_shapes_get_more_likely() {
+    let weights = shape_weights(&[0, 1, 6, 12, 24, 25, 1000]);
+    assert_eq!(weights, [1.0, 1.0 + 1.0 / 12.0, 1.5, 2.0, 3.0, 3.0, 3.0]);
+}
+
+// Runs the game with and without spawn delay. The function ends a falling block and returns whose it was.
+fn check_spawn_delay(player_count: usize, end_block: fn(&mut Game) -> usize) {
+    for (spawn_delay, expected_ticks) in [(false, 0), (true, 1)] {
+        let mut game = create_game(Mode::Traditional, player_count, Shape::L);
+        game.truncate_height(6);
+        game.set_spawn_delay(spawn_delay);
+        let player_idx = end_block(&mut game);
+
+        let mut ticks = 0;
+        while !matches!(
+            game.players[player_idx].borrow().block_or_timer,
+            BlockOrTimer::Block(_)
+        ) {
+            // Soft drop must not carry over to the next block
+            game.handle_key_press(player_idx as u64, false, true, KeyPress::Down);
+            game.move_blocks_down(false);
+            ticks += 1;
+        }
+        assert_eq!(ticks, expected_ticks);
+        assert!(!game.players[player_idx].borrow().fast_down);
+        // The new block hasn't moved yet, so it's still above the playing area
+        assert!(current_block_coords(&game, player_idx)
+            .iter()
+            .all(|(_, y)| *y < 0));
+    }
+}
+
+#[test]
+fn test_spawn_delay_after_landing() {
+    check_spawn_delay(1, |game| {
+        drop_until_landed(game, 0);
+        0
+    });
+}
+
+#[test]
+fn test_spawn_delay_after_bomb() {
+    check_spawn_delay(1, |game| {
+        let points: Vec<WorldPoint> = {
+            let player = game.players[0].borrow();
+            player
+                .block_or_timer
+                .get_coords()
+                .iter()
+                .map(|p| player.player_to_world(*p))
+                .collect()
+        };
+        game.finish_explosion(&[], &points);
+        0
+    });
+}
+
+#[test]
+fn test_spawn_delay_after_drilling() {
+    check_spawn_delay(2, |game| {
+        let is_bomb = |game: &Game| {
+            matches!(
+                &game.players[1].borrow().block_or_timer,
+                BlockOrTimer::Block(b) if b.kind == BlockKind::Bomb
+            )
+        };
+        let (x, y) = game.players[0].borrow().spawn_point;
+        set_falling_block(game, 0, BlockType::Drill, (x, y));
+        set_falling_block(game, 1, BlockType::Bomb { initial_timer: 15 }, (x, y + 5));
+        game.players[1].borrow_mut().fast_down = true;
+        for _ in 0..10 {
+            if !is_bomb(game) {
+                break;
+            }
+            game.move_blocks_down(false);
+        }
+        assert!(!is_bomb(game));
+        1
+    });
+}
+
+#[test]
+fn test_spawn_delay_after_hold() {
+    check_spawn_delay(1, |game| {
+        assert!(game.handle_key_press(0, false, true, KeyPress::Character('h')));
+        0
+    });
+}
+
+#[test]
+fn test_spawn_delay_after_wait_counter() {
+    check_spawn_delay(1, |game| {
+        game.set_waiting_ends_game(false);
+        while !matches!(
+            game.players[0].borrow().block_or_timer,
+            BlockOrTimer::TimerPending
+        ) {
+            game.move_blocks_down(false);
+        }
+        assert_eq!(game.start_pending_please_wait_counters(), Some(vec![0]));
+        while game.tick_please_wait_counter(0) {}
+        0
+    });
+}
@@ -0,0 +1,254 @@
+use crate::escapes::Color;
+use crate::game_logic::blocks::SquareContent;
+use crate::game_logic::board::clear_columns;
+use crate::game_logic::board::insert_columns;
+use crate::game_logic::board::remove_columns;
+use crate::game_logic::board::AddPlayerEffects;
+use crate::game_logic::board::BoardBehavior;
+use crate::game_logic::board::BoardOptions;
+use crate::game_logic::board::FullGroups;
+use crate::game_logic::board::LandedRows;
+use crate::game_logic::player::BlockOrTimer;
+use crate::game_logic::player::Player;
+use crate::game_logic::PlayerPoint;
+use crate::game_logic::WorldPoint;
+use rand::rngs::StdRng;
+use rand::Rng;
+use std::cell::RefCell;
+
+// Players side by side, used in traditional and zen games
+pub struct TraditionalBoard {
+    big_blocks: bool, // big blocks don't fit in narrow areas
+    zen: bool,
+}
+
+impl TraditionalBoard {
+    pub fn new(options: &BoardOptions, zen: bool) -> Self {
+        Self {
+            big_blocks: options.big_blocks,
+            zen,
+        }
+    }
+
+    // Garbage rows at the bottom of the player's columns, each with one hole.
+    // They are not added again when the playing area is cleared.
+    fn add_handicap_rows(
+        &self,
+        rows: &mut LandedRows,
+        player_count: usize,
+        player_idx: usize,
+        row_count: u8,
+        rng: &mut StdRng,
+    ) {
+        let w = self.width_per_player(player_count).unwrap();
+        let left = w * player_idx;
+        let garbage = SquareContent::with_color(Color::GRAY_BACKGROUND);
+
+        let h = rows.len();
+        for row in &mut rows[h.saturating_sub(row_count as usize)..h] {
+            let hole_x = rng.gen_range(0..w);
+            for (x, square) in row[left..(left + w)].iter_mut().enumerate() {
+                *square = if x == hole_x { None } else { Some(garbage) };
+            }
+        }
+    }
+
+    /*
+    Called when a player joins and everyone gets fewer columns. Columns are
+    deleted from the right side of each old player's area, and the new
+    right edge flashes on rows that lost landed squares. Falling blocks move
+    left to stay in their area, unless that would overlap landed squares.
+    */
+    fn shrink_areas_for_new_player(
+        rows: &mut LandedRows,
+        players: &[RefCell<Player>],
+        new_player_idx: usize,
+        old_w: usize,
+        new_w: usize,
+    ) -> AddPlayerEffects {
+        let mut effects = AddPlayerEffects::default();
+
+        // Right to left, so that removing columns doesn't affect areas not handled yet
+        for area_idx in (0..(players.len() - 1)).rev() {
+            let left = area_idx * old_w;
+            let cut_start = left + new_w;
+            let cut_end = left + old_w;
+
+            // Flash points are in coordinates after the new player's columns are added
+            let final_idx = if area_idx >= new_player_idx {
+                area_idx + 1
+            } else {
+                area_idx
+            };
+            let flash_x = (final_idx * new_w + new_w - 1) as i16;
+            for (y, row) in rows.iter().enumerate() {
+                if row[cut_start..cut_end].iter().any(|s| s.is_some()) {
+                    effects.flash_points.push((flash_x, y as i16));
+                }
+            }
+
+            for (player_idx, player) in players.iter().enumerate() {
+                if let BlockOrTimer::Block(block) = &mut player.borrow_mut().block_or_timer {
+                    let coords = block.get_coords();
+                    let min_x = coords.iter().map(|(x, _)| *x).min().unwrap();
+                    let max_x = coords.iter().map(|(x, _)| *x).max().unwrap();
+                    if min_x < left as i32 || max_x < cut_start as i32 || max_x >= cut_end as i32 {
+                        continue;
+                    }
+
+                    let dx = max_x - (cut_start as i32 - 1);
+                    let new_points: Vec<PlayerPoint> =
+                        coords.iter().map(|(x, y)| (x - dx, *y)).collect();
+                    let fits = new_points.iter().all(|&(x, y)| {
+                        x >= left as i32 && (y < 0 || rows[y as usize][x as usize].is_none())
+                    });
+                    if fits {
+                        let (center_x, center_y) = block.center;
+                        block.set_player_coords(&new_points, (center_x - dx, center_y));
+                    } else {
+                        // Better than leaving behind a block that got cut in half
+                        effects.need_new_block.push(player_idx);
+                    }
+                }
+            }
+
+            effects.need_new_block.extend(remove_columns(
+                rows,
+                players,
+                cut_start,
+                cut_end - cut_start,
+            ));
+        }
+        effects
+    }
+}
+
+impl BoardBehavior for TraditionalBoard {
+    fn initial_rows(&self) -> LandedRows {
+        vec![vec![]; 25]
+    }
+
+    fn width(&self, player_count: usize, _rows: &LandedRows) -> usize {
+        self.width_per_player(player_count).unwrap() * player_count
+    }
+
+    // Two players fit side by side with full width, more players must squeeze
+    fn width_per_player(&self, player_count: usize) -> Option<usize> {
+        if player_count >= 3 && !self.big_blocks {
+            Some(7)
+        } else {
+            Some(10)
+        }
+    }
+
+    fn is_valid_landed_coords(&self, point: WorldPoint, width: usize, height: usize) -> bool {
+        let (x, y) = point;
+        (0..width as i16).contains(&x) && (0..height as i16).contains(&y)
+    }
+
+    fn spawn_point_for(&self, player_idx: usize, player_count: usize) -> PlayerPoint {
+        let w = self.width_per_player(player_count).unwrap() as i32;
+        let i = player_idx as i32;
+        ((i * w) + (w / 2), 0)
+    }
+
+    fn add_player(
+        &mut self,
+        rows: &mut LandedRows,
+        players: &[RefCell<Player>],
+        player_idx: usize,
+        handicap: u8,
+        rng: &mut StdRng,
+    ) -> AddPlayerEffects {
+        let old_w = self.width_per_player(players.len() - 1).unwrap();
+        let w = self.width_per_player(players.len()).unwrap();
+        let effects = if w < old_w {
+            Self::shrink_areas_for_new_player(rows, players, player_idx, old_w, w)
+        } else {
+            AddPlayerEffects::default()
+        };
+
+        if player_idx == players.len() - 1 {
+            for row in rows.iter_mut() {
+                row.resize(w * players.len(), None);
+            }
+        } else {
+            insert_columns(rows, players, w * player_idx, w);
+        }
+        self.add_handicap_rows(rows, players.len(), player_idx, handicap, rng);
+        effects
+    }
+
+    fn remove_player(
+        &mut self,
+        rows: &mut LandedRows,
+        players: &[RefCell<Player>],
+        player_idx: usize,
+    ) -> Vec<usize> {
+        let old_w = self.width_per_player(players.len() + 1).unwrap();
+        let w = self.width_per_player(players.len()).unwrap();
+        let need_new_block = remove_columns(rows, players, old_w * player_idx, old_w);
+
+        // Remaining players get empty columns on the right side of their area
+        if w > old_w {
+            for area_idx in (0..players.len()).rev() {
+                insert_columns(rows, players, (area_idx + 1) * old_w, w - old_w);
+            }
+        }
+        need_new_block
+    }
+
+    fn clear_region_for_player(
+        &self,
+        rows: &mut LandedRows,
+        players: &[RefCell<Player>],
+        player_idx: usize,
+    ) {
+        let w = self.width_per_player(players.len()).unwrap();
+        clear_columns(rows, w * player_idx, w * (player_idx + 1));
+    }
+
+    fn find_full_groups(&self, rows: &LandedRows, _player_count: usize) -> FullGroups {
+        let mut result = FullGroups::default();
+        for (y, row) in rows.iter().enumerate() {
+            if !row.iter().any(|cell| cell.is_none()) {
+                result.everyone_count += 1;
+                for (x, _) in row.iter().enumerate() {
+                    result.points.push((x as i16, y as i16));
+                }
+            }
+        }
+        result
+    }
+
+    fn shift_after_clear(&self, rows: &mut LandedRows, full: &[WorldPoint], _player_count: usize) {
+        for y in 0..rows.len() {
+            if full.contains(&(0, y as i16)) {
+                rows[..(y + 1)].rotate_right(1);
+                for cell in &mut rows[0] {
+                    *cell = None;
+                }
+            }
+        }
+    }
+
+    // In zen mode, there's no waiting time when you fill your playing area.
+    // Instead, the bottom half of it is cleared and everything above moves down.
+    fn clear_filled_area(&self, rows: &mut LandedRows) -> bool {
+        if !self.zen {
+            return false;
+        }
+        let h = rows.len();
+        let w = rows[0].len();
+        rows.truncate(h - h / 2);
+        for _ in 0..(h / 2) {
+            rows.insert(0, vec![None; w]);
+        }
+        true
+    }
+
+    // Only in traditional games, because zen games have only one player
+    fn offers_rescues(&self) -> bool {
+        !self.zen
+    }
+}
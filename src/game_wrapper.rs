@@ -1,17 +1,32 @@
 use crate::escapes::Color;
+use crate::escapes::KeyPress;
 use crate::game_logic::game::Game;
+use crate::game_logic::game::Mode;
+use crate::game_logic::game::FLASHING_POINT_EXPIRY;
 use crate::game_logic::WorldPoint;
 use crate::high_scores::add_result_and_get_high_scores;
+use crate::high_scores::add_sprint_record;
 use crate::high_scores::read_all_high_scores;
 use crate::high_scores::AllHighScores;
 use crate::high_scores::GameResult;
 use crate::high_scores::HighScoresForGame;
+use crate::high_scores::SprintRecord;
+use crate::high_scores::SPRINT_ROW_COUNT;
+use crate::logging;
 use chrono::Utc;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::sync::MutexGuard;
+use std::sync::OnceLock;
+use std::sync::PoisonError;
 use std::sync::Weak;
 use std::time::Duration;
 use std::time::Instant;
+use tokio::sync::broadcast;
 use tokio::sync::watch;
 use tokio::time::timeout;
 
@@ -27,12 +42,157 @@ pub enum GameStatus {
     Playing,
     Paused(Instant),
     GameOver(HighScoresStatus<HighScoresForGame>),
+    Crashed, // something panicked while the game was locked
+}
+
+// Things that web clients can play sounds for. They are sent out of band, see connection.rs.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SoundEvent {
+    RowsCleared(usize),
+    BombExploded,
+    BlockLanded,
+    GameOver,
+}
+
+impl SoundEvent {
+    pub fn to_message(self) -> String {
+        match self {
+            Self::RowsCleared(count) => format!("sound rows_cleared {}", count),
+            Self::BombExploded => "sound bomb_exploded".to_string(),
+            Self::BlockLanded => "sound block_landed".to_string(),
+            Self::GameOver => "sound game_over".to_string(),
+        }
+    }
+}
+
+// Same as Instant::now(), but tests can pause and advance it with tokio's clock
+fn clock_now() -> Instant {
+    tokio::time::Instant::now().into_std()
 }
 
 #[derive(Copy, Clone)]
 struct TimeInfo {
     start: Instant,
     previous_pauses: Duration, // if currently paused, doesn't include that
+    pause_count: usize,
+}
+
+// Measures how long it takes to clear the first rows in a single player traditional game.
+// Times are game durations, so that pausing doesn't ruin your sprint.
+#[derive(Default)]
+struct SprintTimer {
+    first_landing: Option<Duration>,
+    disqualified: bool,
+    done: bool,
+}
+
+impl SprintTimer {
+    // Returns the sprint duration when the sprint has just been completed
+    fn update(
+        &mut self,
+        now: Duration,
+        mode: Mode,
+        player_count: usize,
+        landed_block_count: usize,
+        cleared_row_count: usize,
+    ) -> Option<Duration> {
+        if mode != Mode::Traditional || player_count != 1 {
+            // someone joined, the run no longer counts even if they leave
+            self.disqualified = true;
+        }
+        if self.disqualified || self.done {
+            return None;
+        }
+
+        if self.first_landing.is_none() && landed_block_count > 0 {
+            self.first_landing = Some(now);
+        }
+        if cleared_row_count >= SPRINT_ROW_COUNT {
+            self.done = true;
+            return Some(now - self.first_landing.unwrap_or(now));
+        }
+        None
+    }
+}
+
+const SCORE_SAMPLE_INTERVAL: Duration = Duration::from_secs(10);
+
+// Set from command line arguments, games can go on forever if not set.
+// Only lobby games have a time limit, the tutorial doesn't.
+pub static MAX_GAME_DURATION: OnceLock<Duration> = OnceLock::new();
+
+// When the time limit is reached, falling blocks can still land during this time
+const WIND_DOWN_DURATION: Duration = Duration::from_secs(60);
+
+const INITIAL_MOVES_PER_SECOND: f32 = 2.0;
+
+// Blocks fall faster the longer the game has been going on
+fn get_fall_interval(game_duration: Duration) -> Duration {
+    let minutes = game_duration.as_secs_f32() / 60.0;
+    // TODO: should speed up more if you play badly
+    let moves_per_second = INITIAL_MOVES_PER_SECOND * 1.07_f32.powf(minutes);
+    Duration::from_secs_f32(1. / moves_per_second)
+}
+
+// Level 1 at first, and then a new level whenever blocks fall 10% faster
+fn get_level(game_duration: Duration) -> u32 {
+    let initial_interval = get_fall_interval(Duration::ZERO).as_secs_f32();
+    let speedup = initial_interval / get_fall_interval(game_duration).as_secs_f32();
+    1 + ((speedup - 1.0) * 10.0).max(0.0).floor() as u32
+}
+
+/*
+With many players, one player pressing P shouldn't pause everyone, so pressing
+P can instead start a vote. The game pauses (or unpauses) when more than half
+of the players have pressed P before the vote expires.
+*/
+pub const PAUSE_VOTE_DURATION: Duration = Duration::from_secs(15);
+pub const PAUSE_VOTE_MIN_PLAYERS: usize = 3;
+
+#[derive(Debug, Clone)]
+pub struct PauseVote {
+    pub initiator_name: String,
+    pub want_paused: bool,    // unpausing needs a vote too
+    pub voters: HashSet<u64>, // client IDs
+    deadline: Instant,
+}
+
+// Shown next to the game, so that players know how far they got
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GameClock {
+    pub elapsed: Duration, // doesn't include pauses
+    pub level: u32,
+    pub running: bool, // false when paused or over, so the time needs no redrawing
+}
+
+const AUTO_SHIFT_DELAY: Duration = Duration::from_millis(170);
+const AUTO_SHIFT_INTERVAL: Duration = Duration::from_millis(50);
+
+// Key repeat rates of terminals vary a lot, so the server repeats sideways moves instead.
+// Terminals don't tell us when a key is released, so this keeps going until the
+// player presses another key or the block lands.
+struct AutoShift {
+    id: u64,
+    dx: i8,
+    start: Instant, // when the key was pressed
+    moves_done: usize,
+    max_moves: usize,    // width of the game, so that it can't go on forever
+    landed_count: usize, // stop when the player's landed_count changes
+}
+
+impl AutoShift {
+    fn next_move_time(&self) -> Option<Instant> {
+        if self.moves_done >= self.max_moves {
+            None
+        } else {
+            Some(self.start + AUTO_SHIFT_DELAY + AUTO_SHIFT_INTERVAL * (self.moves_done as u32))
+        }
+    }
+
+    // When we are already repeating, key presses from the terminal's own key repeat are ignored
+    fn is_repeating(&self, now: Instant) -> bool {
+        now >= self.start + AUTO_SHIFT_DELAY
+    }
 }
 
 pub struct GameWrapper {
@@ -43,70 +203,439 @@ pub struct GameWrapper {
     status_sender: watch::Sender<GameStatus>,
     pub status_receiver: watch::Receiver<GameStatus>,
 
+    // Events are usually sent just before the status changes, so clients see them when rendering
+    sound_event_sender: broadcast::Sender<SoundEvent>,
+
     // Prevents blocks from falling down while a bomb or cleared row flashes.
     // This is here because of how it affects gameplay, not because of safety
     flash_mutex: tokio::sync::Mutex<()>,
+
+    sprint_timer: Mutex<SprintTimer>,
+
+    // keys are client IDs of players, lock this before locking the game
+    auto_shifts: Mutex<HashMap<u64, AutoShift>>,
+    auto_shift_id_counter: AtomicU64,
+
+    saves_results: bool,            // high scores and sprint records
+    max_duration: Option<Duration>, // None = no time limit
+
+    pause_requires_majority: bool,
+    pause_vote: Mutex<Option<PauseVote>>,
 }
 
 impl GameWrapper {
     pub fn new(game: Game) -> Self {
         let (status_sender, status_receiver) = watch::channel(GameStatus::Playing);
+        let (sound_event_sender, _) = broadcast::channel(16);
         GameWrapper {
             game: Mutex::new(game),
             time_info: Mutex::new(TimeInfo {
-                start: Instant::now(),
+                start: clock_now(),
                 previous_pauses: Duration::ZERO,
+                pause_count: 0,
             }),
             status_sender,
             status_receiver,
+            sound_event_sender,
             flash_mutex: tokio::sync::Mutex::new(()),
+            sprint_timer: Mutex::new(SprintTimer::default()),
+            auto_shifts: Mutex::new(HashMap::new()),
+            auto_shift_id_counter: AtomicU64::new(0),
+            saves_results: true,
+            max_duration: None,
+            pause_requires_majority: false,
+            pause_vote: Mutex::new(None),
+        }
+    }
+
+    // Only affects games with at least PAUSE_VOTE_MIN_PLAYERS players
+    pub fn set_pause_requires_majority(&mut self, enabled: bool) {
+        self.pause_requires_majority = enabled;
+    }
+
+    // Must be called before start_tasks()
+    pub fn set_max_duration(&mut self, max_duration: Option<Duration>) {
+        self.max_duration = max_duration;
+    }
+
+    #[cfg(test)]
+    pub fn set_start_time(&self, start: Instant) {
+        self.time_info.lock().unwrap().start = start;
+    }
+
+    // For games that don't count, such as the tutorial
+    pub fn new_without_saving_results(game: Game) -> Self {
+        Self {
+            saves_results: false,
+            ..Self::new(game)
+        }
+    }
+
+    // Like Game::handle_key_press(), but sideways moves also start auto shifting
+    pub fn handle_key_press(
+        self: &Arc<Self>,
+        player_id: u64,
+        client_prefers_rotating_counter_clockwise: bool,
+        other_keys_cancel_soft_drop: bool,
+        auto_shift_enabled: bool,
+        key: KeyPress,
+    ) -> bool {
+        let dx = match key {
+            KeyPress::Left | KeyPress::Character('A') | KeyPress::Character('a') => Some(-1),
+            KeyPress::Right | KeyPress::Character('D') | KeyPress::Character('d') => Some(1),
+            _ => None,
+        };
+
+        let now = Instant::now();
+        let mut auto_shifts = self.lock_auto_shifts();
+        let Some(mut game) = self.lock_game_for_update() else {
+            return false;
+        };
+        let landed_count = game
+            .players
+            .iter()
+            .find(|p| p.borrow().client_id == player_id)
+            .map(|p| p.borrow().landed_count);
+
+        if let (Some(dx), Some(shift)) = (dx, auto_shifts.get(&player_id)) {
+            if shift.dx == dx && shift.is_repeating(now) && Some(shift.landed_count) == landed_count
+            {
+                return false;
+            }
+        }
+        // Any key press stops auto shifting
+        auto_shifts.remove(&player_id);
+
+        let need_render = game.handle_key_press(
+            player_id,
+            client_prefers_rotating_counter_clockwise,
+            other_keys_cancel_soft_drop,
+            key,
+        );
+
+        if let (Some(dx), true) = (dx, auto_shift_enabled) {
+            if let Some(landed_count) = landed_count {
+                let id = self.auto_shift_id_counter.fetch_add(1, Ordering::Relaxed);
+                auto_shifts.insert(
+                    player_id,
+                    AutoShift {
+                        id,
+                        dx,
+                        start: now,
+                        moves_done: 0,
+                        max_moves: game.get_width(),
+                        landed_count,
+                    },
+                );
+                tokio::spawn(auto_shift(Arc::downgrade(self), player_id, id));
+            }
+        }
+        need_render
+    }
+
+    // Returns false when auto shifting should stop
+    fn do_auto_shift_move(&self, player_id: u64, shift_id: u64) -> bool {
+        let is_playing = matches!(*self.status_receiver.borrow(), GameStatus::Playing);
+
+        let mut auto_shifts = self.lock_auto_shifts();
+        let shift = match auto_shifts.get_mut(&player_id) {
+            Some(shift) if shift.id == shift_id => shift,
+            _ => return false, // another key was pressed
+        };
+
+        let Some(mut game) = self.lock_game_for_update() else {
+            auto_shifts.remove(&player_id);
+            return false;
+        };
+        let landed_count = game
+            .players
+            .iter()
+            .find(|p| p.borrow().client_id == player_id)
+            .map(|p| p.borrow().landed_count);
+        if !is_playing || landed_count != Some(shift.landed_count) {
+            // paused, block landed, or player left the game
+            auto_shifts.remove(&player_id);
+            return false;
+        }
+
+        shift.moves_done += 1;
+        let moved = game.move_sideways(player_id, shift.dx);
+        let keep_going = shift.next_move_time().is_some();
+        if !keep_going {
+            auto_shifts.remove(&player_id);
+        }
+        drop(game);
+        drop(auto_shifts);
+        if moved {
+            self.mark_changed();
         }
+        keep_going
     }
 
     pub fn mark_changed(&self) {
         self.status_sender.send_modify(|_| {});
     }
 
+    /*
+    If something panicked while holding the game lock, the game is probably
+    in a weird state, but showing it is much better than panicking in every
+    client's render loop and disconnecting the whole lobby.
+    */
+    pub fn lock_game(&self) -> MutexGuard<'_, Game> {
+        self.game.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // Changing a game after a panic would likely just panic again, so the game ends instead.
+    // Returns None if the game crashed.
+    fn lock_game_for_update(&self) -> Option<MutexGuard<'_, Game>> {
+        match self.game.lock() {
+            Ok(game) => Some(game),
+            Err(poisoned) => {
+                drop(poisoned); // contains the guard, so this unlocks
+                self.mark_crashed();
+                None
+            }
+        }
+    }
+
+    // Locked together with the game, so a panic in the game poisons this too
+    fn lock_auto_shifts(&self) -> MutexGuard<'_, HashMap<u64, AutoShift>> {
+        self.auto_shifts
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn mark_crashed(&self) {
+        let first_time = self.status_sender.send_if_modified(|status| {
+            if matches!(status, GameStatus::Playing | GameStatus::Paused(_)) {
+                *status = GameStatus::Crashed;
+                true
+            } else {
+                false
+            }
+        });
+        if first_time {
+            logging::log_error(
+                "game",
+                &format!(
+                    "{:?} game crashed, something panicked while the game was locked",
+                    self.lock_game().mode
+                ),
+            );
+        }
+    }
+
+    pub fn subscribe_sound_events(&self) -> broadcast::Receiver<SoundEvent> {
+        self.sound_event_sender.subscribe()
+    }
+
+    fn send_sound_event(&self, event: SoundEvent) {
+        // fails when nobody is subscribed, e.g. everyone left the game
+        _ = self.sound_event_sender.send(event);
+    }
+
     // None means toggle
     pub fn set_paused(&self, want_paused: Option<bool>) {
+        let mut ended_pause = None;
         self.status_sender.send_modify(|value| match *value {
             GameStatus::Playing if want_paused != Some(false) => {
-                *value = GameStatus::Paused(Instant::now());
+                self.time_info.lock().unwrap().pause_count += 1;
+                *value = GameStatus::Paused(clock_now());
             }
             GameStatus::Paused(pause_start) if want_paused != Some(true) => {
-                self.time_info.lock().unwrap().previous_pauses += pause_start.elapsed();
+                let pause_duration = clock_now().saturating_duration_since(pause_start);
+                ended_pause = Some(pause_duration);
+                self.time_info.lock().unwrap().previous_pauses += pause_duration;
                 *value = GameStatus::Playing;
             }
             _ => {}
         });
+        // Game is not locked in send_modify(), because the game is locked when checking the status
+        if let Some(duration) = ended_pause {
+            self.lock_game().postpone_countdown(duration);
+        }
+    }
+
+    // Call this when a player presses P (want_paused=None toggles) or selects a menu item that
+    // pauses or unpauses. Pauses or unpauses, or votes for it.
+    pub fn press_pause(&self, client_id: u64, want_paused: Option<bool>, now: Instant) {
+        let (player_ids, name) = {
+            let game = self.lock_game();
+            let player_ids: HashSet<u64> =
+                game.players.iter().map(|p| p.borrow().client_id).collect();
+            let name = game
+                .players
+                .iter()
+                .find(|p| p.borrow().client_id == client_id)
+                .map(|p| p.borrow().name.clone());
+            (player_ids, name)
+        };
+        if !self.pause_requires_majority || player_ids.len() < PAUSE_VOTE_MIN_PLAYERS {
+            self.set_paused(want_paused);
+            return;
+        }
+
+        let toggled = match *self.status_receiver.borrow() {
+            GameStatus::Playing => true,
+            GameStatus::Paused(_) => false,
+            _ => return,
+        };
+        if want_paused.is_some_and(|wanted| wanted != toggled) {
+            // Already paused or playing, e.g. someone else unpaused first
+            return;
+        }
+        let want_paused = toggled;
+        let Some(name) = name else {
+            return;
+        };
+
+        let mut vote = self.pause_vote.lock().unwrap();
+        if !matches!(&*vote, Some(v) if v.want_paused == want_paused && now < v.deadline) {
+            *vote = Some(PauseVote {
+                initiator_name: name,
+                want_paused,
+                voters: HashSet::new(),
+                deadline: now + PAUSE_VOTE_DURATION,
+            });
+        }
+        let v = vote.as_mut().unwrap();
+        v.voters.insert(client_id);
+        // Players who left the game don't count
+        let vote_count = v.voters.intersection(&player_ids).count();
+        if 2 * vote_count > player_ids.len() {
+            *vote = None;
+            drop(vote);
+            self.set_paused(Some(want_paused));
+        } else {
+            drop(vote);
+            self.mark_changed();
+        }
+    }
+
+    // Returns None if there's no vote, or if the vote expired or no longer makes sense
+    pub fn get_pause_vote(&self, now: Instant) -> Option<PauseVote> {
+        let is_playing = match *self.status_receiver.borrow() {
+            GameStatus::Playing => true,
+            GameStatus::Paused(_) => false,
+            _ => return None,
+        };
+        let mut vote = self.pause_vote.lock().unwrap();
+        if matches!(&*vote, Some(v) if v.want_paused != is_playing || now >= v.deadline) {
+            *vote = None;
+        }
+        vote.clone()
     }
 
+    pub fn get_clock(&self) -> GameClock {
+        let elapsed = self.get_duration();
+        GameClock {
+            elapsed,
+            level: get_level(elapsed),
+            running: matches!(*self.status_receiver.borrow(), GameStatus::Playing),
+        }
+    }
+
+    // Only uses Instant, so changing the system clock doesn't affect durations
     fn get_duration(&self) -> Duration {
         let time_info = *self.time_info.lock().unwrap();
         let including_previous_pauses = match *self.status_receiver.borrow() {
-            GameStatus::Paused(pause_start) => pause_start - time_info.start,
+            GameStatus::Paused(pause_start) => {
+                pause_start.saturating_duration_since(time_info.start)
+            }
             // If game has ended, current time will be the end time
-            _ => time_info.start.elapsed(),
+            _ => clock_now().saturating_duration_since(time_info.start),
+        };
+        including_previous_pauses.saturating_sub(time_info.previous_pauses)
+    }
+
+    // Includes the current pause, if any
+    fn get_paused_duration(&self) -> Duration {
+        let previous_pauses = self.time_info.lock().unwrap().previous_pauses;
+        match *self.status_receiver.borrow() {
+            GameStatus::Paused(pause_start) => {
+                previous_pauses + clock_now().saturating_duration_since(pause_start)
+            }
+            _ => previous_pauses,
+        }
+    }
+
+    // Call this after blocks land or rows get cleared
+    fn update_sprint_timer(&self) {
+        if !self.saves_results {
+            return;
+        }
+        let (mode, player_count, landed, cleared, first_player_name) = {
+            let game = self.lock_game();
+            (
+                game.mode,
+                game.players.len(),
+                game.get_landed_block_count(),
+                game.get_cleared_row_count(),
+                game.players.first().map(|p| p.borrow().name.clone()),
+            )
         };
-        including_previous_pauses - time_info.previous_pauses
+
+        let sprint_duration = self.sprint_timer.lock().unwrap().update(
+            self.get_duration(),
+            mode,
+            player_count,
+            landed,
+            cleared,
+        );
+
+        if let (Some(duration), Some(player)) = (sprint_duration, first_player_name) {
+            let record = SprintRecord {
+                duration,
+                player,
+                timestamp: Utc::now(),
+            };
+            tokio::spawn(async move {
+                if let Err(e) = add_sprint_record(record.clone()).await {
+                    logging::log_error(
+                        "high scores",
+                        &format!(
+                            "saving sprint record failed: {:?}, sprint record = {:?}",
+                            e, record
+                        ),
+                    );
+                }
+            });
+        }
     }
 
     fn get_game_result(&self) -> GameResult {
-        let (mode, score, players) = {
-            let game = self.game.lock().unwrap();
-            let player_names = game
-                .players
-                .iter()
-                .map(|p| p.borrow().name.clone())
-                .collect();
-            (game.mode, game.get_score(), player_names)
+        let (mode, score, players, inactive_players, seed, time_limit_reached) = {
+            let game = self.lock_game();
+            let mut players = vec![];
+            let mut inactive_players = vec![];
+            for (i, player) in game.players.iter().enumerate() {
+                let name = player.borrow().name.clone();
+                if game.is_counted(i) {
+                    players.push(name);
+                } else {
+                    inactive_players.push(name);
+                }
+            }
+            (
+                game.mode,
+                game.get_score(),
+                players,
+                inactive_players,
+                game.get_block_seed(),
+                game.time_limit_reached(),
+            )
         };
         GameResult {
             mode,
             score,
             players,
+            inactive_players,
             duration: self.get_duration(),
             timestamp: Some(Utc::now()),
+            seed,
+            time_limit_reached,
+            paused_duration: self.get_paused_duration(),
+            pause_count: self.time_info.lock().unwrap().pause_count,
         }
     }
 }
@@ -134,7 +663,8 @@ async fn pause_aware_sleep(weak_wrapper: Weak<GameWrapper>, mut duration: Durati
             }
         } else {
             // wait for game to pause or end, by at most the given sleep time
-            let start = Instant::now();
+            // tokio's clock, so that tests can skip the waiting
+            let start = tokio::time::Instant::now();
             match timeout(duration, receiver.changed()).await {
                 Err(_) => {
                     // timed out: we successfully slept the whole duration
@@ -163,9 +693,12 @@ async fn pause_aware_sleep(weak_wrapper: Weak<GameWrapper>, mut duration: Durati
 async fn flash(wrapper: Arc<GameWrapper>, points: &[WorldPoint], bg_color: u8) {
     for color in [bg_color, 0, bg_color, 0] {
         {
-            let mut game = wrapper.game.lock().unwrap();
+            let Some(mut game) = wrapper.lock_game_for_update() else {
+                return;
+            };
+            let now = Instant::now();
             for p in points {
-                game.flashing_points.insert(*p, color);
+                game.set_flashing_point(*p, color, now);
             }
         }
         wrapper.mark_changed();
@@ -173,20 +706,68 @@ async fn flash(wrapper: Arc<GameWrapper>, points: &[WorldPoint], bg_color: u8) {
             return;
         }
     }
-    for p in points {
-        wrapper.game.lock().unwrap().flashing_points.remove(p);
+    // Points may have expired already, e.g. if the game was paused for a long time
+    if let Some(mut game) = wrapper.lock_game_for_update() {
+        for p in points {
+            game.flashing_points.remove(p);
+        }
     }
 }
 
+// Returns false if the game has ended
+async fn move_blocks_down_once(wrapper: Arc<GameWrapper>, fast: bool) -> bool {
+    let mut _lock = wrapper.flash_mutex.lock().await;
+    let (moved, landed, full, cleared) = {
+        let Some(mut game) = wrapper.lock_game_for_update() else {
+            return false;
+        };
+        if game.players.is_empty() {
+            // can happen when the game ends, although it no longer matters what happens to game state
+            // avoid panics though:
+            //    - empty rows are considered full (no blocks missing)
+            //    - full rows increment score
+            //    - score calculation assumes at least 1 player
+            return false;
+        }
+        if game.update_countdown(Instant::now()) {
+            return true;
+        }
+        let old_landed = game.get_landed_block_count();
+        let old_cleared = game.get_cleared_row_count();
+        let moved = game.move_blocks_down(fast);
+        let full = game.find_full_rows_and_increment_score();
+        (
+            moved,
+            game.get_landed_block_count() != old_landed,
+            full,
+            game.get_cleared_row_count() - old_cleared,
+        )
+    };
+    if landed {
+        wrapper.send_sound_event(SoundEvent::BlockLanded);
+    }
+    if !full.is_empty() {
+        wrapper.send_sound_event(SoundEvent::RowsCleared(cleared));
+        flash(wrapper.clone(), &full, Color::WHITE_BACKGROUND.bg).await;
+        let Some(mut game) = wrapper.lock_game_for_update() else {
+            return false;
+        };
+        game.remove_full_rows(&full);
+        wrapper.mark_changed();
+    }
+    if moved {
+        wrapper.mark_changed();
+    }
+    wrapper.update_sprint_timer();
+    true
+}
+
 async fn move_blocks_down(weak_wrapper: Weak<GameWrapper>, fast: bool) {
     loop {
         let sleep_duration = if fast {
             Duration::from_millis(25)
         } else if let Some(wrapper) = weak_wrapper.upgrade() {
-            let minutes = wrapper.get_duration().as_secs_f32() / 60.0;
-            // TODO: should speed up more if you play badly
-            let moves_per_second = 2.0 * 1.07_f32.powf(minutes);
-            Duration::from_secs_f32(1. / moves_per_second)
+            get_fall_interval(wrapper.get_duration())
         } else {
             return;
         };
@@ -196,28 +777,8 @@ async fn move_blocks_down(weak_wrapper: Weak<GameWrapper>, fast: bool) {
 
         match weak_wrapper.upgrade() {
             Some(wrapper) => {
-                let mut _lock = wrapper.flash_mutex.lock().await;
-                let (moved, full) = {
-                    let mut game = wrapper.game.lock().unwrap();
-                    if game.players.is_empty() {
-                        // can happen when the game ends, although it no longer matters what happens to game state
-                        // avoid panics though:
-                        //    - empty rows are considered full (no blocks missing)
-                        //    - full rows increment score
-                        //    - score calculation assumes at least 1 player
-                        return;
-                    }
-                    let moved = game.move_blocks_down(fast);
-                    (moved, game.find_full_rows_and_increment_score())
-                };
-                if !full.is_empty() {
-                    flash(wrapper.clone(), &full, Color::WHITE_BACKGROUND.bg).await;
-                    let mut game = wrapper.game.lock().unwrap();
-                    game.remove_full_rows(&full);
-                    wrapper.mark_changed();
-                }
-                if moved {
-                    wrapper.mark_changed();
+                if !move_blocks_down_once(wrapper, fast).await {
+                    return;
                 }
             }
             None => return,
@@ -225,11 +786,34 @@ async fn move_blocks_down(weak_wrapper: Weak<GameWrapper>, fast: bool) {
     }
 }
 
+async fn auto_shift(weak_wrapper: Weak<GameWrapper>, player_id: u64, shift_id: u64) {
+    loop {
+        let next_move = match weak_wrapper.upgrade() {
+            Some(wrapper) => match wrapper.lock_auto_shifts().get(&player_id) {
+                Some(shift) if shift.id == shift_id => shift.next_move_time(),
+                _ => None,
+            },
+            None => None,
+        };
+        match next_move {
+            Some(time) => tokio::time::sleep_until(tokio::time::Instant::from_std(time)).await,
+            None => return,
+        }
+
+        match weak_wrapper.upgrade() {
+            Some(wrapper) if wrapper.do_auto_shift_move(player_id, shift_id) => {}
+            _ => return,
+        }
+    }
+}
+
 async fn animate_drills(weak_wrapper: Weak<GameWrapper>) {
     while pause_aware_sleep(weak_wrapper.clone(), Duration::from_millis(100)).await {
         match weak_wrapper.upgrade() {
             Some(wrapper) => {
-                let mut game = wrapper.game.lock().unwrap();
+                let Some(mut game) = wrapper.lock_game_for_update() else {
+                    return;
+                };
                 if game.animate_drills() {
                     wrapper.mark_changed();
                 }
@@ -239,11 +823,82 @@ async fn animate_drills(weak_wrapper: Weak<GameWrapper>) {
     }
 }
 
+async fn prune_flashing_points(weak_wrapper: Weak<GameWrapper>) {
+    while pause_aware_sleep(weak_wrapper.clone(), FLASHING_POINT_EXPIRY).await {
+        match weak_wrapper.upgrade() {
+            Some(wrapper) => {
+                let pruned = match wrapper.lock_game_for_update() {
+                    Some(mut game) => game.prune_expired_flashing_points(clock_now()),
+                    None => return,
+                };
+                if pruned {
+                    wrapper.mark_changed();
+                }
+            }
+            None => return,
+        }
+    }
+}
+
+async fn sample_score_history(weak_wrapper: Weak<GameWrapper>) {
+    while pause_aware_sleep(weak_wrapper.clone(), SCORE_SAMPLE_INTERVAL).await {
+        match weak_wrapper.upgrade() {
+            Some(wrapper) => {
+                match wrapper.lock_game_for_update() {
+                    Some(mut game) => game.sample_score(),
+                    None => return,
+                }
+                wrapper.mark_changed();
+            }
+            None => return,
+        }
+    }
+}
+
+async fn enforce_time_limit(weak_wrapper: Weak<GameWrapper>, max_duration: Duration) {
+    if !pause_aware_sleep(weak_wrapper.clone(), max_duration).await {
+        return;
+    }
+    match weak_wrapper.upgrade() {
+        Some(wrapper) => {
+            let Some(mut game) = wrapper.lock_game_for_update() else {
+                return;
+            };
+            logging::log(
+                "game",
+                &format!(
+                    "Time limit reached: {:?} with score {}, winding down",
+                    game.mode,
+                    game.get_score()
+                ),
+            );
+            game.start_wind_down();
+            drop(game);
+            wrapper.mark_changed();
+        }
+        None => return,
+    }
+
+    if !pause_aware_sleep(weak_wrapper.clone(), WIND_DOWN_DURATION).await {
+        return;
+    }
+    if let Some(wrapper) = weak_wrapper.upgrade() {
+        if let Some(mut game) = wrapper.lock_game_for_update() {
+            game.finish_wind_down();
+        }
+        // start_counter_tasks_as_needed() notices that the game is over
+        wrapper.mark_changed();
+    }
+}
+
 async fn tick_bombs(weak_wrapper: Weak<GameWrapper>, bomb_id: u64) {
     while pause_aware_sleep(weak_wrapper.clone(), Duration::from_secs(1)).await {
         match weak_wrapper.upgrade() {
             Some(wrapper) => {
-                let explosion_centers = wrapper.game.lock().unwrap().tick_bombs_by_id(bomb_id);
+                let explosion_centers = match wrapper.lock_game_for_update() {
+                    Some(mut game) => game.tick_bombs_by_id(bomb_id),
+                    None => return,
+                };
                 if explosion_centers.is_none() {
                     // bomb no longer exist
                     return;
@@ -251,19 +906,15 @@ async fn tick_bombs(weak_wrapper: Weak<GameWrapper>, bomb_id: u64) {
                 let mut explosion_centers = explosion_centers.unwrap();
 
                 if !explosion_centers.is_empty() {
+                    wrapper.send_sound_event(SoundEvent::BombExploded);
                     let _lock = wrapper.flash_mutex.lock().await;
                     while !explosion_centers.is_empty() {
-                        let flashing = wrapper
-                            .game
-                            .lock()
-                            .unwrap()
-                            .get_points_to_flash(&explosion_centers);
+                        let flashing = wrapper.lock_game().get_points_to_flash(&explosion_centers);
                         flash(wrapper.clone(), &flashing, Color::RED_BACKGROUND.bg).await;
-                        explosion_centers = wrapper
-                            .game
-                            .lock()
-                            .unwrap()
-                            .finish_explosion(&explosion_centers, &flashing);
+                        let Some(mut game) = wrapper.lock_game_for_update() else {
+                            return;
+                        };
+                        explosion_centers = game.finish_explosion(&explosion_centers, &flashing);
                     }
                 }
 
@@ -278,7 +929,9 @@ async fn tick_please_wait_counter(weak_wrapper: Weak<GameWrapper>, client_id: u6
     while pause_aware_sleep(weak_wrapper.clone(), Duration::from_secs(1)).await {
         match weak_wrapper.upgrade() {
             Some(wrapper) => {
-                let mut game = wrapper.game.lock().unwrap();
+                let Some(mut game) = wrapper.lock_game_for_update() else {
+                    return;
+                };
                 let run_again = game.tick_please_wait_counter(client_id);
                 wrapper.mark_changed();
                 if !run_again {
@@ -290,19 +943,38 @@ async fn tick_please_wait_counter(weak_wrapper: Weak<GameWrapper>, client_id: u6
     }
 }
 
-async fn handle_game_over(status_sender: &watch::Sender<GameStatus>, this_game_result: GameResult) {
+async fn handle_game_over(
+    status_sender: &watch::Sender<GameStatus>,
+    this_game_result: GameResult,
+    save_result: bool,
+) {
     // .send() fails when there are no receivers
     // we don't really care if everyone disconnects while high scores are loading
     _ = status_sender.send(GameStatus::GameOver(HighScoresStatus::Loading));
 
+    if !save_result || !this_game_result.mode.has_high_scores() {
+        _ = status_sender.send(GameStatus::GameOver(HighScoresStatus::Loaded(
+            HighScoresForGame {
+                this_game_result,
+                top_results: vec![],
+                this_game_index: None,
+            },
+        )));
+        return;
+    }
+
     match add_result_and_get_high_scores(this_game_result.clone()).await {
         Ok(info) => {
             _ = status_sender.send(GameStatus::GameOver(HighScoresStatus::Loaded(info)));
         }
         Err(e) => {
-            eprintln!("ERROR: saving game result to high scores file failed");
-            eprintln!("  game result = {:?}", this_game_result);
-            eprintln!("  error = {:?}", e);
+            logging::log_error(
+                "high scores",
+                &format!(
+                    "saving game result failed: {:?}, game result = {:?}",
+                    e, this_game_result
+                ),
+            );
             _ = status_sender.send(GameStatus::GameOver(HighScoresStatus::Error));
         }
     }
@@ -315,8 +987,7 @@ pub async fn handle_loading_all_high_scores(
     match read_all_high_scores().await {
         Ok(result) => _ = status_sender.send(HighScoresStatus::Loaded(result)),
         Err(e) => {
-            eprintln!("ERROR: reading high scores file failed");
-            eprintln!("  error = {:?}", e);
+            logging::log_error("high scores", &format!("reading failed: {:?}", e));
             _ = status_sender.send(HighScoresStatus::Error);
         }
     }
@@ -334,6 +1005,9 @@ async fn start_counter_tasks_as_needed(
             }
             let wrapper = wrapper.unwrap();
 
+            if matches!(*receiver.borrow(), GameStatus::Crashed) {
+                return;
+            }
             // nothing else should get a game out of playing/paused status
             assert!(matches!(
                 *receiver.borrow(),
@@ -342,12 +1016,20 @@ async fn start_counter_tasks_as_needed(
 
             let client_ids_to_wait;
             let new_bomb_ids;
+            let zen_cleared;
             {
-                let mut game = wrapper.game.lock().unwrap();
+                let Some(mut game) = wrapper.lock_game_for_update() else {
+                    return;
+                };
                 new_bomb_ids = game.start_ticking_new_bombs();
+                zen_cleared = game.handle_pending_zen_clears();
                 client_ids_to_wait = game.start_pending_please_wait_counters();
             }
 
+            if zen_cleared {
+                wrapper.mark_changed();
+            }
+
             for bomb_id in new_bomb_ids {
                 tokio::spawn(tick_bombs(Arc::downgrade(&wrapper), bomb_id));
             }
@@ -364,7 +1046,25 @@ async fn start_counter_tasks_as_needed(
                 }
             } else {
                 // game over
-                handle_game_over(&wrapper.status_sender, wrapper.get_game_result()).await;
+                wrapper.send_sound_event(SoundEvent::GameOver);
+                let result = wrapper.get_game_result();
+                logging::log(
+                    "game",
+                    &format!(
+                        "Game over: {:?} with score {}, paused {} times for {}sec, blocks: {}{}",
+                        result.mode,
+                        result.score,
+                        result.pause_count,
+                        result.paused_duration.as_secs(),
+                        wrapper.lock_game().format_block_counts(),
+                        if result.time_limit_reached {
+                            " (time limit reached)"
+                        } else {
+                            ""
+                        }
+                    ),
+                );
+                handle_game_over(&wrapper.status_sender, result, wrapper.saves_results).await;
                 return;
             }
         }
@@ -380,8 +1080,477 @@ pub fn start_tasks(wrapper: Arc<GameWrapper>) {
     tokio::spawn(move_blocks_down(Arc::downgrade(&wrapper), true));
     tokio::spawn(move_blocks_down(Arc::downgrade(&wrapper), false));
     tokio::spawn(animate_drills(Arc::downgrade(&wrapper)));
+    tokio::spawn(sample_score_history(Arc::downgrade(&wrapper)));
+    tokio::spawn(prune_flashing_points(Arc::downgrade(&wrapper)));
+    if let Some(max_duration) = wrapper.max_duration {
+        tokio::spawn(enforce_time_limit(Arc::downgrade(&wrapper), max_duration));
+    }
     tokio::spawn(start_counter_tasks_as_needed(
         Arc::downgrade(&wrapper),
         wrapper.status_receiver.clone(),
     ));
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::game_logic::blocks::SquareContent;
+    use crate::game_logic::game::COUNTDOWN_DURATION;
+    use crate::game_logic::player::BlockOrTimer;
+    use crate::ingame_ui::test::player_spec;
+
+    fn secs(n: u64) -> Duration {
+        Duration::from_secs(n)
+    }
+
+    #[test]
+    fn test_sprint_timer() {
+        let mut timer = SprintTimer::default();
+        assert_eq!(timer.update(secs(1), Mode::Traditional, 1, 0, 0), None);
+        // Timing starts when the first block lands
+        assert_eq!(timer.update(secs(5), Mode::Traditional, 1, 1, 0), None);
+        assert_eq!(timer.update(secs(20), Mode::Traditional, 1, 7, 9), None);
+        assert_eq!(
+            timer.update(secs(25), Mode::Traditional, 1, 8, 11),
+            Some(secs(20))
+        );
+        // Only reported once
+        assert_eq!(timer.update(secs(30), Mode::Traditional, 1, 9, 12), None);
+    }
+
+    #[tokio::test]
+    async fn test_results_not_saved() {
+        // Zen games and tutorials don't go to the high scores file
+        for (mode, save_result) in [(Mode::Zen, true), (Mode::Traditional, false)] {
+            let (sender, receiver) = watch::channel(GameStatus::Playing);
+            let result = GameResult {
+                mode,
+                score: 1234,
+                duration: secs(60),
+                players: vec!["Alice".to_string()],
+                inactive_players: vec![],
+                timestamp: Some(Utc::now()),
+                seed: None,
+                time_limit_reached: false,
+                ..Default::default()
+            };
+            handle_game_over(&sender, result.clone(), save_result).await;
+
+            match &*receiver.borrow() {
+                GameStatus::GameOver(HighScoresStatus::Loaded(info)) => {
+                    assert_eq!(info.this_game_result, result);
+                    assert!(info.top_results.is_empty());
+                    assert_eq!(info.this_game_index, None);
+                }
+                other => panic!("unexpected status: {:?}", other),
+            };
+        }
+    }
+
+    #[test]
+    fn test_sprint_timer_disqualified() {
+        let mut timer = SprintTimer::default();
+        assert_eq!(timer.update(secs(5), Mode::Traditional, 1, 1, 0), None);
+        // Someone joins and leaves
+        assert_eq!(timer.update(secs(6), Mode::Traditional, 2, 2, 0), None);
+        assert_eq!(timer.update(secs(7), Mode::Traditional, 1, 3, 0), None);
+        assert_eq!(timer.update(secs(20), Mode::Traditional, 1, 9, 10), None);
+
+        for mode in [Mode::Bottle, Mode::Ring] {
+            let mut timer = SprintTimer::default();
+            assert_eq!(timer.update(secs(5), mode, 1, 1, 0), None);
+            assert_eq!(timer.update(secs(20), mode, 1, 9, 10), None);
+        }
+    }
+
+    fn ms(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    #[test]
+    fn test_level_progression() {
+        assert_eq!(get_fall_interval(Duration::ZERO), ms(500));
+        assert_eq!(get_level(Duration::ZERO), 1);
+        assert_eq!(get_level(secs(60)), 1);
+        // 1.07^1.5 = 1.107
+        assert_eq!(get_level(secs(90)), 2);
+        assert_eq!(get_level(secs(5 * 60)), 5);
+        assert_eq!(get_level(secs(10 * 60)), 10);
+        assert!(get_fall_interval(secs(10 * 60)) < ms(260));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_clock_stops_while_paused() {
+        let wrapper = create_wrapper();
+        tokio::time::sleep(ms(100)).await;
+        wrapper.set_paused(Some(true));
+        let clock = wrapper.get_clock();
+        assert!(!clock.running);
+        assert_eq!(clock.elapsed, ms(100));
+        assert_eq!(clock.level, 1);
+
+        tokio::time::sleep(ms(300)).await;
+        assert_eq!(wrapper.get_clock(), clock);
+
+        wrapper.set_paused(Some(false));
+        tokio::time::sleep(ms(100)).await;
+        let clock = wrapper.get_clock();
+        assert!(clock.running);
+        assert_eq!(clock.elapsed, ms(200));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_game_result_excludes_pauses() {
+        let wrapper = create_wrapper();
+        for _ in 0..2 {
+            wrapper.set_paused(Some(true));
+            let interval = get_fall_interval(wrapper.get_duration());
+            tokio::time::sleep(ms(200)).await;
+            // Pausing doesn't make the game go faster
+            assert_eq!(get_fall_interval(wrapper.get_duration()), interval);
+            wrapper.set_paused(Some(false));
+        }
+        // Pausing when already paused is not a new pause
+        wrapper.set_paused(Some(true));
+        wrapper.set_paused(Some(true));
+        tokio::time::sleep(ms(100)).await;
+
+        let result = wrapper.get_game_result();
+        assert_eq!(result.pause_count, 3);
+        assert_eq!(result.paused_duration, ms(500));
+        assert_eq!(result.duration, Duration::ZERO);
+        assert_eq!(result.duration, wrapper.get_clock().elapsed);
+    }
+
+    #[test]
+    fn test_auto_shift_cadence() {
+        let start = Instant::now();
+        let mut shift = AutoShift {
+            id: 0,
+            dx: -1,
+            start,
+            moves_done: 0,
+            max_moves: 3,
+            landed_count: 0,
+        };
+        let mut move_times = vec![];
+        while let Some(time) = shift.next_move_time() {
+            move_times.push(time - start);
+            shift.moves_done += 1;
+        }
+        assert_eq!(move_times, [ms(170), ms(220), ms(270)]);
+
+        assert!(!shift.is_repeating(start + ms(169)));
+        assert!(shift.is_repeating(start + ms(170)));
+    }
+
+    fn create_wrapper() -> Arc<GameWrapper> {
+        let mut game = Game::new(Mode::Traditional);
+        game.add_player(&player_spec(1));
+        // Blocks don't fall, because tasks are not started
+        Arc::new(GameWrapper::new(game))
+    }
+
+    fn create_pause_vote_wrapper(player_count: u64) -> GameWrapper {
+        let mut game = Game::new(Mode::Traditional);
+        for client_id in 1..=player_count {
+            game.add_player(&player_spec(client_id));
+        }
+        let mut wrapper = GameWrapper::new(game);
+        wrapper.set_pause_requires_majority(true);
+        wrapper
+    }
+
+    fn is_paused(wrapper: &GameWrapper) -> bool {
+        matches!(*wrapper.status_receiver.borrow(), GameStatus::Paused(_))
+    }
+
+    #[test]
+    fn test_pause_vote_succeeds() {
+        let wrapper = create_pause_vote_wrapper(4);
+        let start = Instant::now();
+
+        wrapper.press_pause(2, None, start);
+        let vote = wrapper.get_pause_vote(start).unwrap();
+        assert_eq!(vote.initiator_name, "Player 2");
+        assert!(vote.want_paused);
+        assert!(!is_paused(&wrapper));
+
+        // Pressing again doesn't count twice
+        wrapper.press_pause(2, None, start + secs(1));
+        wrapper.press_pause(3, None, start + secs(2));
+        assert_eq!(
+            wrapper
+                .get_pause_vote(start + secs(2))
+                .unwrap()
+                .voters
+                .len(),
+            2
+        );
+        assert!(!is_paused(&wrapper));
+
+        // 3/4 is more than half
+        wrapper.press_pause(4, None, start + secs(3));
+        assert!(is_paused(&wrapper));
+        assert!(wrapper.get_pause_vote(start + secs(3)).is_none());
+    }
+
+    #[test]
+    fn test_pause_vote_expires() {
+        let wrapper = create_pause_vote_wrapper(3);
+        let start = Instant::now();
+
+        wrapper.press_pause(1, None, start);
+        assert!(wrapper.get_pause_vote(start + secs(14)).is_some());
+        assert!(wrapper
+            .get_pause_vote(start + PAUSE_VOTE_DURATION)
+            .is_none());
+
+        // This starts a new vote, so 2/3 is not reached
+        wrapper.press_pause(2, None, start + secs(16));
+        assert!(!is_paused(&wrapper));
+        let vote = wrapper.get_pause_vote(start + secs(16)).unwrap();
+        assert_eq!(vote.initiator_name, "Player 2");
+        assert_eq!(vote.voters, HashSet::from([2]));
+    }
+
+    #[test]
+    fn test_pause_vote_resets_between_uses() {
+        let wrapper = create_pause_vote_wrapper(3);
+        let start = Instant::now();
+
+        wrapper.press_pause(1, None, start);
+        wrapper.press_pause(2, None, start);
+        assert!(is_paused(&wrapper));
+
+        // Unpausing is a new vote, earlier votes for pausing don't count
+        wrapper.press_pause(3, None, start + secs(1));
+        let vote = wrapper.get_pause_vote(start + secs(1)).unwrap();
+        assert!(!vote.want_paused);
+        assert_eq!(vote.initiator_name, "Player 3");
+        assert_eq!(vote.voters, HashSet::from([3]));
+        assert!(is_paused(&wrapper));
+        wrapper.press_pause(1, None, start + secs(2));
+        assert!(!is_paused(&wrapper));
+
+        // Unpausing some other way clears the vote
+        wrapper.press_pause(1, None, start + secs(3));
+        assert!(wrapper.get_pause_vote(start + secs(3)).is_some());
+        wrapper.set_paused(Some(true));
+        assert!(wrapper.get_pause_vote(start + secs(3)).is_none());
+    }
+
+    #[test]
+    fn test_small_games_pause_without_vote() {
+        let wrapper = create_pause_vote_wrapper(2);
+        wrapper.press_pause(1, None, Instant::now());
+        assert!(is_paused(&wrapper));
+        assert!(wrapper.get_pause_vote(Instant::now()).is_none());
+        wrapper.press_pause(2, None, Instant::now());
+        assert!(!is_paused(&wrapper));
+    }
+
+    #[test]
+    fn test_continue_twice() {
+        // Without a vote
+        let wrapper = create_pause_vote_wrapper(2);
+        wrapper.set_paused(Some(true));
+        wrapper.press_pause(1, Some(false), Instant::now());
+        assert!(!is_paused(&wrapper));
+        wrapper.press_pause(2, Some(false), Instant::now());
+        assert!(!is_paused(&wrapper));
+
+        // With a vote, continuing a game that is already playing doesn't start a pause vote
+        let wrapper = create_pause_vote_wrapper(3);
+        let start = Instant::now();
+        wrapper.set_paused(Some(true));
+        wrapper.press_pause(1, Some(false), start);
+        wrapper.press_pause(2, Some(false), start);
+        assert!(!is_paused(&wrapper));
+        wrapper.press_pause(3, Some(false), start);
+        assert!(!is_paused(&wrapper));
+        assert!(wrapper.get_pause_vote(start).is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_time_limit() {
+        let mut game = Game::new(Mode::Traditional);
+        game.add_player(&player_spec(1));
+        let mut wrapper = GameWrapper::new_without_saving_results(game);
+        wrapper.set_max_duration(Some(ms(100)));
+        let wrapper = Arc::new(wrapper);
+        start_tasks(wrapper.clone());
+
+        tokio::time::sleep(ms(150)).await;
+        {
+            let mut game = wrapper.lock_game();
+            assert!(game.is_winding_down());
+            // The falling block can still land, but no new block comes
+            game.handle_key_press(1, false, true, KeyPress::Down);
+            while game.move_blocks_down(true) {}
+            game.move_blocks_down(false);
+            assert_eq!(game.get_landed_block_count(), 1);
+            assert!(matches!(
+                game.players[0].borrow().block_or_timer,
+                BlockOrTimer::WindingDown
+            ));
+        }
+        assert!(matches!(
+            *wrapper.status_receiver.borrow(),
+            GameStatus::Playing
+        ));
+
+        tokio::time::sleep(WIND_DOWN_DURATION + ms(100)).await;
+        match &*wrapper.status_receiver.borrow() {
+            GameStatus::GameOver(HighScoresStatus::Loaded(info)) => {
+                assert!(info.this_game_result.time_limit_reached);
+            }
+            other => panic!("unexpected status: {:?}", other),
+        };
+    }
+
+    #[tokio::test]
+    async fn test_sound_events() {
+        let mut game = Game::new(Mode::Traditional);
+        game.add_player(&player_spec(1));
+        // Bottom row is already full, so it gets cleared on the first move.
+        // Rows above it have a gap, and they are stacked high, so that the game ends soon
+        // and the sound event channel doesn't fill up.
+        let full = Some(SquareContent::with_color(Color::RED_BACKGROUND));
+        let bottom_y = game.get_height() as i16 - 1;
+        for y in 5..=bottom_y {
+            let first_x = if y == bottom_y { 0 } else { 1 };
+            for x in first_x..(game.get_width() as i16) {
+                game.set_landed_square((x, y), full);
+            }
+        }
+
+        let wrapper = Arc::new(GameWrapper::new_without_saving_results(game));
+        let mut receiver = wrapper.subscribe_sound_events();
+        let status_receiver = wrapper.status_receiver.clone();
+        tokio::spawn(start_counter_tasks_as_needed(
+            Arc::downgrade(&wrapper),
+            status_receiver.clone(),
+        ));
+        // Like what the move_blocks_down() task does, but without waiting in between
+        while !matches!(*status_receiver.borrow(), GameStatus::GameOver(_)) {
+            move_blocks_down_once(wrapper.clone(), false).await;
+            tokio::task::yield_now().await;
+        }
+
+        let mut events = vec![];
+        while let Ok(event) = receiver.try_recv() {
+            events.push(event);
+        }
+        assert_eq!(events[0], SoundEvent::RowsCleared(1));
+        assert!(events.contains(&SoundEvent::BlockLanded));
+        assert_eq!(events.last(), Some(&SoundEvent::GameOver));
+        assert_eq!(
+            SoundEvent::RowsCleared(2).to_message(),
+            "sound rows_cleared 2"
+        );
+    }
+
+    fn get_block_left_x(wrapper: &GameWrapper) -> i32 {
+        let game = wrapper.game.lock().unwrap();
+        let coords = game.players[0].borrow().block_or_timer.get_coords();
+        coords.iter().map(|(x, _)| *x).min().unwrap()
+    }
+
+    fn get_block_top_y(wrapper: &GameWrapper) -> i32 {
+        let game = wrapper.game.lock().unwrap();
+        let coords = game.players[0].borrow().block_or_timer.get_coords();
+        coords.iter().map(|(_, y)| *y).min().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_blocks_dont_fall_during_countdown() {
+        let wrapper = create_wrapper();
+        wrapper.game.lock().unwrap().start_countdown(Instant::now());
+        let y = get_block_top_y(&wrapper);
+        let x = get_block_left_x(&wrapper);
+
+        wrapper.handle_key_press(1, false, true, false, KeyPress::Left);
+        wrapper.handle_key_press(1, false, true, false, KeyPress::Down);
+        assert!(move_blocks_down_once(wrapper.clone(), false).await);
+        assert!(move_blocks_down_once(wrapper.clone(), true).await);
+        assert_eq!(get_block_top_y(&wrapper), y);
+        assert_eq!(get_block_left_x(&wrapper), x - 1);
+
+        // Falling starts at normal speed, not fast
+        let started_long_ago = Instant::now() - COUNTDOWN_DURATION;
+        wrapper
+            .game
+            .lock()
+            .unwrap()
+            .start_countdown(started_long_ago);
+        move_blocks_down_once(wrapper.clone(), true).await;
+        assert_eq!(get_block_top_y(&wrapper), y);
+        move_blocks_down_once(wrapper.clone(), false).await;
+        assert_eq!(get_block_top_y(&wrapper), y + 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_abandoned_flash_is_pruned() {
+        let wrapper = create_wrapper();
+        // Like a flash whose task got cancelled before it was done
+        wrapper.game.lock().unwrap().set_flashing_point(
+            (1, 2),
+            Color::RED_BACKGROUND.bg,
+            clock_now(),
+        );
+        tokio::spawn(prune_flashing_points(Arc::downgrade(&wrapper)));
+        let receiver = wrapper.status_sender.subscribe();
+
+        tokio::time::sleep(FLASHING_POINT_EXPIRY - ms(1)).await;
+        assert!(!wrapper.game.lock().unwrap().flashing_points.is_empty());
+        // Pruning runs once per expiry time, so it can take up to twice as long
+        tokio::time::sleep(FLASHING_POINT_EXPIRY + ms(2)).await;
+        assert!(wrapper.game.lock().unwrap().flashing_points.is_empty());
+        assert!(receiver.has_changed().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_auto_shift_moves_to_wall() {
+        let wrapper = create_wrapper();
+        let x = get_block_left_x(&wrapper);
+        assert!(x > 1);
+        wrapper.handle_key_press(1, false, true, true, KeyPress::Left);
+        assert_eq!(get_block_left_x(&wrapper), x - 1);
+
+        // Moves at most once for each column, and then stops
+        tokio::time::sleep(AUTO_SHIFT_DELAY + AUTO_SHIFT_INTERVAL * 10 + ms(200)).await;
+        assert_eq!(get_block_left_x(&wrapper), 0);
+        assert!(wrapper.auto_shifts.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_auto_shift_stop_conditions() {
+        let wait_time = AUTO_SHIFT_DELAY + AUTO_SHIFT_INTERVAL * 3;
+
+        // Other key pressed
+        let wrapper = create_wrapper();
+        wrapper.handle_key_press(1, false, true, true, KeyPress::Right);
+        wrapper.handle_key_press(1, false, true, true, KeyPress::Up);
+        let x = get_block_left_x(&wrapper);
+        tokio::time::sleep(wait_time).await;
+        assert_eq!(get_block_left_x(&wrapper), x);
+
+        // Block landed
+        let wrapper = create_wrapper();
+        wrapper.handle_key_press(1, false, true, true, KeyPress::Right);
+        let x = get_block_left_x(&wrapper);
+        wrapper.game.lock().unwrap().players[0]
+            .borrow_mut()
+            .landed_count += 1;
+        tokio::time::sleep(wait_time).await;
+        assert_eq!(get_block_left_x(&wrapper), x);
+
+        // Auto shifting disabled
+        let wrapper = create_wrapper();
+        wrapper.handle_key_press(1, false, true, false, KeyPress::Right);
+        let x = get_block_left_x(&wrapper);
+        tokio::time::sleep(wait_time).await;
+        assert_eq!(get_block_left_x(&wrapper), x);
+    }
+}
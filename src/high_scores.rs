@@ -1,7 +1,10 @@
 use crate::game_logic::game::Mode;
+use crate::game_logic::game::MAX_SCORE;
+use crate::logging;
 use chrono::DateTime;
 use chrono::Utc;
 use std::collections::HashMap;
+use std::collections::VecDeque;
 use std::fs;
 use std::io::BufRead;
 use std::io::BufReader;
@@ -20,7 +23,30 @@ pub struct GameResult {
     pub score: usize,
     pub duration: Duration,
     pub players: Vec<String>,
+    pub inactive_players: Vec<String>, // not counted in players, not saved to the high scores file
     pub timestamp: Option<DateTime<Utc>>,
+    pub seed: Option<u64>,        // not saved to the high scores file
+    pub time_limit_reached: bool, // not saved to the high scores file
+    // Not saved to the high scores file. The duration doesn't include pauses.
+    pub paused_duration: Duration,
+    pub pause_count: usize,
+}
+
+impl Default for GameResult {
+    fn default() -> Self {
+        Self {
+            mode: Mode::Traditional,
+            score: 0,
+            duration: Duration::ZERO,
+            players: vec![],
+            inactive_players: vec![],
+            timestamp: None,
+            seed: None,
+            time_limit_reached: false,
+            paused_duration: Duration::ZERO,
+            pause_count: 0,
+        }
+    }
 }
 
 fn mode_to_string(mode: Mode) -> &'static str {
@@ -28,6 +54,7 @@ fn mode_to_string(mode: Mode) -> &'static str {
         Mode::Traditional => "traditional",
         Mode::Bottle => "bottle",
         Mode::Ring => "ring",
+        Mode::Zen => "zen", // not actually saved, see Mode::has_high_scores()
     }
 }
 
@@ -35,7 +62,7 @@ fn mode_to_string(mode: Mode) -> &'static str {
 const VERSION: &str = env!("CARGO_PKG_VERSION_MAJOR");
 
 fn log(message: &str) {
-    println!("[high scores] {}", message);
+    logging::log("high scores", message);
 }
 
 const HEADER_PREFIX: &str = "catris high scores file v";
@@ -113,7 +140,7 @@ fn append_result_to_file(filename: &str, result: &GameResult) -> Result<(), AnyE
             mode_to_string(result.mode),
             // timestamp can't be None in new high scores, that's a legacy thing
             result.timestamp.unwrap().to_rfc3339(),
-            result.score,
+            result.score.min(MAX_SCORE),
             result.duration.as_secs_f64(),
             &result.players.join("\t")
         )
@@ -158,6 +185,31 @@ fn add_game_result_if_high_score(
     }
 }
 
+/*
+Players who reconnect after a network hiccup can finish the same game again,
+and some code paths used to write a result once for each remaining player.
+A result is considered a duplicate if a recent result looks the same.
+*/
+const DUPLICATE_MAX_SECONDS_APART: i64 = 120;
+const DUPLICATE_SEARCH_COUNT: usize = 20; // how many previous results of the same kind to check
+
+fn is_duplicate(a: &GameResult, b: &GameResult) -> bool {
+    let (a_time, b_time) = match (a.timestamp, b.timestamp) {
+        (Some(a_time), Some(b_time)) => (a_time, b_time),
+        _ => return false,
+    };
+
+    let mut a_players = a.players.clone();
+    let mut b_players = b.players.clone();
+    a_players.sort();
+    b_players.sort();
+
+    a.mode == b.mode
+        && a.score == b.score
+        && a_players == b_players
+        && (a_time - b_time).num_seconds().abs() <= DUPLICATE_MAX_SECONDS_APART
+}
+
 fn looks_like_lobby_id(value: &str) -> bool {
     value.chars().count() == 6 && value.chars().all(|c| matches!(c, 'A'..='Z' | '0'..='9'))
 }
@@ -172,59 +224,392 @@ fn parse_timestamp_field(value: &str) -> Result<Option<DateTime<Utc>>, AnyErrorT
     }
 }
 
-fn read_matching_high_scores(
+// Parts of a line in the high scores file, before parsing the values
+struct LineParts<'a> {
+    mode_name: &'a str,
+    timestamp_string: &'a str,
+    score_string: &'a str,
+    duration_secs_string: &'a str,
+    players: Vec<String>,
+}
+
+// Returns None for comments and blank lines
+fn split_line(line: &str) -> Result<Option<LineParts<'_>>, AnyErrorThreadSafe> {
+    if line.trim().is_empty() || line.trim().starts_with('#') {
+        return Ok(None);
+    }
+    let mut parts = line.split('\t');
+    let (
+        Some(mode_name),
+        Some(timestamp_string),
+        Some(score_string),
+        Some(duration_secs_string),
+        Some(first_player),
+    ) = (
+        parts.next(),
+        parts.next(),
+        parts.next(),
+        parts.next(),
+        parts.next(),
+    )
+    else {
+        return Err("not enough tab-separated parts".into());
+    };
+    let mut players = vec![first_player.to_string()];
+    players.extend(parts.map(|s| s.to_string()));
+    Ok(Some(LineParts {
+        mode_name,
+        timestamp_string,
+        score_string,
+        duration_secs_string,
+        players,
+    }))
+}
+
+// Calls the callback with (timestamp, score, duration, players) of each line with the given mode name
+fn read_lines_with_mode_name<F>(
     filename: &str,
-    mode: Mode,
-    multiplayer: bool,
-) -> Result<Vec<GameResult>, AnyErrorThreadSafe> {
+    wanted_mode_name: &str,
+    mut callback: F,
+) -> Result<(), AnyErrorThreadSafe>
+where
+    F: FnMut(&str, &str, &str, Vec<String>) -> Result<(), AnyErrorThreadSafe>,
+{
     let mut file = fs::OpenOptions::new().read(true).open(filename)?;
     let mut lines = BufReader::new(&mut file).lines();
     lines.next().ok_or("high scores file is empty")??;
 
-    let mut result = vec![];
-
-    let mut lineno = 1;
-    for line in lines {
-        lineno += 1;
-
+    // first line was already consumed, so numbering starts at 2
+    for (lineno, line) in (2..).zip(lines) {
         let line = line?;
-        if line.trim().is_empty() || line.trim().starts_with('#') {
-            continue;
+        let parts = split_line(&line)
+            .map_err(|e| format!("{} on line {} of high scores file", e, lineno))?;
+        if let Some(parts) = parts {
+            if parts.mode_name == wanted_mode_name {
+                callback(
+                    parts.timestamp_string,
+                    parts.score_string,
+                    parts.duration_secs_string,
+                    parts.players,
+                )?;
+            }
         }
+    }
 
-        let split_error = || {
-            format!(
-                "not enough tab-separated parts on line {} of high scores file",
-                lineno
-            )
-        };
+    Ok(())
+}
+
+// No game lasts this long. Longer durations in the file come from clock problems.
+const MAX_DURATION: Duration = Duration::from_secs(24 * 60 * 60);
+
+// Duration::from_secs_f64() panics with negative, infinite or huge values
+fn parse_duration(duration_secs_string: &str) -> Result<Duration, AnyErrorThreadSafe> {
+    let secs: f64 = duration_secs_string.parse()?;
+    if !secs.is_finite() || secs < 0.0 {
+        return Err(format!("bad duration: {}", duration_secs_string).into());
+    }
+    Ok(Duration::from_secs_f64(
+        secs.min(MAX_DURATION.as_secs_f64()),
+    ))
+}
 
-        let mut parts = line.split('\t');
-        let mode_name = parts.next().ok_or_else(split_error)?;
-        let timestamp_string = parts.next().ok_or_else(split_error)?;
-        let score_string = parts.next().ok_or_else(split_error)?;
-        let duration_secs_string = parts.next().ok_or_else(split_error)?;
+fn parse_game_result(
+    mode: Mode,
+    timestamp_string: &str,
+    score_string: &str,
+    duration_secs_string: &str,
+    players: Vec<String>,
+) -> Result<GameResult, AnyErrorThreadSafe> {
+    Ok(GameResult {
+        mode,
+        players,
+        inactive_players: vec![],
+        // Files written before scores were capped can have anything, even bigger than usize
+        score: score_string.parse::<u128>()?.min(MAX_SCORE as u128) as usize,
+        duration: parse_duration(duration_secs_string)?,
+        timestamp: parse_timestamp_field(timestamp_string)?,
+        seed: None,
+        time_limit_reached: false,
+        ..Default::default()
+    })
+}
 
-        let players: Vec<String> = parts.map(|s| s.to_string()).collect();
-        assert!(!players.is_empty());
+fn mode_from_string(mode_name: &str) -> Option<Mode> {
+    Mode::ALL_MODES
+        .iter()
+        .copied()
+        .find(|m| mode_to_string(*m) == mode_name)
+}
 
-        if mode_name == mode_to_string(mode) && (players.len() >= 2) == multiplayer {
-            add_game_result_if_high_score(
-                &mut result,
-                GameResult {
+fn read_matching_high_scores(
+    filename: &str,
+    mode: Mode,
+    multiplayer: bool,
+) -> Result<Vec<GameResult>, AnyErrorThreadSafe> {
+    let mut result = vec![];
+    read_lines_with_mode_name(
+        filename,
+        mode_to_string(mode),
+        |timestamp_string, score_string, duration_secs_string, players| {
+            if (players.len() >= 2) == multiplayer {
+                add_game_result_if_high_score(
+                    &mut result,
+                    parse_game_result(
+                        mode,
+                        timestamp_string,
+                        score_string,
+                        duration_secs_string,
+                        players,
+                    )?,
+                );
+            }
+            Ok(())
+        },
+    )?;
+    Ok(result)
+}
+
+// Returns the last DUPLICATE_SEARCH_COUNT results, oldest first
+fn read_recent_results(
+    filename: &str,
+    mode: Mode,
+    multiplayer: bool,
+) -> Result<VecDeque<GameResult>, AnyErrorThreadSafe> {
+    let mut result = VecDeque::new();
+    read_lines_with_mode_name(
+        filename,
+        mode_to_string(mode),
+        |timestamp_string, score_string, duration_secs_string, players| {
+            if (players.len() >= 2) == multiplayer {
+                result.push_back(parse_game_result(
                     mode,
+                    timestamp_string,
+                    score_string,
+                    duration_secs_string,
                     players,
-                    score: score_string.parse()?,
-                    duration: Duration::from_secs_f64(duration_secs_string.parse()?),
-                    timestamp: parse_timestamp_field(timestamp_string)?,
-                },
-            );
+                )?);
+                if result.len() > DUPLICATE_SEARCH_COUNT {
+                    result.pop_front();
+                }
+            }
+            Ok(())
+        },
+    )?;
+    Ok(result)
+}
+
+// Duplicates written by older versions of catris can be cleaned up with this.
+// Returns how many lines were removed.
+fn remove_duplicates_from_file(filename: &str) -> Result<usize, AnyErrorThreadSafe> {
+    let content = fs::read_to_string(filename)?;
+    let mut lines = content.lines();
+    let mut kept_lines = vec![lines.next().ok_or("high scores file is empty")?];
+    let mut recent_results: HashMap<(Mode, bool), VecDeque<GameResult>> = HashMap::new();
+    let mut removed_count = 0;
+
+    for line in lines {
+        // Comments, sprint records and anything unexpected are kept as is
+        if let Ok(Some(parts)) = split_line(line) {
+            if let Some(mode) = mode_from_string(parts.mode_name) {
+                if let Ok(result) = parse_game_result(
+                    mode,
+                    parts.timestamp_string,
+                    parts.score_string,
+                    parts.duration_secs_string,
+                    parts.players,
+                ) {
+                    let recent = recent_results
+                        .entry((mode, result.players.len() >= 2))
+                        .or_default();
+                    if recent.iter().any(|r| is_duplicate(r, &result)) {
+                        log(&format!("Removing duplicate line: {:?}", line));
+                        removed_count += 1;
+                        continue;
+                    }
+                    recent.push_back(result);
+                    if recent.len() > DUPLICATE_SEARCH_COUNT {
+                        recent.pop_front();
+                    }
+                }
+            }
         }
+        kept_lines.push(line);
+    }
+
+    if removed_count > 0 {
+        fs::write(filename, kept_lines.join("\n") + "\n")?;
+    }
+    Ok(removed_count)
+}
+
+/*
+Sprint records are stored in the same file as other high scores, so that
+there's only one file to back up. The lines look like other lines, but
+the mode is "sprint" and the score is the number of rows cleared. Older
+versions of catris ignore these lines, because they don't know the mode.
+*/
+const SPRINT_MODE_NAME: &str = "sprint";
+pub const SPRINT_ROW_COUNT: usize = 10;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SprintRecord {
+    pub duration: Duration, // from first landed block to clearing SPRINT_ROW_COUNT rows
+    pub player: String,
+    pub timestamp: DateTime<Utc>,
+}
+
+fn append_sprint_record_to_file(
+    filename: &str,
+    record: &SprintRecord,
+) -> Result<(), AnyErrorThreadSafe> {
+    log(&format!("Appending to {}: {:?}", filename, record));
+    let mut file = fs::OpenOptions::new().append(true).open(filename)?;
+    file.write_all(
+        format!(
+            "{}\t{}\t{}\t{}\t{}\n",
+            SPRINT_MODE_NAME,
+            record.timestamp.to_rfc3339(),
+            SPRINT_ROW_COUNT,
+            record.duration.as_secs_f64(),
+            record.player,
+        )
+        .as_bytes(),
+    )?;
+    Ok(())
+}
+
+// Faster is better. Returns Some(i) when records[i] is the newly added record.
+fn add_sprint_record_if_fast_enough(
+    records: &mut Vec<SprintRecord>,
+    record: SprintRecord,
+) -> Option<usize> {
+    if high_score_is_too_old(Some(record.timestamp)) {
+        return None;
     }
 
+    let mut i = 0;
+    while i < records.len() && record.duration >= records[i].duration {
+        i += 1;
+    }
+    records.insert(i, record);
+    records.truncate(5);
+
+    if i < records.len() {
+        Some(i)
+    } else {
+        None
+    }
+}
+
+fn read_sprint_records(filename: &str) -> Result<Vec<SprintRecord>, AnyErrorThreadSafe> {
+    let mut result = vec![];
+    read_lines_with_mode_name(
+        filename,
+        SPRINT_MODE_NAME,
+        |timestamp_string, _, duration_secs_string, mut players| {
+            if let Some(timestamp) = parse_timestamp_field(timestamp_string)? {
+                add_sprint_record_if_fast_enough(
+                    &mut result,
+                    SprintRecord {
+                        duration: parse_duration(duration_secs_string)?,
+                        player: players.remove(0),
+                        timestamp,
+                    },
+                );
+            }
+            Ok(())
+        },
+    )?;
     Ok(result)
 }
 
+// Only parses the line to find errors, the values are not needed
+fn check_line(parts: LineParts<'_>) -> Result<&str, AnyErrorThreadSafe> {
+    if parts.mode_name == SPRINT_MODE_NAME {
+        parse_timestamp_field(parts.timestamp_string)?;
+        parse_duration(parts.duration_secs_string)?;
+    } else {
+        let mode = mode_from_string(parts.mode_name)
+            .ok_or_else(|| format!("unknown mode: {:?}", parts.mode_name))?;
+        parse_game_result(
+            mode,
+            parts.timestamp_string,
+            parts.score_string,
+            parts.duration_secs_string,
+            parts.players,
+        )?;
+    }
+    Ok(parts.mode_name)
+}
+
+#[derive(Debug, Default)]
+pub struct CheckReport {
+    pub counts_by_mode_name: HashMap<String, usize>,
+    pub corrupt_lines: Vec<(usize, String)>, // (line number, error message)
+    pub fixed: bool,
+}
+
+impl CheckReport {
+    pub fn summary_lines(&self) -> Vec<String> {
+        let mut mode_names: Vec<&str> =
+            Mode::ALL_MODES.iter().map(|m| mode_to_string(*m)).collect();
+        mode_names.push(SPRINT_MODE_NAME);
+
+        let mut result = vec![];
+        for name in mode_names {
+            let count = self.counts_by_mode_name.get(name).copied().unwrap_or(0);
+            result.push(format!("{}: {} entries", name, count));
+        }
+        for (lineno, error) in &self.corrupt_lines {
+            result.push(format!("line {} is corrupt: {}", lineno, error));
+        }
+        if self.corrupt_lines.is_empty() {
+            result.push("No corrupt lines found".to_string());
+        } else if self.fixed {
+            result.push(format!(
+                "Removed {} corrupt lines",
+                self.corrupt_lines.len()
+            ));
+        }
+        result
+    }
+}
+
+// With fix=true, corrupt lines are removed and the old file is kept as filename.bak
+fn check_file(filename: &str, fix: bool) -> Result<CheckReport, AnyErrorThreadSafe> {
+    let content = fs::read_to_string(filename)?;
+    let mut lines = content.lines();
+    let header = lines.next().ok_or("high scores file is empty")?;
+    if !header.starts_with(HEADER_PREFIX) {
+        return Err(format!("unexpected first line in high scores file: {:?}", header).into());
+    }
+
+    let mut report = CheckReport::default();
+    let mut kept_lines = vec![header];
+    for (lineno, line) in (2..).zip(lines) {
+        match split_line(line).and_then(|parts| parts.map(check_line).transpose()) {
+            Ok(Some(mode_name)) => {
+                *report
+                    .counts_by_mode_name
+                    .entry(mode_name.to_string())
+                    .or_default() += 1;
+                kept_lines.push(line);
+            }
+            Ok(None) => kept_lines.push(line), // comment or blank line
+            Err(e) => report.corrupt_lines.push((lineno, e.to_string())),
+        }
+    }
+
+    if fix && !report.corrupt_lines.is_empty() {
+        let backup = format!("{}.bak", filename);
+        log(&format!("Copying {} to {}", filename, backup));
+        fs::copy(filename, &backup)?;
+        fs::write(filename, kept_lines.join("\n") + "\n")?;
+        report.fixed = true;
+    }
+    Ok(report)
+}
+
 // Prevent multiple games writing their high scores at once.
 // File name stored here so I won't forget to use this
 lazy_static! {
@@ -256,9 +641,27 @@ pub async fn add_result_and_get_high_scores(
             this_game_result.players.len() >= 2,
         )?;
 
-        append_result_to_file(*filename_handle, &this_game_result)?;
-        let this_game_index =
-            add_game_result_if_high_score(&mut top_results, this_game_result.clone());
+        let recent_results = read_recent_results(
+            *filename_handle,
+            this_game_result.mode,
+            this_game_result.players.len() >= 2,
+        )?;
+
+        let this_game_index = if recent_results
+            .iter()
+            .any(|r| is_duplicate(r, &this_game_result))
+        {
+            log(&format!(
+                "Not appending duplicate of a recent result: {:?}",
+                this_game_result
+            ));
+            top_results
+                .iter()
+                .position(|r| is_duplicate(r, &this_game_result))
+        } else {
+            append_result_to_file(*filename_handle, &this_game_result)?;
+            add_game_result_if_high_score(&mut top_results, this_game_result.clone())
+        };
 
         Ok(HighScoresForGame {
             this_game_result,
@@ -269,12 +672,44 @@ pub async fn add_result_and_get_high_scores(
     .await?
 }
 
+pub async fn add_sprint_record(record: SprintRecord) -> Result<(), AnyErrorThreadSafe> {
+    let filename_handle = FILE_LOCK.lock().await;
+
+    tokio::task::spawn_blocking(move || {
+        ensure_file_exists(*filename_handle)?;
+        upgrade_if_needed(*filename_handle)?;
+        append_sprint_record_to_file(*filename_handle, &record)
+    })
+    .await?
+}
+
+pub async fn remove_duplicates() -> Result<usize, AnyErrorThreadSafe> {
+    let filename_handle = FILE_LOCK.lock().await;
+
+    tokio::task::spawn_blocking(move || {
+        ensure_file_exists(*filename_handle)?;
+        upgrade_if_needed(*filename_handle)?;
+        remove_duplicates_from_file(*filename_handle)
+    })
+    .await?
+}
+
+pub async fn check_high_scores(fix: bool) -> Result<CheckReport, AnyErrorThreadSafe> {
+    let filename_handle = FILE_LOCK.lock().await;
+    tokio::task::spawn_blocking(move || check_file(*filename_handle, fix)).await?
+}
+
 #[derive(Debug)]
 pub struct AllHighScoresForMode {
     pub single_player_results: Vec<GameResult>,
     pub multiplayer_results: Vec<GameResult>,
 }
-pub type AllHighScores = HashMap<Mode, AllHighScoresForMode>;
+
+#[derive(Debug)]
+pub struct AllHighScores {
+    pub by_mode: HashMap<Mode, AllHighScoresForMode>,
+    pub sprint_records: Vec<SprintRecord>,
+}
 
 pub async fn read_all_high_scores() -> Result<AllHighScores, AnyErrorThreadSafe> {
     let filename_handle = FILE_LOCK.lock().await;
@@ -283,11 +718,11 @@ pub async fn read_all_high_scores() -> Result<AllHighScores, AnyErrorThreadSafe>
         ensure_file_exists(*filename_handle)?;
         upgrade_if_needed(*filename_handle)?;
 
-        let mut result = HashMap::new();
-        for mode in Mode::ALL_MODES {
+        let mut by_mode = HashMap::new();
+        for mode in Mode::ALL_MODES.iter().filter(|m| m.has_high_scores()) {
             let single_player_results = read_matching_high_scores(*filename_handle, *mode, false)?;
             let multiplayer_results = read_matching_high_scores(*filename_handle, *mode, true)?;
-            result.insert(
+            by_mode.insert(
                 *mode,
                 AllHighScoresForMode {
                     single_player_results,
@@ -295,7 +730,10 @@ pub async fn read_all_high_scores() -> Result<AllHighScores, AnyErrorThreadSafe>
                 },
             );
         }
-        Ok(result)
+        Ok(AllHighScores {
+            by_mode,
+            sprint_records: read_sprint_records(*filename_handle)?,
+        })
     })
     .await?
 }
@@ -305,7 +743,7 @@ mod test {
     use super::*;
 
     fn read_file(filename: &str) -> String {
-        String::from_utf8(fs::read(&filename).unwrap()).unwrap()
+        String::from_utf8(fs::read(filename).unwrap()).unwrap()
     }
 
     #[test]
@@ -398,33 +836,45 @@ mod test {
                     score: 4000,
                     duration: Duration::from_secs(123),
                     players: vec!["Good player".to_string()],
+                    inactive_players: vec![],
                     timestamp: Some(
                         DateTime::parse_from_rfc3339(&current_timestamp)
                             .unwrap()
                             .into()
                     ),
+                    seed: None,
+                    time_limit_reached: false,
+                    ..Default::default()
                 },
                 GameResult {
                     mode: Mode::Traditional,
                     score: 55,
                     duration: Duration::from_secs(66),
                     players: vec!["#HashTag#".to_string()],
+                    inactive_players: vec![],
                     timestamp: Some(
                         DateTime::parse_from_rfc3339(&current_timestamp)
                             .unwrap()
                             .into()
                     ),
+                    seed: None,
+                    time_limit_reached: false,
+                    ..Default::default()
                 },
                 GameResult {
                     mode: Mode::Traditional,
                     score: 11,
                     duration: Duration::from_secs_f32(22.75),
                     players: vec!["SinglePlayer".to_string()],
+                    inactive_players: vec![],
                     timestamp: Some(
                         DateTime::parse_from_rfc3339(&current_timestamp)
                             .unwrap()
                             .into()
                     ),
+                    seed: None,
+                    time_limit_reached: false,
+                    ..Default::default()
                 }
             ]
         );
@@ -434,7 +884,11 @@ mod test {
             score: 3000,
             duration: Duration::from_secs_f32(123.45),
             players: vec!["Second Place".to_string()],
+            inactive_players: vec![],
             timestamp: Some(Utc::now()),
+            seed: None,
+            time_limit_reached: false,
+            ..Default::default()
         };
         let index = add_game_result_if_high_score(&mut result, second_place_result.clone());
         assert_eq!(result.len(), 4);
@@ -454,11 +908,15 @@ mod test {
                     "Bob".to_string(),
                     "Charlie".to_string()
                 ],
+                inactive_players: vec![],
                 timestamp: Some(
                     DateTime::parse_from_rfc3339(&current_timestamp)
                         .unwrap()
                         .into()
                 ),
+                seed: None,
+                time_limit_reached: false,
+                ..Default::default()
             }]
         );
     }
@@ -479,11 +937,284 @@ mod test {
             score: 7000,
             duration: Duration::from_secs(123),
             players: vec!["Foo".to_string(), "Bar".to_string()],
+            inactive_players: vec![],
             timestamp: Some(Utc::now()),
+            seed: None,
+            time_limit_reached: false,
+            ..Default::default()
         };
 
         append_result_to_file(&filename, &sample_result).unwrap();
         let from_file = read_matching_high_scores(&filename, Mode::Ring, true).unwrap();
         assert_eq!(from_file, [sample_result]);
     }
+
+    #[test]
+    fn test_scores_are_capped() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let filename = tempdir
+            .path()
+            .join("high_scores.txt")
+            .to_str()
+            .unwrap()
+            .to_string();
+        let timestamp = Utc::now().to_rfc3339();
+        let lines = [
+            "catris high scores file v4".to_string(),
+            format!(
+                "traditional\t{}\t18446744073709551616999\t1\tBogus",
+                timestamp
+            ),
+            format!("traditional\t{}\t1000000000\t1\tAlso bogus", timestamp),
+        ];
+        fs::write(&filename, lines.join("\n")).unwrap();
+
+        let results = read_matching_high_scores(&filename, Mode::Traditional, false).unwrap();
+        assert_eq!(results.len(), 2);
+        assert!(results.iter().all(|r| r.score == MAX_SCORE));
+
+        let mut result = results[0].clone();
+        result.score = usize::MAX;
+        append_result_to_file(&filename, &result).unwrap();
+        assert!(read_file(&filename).contains("\t999999999\t"));
+    }
+
+    #[test]
+    fn test_absurd_durations_are_clamped() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let filename = tempdir
+            .path()
+            .join("high_scores.txt")
+            .to_str()
+            .unwrap()
+            .to_string();
+        let timestamp = Utc::now().to_rfc3339();
+        let lines = [
+            "catris high scores file v4".to_string(),
+            // 49710 days, seen once after a clock issue
+            format!("traditional\t{}\t100\t4294967296\tSlow", timestamp),
+            format!("traditional\t{}\t200\t1e300\tSlower", timestamp),
+            format!("traditional\t{}\t300\t86399\tFast enough", timestamp),
+        ];
+        fs::write(&filename, lines.join("\n")).unwrap();
+
+        let results = read_matching_high_scores(&filename, Mode::Traditional, false).unwrap();
+        let durations: Vec<u64> = results.iter().map(|r| r.duration.as_secs()).collect();
+        assert_eq!(durations, [86399, 86400, 86400]);
+    }
+
+    #[test]
+    fn test_sprint_records() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let filename = tempdir
+            .path()
+            .join("high_scores.txt")
+            .to_str()
+            .unwrap()
+            .to_string();
+        ensure_file_exists(&filename).unwrap();
+
+        let slow = SprintRecord {
+            duration: Duration::from_secs_f32(60.5),
+            player: "Slow".to_string(),
+            timestamp: Utc::now(),
+        };
+        let fast = SprintRecord {
+            duration: Duration::from_secs_f32(12.25),
+            player: "Fast".to_string(),
+            timestamp: Utc::now(),
+        };
+        append_sprint_record_to_file(&filename, &slow).unwrap();
+        append_sprint_record_to_file(&filename, &fast).unwrap();
+
+        // Faster records come first. Sprint lines don't affect other high scores.
+        assert_eq!(read_sprint_records(&filename).unwrap(), [fast, slow]);
+        assert!(
+            read_matching_high_scores(&filename, Mode::Traditional, false)
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_duplicate_detection() {
+        let now = Utc::now();
+        let result = GameResult {
+            mode: Mode::Bottle,
+            score: 1234,
+            duration: Duration::from_secs(100),
+            players: vec!["Alice".to_string(), "Bob".to_string()],
+            inactive_players: vec![],
+            timestamp: Some(now),
+            seed: None,
+            time_limit_reached: false,
+            ..Default::default()
+        };
+
+        // Player order and duration don't matter
+        let mut other = result.clone();
+        other.players.reverse();
+        other.duration = Duration::from_secs(90);
+        other.timestamp = Some(now + chrono::Duration::seconds(60));
+        assert!(is_duplicate(&result, &other));
+        assert!(is_duplicate(&other, &result));
+
+        // Near-duplicates that are actually different games
+        let mut other = result.clone();
+        other.score = 1235;
+        assert!(!is_duplicate(&result, &other));
+
+        let mut other = result.clone();
+        other.mode = Mode::Traditional;
+        assert!(!is_duplicate(&result, &other));
+
+        let mut other = result.clone();
+        other.players.push("Charlie".to_string());
+        assert!(!is_duplicate(&result, &other));
+
+        let mut other = result.clone();
+        other.timestamp = Some(now - chrono::Duration::minutes(5));
+        assert!(!is_duplicate(&result, &other));
+
+        let mut other = result.clone();
+        other.timestamp = None;
+        assert!(!is_duplicate(&result, &other));
+    }
+
+    #[test]
+    fn test_removing_duplicates() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let filename = tempdir
+            .path()
+            .join("high_scores.txt")
+            .to_str()
+            .unwrap()
+            .to_string();
+
+        let t1 = "2030-01-01T12:00:00+00:00";
+        let t2 = "2030-01-01T12:01:00+00:00";
+        let t3 = "2030-01-01T13:00:00+00:00";
+        let original = [
+            "catris high scores file v4".to_string(),
+            format!("ring\t{}\t500\t60\tFoo\tBar", t1),
+            format!("ring\t{}\t500\t61\tBar\tFoo", t2), // duplicate
+            format!("ring\t{}\t501\t61\tFoo\tBar", t2), // different score
+            format!("ring\t{}\t500\t60\tFoo\tBar", t3), // much later
+            format!("ring\t{}\t500\t60\tFoo", t2),      // single player
+            format!("bottle\t{}\t500\t60\tFoo\tBar", t2), // different mode
+            "# comment".to_string(),
+            format!("sprint\t{}\t10\t12.5\tFoo", t1),
+            format!("sprint\t{}\t10\t12.5\tFoo", t1),
+            "ring\t-\t500\t60\tFoo\tBar".to_string(),
+            "ring\t-\t500\t60\tFoo\tBar".to_string(),
+        ];
+        fs::write(&filename, original.join("\n") + "\n").unwrap();
+
+        assert_eq!(remove_duplicates_from_file(&filename).unwrap(), 1);
+        let mut expected = original.to_vec();
+        expected.remove(2);
+        assert_eq!(read_file(&filename), expected.join("\n") + "\n");
+
+        assert_eq!(remove_duplicates_from_file(&filename).unwrap(), 0);
+        assert_eq!(read_file(&filename), expected.join("\n") + "\n");
+    }
+
+    fn mixed_file(tempdir: &tempfile::TempDir) -> (String, Vec<String>) {
+        let filename = tempdir
+            .path()
+            .join("high_scores.txt")
+            .to_str()
+            .unwrap()
+            .to_string();
+        let t = "2030-01-01T12:00:00+00:00";
+        let lines = vec![
+            "catris high scores file v4".to_string(),
+            format!("ring\t{}\t500\t60\tFoo\tBar", t),
+            format!("ring\t{}\tlol\t60\tFoo", t), // line 3: bad score
+            "# comment".to_string(),
+            format!("bottle\t{}\t100\t-5\tFoo", t), // line 5: negative duration
+            format!("tetris\t{}\t100\t5\tFoo", t),  // line 6: unknown mode
+            format!("traditional\t{}\t100\t5", t),  // line 7: no players
+            format!("sprint\t{}\t10\t12.5\tFoo", t),
+            format!("traditional\t{}\t100\tinf\tFoo", t), // line 9: infinite duration
+            format!("traditional\t-\t100\t5\tFoo"),
+        ];
+        fs::write(&filename, lines.join("\n") + "\n").unwrap();
+        (filename, lines)
+    }
+
+    #[test]
+    fn test_checking_file() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let (filename, lines) = mixed_file(&tempdir);
+
+        let report = check_file(&filename, false).unwrap();
+        let corrupt_linenos: Vec<usize> = report.corrupt_lines.iter().map(|(n, _)| *n).collect();
+        assert_eq!(corrupt_linenos, vec![3, 5, 6, 7, 9]);
+        assert!(!report.fixed);
+
+        let summary = report.summary_lines();
+        assert_eq!(
+            summary[..5],
+            [
+                "traditional: 1 entries",
+                "bottle: 0 entries",
+                "ring: 1 entries",
+                "zen: 0 entries",
+                "sprint: 1 entries",
+            ]
+        );
+        assert_eq!(
+            summary[5],
+            "line 3 is corrupt: invalid digit found in string"
+        );
+        assert_eq!(summary[7], "line 6 is corrupt: unknown mode: \"tetris\"");
+        assert_eq!(summary.len(), 10);
+
+        // Reporting doesn't change anything
+        assert_eq!(read_file(&filename), lines.join("\n") + "\n");
+        assert!(!fs::exists(filename.clone() + ".bak").unwrap());
+
+        // The server uses the same parser and doesn't panic on the bad lines
+        assert!(read_matching_high_scores(&filename, Mode::Traditional, false).is_err());
+        assert!(read_matching_high_scores(&filename, Mode::Bottle, false).is_err());
+    }
+
+    #[test]
+    fn test_fixing_file() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let (filename, lines) = mixed_file(&tempdir);
+
+        let report = check_file(&filename, true).unwrap();
+        assert_eq!(report.corrupt_lines.len(), 5);
+        assert!(report.fixed);
+        assert_eq!(
+            report.summary_lines().last().unwrap(),
+            "Removed 5 corrupt lines"
+        );
+        assert_eq!(
+            read_file(&(filename.clone() + ".bak")),
+            lines.join("\n") + "\n"
+        );
+
+        let mut expected = lines.clone();
+        for i in [8, 6, 5, 4, 2] {
+            expected.remove(i);
+        }
+        assert_eq!(read_file(&filename), expected.join("\n") + "\n");
+
+        let report = check_file(&filename, true).unwrap();
+        assert!(report.corrupt_lines.is_empty());
+        assert!(!report.fixed);
+        assert_eq!(
+            report.summary_lines().last().unwrap(),
+            "No corrupt lines found"
+        );
+        assert_eq!(
+            read_matching_high_scores(&filename, Mode::Ring, true)
+                .unwrap()
+                .len(),
+            1
+        );
+    }
 }
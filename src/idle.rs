@@ -0,0 +1,104 @@
+// Players who don't press keys get a warning banner, and then get disconnected.
+// Times are passed in as arguments, so that tests don't need to wait.
+
+use std::time::Duration;
+use std::time::Instant;
+
+pub const IDLE_WARNING_TIME: Duration = Duration::from_secs(8 * 60);
+pub const IDLE_DISCONNECT_TIME: Duration = Duration::from_secs(10 * 60);
+const COUNTDOWN_UPDATE_INTERVAL: Duration = Duration::from_secs(10);
+
+pub struct IdleTracker {
+    last_key_press: Instant,
+}
+
+impl IdleTracker {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            last_key_press: now,
+        }
+    }
+
+    pub fn key_pressed(&mut self, now: Instant) {
+        self.last_key_press = now;
+    }
+
+    pub fn should_disconnect(&self, now: Instant) -> bool {
+        now >= self.last_key_press + IDLE_DISCONNECT_TIME
+    }
+
+    // Banner text, or None if no warning is needed yet
+    pub fn get_warning(&self, now: Instant) -> Option<String> {
+        let idle_time = now.saturating_duration_since(self.last_key_press);
+        if idle_time < IDLE_WARNING_TIME {
+            return None;
+        }
+
+        // Countdown only changes every 10 seconds, so that it doesn't cause lots of redrawing
+        let remaining = IDLE_DISCONNECT_TIME.saturating_sub(idle_time).as_secs();
+        let interval = COUNTDOWN_UPDATE_INTERVAL.as_secs();
+        let remaining = remaining.div_ceil(interval) * interval;
+        Some(format!(
+            "You will be disconnected in {}:{:02} due to inactivity. Press any key.",
+            remaining / 60,
+            remaining % 60
+        ))
+    }
+
+    // When the warning may need to change next time
+    pub fn next_check_time(&self, now: Instant) -> Instant {
+        let warn_time = self.last_key_press + IDLE_WARNING_TIME;
+        if now < warn_time {
+            return warn_time;
+        }
+        let intervals_done = (now - warn_time).as_secs() / COUNTDOWN_UPDATE_INTERVAL.as_secs();
+        warn_time + COUNTDOWN_UPDATE_INTERVAL * (intervals_done as u32 + 1)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_warning_and_cancel() {
+        let start = Instant::now();
+        let mut tracker = IdleTracker::new(start);
+
+        let minutes = |m: u64| start + Duration::from_secs(m * 60);
+        assert_eq!(tracker.get_warning(minutes(7)), None);
+        assert_eq!(tracker.next_check_time(minutes(7)), minutes(8));
+
+        assert_eq!(
+            tracker.get_warning(minutes(8)),
+            Some("You will be disconnected in 2:00 due to inactivity. Press any key.".to_string())
+        );
+        let later = minutes(8) + Duration::from_secs(15);
+        assert!(tracker.get_warning(later).unwrap().contains(" 1:50 "));
+        assert_eq!(
+            tracker.next_check_time(later),
+            minutes(8) + Duration::from_secs(20)
+        );
+        assert!(!tracker.should_disconnect(later));
+
+        tracker.key_pressed(later);
+        assert_eq!(tracker.get_warning(later), None);
+        assert_eq!(tracker.get_warning(minutes(10)), None);
+        assert!(!tracker.should_disconnect(minutes(10)));
+    }
+
+    #[test]
+    fn test_warning_and_disconnect() {
+        let start = Instant::now();
+        let tracker = IdleTracker::new(start);
+
+        let almost = start + IDLE_DISCONNECT_TIME - Duration::from_secs(1);
+        assert!(tracker.get_warning(almost).unwrap().contains(" 0:10 "));
+        assert!(!tracker.should_disconnect(almost));
+        assert_eq!(
+            tracker.next_check_time(almost),
+            start + IDLE_DISCONNECT_TIME
+        );
+        assert!(tracker.should_disconnect(start + IDLE_DISCONNECT_TIME));
+    }
+}
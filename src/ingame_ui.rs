@@ -1,23 +1,182 @@
 use crate::client::Client;
 use crate::escapes::Color;
-use crate::escapes::TerminalType;
 use crate::game_logic::blocks::FallingBlock;
+use crate::game_logic::bottle::get_basin_split;
+use crate::game_logic::bottle::BOTTLE_MAP;
+use crate::game_logic::game::format_score;
 use crate::game_logic::game::Game;
+use crate::game_logic::game::LeaveReason;
 use crate::game_logic::game::Mode;
-use crate::game_logic::game::BOTTLE_MAP;
-use crate::game_logic::game::RING_MAP;
-use crate::game_logic::game::RING_OUTER_RADIUS;
+use crate::game_logic::game::RESCUE_COST;
+use crate::game_logic::game::RESCUE_ROWS;
 use crate::game_logic::player::BlockOrTimer;
 use crate::game_logic::player::Player;
+use crate::game_logic::player::Rescue;
+use crate::game_logic::ring::get_ring_stack_height;
+use crate::game_logic::ring::RING_INNER_RADIUS;
+use crate::game_logic::ring::RING_MAP;
+use crate::game_logic::ring::RING_OUTER_RADIUS;
+use crate::game_logic::WorldPoint;
+use crate::game_wrapper::GameClock;
+use crate::game_wrapper::PauseVote;
+use crate::lobby::local_player_id;
 use crate::render::RenderBuffer;
 use crate::render::RenderData;
+use crate::strings::lookup;
 use std::cell::RefCell;
 use std::cmp::max;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::time::Duration;
+use std::time::Instant;
+
+const NAME_FLASH_DURATION: Duration = Duration::from_secs(2);
+const RING_MARKER_BLINK_INTERVAL: Duration = Duration::from_millis(500);
+const RING_DANGER_ROWS: usize = 4; // marker blinks when blocks land this close to where they spawn
+const DANGER_ROWS: usize = 5; // player's area turns red when blocks land this close to spawn
+const SCORE_FLASH_FRAMES: u8 = 2; // renders with bright score after getting points
+const SCORE_FLASH_FRAME_DURATION: Duration = Duration::from_millis(150);
+const RING_CLEAR_SHOW_DURATION: Duration = Duration::from_secs(5);
+const DEPARTURE_SHOW_DURATION: Duration = Duration::from_secs(5);
+
+// Remembers the client's own players between renders, to notice landing blocks and starting timers
+#[derive(Default)]
+pub struct PlayerCues {
+    landed_counts: HashMap<u64, usize>,
+    waiting: HashMap<u64, bool>,
+    flash_ends: HashMap<u64, Instant>,
+    marker_blink_on: bool,
+    next_marker_blink: Option<Instant>,
+    last_score: Option<usize>,
+    score_flash_frames_left: u8,
+    score_flash_redraw: Option<Instant>, // to show the next flash frame or stop flashing
+    countdown_redraw: Option<Instant>,   // when the countdown shows the next number
+    clock_redraw: Option<Instant>,       // when the game time shows the next second
+    last_cleared_row_count: Option<usize>,
+    ring_clear_shown_until: Option<Instant>,
+    last_departure_count: Option<usize>,
+    departure_shown_until: Option<Instant>,
+    // With reduced flashing, color and expiry of each flashing point when first rendered
+    reduced_flashes: HashMap<WorldPoint, (u8, Instant)>,
+}
+
+impl PlayerCues {
+    // Returns how many blocks landed since the previous call
+    fn update(&mut self, game: &Game, client_id: u64, now: Instant) -> usize {
+        let mut landed = 0;
+        for player in &game.players {
+            let player = player.borrow();
+            if player.client_id != client_id && player.client_id != local_player_id(client_id) {
+                continue;
+            }
+
+            let old_count = self
+                .landed_counts
+                .insert(player.client_id, player.landed_count)
+                .unwrap_or(player.landed_count);
+            landed += player.landed_count.saturating_sub(old_count);
+
+            let waiting = !matches!(player.block_or_timer, BlockOrTimer::Block(_));
+            let was_waiting = self
+                .waiting
+                .insert(player.client_id, waiting)
+                .unwrap_or(waiting);
+            if waiting && !was_waiting {
+                self.flash_ends
+                    .insert(player.client_id, now + NAME_FLASH_DURATION);
+            }
+        }
+        self.flash_ends.retain(|_, end| *end > now);
+
+        let score = game.get_score();
+        if matches!(self.last_score, Some(old) if score > old) {
+            self.score_flash_frames_left = SCORE_FLASH_FRAMES;
+        }
+        self.last_score = Some(score);
+
+        let cleared = game.get_cleared_row_count();
+        if game.mode == Mode::Ring
+            && matches!(self.last_cleared_row_count, Some(old) if cleared > old)
+        {
+            self.ring_clear_shown_until = Some(now + RING_CLEAR_SHOW_DURATION);
+        }
+        self.last_cleared_row_count = Some(cleared);
+        if matches!(self.ring_clear_shown_until, Some(end) if end <= now) {
+            self.ring_clear_shown_until = None;
+        }
+
+        let departures = game.get_departure_count();
+        if matches!(self.last_departure_count, Some(old) if departures > old) {
+            self.departure_shown_until = Some(now + DEPARTURE_SHOW_DURATION);
+        }
+        self.last_departure_count = Some(departures);
+        if matches!(self.departure_shown_until, Some(end) if end <= now) {
+            self.departure_shown_until = None;
+        }
+        self.reduced_flashes
+            .retain(|point, _| game.flashing_points.contains_key(point));
+        landed
+    }
+
+    /*
+    With reduced flashing, bombs get a steady highlight instead of blinking, and
+    cleared rows are highlighted only in the first frame of their flash. Every
+    frame of a flash has a new expiry time, see flash() in game_wrapper.rs.
+    */
+    fn show_reduced_flash(&mut self, point: WorldPoint, bg: u8, expiry: Instant) -> bool {
+        let (first_bg, first_expiry) = *self.reduced_flashes.entry(point).or_insert((bg, expiry));
+        first_bg != Color::WHITE_BACKGROUND.bg || expiry == first_expiry
+    }
+
+    // Returns true if the score should be rendered in a bright color this time
+    fn take_score_flash_frame(&mut self, now: Instant) -> bool {
+        if self.score_flash_frames_left == 0 {
+            self.score_flash_redraw = None;
+            return false;
+        }
+        self.score_flash_frames_left -= 1;
+        self.score_flash_redraw = Some(now + SCORE_FLASH_FRAME_DURATION);
+        true
+    }
+
+    fn update_marker_blinking(&mut self, blinking: bool, now: Instant) {
+        if !blinking {
+            self.marker_blink_on = false;
+            self.next_marker_blink = None;
+        } else if !matches!(self.next_marker_blink, Some(time) if time > now) {
+            self.marker_blink_on = !self.marker_blink_on;
+            self.next_marker_blink = Some(now + RING_MARKER_BLINK_INTERVAL);
+        }
+    }
+
+    // The game must be rendered again when flashing ends or a marker blinks
+    pub fn next_redraw_time(&self) -> Option<Instant> {
+        self.flash_ends
+            .values()
+            .copied()
+            .chain(self.next_marker_blink)
+            .chain(self.score_flash_redraw)
+            .chain(self.countdown_redraw)
+            .chain(self.clock_redraw)
+            .chain(self.ring_clear_shown_until)
+            .chain(self.departure_shown_until)
+            .min()
+    }
+}
+
+fn get_name_color(player: &Player, flashing: &HashMap<u64, Instant>) -> Color {
+    if flashing.contains_key(&player.client_id) {
+        Color::new(30, player.color + 10)
+    } else {
+        Color::new(player.color, 0)
+    }
+}
 
 #[allow(clippy::too_many_arguments)]
 fn render_name_lines(
     players: &[RefCell<Player>],
     highlight_client_id: u64,
+    flashing: &HashMap<u64, Instant>,
     buffer: &mut RenderBuffer,
     x_offset: usize,
     width_per_player: usize,
@@ -29,12 +188,10 @@ fn render_name_lines(
         let left = x_offset + (i * width_per_player);
         let right = left + width_per_player;
         let text = player.borrow().get_name_string(width_per_player);
-        let color = Color {
-            fg: player.borrow().color,
-            bg: 0,
-        };
+        let color = Color::new(player.borrow().color, 0);
         let free_space = width_per_player - text.chars().count();
-        buffer.add_text_with_color(left + (free_space / 2), name_y, &text, color);
+        let name_color = get_name_color(&player.borrow(), flashing);
+        buffer.add_text_with_color(left + (free_space / 2), name_y, &text, name_color);
 
         let line_character = if player.borrow().client_id == highlight_client_id {
             "="
@@ -150,11 +307,12 @@ fn get_wrapped_name(player: &Player, letter: char) -> String {
     result
 }
 
-fn prepare_player_for_ring_game_rendering(
+// Finds the player whose name goes to the given letter of RING_MAP
+fn find_ring_player_index(
     players: &[RefCell<Player>],
     this_player_client_id: u64,
     letter: char,
-) -> (String, Color) {
+) -> Option<usize> {
     let (down_x, down_y) = players
         .iter()
         .map(|p| p.borrow())
@@ -172,115 +330,205 @@ fn prepare_player_for_ring_game_rendering(
 
     players
         .iter()
-        .map(|p| p.borrow())
-        .find(|p| p.down_direction == other_down_dir)
-        .map(|p| (get_wrapped_name(&*p, letter), Color { fg: p.color, bg: 0 }))
-        .unwrap_or_else(|| ("".to_string(), Color::DEFAULT))
+        .position(|p| p.borrow().down_direction == other_down_dir)
 }
 
-fn render_walls(game: &Game, buffer: &mut RenderBuffer, client_id: u64) {
-    match game.mode {
-        Mode::Traditional => {
-            buffer.set_char(0, 1, 'o');
-            buffer.set_char(2 * game.get_width() + 1, 1, 'o');
-            render_name_lines(
-                &game.players,
-                client_id,
-                buffer,
-                1,
-                2 * game.get_width_per_player().unwrap(),
-                0,
-                1,
-                false,
-            );
+// Returns name text, wall color and name color
+fn prepare_player_for_ring_game_rendering(
+    players: &[RefCell<Player>],
+    this_player_client_id: u64,
+    flashing: &HashMap<u64, Instant>,
+    letter: char,
+) -> (String, Color, Color) {
+    find_ring_player_index(players, this_player_client_id, letter)
+        .map(|i| {
+            let p = players[i].borrow();
+            (
+                get_wrapped_name(&p, letter),
+                Color::new(p.color, 0),
+                get_name_color(&p, flashing),
+            )
+        })
+        .unwrap_or_else(|| ("".to_string(), Color::DEFAULT, Color::DEFAULT))
+}
 
-            for y in 2..(2 + game.get_height()) {
-                buffer.set_char(0, y, '|');
-                buffer.set_char(2 * game.get_width() + 1, y, '|');
-            }
+fn is_about_to_lose_ring_game(game: &Game, player_idx: usize) -> bool {
+    let quadrant_height = (RING_OUTER_RADIUS - RING_INNER_RADIUS) as usize;
+    let waiting = !matches!(
+        game.players[player_idx].borrow().block_or_timer,
+        BlockOrTimer::Block(_)
+    );
+    waiting || get_ring_stack_height(game, player_idx) + RING_DANGER_ROWS > quadrant_height
+}
 
-            let bottom_y = 2 + game.get_height();
-            buffer.set_char(0, bottom_y, 'o');
-            buffer.set_char(2 * game.get_width() + 1, bottom_y, 'o');
-            for x in 1..(2 * game.get_width() + 1) {
-                buffer.set_char(x, bottom_y, '-');
-            }
+// Shows the first letter of each other player's name on the edge of the ring where they are.
+// People tend to look at their own edge, so the letter blinks when the player is in trouble.
+fn render_ring_markers(
+    game: &Game,
+    buffer: &mut RenderBuffer,
+    client_id: u64,
+    cues: &mut PlayerCues,
+    now: Instant,
+) {
+    let w = RING_MAP[0].len();
+    let h = RING_MAP.len();
+    let mut markers = vec![];
+    for (letter, x, y) in [('a', 0, h / 2), ('s', w / 2, h - 1), ('d', w - 1, h / 2)] {
+        if let Some(i) = find_ring_player_index(&game.players, client_id, letter) {
+            let player = game.players[i].borrow();
+            let initial = player.name.chars().next().unwrap_or('?');
+            markers.push((
+                x,
+                y,
+                initial,
+                player.color,
+                is_about_to_lose_ring_game(game, i),
+            ));
         }
-        Mode::Bottle => {
-            for (player_idx, player) in game.players.iter().enumerate() {
-                let left = player_idx * BOTTLE_MAP[0].len();
-                let color = Color {
-                    fg: player.borrow().color,
-                    bg: 0,
-                };
-                for (y, line) in BOTTLE_MAP.iter().enumerate() {
-                    let is_in_personal_space = !line.starts_with('|');
-                    for (i, ch) in line.chars().enumerate() {
-                        let is_at_edge = (player_idx == 0 && i == 0)
-                            || (player_idx == game.players.len() - 1 && i == line.len() - 1);
-                        if ch != 'x'
-                            && ch != ' '
-                            && (ch != '|' || is_in_personal_space || is_at_edge)
-                        {
-                            buffer.set_char_with_color(left + i, y, ch, color);
-                        }
-                    }
-                }
+    }
+
+    cues.update_marker_blinking(markers.iter().any(|m| m.4), now);
+
+    for (x, y, initial, color, in_trouble) in markers {
+        if in_trouble && cues.marker_blink_on {
+            if buffer.has_color() {
+                let color = Color::new(30, color + 10);
+                buffer.set_char_with_color(x, y, initial, color);
+            } else {
+                buffer.set_char(x, y, '!');
             }
-            render_name_lines(
-                &game.players,
-                client_id,
-                buffer,
-                0,
-                BOTTLE_MAP[0].len(),
-                BOTTLE_MAP.len() + 1,
-                BOTTLE_MAP.len(),
-                true,
-            );
+        } else {
+            buffer.set_char_with_color(x, y, initial, Color::new(color, 0));
         }
-        Mode::Ring => {
-            let (w_text, w_color) =
-                prepare_player_for_ring_game_rendering(&game.players, client_id, 'w');
-            let (a_text, a_color) =
-                prepare_player_for_ring_game_rendering(&game.players, client_id, 'a');
-            let (s_text, s_color) =
-                prepare_player_for_ring_game_rendering(&game.players, client_id, 's');
-            let (d_text, d_color) =
-                prepare_player_for_ring_game_rendering(&game.players, client_id, 'd');
-            let mut w_chars = w_text.chars();
-            let mut a_chars = a_text.chars();
-            let mut s_chars = s_text.chars();
-            let mut d_chars = d_text.chars();
-
-            for (y, line) in RING_MAP.iter().enumerate() {
-                for (x, spec_char) in line.chars().enumerate() {
-                    let ch = match spec_char {
-                        'w' => w_chars.next().unwrap_or(' '),
-                        'a' => a_chars.next().unwrap_or(' '),
-                        's' => s_chars.next().unwrap_or(' '),
-                        'd' => d_chars.next().unwrap_or(' '),
-                        'x' | ' ' => continue,
-                        other => other,
-                    };
-                    let color = match spec_char {
-                        'w' => w_color,
-                        'a' => a_color,
-                        's' => s_color,
-                        'd' => d_color,
-                        '|' if (1..(line.len() / 2)).contains(&x) => a_color,
-                        '|' if ((line.len() / 2)..(line.len() - 1)).contains(&x) => d_color,
-                        '=' => w_color,
-                        '-' if y != 0 && y != RING_MAP.len() - 1 => s_color,
-                        _ => Color::DEFAULT,
-                    };
-                    buffer.set_char_with_color(x, y, ch, color);
+    }
+}
+
+// Walls and names around the board. Each mode has its own layout, the stuff on the side is shared.
+fn render_traditional_layout(
+    game: &Game,
+    buffer: &mut RenderBuffer,
+    client_id: u64,
+    flashing: &HashMap<u64, Instant>,
+) {
+    buffer.set_char(0, 1, 'o');
+    buffer.set_char(2 * game.get_width() + 1, 1, 'o');
+    render_name_lines(
+        &game.players,
+        client_id,
+        flashing,
+        buffer,
+        1,
+        2 * game.get_width_per_player().unwrap(),
+        0,
+        1,
+        false,
+    );
+
+    for y in 2..(2 + game.get_height()) {
+        buffer.set_char(0, y, '|');
+        buffer.set_char(2 * game.get_width() + 1, y, '|');
+    }
+
+    let bottom_y = 2 + game.get_height();
+    buffer.set_char(0, bottom_y, 'o');
+    buffer.set_char(2 * game.get_width() + 1, bottom_y, 'o');
+    for x in 1..(2 * game.get_width() + 1) {
+        buffer.set_char(x, bottom_y, '-');
+    }
+}
+
+fn render_bottle_layout(
+    game: &Game,
+    buffer: &mut RenderBuffer,
+    client_id: u64,
+    flashing: &HashMap<u64, Instant>,
+) {
+    let options = game.get_board_options();
+    let split = get_basin_split(options.two_bottle_basins, game.players.len());
+    let personal_height = options.bottle_necks.personal_space_height();
+    for (player_idx, player) in game.players.iter().enumerate() {
+        let left = player_idx * BOTTLE_MAP[0].len();
+        let color = Color::new(player.borrow().color, 0);
+        // Walls on the sides of basins are drawn, walls between players are landed squares
+        let is_at_left_edge = player_idx == 0 || Some(player_idx) == split;
+        let is_at_right_edge =
+            player_idx == game.players.len() - 1 || Some(player_idx + 1) == split;
+        for (y, line) in BOTTLE_MAP.iter().enumerate() {
+            let is_in_personal_space = y < personal_height;
+            for (i, ch) in line.chars().enumerate() {
+                let is_at_edge =
+                    (is_at_left_edge && i == 0) || (is_at_right_edge && i == line.len() - 1);
+                if ch != 'x' && ch != ' ' && (ch != '|' || is_in_personal_space || is_at_edge) {
+                    buffer.set_char_with_color(left + i, y, ch, color);
                 }
             }
         }
     }
+    render_name_lines(
+        &game.players,
+        client_id,
+        flashing,
+        buffer,
+        0,
+        BOTTLE_MAP[0].len(),
+        BOTTLE_MAP.len() + 1,
+        BOTTLE_MAP.len(),
+        true,
+    );
+}
+
+fn render_ring_layout(
+    game: &Game,
+    buffer: &mut RenderBuffer,
+    client_id: u64,
+    flashing: &HashMap<u64, Instant>,
+) {
+    let (w_text, w_color, w_name_color) =
+        prepare_player_for_ring_game_rendering(&game.players, client_id, flashing, 'w');
+    let (a_text, a_color, a_name_color) =
+        prepare_player_for_ring_game_rendering(&game.players, client_id, flashing, 'a');
+    let (s_text, s_color, s_name_color) =
+        prepare_player_for_ring_game_rendering(&game.players, client_id, flashing, 's');
+    let (d_text, d_color, d_name_color) =
+        prepare_player_for_ring_game_rendering(&game.players, client_id, flashing, 'd');
+    let mut w_chars = w_text.chars();
+    let mut a_chars = a_text.chars();
+    let mut s_chars = s_text.chars();
+    let mut d_chars = d_text.chars();
+
+    for (y, line) in RING_MAP.iter().enumerate() {
+        for (x, spec_char) in line.chars().enumerate() {
+            let ch = match spec_char {
+                'w' => w_chars.next().unwrap_or(' '),
+                'a' => a_chars.next().unwrap_or(' '),
+                's' => s_chars.next().unwrap_or(' '),
+                'd' => d_chars.next().unwrap_or(' '),
+                'x' | ' ' => continue,
+                other => other,
+            };
+            let color = match spec_char {
+                'w' => w_name_color,
+                'a' => a_name_color,
+                's' => s_name_color,
+                'd' => d_name_color,
+                '|' if (1..(line.len() / 2)).contains(&x) => a_color,
+                '|' if ((line.len() / 2)..(line.len() - 1)).contains(&x) => d_color,
+                '=' => w_color,
+                '-' if y != 0 && y != RING_MAP.len() - 1 => s_color,
+                _ => Color::DEFAULT,
+            };
+            buffer.set_char_with_color(x, y, ch, color);
+        }
+    }
 }
 
-fn render_blocks(game: &Game, buffer: &mut RenderBuffer, client_id: u64) {
+fn render_blocks(
+    game: &Game,
+    buffer: &mut RenderBuffer,
+    client_id: u64,
+    reduced_flashing: bool,
+    cues: &mut PlayerCues,
+) {
     let player_idx = game
         .players
         .iter()
@@ -288,7 +536,7 @@ fn render_blocks(game: &Game, buffer: &mut RenderBuffer, client_id: u64) {
         .unwrap();
 
     let (offset_x, offset_y) = match game.mode {
-        Mode::Traditional => (1, 2),
+        Mode::Traditional | Mode::Zen => (1, 2),
         Mode::Bottle => (1, 0),
         Mode::Ring => {
             let r = RING_OUTER_RADIUS as i32;
@@ -313,6 +561,12 @@ fn render_blocks(game: &Game, buffer: &mut RenderBuffer, client_id: u64) {
 
     let (viewer_dir_x, viewer_dir_y) = game.players[player_idx].borrow().down_direction;
 
+    // Empty squares of players who need help
+    let danger_points: HashSet<WorldPoint> = (0..game.players.len())
+        .filter(|i| game.get_free_rows_above_stack(*i) <= DANGER_ROWS)
+        .flat_map(|i| game.get_player_region(i))
+        .collect();
+
     let (x_start, x_end, y_start, y_end) = game.get_bounds_in_player_coords();
     for x in x_start..x_end {
         for y in y_start..y_end {
@@ -324,20 +578,24 @@ fn render_blocks(game: &Game, buffer: &mut RenderBuffer, client_id: u64) {
             let buffer_x = (offset_x + 2 * x) as usize;
             let buffer_y = (offset_y + y) as usize;
 
-            if let Some(flash_bg) = game.flashing_points.get(&world_point) {
-                if *flash_bg != 0 && !buffer.terminal_type.has_color() {
+            let flash = game
+                .flashing_points
+                .get(&world_point)
+                .filter(|(bg, expiry)| {
+                    !reduced_flashing || cues.show_reduced_flash(world_point, *bg, *expiry)
+                });
+            if let Some((flash_bg, _)) = flash {
+                // Flashing alternates between a bright color and no color.
+                // With reduced flashing, it's a dim color that doesn't blink.
+                if reduced_flashing && !buffer.has_color() {
+                    buffer.add_text(buffer_x, buffer_y, "::");
+                } else if reduced_flashing {
+                    buffer.add_text_with_color(buffer_x, buffer_y, "  ", Color::GRAY_BACKGROUND);
+                } else if *flash_bg != 0 && !buffer.has_color() {
                     // Use XX instead of colored spaces when colors are not available
                     buffer.add_text(buffer_x, buffer_y, "XX");
                 } else {
-                    buffer.add_text_with_color(
-                        buffer_x,
-                        buffer_y,
-                        "  ",
-                        Color {
-                            fg: 0,
-                            bg: *flash_bg,
-                        },
-                    );
+                    buffer.add_text_with_color(buffer_x, buffer_y, "  ", Color::new(0, *flash_bg));
                 }
             } else if let Some((content, relative_coords, owner_idx)) =
                 game.get_falling_square(world_point)
@@ -349,6 +607,7 @@ fn render_blocks(game: &Game, buffer: &mut RenderBuffer, client_id: u64) {
                     buffer_y,
                     Some((relative_coords, (moving_x as i8, moving_y as i8))),
                     (viewer_dir_x as i8, viewer_dir_y as i8),
+                    None,
                 );
             } else if let Some(content) = game.get_landed_square(world_point) {
                 content.render(
@@ -357,13 +616,16 @@ fn render_blocks(game: &Game, buffer: &mut RenderBuffer, client_id: u64) {
                     buffer_y,
                     None,
                     (viewer_dir_x as i8, viewer_dir_y as i8),
+                    game.get_ownership_tint(&content),
                 );
+            } else if danger_points.contains(&world_point) {
+                buffer.add_text_with_color(buffer_x, buffer_y, "  ", Color::DIM_RED_BACKGROUND);
             }
 
             if trace_points.contains(&world_point)
                 && buffer.get_char(buffer_x, buffer_y) == ' '
                 && buffer.get_char(buffer_x + 1, buffer_y) == ' '
-                && buffer.terminal_type != TerminalType::VT52
+                && buffer.has_color()
             {
                 buffer.add_text_with_foreground_color(buffer_x, buffer_y, "::", trace_color.fg);
             }
@@ -373,13 +635,29 @@ fn render_blocks(game: &Game, buffer: &mut RenderBuffer, client_id: u64) {
 
 fn get_size_without_stuff_on_side(game: &Game) -> (usize, usize) {
     let (extra_w, extra_h) = match game.mode {
-        Mode::Traditional => (2, 3), // 3 = player names, dashes below them, dashes at bottom
+        Mode::Traditional | Mode::Zen => (2, 3), // 3 = player names, dashes below them, dashes at bottom
         Mode::Bottle | Mode::Ring => (2, 2),
     };
     (game.get_width() * 2 + extra_w, game.get_height() + extra_h)
 }
 
 pub const SCORE_TEXT_COLOR: Color = Color::CYAN_FOREGROUND;
+const SCORE_FLASH_COLOR: Color = Color::new(97, 0); // bright white
+
+const SPARKLINE_CHARS: &[char] = &['▁', '▂', '▃', '▄', '▅', '▆', '▇'];
+const SPARKLINE_CHARS_ASCII: &[char] = &['.', ':', '-', '=', '+', '*'];
+
+// One character per sample, the biggest sample gets the last character of chars
+fn sparkline(samples: &[usize], chars: &[char]) -> String {
+    let max_sample = samples.iter().copied().max().unwrap_or(0);
+    samples
+        .iter()
+        .map(|sample| {
+            let index = (sample * (chars.len() - 1)).checked_div(max_sample);
+            chars[index.unwrap_or(0)]
+        })
+        .collect()
+}
 
 fn render_block(
     block: &FallingBlock,
@@ -401,13 +679,14 @@ fn render_block(
     let center_x = (text_x as isize) + 6;
     let center_y = (text_y as isize) + 4;
 
-    for (x, y) in block.get_relative_coords() {
+    for (x, y) in block.get_preview_coords() {
         block.square_content.render(
             buffer,
-            (center_x + 2 * (*x as isize)) as usize,
-            (center_y + (*y as isize)) as usize,
-            Some(((*x, *y), (0, 1))),
+            (center_x + 2 * (x as isize)) as usize,
+            (center_y + (y as isize)) as usize,
+            Some(((x, y), (0, 1))),
             (0, 1),
+            None,
         );
     }
 }
@@ -416,47 +695,1218 @@ fn render_stuff_on_side(
     game: &Game,
     buffer: &mut RenderBuffer,
     client: &Client,
-    lobby_id: &str,
+    lobby_id: Option<&str>, // None for games that aren't in a lobby
     x_offset: usize,
+    score_flashing: bool,
+    clock: GameClock,
 ) {
-    if client.lobby_id_hidden {
-        buffer.add_text(x_offset, 4, "Lobby ID: ******");
-    } else {
-        buffer.add_text(x_offset, 4, &format!("Lobby ID: {}", lobby_id));
+    match lobby_id {
+        Some(_) if client.lobby_id_hidden => {
+            buffer.add_text(
+                x_offset,
+                4,
+                &lookup("side.lobby_id").replace("{id}", "******"),
+            );
+        }
+        Some(id) => {
+            buffer.add_text(x_offset, 4, &lookup("side.lobby_id").replace("{id}", id));
+        }
+        None => {}
     }
 
-    buffer.add_text_with_color(
+    let mut score_end_x = buffer.add_text_with_color(
         x_offset,
         5,
-        &format!("Score: {}", game.get_score()),
-        SCORE_TEXT_COLOR,
+        &lookup("side.score").replace("{score}", &format_score(game.get_score())),
+        if score_flashing {
+            SCORE_FLASH_COLOR
+        } else {
+            SCORE_TEXT_COLOR
+        },
+    );
+    // Multiplayer scores are not comparable with single player scores
+    let multiplier = game.get_score_multiplier();
+    if multiplier > 1 {
+        let times = if buffer.terminal_type.supports_unicode() {
+            '×'
+        } else {
+            'x'
+        };
+        score_end_x = buffer.add_text_with_color(
+            score_end_x,
+            5,
+            &format!(" ({}{})", times, multiplier),
+            Color::GRAY_FOREGROUND,
+        );
+    }
+    if game.mode == Mode::Zen {
+        buffer.add_text(score_end_x, 5, lookup("side.zen"));
+    }
+
+    // Ring mode doesn't have room for this, the game is wide
+    if game.mode != Mode::Ring {
+        let history = game.get_score_history();
+        let max_len = buffer.width.saturating_sub(x_offset + 1);
+        let chars = if buffer.terminal_type.supports_unicode() {
+            SPARKLINE_CHARS
+        } else {
+            SPARKLINE_CHARS_ASCII
+        };
+        let line = sparkline(&history[history.len().saturating_sub(max_len)..], chars);
+        buffer.add_text_with_color(x_offset, 6, &line, SCORE_TEXT_COLOR);
+    }
+
+    let seconds = clock.elapsed.as_secs();
+    let time = format!("{:02}:{:02}", seconds / 60, seconds % 60);
+    let clock_text = format!(
+        "{}  {}",
+        lookup("side.time").replace("{time}", &time),
+        lookup("side.level").replace("{level}", &clock.level.to_string())
     );
+    if game.time_limit_reached() {
+        buffer.add_text_with_color(
+            x_offset,
+            7,
+            lookup("side.time_limit_reached"),
+            Color::RED_FOREGROUND,
+        );
+    } else {
+        buffer.add_text_with_color(x_offset, 7, &clock_text, Color::GRAY_FOREGROUND);
+    }
 
     if client.prefer_rotating_counter_clockwise {
-        buffer.add_text(x_offset, 6, "Counter-clockwise");
+        buffer.add_text(x_offset, 2, lookup("side.counter_clockwise"));
+    }
+    if !client.other_keys_cancel_soft_drop {
+        buffer.add_text(x_offset, 3, lookup("side.other_keys_keep_fast_down"));
+    }
+    if client.auto_shift_enabled {
+        buffer.add_text(x_offset, 21, lookup("side.auto_shift_on"));
+    }
+    if !client.bell_enabled {
+        buffer.add_text(x_offset, 22, lookup("side.bell_off"));
+    }
+    if client.reduced_flashing {
+        buffer.add_text(x_offset, 20, lookup("side.reduced_flashing"));
+    }
+    if game.can_undo() {
+        buffer.add_text_with_color(x_offset, 14, lookup("side.undo"), Color::GRAY_FOREGROUND);
+    }
+    if let Some(seed) = game.get_block_seed() {
+        let text = lookup("side.seed").replace("{seed}", &seed.to_string());
+        buffer.add_text(x_offset, 23, &text);
     }
 
+    // Previews are shown only to their own player, so nobody can time things around them
     let player = game
         .players
         .iter()
         .find(|p| p.borrow().client_id == client.id)
         .unwrap()
         .borrow();
-    render_block(&player.next_block_queue[0], buffer, x_offset, 8, "Next:");
+    render_block(
+        &player.next_block_queue[0],
+        buffer,
+        x_offset,
+        8,
+        lookup("side.next"),
+    );
 
     if let Some(block) = &player.block_in_hold {
-        render_block(block, buffer, x_offset, 16, "Holding:");
+        render_block(block, buffer, x_offset, 16, lookup("side.holding"));
     } else {
-        buffer.add_text(x_offset, 16, "Nothing in hold");
-        buffer.add_text(x_offset, 17, "   (press h)");
+        buffer.add_text(x_offset, 16, lookup("side.nothing_in_hold"));
+        buffer.add_text(x_offset, 17, lookup("side.press_h"));
+    }
+}
+
+// Who landed the squares of the cleared rings. Ring mode is tall, so this goes below everything else.
+fn render_ring_clear(game: &Game, buffer: &mut RenderBuffer, x_offset: usize) {
+    if game.get_last_ring_clear().is_empty() {
+        return;
+    }
+    buffer.add_text(x_offset, 25, lookup("side.ring_cleared"));
+    for (i, (name, color, count)) in game.get_last_ring_clear().iter().enumerate() {
+        let y = 26 + i;
+        let x = buffer.add_text_with_color(x_offset + 2, y, name, Color::new(*color, 0));
+        buffer.add_text(x, y, &format!(" {}", count));
     }
 }
 
-pub fn render(game: &Game, render_data: &mut RenderData, client: &Client, lobby_id: &str) {
+// Covers the top of the board for a while, so that the board changing doesn't look like a bug
+fn render_departure(game: &Game, buffer: &mut RenderBuffer, board_width: usize) {
+    let departure = match game.get_last_departure() {
+        Some(departure) => departure,
+        None => return,
+    };
+    let mut text = match departure.reason {
+        LeaveReason::Quit => lookup("departure.quit"),
+        LeaveReason::Disconnected => lookup("departure.disconnected"),
+    }
+    .to_string();
+    // Ring mode keeps the squares of players who left
+    if game.mode != Mode::Ring {
+        text.push_str(lookup("departure.board_shrank"));
+    }
+
+    let (before, after) = text.split_once("{name}").unwrap_or((&text, ""));
+    let width = before.chars().count() + departure.name.chars().count() + after.chars().count();
+    let x = board_width.saturating_sub(width) / 2;
+    let x = buffer.add_text_with_color(x, 0, before, Color::YELLOW_FOREGROUND);
+    let name_color = Color::new(departure.color, 0);
+    let x = buffer.add_text_with_color(x, 0, &departure.name, name_color);
+    buffer.add_text_with_color(x, 0, after, Color::YELLOW_FOREGROUND);
+}
+
+// 3 pixels wide and 5 tall, each pixel is two characters
+const BIG_DIGITS: [[&str; 5]; 5] = [
+    [" # ", "## ", " # ", " # ", "###"],
+    ["## ", "  #", " # ", "#  ", "###"],
+    ["## ", "  #", " # ", "  #", "## "],
+    ["# #", "# #", "###", "  #", "  #"],
+    ["###", "#  ", "## ", "  #", "## "],
+];
+
+// Big number in the middle of the game, seen by everyone until blocks start falling
+fn render_countdown(game: &Game, buffer: &mut RenderBuffer, cues: &mut PlayerCues, now: Instant) {
+    let remaining = match game.get_countdown_remaining(now) {
+        Some(remaining) => remaining,
+        None => {
+            cues.countdown_redraw = None;
+            return;
+        }
+    };
+    let seconds = remaining.as_secs() + u64::from(remaining.subsec_nanos() != 0);
+    cues.countdown_redraw = Some(now + (remaining - Duration::from_secs(seconds - 1)));
+
     let (w, h) = get_size_without_stuff_on_side(game);
-    let room_for_stuff_on_side_size = 20;
+    let digit = BIG_DIGITS[(seconds.clamp(1, BIG_DIGITS.len() as u64) - 1) as usize];
+    let left = (w / 2).saturating_sub(5);
+    let top = (h / 2).saturating_sub(4);
+    let has_color = buffer.terminal_type.has_color();
+    for (i, row) in digit.iter().enumerate() {
+        // Border around the digit, so that it doesn't get lost among landed blocks
+        buffer.add_text_with_color(left, top + i + 1, "  ", Color::BLACK_ON_WHITE);
+        buffer.add_text_with_color(left + 8, top + i + 1, "  ", Color::BLACK_ON_WHITE);
+        for (j, pixel) in row.chars().enumerate() {
+            let x = left + 2 + 2 * j;
+            match (pixel, has_color) {
+                ('#', true) => buffer.add_text_with_color(x, top + i + 1, "  ", Color::DEFAULT),
+                ('#', false) => buffer.add_text(x, top + i + 1, "##"),
+                _ => buffer.add_text_with_color(x, top + i + 1, "  ", Color::BLACK_ON_WHITE),
+            };
+        }
+    }
+    buffer.add_text_with_color(left, top, "          ", Color::BLACK_ON_WHITE);
+    buffer.add_text_with_color(left, top + 6, "          ", Color::BLACK_ON_WHITE);
+}
+
+// Drawn on top of the player's columns, only for that player. Offered only in traditional mode.
+fn render_rescue_offers(game: &Game, buffer: &mut RenderBuffer, client_id: u64) {
+    for (player_idx, player) in game.players.iter().enumerate() {
+        let player = player.borrow();
+        if player.rescue != Rescue::Offered
+            || (player.client_id != client_id && player.client_id != local_player_id(client_id))
+        {
+            continue;
+        }
+
+        let region = game.get_player_region(player_idx);
+        let x_min = region.iter().map(|(x, _)| *x).min().unwrap();
+        let x_max = region.iter().map(|(x, _)| *x).max().unwrap();
+        let width = 2 * (x_max - x_min + 1) as usize;
+        // Columns of a player are only 14 characters wide
+        let lines = [
+            "".to_string(),
+            lookup("rescue.clear_rows").replace("{rows}", &RESCUE_ROWS.to_string()),
+            lookup("rescue.cost").replace("{cost}", &RESCUE_COST.to_string()),
+            lookup("rescue.question").to_string(),
+            lookup("rescue.answer").to_string(),
+            "".to_string(),
+        ];
+        for (i, line) in lines.iter().enumerate() {
+            buffer.add_text_with_color(
+                (1 + 2 * x_min) as usize,
+                3 + i,
+                &format!("{:^width$}", line, width = width),
+                Color::BLACK_ON_WHITE,
+            );
+        }
+    }
+}
+
+pub fn render(
+    game: &Game,
+    render_data: &mut RenderData,
+    client: &Client,
+    lobby_id: Option<&str>,
+    cues: &mut PlayerCues,
+    clock: GameClock,
+) {
+    // The rendering code below assumes that the client is a player.
+    // It isn't when rendering after leaving the game.
+    if !game
+        .players
+        .iter()
+        .any(|p| p.borrow().client_id == client.id)
+    {
+        client.logger.log("Not rendering game, not playing anymore");
+        return;
+    }
+
+    let now = Instant::now();
+    let landed = cues.update(game, client.id, now);
+    if client.bell_enabled {
+        render_data.pending_bells += landed;
+    }
+
+    let (w, h) = get_size_without_stuff_on_side(game);
+    let room_for_stuff_on_side_size = 24; // fits "Time: 01:23  Level: 2" and the previews
     render_data.clear(max(w + room_for_stuff_on_side_size, 80), max(h, 24));
-    render_walls(game, &mut render_data.buffer, client.id);
-    render_blocks(game, &mut render_data.buffer, client.id);
-    render_stuff_on_side(game, &mut render_data.buffer, client, lobby_id, w + 2);
+    render_data.dim_motd_banner = true;
+    match game.mode {
+        Mode::Traditional | Mode::Zen => {
+            render_traditional_layout(game, &mut render_data.buffer, client.id, &cues.flash_ends);
+        }
+        Mode::Bottle => {
+            render_bottle_layout(game, &mut render_data.buffer, client.id, &cues.flash_ends);
+        }
+        Mode::Ring => {
+            render_ring_layout(game, &mut render_data.buffer, client.id, &cues.flash_ends);
+            render_ring_markers(game, &mut render_data.buffer, client.id, cues, now);
+        }
+    }
+    render_blocks(
+        game,
+        &mut render_data.buffer,
+        client.id,
+        client.reduced_flashing,
+        cues,
+    );
+    render_rescue_offers(game, &mut render_data.buffer, client.id);
+    render_countdown(game, &mut render_data.buffer, cues, now);
+    let score_flashing = cues.take_score_flash_frame(now);
+    render_stuff_on_side(
+        game,
+        &mut render_data.buffer,
+        client,
+        lobby_id,
+        w + 2,
+        score_flashing,
+        clock,
+    );
+    if cues.ring_clear_shown_until.is_some() {
+        render_ring_clear(game, &mut render_data.buffer, w + 2);
+    }
+    if cues.departure_shown_until.is_some() {
+        render_departure(game, &mut render_data.buffer, w);
+    }
+    cues.clock_redraw = if clock.running {
+        let until_next_second =
+            Duration::from_secs(1) - Duration::from_nanos(clock.elapsed.subsec_nanos().into());
+        Some(now + until_next_second)
+    } else {
+        None
+    };
+}
+
+// Above the usual stuff on the side, so that it's visible on the pause screen too
+pub fn render_pause_vote(render_data: &mut RenderData, game: &Game, vote: &PauseVote) {
+    let key = if vote.want_paused {
+        "side.pause_vote"
+    } else {
+        "side.unpause_vote"
+    };
+    let player_ids: HashSet<u64> = game.players.iter().map(|p| p.borrow().client_id).collect();
+    let text = lookup(key)
+        .replace("{name}", &vote.initiator_name)
+        .replace(
+            "{votes}",
+            &vote.voters.intersection(&player_ids).count().to_string(),
+        )
+        .replace("{players}", &player_ids.len().to_string());
+
+    let x = get_size_without_stuff_on_side(game).0 + 2;
+    let width = render_data.buffer.width.saturating_sub(x + 1);
+    // Usually 2 lines, but ring mode doesn't leave much room on the side
+    for (y, line) in wrap_text(&text, width).iter().enumerate() {
+        render_data
+            .buffer
+            .add_text_with_color(x, y, line, Color::YELLOW_FOREGROUND);
+    }
+}
+
+// Tutorial instructions, to the right of the usual stuff on the side
+pub fn render_tutorial_prompt(render_data: &mut RenderData, text: &str) {
+    let x = render_data.buffer.width - 28;
+    for (i, line) in wrap_text(text, 26).iter().enumerate() {
+        render_data
+            .buffer
+            .add_text_with_color(x, 2 + i, line, Color::GREEN_FOREGROUND);
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::connection::FrameQueue;
+    use crate::connection::FrameStats;
+    use crate::connection::Receiver;
+    use crate::escapes::KeyPress;
+    use crate::escapes::TerminalType;
+    use crate::game_logic::blocks::BlockType;
+    use crate::game_logic::blocks::Shape;
+    use crate::game_logic::blocks::SquareContent;
+    use crate::game_logic::game::COUNTDOWN_DURATION;
+    use crate::game_logic::game::FLASHING_POINT_EXPIRY;
+    use crate::game_logic::ring::ring_points;
+    use crate::game_logic::PlayerSpec;
+    use std::sync::Arc;
+    use tokio::time::timeout;
+
+    // Not running, so that it doesn't ask for a redraw every second
+    const TEST_CLOCK: GameClock = GameClock {
+        elapsed: Duration::from_secs(83),
+        level: 2,
+        running: false,
+    };
+
+    // Tests don't care about anything else than the client ID
+    pub fn player_spec(client_id: u64) -> PlayerSpec {
+        PlayerSpec {
+            name: format!("Player {}", client_id),
+            client_id,
+            lobby_order: client_id,
+            color: Color::RED_FOREGROUND.fg,
+            handicap: 0,
+        }
+    }
+
+    fn create_game(mode: Mode, player_count: usize) -> Game {
+        let mut game = Game::new(mode);
+        game.set_normal_block_factory(|_| FallingBlock::normal_from_shape(Shape::L));
+        for client_id in 0..(player_count as u64) {
+            game.add_player(&player_spec(client_id));
+        }
+        game
+    }
+
+    #[test]
+    fn test_removed_player_key_press_and_render() {
+        let mut game = create_game(Mode::Ring, 2);
+        game.remove_player_if_exists(1, LeaveReason::Quit);
+        assert!(!game.handle_key_press(1, false, true, KeyPress::Left));
+        assert!(!game.handle_key_press(1, false, true, KeyPress::Down));
+
+        let client = Client::new(1, Receiver::Test("".to_string()), TerminalType::Ansi);
+        let mut render_data = client.render_data.lock().unwrap();
+        render(
+            &game,
+            &mut render_data,
+            &client,
+            Some("ABCDEF"),
+            &mut PlayerCues::default(),
+            TEST_CLOCK,
+        );
+    }
+
+    #[test]
+    fn test_bomb_timer_in_next_block_preview() {
+        let mut game = create_game(Mode::Traditional, 1);
+        game.players[0].borrow_mut().next_block_queue[0] = FallingBlock::new(
+            BlockType::Bomb { initial_timer: 12 },
+            &mut rand::thread_rng(),
+        );
+
+        // Bombs in the preview don't tick yet
+        assert!(game.start_ticking_new_bombs().is_empty());
+
+        let client = Client::new(0, Receiver::Test("".to_string()), TerminalType::Ansi);
+        {
+            let mut render_data = client.render_data.lock().unwrap();
+            render(
+                &game,
+                &mut render_data,
+                &client,
+                Some("ABCDEF"),
+                &mut PlayerCues::default(),
+                TEST_CLOCK,
+            );
+        }
+        let text = client.text();
+        let lines: Vec<&str> = text.lines().filter(|line| line.contains("1212")).collect();
+        assert_eq!(lines.len(), 2);
+    }
+
+    #[test]
+    fn test_clock_rendering() {
+        let game = create_game(Mode::Traditional, 1);
+        let client = Client::new(0, Receiver::Test("".to_string()), TerminalType::Ansi);
+        let mut cues = PlayerCues::default();
+        let clock = GameClock {
+            elapsed: Duration::from_millis(83_250),
+            level: 2,
+            running: true,
+        };
+        {
+            let mut render_data = client.render_data.lock().unwrap();
+            render(&game, &mut render_data, &client, None, &mut cues, clock);
+        }
+        let text = client.text();
+        assert_eq!(
+            text.lines().nth(7).unwrap().trim_end(),
+            "|                    |  Time: 01:23  Level: 2"
+        );
+
+        // Redrawn when the time changes
+        let redraw = cues.next_redraw_time().unwrap();
+        assert!(redraw <= Instant::now() + Duration::from_millis(750));
+
+        // The time doesn't change while paused
+        let paused_clock = GameClock {
+            running: false,
+            ..clock
+        };
+        {
+            let mut render_data = client.render_data.lock().unwrap();
+            render(
+                &game,
+                &mut render_data,
+                &client,
+                None,
+                &mut cues,
+                paused_clock,
+            );
+        }
+        assert_eq!(cues.next_redraw_time(), None);
+
+        // Wide games leave room for the whole text
+        for (mode, player_count) in [(Mode::Ring, 4), (Mode::Bottle, 3), (Mode::Traditional, 3)] {
+            let game = create_game(mode, player_count);
+            {
+                let mut render_data = client.render_data.lock().unwrap();
+                render(&game, &mut render_data, &client, None, &mut cues, clock);
+            }
+            let text = client.text();
+            assert!(
+                text.lines()
+                    .nth(7)
+                    .unwrap()
+                    .trim_end()
+                    .ends_with("Time: 01:23  Level: 2"),
+                "{:?}",
+                mode
+            );
+        }
+    }
+
+    #[test]
+    fn test_previews_are_only_shown_to_their_player() {
+        let game = create_game(Mode::Traditional, 2);
+        game.players[0].borrow_mut().next_block_queue[0] = FallingBlock::new(
+            BlockType::Bomb { initial_timer: 12 },
+            &mut rand::thread_rng(),
+        );
+        game.players[1].borrow_mut().block_in_hold = Some(FallingBlock::new(
+            BlockType::Bomb { initial_timer: 34 },
+            &mut rand::thread_rng(),
+        ));
+
+        let render_text = |client_id| {
+            let client = Client::new(
+                client_id,
+                Receiver::Test("".to_string()),
+                TerminalType::Ansi,
+            );
+            {
+                let mut render_data = client.render_data.lock().unwrap();
+                render(
+                    &game,
+                    &mut render_data,
+                    &client,
+                    None,
+                    &mut PlayerCues::default(),
+                    TEST_CLOCK,
+                );
+            }
+            client.text()
+        };
+
+        let text = render_text(0);
+        assert!(text.contains("1212"));
+        assert!(text.contains("Nothing in hold"));
+        assert!(!text.contains("3434"));
+
+        let text = render_text(1);
+        assert!(text.contains("3434"));
+        assert!(text.contains("Holding:"));
+        assert!(!text.contains("1212"));
+    }
+
+    #[test]
+    fn test_countdown_rendering() {
+        let mut game = create_game(Mode::Traditional, 2);
+        game.start_countdown(Instant::now());
+
+        for client_id in [0, 1] {
+            let client = Client::new(
+                client_id,
+                Receiver::Test("".to_string()),
+                TerminalType::VT52,
+            );
+            let mut cues = PlayerCues::default();
+            {
+                let mut render_data = client.render_data.lock().unwrap();
+                render(
+                    &game,
+                    &mut render_data,
+                    &client,
+                    None,
+                    &mut cues,
+                    TEST_CLOCK,
+                );
+            }
+            let text = client.text();
+            let digit: Vec<String> = text
+                .lines()
+                .skip(11)
+                .take(5)
+                .map(|line| line.chars().skip(18).take(6).collect())
+                .collect();
+            assert_eq!(digit, ["######", "##    ", "####  ", "    ##", "####  "]);
+            // Redrawn when the number changes
+            let redraw = cues.next_redraw_time().unwrap();
+            assert!(redraw <= Instant::now() + COUNTDOWN_DURATION);
+        }
+    }
+
+    #[test]
+    fn test_expired_flashing_points_not_rendered() {
+        let mut game = create_game(Mode::Traditional, 1);
+        let start = Instant::now();
+        game.set_flashing_point((3, 10), Color::RED_BACKGROUND.bg, start);
+
+        let client = Client::new(0, Receiver::Test("".to_string()), TerminalType::VT52);
+        let render_text = |game: &Game| {
+            {
+                let mut render_data = client.render_data.lock().unwrap();
+                render(
+                    game,
+                    &mut render_data,
+                    &client,
+                    None,
+                    &mut PlayerCues::default(),
+                    TEST_CLOCK,
+                );
+            }
+            client.text()
+        };
+
+        assert!(render_text(&game).contains("XX"));
+        game.prune_expired_flashing_points(start + FLASHING_POINT_EXPIRY);
+        assert!(!render_text(&game).contains("XX"));
+    }
+
+    #[test]
+    fn test_reduced_flashing() {
+        let mut game = create_game(Mode::Traditional, 1);
+        let points = [(3, 10), (4, 10), (5, 10)];
+        let not_flashing = (' ', Color::DEFAULT, ' ', Color::DEFAULT);
+
+        // Cleared rows flash white, bombs flash red
+        for flash_bg in [Color::WHITE_BACKGROUND.bg, Color::RED_BACKGROUND.bg] {
+            for reduced_flashing in [false, true] {
+                for terminal_type in [TerminalType::Ansi, TerminalType::VT52] {
+                    let mut client = Client::new(0, Receiver::Test("".to_string()), terminal_type);
+                    client.reduced_flashing = reduced_flashing;
+                    let mut cues = PlayerCues::default();
+
+                    // Like what flash() in game_wrapper.rs does. Each frame is rendered twice,
+                    // because other things happening in the game also cause renders.
+                    let start = Instant::now();
+                    let mut frames = vec![];
+                    for (i, bg) in [flash_bg, 0, flash_bg, 0].into_iter().enumerate() {
+                        for p in points {
+                            game.set_flashing_point(
+                                p,
+                                bg,
+                                start + Duration::from_millis(100) * i as u32,
+                            );
+                        }
+                        for _ in 0..2 {
+                            let mut render_data = client.render_data.lock().unwrap();
+                            render(
+                                &game,
+                                &mut render_data,
+                                &client,
+                                None,
+                                &mut cues,
+                                TEST_CLOCK,
+                            );
+                            let buffer = &render_data.buffer;
+                            // Game area starts at x=1 and y=2, and each square is 2 characters wide
+                            frames.push((
+                                buffer.get_char(7, 12),
+                                buffer.get_color(7, 12),
+                                buffer.get_char(11, 12),
+                                buffer.get_color(11, 12),
+                            ));
+                        }
+                    }
+
+                    assert_eq!(frames[0], frames[1]);
+                    assert_ne!(frames[0], not_flashing);
+                    if !reduced_flashing {
+                        assert_ne!(frames[0], frames[2]);
+                        assert_eq!(frames[0], frames[4]);
+                    } else if flash_bg == Color::RED_BACKGROUND.bg {
+                        assert!(frames.iter().all(|f| *f == frames[0]), "{:?}", frames);
+                    } else {
+                        assert!(
+                            frames[2..].iter().all(|f| *f == not_flashing),
+                            "{:?}",
+                            frames
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_ownership_tint() {
+        let mut game = create_game(Mode::Traditional, 2);
+        game.players[1].borrow_mut().color = Color::GREEN_FOREGROUND.fg;
+        let bottom_y = game.get_height() as i16 - 1;
+        for (x, owner, color) in [
+            (0, Some(0), Color::YELLOW_BACKGROUND),
+            (1, Some(1), Color::YELLOW_BACKGROUND),
+            (2, None, Color::YELLOW_BACKGROUND),
+            (3, Some(1), Color::BLUE_BACKGROUND),
+        ] {
+            let content = SquareContent::Normal([(' ', color); 2], owner, false);
+            game.set_landed_square((x, bottom_y), Some(content));
+        }
+
+        // Colors of the markers from left to right
+        let markers = |game: &Game, terminal_type: TerminalType| -> Vec<Color> {
+            let client = Client::new(0, Receiver::Test("".to_string()), terminal_type);
+            let mut render_data = client.render_data.lock().unwrap();
+            let mut cues = PlayerCues::default();
+            render(game, &mut render_data, &client, None, &mut cues, TEST_CLOCK);
+            let buffer = &render_data.buffer;
+            let mut result = vec![];
+            for y in 0..buffer.height {
+                for x in 0..buffer.width {
+                    if buffer.get_char(x, y) == '·' {
+                        result.push(buffer.get_color(x, y));
+                    }
+                }
+            }
+            result
+        };
+
+        assert_eq!(markers(&game, TerminalType::Ansi), vec![]);
+        game.set_ownership_tint(true);
+        assert_eq!(
+            markers(&game, TerminalType::Ansi),
+            vec![Color::new(31, 43), Color::new(32, 43), Color::new(32, 44),]
+        );
+        assert_eq!(markers(&game, TerminalType::VT52), vec![]);
+
+        // Squares of players who left don't get a marker
+        game.remove_player_if_exists(1, LeaveReason::Quit);
+        assert_eq!(markers(&game, TerminalType::Ansi), vec![Color::new(31, 43)]);
+    }
+
+    #[test]
+    fn test_departure_message() {
+        let mut game = create_game(Mode::Traditional, 3);
+        let clients =
+            [0, 2].map(|id| Client::new(id, Receiver::Test("".to_string()), TerminalType::Ansi));
+        let mut all_cues = [PlayerCues::default(), PlayerCues::default()];
+        let render_top_rows = |game: &Game, all_cues: &mut [PlayerCues; 2]| {
+            clients
+                .iter()
+                .zip(all_cues.iter_mut())
+                .map(|(client, cues)| {
+                    let mut render_data = client.render_data.lock().unwrap();
+                    render(game, &mut render_data, client, None, cues, TEST_CLOCK);
+                    let buffer = &render_data.buffer;
+                    (0..buffer.width)
+                        .map(|x| buffer.get_char(x, 0))
+                        .collect::<String>()
+                        .trim()
+                        .to_string()
+                })
+                .collect::<Vec<String>>()
+        };
+        let names = "Player 0      Player 1      Player 2";
+        assert_eq!(render_top_rows(&game, &mut all_cues), [names, names]);
+
+        game.remove_player_if_exists(1, LeaveReason::Disconnected);
+        let message = "Player 1 disconnected - the board shrank";
+        assert_eq!(render_top_rows(&game, &mut all_cues), [message, message]);
+        for cues in &all_cues {
+            assert!(cues.next_redraw_time().unwrap() <= cues.departure_shown_until.unwrap());
+        }
+
+        for cues in &mut all_cues {
+            cues.departure_shown_until = Some(Instant::now());
+        }
+        let names = "Player 0            Player 2";
+        assert_eq!(render_top_rows(&game, &mut all_cues), [names, names]);
+    }
+
+    #[test]
+    fn test_motd_banner_dimmed_during_games() {
+        let game = create_game(Mode::Traditional, 1);
+        let client = Client::new(0, Receiver::Test("".to_string()), TerminalType::Ansi);
+        let mut render_data = client.render_data.lock().unwrap();
+        render_data.set_banner("motd", Some("Restarting at 20:00".to_string()));
+        render_data.set_banner("admin", Some("Restarting soon".to_string()));
+        render(
+            &game,
+            &mut render_data,
+            &client,
+            None,
+            &mut PlayerCues::default(),
+            TEST_CLOCK,
+        );
+
+        let mut buffer = RenderBuffer::new(TerminalType::Ansi);
+        render_data.buffer.copy_into(&mut buffer);
+        render_data.add_banners(&mut buffer);
+        assert_eq!(buffer.get_char(0, 0), ' ');
+        assert_eq!(buffer.get_color(0, 0), Color::DIM_BLACK_ON_WHITE);
+        assert_eq!(buffer.get_color(40, 0), Color::DIM_BLACK_ON_WHITE);
+        // Other banners are still shown at full brightness
+        assert_eq!(buffer.get_color(40, 1), Color::BLACK_ON_WHITE);
+
+        // Not dimmed in menus
+        render_data.clear(80, 24);
+        render_data.buffer.copy_into(&mut buffer);
+        render_data.add_banners(&mut buffer);
+        assert_eq!(buffer.get_color(40, 0), Color::BLACK_ON_WHITE);
+    }
+
+    #[test]
+    fn test_ring_clear_breakdown() {
+        let mut game = create_game(Mode::Ring, 2);
+        let client = Client::new(0, Receiver::Test("".to_string()), TerminalType::Ansi);
+        let mut cues = PlayerCues::default();
+        let render_side_rows = |game: &Game, cues: &mut PlayerCues| {
+            let mut render_data = client.render_data.lock().unwrap();
+            render(game, &mut render_data, &client, None, cues, TEST_CLOCK);
+            let buffer = &render_data.buffer;
+            [25, 26, 27].map(|y| {
+                (78..buffer.width)
+                    .map(|x| buffer.get_char(x, y))
+                    .collect::<String>()
+                    .trim_end()
+                    .to_string()
+            })
+        };
+        assert_eq!(render_side_rows(&game, &mut cues), ["", "", ""]);
+
+        for (i, point) in ring_points(5).iter().enumerate() {
+            let client_id = if i < 3 { 1 } else { 0 };
+            let content =
+                SquareContent::Normal([(' ', Color::RED_BACKGROUND); 2], Some(client_id), false);
+            game.set_landed_square(*point, Some(content));
+        }
+        game.find_full_rows_and_increment_score();
+        assert_eq!(
+            render_side_rows(&game, &mut cues),
+            ["Ring cleared:", "  Player 0 37", "  Player 1 3"]
+        );
+        assert!(cues.next_redraw_time().unwrap() <= cues.ring_clear_shown_until.unwrap());
+
+        cues.ring_clear_shown_until = Some(Instant::now());
+        assert_eq!(render_side_rows(&game, &mut cues), ["", "", ""]);
+    }
+
+    #[tokio::test]
+    async fn test_bell_rings_for_own_landed_blocks() {
+        let mut game = create_game(Mode::Traditional, 2);
+        // Only the first player's blocks move down
+        game.players[1].borrow_mut().fast_down = true;
+
+        let clients =
+            [0, 1].map(|id| Client::new(id, Receiver::Test("".to_string()), TerminalType::Ansi));
+        let queues = [(); 2].map(|_| Arc::new(FrameQueue::new()));
+        for (client, queue) in clients.iter().zip(&queues) {
+            let render_data = client.render_data.clone();
+            let queue = queue.clone();
+            tokio::spawn(async move {
+                crate::handle_sending(
+                    &queue,
+                    &mut FrameStats::default(),
+                    render_data,
+                    TerminalType::Ansi,
+                )
+                .await
+            });
+        }
+
+        let mut cues = [PlayerCues::default(), PlayerCues::default()];
+        let mut bell_counts = [0, 0];
+        // Enough moves for a few blocks to fall all the way down
+        for _ in 0..(3 * game.get_height()) {
+            game.move_blocks_down(false);
+            for i in 0..2 {
+                {
+                    let mut render_data = clients[i].render_data.lock().unwrap();
+                    render(
+                        &game,
+                        &mut render_data,
+                        &clients[i],
+                        Some("ABCDEF"),
+                        &mut cues[i],
+                        TEST_CLOCK,
+                    );
+                    render_data.mark_changed();
+                }
+                let frame = timeout(Duration::from_secs(1), queues[i].pop_frame())
+                    .await
+                    .unwrap();
+                bell_counts[i] += frame.iter().filter(|byte| **byte == b'\x07').count();
+            }
+        }
+
+        let landed_count = game.players[0].borrow().landed_count;
+        assert!(landed_count >= 2);
+        assert_eq!(bell_counts, [landed_count, 0]);
+    }
+
+    #[test]
+    fn test_ring_marker_blinking() {
+        let mut game = create_game(Mode::Ring, 2);
+        let client = Client::new(0, Receiver::Test("".to_string()), TerminalType::Ansi);
+        let mut cues = PlayerCues::default();
+        // The other player is on the opposite side, so their marker is at the bottom
+        let marker_x = RING_MAP[0].len() / 2;
+        let marker_y = RING_MAP.len() - 1;
+        let normal_color = Color::new(31, 0);
+        let blink_color = Color::new(30, 41);
+
+        let get_marker = |game: &Game, cues: &mut PlayerCues| {
+            let mut render_data = client.render_data.lock().unwrap();
+            render(
+                game,
+                &mut render_data,
+                &client,
+                Some("ABCDEF"),
+                cues,
+                TEST_CLOCK,
+            );
+            (
+                render_data.buffer.get_char(marker_x, marker_y),
+                render_data.buffer.get_color(marker_x, marker_y),
+            )
+        };
+
+        assert_eq!(get_marker(&game, &mut cues), ('P', normal_color));
+        assert_eq!(cues.next_redraw_time(), None);
+
+        game.players[1].borrow_mut().block_or_timer = BlockOrTimer::Timer(3);
+        assert_eq!(get_marker(&game, &mut cues), ('P', blink_color));
+        assert_eq!(get_marker(&game, &mut cues), ('P', blink_color));
+        assert!(cues.next_redraw_time().is_some());
+        cues.next_marker_blink = Some(Instant::now());
+        assert_eq!(get_marker(&game, &mut cues), ('P', normal_color));
+
+        // Blocks landed close to where the other player's blocks spawn
+        game.players[1].borrow_mut().block_or_timer =
+            BlockOrTimer::Block(FallingBlock::normal_from_shape(Shape::L));
+        let point = game.players[1].borrow().player_to_world((0, -16));
+        game.set_landed_square(
+            point,
+            Some(SquareContent::with_color(Color::YELLOW_FOREGROUND)),
+        );
+        cues.next_marker_blink = Some(Instant::now());
+        assert_eq!(get_marker(&game, &mut cues), ('P', blink_color));
+
+        game.set_landed_square(point, None);
+        assert_eq!(get_marker(&game, &mut cues), ('P', normal_color));
+        assert_eq!(cues.next_redraw_time(), None);
+    }
+
+    #[test]
+    fn test_sparkline_scaling() {
+        assert_eq!(sparkline(&[], SPARKLINE_CHARS), "");
+        assert_eq!(sparkline(&[0, 0, 0], SPARKLINE_CHARS), "▁▁▁");
+        assert_eq!(sparkline(&[0, 0, 0], SPARKLINE_CHARS_ASCII), "...");
+        assert_eq!(sparkline(&[0, 10, 20, 30], SPARKLINE_CHARS), "▁▃▅▇");
+        assert_eq!(sparkline(&[0, 10, 10, 100000], SPARKLINE_CHARS), "▁▁▁▇");
+        assert_eq!(sparkline(&[5, 5], SPARKLINE_CHARS_ASCII), "**");
+    }
+
+    fn render_score_history(terminal_type: TerminalType) -> String {
+        let mut game = create_game(Mode::Traditional, 1);
+        game.sample_score();
+        let bottom_y = (game.get_height() - 1) as i16;
+        for x in 0..(game.get_width() as i16) {
+            game.set_landed_square(
+                (x, bottom_y),
+                Some(SquareContent::with_color(Color::RED_BACKGROUND)),
+            );
+        }
+        game.find_full_rows_and_increment_score();
+        game.sample_score();
+        game.sample_score();
+
+        let client = Client::new(0, Receiver::Test("".to_string()), terminal_type);
+        {
+            let mut render_data = client.render_data.lock().unwrap();
+            render(
+                &game,
+                &mut render_data,
+                &client,
+                None,
+                &mut PlayerCues::default(),
+                TEST_CLOCK,
+            );
+        }
+        let text = client.text();
+        // Text to the right of the game
+        let line = text.lines().nth(6).unwrap();
+        line.rsplit('|').next().unwrap().trim().to_string()
+    }
+
+    #[test]
+    fn test_score_history_rendering() {
+        assert_eq!(render_score_history(TerminalType::Ansi), "▁▇▇");
+        assert_eq!(render_score_history(TerminalType::VT52), ".**");
+    }
+
+    #[test]
+    fn test_score_flash() {
+        let mut game = create_game(Mode::Traditional, 2);
+        let client = Client::new(0, Receiver::Test("".to_string()), TerminalType::Ansi);
+        let mut cues = PlayerCues::default();
+        let score_x = 2 * game.get_width() + 4;
+
+        let render_score = |game: &Game, cues: &mut PlayerCues| {
+            let color = {
+                let mut render_data = client.render_data.lock().unwrap();
+                render(game, &mut render_data, &client, None, cues, TEST_CLOCK);
+                render_data.buffer.get_color(score_x, 5)
+            };
+            (
+                color,
+                client
+                    .text()
+                    .lines()
+                    .nth(5)
+                    .unwrap()
+                    .rsplit('|')
+                    .next()
+                    .unwrap()
+                    .trim()
+                    .to_string(),
+            )
+        };
+
+        assert_eq!(
+            render_score(&game, &mut cues),
+            (SCORE_TEXT_COLOR, "Score: 0 (×2)".to_string())
+        );
+        assert_eq!(cues.next_redraw_time(), None);
+
+        let bottom_y = (game.get_height() - 1) as i16;
+        for x in 0..(game.get_width() as i16) {
+            game.set_landed_square(
+                (x, bottom_y),
+                Some(SquareContent::with_color(Color::RED_BACKGROUND)),
+            );
+        }
+        game.find_full_rows_and_increment_score();
+        let score = format!("Score: {} (×2)", game.get_score());
+        assert_eq!(
+            render_score(&game, &mut cues),
+            (SCORE_FLASH_COLOR, score.clone())
+        );
+        assert!(cues.next_redraw_time().is_some());
+        assert_eq!(
+            render_score(&game, &mut cues),
+            (SCORE_FLASH_COLOR, score.clone())
+        );
+        assert_eq!(render_score(&game, &mut cues), (SCORE_TEXT_COLOR, score));
+        assert_eq!(cues.next_redraw_time(), None);
+    }
+
+    #[test]
+    fn test_rescue_offer_rendering() {
+        let game = create_game(Mode::Traditional, 3);
+        game.players[1].borrow_mut().rescue = Rescue::Offered;
+
+        let render_text = |client_id| {
+            let client = Client::new(
+                client_id,
+                Receiver::Test("".to_string()),
+                TerminalType::Ansi,
+            );
+            {
+                let mut render_data = client.render_data.lock().unwrap();
+                render(
+                    &game,
+                    &mut render_data,
+                    &client,
+                    None,
+                    &mut PlayerCues::default(),
+                    TEST_CLOCK,
+                );
+            }
+            client.text()
+        };
+
+        assert!(!render_text(0).contains("[Y]es"));
+        let text = render_text(1);
+        // Second player's columns start at x = 1 + 2*7
+        let overlay: Vec<String> = text
+            .lines()
+            .skip(3)
+            .take(6)
+            .map(|line| line.chars().skip(15).take(14).collect())
+            .collect();
+        assert_eq!(
+            overlay,
+            [
+                "              ",
+                " Clear top 4  ",
+                " rows for 50  ",
+                "   points?    ",
+                " [Y]es  [N]o  ",
+                "              ",
+            ]
+        );
+    }
+
+    fn render_colors_of_bottom_row(game: &Game) -> (Color, Color) {
+        let client = Client::new(0, Receiver::Test("".to_string()), TerminalType::Ansi);
+        let mut render_data = client.render_data.lock().unwrap();
+        render(
+            game,
+            &mut render_data,
+            &client,
+            None,
+            &mut PlayerCues::default(),
+            TEST_CLOCK,
+        );
+        let y = 2 + game.get_height() - 1;
+        (
+            render_data.buffer.get_color(1 + 2 * 3, y),
+            render_data.buffer.get_color(1 + 2 * 10, y),
+        )
+    }
+
+    #[test]
+    fn test_danger_tint() {
+        let mut game = create_game(Mode::Traditional, 3);
+        assert_eq!(
+            render_colors_of_bottom_row(&game),
+            (Color::DEFAULT, Color::DEFAULT)
+        );
+
+        // Stack of the second player is close to where blocks spawn
+        let content = Some(SquareContent::with_color(Color::RED_BACKGROUND));
+        game.set_landed_square((8, DANGER_ROWS as i16), content);
+        assert_eq!(
+            render_colors_of_bottom_row(&game),
+            (Color::DEFAULT, Color::DIM_RED_BACKGROUND)
+        );
+        assert_eq!(
+            TerminalType::Ansi.format_color(Color::DIM_RED_BACKGROUND),
+            "\x1b[0m\x1b[48;5;52m"
+        );
+        assert_eq!(
+            TerminalType::VT52.format_color(Color::DIM_RED_BACKGROUND),
+            ""
+        );
+
+        game.set_landed_square((8, DANGER_ROWS as i16), None);
+        game.set_landed_square((8, DANGER_ROWS as i16 + 1), content);
+        assert_eq!(
+            render_colors_of_bottom_row(&game),
+            (Color::DEFAULT, Color::DEFAULT)
+        );
+    }
+
+    // Renders a game that doesn't depend on randomness or time and compares it to
+    // tests/goldens/<name>.txt. Run with CATRIS_UPDATE_GOLDENS=1 to write the files.
+    // Without colors, blocks are drawn with characters, so they show up in the text.
+    fn check_golden(name: &str, mode: Mode, player_count: usize) {
+        let mut game = Game::new(mode);
+        game.set_block_seed(123, false);
+        game.set_special_blocks_enabled(false);
+        game.set_normal_block_factory(|_| FallingBlock::normal_from_shape(Shape::L));
+        // Different lengths, so that the goldens show how names are cut and centered
+        for (client_id, name) in (0..)
+            .zip(["Alice", "Bob", "Charlie", "Dave"])
+            .take(player_count)
+        {
+            game.add_player(&PlayerSpec {
+                name: name.to_string(),
+                ..player_spec(client_id)
+            });
+        }
+        // Land some blocks and put one in hold, so that the goldens aren't just walls
+        for _ in 0..40 {
+            game.move_blocks_down(false);
+        }
+        for client_id in 0..(player_count as u64) {
+            game.handle_key_press(client_id, false, true, KeyPress::Character('h'));
+        }
+
+        let client = Client::new(0, Receiver::Test("".to_string()), TerminalType::VT52);
+        {
+            let mut render_data = client.render_data.lock().unwrap();
+            render(
+                &game,
+                &mut render_data,
+                &client,
+                Some("ABCDEF"),
+                &mut PlayerCues::default(),
+                TEST_CLOCK,
+            );
+        }
+        let actual: String = client
+            .text()
+            .lines()
+            .map(|line| line.trim_end().to_string() + "\n")
+            .collect();
+
+        let path = format!("{}/tests/goldens/{}.txt", env!("CARGO_MANIFEST_DIR"), name);
+        if std::env::var("CATRIS_UPDATE_GOLDENS").is_ok() {
+            std::fs::write(&path, &actual).unwrap();
+        }
+        let expected = std::fs::read_to_string(&path).unwrap();
+        assert!(
+            actual == expected,
+            "{} doesn't match, got:\n{}",
+            path,
+            actual
+        );
+    }
+
+    #[test]
+    fn test_golden_traditional() {
+        check_golden("traditional", Mode::Traditional, 2);
+    }
+
+    #[test]
+    fn test_golden_zen() {
+        check_golden("zen", Mode::Zen, 1);
+    }
+
+    #[test]
+    fn test_golden_bottle() {
+        check_golden("bottle", Mode::Bottle, 3);
+    }
+
+    #[test]
+    fn test_golden_ring() {
+        check_golden("ring", Mode::Ring, 4);
+    }
 }
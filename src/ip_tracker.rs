@@ -6,11 +6,51 @@ use std::io::ErrorKind;
 use std::net::IpAddr;
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::sync::OnceLock;
+use std::time::Duration;
 use std::time::Instant;
 
+const DEFAULT_MAX_CONNECTIONS_PER_IP: usize = 5;
+
+// Set from command line arguments
+pub static MAX_CONNECTIONS_PER_IP: OnceLock<usize> = OnceLock::new();
+
+/*
+The prompts allow one lobby ID or player name per second, but that doesn't
+help if someone opens many connections and guesses with all of them. So
+failed attempts to join a lobby are also counted for each IP address, and
+too many of them blocks joining lobbies for a while.
+*/
+pub const MAX_FAILED_LOBBY_ATTEMPTS: usize = 20;
+const FAILED_LOBBY_ATTEMPT_WINDOW: Duration = Duration::from_secs(10 * 60);
+const LOBBY_ATTEMPT_COOLDOWN: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Default)]
+struct FailedLobbyAttempts {
+    times: VecDeque<Instant>, // within FAILED_LOBBY_ATTEMPT_WINDOW, oldest first
+    blocked_until: Option<Instant>,
+}
+
+impl FailedLobbyAttempts {
+    fn forget_expired(&mut self, now: Instant) {
+        while matches!(self.times.front(), Some(t) if now.duration_since(*t) > FAILED_LOBBY_ATTEMPT_WINDOW)
+        {
+            self.times.pop_front();
+        }
+        if matches!(self.blocked_until, Some(end) if end <= now) {
+            self.blocked_until = None;
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.times.is_empty() && self.blocked_until.is_none()
+    }
+}
+
 pub struct IpTracker {
     recent_ips: VecDeque<(Instant, IpAddr)>,
     client_counts_by_ip: HashMap<IpAddr, usize>,
+    failed_lobby_attempts: HashMap<IpAddr, FailedLobbyAttempts>,
 }
 
 pub struct ForgetClientOnDrop {
@@ -18,6 +58,13 @@ pub struct ForgetClientOnDrop {
     ip: IpAddr,
     ip_tracker: Arc<Mutex<IpTracker>>,
 }
+impl ForgetClientOnDrop {
+    // With a proxy, this is the IP from the X-Real-IP header, not the proxy's IP
+    pub fn ip(&self) -> IpAddr {
+        self.ip
+    }
+}
+
 impl Drop for ForgetClientOnDrop {
     fn drop(&mut self) {
         let mut tracker = self.ip_tracker.lock().unwrap();
@@ -43,6 +90,35 @@ impl IpTracker {
         Self {
             recent_ips: VecDeque::new(),
             client_counts_by_ip: HashMap::new(),
+            failed_lobby_attempts: HashMap::new(),
+        }
+    }
+
+    fn forget_expired_lobby_attempts(&mut self, now: Instant) {
+        self.failed_lobby_attempts.retain(|_, attempts| {
+            attempts.forget_expired(now);
+            !attempts.is_empty()
+        });
+    }
+
+    pub fn lobby_attempts_blocked(&mut self, ip: IpAddr, now: Instant) -> bool {
+        self.forget_expired_lobby_attempts(now);
+        self.failed_lobby_attempts
+            .get(&ip)
+            .is_some_and(|attempts| attempts.blocked_until.is_some())
+    }
+
+    // Returns true if this attempt used up the budget and the IP is now blocked
+    pub fn record_failed_lobby_attempt(&mut self, ip: IpAddr, now: Instant) -> bool {
+        self.forget_expired_lobby_attempts(now);
+        let attempts = self.failed_lobby_attempts.entry(ip).or_default();
+        attempts.times.push_back(now);
+        if attempts.times.len() >= MAX_FAILED_LOBBY_ATTEMPTS {
+            attempts.times.clear();
+            attempts.blocked_until = Some(now + LOBBY_ATTEMPT_COOLDOWN);
+            true
+        } else {
+            false
         }
     }
 
@@ -76,7 +152,11 @@ impl IpTracker {
             }
 
             let old_count = *tracker.client_counts_by_ip.get(&ip).unwrap_or(&0);
-            if old_count >= 5 {
+            let max = MAX_CONNECTIONS_PER_IP
+                .get()
+                .copied()
+                .unwrap_or(DEFAULT_MAX_CONNECTIONS_PER_IP);
+            if old_count >= max {
                 return Err(io::Error::new(
                     ErrorKind::ConnectionAborted,
                     format!(
@@ -101,3 +181,67 @@ impl IpTracker {
         })
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::from([10, 0, 0, last])
+    }
+
+    #[test]
+    fn test_failed_lobby_attempt_budget() {
+        let mut tracker = IpTracker::new();
+        let start = Instant::now();
+
+        for i in 0..(MAX_FAILED_LOBBY_ATTEMPTS - 1) {
+            let now = start + Duration::from_secs(i as u64);
+            assert!(!tracker.lobby_attempts_blocked(ip(1), now));
+            assert!(!tracker.record_failed_lobby_attempt(ip(1), now));
+        }
+        let now = start + Duration::from_secs(60);
+        assert!(tracker.record_failed_lobby_attempt(ip(1), now));
+        assert!(tracker.lobby_attempts_blocked(ip(1), now));
+
+        // Other IPs are not affected
+        assert!(!tracker.lobby_attempts_blocked(ip(2), now));
+    }
+
+    #[test]
+    fn test_lobby_attempt_cooldown_expires() {
+        let mut tracker = IpTracker::new();
+        let start = Instant::now();
+        for _ in 0..MAX_FAILED_LOBBY_ATTEMPTS {
+            tracker.record_failed_lobby_attempt(ip(1), start);
+        }
+
+        let almost = start + LOBBY_ATTEMPT_COOLDOWN - Duration::from_secs(1);
+        assert!(tracker.lobby_attempts_blocked(ip(1), almost));
+        let after = start + LOBBY_ATTEMPT_COOLDOWN;
+        assert!(!tracker.lobby_attempts_blocked(ip(1), after));
+
+        // The budget starts from scratch, and nothing is left in memory
+        assert!(tracker.failed_lobby_attempts.is_empty());
+        assert!(!tracker.record_failed_lobby_attempt(ip(1), after));
+        assert!(!tracker.lobby_attempts_blocked(ip(1), after));
+    }
+
+    #[test]
+    fn test_occasional_wrong_lobby_ids_are_fine() {
+        let mut tracker = IpTracker::new();
+        let start = Instant::now();
+
+        // A few typos every now and then, for a long time
+        for minute in 0..120 {
+            let now = start + Duration::from_secs(60 * minute);
+            assert!(!tracker.lobby_attempts_blocked(ip(1), now));
+            assert!(!tracker.record_failed_lobby_attempt(ip(1), now));
+        }
+
+        // Old failures expire
+        let later = start + Duration::from_secs(60 * 119) + FAILED_LOBBY_ATTEMPT_WINDOW * 2;
+        assert!(!tracker.lobby_attempts_blocked(ip(1), later));
+        assert!(tracker.failed_lobby_attempts.is_empty());
+    }
+}
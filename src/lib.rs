@@ -0,0 +1,42 @@
+/*!
+The game rules of catris, without the server, terminal handling or async code.
+
+This lets other programs (e.g. a desktop frontend) run games. Here's how to
+play a game without displaying anything:
+
+```
+use catris::escapes::KeyPress;
+use catris::game_logic::game::Game;
+use catris::game_logic::game::Mode;
+use catris::game_logic::PlayerSpec;
+
+let mut game = Game::new(Mode::Traditional);
+// Same blocks every time, so that the last row below is always the same
+game.set_block_seed(1, false);
+let ok = game.add_player(&PlayerSpec {
+    client_id: 123,
+    lobby_order: 0,
+    name: "Alice".to_string(),
+    color: 31, // ANSI color number, red
+    handicap: 0,
+});
+assert!(ok);
+
+game.handle_key_press(123, false, true, KeyPress::Left);
+game.handle_key_press(123, false, true, KeyPress::Up); // rotate
+
+// The server calls move_blocks_down() repeatedly with a timer
+for _ in 0..game.get_height() {
+    game.move_blocks_down(false);
+    let full_rows = game.find_full_rows_and_increment_score();
+    game.remove_full_rows(&full_rows);
+}
+
+// One string per row, with 2 characters for each square ("LL" = landed)
+let rows = game.debug_dump();
+assert_eq!(rows.len(), game.get_height());
+assert!(rows.last().unwrap().contains("LL"));
+```
+*/
+pub mod escapes;
+pub mod game_logic;
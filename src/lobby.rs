@@ -1,52 +1,217 @@
 use crate::client::log_for_client;
+use crate::game_logic::blocks::CursedBlocks;
+use crate::game_logic::board::BoardOptions;
+use crate::game_logic::bottle::BottleNecks;
 use crate::game_logic::game::Game;
+use crate::game_logic::game::LeaveReason;
 use crate::game_logic::game::Mode;
+use crate::game_logic::game::MAX_PLAYERS;
+use crate::game_logic::PlayerSpec;
 use crate::game_wrapper;
+use crate::game_wrapper::GameStatus;
 use crate::game_wrapper::GameWrapper;
 use rand::Rng;
 use std::collections::HashMap;
+use std::net::IpAddr;
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::sync::OnceLock;
 use std::sync::Weak;
+use std::time::Duration;
+use std::time::Instant;
 use tokio::sync::watch;
 use weak_table::WeakValueHashMap;
 
+// Why a client can't join a game
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinError {
+    Full,
+    IpRestricted,     // see Lobby::one_player_per_ip
+    NotAllowed(Mode), // a game of this mode must end first, see Lobby::allow_parallel_games
+}
+
 pub struct ClientInfo {
     pub client_id: u64,
+    // Games sort their players by this, so that they appear in the same order as in the lobby
+    pub lobby_order: u64,
     pub name: String,
     pub color: u8,
+    pub handicap: u8, // number of garbage rows given to the player in traditional games
+    pub ip: Option<IpAddr>, // local players have the same IP as the client they play with
+}
+
+impl ClientInfo {
+    pub fn player_spec(&self) -> PlayerSpec {
+        PlayerSpec {
+            client_id: self.client_id,
+            lobby_order: self.lobby_order,
+            name: self.name.clone(),
+            color: self.color,
+            handicap: self.handicap,
+        }
+    }
 }
 
 pub struct Lobby {
     pub id: String,
     pub clients: Vec<ClientInfo>,
+    // second players sharing a connection with a client, see local_player_id()
+    pub local_players: Vec<ClientInfo>,
     // change triggers when people join/leave the lobby or a game.
     // Lobby UI shows how many players are in each game, that must refresh
     changed_sender: watch::Sender<()>,
     pub changed_receiver: watch::Receiver<()>,
     // games get deleted when players leave them
     game_wrappers: HashMap<Mode, Arc<GameWrapper>>,
+    // bottle games with 4 or more players get two separate basins at the bottom
+    pub two_bottle_basins: bool,
+    // where personal areas of bottle games end, see BottleNecks::personal_space_height()
+    pub bottle_necks: BottleNecks,
+    // if true, the lobby can be joined only with its ID, not by typing a player's name
+    pub unlisted_for_name_search: bool,
+    // new games get their blocks from this seed, so that people in different lobbies can race
+    pub block_seed: Option<u64>,
+    pub shared_block_sequence: bool, // same blocks for all players of a seeded game
+    // if false, only one game can be played at a time, so that people don't get split up
+    pub allow_parallel_games: bool,
+    // if true, people can't fill a game with many connections and leave no room for others
+    pub one_player_per_ip: bool,
+    pub cursed_blocks: CursedBlocks,
+    // normal blocks scaled 2x, without special blocks
+    pub big_blocks: bool,
+    pub ownership_tint: bool,
+    pub pause_requires_majority: bool,
+    // blocks landing near the top survive one bomb explosion, see Game::set_reinforced_squares()
+    pub reinforced_squares: bool,
+    // ring mode blocks that fall for a long time cost points, see Game::set_stall_decay()
+    pub stall_decay: bool,
+    // new blocks appear one tick after the previous block is gone, see Game::set_spawn_delay()
+    pub spawn_delay: bool,
+    // players of a game that just ended can start a new game of the same mode together
+    rematches: HashMap<Mode, Rematch>,
+    // how long players of an ended game have to decide whether to play again
+    pub rematch_timeout: Duration,
+    // clients waiting for a slot in a full game, see join_waitlist()
+    waitlists: HashMap<Mode, Waitlist>,
+    lobby_order_counter: u64,
+    // everyone left a game before it ended, e.g. because their wifi broke
+    game_interrupted: bool,
 }
 
-pub const MAX_CLIENTS_PER_LOBBY: usize = 6;
+// Set from command line arguments, see leave_lobby()
+pub static LOBBY_GRACE_PERIOD: OnceLock<Duration> = OnceLock::new();
+const DEFAULT_LOBBY_GRACE_PERIOD: Duration = Duration::from_secs(2 * 60);
+
+fn lobby_grace_period() -> Duration {
+    LOBBY_GRACE_PERIOD
+        .get()
+        .copied()
+        .unwrap_or(DEFAULT_LOBBY_GRACE_PERIOD)
+}
+
+const REMATCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Where players of a game that ended meet if they want to play again
+struct Rematch {
+    game: Weak<GameWrapper>, // the game that ended
+    client_ids: Vec<u64>,    // everyone who played it, without local players
+    accepted: Vec<u64>,
+    declined: Vec<u64>,
+    deadline: Instant,
+}
+
+const WAITLIST_OFFER_TIMEOUT: Duration = Duration::from_secs(15);
+
+// Clients waiting for a full game. The first one who fits in gets to join, if they want.
+#[derive(Default)]
+struct Waitlist {
+    client_ids: Vec<u64>,          // first in line first
+    offer: Option<(u64, Instant)>, // client who can join now, and deadline for joining
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RematchStatus {
+    Unavailable,
+    Open {
+        accepted: usize,
+        total: usize,
+        deadline: Instant,
+    },
+    Start, // client chose to play again, and others had enough time to decide
+}
+
+pub const MAX_CLIENTS_PER_LOBBY: usize = MAX_PLAYERS;
+pub const MAX_HANDICAP: u8 = 5;
 const ALL_COLORS: [u8; MAX_CLIENTS_PER_LOBBY] = [31, 32, 33, 34, 35, 36];
 
+// Local players don't have a connection of their own, so they get a made-up client ID.
+// Real client IDs come from a counter and never get this big.
+pub fn local_player_id(client_id: u64) -> u64 {
+    client_id | (1 << 63)
+}
+
+pub fn is_local_player_id(client_id: u64) -> bool {
+    client_id & (1 << 63) != 0
+}
+
+// Adds the client and its local player, or neither of them.
+// The lobby checks for room first, so this fails only if the game disagrees with it.
+fn add_players_to_game(
+    game: &mut Game,
+    client_info: &ClientInfo,
+    local_player_info: Option<&ClientInfo>,
+) -> Result<(), JoinError> {
+    let client_id = client_info.client_id;
+    if !game.add_player(&client_info.player_spec()) {
+        log_for_client(client_id, "Game is unexpectedly full");
+        return Err(JoinError::Full);
+    }
+    if let Some(info) = local_player_info {
+        if !game.add_player(&info.player_spec()) {
+            log_for_client(client_id, "Game is unexpectedly full for local player");
+            game.remove_player_silently(client_id);
+            return Err(JoinError::Full);
+        }
+    }
+    Ok(())
+}
+
 impl Lobby {
     pub fn new(id: &str) -> Lobby {
         let (sender, receiver) = watch::channel(());
         Lobby {
             id: id.to_string(),
             clients: vec![],
+            local_players: vec![],
             changed_sender: sender,
             changed_receiver: receiver,
             game_wrappers: HashMap::new(),
+            two_bottle_basins: false,
+            bottle_necks: BottleNecks::default(),
+            unlisted_for_name_search: false,
+            block_seed: None,
+            shared_block_sequence: false,
+            allow_parallel_games: true,
+            one_player_per_ip: false,
+            cursed_blocks: CursedBlocks::default(),
+            big_blocks: false,
+            ownership_tint: false,
+            pause_requires_majority: false,
+            reinforced_squares: false,
+            stall_decay: false,
+            spawn_delay: false,
+            rematches: HashMap::new(),
+            rematch_timeout: REMATCH_TIMEOUT,
+            waitlists: HashMap::new(),
+            lobby_order_counter: 0,
+            game_interrupted: false,
         }
     }
 
     pub fn get_player_count(&self, mode: Mode) -> usize {
         match self.game_wrappers.get(&mode) {
             Some(wrapper) => {
-                let n = wrapper.game.lock().unwrap().players.len();
+                let n = wrapper.lock_game().players.len();
                 assert!(n > 0);
                 n
             }
@@ -54,15 +219,92 @@ impl Lobby {
         }
     }
 
+    // Names and colors, so that the mode menu can show who is playing
+    pub fn get_player_names(&self, mode: Mode) -> Vec<(String, u8)> {
+        match self.game_wrappers.get(&mode) {
+            Some(wrapper) => wrapper
+                .lock_game()
+                .players
+                .iter()
+                .map(|p| (p.borrow().name.clone(), p.borrow().color))
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    pub fn get_game_wrapper(&self, mode: Mode) -> Option<Arc<GameWrapper>> {
+        self.game_wrappers.get(&mode).cloned()
+    }
+
+    // Returns the mode of a game that must end before a game of the given mode can start
+    pub fn get_blocking_game(&self, mode: Mode) -> Option<Mode> {
+        if self.allow_parallel_games {
+            return None;
+        }
+        Mode::ALL_MODES
+            .iter()
+            .copied()
+            .find(|other| *other != mode && self.game_wrappers.contains_key(other))
+    }
+
+    // A client's own local player doesn't count, because it was added on purpose
+    pub fn ip_already_in_game(&self, client_id: u64, mode: Mode) -> bool {
+        if !self.one_player_per_ip {
+            return false;
+        }
+        let ip = match self.clients.iter().find(|info| info.client_id == client_id) {
+            Some(ClientInfo { ip: Some(ip), .. }) => *ip,
+            _ => return false,
+        };
+        let wrapper = match self.game_wrappers.get(&mode) {
+            Some(wrapper) => wrapper,
+            None => return false,
+        };
+
+        let game = wrapper.lock_game();
+        game.players.iter().any(|player| {
+            let id = player.borrow().client_id;
+            id != client_id
+                && id != local_player_id(client_id)
+                && self
+                    .clients
+                    .iter()
+                    .chain(&self.local_players)
+                    .any(|info| info.client_id == id && info.ip == Some(ip))
+        })
+    }
+
+    // Local players need colors too
     pub fn lobby_is_full(&self) -> bool {
-        self.clients.len() == MAX_CLIENTS_PER_LOBBY
+        self.clients.len() + self.local_players.len() == MAX_CLIENTS_PER_LOBBY
+    }
+
+    fn get_unused_color(&self) -> u8 {
+        let used_colors: Vec<u8> = self
+            .clients
+            .iter()
+            .chain(&self.local_players)
+            .map(|c| c.color)
+            .collect();
+        *ALL_COLORS
+            .iter()
+            .find(|color| !used_colors.contains(*color))
+            .unwrap()
     }
 
     pub fn mark_changed(&self) {
         self.changed_sender.send(()).unwrap();
     }
 
-    pub fn add_client(&mut self, client_id: u64, name: &str) {
+    // Tests use this to see what happens when a view outlives the channel it's watching
+    #[cfg(test)]
+    pub fn close_changed_channel(&mut self) {
+        let (sender, receiver) = watch::channel(());
+        self.changed_sender = sender;
+        self.changed_receiver = receiver;
+    }
+
+    pub fn add_client(&mut self, client_id: u64, name: &str, ip: Option<IpAddr>) {
         log_for_client(
             client_id,
             &format!(
@@ -73,19 +315,153 @@ impl Lobby {
         );
 
         assert!(!self.lobby_is_full());
-        let used_colors: Vec<u8> = self.clients.iter().map(|c| c.color).collect();
-        let unused_color = *ALL_COLORS
-            .iter()
-            .find(|color| !used_colors.contains(*color))
-            .unwrap();
+        self.game_interrupted = false;
+        let color = self.get_unused_color();
+        // Leave room for a local player right after this client
+        self.lobby_order_counter += 2;
         self.clients.push(ClientInfo {
             client_id,
+            lobby_order: self.lobby_order_counter,
             name: name.to_string(),
-            color: unused_color,
+            color,
+            handicap: 0,
+            ip,
         });
         self.mark_changed();
     }
 
+    pub fn get_local_player(&self, client_id: u64) -> Option<&ClientInfo> {
+        let id = local_player_id(client_id);
+        self.local_players.iter().find(|info| info.client_id == id)
+    }
+
+    pub fn add_local_player(&mut self, client_id: u64, name: &str) {
+        log_for_client(client_id, &format!("Adding local player: {}", name));
+        assert!(!self.lobby_is_full());
+        assert!(self.get_local_player(client_id).is_none());
+        let color = self.get_unused_color();
+        let host = self
+            .clients
+            .iter()
+            .find(|info| info.client_id == client_id)
+            .unwrap();
+        let (host_order, host_ip) = (host.lobby_order, host.ip);
+        self.local_players.push(ClientInfo {
+            client_id: local_player_id(client_id),
+            lobby_order: host_order + 1,
+            name: name.to_string(),
+            color,
+            handicap: 0,
+            ip: host_ip,
+        });
+        self.mark_changed();
+    }
+
+    pub fn remove_local_player(&mut self, client_id: u64) {
+        let id = local_player_id(client_id);
+        if let Some(i) = self.local_players.iter().position(|c| c.client_id == id) {
+            log_for_client(client_id, "Removing local player");
+            self.local_players.remove(i);
+            // The client may now fit into a game that it's waiting for
+            self.update_waitlist_offers(Instant::now());
+            self.mark_changed();
+        }
+    }
+
+    // The client who has been in the lobby for the longest time decides handicaps and other settings
+    pub fn can_change_settings(&self, client_id: u64) -> bool {
+        self.clients[0].client_id == client_id
+    }
+
+    // Takes effect when the player joins a game
+    pub fn cycle_handicap(&mut self, client_index: usize) {
+        if let Some(info) = self.clients.get_mut(client_index) {
+            info.handicap = (info.handicap + 1) % (MAX_HANDICAP + 1);
+            self.mark_changed();
+        }
+    }
+
+    // Takes effect when a new bottle game starts
+    pub fn toggle_two_bottle_basins(&mut self) {
+        self.two_bottle_basins = !self.two_bottle_basins;
+        self.mark_changed();
+    }
+
+    // Takes effect when a new bottle game starts
+    pub fn cycle_bottle_necks(&mut self) {
+        self.bottle_necks = self.bottle_necks.next();
+        self.mark_changed();
+    }
+
+    pub fn toggle_unlisted_for_name_search(&mut self) {
+        self.unlisted_for_name_search = !self.unlisted_for_name_search;
+        self.mark_changed();
+    }
+
+    pub fn set_block_seed(&mut self, seed: Option<u64>) {
+        self.block_seed = seed;
+        self.mark_changed();
+    }
+
+    pub fn toggle_shared_block_sequence(&mut self) {
+        self.shared_block_sequence = !self.shared_block_sequence;
+        self.mark_changed();
+    }
+
+    // Doesn't affect games that are already running
+    pub fn toggle_allow_parallel_games(&mut self) {
+        self.allow_parallel_games = !self.allow_parallel_games;
+        self.mark_changed();
+    }
+
+    // Takes effect when a new game starts
+    pub fn cycle_cursed_blocks(&mut self) {
+        self.cursed_blocks = self.cursed_blocks.next();
+        self.mark_changed();
+    }
+
+    // Takes effect when a new game starts
+    pub fn toggle_big_blocks(&mut self) {
+        self.big_blocks = !self.big_blocks;
+        self.mark_changed();
+    }
+
+    // Takes effect when a new game starts
+    pub fn toggle_ownership_tint(&mut self) {
+        self.ownership_tint = !self.ownership_tint;
+        self.mark_changed();
+    }
+
+    // Takes effect when a new game starts
+    pub fn toggle_reinforced_squares(&mut self) {
+        self.reinforced_squares = !self.reinforced_squares;
+        self.mark_changed();
+    }
+
+    // Takes effect when a new ring game starts
+    pub fn toggle_stall_decay(&mut self) {
+        self.stall_decay = !self.stall_decay;
+        self.mark_changed();
+    }
+
+    // Takes effect when a new game starts
+    pub fn toggle_spawn_delay(&mut self) {
+        self.spawn_delay = !self.spawn_delay;
+        self.mark_changed();
+    }
+
+    // Takes effect when a new game starts
+    pub fn toggle_pause_requires_majority(&mut self) {
+        self.pause_requires_majority = !self.pause_requires_majority;
+        self.mark_changed();
+    }
+
+    // Doesn't kick anyone out of games that are already running
+    pub fn toggle_one_player_per_ip(&mut self) {
+        self.one_player_per_ip = !self.one_player_per_ip;
+        self.mark_changed();
+    }
+
     pub fn remove_client(&mut self, client_id: u64) {
         log_for_client(client_id, &format!("Leaving lobby: {}", self.id));
         let i = self
@@ -94,19 +470,237 @@ impl Lobby {
             .position(|c| c.client_id == client_id)
             .unwrap();
         self.clients.remove(i);
+        self.remove_local_player(client_id);
+        self.leave_waitlist(client_id, Instant::now());
+        self.mark_changed();
+    }
+
+    // The client goes back to the mode menu, and gets an offer when a slot opens
+    pub fn join_waitlist(&mut self, client_id: u64, mode: Mode, now: Instant) {
+        // Waiting for many games at once would mean many offers at once
+        self.leave_waitlists_except(client_id, Some(mode));
+        let waitlist = self.waitlists.entry(mode).or_default();
+        if !waitlist.client_ids.contains(&client_id) {
+            log_for_client(client_id, &format!("Joining waitlist: {:?}", mode));
+            waitlist.client_ids.push(client_id);
+        }
+        self.update_waitlist_offers(now);
+        self.mark_changed();
+    }
+
+    // Called when the client disconnects or goes somewhere else than the game it waits for
+    pub fn leave_waitlist(&mut self, client_id: u64, now: Instant) {
+        if self.leave_waitlists_except(client_id, None) {
+            self.update_waitlist_offers(now);
+            self.mark_changed();
+        }
+    }
+
+    fn leave_waitlists_except(&mut self, client_id: u64, keep: Option<Mode>) -> bool {
+        let mut removed = false;
+        for (mode, waitlist) in &mut self.waitlists {
+            if Some(*mode) != keep && waitlist.client_ids.contains(&client_id) {
+                log_for_client(client_id, &format!("Leaving waitlist: {:?}", mode));
+                waitlist.client_ids.retain(|id| *id != client_id);
+                if matches!(waitlist.offer, Some((id, _)) if id == client_id) {
+                    waitlist.offer = None;
+                }
+                removed = true;
+            }
+        }
+        self.waitlists
+            .retain(|_, waitlist| !waitlist.client_ids.is_empty());
+        removed
+    }
+
+    // Returns the mode and the client's position in line, starting at 1
+    pub fn get_waitlist_position(&self, client_id: u64) -> Option<(Mode, usize)> {
+        self.waitlists.iter().find_map(|(mode, waitlist)| {
+            let i = waitlist.client_ids.iter().position(|id| *id == client_id)?;
+            Some((*mode, i + 1))
+        })
+    }
+
+    // Returns the mode that the client can join now, and the deadline for joining
+    pub fn get_waitlist_offer(&self, client_id: u64) -> Option<(Mode, Instant)> {
+        self.waitlists
+            .iter()
+            .find_map(|(mode, waitlist)| match waitlist.offer {
+                Some((id, deadline)) if id == client_id => Some((*mode, deadline)),
+                _ => None,
+            })
+    }
+
+    // Declining or not answering in time passes the offer to the next client in line
+    pub fn expire_waitlist_offers(&mut self, now: Instant) {
+        let expired: Vec<u64> = self
+            .waitlists
+            .values()
+            .filter_map(|waitlist| match waitlist.offer {
+                Some((id, deadline)) if now >= deadline => Some(id),
+                _ => None,
+            })
+            .collect();
+        for client_id in expired {
+            log_for_client(client_id, "Waitlist offer expired");
+            self.leave_waitlist(client_id, now);
+        }
+    }
+
+    // Someone else waiting for the same game gets a chance, so it mustn't fill up in the meantime
+    fn reserved_slots(&self, client_id: u64, mode: Mode) -> usize {
+        match self.waitlists.get(&mode).and_then(|w| w.offer) {
+            Some((id, _)) if id != client_id => self.players_needed_to_join(id),
+            _ => 0,
+        }
+    }
+
+    // Gives an offer to the first client in line who fits into the game
+    fn update_waitlist_offers(&mut self, now: Instant) {
+        let modes: Vec<Mode> = self.waitlists.keys().copied().collect();
+        for mode in modes {
+            let waitlist = &self.waitlists[&mode];
+            if waitlist.offer.is_some() {
+                continue;
+            }
+            let lucky_client = waitlist
+                .client_ids
+                .iter()
+                .copied()
+                .find(|id| self.check_joining(*id, mode).is_ok());
+            if let Some(client_id) = lucky_client {
+                log_for_client(client_id, &format!("Got waitlist offer: {:?}", mode));
+                self.waitlists.get_mut(&mode).unwrap().offer =
+                    Some((client_id, now + WAITLIST_OFFER_TIMEOUT));
+                self.mark_changed();
+            }
+        }
+    }
+
+    // Called by everyone who sees the game end. Only the first call does something.
+    pub fn offer_rematch(&mut self, mode: Mode, game: &Arc<GameWrapper>, client_ids: Vec<u64>) {
+        if let Some(rematch) = self.rematches.get(&mode) {
+            if Weak::ptr_eq(&rematch.game, &Arc::downgrade(game)) {
+                return;
+            }
+        }
+        self.rematches.insert(
+            mode,
+            Rematch {
+                game: Arc::downgrade(game),
+                client_ids,
+                accepted: vec![],
+                declined: vec![],
+                deadline: Instant::now() + self.rematch_timeout,
+            },
+        );
         self.mark_changed();
     }
 
-    fn join_game(&mut self, client_id: u64, mode: Mode) -> Option<Arc<GameWrapper>> {
+    pub fn answer_rematch(&mut self, mode: Mode, client_id: u64, accept: bool) {
+        if let Some(rematch) = self.rematches.get_mut(&mode) {
+            if rematch.client_ids.contains(&client_id) {
+                if accept {
+                    rematch.accepted.push(client_id);
+                } else {
+                    rematch.declined.push(client_id);
+                }
+                self.mark_changed();
+            }
+        }
+    }
+
+    /*
+    Playing again is offered only if everyone from the ended game is still in
+    the lobby, or has decided not to play again. After choosing to play again,
+    people who leave or don't answer in time are left out.
+    */
+    pub fn get_rematch_status(&self, mode: Mode, client_id: u64) -> RematchStatus {
+        let rematch = match self.rematches.get(&mode) {
+            Some(rematch) if rematch.client_ids.contains(&client_id) => rematch,
+            _ => return RematchStatus::Unavailable,
+        };
+        let is_in_lobby = |id: &u64| self.clients.iter().any(|c| c.client_id == *id);
+        let undecided = rematch
+            .client_ids
+            .iter()
+            .filter(|id| !rematch.accepted.contains(id) && !rematch.declined.contains(id))
+            .filter(|id| is_in_lobby(id))
+            .count();
+        let time_is_up = Instant::now() >= rematch.deadline;
+
+        if rematch.accepted.contains(&client_id) {
+            if undecided == 0 || time_is_up {
+                RematchStatus::Start
+            } else {
+                RematchStatus::Open {
+                    accepted: rematch.accepted.len(),
+                    total: rematch.client_ids.len(),
+                    deadline: rematch.deadline,
+                }
+            }
+        } else if rematch.declined.contains(&client_id)
+            || time_is_up
+            || !rematch
+                .client_ids
+                .iter()
+                .all(|id| rematch.declined.contains(id) || is_in_lobby(id))
+        {
+            RematchStatus::Unavailable
+        } else {
+            RematchStatus::Open {
+                accepted: rematch.accepted.len(),
+                total: rematch.client_ids.len(),
+                deadline: rematch.deadline,
+            }
+        }
+    }
+
+    // Playing with a local player needs room for two players in the game
+    pub fn players_needed_to_join(&self, client_id: u64) -> usize {
+        if self.get_local_player(client_id).is_some() {
+            2
+        } else {
+            1
+        }
+    }
+
+    // The mode menu uses this to show why the selected game can't be joined
+    pub fn check_joining(&self, client_id: u64, mode: Mode) -> Result<(), JoinError> {
+        if let Some(other) = self.get_blocking_game(mode) {
+            return Err(JoinError::NotAllowed(other));
+        }
+        if self.ip_already_in_game(client_id, mode) {
+            return Err(JoinError::IpRestricted);
+        }
+        if self.get_player_count(mode)
+            + self.players_needed_to_join(client_id)
+            + self.reserved_slots(client_id, mode)
+            > mode.max_players()
+        {
+            return Err(JoinError::Full);
+        }
+        Ok(())
+    }
+
+    fn join_game(&mut self, client_id: u64, mode: Mode) -> Result<Arc<GameWrapper>, JoinError> {
         let client_info = self
             .clients
             .iter()
             .find(|info| info.client_id == client_id)
             .unwrap();
 
+        let local_player_info = self.get_local_player(client_id);
+        if let Err(e) = self.check_joining(client_id, mode) {
+            log_for_client(client_id, &format!("Can't join {:?} game: {:?}", mode, e));
+            return Err(e);
+        }
+
         let wrapper = if let Some(wrapper) = self.game_wrappers.get(&mode) {
-            if !wrapper.game.lock().unwrap().add_player(client_info) {
-                return None;
+            {
+                let mut game = wrapper.lock_game();
+                add_players_to_game(&mut game, client_info, local_player_info)?;
+                game.restart_countdown_once(Instant::now());
             }
             log_for_client(client_id, &format!("Joining existing game: {:?}", mode));
             wrapper.mark_changed();
@@ -114,23 +708,46 @@ impl Lobby {
         } else {
             log_for_client(client_id, &format!("Creating and joining game: {:?}", mode));
             let mut game = Game::new(mode);
-            let ok = game.add_player(client_info);
-            assert!(ok);
-            let wrapper = Arc::new(GameWrapper::new(game));
+            game.set_logger(Box::new(log_for_client));
+            game.set_board_options(BoardOptions {
+                two_bottle_basins: self.two_bottle_basins,
+                bottle_necks: self.bottle_necks,
+                big_blocks: self.big_blocks,
+            });
+            game.set_undo_enabled(!mode.has_high_scores());
+            game.set_cursed_blocks(self.cursed_blocks);
+            game.set_ownership_tint(self.ownership_tint);
+            game.set_reinforced_squares(self.reinforced_squares);
+            game.set_stall_decay(self.stall_decay);
+            game.set_spawn_delay(self.spawn_delay);
+            if let Some(seed) = self.block_seed {
+                game.set_block_seed(seed, self.shared_block_sequence);
+            }
+            add_players_to_game(&mut game, client_info, local_player_info)?;
+            game.start_countdown(Instant::now());
+            let mut wrapper = GameWrapper::new(game);
+            wrapper.set_pause_requires_majority(self.pause_requires_majority);
+            wrapper.set_max_duration(game_wrapper::MAX_GAME_DURATION.get().copied());
+            let wrapper = Arc::new(wrapper);
             game_wrapper::start_tasks(wrapper.clone());
             self.game_wrappers.insert(mode, wrapper.clone());
             wrapper
         };
 
+        self.leave_waitlist(client_id, Instant::now());
         self.mark_changed();
-        Some(wrapper)
+        Ok(wrapper)
     }
 
-    fn leave_game(&mut self, client_id: u64, mode: Mode) {
-        log_for_client(client_id, &format!("Leaving game: {:?}", mode));
+    fn leave_game(&mut self, client_id: u64, mode: Mode, reason: LeaveReason) {
+        log_for_client(
+            client_id,
+            &format!("Leaving game: {:?} ({:?})", mode, reason),
+        );
         let last_player_removed = if let Some(wrapper) = self.game_wrappers.get(&mode) {
-            let mut game = wrapper.game.lock().unwrap();
-            game.remove_player_if_exists(client_id);
+            let mut game = wrapper.lock_game();
+            game.remove_player_if_exists(client_id, reason);
+            game.remove_player_if_exists(local_player_id(client_id), reason);
             wrapper.mark_changed();
             game.players.is_empty()
         } else {
@@ -138,10 +755,58 @@ impl Lobby {
         };
 
         if last_player_removed {
-            self.game_wrappers.remove(&mode);
+            let wrapper = self.game_wrappers.remove(&mode).unwrap();
+            if matches!(
+                *wrapper.status_receiver.borrow(),
+                GameStatus::Playing | GameStatus::Paused(_)
+            ) {
+                self.game_interrupted = true;
+            }
         }
+        self.update_waitlist_offers(Instant::now());
         self.mark_changed();
     }
+
+    // An empty lobby is kept for a while only if its players were in a game
+    fn needs_grace_period(&self) -> bool {
+        self.clients.is_empty() && (self.game_interrupted || !self.game_wrappers.is_empty())
+    }
+}
+
+/*
+Lobbies are deleted when nobody refers to them anymore. If everyone
+disconnects during a game, e.g. because of a wifi problem, the lobby stays
+for a while so that they can come back with the same lobby ID and find each
+other again. If people join and everyone leaves again without playing, the
+lobby is deleted right away.
+*/
+pub fn leave_lobby(lobby: &Arc<Mutex<Lobby>>, client_id: u64) {
+    let (needs_grace_period, mut changed_receiver) = {
+        let mut lobby = lobby.lock().unwrap();
+        lobby.remove_client(client_id);
+        (lobby.needs_grace_period(), lobby.changed_receiver.clone())
+    };
+    if !needs_grace_period {
+        return;
+    }
+
+    let lobby = lobby.clone();
+    tokio::spawn(async move {
+        let timeout = tokio::time::sleep(lobby_grace_period());
+        tokio::pin!(timeout);
+        loop {
+            tokio::select! {
+                _ = &mut timeout => break,
+                result = changed_receiver.changed() => {
+                    let lobby = lobby.lock().unwrap();
+                    if result.is_err() || (lobby.clients.is_empty() && !lobby.needs_grace_period()) {
+                        break;
+                    }
+                }
+            }
+        }
+        drop(lobby);
+    });
 }
 
 // Removes client from lobby automatically when game ends
@@ -149,13 +814,20 @@ pub struct PlayingToken {
     client_id: u64,
     mode: Mode,
     lobby: Arc<Mutex<Lobby>>,
+    reason: LeaveReason,
+}
+impl PlayingToken {
+    // Without calling this, dropping the token means that the client disconnected
+    pub fn set_quit(&mut self) {
+        self.reason = LeaveReason::Quit;
+    }
 }
 impl Drop for PlayingToken {
     fn drop(&mut self) {
         self.lobby
             .lock()
             .unwrap()
-            .leave_game(self.client_id, self.mode);
+            .leave_game(self.client_id, self.mode, self.reason);
     }
 }
 
@@ -163,15 +835,16 @@ pub fn join_game_in_a_lobby(
     lobby: Arc<Mutex<Lobby>>,
     client_id: u64,
     mode: Mode,
-) -> Option<(Arc<GameWrapper>, PlayingToken)> {
-    let game_wrapper_if_not_full = lobby.lock().unwrap().join_game(client_id, mode);
-    game_wrapper_if_not_full.map(|game_wrapper| {
+) -> Result<(Arc<GameWrapper>, PlayingToken), JoinError> {
+    let game_wrapper = lobby.lock().unwrap().join_game(client_id, mode);
+    game_wrapper.map(|game_wrapper| {
         (
             game_wrapper,
             PlayingToken {
                 client_id,
                 mode,
                 lobby,
+                reason: LeaveReason::Disconnected,
             },
         )
     })
@@ -179,6 +852,21 @@ pub fn join_game_in_a_lobby(
 
 pub type Lobbies = Arc<Mutex<WeakValueHashMap<String, Weak<Mutex<Lobby>>>>>;
 
+// Case insensitive like names in general, but otherwise exact, so that it can't be used to list players.
+// Lobbies are searched when needed, so there's no separate index to keep up to date.
+pub fn find_lobby_by_player_name(lobbies: &Lobbies, name: &str) -> Option<Arc<Mutex<Lobby>>> {
+    let name = name.to_lowercase();
+    lobbies.lock().unwrap().values().find(|lobby| {
+        let lobby = lobby.lock().unwrap();
+        !lobby.unlisted_for_name_search
+            && lobby
+                .clients
+                .iter()
+                .chain(&lobby.local_players)
+                .any(|info| info.name.to_lowercase() == name)
+    })
+}
+
 /*
 I started with A-Z0-9 and removed chars that look confusingly similar
 in small font:
@@ -197,8 +885,47 @@ const ID_ALPHABET: [char; 16] = [
     'D', 'H', 'J', 'K', 'L', 'M', 'N', 'P', 'R', 'T', 'W', 'X', 'Y', '3', '7', '9',
 ];
 
+// Characters that people type when they mean a character of ID_ALPHABET.
+// Only groups that contain a character of ID_ALPHABET are useful here.
+const CONFUSABLE_GROUPS: &[&str] = &["0OQD", "1IL"];
+
+fn get_intended_chars(ch: char) -> Vec<char> {
+    let mut result = vec![];
+    if ID_ALPHABET.contains(&ch) {
+        result.push(ch);
+    }
+    for group in CONFUSABLE_GROUPS {
+        if group.contains(ch) {
+            for other in group.chars() {
+                if other != ch && ID_ALPHABET.contains(&other) {
+                    result.push(other);
+                }
+            }
+        }
+    }
+    result
+}
+
+// Accepts IDs with confusable characters, such as "1" when "L" was intended.
 pub fn looks_like_lobby_id(string: &str) -> bool {
-    return string.len() == 6 && string.chars().all(|ch| ID_ALPHABET.contains(&ch));
+    string.chars().count() == 6 && string.chars().all(|ch| !get_intended_chars(ch).is_empty())
+}
+
+// Returns all IDs that the user might have meant, including the ID itself if it's valid.
+pub fn get_possibly_intended_ids(id: &str) -> Vec<String> {
+    let mut result = vec!["".to_string()];
+    for ch in id.chars() {
+        let intended_chars = get_intended_chars(ch);
+        result = result
+            .iter()
+            .flat_map(|start| {
+                intended_chars
+                    .iter()
+                    .map(move |c| format!("{}{}", start, c))
+            })
+            .collect();
+    }
+    result
 }
 
 pub fn generate_unused_id(
@@ -206,7 +933,6 @@ pub fn generate_unused_id(
 ) -> String {
     loop {
         let id = (0..6)
-            .into_iter()
             .map(|_| ID_ALPHABET[rand::thread_rng().gen_range(0..ID_ALPHABET.len())])
             .collect::<String>();
         if !existing_lobbies.contains_key(&id) {
@@ -214,3 +940,381 @@ pub fn generate_unused_id(
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_generated_ids_dont_contain_confusable_characters() {
+        let lobbies = WeakValueHashMap::new();
+        for _ in 0..1000 {
+            let id = generate_unused_id(&lobbies);
+            assert!(looks_like_lobby_id(&id));
+            assert!(id.chars().all(|c| !"0OQ1I".contains(c)));
+            assert_eq!(get_possibly_intended_ids(&id), [id]);
+        }
+    }
+
+    #[test]
+    fn test_possibly_intended_ids() {
+        assert_eq!(get_possibly_intended_ids("LLDDKK"), ["LLDDKK"]);
+        assert_eq!(get_possibly_intended_ids("11OOKK"), ["LLDDKK"]);
+        assert_eq!(get_possibly_intended_ids("I1O0KK"), ["LLDDKK"]);
+        assert!(get_possibly_intended_ids("ABCDEF").is_empty());
+        assert!(!looks_like_lobby_id("ABCDEF"));
+        assert!(looks_like_lobby_id("I1O0KK"));
+    }
+
+    #[tokio::test]
+    async fn test_local_player_joins_and_leaves_with_client() {
+        let lobby = Arc::new(Mutex::new(Lobby::new("DHJKLM")));
+        lobby.lock().unwrap().add_client(1, "Alice", None);
+        lobby.lock().unwrap().add_local_player(1, "Bob");
+        assert_eq!(lobby.lock().unwrap().clients.len(), 1);
+
+        let (wrapper, token) = join_game_in_a_lobby(lobby.clone(), 1, Mode::Traditional).unwrap();
+        let ids: Vec<u64> = wrapper
+            .game
+            .lock()
+            .unwrap()
+            .players
+            .iter()
+            .map(|p| p.borrow().client_id)
+            .collect();
+        assert_eq!(ids, [1, local_player_id(1)]);
+        assert_eq!(lobby.lock().unwrap().get_player_count(Mode::Traditional), 2);
+
+        // zen mode has room for only one player
+        assert!(matches!(
+            join_game_in_a_lobby(lobby.clone(), 1, Mode::Zen),
+            Err(JoinError::Full)
+        ));
+
+        drop(token);
+        assert!(wrapper.lock_game().players.is_empty());
+        assert_eq!(lobby.lock().unwrap().get_player_count(Mode::Traditional), 0);
+
+        lobby.lock().unwrap().remove_client(1);
+        assert!(lobby.lock().unwrap().local_players.is_empty());
+    }
+
+    fn lobby_with_interrupted_game() -> Weak<Mutex<Lobby>> {
+        let lobby = Arc::new(Mutex::new(Lobby::new("DHJKLM")));
+        lobby.lock().unwrap().add_client(1, "Alice", None);
+        let (_wrapper, token) = join_game_in_a_lobby(lobby.clone(), 1, Mode::Traditional).unwrap();
+        drop(token);
+        leave_lobby(&lobby, 1);
+        Arc::downgrade(&lobby)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_lobby_kept_after_interrupted_game() {
+        let weak = lobby_with_interrupted_game();
+        tokio::time::sleep(DEFAULT_LOBBY_GRACE_PERIOD - Duration::from_secs(1)).await;
+        assert!(weak.upgrade().is_some());
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert!(weak.upgrade().is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_lobby_repopulated_during_grace_period() {
+        let weak = lobby_with_interrupted_game();
+        let lobby = weak.upgrade().unwrap();
+        lobby.lock().unwrap().add_client(2, "Bob", None);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // Bob leaves without playing, no need to keep the lobby
+        leave_lobby(&lobby, 2);
+        drop(lobby);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(weak.upgrade().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_lobby_without_game_deleted_immediately() {
+        let lobby = Arc::new(Mutex::new(Lobby::new("DHJKLM")));
+        lobby.lock().unwrap().add_client(1, "Alice", None);
+        leave_lobby(&lobby, 1);
+        let weak = Arc::downgrade(&lobby);
+        drop(lobby);
+        assert!(weak.upgrade().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_parallel_games_disallowed() {
+        let lobby = Arc::new(Mutex::new(Lobby::new("DHJKLM")));
+        lobby.lock().unwrap().add_client(1, "Alice", None);
+        lobby.lock().unwrap().add_client(2, "Bob", None);
+        lobby.lock().unwrap().toggle_allow_parallel_games();
+
+        let (_wrapper, token) = join_game_in_a_lobby(lobby.clone(), 1, Mode::Bottle).unwrap();
+        assert_eq!(
+            lobby.lock().unwrap().get_blocking_game(Mode::Ring),
+            Some(Mode::Bottle)
+        );
+        assert!(matches!(
+            join_game_in_a_lobby(lobby.clone(), 2, Mode::Ring),
+            Err(JoinError::NotAllowed(Mode::Bottle))
+        ));
+        assert_eq!(lobby.lock().unwrap().get_player_count(Mode::Ring), 0);
+
+        // Joining the game in progress is fine
+        let (_wrapper2, token2) = join_game_in_a_lobby(lobby.clone(), 2, Mode::Bottle).unwrap();
+        assert_eq!(lobby.lock().unwrap().get_player_count(Mode::Bottle), 2);
+
+        // Other games can start when everyone has left
+        drop(token);
+        drop(token2);
+        assert_eq!(lobby.lock().unwrap().get_blocking_game(Mode::Ring), None);
+        assert!(join_game_in_a_lobby(lobby.clone(), 2, Mode::Ring).is_ok());
+    }
+
+    #[test]
+    fn test_adding_players_to_almost_full_game() {
+        let mut lobby = Lobby::new("DHJKLM");
+        lobby.add_client(1, "Alice", None);
+        lobby.add_client(2, "Bob", None);
+        lobby.add_local_player(2, "Bob's friend");
+
+        // Only one player fits, so Bob and the local player can't join together
+        let mut game = Game::new(Mode::Bottle);
+        for _ in 1..Mode::Bottle.max_players() {
+            assert!(game.add_player(&lobby.clients[0].player_spec()));
+        }
+        let result = add_players_to_game(&mut game, &lobby.clients[1], lobby.get_local_player(2));
+        assert_eq!(result, Err(JoinError::Full));
+        assert_eq!(game.players.len(), Mode::Bottle.max_players() - 1);
+        assert!(game.players.iter().all(|p| p.borrow().client_id == 1));
+    }
+
+    #[tokio::test]
+    async fn test_parallel_games_allowed_by_default() {
+        let lobby = Arc::new(Mutex::new(Lobby::new("DHJKLM")));
+        lobby.lock().unwrap().add_client(1, "Alice", None);
+        lobby.lock().unwrap().add_client(2, "Bob", None);
+
+        let _bottle = join_game_in_a_lobby(lobby.clone(), 1, Mode::Bottle).unwrap();
+        assert_eq!(lobby.lock().unwrap().get_blocking_game(Mode::Ring), None);
+        let _ring = join_game_in_a_lobby(lobby.clone(), 2, Mode::Ring).unwrap();
+        assert_eq!(lobby.lock().unwrap().get_player_count(Mode::Bottle), 1);
+        assert_eq!(lobby.lock().unwrap().get_player_count(Mode::Ring), 1);
+    }
+
+    fn create_lobby_with_ips(ips: &[&str]) -> Arc<Mutex<Lobby>> {
+        let lobby = Arc::new(Mutex::new(Lobby::new("DHJKLM")));
+        for (i, ip) in ips.iter().enumerate() {
+            let name = format!("Client {}", i);
+            let ip = Some(ip.parse().unwrap());
+            lobby.lock().unwrap().add_client(i as u64, &name, ip);
+        }
+        lobby
+    }
+
+    #[tokio::test]
+    async fn test_one_player_per_ip() {
+        let lobby = create_lobby_with_ips(&["12.34.56.78", "12.34.56.78", "11.22.33.44"]);
+        lobby.lock().unwrap().toggle_one_player_per_ip();
+
+        let _first = join_game_in_a_lobby(lobby.clone(), 0, Mode::Ring).unwrap();
+        assert!(lobby.lock().unwrap().ip_already_in_game(1, Mode::Ring));
+        assert!(matches!(
+            join_game_in_a_lobby(lobby.clone(), 1, Mode::Ring),
+            Err(JoinError::IpRestricted)
+        ));
+        assert_eq!(lobby.lock().unwrap().get_player_count(Mode::Ring), 1);
+
+        // Different IP, or same IP in a different game
+        let _second = join_game_in_a_lobby(lobby.clone(), 2, Mode::Ring).unwrap();
+        let _third = join_game_in_a_lobby(lobby.clone(), 1, Mode::Bottle).unwrap();
+        assert_eq!(lobby.lock().unwrap().get_player_count(Mode::Ring), 2);
+    }
+
+    #[tokio::test]
+    async fn test_same_ip_allowed_by_default() {
+        let lobby = create_lobby_with_ips(&["12.34.56.78", "12.34.56.78"]);
+        let _first = join_game_in_a_lobby(lobby.clone(), 0, Mode::Ring).unwrap();
+        assert!(!lobby.lock().unwrap().ip_already_in_game(1, Mode::Ring));
+        let _second = join_game_in_a_lobby(lobby.clone(), 1, Mode::Ring).unwrap();
+        assert_eq!(lobby.lock().unwrap().get_player_count(Mode::Ring), 2);
+    }
+
+    #[tokio::test]
+    async fn test_one_player_per_ip_with_local_player() {
+        let lobby = create_lobby_with_ips(&["12.34.56.78", "12.34.56.78"]);
+        lobby.lock().unwrap().toggle_one_player_per_ip();
+        lobby.lock().unwrap().add_local_player(0, "Local");
+
+        // The local player plays on the same connection, but another connection can't join
+        let _first = join_game_in_a_lobby(lobby.clone(), 0, Mode::Ring).unwrap();
+        assert_eq!(lobby.lock().unwrap().get_player_count(Mode::Ring), 2);
+        assert!(matches!(
+            join_game_in_a_lobby(lobby.clone(), 1, Mode::Ring),
+            Err(JoinError::IpRestricted)
+        ));
+    }
+
+    #[test]
+    fn test_rematch_needs_everyone_in_lobby() {
+        let lobby = create_lobby_with_ips(&["1.1.1.1", "2.2.2.2", "3.3.3.3"]);
+        let mut lobby = lobby.lock().unwrap();
+        let ended_game = Arc::new(GameWrapper::new(Game::new(Mode::Ring)));
+        lobby.offer_rematch(Mode::Ring, &ended_game, vec![0, 1, 2]);
+        assert!(matches!(
+            lobby.get_rematch_status(Mode::Ring, 0),
+            RematchStatus::Open {
+                accepted: 0,
+                total: 3,
+                ..
+            }
+        ));
+        assert_eq!(
+            lobby.get_rematch_status(Mode::Bottle, 0),
+            RematchStatus::Unavailable
+        );
+
+        // Offering again from another client of the same game changes nothing
+        lobby.answer_rematch(Mode::Ring, 1, true);
+        lobby.offer_rematch(Mode::Ring, &ended_game, vec![0, 1]);
+        assert!(matches!(
+            lobby.get_rematch_status(Mode::Ring, 0),
+            RematchStatus::Open {
+                accepted: 1,
+                total: 3,
+                ..
+            }
+        ));
+
+        // Leaving without answering counts as not playing again
+        lobby.remove_client(2);
+        assert_eq!(
+            lobby.get_rematch_status(Mode::Ring, 0),
+            RematchStatus::Unavailable
+        );
+        assert_eq!(
+            lobby.get_rematch_status(Mode::Ring, 1),
+            RematchStatus::Open {
+                accepted: 1,
+                total: 3,
+                deadline: lobby.rematches[&Mode::Ring].deadline
+            }
+        );
+        lobby.answer_rematch(Mode::Ring, 0, false);
+        assert_eq!(
+            lobby.get_rematch_status(Mode::Ring, 1),
+            RematchStatus::Start
+        );
+    }
+
+    // Clients 0-3 play a ring game, and clients 4 and 5 wait for it
+    fn create_full_ring_game_with_waitlist() -> (Arc<Mutex<Lobby>>, Vec<PlayingToken>) {
+        let lobby = Arc::new(Mutex::new(Lobby::new("DHJKLM")));
+        for i in 0..6 {
+            lobby
+                .lock()
+                .unwrap()
+                .add_client(i, &format!("Client {}", i), None);
+        }
+        let tokens = (0..4)
+            .map(|i| {
+                join_game_in_a_lobby(lobby.clone(), i, Mode::Ring)
+                    .unwrap()
+                    .1
+            })
+            .collect();
+        for i in [4, 5] {
+            assert_eq!(
+                lobby.lock().unwrap().check_joining(i, Mode::Ring),
+                Err(JoinError::Full)
+            );
+            lobby
+                .lock()
+                .unwrap()
+                .join_waitlist(i, Mode::Ring, Instant::now());
+        }
+        (lobby, tokens)
+    }
+
+    #[tokio::test]
+    async fn test_waitlist_order() {
+        let (lobby, mut tokens) = create_full_ring_game_with_waitlist();
+        {
+            let lobby = lobby.lock().unwrap();
+            assert_eq!(lobby.get_waitlist_position(4), Some((Mode::Ring, 1)));
+            assert_eq!(lobby.get_waitlist_position(5), Some((Mode::Ring, 2)));
+            assert_eq!(lobby.get_waitlist_offer(4), None);
+        }
+
+        // The first client in line gets the slot, and nobody else can take it meanwhile
+        drop(tokens.pop());
+        {
+            let lobby = lobby.lock().unwrap();
+            let (mode, deadline) = lobby.get_waitlist_offer(4).unwrap();
+            assert_eq!(mode, Mode::Ring);
+            assert!(deadline > Instant::now() + Duration::from_secs(14));
+            assert_eq!(lobby.get_waitlist_offer(5), None);
+            assert_eq!(lobby.check_joining(5, Mode::Ring), Err(JoinError::Full));
+        }
+
+        let _token = join_game_in_a_lobby(lobby.clone(), 4, Mode::Ring)
+            .unwrap()
+            .1;
+        let lobby = lobby.lock().unwrap();
+        assert_eq!(lobby.get_waitlist_position(4), None);
+        assert_eq!(lobby.get_waitlist_position(5), Some((Mode::Ring, 1)));
+        assert_eq!(lobby.get_waitlist_offer(5), None);
+    }
+
+    #[tokio::test]
+    async fn test_waitlist_offer_timeout() {
+        let (lobby, mut tokens) = create_full_ring_game_with_waitlist();
+        drop(tokens.pop());
+        let mut lobby = lobby.lock().unwrap();
+        let (_, deadline) = lobby.get_waitlist_offer(4).unwrap();
+
+        lobby.expire_waitlist_offers(deadline - Duration::from_millis(1));
+        assert!(lobby.get_waitlist_offer(4).is_some());
+        assert_eq!(lobby.get_waitlist_offer(5), None);
+
+        // Not answering in time passes the offer to the next client in line
+        lobby.expire_waitlist_offers(deadline);
+        assert_eq!(lobby.get_waitlist_position(4), None);
+        assert_eq!(lobby.get_waitlist_position(5), Some((Mode::Ring, 1)));
+        let (mode, new_deadline) = lobby.get_waitlist_offer(5).unwrap();
+        assert_eq!(mode, Mode::Ring);
+        assert_eq!(new_deadline, deadline + WAITLIST_OFFER_TIMEOUT);
+        assert_eq!(lobby.check_joining(4, Mode::Ring), Err(JoinError::Full));
+
+        // Declining works the same way, and then the waitlist is empty
+        lobby.leave_waitlist(5, new_deadline);
+        assert_eq!(lobby.get_waitlist_offer(5), None);
+        assert!(lobby.waitlists.is_empty());
+        assert!(lobby.check_joining(4, Mode::Ring).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_waitlist_cleanup_on_disconnect() {
+        let (lobby, mut tokens) = create_full_ring_game_with_waitlist();
+
+        // Waiting client leaves before a slot opens
+        lobby.lock().unwrap().remove_client(4);
+        assert_eq!(
+            lobby.lock().unwrap().get_waitlist_position(5),
+            Some((Mode::Ring, 1))
+        );
+
+        // Client with an offer leaves, and the offer goes to the next client in line
+        lobby.lock().unwrap().add_client(4, "Client 4", None);
+        lobby
+            .lock()
+            .unwrap()
+            .join_waitlist(4, Mode::Ring, Instant::now());
+        drop(tokens.pop());
+        assert!(lobby.lock().unwrap().get_waitlist_offer(5).is_some());
+        leave_lobby(&lobby, 5);
+        let lobby = lobby.lock().unwrap();
+        assert_eq!(lobby.get_waitlist_position(5), None);
+        assert_eq!(lobby.get_waitlist_position(4), Some((Mode::Ring, 1)));
+        assert!(lobby.get_waitlist_offer(4).is_some());
+    }
+}
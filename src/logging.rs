@@ -0,0 +1,282 @@
+use std::fmt::Write;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::OnceLock;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LogFormat {
+    Text, // "[client 123] message", easy to read
+    Json, // one object per line, easy to grep by client, lobby or name
+}
+
+// Set from command line arguments, text if not set
+pub static LOG_FORMAT: OnceLock<LogFormat> = OnceLock::new();
+
+fn get_log_format() -> LogFormat {
+    LOG_FORMAT.get().copied().unwrap_or(LogFormat::Text)
+}
+
+// Events that are useful when reconstructing what a client did
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EventKind {
+    Connect,
+    NameSet,
+    LobbyJoin,
+    GameStart,
+    Disconnect, // message contains the reason
+}
+
+impl EventKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            EventKind::Connect => "connect",
+            EventKind::NameSet => "name-set",
+            EventKind::LobbyJoin => "lobby-join",
+            EventKind::GameStart => "game-start",
+            EventKind::Disconnect => "disconnect",
+        }
+    }
+}
+
+// Things about a client that become known over time and go to every log record
+#[derive(Clone, Default, Debug)]
+pub struct ClientContext {
+    pub name: Option<String>,
+    pub lobby_id: Option<String>,
+}
+
+fn json_string(s: &str) -> String {
+    let mut result = "\"".to_string();
+    for ch in s.chars() {
+        match ch {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            '\r' => result.push_str("\\r"),
+            '\t' => result.push_str("\\t"),
+            ch if (ch as u32) < 0x20 => write!(result, "\\u{:04x}", ch as u32).unwrap(),
+            ch => result.push(ch),
+        }
+    }
+    result.push('"');
+    result
+}
+
+fn json_optional_string(s: &Option<String>) -> String {
+    match s {
+        Some(s) => json_string(s),
+        None => "null".to_string(),
+    }
+}
+
+fn format_record(
+    format: LogFormat,
+    client_id: u64,
+    context: &ClientContext,
+    event: Option<EventKind>,
+    message: &str,
+) -> String {
+    match format {
+        LogFormat::Text => format!("[client {}] {}", client_id, message),
+        LogFormat::Json => format!(
+            "{{\"timestamp\":{},\"client_id\":{},\"lobby_id\":{},\"name\":{},\"event\":{},\"message\":{}}}",
+            json_string(&chrono::Utc::now().to_rfc3339()),
+            client_id,
+            json_optional_string(&context.lobby_id),
+            json_optional_string(&context.name),
+            match event {
+                Some(event) => json_string(event.name()),
+                None => "null".to_string(),
+            },
+            json_string(message),
+        ),
+    }
+}
+
+fn format_server_record(format: LogFormat, component: &str, error: bool, message: &str) -> String {
+    match format {
+        LogFormat::Text if error => format!("[{}] ERROR: {}", component, message),
+        LogFormat::Text => format!("[{}] {}", component, message),
+        LogFormat::Json => format!(
+            "{{\"timestamp\":{},\"component\":{},\"level\":{},\"message\":{}}}",
+            json_string(&chrono::Utc::now().to_rfc3339()),
+            json_string(component),
+            json_string(if error { "error" } else { "info" }),
+            json_string(message),
+        ),
+    }
+}
+
+// For things that don't belong to any one client, e.g. log("game", "Game over: ...")
+pub fn log(component: &str, message: &str) {
+    println!(
+        "{}",
+        format_server_record(get_log_format(), component, false, message)
+    );
+}
+
+// Like log(), but goes to stderr
+pub fn log_error(component: &str, message: &str) {
+    eprintln!(
+        "{}",
+        format_server_record(get_log_format(), component, true, message)
+    );
+}
+
+// For code that knows only the client ID, e.g. lobbies and games
+pub fn log_without_context(client_id: u64, message: &str) {
+    let context = ClientContext::default();
+    println!(
+        "{}",
+        format_record(get_log_format(), client_id, &context, None, message)
+    );
+}
+
+/*
+Clones share the same context, so when the client's name or lobby changes,
+it shows up in everything logged after that. This way the context doesn't
+need to be passed around to every place that logs something.
+*/
+#[derive(Clone)]
+pub struct ClientLogger {
+    client_id: u64,
+    context: Arc<Mutex<ClientContext>>,
+    format: LogFormat,
+    output: Arc<dyn Fn(&str) + Send + Sync>,
+}
+
+impl ClientLogger {
+    pub fn new(client_id: u64) -> Self {
+        Self::with_output(
+            client_id,
+            get_log_format(),
+            Arc::new(|line| println!("{}", line)),
+        )
+    }
+
+    pub fn with_output(
+        client_id: u64,
+        format: LogFormat,
+        output: Arc<dyn Fn(&str) + Send + Sync>,
+    ) -> Self {
+        Self {
+            client_id,
+            context: Arc::new(Mutex::new(ClientContext::default())),
+            format,
+            output,
+        }
+    }
+
+    // Tests use this to see what got logged
+    #[cfg(test)]
+    pub fn capturing(client_id: u64, format: LogFormat) -> (Self, Arc<Mutex<Vec<String>>>) {
+        let lines = Arc::new(Mutex::new(vec![]));
+        let lines2 = lines.clone();
+        let output = Arc::new(move |line: &str| lines2.lock().unwrap().push(line.to_string()));
+        (Self::with_output(client_id, format, output), lines)
+    }
+
+    pub fn set_name(&self, name: &str) {
+        self.context.lock().unwrap().name = Some(name.to_string());
+    }
+
+    pub fn set_lobby_id(&self, lobby_id: Option<&str>) {
+        self.context.lock().unwrap().lobby_id = lobby_id.map(|id| id.to_string());
+    }
+
+    fn write(&self, event: Option<EventKind>, message: &str) {
+        let context = self.context.lock().unwrap().clone();
+        (self.output)(&format_record(
+            self.format,
+            self.client_id,
+            &context,
+            event,
+            message,
+        ));
+    }
+
+    pub fn log(&self, message: &str) {
+        self.write(None, message);
+    }
+
+    pub fn log_event(&self, event: EventKind, message: &str) {
+        self.write(Some(event), message);
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    // Checks that the timestamp is valid and returns the rest of the line
+    pub fn strip_timestamp(line: &str) -> String {
+        let rest = line.strip_prefix("{\"timestamp\":\"").unwrap();
+        let (timestamp, rest) = rest.split_once("\",").unwrap();
+        assert!(chrono::DateTime::parse_from_rfc3339(timestamp).is_ok());
+        rest.to_string()
+    }
+
+    #[test]
+    fn test_json_session() {
+        let (logger, lines) = ClientLogger::capturing(123, LogFormat::Json);
+        logger.log_event(EventKind::Connect, "New raw TCP connection");
+        logger.set_name("Alice \"the\" cat");
+        logger.log_event(EventKind::NameSet, "Name set");
+        logger.clone().set_lobby_id(Some("DHJKLM"));
+        logger.log_event(EventKind::LobbyJoin, "Created lobby");
+        logger.log_event(EventKind::GameStart, "Playing Traditional game");
+        logger.log("Something\nelse");
+        logger.log_event(
+            EventKind::Disconnect,
+            "Disconnected: received quit key press",
+        );
+
+        let lines: Vec<String> = lines
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|line| strip_timestamp(line))
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                r#""client_id":123,"lobby_id":null,"name":null,"event":"connect","message":"New raw TCP connection"}"#,
+                r#""client_id":123,"lobby_id":null,"name":"Alice \"the\" cat","event":"name-set","message":"Name set"}"#,
+                r#""client_id":123,"lobby_id":"DHJKLM","name":"Alice \"the\" cat","event":"lobby-join","message":"Created lobby"}"#,
+                r#""client_id":123,"lobby_id":"DHJKLM","name":"Alice \"the\" cat","event":"game-start","message":"Playing Traditional game"}"#,
+                r#""client_id":123,"lobby_id":"DHJKLM","name":"Alice \"the\" cat","event":null,"message":"Something\nelse"}"#,
+                r#""client_id":123,"lobby_id":"DHJKLM","name":"Alice \"the\" cat","event":"disconnect","message":"Disconnected: received quit key press"}"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_text_format_unchanged() {
+        let (logger, lines) = ClientLogger::capturing(123, LogFormat::Text);
+        logger.set_name("Alice");
+        logger.log_event(EventKind::NameSet, "Name set");
+        assert_eq!(*lines.lock().unwrap(), ["[client 123] Name set"]);
+    }
+
+    #[test]
+    fn test_server_records() {
+        assert_eq!(
+            format_server_record(LogFormat::Text, "game", false, "Game over"),
+            "[game] Game over"
+        );
+        assert_eq!(
+            format_server_record(LogFormat::Text, "prefs", true, "Saving failed"),
+            "[prefs] ERROR: Saving failed"
+        );
+        let line = format_server_record(LogFormat::Json, "admin", true, "a\nb");
+        assert_eq!(
+            strip_timestamp(&line),
+            r#""component":"admin","level":"error","message":"a\nb"}"#
+        );
+    }
+
+    #[test]
+    fn test_json_control_characters() {
+        assert_eq!(json_string("a\u{1b}b\\"), r#""a\u001bb\\""#);
+    }
+}
@@ -1,108 +1,248 @@
 #[macro_use(lazy_static)]
 extern crate lazy_static;
 
+use crate::accept::accept_forever;
+use crate::accept::ConnectionLimit;
+use crate::accept::ConnectionSlot;
+use crate::admin::ClientRegistry;
 use crate::client::log_for_client;
 use crate::client::Client;
 use crate::connection::get_websocket_proxy_ip;
 use crate::connection::initialize_connection;
+use crate::connection::FrameQueue;
+use crate::connection::FrameStats;
 use crate::connection::Receiver;
 use crate::connection::Sender;
 use crate::escapes::KeyPress;
 use crate::escapes::TerminalType;
 use crate::ip_tracker::IpTracker;
+use crate::logging::ClientLogger;
+use crate::logging::EventKind;
+use crate::logging::LogFormat;
+use crate::name_metrics::NameMetrics;
 use crate::render::RenderBuffer;
+use catris::escapes;
+use catris::game_logic;
 use std::collections::HashSet;
 use std::io;
 use std::io::ErrorKind;
 use std::net::IpAddr;
+use std::net::SocketAddr;
 use std::sync::atomic::AtomicU64;
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::sync::OnceLock;
 use std::time::Duration;
+use std::time::Instant;
 use tokio::net::TcpListener;
 use tokio::net::TcpStream;
 use tokio::time::timeout;
 use weak_table::WeakValueHashMap;
 
+mod accept;
+mod admin;
 mod client;
 mod connection;
-mod escapes;
-mod game_logic;
+mod deflate;
 mod game_wrapper;
 mod high_scores;
+mod idle;
 mod ingame_ui;
 mod ip_tracker;
 mod lobby;
+mod logging;
+mod name_metrics;
+mod prefs;
 mod render;
+mod reports;
+mod strings;
+mod tutorial;
 mod views;
 
 async fn handle_receiving(
     mut client: Client,
     lobbies: lobby::Lobbies,
     used_names: Arc<Mutex<HashSet<String>>>,
+    name_metrics: Arc<Mutex<NameMetrics>>,
+    ip_tracker: Arc<Mutex<IpTracker>>,
 ) -> Result<(), io::Error> {
-    views::ask_name(&mut client, used_names).await?;
-    log_for_client(
-        client.id,
-        &format!("Name asking done: {}", client.get_name().unwrap()),
-    );
+    views::ask_name(&mut client, used_names, name_metrics).await?;
 
-    let want_new_lobby = views::ask_if_new_lobby(&mut client).await?;
-    if want_new_lobby {
-        client.make_lobby(lobbies);
-    } else {
-        views::ask_lobby_id_and_join_lobby(&mut client, lobbies).await?;
+    loop {
+        match views::ask_lobby_choice(&mut client).await? {
+            views::LobbyChoice::NewLobby => client.make_lobby(lobbies),
+            views::LobbyChoice::JoinById => {
+                views::ask_lobby_id_and_join_lobby(&mut client, lobbies, ip_tracker).await?
+            }
+            views::LobbyChoice::JoinByPlayerName => {
+                views::ask_player_name_and_join_lobby(&mut client, lobbies, ip_tracker).await?
+            }
+            views::LobbyChoice::Tutorial => {
+                views::play_tutorial(&mut client).await?;
+                continue;
+            }
+        }
+        break;
     }
 
     let mut selected_index = 0;
     loop {
         let game_mode = views::show_mode_menu(&mut client, &mut selected_index).await?;
         match game_mode {
-            views::ModeMenuChoice::PlayGame(mode) => views::play_game(&mut client, mode).await?,
+            views::ModeMenuChoice::PlayGame(mode) => {
+                client.handle.status.lock().unwrap().mode = Some(mode);
+                views::play_game(&mut client, mode).await?;
+                client.handle.status.lock().unwrap().mode = None;
+            }
             views::ModeMenuChoice::GameplayTips => views::show_gameplay_tips(&mut client).await?,
             views::ModeMenuChoice::ShowAllHighScores => {
                 views::show_all_high_scores(&mut client).await?
             }
+            views::ModeMenuChoice::AddLocalPlayer => {
+                views::ask_local_player_name(&mut client).await?
+            }
+            views::ModeMenuChoice::EnterBlockSeed => views::ask_block_seed(&mut client).await?,
+            views::ModeMenuChoice::ReportProblem => views::report_problem(&mut client).await?,
+            views::ModeMenuChoice::LobbySettings => views::show_lobby_settings(&mut client).await?,
         }
     }
 }
 
+// Clients in low bandwidth mode get at most 2 frames per second
+const LOW_BANDWIDTH_FRAME_INTERVAL: Duration = Duration::from_millis(500);
+
 async fn handle_sending(
-    sender: &mut Sender,
-    render_data: Arc<Mutex<render::RenderData>>,
+    frame_queue: &FrameQueue,
+    frame_stats: &mut FrameStats,
+    render_data: Arc<render::SharedRenderData>,
     terminal_type: TerminalType,
 ) -> Result<(), io::Error> {
     let mut last_render = RenderBuffer::new(terminal_type);
     let mut current_render = RenderBuffer::new(terminal_type); // Please get rid of this if copying turns out to be slow
-    let change_notify = render_data.lock().unwrap().changed.clone();
+    let mut sequence_receiver = render_data.subscribe();
+    let mut copied_sequence = None;
+    let mut last_frame_time: Option<Instant> = None;
 
     loop {
-        change_notify.notified().await;
+        // A rate limited redraw can happen even if nothing changes
+        let redraw_time = render_data.lock().unwrap().get_requested_redraw_time();
+        // Doesn't fail, because render data contains a receiver
+        match redraw_time {
+            Some(time) => {
+                tokio::select! {
+                    result = sequence_receiver.changed() => result.unwrap(),
+                    _ = tokio::time::sleep_until(tokio::time::Instant::from_std(time)) => {}
+                }
+            }
+            None => sequence_receiver.changed().await.unwrap(),
+        }
+        let low_bandwidth = render_data.lock().unwrap().buffer.low_bandwidth;
+        if let (true, Some(last)) = (low_bandwidth, last_frame_time) {
+            // Changes that arrive while sleeping go into the same frame
+            let next_frame_time = last + LOW_BANDWIDTH_FRAME_INTERVAL;
+            tokio::time::sleep_until(tokio::time::Instant::from_std(next_frame_time)).await;
+        }
+        let sequence = *sequence_receiver.borrow_and_update();
 
         let cursor_pos;
         let force_redraw;
+        let bells;
+        let sound_events;
         {
             let mut render_data = render_data.lock().unwrap();
-            render_data.buffer.copy_into(&mut current_render);
+            if copied_sequence != Some(sequence) {
+                render_data.buffer.copy_into(&mut current_render);
+                render_data.add_banners(&mut current_render);
+                copied_sequence = Some(sequence);
+            }
             cursor_pos = render_data.cursor_pos;
-            force_redraw = render_data.force_redraw;
-            render_data.force_redraw = false;
+            force_redraw = render_data.take_redraw(Instant::now());
+            bells = render_data.pending_bells;
+            render_data.pending_bells = 0;
+            sound_events = std::mem::take(&mut render_data.pending_sound_events);
+        }
+
+        for event in sound_events {
+            frame_queue.push_sound_event(event.to_message());
         }
 
         // In the beginning of a connection, the buffer isn't ready yet
         if current_render.width != 0 && current_render.height != 0 {
-            let to_send =
-                current_render.get_updates_as_escape_codes(&last_render, cursor_pos, force_redraw);
-            sender.send(to_send.as_bytes()).await?;
+            let full_redraw = if frame_queue.is_full() {
+                // Client can't keep up, so replace the frames it hasn't received with a full redraw.
+                // This also drops their bells, but ringing late wouldn't be useful anyway.
+                frame_queue.drop_unsent_frames();
+                true
+            } else {
+                force_redraw
+            };
+            let mut to_send =
+                current_render.get_updates_as_escape_codes(&last_render, cursor_pos, full_redraw);
+            to_send.push_str(&"\x07".repeat(bells));
+            frame_stats.record(to_send.len(), full_redraw);
+            frame_queue.push(to_send.into_bytes());
             current_render.copy_into(&mut last_render);
+            last_frame_time = Some(Instant::now());
+        }
+    }
+}
+
+// Terminals respond to the DSR and ident in detect_terminal_type() with several key presses
+#[derive(Clone, Copy)]
+enum DetectionState {
+    Initial,
+    AfterEscape,
+    AfterVt52Ident,
+    InAnsiResponse(u8), // number of non-R characters received after "ESC ["
+}
+
+fn handle_detection_key(
+    state: &mut DetectionState,
+    key: KeyPress,
+) -> Result<Option<TerminalType>, io::Error> {
+    match (*state, key) {
+        (DetectionState::Initial, KeyPress::Character('a')) => Ok(Some(TerminalType::Ansi)),
+        (DetectionState::Initial, KeyPress::Character('v')) => Ok(Some(TerminalType::VT52)),
+        // Response to ANSI DSR
+        (DetectionState::Initial, KeyPress::CursorPositionReport) => Ok(Some(TerminalType::Ansi)),
+        (DetectionState::Initial, KeyPress::Character('\x1b')) => {
+            // Escape character, probably in response to ANSI DSR or VT52 ident
+            *state = DetectionState::AfterEscape;
+            Ok(None)
         }
+        (DetectionState::AfterEscape, KeyPress::Character('/')) => {
+            // VT5* ident. Next character distinguishes, VT50, VT52 etc
+            *state = DetectionState::AfterVt52Ident;
+            Ok(None)
+        }
+        (DetectionState::AfterVt52Ident, KeyPress::Character('K' | 'L' | 'Z')) => {
+            Ok(Some(TerminalType::VT52))
+        }
+        (DetectionState::AfterEscape, KeyPress::Character('[')) => {
+            // ANSI terminal. Response to DSR ends with letter R.
+            *state = DetectionState::InAnsiResponse(0);
+            Ok(None)
+        }
+        (DetectionState::InAnsiResponse(_), KeyPress::Character('R')) => {
+            Ok(Some(TerminalType::Ansi))
+        }
+        // Length limit so client can't send a lot of garbage to consume server CPU.
+        (DetectionState::InAnsiResponse(n), _) if n < 9 => {
+            *state = DetectionState::InAnsiResponse(n + 1);
+            Ok(None)
+        }
+        _ => Err(io::Error::new(
+            ErrorKind::ConnectionAborted,
+            "unable to detect terminal type",
+        )),
     }
 }
 
 pub async fn detect_terminal_type(
     sender: &mut Sender,
     receiver: &mut Receiver,
+    time_limit: Duration,
 ) -> Result<TerminalType, io::Error> {
     let message = concat!(
         "\r\n",
@@ -115,91 +255,158 @@ pub async fn detect_terminal_type(
         "  * Create an issue at https://github.com/Akuli/catris/ if you have\r\n",
         "    trouble connecting.\r\n",
         "\r\n",
-        // Send DSR (Device Status Report, aka query cursor location) for ansi terminals.
-        // Send ident (aka identify terminal type) for VT52 terminals.
-        // Both types of terminals respond without user input.
-        "\x1b[6n\x1bZ",
     );
-    sender.send(message.as_bytes()).await?;
+    let mut message = message.as_bytes().to_vec();
+    // Send DSR (Device Status Report, aka query cursor location) for ansi terminals.
+    // Send ident (aka identify terminal type) for VT52 terminals.
+    // Both types of terminals respond without user input.
+    message.extend(escapes::DEVICE_STATUS_REPORT);
+    message.extend(b"\x1bZ");
+    sender.send(&message).await?;
 
-    match receiver.receive_key_press().await? {
-        KeyPress::Character('a') => return Ok(TerminalType::Ansi),
-        KeyPress::Character('v') => return Ok(TerminalType::VT52),
-        KeyPress::Character('\x1b') => {
-            // Escape character, probably in response to ANSI DSR or VT52 ident
-            match receiver.receive_key_press().await? {
-                KeyPress::Character('/') => {
-                    // VT5* ident. Next character distinguishes, VT50, VT52 etc
-                    if matches!(
-                        receiver.receive_key_press().await?,
-                        KeyPress::Character('K')
-                            | KeyPress::Character('L')
-                            | KeyPress::Character('Z')
-                    ) {
-                        return Ok(TerminalType::VT52);
-                    }
+    let deadline = Instant::now() + time_limit;
+    let mut countdown_interval = tokio::time::interval(Duration::from_secs(1));
+    let mut state = DetectionState::Initial;
+
+    loop {
+        // Receiving key presses can be cancelled, partial responses stay in the receiver's buffer
+        tokio::select! {
+            key = receiver.receive_key_press() => {
+                if let Some(terminal_type) = handle_detection_key(&mut state, key?)? {
+                    return Ok(terminal_type);
                 }
-                KeyPress::Character('[') => {
-                    // ANSI terminal. Response to DSR ends with letter R.
-                    // Length limit so client can't send a lot of garbage to consume server CPU.
-                    let mut n = 0;
-                    while n < 10 {
-                        if matches!(
-                            receiver.receive_key_press().await?,
-                            KeyPress::Character('R')
-                        ) {
-                            return Ok(TerminalType::Ansi);
-                        }
-                        n += 1;
-                    }
+            }
+            _ = countdown_interval.tick() => {
+                let seconds_left = deadline
+                    .saturating_duration_since(Instant::now())
+                    .as_secs_f32()
+                    .round() as u64;
+                if seconds_left == 0 {
+                    return Err(io::Error::new(
+                        ErrorKind::TimedOut,
+                        "terminal type detection timed out",
+                    ));
                 }
-                _ => {}
+                // We don't know the terminal type yet, so the line is updated with just \r
+                let line = format!(
+                    "\rAuto-detecting... {}s left - press a for ANSI, v for VT52  ",
+                    seconds_left
+                );
+                sender.send(line.as_bytes()).await?;
             }
         }
-        _ => {}
     }
-
-    Err(io::Error::new(
-        ErrorKind::ConnectionAborted,
-        "unable to detect terminal type",
-    ))
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn handle_connection_until_error(
     client_id: u64,
+    logger: ClientLogger,
     socket: TcpStream,
     source_ip: IpAddr,
     lobbies: lobby::Lobbies,
     used_names: Arc<Mutex<HashSet<String>>>,
+    name_metrics: Arc<Mutex<NameMetrics>>,
     ip_tracker: Arc<Mutex<IpTracker>>,
+    registry: Arc<ClientRegistry>,
     is_websocket: bool,
+    frame_stats: &mut FrameStats,
 ) -> Result<(), io::Error> {
-    let (mut sender, mut receiver, _decrementer) =
-        initialize_connection(ip_tracker, client_id, socket, source_ip, is_websocket).await?;
-
-    let terminal_type = timeout(
-        Duration::from_secs(20),
-        detect_terminal_type(&mut sender, &mut receiver),
+    let (mut sender, mut receiver, decrementer) = initialize_connection(
+        ip_tracker.clone(),
+        client_id,
+        socket,
+        source_ip,
+        is_websocket,
     )
-    .await??;
+    .await?;
+    if is_websocket {
+        log_for_client(
+            client_id,
+            &format!(
+                "Websocket handshake done, compression: {}",
+                if sender.compression_enabled() {
+                    "permessage-deflate"
+                } else {
+                    "none"
+                }
+            ),
+        );
+    }
+
+    let terminal_type =
+        detect_terminal_type(&mut sender, &mut receiver, Duration::from_secs(20)).await?;
     log_for_client(
         client_id,
         &format!("Terminal type detected: {:?}", terminal_type),
     );
 
-    let client = Client::new(client_id, receiver, terminal_type);
+    let mut client = Client::new(client_id, receiver, terminal_type);
+    client.logger = logger;
+    client.ip = Some(decrementer.ip());
+    client.sound_events_enabled = sender.wants_sound_events();
     let render_data = client.render_data.clone();
+    let client_handle = client.handle.clone();
+    let _unregister = ClientRegistry::register(registry.clone(), client_id, client.handle.clone());
 
+    let frame_queue = FrameQueue::new();
     let result = tokio::select! {
-        res = handle_receiving(client, lobbies, used_names) => res,
-        res = handle_sending(&mut sender, render_data, terminal_type) => res,
+        res = handle_receiving(client, lobbies, used_names, name_metrics, ip_tracker) => res,
+        res = handle_sending(&frame_queue, frame_stats, render_data.clone(), terminal_type) => res,
+        res = sender.send_queued_frames(&frame_queue) => res,
     };
 
-    // Try to leave the terminal in a sane state
+    let coalesced = render_data.lock().unwrap().coalesced_redraw_requests;
+    if coalesced != 0 {
+        log_for_client(
+            client_id,
+            &format!(
+                "Merged {} redraw requests because they came too often",
+                coalesced
+            ),
+        );
+    }
+    let dropped = frame_queue.get_dropped_count();
+    if dropped != 0 {
+        log_for_client(
+            client_id,
+            &format!("Dropped {} frames because sending was slow", dropped),
+        );
+    }
+
+    let final_message = if let Some(reason) = client_handle.get_kick_reason() {
+        let mut lines = vec!["You were kicked from the server.".to_string()];
+        if !reason.is_empty() {
+            lines.push(format!("Reason: {}", reason));
+        }
+        Some(lines)
+    } else if matches!(&result, Err(e) if e.kind() == ErrorKind::TimedOut) {
+        Some(vec![
+            "You were disconnected due to inactivity.".to_string(),
+            "Connect again to continue playing.".to_string(),
+        ])
+    } else {
+        None
+    };
+    if let Some(lines) = final_message {
+        let mut buffer = RenderBuffer::new(terminal_type);
+        buffer.resize(80, 24);
+        for (i, line) in lines.iter().enumerate() {
+            buffer.add_centered_text(10 + 2 * i, line);
+        }
+        let message =
+            buffer.get_updates_as_escape_codes(&RenderBuffer::new(terminal_type), None, true);
+        timeout(Duration::from_millis(500), sender.send(message.as_bytes())).await??;
+    }
+
+    // Try to leave the terminal in a sane state. Unsent frames don't matter anymore.
     let cleanup = terminal_type.show_cursor().to_string()
         + terminal_type.move_cursor_to_leftmost_column()
         + terminal_type.clear_from_cursor_to_end_of_screen();
-    _ = timeout(Duration::from_millis(500), sender.send(cleanup.as_bytes())).await??;
+    timeout(Duration::from_millis(500), sender.send(cleanup.as_bytes())).await??;
+    if let Err(e) = &result {
+        timeout(Duration::from_millis(500), sender.close_after_error(e)).await??;
+    }
 
     assert!(result.is_err());
     result
@@ -207,85 +414,376 @@ async fn handle_connection_until_error(
 
 static CLIENT_ID_COUNTER: AtomicU64 = AtomicU64::new(1);
 
+#[allow(clippy::too_many_arguments)]
 async fn handle_connection(
     socket: TcpStream,
     source_ip: IpAddr,
     lobbies: lobby::Lobbies,
     used_names: Arc<Mutex<HashSet<String>>>,
+    name_metrics: Arc<Mutex<NameMetrics>>,
     ip_tracker: Arc<Mutex<IpTracker>>,
+    registry: Arc<ClientRegistry>,
     is_websocket: bool,
+    _slot: ConnectionSlot, // frees up room for another connection when this one closes
 ) {
     // https://stackoverflow.com/a/32936288
     // not sure what ordering to use, so choosing the one with most niceness guarantees
     let client_id = CLIENT_ID_COUNTER.fetch_add(1, Ordering::SeqCst);
 
+    // Shares the context with the client, so the disconnect message shows the name and lobby
+    let logger = ClientLogger::new(client_id);
     if is_websocket {
-        log_for_client(client_id, "New websocket connection");
+        logger.log_event(EventKind::Connect, "New websocket connection");
     } else {
-        log_for_client(client_id, "New raw TCP connection");
+        logger.log_event(EventKind::Connect, "New raw TCP connection");
     }
 
+    let mut frame_stats = FrameStats::default();
     let error = handle_connection_until_error(
         client_id,
+        logger.clone(),
         socket,
         source_ip,
         lobbies,
         used_names,
+        name_metrics,
         ip_tracker,
+        registry,
         is_websocket,
+        &mut frame_stats,
     )
     .await
     .unwrap_err();
-    log_for_client(client_id, &format!("Disconnected: {}", error));
+    logger.log_event(
+        EventKind::Disconnect,
+        &format!("Disconnected: {} ({})", error, frame_stats.summary()),
+    );
+}
+
+async fn log_name_metrics_periodically(name_metrics: Arc<Mutex<NameMetrics>>) {
+    loop {
+        tokio::time::sleep(Duration::from_secs(10 * 60)).await;
+        let summary = name_metrics.lock().unwrap().summary();
+        logging::log("name metrics", &summary);
+    }
+}
+
+struct Args {
+    port: u16,
+    websocket_port: u16,
+    admin_port: Option<u16>,
+    remove_duplicate_high_scores: bool,
+    max_connections: usize,
+}
+
+// Setting these twice would panic
+fn set_option_once<T>(cell: &OnceLock<T>, value: T, option: &str) {
+    if cell.set(value).is_err() {
+        eprintln!("{} can be given only once", option);
+        std::process::exit(2);
+    }
+}
+
+fn parse_args() -> Args {
+    let mut port = 12345;
+    let mut websocket_port = 54321;
+    let mut admin_port = None;
+    let mut max_connections = accept::DEFAULT_MAX_CONNECTIONS;
+    let mut remove_duplicate_high_scores = false;
+    let mut allowed_origins = vec![];
+    let mut loaded_translations = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            // Port 0 means any free port, which is handy for tests
+            "--port" => match args.next().and_then(|value| value.parse().ok()) {
+                Some(p) => port = p,
+                None => {
+                    eprintln!("--port must be followed by a port number");
+                    std::process::exit(2);
+                }
+            },
+            "--websocket-port" => match args.next().and_then(|value| value.parse().ok()) {
+                Some(p) => websocket_port = p,
+                None => {
+                    eprintln!("--websocket-port must be followed by a port number");
+                    std::process::exit(2);
+                }
+            },
+            "--admin-port" => match args.next().and_then(|value| value.parse().ok()) {
+                Some(port) => admin_port = Some(port),
+                None => {
+                    eprintln!("--admin-port must be followed by a port number");
+                    std::process::exit(2);
+                }
+            },
+            "--remove-duplicate-high-scores" => remove_duplicate_high_scores = true,
+            "--max-connections" => match args.next().and_then(|value| value.parse().ok()) {
+                Some(n) => max_connections = n,
+                None => {
+                    eprintln!("--max-connections must be followed by a number");
+                    std::process::exit(2);
+                }
+            },
+            // Load testing from one machine needs more than the default
+            "--max-connections-per-ip" => {
+                match args.next().and_then(|value| value.parse::<usize>().ok()) {
+                    Some(n) if n > 0 => {
+                        set_option_once(&ip_tracker::MAX_CONNECTIONS_PER_IP, n, &arg)
+                    }
+                    _ => {
+                        eprintln!("--max-connections-per-ip must be followed by a positive number");
+                        std::process::exit(2);
+                    }
+                }
+            }
+            "--lobby-grace-minutes" => {
+                match args
+                    .next()
+                    .and_then(|value| value.parse::<u64>().ok())
+                    .and_then(|n| n.checked_mul(60))
+                {
+                    Some(secs) => {
+                        set_option_once(&lobby::LOBBY_GRACE_PERIOD, Duration::from_secs(secs), &arg)
+                    }
+                    None => {
+                        eprintln!("--lobby-grace-minutes must be followed by a number");
+                        std::process::exit(2);
+                    }
+                }
+            }
+            "--max-game-minutes" => match args
+                .next()
+                .and_then(|value| value.parse::<u64>().ok())
+                .and_then(|n| n.checked_mul(60))
+            {
+                Some(secs) if secs > 0 => set_option_once(
+                    &game_wrapper::MAX_GAME_DURATION,
+                    Duration::from_secs(secs),
+                    &arg,
+                ),
+                _ => {
+                    eprintln!("--max-game-minutes must be followed by a positive number");
+                    std::process::exit(2);
+                }
+            },
+            "--log-format" => match args.next().as_deref() {
+                Some("text") => set_option_once(&logging::LOG_FORMAT, LogFormat::Text, &arg),
+                Some("json") => set_option_once(&logging::LOG_FORMAT, LogFormat::Json, &arg),
+                _ => {
+                    eprintln!("--log-format must be followed by text or json");
+                    std::process::exit(2);
+                }
+            },
+            "--lang" => {
+                let path = args.next().unwrap_or_default();
+                match strings::load_translations(&path) {
+                    Ok(translations) => {
+                        // Logged after parsing, because --log-format may come later
+                        loaded_translations = Some((translations.len(), path));
+                        set_option_once(&strings::TRANSLATIONS, translations, &arg);
+                    }
+                    Err(e) => {
+                        eprintln!("--lang must be followed by a translation file: {}", e);
+                        std::process::exit(2);
+                    }
+                }
+            }
+            // Can be given multiple times, each value is comma-separated
+            "--allowed-origins" => {
+                match connection::parse_allowed_origins(&args.next().unwrap_or_default()) {
+                    Ok(origins) => allowed_origins.extend(origins),
+                    Err(e) => {
+                        eprintln!("--allowed-origins must be followed by origins: {}", e);
+                        std::process::exit(2);
+                    }
+                }
+            }
+            "--debug-dump-key" => {
+                let value = args.next().unwrap_or_default();
+                let mut chars = value.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(ch), None) => set_option_once(&views::DEBUG_DUMP_KEY, ch, &arg),
+                    _ => {
+                        eprintln!("--debug-dump-key must be followed by a single character");
+                        std::process::exit(2);
+                    }
+                }
+            }
+            _ => {
+                eprintln!("unknown argument: {}", arg);
+                std::process::exit(2);
+            }
+        }
+    }
+
+    if let Some(ch) = views::DEBUG_DUMP_KEY.get() {
+        logging::log(
+            "game",
+            &format!("Pressing {:?} in a game logs the game state", ch),
+        );
+    }
+    if let Some((count, path)) = loaded_translations {
+        logging::log(
+            "strings",
+            &format!("Loaded {} translated texts from {}", count, path),
+        );
+    }
+    if allowed_origins.is_empty() {
+        // Empty means not set, like CATRIS_WEBSOCKET_PROXY_IP
+        match std::env::var("CATRIS_ALLOWED_ORIGINS").as_deref() {
+            Err(_) | Ok("") => {}
+            Ok(value) => match connection::parse_allowed_origins(value) {
+                Ok(origins) => allowed_origins = origins,
+                Err(e) => {
+                    eprintln!("bad CATRIS_ALLOWED_ORIGINS: {}", e);
+                    std::process::exit(2);
+                }
+            },
+        }
+    }
+    if !allowed_origins.is_empty() {
+        logging::log(
+            "connection",
+            &format!("Allowed websocket origins: {}", allowed_origins.join(", ")),
+        );
+        connection::ALLOWED_ORIGINS.set(allowed_origins).unwrap();
+    }
+    Args {
+        port,
+        websocket_port,
+        admin_port,
+        remove_duplicate_high_scores,
+        max_connections,
+    }
+}
+
+// Usage: catris check-highscores [--fix]
+async fn check_high_scores_and_exit() -> ! {
+    let fix = match std::env::args().skip(2).collect::<Vec<_>>().as_slice() {
+        [] => false,
+        [flag] if flag == "--fix" => true,
+        _ => {
+            eprintln!("usage: catris check-highscores [--fix]");
+            std::process::exit(2);
+        }
+    };
+    match high_scores::check_high_scores(fix).await {
+        Ok(report) => {
+            for line in report.summary_lines() {
+                println!("{}", line);
+            }
+            if report.corrupt_lines.is_empty() || report.fixed {
+                std::process::exit(0);
+            }
+            println!("Run again with --fix to remove the corrupt lines");
+            std::process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("Checking high scores failed: {}", e);
+            std::process::exit(1);
+        }
+    }
 }
 
 #[tokio::main]
 async fn main() {
+    if std::env::args().nth(1).as_deref() == Some("check-highscores") {
+        check_high_scores_and_exit().await;
+    }
+    let args = parse_args();
+    if args.remove_duplicate_high_scores {
+        match high_scores::remove_duplicates().await {
+            Ok(count) => logging::log(
+                "high scores",
+                &format!("Removed {} duplicate high scores", count),
+            ),
+            Err(e) => {
+                eprintln!("Removing duplicate high scores failed: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if let Err(e) = prefs::load_secret() {
+        eprintln!("Loading the preferences secret key failed: {}", e);
+        std::process::exit(1);
+    }
+
+    let registry = Arc::new(ClientRegistry::new());
+    tokio::spawn(admin::check_motd_periodically(registry.clone()));
+    if let Some(port) = args.admin_port {
+        tokio::spawn(admin::listen_for_admin_connections(registry.clone(), port));
+    }
+
     let used_names = Arc::new(Mutex::new(HashSet::new()));
+    let name_metrics = Arc::new(Mutex::new(NameMetrics::new()));
+    tokio::spawn(log_name_metrics_periodically(name_metrics.clone()));
     let lobbies: lobby::Lobbies = Arc::new(Mutex::new(WeakValueHashMap::new()));
     let ip_tracker = Arc::new(Mutex::new(IpTracker::new()));
 
-    let raw_listener = TcpListener::bind("0.0.0.0:12345").await.unwrap();
-    println!("Listening for raw TCP connections on port 12345...");
+    let raw_listener = TcpListener::bind(("0.0.0.0", args.port)).await.unwrap();
+    println!(
+        "Listening for raw TCP connections on port {}...",
+        raw_listener.local_addr().unwrap().port()
+    );
 
     let ws_listener;
     if let Some(proxy_ip) = get_websocket_proxy_ip() {
         // In production, avoid unnecessary listening.
-        ws_listener = TcpListener::bind((proxy_ip, 54321)).await.unwrap();
+        ws_listener = TcpListener::bind((proxy_ip, args.websocket_port))
+            .await
+            .unwrap();
         println!(
-            "Listening for websocket connections on port 54321 (only from {})...",
+            "Listening for websocket connections on port {} (only from {})...",
+            ws_listener.local_addr().unwrap().port(),
             proxy_ip
         );
     } else {
         // Allow connections from anywhere. Needed for local-playing.md
-        ws_listener = TcpListener::bind("0.0.0.0:54321").await.unwrap();
-        println!("Listening for websocket connections on port 54321...");
+        ws_listener = TcpListener::bind(("0.0.0.0", args.websocket_port))
+            .await
+            .unwrap();
+        println!(
+            "Listening for websocket connections on port {}...",
+            ws_listener.local_addr().unwrap().port()
+        );
     }
 
-    loop {
-        tokio::select! {
-            result = raw_listener.accept() => {
-                let (socket, sockaddr) = result.unwrap();
-                tokio::spawn(handle_connection(
-                    socket,
-                    sockaddr.ip(),
-                    lobbies.clone(),
-                    used_names.clone(),
-                    ip_tracker.clone(),
-                    false,
-                ));
-            }
-            result = ws_listener.accept() => {
-                let (socket, sockaddr) = result.unwrap();
-                tokio::spawn(handle_connection(
-                    socket,
-                    sockaddr.ip(),
-                    lobbies.clone(),
-                    used_names.clone(),
-                    ip_tracker.clone(),
-                    true,
-                ));
-            }
+    let spawn_handler = |is_websocket: bool| {
+        let lobbies = lobbies.clone();
+        let used_names = used_names.clone();
+        let name_metrics = name_metrics.clone();
+        let ip_tracker = ip_tracker.clone();
+        let registry = registry.clone();
+        move |socket: TcpStream, sockaddr: SocketAddr, slot: ConnectionSlot| {
+            tokio::spawn(handle_connection(
+                socket,
+                sockaddr.ip(),
+                lobbies.clone(),
+                used_names.clone(),
+                name_metrics.clone(),
+                ip_tracker.clone(),
+                registry.clone(),
+                is_websocket,
+                slot,
+            ));
         }
-    }
+    };
+
+    let limit = Arc::new(ConnectionLimit::new(args.max_connections));
+    tokio::join!(
+        accept_forever(
+            raw_listener,
+            limit.clone(),
+            accept::RAW_TCP_FULL_MESSAGE,
+            spawn_handler(false),
+        ),
+        accept_forever(
+            ws_listener,
+            limit,
+            accept::WEBSOCKET_FULL_MESSAGE,
+            spawn_handler(true),
+        ),
+    );
 }
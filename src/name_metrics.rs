@@ -0,0 +1,64 @@
+// Counts what happens when clients enter their names.
+// Helps with figuring out whether bots are hammering the name prompt.
+
+#[derive(Debug, Clone, Copy)]
+pub enum NameEvent {
+    Empty,
+    InvalidChar,
+    TooLong, // more than 15 characters typed, the rest were ignored
+    InUse,
+    Registered,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct NameCounts {
+    pub empty: u64,
+    pub invalid_char: u64,
+    pub too_long: u64,
+    pub in_use: u64,
+    pub registered: u64,
+}
+
+impl NameCounts {
+    fn summary(&self) -> String {
+        format!(
+            "{} empty, {} invalid char, {} too long, {} in use, {} registered",
+            self.empty, self.invalid_char, self.too_long, self.in_use, self.registered
+        )
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct NameMetrics {
+    pub raw_tcp: NameCounts,
+    pub websocket: NameCounts,
+}
+
+impl NameMetrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&mut self, is_websocket: bool, event: NameEvent) {
+        let counts = if is_websocket {
+            &mut self.websocket
+        } else {
+            &mut self.raw_tcp
+        };
+        match event {
+            NameEvent::Empty => counts.empty += 1,
+            NameEvent::InvalidChar => counts.invalid_char += 1,
+            NameEvent::TooLong => counts.too_long += 1,
+            NameEvent::InUse => counts.in_use += 1,
+            NameEvent::Registered => counts.registered += 1,
+        }
+    }
+
+    pub fn summary(&self) -> String {
+        format!(
+            "raw TCP: {} / websocket: {}",
+            self.raw_tcp.summary(),
+            self.websocket.summary()
+        )
+    }
+}
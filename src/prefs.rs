@@ -0,0 +1,540 @@
+/*
+Settings of players who chose a settings passphrase. Raw TCP clients can't
+store anything on the player's computer, so we store it for them.
+
+There are no accounts. Anyone who enters the same name and passphrase gets
+the same settings, and that's fine, because settings aren't secret. The file
+contains a keyed hash (HMAC) of the name and passphrase, not the passphrase
+itself, because people tend to reuse their passwords. The key is in a separate
+secret file, so the hashes are useless to anyone who only gets the prefs file.
+
+The file has a size limit. When it fills up, the entries that haven't been
+used for the longest time are deleted.
+*/
+use crate::client::Client;
+use crate::logging;
+use sha1::Digest;
+use sha1::Sha1;
+use std::fmt::Write;
+use std::fs;
+use std::io::ErrorKind;
+use std::sync::OnceLock;
+
+type AnyErrorThreadSafe = Box<dyn std::error::Error + Send + Sync>;
+
+const HEADER: &str = "catris preferences file";
+const SECRET_FILENAME: &str = "catris_preferences_secret.txt";
+const MAX_ENTRY_LEN: usize = 200; // for the settings part of a line
+const MAX_FILE_SIZE: usize = 1_000_000;
+
+// Makes guessing passphrases from the hashes slower
+const HASH_ROUNDS: usize = 1000;
+
+fn log(message: &str) {
+    logging::log("prefs", message);
+}
+
+static SECRET: OnceLock<Vec<u8>> = OnceLock::new();
+
+// Creates the file with a random secret when it doesn't exist yet
+fn read_or_create_secret(filename: &str) -> Result<Vec<u8>, AnyErrorThreadSafe> {
+    match fs::read_to_string(filename) {
+        Ok(content) if !content.trim().is_empty() => Ok(content.trim().as_bytes().to_vec()),
+        Ok(_) => Err(format!("{} is empty", filename).into()),
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            let secret = format!("{:032x}", rand::random::<u128>());
+            fs::write(filename, format!("{}\n", secret))?;
+            log(&format!("Created a new secret key into {}", filename));
+            Ok(secret.into_bytes())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Call this once when the server starts
+pub fn load_secret() -> Result<(), AnyErrorThreadSafe> {
+    let secret = read_or_create_secret(SECRET_FILENAME)?;
+    SECRET
+        .set(secret)
+        .map_err(|_| "secret key loaded twice".into())
+}
+
+fn get_secret() -> Result<&'static [u8], AnyErrorThreadSafe> {
+    match SECRET.get() {
+        Some(secret) => Ok(secret),
+        None => Err("secret key not loaded".into()),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Prefs {
+    pub prefer_rotating_counter_clockwise: bool,
+    pub other_keys_cancel_soft_drop: bool,
+    pub bell_enabled: bool,
+    pub auto_shift_enabled: bool,
+    pub reduced_flashing: bool,
+}
+
+// What new clients get
+impl Default for Prefs {
+    fn default() -> Self {
+        Self {
+            prefer_rotating_counter_clockwise: false,
+            other_keys_cancel_soft_drop: true,
+            bell_enabled: true,
+            auto_shift_enabled: false, // opt-in, changes how held keys behave
+            reduced_flashing: false,
+        }
+    }
+}
+
+impl Prefs {
+    pub fn from_client(client: &Client) -> Self {
+        Self {
+            prefer_rotating_counter_clockwise: client.prefer_rotating_counter_clockwise,
+            other_keys_cancel_soft_drop: client.other_keys_cancel_soft_drop,
+            bell_enabled: client.bell_enabled,
+            auto_shift_enabled: client.auto_shift_enabled,
+            reduced_flashing: client.reduced_flashing,
+        }
+    }
+
+    pub fn apply_to_client(self, client: &mut Client) {
+        client.prefer_rotating_counter_clockwise = self.prefer_rotating_counter_clockwise;
+        client.other_keys_cancel_soft_drop = self.other_keys_cancel_soft_drop;
+        client.bell_enabled = self.bell_enabled;
+        client.auto_shift_enabled = self.auto_shift_enabled;
+        client.reduced_flashing = self.reduced_flashing;
+    }
+
+    fn fields(self) -> [(&'static str, bool); 5] {
+        [
+            ("counter_clockwise", self.prefer_rotating_counter_clockwise),
+            ("cancel_soft_drop", self.other_keys_cancel_soft_drop),
+            ("bell", self.bell_enabled),
+            ("auto_shift", self.auto_shift_enabled),
+            ("reduced_flashing", self.reduced_flashing),
+        ]
+    }
+
+    // e.g. "counter_clockwise=0 cancel_soft_drop=1 bell=1 auto_shift=1 reduced_flashing=0"
+    fn to_text(self) -> String {
+        let parts: Vec<String> = self
+            .fields()
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, *value as u8))
+            .collect();
+        parts.join(" ")
+    }
+
+    // Settings missing from the text get their default values
+    fn from_text(text: &str) -> Option<Self> {
+        let mut result = Self::default();
+        for part in text.split(' ') {
+            let (key, value) = part.split_once('=')?;
+            let value = match value {
+                "0" => false,
+                "1" => true,
+                _ => return None,
+            };
+            match key {
+                "counter_clockwise" => result.prefer_rotating_counter_clockwise = value,
+                "cancel_soft_drop" => result.other_keys_cancel_soft_drop = value,
+                "bell" => result.bell_enabled = value,
+                "auto_shift" => result.auto_shift_enabled = value,
+                "reduced_flashing" => result.reduced_flashing = value,
+                _ => {} // from a newer version of catris
+            }
+        }
+        Some(result)
+    }
+}
+
+struct Entry {
+    key: String,
+    last_used: i64, // unix timestamp
+    text: String,
+}
+
+impl Entry {
+    fn to_line(&self) -> String {
+        format!("{}\t{}\t{}\n", self.key, self.last_used, self.text)
+    }
+}
+
+struct PrefsFile {
+    entries: Vec<Entry>,
+}
+
+// https://en.wikipedia.org/wiki/HMAC
+fn hmac_sha1(key: &[u8], message: &[u8]) -> [u8; 20] {
+    const BLOCK_SIZE: usize = 64;
+    let mut padded_key = [0u8; BLOCK_SIZE];
+    if key.len() > BLOCK_SIZE {
+        padded_key[..20].copy_from_slice(&Sha1::digest(key));
+    } else {
+        padded_key[..key.len()].copy_from_slice(key);
+    }
+
+    let inner_key = padded_key.map(|byte| byte ^ 0x36);
+    let outer_key = padded_key.map(|byte| byte ^ 0x5c);
+    let inner = Sha1::new()
+        .chain_update(inner_key)
+        .chain_update(message)
+        .finalize();
+    Sha1::new()
+        .chain_update(outer_key)
+        .chain_update(inner)
+        .finalize()
+        .into()
+}
+
+fn hash_key(secret: &[u8], name: &str, passphrase: &str) -> String {
+    // Names are case-insensitive, see Client::set_name()
+    let message = format!("{}\0{}", name.to_lowercase(), passphrase);
+    let mut hash = hmac_sha1(secret, message.as_bytes());
+    for _ in 0..HASH_ROUNDS {
+        hash = hmac_sha1(secret, &hash);
+    }
+
+    let mut result = String::new();
+    for byte in hash {
+        write!(result, "{:02x}", byte).unwrap();
+    }
+    result
+}
+
+fn read_file(filename: &str) -> Result<PrefsFile, AnyErrorThreadSafe> {
+    let content = match fs::read_to_string(filename) {
+        Ok(content) => content,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(PrefsFile { entries: vec![] }),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut lines = content.lines();
+    if lines.next() != Some(HEADER) {
+        return Err(format!("{} doesn't start with {:?}", filename, HEADER).into());
+    }
+
+    let mut entries = vec![];
+    for line in lines {
+        let parts: Vec<&str> = line.split('\t').collect();
+        match parts.as_slice() {
+            [key, last_used, text] if text.len() <= MAX_ENTRY_LEN => {
+                if let Ok(last_used) = last_used.parse() {
+                    entries.push(Entry {
+                        key: key.to_string(),
+                        last_used,
+                        text: text.to_string(),
+                    });
+                    continue;
+                }
+            }
+            _ => {}
+        }
+        log(&format!("Ignoring bad line in {}: {:?}", filename, line));
+    }
+    Ok(PrefsFile { entries })
+}
+
+fn write_file(filename: &str, file: &PrefsFile) -> Result<(), AnyErrorThreadSafe> {
+    let mut content = format!("{}\n", HEADER);
+    for entry in &file.entries {
+        content.push_str(&entry.to_line());
+    }
+    fs::write(filename, content)?;
+    Ok(())
+}
+
+fn load_from_file(
+    filename: &str,
+    secret: &[u8],
+    name: &str,
+    passphrase: &str,
+    now: i64,
+) -> Result<Option<Prefs>, AnyErrorThreadSafe> {
+    let mut file = read_file(filename)?;
+    let key = hash_key(secret, name, passphrase);
+    let Some(entry) = file.entries.iter_mut().find(|e| e.key == key) else {
+        return Ok(None);
+    };
+
+    let result = Prefs::from_text(&entry.text);
+    // Remember that this entry is still in use, so it won't get evicted
+    entry.last_used = now;
+    write_file(filename, &file)?;
+    Ok(result)
+}
+
+fn store_to_file(
+    filename: &str,
+    secret: &[u8],
+    name: &str,
+    passphrase: &str,
+    prefs: Prefs,
+    now: i64,
+    max_file_size: usize,
+) -> Result<(), AnyErrorThreadSafe> {
+    let mut file = read_file(filename)?;
+    let key = hash_key(secret, name, passphrase);
+    file.entries.retain(|e| e.key != key);
+    file.entries.push(Entry {
+        key,
+        last_used: now,
+        text: prefs.to_text(),
+    });
+
+    // Least recently used first
+    file.entries.sort_by_key(|e| e.last_used);
+    let mut size = HEADER.len() + 1;
+    size += file
+        .entries
+        .iter()
+        .map(|e| e.to_line().len())
+        .sum::<usize>();
+    while size > max_file_size && file.entries.len() > 1 {
+        size -= file.entries.remove(0).to_line().len();
+    }
+    write_file(filename, &file)
+}
+
+// File name stored here so I won't forget to use this
+lazy_static! {
+    static ref FILE_LOCK: tokio::sync::Mutex<&'static str> =
+        tokio::sync::Mutex::new("catris_preferences.txt");
+}
+
+pub async fn load(name: &str, passphrase: &str) -> Result<Option<Prefs>, AnyErrorThreadSafe> {
+    let secret = get_secret()?;
+    let filename_handle = FILE_LOCK.lock().await;
+    let name = name.to_string();
+    let passphrase = passphrase.to_string();
+    let now = chrono::Utc::now().timestamp();
+    tokio::task::spawn_blocking(move || {
+        load_from_file(*filename_handle, secret, &name, &passphrase, now)
+    })
+    .await?
+}
+
+pub async fn store(name: &str, passphrase: &str, prefs: Prefs) -> Result<(), AnyErrorThreadSafe> {
+    let secret = get_secret()?;
+    let filename_handle = FILE_LOCK.lock().await;
+    let name = name.to_string();
+    let passphrase = passphrase.to_string();
+    let now = chrono::Utc::now().timestamp();
+    tokio::task::spawn_blocking(move || {
+        store_to_file(
+            *filename_handle,
+            secret,
+            &name,
+            &passphrase,
+            prefs,
+            now,
+            MAX_FILE_SIZE,
+        )
+    })
+    .await?
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const TEST_SECRET: &[u8] = b"test secret";
+
+    fn temp_filename(tempdir: &tempfile::TempDir) -> String {
+        tempdir
+            .path()
+            .join("prefs.txt")
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    fn custom_prefs() -> Prefs {
+        Prefs {
+            prefer_rotating_counter_clockwise: true,
+            other_keys_cancel_soft_drop: false,
+            bell_enabled: false,
+            auto_shift_enabled: true,
+            reduced_flashing: true,
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let filename = temp_filename(&tempdir);
+
+        store_to_file(
+            &filename,
+            TEST_SECRET,
+            "Foo",
+            "secret",
+            custom_prefs(),
+            100,
+            1000,
+        )
+        .unwrap();
+        assert_eq!(
+            load_from_file(&filename, TEST_SECRET, "Foo", "secret", 200).unwrap(),
+            Some(custom_prefs())
+        );
+        // Names are case-insensitive, just like when checking if a name is in use
+        assert_eq!(
+            load_from_file(&filename, TEST_SECRET, "foo", "secret", 300).unwrap(),
+            Some(custom_prefs())
+        );
+
+        let content = fs::read_to_string(&filename).unwrap();
+        assert!(content.starts_with(HEADER));
+        assert!(!content.contains("secret"));
+        assert!(!content.contains("Foo"));
+        assert!(content.contains("\t300\t"));
+    }
+
+    #[test]
+    fn test_wrong_passphrase_loads_nothing() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let filename = temp_filename(&tempdir);
+
+        assert_eq!(
+            load_from_file(&filename, TEST_SECRET, "Foo", "secret", 100).unwrap(),
+            None
+        );
+        store_to_file(
+            &filename,
+            TEST_SECRET,
+            "Foo",
+            "secret",
+            custom_prefs(),
+            100,
+            1000,
+        )
+        .unwrap();
+        assert_eq!(
+            load_from_file(&filename, TEST_SECRET, "Foo", "Secret", 200).unwrap(),
+            None
+        );
+        assert_eq!(
+            load_from_file(&filename, TEST_SECRET, "Bar", "secret", 200).unwrap(),
+            None
+        );
+        // Unlike a plain concatenation, the separator keeps these apart
+        assert_eq!(
+            load_from_file(&filename, TEST_SECRET, "Foos", "ecret", 200).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_hmac_sha1() {
+        // Test cases 1 and 6 from RFC 2202
+        let hex =
+            |bytes: [u8; 20]| -> String { bytes.iter().map(|b| format!("{:02x}", b)).collect() };
+        assert_eq!(
+            hex(hmac_sha1(&[0x0b; 20], b"Hi There")),
+            "b617318655057264e28bc0b6fb378c8ef146be00"
+        );
+        assert_eq!(
+            hex(hmac_sha1(
+                &[0xaa; 80],
+                b"Test Using Larger Than Block-Size Key - Hash Key First"
+            )),
+            "aa4ae5e15272d00e95705637ce8a3b55ed402112"
+        );
+    }
+
+    #[test]
+    fn test_other_secret_loads_nothing() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let filename = temp_filename(&tempdir);
+
+        store_to_file(
+            &filename,
+            TEST_SECRET,
+            "Foo",
+            "secret",
+            custom_prefs(),
+            100,
+            1000,
+        )
+        .unwrap();
+        assert_eq!(
+            load_from_file(&filename, b"other secret", "Foo", "secret", 200).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_secret_is_created_once() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let filename = tempdir
+            .path()
+            .join("secret.txt")
+            .to_str()
+            .unwrap()
+            .to_string();
+
+        let secret = read_or_create_secret(&filename).unwrap();
+        assert_eq!(secret.len(), 32);
+        assert_eq!(read_or_create_secret(&filename).unwrap(), secret);
+
+        fs::write(&filename, "\n").unwrap();
+        assert!(read_or_create_secret(&filename).is_err());
+    }
+
+    #[test]
+    fn test_least_recently_used_entries_get_evicted() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let filename = temp_filename(&tempdir);
+
+        // Room for the header and 2 entries
+        store_to_file(&filename, TEST_SECRET, "A", "x", custom_prefs(), 100, 1000).unwrap();
+        let entry_size = fs::read_to_string(&filename)
+            .unwrap()
+            .lines()
+            .nth(1)
+            .unwrap()
+            .len()
+            + 1;
+        let header_size = fs::metadata(&filename).unwrap().len() as usize - entry_size;
+        let max_size = header_size + 2 * entry_size;
+
+        store_to_file(
+            &filename,
+            TEST_SECRET,
+            "B",
+            "x",
+            custom_prefs(),
+            200,
+            max_size,
+        )
+        .unwrap();
+        assert!(load_from_file(&filename, TEST_SECRET, "A", "x", 300)
+            .unwrap()
+            .is_some());
+        store_to_file(
+            &filename,
+            TEST_SECRET,
+            "C",
+            "x",
+            custom_prefs(),
+            400,
+            max_size,
+        )
+        .unwrap();
+
+        // B was used least recently, because loading A counts as using it
+        assert!(load_from_file(&filename, TEST_SECRET, "A", "x", 500)
+            .unwrap()
+            .is_some());
+        assert!(load_from_file(&filename, TEST_SECRET, "B", "x", 500)
+            .unwrap()
+            .is_none());
+        assert!(load_from_file(&filename, TEST_SECRET, "C", "x", 500)
+            .unwrap()
+            .is_some());
+        assert_eq!(fs::metadata(&filename).unwrap().len() as usize, max_size);
+    }
+}
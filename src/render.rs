@@ -1,10 +1,91 @@
 use crate::escapes::Color;
 use crate::escapes::TerminalType;
-use std::sync::Arc;
-use tokio::sync::Notify;
+use crate::game_logic::blocks::SquareCanvas;
+use crate::game_wrapper::SoundEvent;
+use std::ops::Deref;
+use std::ops::DerefMut;
+use std::sync::LockResult;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+use std::sync::PoisonError;
+use std::time::Duration;
+use std::time::Instant;
+use tokio::sync::watch;
+
+// Redrawing everything sends a lot of data, so clients can't request it too often
+const MIN_TIME_BETWEEN_REQUESTED_REDRAWS: Duration = Duration::from_millis(500);
+
+fn markup_color(opening_marker: char) -> Color {
+    match opening_marker {
+        '[' => Color::MAGENTA_FOREGROUND,
+        '{' => Color::CYAN_FOREGROUND,
+        _ => unreachable!(),
+    }
+}
+
+/*
+Text in [brackets] or {braces} is colored, and markers can be nested. When
+a marker closes, the color goes back to what it was outside the marker.
+Markers that aren't closed continue to the next line, so the caller keeps
+open_markers between lines. Closing markers that weren't opened are ignored.
+*/
+pub fn parse_styled_line(line: &str, open_markers: &mut Vec<char>) -> Vec<(String, Color)> {
+    let mut spans: Vec<(String, Color)> = vec![];
+    for ch in line.chars() {
+        match ch {
+            '[' | '{' => open_markers.push(ch),
+            ']' | '}' => {
+                let opening = if ch == ']' { '[' } else { '{' };
+                if let Some(i) = open_markers.iter().rposition(|m| *m == opening) {
+                    open_markers.truncate(i);
+                }
+            }
+            ch => {
+                let color = open_markers
+                    .last()
+                    .map(|m| markup_color(*m))
+                    .unwrap_or(Color::DEFAULT);
+                match spans.last_mut() {
+                    Some((text, span_color)) if *span_color == color => text.push(ch),
+                    _ => spans.push((ch.to_string(), color)),
+                }
+            }
+        }
+    }
+    spans
+}
+
+/*
+Splits styled text into lines of at most max_width visible characters, so
+markers don't count. Empty lines separate paragraphs, and other line breaks
+become spaces, so texts that are already wrapped can be wrapped again. A
+word that doesn't fit on a line by itself goes on a line of its own.
+*/
+pub fn wrap_styled_text(text: &str, max_width: usize) -> Vec<String> {
+    let visible_width = |s: &str| s.chars().filter(|c| !"[]{}".contains(*c)).count();
+    let mut lines: Vec<String> = vec![];
+    for (i, paragraph) in text.split("\n\n").enumerate() {
+        if i != 0 {
+            lines.push("".to_string());
+        }
+        let mut line = String::new();
+        for word in paragraph.split_whitespace() {
+            if !line.is_empty() && visible_width(&line) + 1 + visible_width(word) > max_width {
+                lines.push(std::mem::take(&mut line));
+            }
+            if !line.is_empty() {
+                line.push(' ');
+            }
+            line.push_str(word);
+        }
+        lines.push(line);
+    }
+    lines
+}
 
 pub struct RenderBuffer {
     pub terminal_type: TerminalType,
+    pub low_bandwidth: bool, // no colors or animations, for clients with slow connections
     pub width: usize,
     pub height: usize,
     chars: Vec<Vec<char>>,
@@ -15,6 +96,7 @@ impl RenderBuffer {
     pub fn new(terminal_type: TerminalType) -> Self {
         Self {
             terminal_type,
+            low_bandwidth: false,
             width: 0,
             height: 0,
             chars: vec![],
@@ -47,6 +129,10 @@ impl RenderBuffer {
         self.height = height;
     }
 
+    pub fn has_color(&self) -> bool {
+        self.terminal_type.has_color() && !self.low_bandwidth
+    }
+
     pub fn get_char(&self, x: usize, y: usize) -> char {
         self.chars[y][x]
     }
@@ -67,15 +153,38 @@ impl RenderBuffer {
     pub fn add_text(&mut self, x: usize, y: usize, text: &str) -> usize {
         self.add_text_with_color(x, y, text, Color::DEFAULT)
     }
+    // Text that doesn't fit is clipped at the right edge of the buffer
     pub fn add_text_with_color(&mut self, x: usize, y: usize, text: &str, color: Color) -> usize {
         let mut x = x;
+        if y >= self.height {
+            return x;
+        }
         for ch in text.chars() {
+            if x >= self.width {
+                break;
+            }
+            // Control characters would mess up the terminal, e.g. escape sequences
+            let ch = if ch.is_control() { '?' } else { ch };
             self.set_char_with_color(x, y, ch, color);
             x += 1;
         }
         x
     }
 
+    // See parse_styled_line()
+    pub fn add_styled_text(
+        &mut self,
+        mut x: usize,
+        y: usize,
+        line: &str,
+        open_markers: &mut Vec<char>,
+    ) -> usize {
+        for (text, color) in parse_styled_line(line, open_markers) {
+            x = self.add_text_with_color(x, y, &text, color);
+        }
+        x
+    }
+
     // does not change background colors
     pub fn add_text_with_foreground_color(
         &mut self,
@@ -85,7 +194,13 @@ impl RenderBuffer {
         fg: u8,
     ) -> usize {
         let mut x = x;
+        if y >= self.height {
+            return x;
+        }
         for ch in text.chars() {
+            if x >= self.width {
+                break;
+            }
             self.colors[y][x].fg = fg;
             self.chars[y][x] = ch;
             x += 1;
@@ -131,6 +246,7 @@ impl RenderBuffer {
 
     pub fn copy_into(&self, dest: &mut RenderBuffer) {
         dest.resize(self.width, self.height);
+        dest.low_bandwidth = self.low_bandwidth;
         for y in 0..self.height {
             for x in 0..self.width {
                 dest.chars[y][x] = self.chars[y][x];
@@ -148,7 +264,7 @@ impl RenderBuffer {
         for y in 0..self.height {
             result.push_str(&self.terminal_type.move_cursor(0, y));
             for x in 0..self.width {
-                if self.colors[y][x] != current_color && self.terminal_type.has_color() {
+                if self.colors[y][x] != current_color && self.has_color() {
                     current_color = self.colors[y][x];
                     result.push_str(&self.terminal_type.format_color(current_color));
                 }
@@ -171,11 +287,16 @@ impl RenderBuffer {
             Some((_, y)) => y,
             None => self.height - 1,
         };
+        // Without colors, changing only the color doesn't change what the terminal shows
+        let has_color = self.has_color();
 
         for y in 0..self.height {
             // Output nothing for unchanged lines, but consider cursor line potentially changed.
             // This way we wipe away the character typed by user.
-            if self.chars[y] == old.chars[y] && self.colors[y] == old.colors[y] && y != cursor_y {
+            if self.chars[y] == old.chars[y]
+                && (!has_color || self.colors[y] == old.colors[y])
+                && y != cursor_y
+            {
                 continue;
             }
 
@@ -183,7 +304,7 @@ impl RenderBuffer {
             let mut end = self.width;
             while end > 0
                 && self.chars[y][end - 1] == ' '
-                && self.colors[y][end - 1] == Color::DEFAULT
+                && (!has_color || self.colors[y][end - 1] == Color::DEFAULT)
             {
                 end -= 1;
             }
@@ -191,7 +312,9 @@ impl RenderBuffer {
             let mut current_color = Color::DEFAULT;
             let mut cursor_at_xy = false;
             for x in 0..end {
-                if self.colors[y][x] == old.colors[y][x] && self.chars[y][x] == old.chars[y][x] {
+                if (!has_color || self.colors[y][x] == old.colors[y][x])
+                    && self.chars[y][x] == old.chars[y][x]
+                {
                     // skip redrawing this charater
                     cursor_at_xy = false;
                 } else {
@@ -199,7 +322,7 @@ impl RenderBuffer {
                         result.push_str(&self.terminal_type.move_cursor(x, y));
                         cursor_at_xy = true;
                     }
-                    if self.colors[y][x] != current_color && self.terminal_type.has_color() {
+                    if self.colors[y][x] != current_color && has_color {
                         result.push_str(&self.terminal_type.format_color(self.colors[y][x]));
                         current_color = self.colors[y][x];
                     }
@@ -223,6 +346,10 @@ impl RenderBuffer {
         cursor_pos: Option<(usize, usize)>,
         force_redraw: bool,
     ) -> String {
+        // A cursor outside the screen can make the terminal scroll, and then
+        // the screen no longer matches what we think it contains
+        let cursor_pos = cursor_pos.map(|(x, y)| (x.min(self.width - 1), y.min(self.height - 1)));
+
         let mut result = if self.width != old.width || self.height != old.height || force_redraw {
             self.clear_and_render_entire_screen()
         } else {
@@ -244,17 +371,766 @@ impl RenderBuffer {
     }
 }
 
+impl SquareCanvas for RenderBuffer {
+    fn has_color(&self) -> bool {
+        RenderBuffer::has_color(self)
+    }
+    fn low_bandwidth(&self) -> bool {
+        self.low_bandwidth
+    }
+    fn set_char_with_color(&mut self, x: usize, y: usize, ch: char, color: Color) {
+        RenderBuffer::set_char_with_color(self, x, y, ch, color);
+    }
+    fn add_text_with_color(&mut self, x: usize, y: usize, text: &str, color: Color) {
+        RenderBuffer::add_text_with_color(self, x, y, text, color);
+    }
+}
+
 pub struct RenderData {
     pub buffer: RenderBuffer,
     pub cursor_pos: Option<(usize, usize)>,
-    pub changed: Arc<Notify>,
-    pub force_redraw: bool,
+    changed: bool,          // sending task gets notified when the lock is released
+    pub force_redraw: bool, // not rate limited, unlike request_redraw()
+    // Shown on top of whatever view is active, one line each, e.g. ("admin", "Restarting soon")
+    pub banners: Vec<(&'static str, String)>,
+    // The motd banner is dimmed during games, so that it doesn't distract players too much
+    pub dim_motd_banner: bool,
+    // BEL characters to send with the next frame, not part of the buffer so they don't get diffed away
+    pub pending_bells: usize,
+    // Sent out of band to websocket clients that asked for them, see connection.rs
+    pub pending_sound_events: Vec<SoundEvent>,
+    redraw_requested: bool,
+    last_requested_redraw: Option<Instant>,
+    pub coalesced_redraw_requests: usize, // requests that got merged into another redraw
 }
 
 impl RenderData {
+    pub fn new(terminal_type: TerminalType) -> Self {
+        Self {
+            buffer: RenderBuffer::new(terminal_type),
+            cursor_pos: None,
+            changed: false,
+            force_redraw: false,
+            banners: vec![],
+            dim_motd_banner: false,
+            pending_bells: 0,
+            pending_sound_events: vec![],
+            redraw_requested: false,
+            last_requested_redraw: None,
+            coalesced_redraw_requests: 0,
+        }
+    }
+
+    // Call this after changing something, so that it gets sent to the client
+    pub fn mark_changed(&mut self) {
+        self.changed = true;
+    }
+
+    // For when the client asks for a redraw, e.g. by pressing Ctrl+R
+    pub fn request_redraw(&mut self) {
+        if self.redraw_requested {
+            self.coalesced_redraw_requests += 1;
+        }
+        self.redraw_requested = true;
+        self.mark_changed();
+    }
+
+    // If a requested redraw has to wait, this returns when it can be done
+    pub fn get_requested_redraw_time(&self) -> Option<Instant> {
+        if !self.redraw_requested {
+            return None;
+        }
+        Some(match self.last_requested_redraw {
+            Some(last) => last + MIN_TIME_BETWEEN_REQUESTED_REDRAWS,
+            None => Instant::now(),
+        })
+    }
+
+    // Called for each frame, returns whether to redraw everything
+    pub fn take_redraw(&mut self, now: Instant) -> bool {
+        let mut result = self.force_redraw;
+        self.force_redraw = false;
+        let allowed = match self.last_requested_redraw {
+            Some(last) => now >= last + MIN_TIME_BETWEEN_REQUESTED_REDRAWS,
+            None => true,
+        };
+        if self.redraw_requested && allowed {
+            self.redraw_requested = false;
+            self.last_requested_redraw = Some(now);
+            result = true;
+        }
+        result
+    }
+
     pub fn clear(&mut self, width: usize, height: usize) {
         self.buffer.clear();
         self.buffer.resize(width, height);
         self.cursor_pos = None;
+        self.dim_motd_banner = false;
+    }
+
+    // None removes the banner
+    pub fn set_banner(&mut self, source: &'static str, text: Option<String>) {
+        let old_index = self.banners.iter().position(|(s, _)| *s == source);
+        match (old_index, text) {
+            (Some(i), Some(text)) if self.banners[i].1 == text => return,
+            (Some(i), Some(text)) => self.banners[i].1 = text,
+            (Some(i), None) => {
+                self.banners.remove(i);
+            }
+            (None, Some(text)) => self.banners.push((source, text)),
+            (None, None) => return,
+        }
+        self.mark_changed();
+    }
+
+    // Called for each frame before it is sent
+    pub fn add_banners(&self, buffer: &mut RenderBuffer) {
+        for (y, (source, text)) in self.banners.iter().enumerate() {
+            if y < buffer.height {
+                let color = if *source == "motd" && self.dim_motd_banner {
+                    Color::DIM_BLACK_ON_WHITE
+                } else {
+                    Color::BLACK_ON_WHITE
+                };
+                buffer.fill_row_with_char(y, ' ');
+                buffer.set_row_color(y, color);
+                buffer.add_centered_text_with_color(y, text, color);
+            }
+        }
+    }
+}
+
+/*
+Render data is shared by the task that renders views and the task that sends
+frames to the client. The sending task is notified only after the lock is
+released, so that it doesn't wake up just to wait for the lock. Each change
+gets a sequence number, and multiple changes before the sending task wakes
+up become just one frame.
+*/
+pub struct SharedRenderData {
+    data: Mutex<RenderData>,
+    sequence_sender: watch::Sender<u64>,
+    sequence_receiver: watch::Receiver<u64>, // never used, clones see all changes
+}
+
+pub struct RenderDataGuard<'a> {
+    guard: Option<MutexGuard<'a, RenderData>>,
+    sequence_sender: &'a watch::Sender<u64>,
+}
+
+impl SharedRenderData {
+    pub fn new(terminal_type: TerminalType) -> Self {
+        let (sequence_sender, sequence_receiver) = watch::channel(0);
+        Self {
+            data: Mutex::new(RenderData::new(terminal_type)),
+            sequence_sender,
+            sequence_receiver,
+        }
+    }
+
+    // Works like Mutex::lock()
+    pub fn lock(&self) -> LockResult<RenderDataGuard<'_>> {
+        let wrap = |guard| RenderDataGuard {
+            guard: Some(guard),
+            sequence_sender: &self.sequence_sender,
+        };
+        match self.data.lock() {
+            Ok(guard) => Ok(wrap(guard)),
+            Err(e) => Err(PoisonError::new(wrap(e.into_inner()))),
+        }
+    }
+
+    // Sequence number changes whenever something was changed and the lock was released
+    pub fn subscribe(&self) -> watch::Receiver<u64> {
+        self.sequence_receiver.clone()
+    }
+}
+
+impl Deref for RenderDataGuard<'_> {
+    type Target = RenderData;
+    fn deref(&self) -> &RenderData {
+        self.guard.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for RenderDataGuard<'_> {
+    fn deref_mut(&mut self) -> &mut RenderData {
+        self.guard.as_mut().unwrap()
+    }
+}
+
+impl Drop for RenderDataGuard<'_> {
+    fn drop(&mut self) {
+        let mut guard = self.guard.take().unwrap();
+        let changed = guard.changed;
+        guard.changed = false;
+        drop(guard);
+        if changed {
+            self.sequence_sender.send_modify(|seq| *seq += 1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::connection::FrameQueue;
+    use crate::connection::FrameStats;
+    use crate::game_logic::blocks::SquareContent;
+    use rand::rngs::StdRng;
+    use rand::Rng;
+    use rand::SeedableRng;
+    use std::sync::Arc;
+    use tokio::time::timeout;
+
+    fn styled(text: &str, color: Color) -> (String, Color) {
+        (text.to_string(), color)
+    }
+
+    #[test]
+    fn test_parse_styled_line() {
+        let mut open = vec![];
+        assert_eq!(
+            parse_styled_line("Press [P] to {pause [now] or} later", &mut open),
+            [
+                styled("Press ", Color::DEFAULT),
+                styled("P", Color::MAGENTA_FOREGROUND),
+                styled(" to ", Color::DEFAULT),
+                styled("pause ", Color::CYAN_FOREGROUND),
+                styled("now", Color::MAGENTA_FOREGROUND),
+                styled(" or", Color::CYAN_FOREGROUND),
+                styled(" later", Color::DEFAULT),
+            ]
+        );
+        assert!(open.is_empty());
+
+        // Unclosed markers continue on the next line
+        assert_eq!(
+            parse_styled_line("a {b", &mut open),
+            [
+                styled("a ", Color::DEFAULT),
+                styled("b", Color::CYAN_FOREGROUND)
+            ]
+        );
+        assert_eq!(
+            parse_styled_line("c} d", &mut open),
+            [
+                styled("c", Color::CYAN_FOREGROUND),
+                styled(" d", Color::DEFAULT)
+            ]
+        );
+
+        // Extra closing markers do nothing, and closing an outer marker closes the inner too
+        assert_eq!(
+            parse_styled_line("a]}b", &mut open),
+            [styled("ab", Color::DEFAULT)]
+        );
+        assert_eq!(
+            parse_styled_line("{a[b}c", &mut open),
+            [
+                styled("a", Color::CYAN_FOREGROUND),
+                styled("b", Color::MAGENTA_FOREGROUND),
+                styled("c", Color::DEFAULT),
+            ]
+        );
+        assert!(open.is_empty());
+        assert_eq!(parse_styled_line("", &mut open), []);
+    }
+
+    #[test]
+    fn test_wrap_styled_text() {
+        assert_eq!(
+            wrap_styled_text("aa {bb cc}\ndd ee\n\nff", 5),
+            ["aa {bb", "cc} dd", "ee", "", "ff"]
+        );
+        // Markers take no room
+        assert_eq!(wrap_styled_text("{aa} [bb]", 5), ["{aa} [bb]"]);
+        assert_eq!(
+            wrap_styled_text("a verylongword b", 5),
+            ["a", "verylongword", "b"]
+        );
+        assert_eq!(wrap_styled_text("", 5), [""]);
+    }
+
+    #[test]
+    fn test_banners() {
+        let mut render_data = RenderData::new(TerminalType::Ansi);
+        render_data.clear(80, 24);
+        render_data.set_banner("admin", Some("Restarting soon".to_string()));
+        render_data.set_banner("idle", Some("Wake up".to_string()));
+        render_data.set_banner("admin", Some("Restarting now".to_string()));
+
+        let mut buffer = RenderBuffer::new(TerminalType::Ansi);
+        render_data.buffer.copy_into(&mut buffer);
+        render_data.add_banners(&mut buffer);
+        let row = |buffer: &RenderBuffer, y| -> String {
+            (0..buffer.width).map(|x| buffer.get_char(x, y)).collect()
+        };
+        assert_eq!(row(&buffer, 0).trim(), "Restarting now");
+        assert_eq!(row(&buffer, 1).trim(), "Wake up");
+        assert_eq!(buffer.get_color(0, 1), Color::BLACK_ON_WHITE);
+
+        render_data.set_banner("admin", None);
+        assert_eq!(render_data.banners, [("idle", "Wake up".to_string())]);
+    }
+
+    #[test]
+    fn test_control_characters_in_text() {
+        let mut buffer = RenderBuffer::new(TerminalType::Ansi);
+        buffer.resize(80, 24);
+        assert_eq!(buffer.add_text(0, 0, "a\x1b[1mb\x07c\x7fd\u{9b}e"), 12);
+        let row: String = (0..12).map(|x| buffer.get_char(x, 0)).collect();
+        assert_eq!(row, "a?[1mb?c?d?e");
+
+        let updates =
+            buffer.get_updates_as_escape_codes(&RenderBuffer::new(TerminalType::Ansi), None, false);
+        assert!(!updates.contains('\x07') && !updates.contains('\x7f'));
+        assert!(!updates.contains("\x1b[1m"));
+    }
+
+    #[test]
+    fn test_cursor_pos_clamped() {
+        let mut old = RenderBuffer::new(TerminalType::Ansi);
+        old.resize(80, 24);
+        let mut new = RenderBuffer::new(TerminalType::Ansi);
+        new.resize(80, 24);
+        new.add_text(0, 0, "hello");
+
+        let updates = new.get_updates_as_escape_codes(&old, Some((200, 50)), false);
+        assert!(updates.ends_with("\x1b[24;80H\x1b[?25h"));
+        assert!(!updates.contains("\x1b[51;201H"));
+    }
+
+    #[test]
+    fn test_force_redraw_resends_everything() {
+        let mut buffer = RenderBuffer::new(TerminalType::Ansi);
+        buffer.resize(80, 24);
+        buffer.add_text(0, 0, "hello");
+        buffer.add_text(0, 23, "world");
+
+        let mut old = RenderBuffer::new(TerminalType::Ansi);
+        buffer.copy_into(&mut old);
+        assert!(!buffer
+            .get_updates_as_escape_codes(&old, None, false)
+            .contains("hello"));
+
+        let updates = buffer.get_updates_as_escape_codes(&old, Some((200, 50)), true);
+        assert!(updates.contains(TerminalType::Ansi.clear()));
+        assert!(updates.contains("hello"));
+        assert!(updates.contains("world"));
+        assert!(updates.ends_with("\x1b[24;80H\x1b[?25h"));
+    }
+
+    // Just enough of a terminal to check that RenderBuffer only sends what it should
+    struct FakeTerminal {
+        terminal_type: TerminalType,
+        chars: Vec<Vec<char>>,
+        colors: Vec<Vec<Color>>,
+        cursor: (usize, usize),
+        cursor_visible: bool,
+        color: Color,
+    }
+
+    impl FakeTerminal {
+        fn new(terminal_type: TerminalType, width: usize, height: usize) -> Self {
+            let mut result = Self {
+                terminal_type,
+                chars: vec![],
+                colors: vec![],
+                cursor: (0, 0),
+                cursor_visible: true,
+                color: Color::DEFAULT,
+            };
+            result.resize(width, height);
+            result
+        }
+
+        fn resize(&mut self, width: usize, height: usize) {
+            self.chars = vec![vec![' '; width]; height];
+            self.colors = vec![vec![Color::DEFAULT; width]; height];
+        }
+
+        fn clear_line_from(&mut self, x: usize, y: usize) {
+            assert_eq!(self.color, Color::DEFAULT, "clearing with colors");
+            for x in x..self.chars[y].len() {
+                self.chars[y][x] = ' ';
+                self.colors[y][x] = Color::DEFAULT;
+            }
+        }
+
+        fn move_cursor(&mut self, x: usize, y: usize) {
+            assert!(
+                y < self.chars.len() && x <= self.chars[y].len(),
+                "{} {}",
+                x,
+                y
+            );
+            self.cursor = (x, y);
+        }
+
+        fn ansi_sequence(&mut self, params: &str, command: char) {
+            let numbers: Vec<usize> = params
+                .trim_start_matches('?')
+                .split(';')
+                .map(|n| n.parse().unwrap())
+                .collect();
+            match (command, numbers.as_slice()) {
+                ('t', [8, h, w]) => self.resize(*w, *h),
+                ('J', [2]) => {
+                    for y in 0..self.chars.len() {
+                        self.clear_line_from(0, y);
+                    }
+                }
+                ('H', [y, x]) => self.move_cursor(x - 1, y - 1),
+                ('K', [0]) => self.clear_line_from(self.cursor.0, self.cursor.1),
+                ('m', [0]) => self.color = Color::DEFAULT,
+                ('m', [1, fg @ (30..=37 | 90..=97)]) => self.color.fg = *fg as u8,
+                ('m', [1, bg @ (40..=47 | 100..=107)]) => self.color.bg = *bg as u8,
+                ('m', [48, 5, 52]) => {
+                    self.color.bg = Color::DIM_RED_BACKGROUND.bg;
+                    self.color.dim = true;
+                }
+                ('h', [25]) => self.cursor_visible = true,
+                ('l', [25]) => self.cursor_visible = false,
+                _ => panic!("unexpected escape sequence: {:?} {:?}", params, command),
+            }
+        }
+
+        fn feed(&mut self, output: &str) {
+            let mut chars = output.chars();
+            while let Some(ch) = chars.next() {
+                if ch != '\x1b' {
+                    assert!(!ch.is_control(), "{:?}", ch);
+                    let (x, y) = self.cursor;
+                    assert!(x < self.chars[y].len(), "text goes past the end of line");
+                    self.chars[y][x] = ch;
+                    self.colors[y][x] = self.color;
+                    self.cursor.0 += 1;
+                    continue;
+                }
+
+                match (self.terminal_type, chars.next().unwrap()) {
+                    (TerminalType::Ansi, '[') => {
+                        let mut params = String::new();
+                        loop {
+                            match chars.next().unwrap() {
+                                c if c.is_ascii_digit() || c == ';' || c == '?' => params.push(c),
+                                command => {
+                                    self.ansi_sequence(&params, command);
+                                    break;
+                                }
+                            }
+                        }
+                    }
+                    (TerminalType::VT52, 'H') => self.move_cursor(0, 0),
+                    (TerminalType::VT52, 'J') => {
+                        let (x, y) = self.cursor;
+                        self.clear_line_from(x, y);
+                        for y in (y + 1)..self.chars.len() {
+                            self.clear_line_from(0, y);
+                        }
+                    }
+                    (TerminalType::VT52, 'K') => self.clear_line_from(self.cursor.0, self.cursor.1),
+                    (TerminalType::VT52, 'Y') => {
+                        let y = chars.next().unwrap() as usize - 32;
+                        let x = chars.next().unwrap() as usize - 32;
+                        self.move_cursor(x, y);
+                    }
+                    (TerminalType::VT52, 'e') => self.cursor_visible = true,
+                    (TerminalType::VT52, 'f') => self.cursor_visible = false,
+                    (_, other) => panic!("unexpected escape sequence: ESC {:?}", other),
+                }
+            }
+        }
+
+        fn check_matches(&self, buffer: &RenderBuffer) {
+            assert_eq!(self.chars.len(), buffer.height);
+            for y in 0..buffer.height {
+                assert_eq!(self.chars[y], buffer.chars[y], "row {}", y);
+                if buffer.has_color() {
+                    assert_eq!(self.colors[y], buffer.colors[y], "row {}", y);
+                }
+            }
+            assert_eq!(self.color, Color::DEFAULT);
+        }
+    }
+
+    fn random_color(rng: &mut StdRng) -> Color {
+        let colors = [
+            Color::DEFAULT,
+            Color::BLACK_ON_WHITE,
+            Color::GRAY_FOREGROUND,
+            Color::GRAY_BACKGROUND,
+            Color::RED_FOREGROUND,
+            Color::CYAN_FOREGROUND,
+            Color::MAGENTA_BACKGROUND,
+            Color::WHITE_BACKGROUND,
+            Color::DIM_RED_BACKGROUND,
+            Color::new(97, 0),
+            Color::new(33, 44),
+        ];
+        colors[rng.gen_range(0..colors.len())]
+    }
+
+    fn random_text(rng: &mut StdRng) -> String {
+        let chars = [' ', 'a', 'Z', '|', '(', ')', '▁', '▇', '×', 'ö', '█'];
+        (0..rng.gen_range(0..30))
+            .map(|_| chars[rng.gen_range(0..chars.len())])
+            .collect()
+    }
+
+    #[test]
+    fn test_updates_reproduce_the_buffer() {
+        let mut rng = StdRng::seed_from_u64(1903);
+        for (terminal_type, low_bandwidth) in [
+            (TerminalType::Ansi, false),
+            (TerminalType::Ansi, true),
+            (TerminalType::VT52, false),
+        ] {
+            let mut terminal = FakeTerminal::new(terminal_type, 80, 24);
+            let mut old = RenderBuffer::new(terminal_type);
+            let mut buffer = RenderBuffer::new(terminal_type);
+            buffer.low_bandwidth = low_bandwidth;
+            buffer.resize(80, 24);
+
+            for _ in 0..300 {
+                // VT52 has no way to resize the terminal
+                if terminal_type == TerminalType::Ansi && rng.gen_bool(0.05) {
+                    buffer.resize(rng.gen_range(80..100), rng.gen_range(24..30));
+                }
+                if rng.gen_bool(0.1) {
+                    buffer.clear();
+                }
+                for _ in 0..rng.gen_range(0..5) {
+                    let x = rng.gen_range(0..buffer.width);
+                    let y = rng.gen_range(0..buffer.height);
+                    let text = random_text(&mut rng);
+                    buffer.add_text_with_color(x, y, &text, random_color(&mut rng));
+                }
+                if rng.gen_bool(0.1) {
+                    let y = rng.gen_range(0..buffer.height);
+                    buffer.set_row_color(y, random_color(&mut rng));
+                }
+
+                let cursor_pos = if rng.gen_bool(0.5) {
+                    Some((rng.gen_range(0..200), rng.gen_range(0..50)))
+                } else {
+                    None
+                };
+                let force_redraw = rng.gen_bool(0.05);
+                terminal.feed(&buffer.get_updates_as_escape_codes(&old, cursor_pos, force_redraw));
+                terminal.check_matches(&buffer);
+                assert_eq!(terminal.cursor_visible, cursor_pos.is_some());
+                let expected_cursor = cursor_pos
+                    .map(|(x, y)| (x.min(buffer.width - 1), y.min(buffer.height - 1)))
+                    .unwrap_or((0, buffer.height - 1));
+                assert_eq!(terminal.cursor, expected_cursor);
+
+                buffer.copy_into(&mut old);
+            }
+        }
+    }
+
+    // Colorful blocks everywhere, like a busy game
+    fn render_busy_frame(buffer: &mut RenderBuffer) {
+        buffer.resize(80, 24);
+        for y in 0..24 {
+            for x in (0..80).step_by(2) {
+                let color = Color::new(0, 41 + ((x / 2 + y) % 6) as u8);
+                SquareContent::with_color(color).render(buffer, x, y, None, (0, 1), None);
+            }
+        }
+    }
+
+    #[test]
+    fn test_low_bandwidth_mode() {
+        let empty = RenderBuffer::new(TerminalType::Ansi);
+
+        let mut normal = RenderBuffer::new(TerminalType::Ansi);
+        render_busy_frame(&mut normal);
+        let normal_bytes = normal.get_updates_as_escape_codes(&empty, None, true);
+
+        let mut low = RenderBuffer::new(TerminalType::Ansi);
+        low.low_bandwidth = true;
+        render_busy_frame(&mut low);
+        let low_bytes = low.get_updates_as_escape_codes(&empty, None, true);
+
+        assert!(low_bytes.len() * 3 < normal_bytes.len());
+        // Monochrome: blocks are drawn with () instead of colored spaces
+        for bg in 41..=46 {
+            let color = Color::new(0, bg);
+            assert!(!low_bytes.contains(&TerminalType::Ansi.format_color(color)));
+        }
+        assert!(!low_bytes.contains(TerminalType::Ansi.reset_colors()));
+        assert_eq!(low_bytes.matches("()").count(), 40 * 24);
+        assert_eq!(low.get_char(0, 0), '(');
+        assert_eq!(low.get_char(1, 0), ')');
+
+        // Changing only colors sends nothing
+        let mut old = RenderBuffer::new(TerminalType::Ansi);
+        low.copy_into(&mut old);
+        assert!(old.low_bandwidth);
+        low.set_row_color(5, Color::RED_FOREGROUND);
+        assert_eq!(
+            low.get_updates_as_escape_codes(&old, None, false),
+            low.get_updates_as_escape_codes(&low, None, false),
+        );
+    }
+
+    #[tokio::test]
+    async fn test_low_bandwidth_frame_rate() {
+        let render_data = Arc::new(SharedRenderData::new(TerminalType::Ansi));
+        let queue = Arc::new(FrameQueue::new());
+        {
+            let render_data = render_data.clone();
+            let queue = queue.clone();
+            tokio::spawn(async move {
+                crate::handle_sending(
+                    &queue,
+                    &mut FrameStats::default(),
+                    render_data,
+                    TerminalType::Ansi,
+                )
+                .await
+            });
+        }
+        {
+            let mut render_data = render_data.lock().unwrap();
+            render_data.clear(80, 24);
+            render_data.buffer.low_bandwidth = true;
+            render_data.mark_changed();
+        }
+        queue.pop_frame().await;
+
+        // 20 changes in one second become about 2 frames
+        let start = Instant::now();
+        for i in 0..20 {
+            {
+                let mut render_data = render_data.lock().unwrap();
+                render_data.buffer.add_text(0, i, &format!("row{}", i));
+                render_data.mark_changed();
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        let mut frames = vec![];
+        while let Ok(frame) = timeout(Duration::from_millis(600), queue.pop_frame()).await {
+            frames.push(String::from_utf8(frame).unwrap());
+        }
+        assert!(frames.len() <= 3);
+        assert!(frames.last().unwrap().contains("row19"));
+        assert!(start.elapsed() >= Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn test_requested_redraws_are_rate_limited() {
+        let render_data = Arc::new(SharedRenderData::new(TerminalType::Ansi));
+        let queue = Arc::new(FrameQueue::new());
+        {
+            let render_data = render_data.clone();
+            let queue = queue.clone();
+            tokio::spawn(async move {
+                crate::handle_sending(
+                    &queue,
+                    &mut FrameStats::default(),
+                    render_data,
+                    TerminalType::Ansi,
+                )
+                .await
+            });
+        }
+        let next_frame_is_full = || async {
+            let frame = timeout(Duration::from_secs(1), queue.pop_frame())
+                .await
+                .unwrap();
+            String::from_utf8(frame).unwrap().contains("\x1b[2J")
+        };
+
+        {
+            let mut render_data = render_data.lock().unwrap();
+            render_data.clear(80, 24);
+            render_data.mark_changed();
+        }
+        assert!(next_frame_is_full().await);
+
+        let mut full_count = 0;
+        for _ in 0..100 {
+            render_data.lock().unwrap().request_redraw();
+            if next_frame_is_full().await {
+                full_count += 1;
+            }
+        }
+        assert_eq!(full_count, 1);
+        assert_eq!(render_data.lock().unwrap().coalesced_redraw_requests, 98);
+
+        // The other 99 requests become one redraw later
+        assert!(next_frame_is_full().await);
+
+        // Automatic redraws aren't limited
+        render_data.lock().unwrap().force_redraw = true;
+        render_data.lock().unwrap().mark_changed();
+        assert!(next_frame_is_full().await);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_shared_render_data_stress() {
+        const LAST_VALUE: u64 = 10000;
+        let render_data = Arc::new(SharedRenderData::new(TerminalType::Ansi));
+        render_data.lock().unwrap().clear(80, 24);
+
+        let writer = {
+            let render_data = render_data.clone();
+            tokio::spawn(async move {
+                for value in 1..=LAST_VALUE {
+                    {
+                        let mut render_data = render_data.lock().unwrap();
+                        render_data.buffer.add_text(0, 0, &format!("{:06}", value));
+                        render_data.mark_changed();
+                    }
+                    if value % 100 == 0 {
+                        tokio::task::yield_now().await;
+                    }
+                }
+            })
+        };
+
+        let reader = {
+            let render_data = render_data.clone();
+            tokio::spawn(async move {
+                let mut receiver = render_data.subscribe();
+                let mut last_sequence = 0;
+                let mut last_value = 0;
+                let mut frame_count = 0;
+                while last_value != LAST_VALUE {
+                    receiver.changed().await.unwrap();
+                    let sequence = *receiver.borrow_and_update();
+                    assert!(sequence > last_sequence);
+                    last_sequence = sequence;
+
+                    let text: String = {
+                        let render_data = render_data.lock().unwrap();
+                        (0..6).map(|x| render_data.buffer.get_char(x, 0)).collect()
+                    };
+                    // The buffer can be newer than the sequence number, but never older
+                    let value: u64 = text.parse().unwrap();
+                    assert!(value >= last_value);
+                    assert!(value >= sequence);
+                    last_value = value;
+                    frame_count += 1;
+                }
+                frame_count
+            })
+        };
+
+        let frame_count = timeout(Duration::from_secs(10), reader)
+            .await
+            .unwrap()
+            .unwrap();
+        timeout(Duration::from_secs(10), writer)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(frame_count <= LAST_VALUE);
+        assert_eq!(*render_data.subscribe().borrow(), LAST_VALUE);
     }
 }
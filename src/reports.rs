@@ -0,0 +1,152 @@
+/*
+Problems that players report with "Report a problem" in the menu. Each report
+is one line in a text file, so that I can read them with any text editor.
+
+Players can type anything, so control characters are removed and the lengths
+are limited. The file also has a size limit, so that nobody can fill the disk
+by reporting the same thing over and over again.
+*/
+use crate::logging;
+use chrono::DateTime;
+use chrono::Utc;
+use std::fs;
+use std::io::Write;
+
+type AnyErrorThreadSafe = Box<dyn std::error::Error + Send + Sync>;
+
+const MAX_FIELD_LEN: usize = 100;
+const MAX_FILE_SIZE: u64 = 1_000_000;
+
+fn log(message: &str) {
+    logging::log("reports", message);
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Report {
+    pub name: String,
+    pub lobby_id: Option<String>,
+    pub description: String,
+    pub contact: String, // empty if not given
+}
+
+// Tabs are control characters too, so they can be used to separate the fields
+fn sanitize(text: &str) -> String {
+    let text: String = text.chars().filter(|c| !c.is_control()).collect();
+    text.trim().chars().take(MAX_FIELD_LEN).collect()
+}
+
+fn format_report_line(report: &Report, time: DateTime<Utc>) -> String {
+    format!(
+        "{}\t{}\t{}\t{}\t{}\n",
+        time.to_rfc3339(),
+        sanitize(&report.name),
+        sanitize(report.lobby_id.as_deref().unwrap_or("-")),
+        sanitize(&report.description),
+        sanitize(&report.contact),
+    )
+}
+
+// Returns false if the file is full
+fn append_to_file(
+    filename: &str,
+    line: &str,
+    max_file_size: u64,
+) -> Result<bool, AnyErrorThreadSafe> {
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(filename)?;
+    if file.metadata()?.len() + (line.len() as u64) > max_file_size {
+        return Ok(false);
+    }
+    file.write_all(line.as_bytes())?;
+    Ok(true)
+}
+
+// File name stored here so I won't forget to use this
+lazy_static! {
+    static ref FILE_LOCK: tokio::sync::Mutex<&'static str> =
+        tokio::sync::Mutex::new("catris_reports.txt");
+}
+
+pub async fn append(report: &Report) -> Result<(), AnyErrorThreadSafe> {
+    let filename_handle = FILE_LOCK.lock().await;
+    let line = format_report_line(report, Utc::now());
+    let appended =
+        tokio::task::spawn_blocking(move || append_to_file(*filename_handle, &line, MAX_FILE_SIZE))
+            .await??;
+    if !appended {
+        log("Reports file is full, not adding a new report");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn temp_filename(tempdir: &tempfile::TempDir) -> String {
+        tempdir
+            .path()
+            .join("reports.txt")
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    fn sample_time() -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339("2024-01-02T03:04:05+00:00")
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    fn sample_report() -> Report {
+        Report {
+            name: "Alice".to_string(),
+            lobby_id: Some("LLDDKK".to_string()),
+            description: "Blocks go through walls".to_string(),
+            contact: "".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_report_line() {
+        let time = sample_time();
+        assert_eq!(
+            format_report_line(&sample_report(), time),
+            "2024-01-02T03:04:05+00:00\tAlice\tLLDDKK\tBlocks go through walls\t\n"
+        );
+
+        let report = Report {
+            name: "Bob".to_string(),
+            lobby_id: None,
+            description: format!(" \x1b[31mred\ttext\r\n{}", "x".repeat(200)),
+            contact: "bob@example.com\n".to_string(),
+        };
+        let line = format_report_line(&report, time);
+        let fields: Vec<&str> = line.trim_end_matches('\n').split('\t').collect();
+        assert_eq!(fields.len(), 5);
+        assert_eq!(fields[1], "Bob");
+        assert_eq!(fields[2], "-");
+        assert!(fields[3].starts_with("[31mredtextxxx"));
+        assert_eq!(fields[3].chars().count(), MAX_FIELD_LEN);
+        assert_eq!(fields[4], "bob@example.com");
+    }
+
+    #[test]
+    fn test_append_to_file() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let filename = temp_filename(&tempdir);
+        let time = sample_time();
+        let line = format_report_line(&sample_report(), time);
+
+        assert!(append_to_file(&filename, &line, 1000).unwrap());
+        assert!(append_to_file(&filename, &line, 1000).unwrap());
+        assert_eq!(fs::read_to_string(&filename).unwrap(), line.repeat(2));
+
+        // Doesn't go over the size limit
+        let size = 2 * line.len() as u64;
+        assert!(!append_to_file(&filename, &line, size).unwrap());
+        assert_eq!(fs::read_to_string(&filename).unwrap(), line.repeat(2));
+    }
+}
@@ -0,0 +1,533 @@
+/*
+User-facing text, looked up by key. English is built in, and running with
+--lang path/to/file.toml replaces the texts found in the file. Everything
+that the file doesn't mention stays English.
+
+The file format is a small subset of TOML: one `key = "value"` per line,
+lines starting with # are comments, and values can contain the escapes
+\n, \" and \\. Texts may contain [brackets] and {braces} for colors, see
+parse_styled_line(), and some contain {placeholders} that get replaced.
+*/
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+const ENGLISH: &[(&str, &str)] = &[
+    ("keys.quit", "  [Ctrl+C], [Ctrl+D] or [Ctrl+Q]: quit"),
+    (
+        "keys.redraw",
+        "  [Ctrl+R]: redraw the whole screen (may be needed after resizing the window)",
+    ),
+    (
+        "keys.move",
+        "  [W]/[A]/[S]/[D] or [↑]/[←]/[↓]/[→]: move and rotate (don't hold down [S] or [↓])",
+    ),
+    (
+        "keys.hold",
+        "  [H]: hold (aka save) block for later, switch to previously held block if any",
+    ),
+    ("keys.rotate_direction", "  [R]: change rotating direction"),
+    (
+        "keys.cancel_soft_drop",
+        "  [C]: choose whether other keys cancel moving down fast with [S] or [↓]",
+    ),
+    (
+        "keys.bell",
+        "  [B]: turn on/off the bell that rings when your block lands",
+    ),
+    (
+        "keys.auto_shift",
+        "  [M]: turn on/off moving sideways repeatedly after pressing [A]/[D] or [←]/[→]",
+    ),
+    (
+        "keys.reduced_flashing",
+        "  [G]: turn on/off reduced flashing of bombs and cleared rows",
+    ),
+    ("keys.pause", "  [P]: pause/unpause (affects all players)"),
+    (
+        "keys.flip",
+        "  [F]: flip the game upside down (only available in ring mode with 1 player)",
+    ),
+    ("keys.help", "  [?]: show these keys while playing"),
+    ("keys.title", "Keys"),
+    ("keys.continue", "Press any key to continue playing."),
+    (
+        "tips",
+        "There's only one score. {You play together}, not against other players. \
+         Try to work together and make good use of everyone's blocks.\n\
+         \n\
+         With multiple players, when your playing area fills all the way to the top, \
+         you need to wait 30 seconds before you can continue playing. The game ends \
+         when all players are simultaneously on their 30 seconds waiting time. This \
+         means that if other players are doing well, you can {intentionally fill your \
+         playing area} to do your waiting time before others mess up.",
+    ),
+    ("pause.title", "Game paused"),
+    (
+        "pause.idle_warning",
+        "You will be disconnected automatically if\n\
+         you don't press any keys for 10 minutes.",
+    ),
+    ("side.lobby_id", "Lobby ID: {id}"),
+    ("side.score", "Score: {score}"),
+    ("side.zen", " (zen)"),
+    ("side.counter_clockwise", "Counter-clockwise"),
+    (
+        "side.other_keys_keep_fast_down",
+        "Other keys keep fast down",
+    ),
+    ("side.auto_shift_on", "Auto shift on"),
+    ("side.bell_off", "Bell off"),
+    ("side.reduced_flashing", "Reduced flashing"),
+    ("side.seed", "Seed: {seed}"),
+    ("side.time", "Time: {time}"),
+    ("side.level", "Level: {level}"),
+    (
+        "side.time_limit_reached",
+        "Time limit reached, no more blocks",
+    ),
+    ("side.undo", "Press u to undo"),
+    ("side.ring_cleared", "Ring cleared:"),
+    ("side.next", "Next:"),
+    ("side.holding", "Holding:"),
+    ("side.nothing_in_hold", "Nothing in hold"),
+    ("side.press_h", "   (press h)"),
+    (
+        "side.pause_vote",
+        "{name} wants to pause ({votes}/{players}) - press P to agree",
+    ),
+    (
+        "side.unpause_vote",
+        "{name} wants to continue ({votes}/{players}) - press P to agree",
+    ),
+    ("departure.quit", "{name} quit"),
+    ("departure.disconnected", "{name} disconnected"),
+    ("departure.board_shrank", " - the board shrank"),
+    ("rescue.clear_rows", "Clear top {rows}"),
+    ("rescue.cost", "rows for {cost}"),
+    ("rescue.question", "points?"),
+    ("rescue.answer", "[Y]es  [N]o"),
+    (
+        "form.not_raw_mode",
+        "Your terminal doesn't seem to be in raw mode. Run 'stty raw' and try again.",
+    ),
+    ("name.prompt", "Name: "),
+    (
+        "name.notes",
+        "If you play well, your name will be\n\
+         visible to everyone in the high scores.\n\
+         \n\
+         Your IP will be logged on the server only if you\n\
+         connect 5 or more times within the same minute.\n\
+         \n\
+         Source code: https://github.com/Akuli/catris",
+    ),
+    ("name.empty", "Please write a name before pressing Enter."),
+    (
+        "name.invalid_char",
+        "The name can't contain a '{char}' character.",
+    ),
+    ("name.in_use", "This name is in use. Try a different name."),
+    ("local_player.prompt", "Second player's name: "),
+    (
+        "local_player.lobby_full",
+        "The lobby is full. It already has {count} players.",
+    ),
+    (
+        "local_player.notes",
+        "Both players use the same keyboard.\n\
+         [W]/[A]/[S]/[D] control you, and arrow keys control the second player.",
+    ),
+    ("seed.prompt", "Seed (empty for random blocks): "),
+    ("seed.not_a_number", "The seed must be a number, e.g. 123."),
+    (
+        "seed.notes",
+        "Games started in this lobby get their blocks from the seed.\n\
+         Race against a friend by using the same seed in another lobby.",
+    ),
+    ("join.id_prompt", "Lobby ID (6 characters): "),
+    (
+        "join.bad_id",
+        "The text you entered doesn't look like a lobby ID.",
+    ),
+    (
+        "join.too_many_attempts",
+        "Too many wrong lobby IDs or player names. Please try again later.",
+    ),
+    (
+        "join.lobby_full",
+        "Lobby '{id}' is full. It already has {count} players.",
+    ),
+    (
+        "join.no_such_lobby",
+        "There is no lobby with ID '{id}'. Maybe you mixed up similar characters?",
+    ),
+    ("join.name_prompt", "Name of a player in the lobby: "),
+    ("join.no_such_player", "There is no player named '{name}'."),
+    (
+        "join.player_lobby_full",
+        "The lobby of '{name}' is full. It already has {count} players.",
+    ),
+    ("report.problem", "Problem: "),
+    ("report.contact", "Contact (optional): "),
+    (
+        "report.empty",
+        "Please describe the problem before pressing Enter.",
+    ),
+    (
+        "report.notes",
+        "Your name, lobby ID and what you write here\n\
+         will be saved on the server.\n\
+         \n\
+         Press Tab or arrow keys to move between the fields.",
+    ),
+    ("passphrase.prompt", "Settings passphrase: "),
+    (
+        "passphrase.empty",
+        "Please write a passphrase before pressing Enter.",
+    ),
+    (
+        "passphrase.notes",
+        "Your settings, such as rotating direction and bell, are saved\n\
+         on the server with your name and this passphrase. To get them\n\
+         back later, enter the same name and passphrase.\n\
+         \n\
+         There are no accounts, and anyone who knows the passphrase\n\
+         can change your settings. Don't use a password here.",
+    ),
+    ("passphrase.loaded", "Your settings were loaded."),
+    (
+        "passphrase.new",
+        "New passphrase. Your settings will be saved with it.",
+    ),
+    ("passphrase.save_failed", "Saving settings failed."),
+    ("passphrase.load_failed", "Loading settings failed."),
+    ("menu.new_lobby", "New lobby"),
+    ("menu.join_by_id", "Join an existing lobby"),
+    ("menu.join_by_name", "Join by player name"),
+    ("menu.tutorial", "Tutorial"),
+    ("menu.settings_passphrase", "Settings passphrase"),
+    ("menu.reduced_flashing_off", "Reduced flashing: off"),
+    ("menu.reduced_flashing_on", "Reduced flashing: on"),
+    ("menu.quit", "Quit"),
+    ("menu.back", "Back to menu"),
+    ("menu.yes", "Yes"),
+    ("menu.no", "No"),
+    (
+        "menu.intro",
+        "New to catris? Try the tutorial. To play alone, make a new lobby.\n\
+         For multiplayer, one player makes a lobby and others join it.",
+    ),
+    ("lobby.press_i_to_show", " (press i to show)"),
+    ("lobby.press_i_to_hide", " (press i to hide)"),
+    ("lobby.cursed_blocks", "Cursed blocks: {name}"),
+    ("lobby.big_on", ", big: on"),
+    ("lobby.big_off", ", big: off"),
+    ("lobby.ownership_tint_on", "Ownership tint: on"),
+    ("lobby.ownership_tint_off", "Ownership tint: off"),
+    ("lobby.pause_vote_on", "Pause by vote: on"),
+    ("lobby.pause_vote_off", "Pause by vote: off"),
+    ("lobby.reinforced_on", "Reinforced: on"),
+    ("lobby.reinforced_off", "Reinforced: off"),
+    ("lobby.stall_decay_on", "Stall decay: on"),
+    ("lobby.stall_decay_off", "Stall decay: off"),
+    ("lobby.spawn_delay_on", "Spawn delay: one tick"),
+    ("lobby.spawn_delay_off", "Spawn delay: off"),
+    // Shallow necks are only 1 row shallower than classic, the bottle gets narrower above that
+    ("lobby.bottle", "Bottle: {name} ({rows} personal rows)"),
+    ("lobby.more_settings", "Press M for more settings"),
+    ("lobby.you", " (you)"),
+    ("lobby.handicap", " (handicap: {handicap})"),
+    (
+        "lobby.allow_many_per_ip",
+        "Press O to allow many players from the same IP address in a game.",
+    ),
+    (
+        "lobby.allow_one_per_ip",
+        "Press O to allow only one player per IP address in each game.",
+    ),
+    (
+        "lobby.disallow_parallel_games",
+        "Press P to allow only one game at a time in this lobby.",
+    ),
+    (
+        "lobby.allow_parallel_games",
+        "Press P to allow playing different games at the same time.",
+    ),
+    (
+        "lobby.handicaps",
+        "Press 1-6 to give handicaps in traditional game.",
+    ),
+    (
+        "lobby.one_bottle_basin",
+        "Press D for one bottle basin with 4+ players.",
+    ),
+    (
+        "lobby.two_bottle_basins",
+        "Press D for two bottle basins with 4+ players.",
+    ),
+    (
+        "lobby.list_for_name_search",
+        "Press U to let others join by typing the name of a player in this lobby.",
+    ),
+    (
+        "lobby.unlist_for_name_search",
+        "Press U to require the lobby ID for joining this lobby.",
+    ),
+    (
+        "lobby.seed",
+        "Seed: {seed} ({sharing}). Press E/S to change.",
+    ),
+    ("lobby.seed_shared", "same blocks for all players"),
+    ("lobby.seed_not_shared", "different blocks per player"),
+    (
+        "lobby.no_seed",
+        "Press E to enter a seed, so that games in other lobbies get the same blocks.",
+    ),
+    (
+        "waitlist.offer",
+        "A slot opened in {mode} — press Enter to join within {seconds} seconds",
+    ),
+    (
+        "waitlist.give_up",
+        "Press Esc to give your place to the next player in line.",
+    ),
+    (
+        "waitlist.position",
+        "Waiting for a slot in {mode}, you are number {position} in line.",
+    ),
+    ("waitlist.join", "Join waitlist"),
+    ("mode_menu.game", "{mode} ({count}/{max} players)"),
+    ("mode_menu.tips", "Gameplay tips"),
+    ("mode_menu.high_scores", "High scores"),
+    ("mode_menu.report", "Report a problem"),
+    ("mode_menu.add_local_player", "Add local player"),
+    ("mode_menu.remove_local_player", "Remove local player"),
+    (
+        "mode_menu.low_bandwidth_off",
+        "Press L to turn off low bandwidth mode.",
+    ),
+    (
+        "mode_menu.low_bandwidth_on",
+        "Press L for low bandwidth mode (no colors, 2 updates per second).",
+    ),
+    ("tips.keys", "Keys:"),
+    ("pause.continue", "Continue playing"),
+    ("pause.quit_game", "Quit game"),
+    ("pause.quit_tutorial", "Quit tutorial"),
+    ("pause.players", "Players: "),
+    (
+        "crash.message",
+        "The game crashed, sorry - returning to menu...",
+    ),
+    (
+        "old_game.duration",
+        "This game has been running for {minutes} minutes and is quite fast.",
+    ),
+    (
+        "old_game.score",
+        "This game already has {score} points and can be difficult.",
+    ),
+    ("old_game.question", "Join anyway? [y/n]"),
+    ("join_error.full", "This game is full."),
+    (
+        "join_error.ip_restricted",
+        "Someone from your IP address is already playing this game.",
+    ),
+    (
+        "join_error.not_allowed",
+        "A {mode} is in progress — join it or wait",
+    ),
+    ("duration.seconds", "{n}sec"),
+    ("duration.minutes", "{n}min"),
+    ("ago.now", "now"),
+    ("ago.second", "1 second ago"),
+    ("ago.seconds", "{n} seconds ago"),
+    ("ago.minute", "1 minute ago"),
+    ("ago.minutes", "{n} minutes ago"),
+    ("ago.hour", "1 hour ago"),
+    ("ago.hours", "{n} hours ago"),
+    ("ago.day", "1 day ago"),
+    ("ago.days", "{n} days ago"),
+    ("ago.week", "1 week ago"),
+    ("ago.weeks", "{n} weeks ago"),
+    ("ago.month", "1 month ago"),
+    ("ago.months", "{n} months ago"),
+    ("ago.year", "1 year ago"),
+    ("ago.years", "{n} years ago"),
+    ("names.one_other", " +1 other"),
+    ("names.others", " +{n} others"),
+    ("game_over.high_score", "Game over :)"),
+    ("game_over.no_high_score", "Game over :("),
+    ("game_over.paused", " (paused {duration})"),
+    (
+        "game_over.summary",
+        "The game lasted {duration} and it ended with score {score}.",
+    ),
+    ("game_over.seed", "Blocks came from seed {seed}."),
+    (
+        "game_over.multiplier",
+        "Points were multiplied by {multiplier} because there were {count} players.",
+    ),
+    ("game_over.inactive", "{name} (inactive, not counted)"),
+    ("high_scores.header", " HIGH SCORES: {mode} with {players} "),
+    ("high_scores.multiplayer", "multiplayer"),
+    ("high_scores.single_player", "single player"),
+    ("high_scores.score", "Score"),
+    ("high_scores.duration", "Duration"),
+    ("high_scores.when", "When"),
+    ("high_scores.player", "Player"),
+    ("high_scores.players", "Players"),
+    ("high_scores.time", "Time"),
+    ("high_scores.loading", "Loading..."),
+    ("high_scores.error", "High Scores Error"),
+    (
+        "high_scores.old",
+        "High scores older than 90 days are not shown.",
+    ),
+    ("high_scores.sprint_records", "Sprint records"),
+    (
+        "high_scores.sprint_header",
+        " FASTEST {rows} ROWS: traditional single player ",
+    ),
+    (
+        "high_scores.no_sprint_records",
+        "Clear {rows} rows in a single player traditional game to get here.",
+    ),
+    ("high_scores.continue", "Press Enter to continue..."),
+    ("rematch.continue", "Continue"),
+    ("rematch.play_again", "Play again"),
+    ("rematch.waiting", "Waiting for others: {accepted}/{total}"),
+    ("rematch.stop_waiting", "Press Enter to stop waiting"),
+    ("tutorial.move", "Welcome to catris! Move the falling block sideways with A and D, or with the arrow keys."),
+    ("tutorial.rotate", "Nice! Now rotate the block with W or the up arrow key."),
+    ("tutorial.soft_drop", "Press S or the down arrow key to make the block fall faster."),
+    ("tutorial.hold", "Press H to put the falling block aside. Later you can press H again to take it back."),
+    ("tutorial.drill", "This is a drill. Press S and watch it dig through the gray squares below it."),
+    ("tutorial.bomb", "This is a bomb. When its timer runs out, it explodes and destroys everything around it, even if it's still falling."),
+    ("tutorial.top_out", "Your area is almost full. Keep dropping blocks until there's no room for a new block."),
+    ("tutorial.timer", "When there's no room, you wait 30 seconds. Then your area is cleared and you can continue. In multiplayer games, others keep playing while you wait."),
+    ("tutorial.done", "That's all! Press Enter to go back to the menu."),
+];
+
+// Set from command line arguments, everything is English if not set
+pub static TRANSLATIONS: OnceLock<HashMap<String, String>> = OnceLock::new();
+
+// Panics with an unknown key, so that typos are noticed right away
+pub fn lookup(key: &str) -> &'static str {
+    lookup_in(TRANSLATIONS.get(), key)
+}
+
+fn lookup_in<'a>(translations: Option<&'a HashMap<String, String>>, key: &str) -> &'a str {
+    if let Some(text) = translations.and_then(|t| t.get(key)) {
+        return text;
+    }
+    match ENGLISH.iter().find(|(k, _)| *k == key) {
+        Some((_, text)) => text,
+        None => panic!("unknown string key: {:?}", key),
+    }
+}
+
+fn parse_value(quoted: &str) -> Option<String> {
+    let inner = quoted.strip_prefix('"')?.strip_suffix('"')?;
+    let mut result = String::new();
+    let mut chars = inner.chars();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '\\' => match chars.next()? {
+                'n' => result.push('\n'),
+                '"' => result.push('"'),
+                '\\' => result.push('\\'),
+                _ => return None,
+            },
+            '"' => return None, // not escaped, so the string ended too early
+            ch => result.push(ch),
+        }
+    }
+    Some(result)
+}
+
+pub fn parse_translations(content: &str) -> Result<HashMap<String, String>, String> {
+    let mut result = HashMap::new();
+    for (line_num, line) in (1..).zip(content.lines()) {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let error = |message: &str| Err(format!("line {}: {}", line_num, message));
+
+        let Some((key, value)) = line.split_once('=') else {
+            return error("expected key = \"value\"");
+        };
+        let key = key.trim();
+        if !ENGLISH.iter().any(|(k, _)| *k == key) {
+            return error(&format!("unknown key {:?}", key));
+        }
+        let Some(value) = parse_value(value.trim()) else {
+            return error("value must be a string in double quotes");
+        };
+        if result.insert(key.to_string(), value).is_some() {
+            return error(&format!("{:?} appears twice", key));
+        }
+    }
+    Ok(result)
+}
+
+pub fn load_translations(path: &str) -> Result<HashMap<String, String>, String> {
+    let content = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+    parse_translations(&content)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_partial_translation() {
+        let translations = parse_translations(
+            "# Finnish, not finished yet\n\
+             \n\
+             side.next = \"Seuraava:\"\n\
+             pause.title = \"Peli \\\"tauolla\\\"\"\n\
+             side.press_h = \"paina \\\\ ja\\nh\"\n",
+        )
+        .unwrap();
+        assert_eq!(translations.len(), 3);
+
+        let t = Some(&translations);
+        assert_eq!(lookup_in(t, "side.next"), "Seuraava:");
+        assert_eq!(lookup_in(t, "pause.title"), "Peli \"tauolla\"");
+        assert_eq!(lookup_in(t, "side.press_h"), "paina \\ ja\nh");
+        // Falls back to English one key at a time
+        assert_eq!(lookup_in(t, "side.holding"), "Holding:");
+        assert_eq!(lookup_in(None, "side.next"), "Next:");
+    }
+
+    #[test]
+    fn test_bad_translation_files() {
+        assert_eq!(
+            parse_translations("side.next = \"a\"\nside.nxet = \"b\""),
+            Err("line 2: unknown key \"side.nxet\"".to_string())
+        );
+        assert_eq!(
+            parse_translations("side.next \"a\""),
+            Err("line 1: expected key = \"value\"".to_string())
+        );
+        for value in ["a", "\"a", "\"a\"b\"", "\"\\x\""] {
+            assert_eq!(
+                parse_translations(&format!("side.next = {}", value)),
+                Err("line 1: value must be a string in double quotes".to_string())
+            );
+        }
+        assert_eq!(
+            parse_translations("side.next = \"a\"\nside.next = \"b\""),
+            Err("line 2: \"side.next\" appears twice".to_string())
+        );
+    }
+
+    #[test]
+    fn test_english_keys_are_unique() {
+        for (i, (key, _)) in ENGLISH.iter().enumerate() {
+            assert!(!ENGLISH[..i].iter().any(|(k, _)| k == key), "{}", key);
+        }
+    }
+}
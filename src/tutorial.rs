@@ -0,0 +1,318 @@
+use crate::escapes::Color;
+use crate::game_logic::blocks::BlockType;
+use crate::game_logic::blocks::FallingBlock;
+use crate::game_logic::blocks::Shape;
+use crate::game_logic::blocks::SquareContent;
+use crate::game_logic::game::Game;
+use crate::game_logic::game::Mode;
+use crate::game_logic::player::BlockOrTimer;
+use crate::game_logic::BlockRelativeCoords;
+use crate::game_logic::PlayerSpec;
+use crate::strings::lookup;
+use rand::seq::SliceRandom;
+
+// Same blocks every time, so that the tutorial is the same for everyone
+const TUTORIAL_SEED: u64 = 1;
+
+// O blocks would make the rotating step confusing, because they don't rotate
+const TUTORIAL_SHAPES: &[Shape] = &[Shape::L, Shape::I, Shape::J, Shape::T, Shape::Z, Shape::S];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Step {
+    Move,
+    Rotate,
+    SoftDrop,
+    Hold,
+    Drill,
+    Bomb,
+    TopOut,
+    Timer,
+    Done,
+}
+
+impl Step {
+    pub fn prompt(self) -> &'static str {
+        let key = match self {
+            Step::Move => "tutorial.move",
+            Step::Rotate => "tutorial.rotate",
+            Step::SoftDrop => "tutorial.soft_drop",
+            Step::Hold => "tutorial.hold",
+            Step::Drill => "tutorial.drill",
+            Step::Bomb => "tutorial.bomb",
+            Step::TopOut => "tutorial.top_out",
+            Step::Timer => "tutorial.timer",
+            Step::Done => "tutorial.done",
+        };
+        lookup(key)
+    }
+
+    fn next(self) -> Self {
+        match self {
+            Step::Move => Step::Rotate,
+            Step::Rotate => Step::SoftDrop,
+            Step::SoftDrop => Step::Hold,
+            Step::Hold => Step::Drill,
+            Step::Drill => Step::Bomb,
+            Step::Bomb => Step::TopOut,
+            Step::TopOut => Step::Timer,
+            Step::Timer | Step::Done => Step::Done,
+        }
+    }
+}
+
+// Single player traditional game, except that topping out shows the timer instead of ending the game
+pub fn create_game(client_id: u64, name: &str, color: u8) -> Game {
+    let mut game = Game::new(Mode::Traditional);
+    game.set_block_seed(TUTORIAL_SEED, false);
+    game.set_normal_block_factory(|generator| {
+        FallingBlock::normal_from_shape(*TUTORIAL_SHAPES.choose(&mut generator.shape_rng).unwrap())
+    });
+    game.set_special_blocks_enabled(false);
+    game.set_waiting_ends_game(false);
+    let ok = game.add_player(&PlayerSpec {
+        client_id,
+        lobby_order: 0,
+        name: name.to_string(),
+        color,
+        handicap: 0,
+    });
+    assert!(ok);
+    game
+}
+
+// (landed count, x of center, shape) of the falling block
+type BlockSnapshot = (usize, i32, Vec<BlockRelativeCoords>);
+
+fn take_block_snapshot(game: &Game) -> Option<BlockSnapshot> {
+    let player = game.players[0].borrow();
+    match &player.block_or_timer {
+        BlockOrTimer::Block(block) => Some((
+            player.landed_count,
+            block.center.0,
+            block.get_relative_coords().to_vec(),
+        )),
+        _ => None,
+    }
+}
+
+fn replace_falling_block(game: &mut Game, block_type: BlockType) {
+    let mut player = game.players[0].borrow_mut();
+    let player = &mut *player;
+    let mut block = FallingBlock::new(block_type, &mut player.block_generator.rng);
+    block.spawn_at(player.spawn_point);
+    player.block_or_timer = BlockOrTimer::Block(block);
+    player.fast_down = false;
+}
+
+// Rows that can't be cleared, because they have a hole
+fn add_gray_rows(game: &mut Game, first_y: usize) {
+    let w = game.get_width();
+    for y in first_y..game.get_height() {
+        let hole_x = (3 * y) % w;
+        for x in 0..w {
+            let square = if x == hole_x {
+                None
+            } else {
+                Some(SquareContent::with_color(Color::GRAY_BACKGROUND))
+            };
+            game.set_landed_square((x as i16, y as i16), square);
+        }
+    }
+}
+
+fn bomb_exists(game: &Game) -> bool {
+    let is_bomb = |content: &SquareContent| matches!(content, SquareContent::Bomb { .. });
+
+    let player = game.players[0].borrow();
+    if let BlockOrTimer::Block(block) = &player.block_or_timer {
+        if is_bomb(&block.square_content) {
+            return true;
+        }
+    }
+    if let Some(block) = &player.block_in_hold {
+        if is_bomb(&block.square_content) {
+            return true;
+        }
+    }
+
+    (0..game.get_height()).any(|y| {
+        (0..game.get_width()).any(|x| {
+            game.get_landed_square((x as i16, y as i16))
+                .is_some_and(|content| is_bomb(&content))
+        })
+    })
+}
+
+// Watches what the player does and moves on to the next step when the prompt has been followed
+pub struct Tutorial {
+    pub step: Step,
+    landed_count: usize,                   // when the step started
+    block_snapshot: Option<BlockSnapshot>, // for noticing moving and rotating
+}
+
+impl Tutorial {
+    pub fn new(game: &Game) -> Self {
+        Self {
+            step: Step::Move,
+            landed_count: game.players[0].borrow().landed_count,
+            block_snapshot: take_block_snapshot(game),
+        }
+    }
+
+    fn is_step_done(&mut self, game: &Game) -> bool {
+        let player = game.players[0].borrow();
+        match self.step {
+            Step::Move | Step::Rotate => {
+                let new_snapshot = take_block_snapshot(game);
+                let done = match (&self.block_snapshot, &new_snapshot) {
+                    // Same block as before
+                    (Some((old_landed, old_x, old_shape)), Some((landed, x, shape)))
+                        if old_landed == landed =>
+                    {
+                        if self.step == Step::Move {
+                            old_x != x
+                        } else {
+                            old_shape != shape
+                        }
+                    }
+                    _ => false,
+                };
+                self.block_snapshot = new_snapshot;
+                done
+            }
+            Step::SoftDrop => player.fast_down,
+            Step::Hold => player.block_in_hold.is_some(),
+            Step::Drill => player.landed_count > self.landed_count,
+            Step::Bomb => !bomb_exists(game),
+            Step::TopOut => !matches!(player.block_or_timer, BlockOrTimer::Block(_)),
+            Step::Timer => matches!(player.block_or_timer, BlockOrTimer::Block(_)),
+            Step::Done => false,
+        }
+    }
+
+    fn start_step(&mut self, game: &mut Game) {
+        match self.step {
+            Step::Drill => {
+                add_gray_rows(game, game.get_height() - 4);
+                replace_falling_block(game, BlockType::Drill);
+            }
+            Step::Bomb => replace_falling_block(game, BlockType::Bomb { initial_timer: 5 }),
+            Step::TopOut => {
+                add_gray_rows(game, 6);
+                replace_falling_block(game, BlockType::Normal);
+            }
+            _ => {}
+        }
+        self.landed_count = game.players[0].borrow().landed_count;
+        self.block_snapshot = take_block_snapshot(game);
+    }
+
+    // Call this whenever the game changes. Returns true if the step changed.
+    pub fn update(&mut self, game: &mut Game) -> bool {
+        if self.is_step_done(game) {
+            self.step = self.step.next();
+            self.start_step(game);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::escapes::KeyPress;
+
+    fn press(game: &mut Game, tutorial: &mut Tutorial, key: KeyPress) {
+        game.handle_key_press(1, false, true, key);
+        tutorial.update(game);
+    }
+
+    // Does what the game wrapper does, until the step changes
+    fn play_until_step_changes(game: &mut Game, tutorial: &mut Tutorial) {
+        let step = tutorial.step;
+        let mut bomb_ids = vec![];
+        for _ in 0..1000 {
+            game.move_blocks_down(true);
+            game.move_blocks_down(false);
+            let full = game.find_full_rows_and_increment_score();
+            game.remove_full_rows(&full);
+
+            // One tick per block movement, roughly like with real timers
+            bomb_ids.extend(game.start_ticking_new_bombs());
+            bomb_ids.retain(|id| match game.tick_bombs_by_id(*id) {
+                Some(mut centers) => {
+                    while !centers.is_empty() {
+                        let flashing = game.get_points_to_flash(&centers);
+                        centers = game.finish_explosion(&centers, &flashing);
+                    }
+                    true
+                }
+                None => false,
+            });
+            game.start_pending_please_wait_counters().unwrap();
+            let waiting = matches!(
+                game.players[0].borrow().block_or_timer,
+                BlockOrTimer::Timer(_)
+            );
+            if waiting {
+                game.tick_please_wait_counter(1);
+            }
+
+            tutorial.update(game);
+            if tutorial.step != step {
+                return;
+            }
+        }
+        panic!("stuck in tutorial step {:?}", step);
+    }
+
+    #[test]
+    fn test_tutorial_script() {
+        let mut game = create_game(1, "Alice", 32);
+        let mut tutorial = Tutorial::new(&game);
+        assert_eq!(tutorial.step, Step::Move);
+
+        // Nothing happens without the right key presses
+        press(&mut game, &mut tutorial, KeyPress::Up);
+        assert_eq!(tutorial.step, Step::Move);
+        press(&mut game, &mut tutorial, KeyPress::Left);
+        assert_eq!(tutorial.step, Step::Rotate);
+
+        press(&mut game, &mut tutorial, KeyPress::Right);
+        assert_eq!(tutorial.step, Step::Rotate);
+        press(&mut game, &mut tutorial, KeyPress::Character('w'));
+        assert_eq!(tutorial.step, Step::SoftDrop);
+
+        press(&mut game, &mut tutorial, KeyPress::Down);
+        assert_eq!(tutorial.step, Step::Hold);
+
+        press(&mut game, &mut tutorial, KeyPress::Character('h'));
+        assert_eq!(tutorial.step, Step::Drill);
+        assert!(matches!(
+            &game.players[0].borrow().block_or_timer,
+            BlockOrTimer::Block(block) if matches!(block.square_content, SquareContent::FallingDrill { .. })
+        ));
+
+        play_until_step_changes(&mut game, &mut tutorial);
+        assert_eq!(tutorial.step, Step::Bomb);
+        assert!(bomb_exists(&game));
+
+        play_until_step_changes(&mut game, &mut tutorial);
+        assert_eq!(tutorial.step, Step::TopOut);
+        assert!(!bomb_exists(&game));
+
+        play_until_step_changes(&mut game, &mut tutorial);
+        assert_eq!(tutorial.step, Step::Timer);
+        // The game didn't end, even though the only player has to wait
+        assert!(matches!(
+            game.players[0].borrow().block_or_timer,
+            BlockOrTimer::TimerPending | BlockOrTimer::Timer(_)
+        ));
+
+        play_until_step_changes(&mut game, &mut tutorial);
+        assert_eq!(tutorial.step, Step::Done);
+        assert!(!tutorial.update(&mut game));
+    }
+}
@@ -1,31 +1,56 @@
-use crate::client::log_for_client;
 use crate::client::Client;
 use crate::escapes::Color;
 use crate::escapes::KeyPress;
+use crate::game_logic::game::format_score;
+use crate::game_logic::game::score_multiplier;
+use crate::game_logic::game::Game;
 use crate::game_logic::game::Mode;
 use crate::game_wrapper;
+use crate::game_wrapper::GameClock;
 use crate::game_wrapper::GameStatus;
+use crate::game_wrapper::GameWrapper;
 use crate::game_wrapper::HighScoresStatus;
+use crate::game_wrapper::SoundEvent;
 use crate::high_scores::GameResult;
+use crate::high_scores::SprintRecord;
+use crate::high_scores::SPRINT_ROW_COUNT;
 use crate::ingame_ui;
+use crate::ip_tracker::IpTracker;
+use crate::lobby::find_lobby_by_player_name;
+use crate::lobby::get_possibly_intended_ids;
+use crate::lobby::is_local_player_id;
 use crate::lobby::join_game_in_a_lobby;
 use crate::lobby::looks_like_lobby_id;
+use crate::lobby::JoinError;
 use crate::lobby::Lobbies;
 use crate::lobby::Lobby;
+use crate::lobby::RematchStatus;
 use crate::lobby::MAX_CLIENTS_PER_LOBBY;
+use crate::logging::EventKind;
+use crate::name_metrics::NameEvent;
+use crate::name_metrics::NameMetrics;
+use crate::prefs;
+use crate::prefs::Prefs;
 use crate::render;
 use crate::render::RenderBuffer;
+use crate::reports;
+use crate::reports::Report;
+use crate::strings::lookup;
+use crate::tutorial;
+use crate::tutorial::Tutorial;
 use chrono::Utc;
 use std::collections::HashSet;
 use std::io;
 use std::io::ErrorKind;
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::sync::OnceLock;
 use std::time::Duration;
 use std::time::Instant;
 use tokio::fs::OpenOptions;
 use tokio::io::AsyncBufReadExt;
 use tokio::io::BufReader;
+use tokio::sync::broadcast;
 use tokio::sync::watch;
 
 const ASCII_ART: &[&str] = &[
@@ -45,85 +70,202 @@ fn add_ascii_art(buffer: &mut RenderBuffer) {
     }
 }
 
-async fn prompt<F>(
-    client: &mut Client,
-    prompt: &str,
-    mut enter_pressed_callback: F,
+const RATE_LIMITED_PROMPT_ERROR_DELAY: Duration = Duration::from_millis(200);
+
+// One text input on a form
+struct FormField {
+    label: String,
+    max_len: usize, // important to have, otherwise someone could fill the server's memory
+    // Returns an error message, checked for each field when Enter is pressed
+    validator: Option<fn(&str) -> Option<String>>,
+    text: String,
+    truncated: bool, // true if some characters were ignored because the text was too long
+}
+
+impl FormField {
+    fn new(label: &str, max_len: usize) -> Self {
+        Self {
+            label: label.to_string(),
+            max_len,
+            validator: None,
+            text: "".to_string(),
+            truncated: false,
+        }
+    }
+
+    fn value(&self) -> &str {
+        self.text.trim()
+    }
+}
+
+// Text inputs on the same screen. Tab and arrow keys move between them, and Enter submits.
+struct Form {
+    fields: Vec<FormField>,
+    focused_index: usize,
     add_extra_text: Option<fn(&mut RenderBuffer)>,
     min_duration_between_enter_presses: Duration,
-) -> Result<(), io::Error>
-where
-    F: FnMut(&str, &mut Client) -> Option<String>,
-{
-    let mut error = Some("".to_string());
-    let mut current_text = "".to_string();
-    let mut last_enter_press: Option<Instant> = None;
+}
 
-    loop {
-        {
-            let mut render_data = client.render_data.lock().unwrap();
-            render_data.clear(80, 24);
+impl Form {
+    fn new(fields: Vec<FormField>) -> Self {
+        assert!(!fields.is_empty());
+        Self {
+            fields,
+            focused_index: 0,
+            add_extra_text: None,
+            min_duration_between_enter_presses: Duration::ZERO,
+        }
+    }
 
-            add_ascii_art(&mut render_data.buffer);
-            let mut x = render_data.buffer.add_text(20, 10, prompt);
-            x = render_data.buffer.add_text(x, 10, &current_text);
-            render_data.cursor_pos = Some((x, 10));
-            render_data.buffer.add_text_with_color(
-                2,
-                13,
-                &error.clone().unwrap_or_default(),
-                Color::RED_FOREGROUND,
-            );
-            if let Some(f) = add_extra_text {
-                f(&mut render_data.buffer);
+    fn render(&self, buffer: &mut RenderBuffer, error: &str) -> (usize, usize) {
+        add_ascii_art(buffer);
+        let text_x = 20
+            + self
+                .fields
+                .iter()
+                .map(|f| f.label.chars().count())
+                .max()
+                .unwrap();
+        let mut cursor_pos = (0, 0);
+        for (i, field) in self.fields.iter().enumerate() {
+            let y = 10 + 2 * i;
+            buffer.add_text(text_x - field.label.chars().count(), y, &field.label);
+            let x = buffer.add_text(text_x, y, &field.text);
+            if i == self.focused_index {
+                cursor_pos = (x, y);
             }
-
-            render_data.changed.notify_one();
         }
+        let error_y = 11 + 2 * self.fields.len();
+        buffer.add_text_with_color(2, error_y, error, Color::RED_FOREGROUND);
+        if let Some(f) = self.add_extra_text {
+            f(buffer);
+        }
+        cursor_pos
+    }
 
-        match client.receive_key_press().await? {
-            /*
-            \r\n: Enter press in windows cmd.exe
-            \r:   Enter press in other os with raw mode
-            \n:   Enter press in other os without raw mode (bad)
-
-            \r is also known as KeyPress::Enter. If we haven't gotten that
-            yet, and we get \n, it means someone forgot to set raw mode.
-            */
-            KeyPress::Character('\n') if last_enter_press == None => {
-                error = Some(
-                    "Your terminal doesn't seem to be in raw mode. Run 'stty raw' and try again."
-                        .to_string(),
-                );
+    // Returns true when Enter is pressed
+    fn handle_key_press(&mut self, key: KeyPress) -> bool {
+        let count = self.fields.len();
+        let field = &mut self.fields[self.focused_index];
+        match key {
+            KeyPress::Character('\t') | KeyPress::Down => {
+                self.focused_index = (self.focused_index + 1) % count;
             }
-            KeyPress::Character(ch) => {
-                // 15 chars is enough for names and lobby IDs
-                // It's important to have limit (potential out of mem dos attack otherwise)
-                if current_text.chars().count() < 15 {
-                    current_text.push(ch);
-                }
+            KeyPress::Up => {
+                self.focused_index = (self.focused_index + count - 1) % count;
+            }
+            // Pasted text can contain e.g. escape characters, and we don't want to echo them
+            KeyPress::Character(ch) if ch.is_control() => {}
+            KeyPress::Character(ch) if field.text.chars().count() < field.max_len => {
+                field.text.push(ch);
+            }
+            KeyPress::Character(_) => {
+                field.truncated = true;
             }
             KeyPress::BackSpace => {
-                current_text.pop();
+                field.text.pop();
             }
-            KeyPress::Enter => {
-                if last_enter_press == None
-                    || last_enter_press.unwrap().elapsed() > min_duration_between_enter_presses
-                {
-                    last_enter_press = Some(Instant::now());
-                    error = enter_pressed_callback(current_text.trim(), client);
-                    if error == None {
+            KeyPress::Enter => return true,
+            _ => {}
+        }
+        false
+    }
+
+    // Moves focus to the first invalid field and returns its error
+    fn validate(&mut self) -> Option<String> {
+        for (i, field) in self.fields.iter().enumerate() {
+            if let Some(error) = field.validator.and_then(|validate| validate(field.value())) {
+                self.focused_index = i;
+                return Some(error);
+            }
+        }
+        None
+    }
+
+    // The callback gets called after all fields are valid, and it can return an error message
+    async fn run<F>(&mut self, client: &mut Client, mut on_submit: F) -> Result<(), io::Error>
+    where
+        F: FnMut(&[FormField], &mut Client) -> Option<String>,
+    {
+        let mut error: Option<String> = None;
+        let mut last_enter_press: Option<Instant> = None;
+
+        loop {
+            {
+                let mut render_data = client.render_data.lock().unwrap();
+                render_data.clear(80, 24);
+                let cursor_pos =
+                    self.render(&mut render_data.buffer, error.as_deref().unwrap_or(""));
+                render_data.cursor_pos = Some(cursor_pos);
+                render_data.mark_changed();
+            }
+
+            match client.receive_key_press().await? {
+                /*
+                \r\n: Enter press in windows cmd.exe
+                \r:   Enter press in other os with raw mode
+                \n:   Enter press in other os without raw mode (bad)
+
+                \r is also known as KeyPress::Enter. If we haven't gotten that
+                yet, and we get \n, it means someone forgot to set raw mode.
+                */
+                KeyPress::Character('\n') if last_enter_press.is_none() => {
+                    error = Some(lookup("form.not_raw_mode").to_string());
+                }
+                KeyPress::Enter
+                    if last_enter_press.is_some_and(|last| {
+                        last.elapsed() <= self.min_duration_between_enter_presses
+                    }) => {}
+                key => {
+                    if !self.handle_key_press(key) {
+                        continue;
+                    }
+                    let enter_time = Instant::now();
+                    last_enter_press = Some(enter_time);
+                    error = self.validate().or_else(|| on_submit(&self.fields, client));
+                    // The callback already saw the ignored characters, don't report them again
+                    for field in &mut self.fields {
+                        field.truncated = false;
+                    }
+                    if error.is_some() && !self.min_duration_between_enter_presses.is_zero() {
+                        // Rate limited prompts are for guessing-sensitive things, such as lobby IDs.
+                        // Errors appear after a fixed time, so the timing doesn't reveal which error it was.
+                        let show_time = enter_time + RATE_LIMITED_PROMPT_ERROR_DELAY;
+                        tokio::time::sleep_until(tokio::time::Instant::from_std(show_time)).await;
+                    }
+                    if error.is_none() {
                         // With xterm emulating VT52, the enter press tends to leave ^M visible after typing name
                         client.render_data.lock().unwrap().force_redraw = true;
                         return Ok(());
                     }
                 }
             }
-            _ => {}
         }
     }
 }
 
+// A form with only one field
+async fn prompt<F>(
+    client: &mut Client,
+    prompt: &str,
+    mut enter_pressed_callback: F,
+    add_extra_text: Option<fn(&mut RenderBuffer)>,
+    min_duration_between_enter_presses: Duration,
+) -> Result<(), io::Error>
+where
+    // The bool is true if some characters were ignored because the text was too long
+    F: FnMut(&str, bool, &mut Client) -> Option<String>,
+{
+    // 15 chars is enough for names and lobby IDs
+    let mut form = Form::new(vec![FormField::new(prompt, 15)]);
+    form.add_extra_text = add_extra_text;
+    form.min_duration_between_enter_presses = min_duration_between_enter_presses;
+    form.run(client, |fields, client| {
+        enter_pressed_callback(fields[0].value(), fields[0].truncated, client)
+    })
+    .await
+}
+
 // I started with all 256 latin-1 chars and removed some of them.
 // It's important to ban characters that are more than 1 unit wide on terminal.
 const VALID_NAME_CHARS: &str = concat!(
@@ -134,35 +276,49 @@ const VALID_NAME_CHARS: &str = concat!(
     "ÀÁÂÃÄÅÆÇÈÉÊËÌÍÎÏÐÑÒÓÔÕÖØÙÚÛÜÝÞßàáâãäåæçèéêëìíîïðñòóôõöøùúûüýþÿ∀",
 );
 
-fn add_name_asking_notes(buffer: &mut RenderBuffer) {
-    buffer.add_centered_text(15, "If you play well, your name will be");
-    buffer.add_centered_text(16, "visible to everyone in the high scores.");
+// For texts in strings.rs that have several lines
+fn add_centered_lines(buffer: &mut RenderBuffer, top_y: usize, text: &str) {
+    for (i, line) in text.lines().enumerate() {
+        buffer.add_centered_text(top_y + i, line);
+    }
+}
 
-    buffer.add_centered_text(18, "Your IP will be logged on the server only if you");
-    buffer.add_centered_text(19, "connect 5 or more times within the same minute.");
+fn add_name_asking_notes(buffer: &mut RenderBuffer) {
+    add_centered_lines(buffer, 15, lookup("name.notes"));
+}
 
-    buffer.add_centered_text(21, "Source code: https://github.com/Akuli/catris");
+fn find_invalid_name_char(name: &str) -> Option<char> {
+    name.chars().find(|ch| !VALID_NAME_CHARS.contains(*ch))
 }
 
 pub async fn ask_name(
     client: &mut Client,
     used_names: Arc<Mutex<HashSet<String>>>,
+    name_metrics: Arc<Mutex<NameMetrics>>,
 ) -> Result<(), io::Error> {
     prompt(
         client,
-        "Name: ",
-        |name, client| {
+        lookup("name.prompt"),
+        |name, truncated, client| {
+            let is_websocket = client.is_connected_with_websocket();
+            let record = |event| name_metrics.lock().unwrap().record(is_websocket, event);
+
+            if truncated {
+                record(NameEvent::TooLong);
+            }
             if name.is_empty() {
-                return Some("Please write a name before pressing Enter.".to_string());
+                record(NameEvent::Empty);
+                return Some(lookup("name.empty").to_string());
             }
-            for ch in name.chars() {
-                if !VALID_NAME_CHARS.contains(ch) {
-                    return Some(format!("The name can't contain a '{}' character.", ch));
-                }
+            if let Some(ch) = find_invalid_name_char(name) {
+                record(NameEvent::InvalidChar);
+                return Some(lookup("name.invalid_char").replace("{char}", &ch.to_string()));
             }
             if !client.set_name(name, used_names.clone()) {
-                return Some("This name is in use. Try a different name.".to_string());
+                record(NameEvent::InUse);
+                return Some(lookup("name.in_use").to_string());
             }
+            record(NameEvent::Registered);
             None
         },
         Some(add_name_asking_notes),
@@ -172,41 +328,239 @@ pub async fn ask_name(
     Ok(())
 }
 
+// The local player plays on the same connection, see play_game()
+pub async fn ask_local_player_name(client: &mut Client) -> Result<(), io::Error> {
+    prompt(
+        client,
+        lookup("local_player.prompt"),
+        |name, _, client| {
+            if name.is_empty() {
+                return Some(lookup("name.empty").to_string());
+            }
+            if let Some(ch) = find_invalid_name_char(name) {
+                return Some(lookup("name.invalid_char").replace("{char}", &ch.to_string()));
+            }
+            if client
+                .lobby
+                .as_ref()
+                .unwrap()
+                .lock()
+                .unwrap()
+                .lobby_is_full()
+            {
+                return Some(
+                    lookup("local_player.lobby_full")
+                        .replace("{count}", &MAX_CLIENTS_PER_LOBBY.to_string()),
+                );
+            }
+            if !client.add_local_player(name) {
+                return Some(lookup("name.in_use").to_string());
+            }
+            None
+        },
+        Some(add_local_player_notes),
+        Duration::ZERO,
+    )
+    .await?;
+    Ok(())
+}
+
+pub async fn ask_block_seed(client: &mut Client) -> Result<(), io::Error> {
+    prompt(
+        client,
+        lookup("seed.prompt"),
+        |text, _, client| {
+            let seed = if text.is_empty() {
+                None
+            } else {
+                match text.parse::<u64>() {
+                    Ok(seed) => Some(seed),
+                    Err(_) => return Some(lookup("seed.not_a_number").to_string()),
+                }
+            };
+            let mut lobby = client.lobby.as_ref().unwrap().lock().unwrap();
+            if lobby.can_change_settings(client.id) {
+                lobby.set_block_seed(seed);
+            }
+            None
+        },
+        Some(add_block_seed_notes),
+        Duration::ZERO,
+    )
+    .await?;
+    Ok(())
+}
+
+fn add_block_seed_notes(buffer: &mut RenderBuffer) {
+    add_centered_lines(buffer, 15, lookup("seed.notes"));
+}
+
+fn add_local_player_notes(buffer: &mut RenderBuffer) {
+    add_centered_lines(buffer, 15, lookup("local_player.notes"));
+}
+
+// Returns an error message on failure
+fn join_lobby_by_id(client: &mut Client, lobbies: &Lobbies, id: &str) -> Option<String> {
+    // If the ID contains e.g. "1" instead of "L", look up what the user meant
+    let lobbies = lobbies.lock().unwrap();
+    let mut found: Vec<(String, Arc<Mutex<Lobby>>)> = get_possibly_intended_ids(id)
+        .into_iter()
+        .filter_map(|id| lobbies.get(&id).map(|lobby| (id, lobby)))
+        .collect();
+
+    if found.len() == 1 {
+        let (id, lobby) = found.pop().unwrap();
+        if client.join_lobby(lobby) {
+            None
+        } else {
+            Some(
+                lookup("join.lobby_full")
+                    .replace("{id}", &id)
+                    .replace("{count}", &MAX_CLIENTS_PER_LOBBY.to_string()),
+            )
+        }
+    } else {
+        Some(lookup("join.no_such_lobby").replace("{id}", id))
+    }
+}
+
+// Returns an error message, or None if the client joined a lobby
+fn join_lobby_with_ip_limit(
+    client: &mut Client,
+    ip_tracker: &Mutex<IpTracker>,
+    join: impl FnOnce(&mut Client) -> Option<String>,
+) -> Option<String> {
+    // Clients without an IP are tests
+    let now = Instant::now();
+    if let Some(ip) = client.ip {
+        if ip_tracker.lock().unwrap().lobby_attempts_blocked(ip, now) {
+            return Some(lookup("join.too_many_attempts").to_string());
+        }
+    }
+
+    let error = join(client);
+    if let (Some(ip), Some(_)) = (client.ip, &error) {
+        if ip_tracker
+            .lock()
+            .unwrap()
+            .record_failed_lobby_attempt(ip, now)
+        {
+            client
+                .logger
+                .log("Too many failed lobby joins from this IP, blocking for a while");
+        }
+    }
+    error
+}
+
 pub async fn ask_lobby_id_and_join_lobby(
     client: &mut Client,
     lobbies: Lobbies,
+    ip_tracker: Arc<Mutex<IpTracker>>,
 ) -> Result<(), io::Error> {
     prompt(
         client,
-        "Lobby ID (6 characters): ",
-        |id, client| {
+        lookup("join.id_prompt"),
+        |id, _, client| {
             let id = id.to_uppercase();
             if !looks_like_lobby_id(&id) {
-                return Some("The text you entered doesn't look like a lobby ID.".to_string());
+                return Some(lookup("join.bad_id").to_string());
             }
+            join_lobby_with_ip_limit(client, &ip_tracker, |client| {
+                join_lobby_by_id(client, &lobbies, &id)
+            })
+        },
+        None,
+        // prevent brute-force-guessing lobby IDs, max 1 attempt per second
+        Duration::from_secs(1),
+    )
+    .await?;
+    Ok(())
+}
 
-            let lobbies = lobbies.lock().unwrap();
-            return if let Some(lobby) = lobbies.get(&id) {
+pub async fn ask_player_name_and_join_lobby(
+    client: &mut Client,
+    lobbies: Lobbies,
+    ip_tracker: Arc<Mutex<IpTracker>>,
+) -> Result<(), io::Error> {
+    prompt(
+        client,
+        lookup("join.name_prompt"),
+        |name, _, client| {
+            join_lobby_with_ip_limit(client, &ip_tracker, |client| {
+                // Unlisted lobbies look just like lobbies that don't exist
+                let lobby = match find_lobby_by_player_name(&lobbies, name) {
+                    Some(lobby) => lobby,
+                    None => return Some(lookup("join.no_such_player").replace("{name}", name)),
+                };
                 if client.join_lobby(lobby) {
                     None
                 } else {
-                    Some(format!(
-                        "Lobby '{}' is full. It already has {} players.",
-                        id, MAX_CLIENTS_PER_LOBBY
-                    ))
+                    Some(
+                        lookup("join.player_lobby_full")
+                            .replace("{name}", name)
+                            .replace("{count}", &MAX_CLIENTS_PER_LOBBY.to_string()),
+                    )
                 }
-            } else {
-                Some(format!("There is no lobby with ID '{}'.", id))
-            };
+            })
         },
         None,
-        // prevent brute-force-guessing lobby IDs, max 1 attempt per second
+        // prevent guessing names to find lobbies, max 1 attempt per second
         Duration::from_secs(1),
     )
     .await?;
     Ok(())
 }
 
+fn add_problem_report_notes(buffer: &mut RenderBuffer) {
+    add_centered_lines(buffer, 17, lookup("report.notes"));
+}
+
+fn check_problem_description(text: &str) -> Option<String> {
+    if text.is_empty() {
+        Some(lookup("report.empty").to_string())
+    } else {
+        None
+    }
+}
+
+async fn ask_problem_report(client: &mut Client) -> Result<Report, io::Error> {
+    let mut description = FormField::new(lookup("report.problem"), 38);
+    description.validator = Some(check_problem_description);
+    let mut form = Form::new(vec![
+        description,
+        FormField::new(lookup("report.contact"), 38),
+    ]);
+    form.add_extra_text = Some(add_problem_report_notes);
+
+    let mut report = None;
+    form.run(client, |fields, client| {
+        report = Some(Report {
+            name: client.get_name().unwrap_or_default().to_string(),
+            lobby_id: client
+                .lobby
+                .as_ref()
+                .map(|lobby| lobby.lock().unwrap().id.clone()),
+            description: fields[0].value().to_string(),
+            contact: fields[1].value().to_string(),
+        });
+        None
+    })
+    .await?;
+    Ok(report.unwrap())
+}
+
+pub async fn report_problem(client: &mut Client) -> Result<(), io::Error> {
+    let report = ask_problem_report(client).await?;
+    match reports::append(&report).await {
+        Ok(()) => client.logger.log("Problem reported"),
+        Err(e) => client
+            .logger
+            .log(&format!("Saving problem report failed: {}", e)),
+    }
+    Ok(())
+}
+
 struct Menu {
     items: Vec<Option<String>>, // None is a separator
     selected_index: usize,
@@ -239,34 +593,44 @@ impl Menu {
         }
     }
 
+    // Wraps around at the ends, skipping separators
+    fn move_selection(&mut self, forward: bool) {
+        let len = self.items.len();
+        loop {
+            self.selected_index = if forward {
+                (self.selected_index + 1) % len
+            } else {
+                (self.selected_index + len - 1) % len
+            };
+            if self.items[self.selected_index].is_some() {
+                break;
+            }
+        }
+    }
+
     // true means enter pressed
     fn handle_key_press(&mut self, key: KeyPress) -> bool {
-        let last = self.items.len() - 1;
         match key {
-            KeyPress::Up if self.selected_index != 0 => {
-                self.selected_index -= 1;
-                while self.items[self.selected_index].is_none() {
-                    self.selected_index -= 1;
-                }
+            KeyPress::Up => self.move_selection(false),
+            KeyPress::Down => self.move_selection(true),
+            KeyPress::Home => {
+                self.selected_index = self.items.iter().position(|i| i.is_some()).unwrap();
             }
-            KeyPress::Down if self.selected_index != last => {
-                self.selected_index += 1;
-                while self.items[self.selected_index].is_none() {
-                    self.selected_index += 1;
-                }
+            KeyPress::End => {
+                self.selected_index = self.items.iter().rposition(|i| i.is_some()).unwrap();
             }
             KeyPress::Character(ch) => {
-                // pressing r selects Ring Game
-                for (i, item) in self.items.iter().enumerate() {
-                    if item
-                        .as_ref()
-                        .unwrap_or(&"".to_string())
-                        .to_lowercase()
-                        .starts_with(&ch.to_lowercase().to_string())
-                    {
-                        self.selected_index = i;
-                        break;
-                    }
+                // pressing r selects Ring Game, pressing it again goes to the next item with r
+                let prefix = ch.to_lowercase().to_string();
+                let len = self.items.len();
+                let found = (1..=len)
+                    .map(|offset| (self.selected_index + offset) % len)
+                    .find(|&i| match &self.items[i] {
+                        Some(text) => text.to_lowercase().starts_with(&prefix),
+                        None => false,
+                    });
+                if let Some(i) = found {
+                    self.selected_index = i;
                 }
             }
             KeyPress::Enter => {
@@ -278,11 +642,14 @@ impl Menu {
     }
 }
 
-async fn read_motd() -> Result<Vec<String>, io::Error> {
-    let file = OpenOptions::new()
-        .read(true)
-        .open("catris_motd.txt")
-        .await?;
+pub const MOTD_FILENAME: &str = "catris_motd.txt";
+
+pub async fn read_motd() -> Result<Vec<String>, io::Error> {
+    read_motd_file(MOTD_FILENAME).await
+}
+
+pub async fn read_motd_file(filename: &str) -> Result<Vec<String>, io::Error> {
+    let file = OpenOptions::new().read(true).open(filename).await?;
     let buf_reader = BufReader::new(file);
     let mut lines = buf_reader.lines();
     let mut result = vec![];
@@ -292,55 +659,137 @@ async fn read_motd() -> Result<Vec<String>, io::Error> {
     Ok(result)
 }
 
-pub async fn ask_if_new_lobby(client: &mut Client) -> Result<bool, io::Error> {
+fn add_settings_passphrase_notes(buffer: &mut RenderBuffer) {
+    add_centered_lines(buffer, 15, lookup("passphrase.notes"));
+}
+
+// Returns a message to show in the menu
+async fn ask_settings_passphrase(client: &mut Client) -> Result<&'static str, io::Error> {
+    let mut passphrase = "".to_string();
+    prompt(
+        client,
+        lookup("passphrase.prompt"),
+        |text, _, _| {
+            if text.is_empty() {
+                return Some(lookup("passphrase.empty").to_string());
+            }
+            passphrase = text.to_string();
+            None
+        },
+        Some(add_settings_passphrase_notes),
+        Duration::ZERO,
+    )
+    .await?;
+
+    let name = client.get_name().unwrap().to_string();
+    let message = match prefs::load(&name, &passphrase).await {
+        Ok(Some(loaded)) => {
+            loaded.apply_to_client(client);
+            lookup("passphrase.loaded")
+        }
+        Ok(None) => match prefs::store(&name, &passphrase, Prefs::from_client(client)).await {
+            Ok(()) => lookup("passphrase.new"),
+            Err(e) => {
+                client.logger.log(&format!("Saving settings failed: {}", e));
+                lookup("passphrase.save_failed")
+            }
+        },
+        Err(e) => {
+            client
+                .logger
+                .log(&format!("Loading settings failed: {}", e));
+            lookup("passphrase.load_failed")
+        }
+    };
+    client.settings_passphrase = Some(passphrase);
+    Ok(message)
+}
+
+// Same setting as pressing G while playing, but can be turned on before seeing any flashing
+fn reduced_flashing_menu_item(client: &Client) -> String {
+    if client.reduced_flashing {
+        lookup("menu.reduced_flashing_on").to_string()
+    } else {
+        lookup("menu.reduced_flashing_off").to_string()
+    }
+}
+
+#[derive(PartialEq, Debug)]
+pub enum LobbyChoice {
+    NewLobby,
+    JoinById,
+    JoinByPlayerName,
+    Tutorial,
+}
+
+pub async fn ask_lobby_choice(client: &mut Client) -> Result<LobbyChoice, io::Error> {
     let motd = match read_motd().await {
         Ok(lines) => lines,
         Err(e) if e.kind() == ErrorKind::NotFound => vec![],
         Err(e) => {
-            log_for_client(client.id, &format!("reading motd file failed: {:?}", e));
+            client
+                .logger
+                .log(&format!("reading motd file failed: {:?}", e));
             vec![]
         }
     };
     let mut menu = Menu {
         items: vec![
-            Some("New lobby".to_string()),
-            Some("Join an existing lobby".to_string()),
-            Some("Quit".to_string()),
+            Some(lookup("menu.new_lobby").to_string()),
+            Some(lookup("menu.join_by_id").to_string()),
+            Some(lookup("menu.join_by_name").to_string()),
+            Some(lookup("menu.tutorial").to_string()),
+            Some(lookup("menu.settings_passphrase").to_string()),
+            Some(reduced_flashing_menu_item(client)),
+            Some(lookup("menu.quit").to_string()),
         ],
         selected_index: 0,
     };
+    let reduced_flashing_index = menu.items.len() - 2;
+    let mut settings_message = None;
 
     loop {
+        // Changes when selected, and loading settings with a passphrase can change it too
+        menu.items[reduced_flashing_index] = Some(reduced_flashing_menu_item(client));
         {
             let mut render_data = client.render_data.lock().unwrap();
             render_data.clear(80, 24);
 
             add_ascii_art(&mut render_data.buffer);
             menu.render(&mut render_data.buffer, 10);
-            render_data
-                .buffer
-                .add_centered_text(16, "If you want to play alone, just make a new lobby.");
-            render_data.buffer.add_centered_text(
-                17,
-                "For multiplayer, one player makes a lobby and others join it.",
-            );
+            add_centered_lines(&mut render_data.buffer, 17, lookup("menu.intro"));
+            if let Some(message) = settings_message {
+                render_data.buffer.add_centered_text(19, message);
+            }
             for (i, line) in motd.iter().enumerate() {
                 render_data.buffer.add_centered_text_with_color(
-                    19 + i,
+                    20 + i,
                     line,
                     Color::GREEN_FOREGROUND,
                 );
             }
 
-            render_data.changed.notify_one();
+            render_data.mark_changed();
         }
 
         let key = client.receive_key_press().await?;
         if menu.handle_key_press(key) {
             return match menu.selected_text() {
-                "New lobby" => Ok(true),
-                "Join an existing lobby" => Ok(false),
-                "Quit" => Err(io::Error::new(
+                t if t == lookup("menu.new_lobby") => Ok(LobbyChoice::NewLobby),
+                t if t == lookup("menu.join_by_id") => Ok(LobbyChoice::JoinById),
+                t if t == lookup("menu.join_by_name") => Ok(LobbyChoice::JoinByPlayerName),
+                t if t == lookup("menu.tutorial") => Ok(LobbyChoice::Tutorial),
+                t if t == lookup("menu.settings_passphrase") => {
+                    settings_message = Some(ask_settings_passphrase(client).await?);
+                    continue;
+                }
+                t if t == lookup("menu.reduced_flashing_off")
+                    || t == lookup("menu.reduced_flashing_on") =>
+                {
+                    client.reduced_flashing = !client.reduced_flashing;
+                    continue;
+                }
+                t if t == lookup("menu.quit") => Err(io::Error::new(
                     ErrorKind::ConnectionAborted,
                     "user selected \"Quit\" in menu",
                 )),
@@ -351,74 +800,267 @@ pub async fn ask_if_new_lobby(client: &mut Client) -> Result<bool, io::Error> {
 }
 
 fn render_lobby_status(client: &Client, render_data: &mut render::RenderData, lobby: &Lobby) {
-    let mut x = 3;
-    x = render_data.buffer.add_text(x, 2, "Lobby ID: ");
+    let mut x;
     if client.lobby_id_hidden {
-        x = render_data.buffer.add_text(x, 2, "******");
+        x = render_data
+            .buffer
+            .add_text(3, 2, &lookup("side.lobby_id").replace("{id}", "******"));
         x = render_data.buffer.add_text_with_color(
             x,
             2,
-            " (press i to show)",
+            lookup("lobby.press_i_to_show"),
             Color::GRAY_FOREGROUND,
         );
     } else {
-        x = render_data.buffer.add_text(x, 2, &lobby.id);
+        x = render_data
+            .buffer
+            .add_text(3, 2, &lookup("side.lobby_id").replace("{id}", &lobby.id));
         x = render_data.buffer.add_text_with_color(
             x,
             2,
-            " (press i to hide)",
+            lookup("lobby.press_i_to_hide"),
             Color::GRAY_FOREGROUND,
         );
     }
 
+    // Right side of the same line, the lobby ID isn't long
+    let can_change_settings = lobby.can_change_settings(client.id);
+    x = render_data.buffer.add_text(
+        x.max(40),
+        2,
+        &lookup("lobby.cursed_blocks").replace("{name}", lobby.cursed_blocks.name()),
+    );
+    if can_change_settings {
+        x = render_data
+            .buffer
+            .add_text_with_color(x, 2, " (C)", Color::GRAY_FOREGROUND);
+    }
+    x = render_data.buffer.add_text(
+        x,
+        2,
+        if lobby.big_blocks {
+            lookup("lobby.big_on")
+        } else {
+            lookup("lobby.big_off")
+        },
+    );
+    if can_change_settings {
+        render_data
+            .buffer
+            .add_text_with_color(x, 2, " (X)", Color::GRAY_FOREGROUND);
+    }
+    // The list of players below leaves no room for more settings
+    render_data.buffer.add_text_with_color(
+        53,
+        3,
+        lookup("lobby.more_settings"),
+        Color::GRAY_FOREGROUND,
+    );
+
     for (i, info) in lobby.clients.iter().enumerate() {
         let y = 5 + i;
 
         x = 6;
         x = render_data.buffer.add_text(x, y, &format!("{}. ", i + 1));
-        x = render_data.buffer.add_text_with_color(
-            x,
-            y,
-            &info.name,
-            Color {
-                fg: info.color,
-                bg: 0,
-            },
-        );
+        x = render_data
+            .buffer
+            .add_text_with_color(x, y, &info.name, Color::new(info.color, 0));
         if info.client_id == client.id {
-            render_data
-                .buffer
-                .add_text_with_color(x, y, " (you)", Color::GRAY_FOREGROUND);
+            x = render_data.buffer.add_text_with_color(
+                x,
+                y,
+                lookup("lobby.you"),
+                Color::GRAY_FOREGROUND,
+            );
+        }
+        if let Some(local_info) = lobby.get_local_player(info.client_id) {
+            x = render_data.buffer.add_text(x, y, " + ");
+            x = render_data.buffer.add_text_with_color(
+                x,
+                y,
+                &local_info.name,
+                Color::new(local_info.color, 0),
+            );
+        }
+        if info.handicap != 0 {
+            render_data.buffer.add_text_with_color(
+                x,
+                y,
+                &lookup("lobby.handicap").replace("{handicap}", &info.handicap.to_string()),
+                Color::GRAY_FOREGROUND,
+            );
         }
     }
-
     _ = x; // silence compiler warning
-}
-
-#[derive(PartialEq, Debug)]
-pub enum ModeMenuChoice {
-    PlayGame(Mode),
-    GameplayTips,
-    ShowAllHighScores,
-}
-
-pub async fn show_mode_menu(
-    client: &mut Client,
-    selected_index: &mut usize,
-) -> Result<ModeMenuChoice, io::Error> {
-    let mut items = vec![];
-    items.resize(Mode::ALL_MODES.len(), None);
-    items.push(None);
-    items.push(Some("Gameplay tips".to_string()));
-    items.push(Some("High scores".to_string()));
-    items.push(Some("Quit".to_string()));
-    let mut menu = Menu {
-        items,
-        selected_index: *selected_index,
-    };
 
-    let mut changed_receiver = client
-        .lobby
+    if lobby.can_change_settings(client.id) {
+        render_data.buffer.add_text_with_color(
+            3,
+            0,
+            if lobby.one_player_per_ip {
+                lookup("lobby.allow_many_per_ip")
+            } else {
+                lookup("lobby.allow_one_per_ip")
+            },
+            Color::GRAY_FOREGROUND,
+        );
+        render_data.buffer.add_text_with_color(
+            3,
+            1,
+            if lobby.allow_parallel_games {
+                lookup("lobby.disallow_parallel_games")
+            } else {
+                lookup("lobby.allow_parallel_games")
+            },
+            Color::GRAY_FOREGROUND,
+        );
+        render_data.buffer.add_text_with_color(
+            3,
+            3,
+            lookup("lobby.handicaps"),
+            Color::GRAY_FOREGROUND,
+        );
+        render_data.buffer.add_text_with_color(
+            3,
+            4,
+            if lobby.two_bottle_basins {
+                lookup("lobby.one_bottle_basin")
+            } else {
+                lookup("lobby.two_bottle_basins")
+            },
+            Color::GRAY_FOREGROUND,
+        );
+        render_data.buffer.add_text_with_color(
+            3,
+            11,
+            if lobby.unlisted_for_name_search {
+                lookup("lobby.list_for_name_search")
+            } else {
+                lookup("lobby.unlist_for_name_search")
+            },
+            Color::GRAY_FOREGROUND,
+        );
+        let seed_text = match lobby.block_seed {
+            Some(seed) => lookup("lobby.seed")
+                .replace("{seed}", &seed.to_string())
+                .replace(
+                    "{sharing}",
+                    if lobby.shared_block_sequence {
+                        lookup("lobby.seed_shared")
+                    } else {
+                        lookup("lobby.seed_not_shared")
+                    },
+                ),
+            None => lookup("lobby.no_seed").to_string(),
+        };
+        render_data
+            .buffer
+            .add_text_with_color(3, 12, &seed_text, Color::GRAY_FOREGROUND);
+    }
+}
+
+// Shown to the right of the mode menu, so that you can see whether your friends started already
+const PLAYER_NAMES_X: usize = 61;
+
+fn add_player_names(
+    buffer: &mut RenderBuffer,
+    x: usize,
+    y: usize,
+    width: usize,
+    players: &[(String, u8)],
+) {
+    if players.is_empty() {
+        return;
+    }
+    let full_names: Vec<String> = players.iter().map(|(name, _)| name.clone()).collect();
+    let (names, others) = fit_player_names(&full_names, width);
+
+    let mut x = x;
+    for (i, name) in names.iter().enumerate() {
+        if i != 0 {
+            x = buffer.add_text(x, y, ", ");
+        }
+        let color = Color::new(players[i].1, 0);
+        x = buffer.add_text_with_color(x, y, name, color);
+    }
+    buffer.add_text_with_color(x, y, &others, Color::GRAY_FOREGROUND);
+}
+
+// Drawn over the mode menu when a slot opens in the game that the client is waiting for
+fn render_waitlist_offer(buffer: &mut RenderBuffer, mode: Mode, seconds_left: u64) {
+    let top_y = 9;
+    let height = 7;
+    let green_line = |buffer: &mut RenderBuffer, y: usize, left: char, middle: char| {
+        let text = format!("{}{}{}", left, middle.to_string().repeat(78), left);
+        buffer.add_centered_text_with_color(y, &text, Color::GREEN_FOREGROUND);
+    };
+
+    green_line(buffer, top_y, 'o', '=');
+    for y in (top_y + 1)..(top_y + height - 1) {
+        green_line(buffer, y, '|', ' ');
+    }
+    green_line(buffer, top_y + height - 1, 'o', '=');
+
+    buffer.add_centered_text(
+        top_y + 2,
+        &lookup("waitlist.offer")
+            .replace("{mode}", mode.name())
+            .replace("{seconds}", &seconds_left.to_string()),
+    );
+    buffer.add_centered_text_with_color(
+        top_y + 4,
+        lookup("waitlist.give_up"),
+        Color::GRAY_FOREGROUND,
+    );
+}
+
+fn format_waitlist_position(mode: Mode, position: usize) -> String {
+    lookup("waitlist.position")
+        .replace("{mode}", mode.name())
+        .replace("{position}", &position.to_string())
+}
+
+// Going anywhere else than the game that the client waits for gives up the place in line
+fn leave_waitlist_unless_joining(client: &Client, choice: ModeMenuChoice) -> ModeMenuChoice {
+    let mut lobby = client.lobby.as_ref().unwrap().lock().unwrap();
+    let waiting_for = lobby.get_waitlist_position(client.id).map(|(mode, _)| mode);
+    if waiting_for.map(ModeMenuChoice::PlayGame) != Some(choice) {
+        lobby.leave_waitlist(client.id, Instant::now());
+    }
+    choice
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum ModeMenuChoice {
+    PlayGame(Mode),
+    GameplayTips,
+    ShowAllHighScores,
+    AddLocalPlayer,
+    EnterBlockSeed,
+    ReportProblem,
+    LobbySettings,
+}
+
+pub async fn show_mode_menu(
+    client: &mut Client,
+    selected_index: &mut usize,
+) -> Result<ModeMenuChoice, io::Error> {
+    let mut items = vec![];
+    items.resize(Mode::ALL_MODES.len(), None);
+    items.push(None);
+    items.push(Some(lookup("mode_menu.tips").to_string()));
+    items.push(Some(lookup("mode_menu.high_scores").to_string()));
+    items.push(Some(lookup("mode_menu.report").to_string()));
+    items.push(None); // add or remove local player, depends on whether we have one
+    items.push(Some(lookup("menu.quit").to_string()));
+    let local_player_index = items.len() - 2;
+    let mut menu = Menu {
+        items,
+        selected_index: *selected_index,
+    };
+
+    let mut changed_receiver = client
+        .lobby
         .as_ref()
         .unwrap()
         .lock()
@@ -427,92 +1069,403 @@ pub async fn show_mode_menu(
         .clone();
 
     loop {
+        let waitlist_offer;
+        let waitlist_position;
+        let mut redraw_time = None;
         {
             let mut render_data = client.render_data.lock().unwrap();
             render_data.clear(80, 24);
 
-            let mut selected_game_is_full = false;
+            let mut selected_game_error = None;
+            let mut blocked_indexes = vec![];
             {
                 let idk_why_i_need_this = client.lobby.clone().unwrap();
                 let lobby = idk_why_i_need_this.lock().unwrap();
-                render_lobby_status(client, &mut *render_data, &lobby);
+                render_lobby_status(client, &mut render_data, &lobby);
+                waitlist_offer = lobby.get_waitlist_offer(client.id);
+                waitlist_position = lobby.get_waitlist_position(client.id);
 
                 for (i, mode) in Mode::ALL_MODES.iter().enumerate() {
                     let count = lobby.get_player_count(*mode);
                     let max = mode.max_players();
-                    menu.items[i] = Some(format!("{} ({}/{} players)", mode.name(), count, max));
-                    if i == menu.selected_index && count == max {
-                        selected_game_is_full = true;
+                    menu.items[i] = Some(
+                        lookup("mode_menu.game")
+                            .replace("{mode}", mode.name())
+                            .replace("{count}", &count.to_string())
+                            .replace("{max}", &max.to_string()),
+                    );
+                    let width = render_data.buffer.width.saturating_sub(PLAYER_NAMES_X);
+                    add_player_names(
+                        &mut render_data.buffer,
+                        PLAYER_NAMES_X,
+                        13 + i,
+                        width,
+                        &lobby.get_player_names(*mode),
+                    );
+                    if i == menu.selected_index {
+                        selected_game_error = lobby.check_joining(client.id, *mode).err();
+                    }
+                    if lobby.get_blocking_game(*mode).is_some() {
+                        blocked_indexes.push(i);
                     }
                 }
             }
+            menu.items[local_player_index] = Some(if client.get_local_player_name().is_some() {
+                lookup("mode_menu.remove_local_player").to_string()
+            } else {
+                lookup("mode_menu.add_local_player").to_string()
+            });
 
             menu.render(&mut render_data.buffer, 13);
-            if selected_game_is_full {
+            // Draw again in gray, but the selected item stays highlighted
+            for i in blocked_indexes {
+                if i != menu.selected_index {
+                    render_data.buffer.add_centered_text_with_color(
+                        13 + i,
+                        menu.items[i].as_ref().unwrap(),
+                        Color::GRAY_FOREGROUND,
+                    );
+                }
+            }
+            let low_bandwidth_hint = if render_data.buffer.low_bandwidth {
+                lookup("mode_menu.low_bandwidth_off")
+            } else {
+                lookup("mode_menu.low_bandwidth_on")
+            };
+            // The menu goes all the way down, so the error replaces the hint
+            if let Some((mode, position)) = waitlist_position {
+                render_data.buffer.add_centered_text_with_color(
+                    23,
+                    &format_waitlist_position(mode, position),
+                    Color::GREEN_FOREGROUND,
+                );
+            } else if let Some(error) = selected_game_error {
                 render_data.buffer.add_centered_text_with_color(
-                    21,
-                    "This game is full.",
+                    23,
+                    &join_error_text(error),
                     Color::RED_FOREGROUND,
                 );
+            } else {
+                render_data.buffer.add_centered_text_with_color(
+                    23,
+                    low_bandwidth_hint,
+                    Color::GRAY_FOREGROUND,
+                );
+            }
+            if let Some((mode, deadline)) = waitlist_offer {
+                let left = deadline.saturating_duration_since(Instant::now());
+                let seconds_left = left.as_secs() + u64::from(left.subsec_nanos() != 0);
+                render_waitlist_offer(&mut render_data.buffer, mode, seconds_left);
+                // Redraw when the number of seconds changes, or expire the offer at the deadline
+                redraw_time = Some(deadline - Duration::from_secs(seconds_left.saturating_sub(1)));
             }
-            render_data.changed.notify_one();
+            render_data.mark_changed();
         }
 
+        let wait_for_redraw_time = async {
+            match redraw_time {
+                Some(time) => tokio::time::sleep_until(tokio::time::Instant::from_std(time)).await,
+                None => std::future::pending().await,
+            }
+        };
+
         tokio::select! {
+            _ = wait_for_redraw_time => {
+                let mut lobby = client.lobby.as_ref().unwrap().lock().unwrap();
+                lobby.expire_waitlist_offers(Instant::now());
+            }
             key_or_error = client.receive_key_press() => {
-                match key_or_error? {
+                let key = key_or_error?;
+                if let Some((mode, _)) = waitlist_offer {
+                    // Other keys are ignored, they would change things behind the offer
+                    match key {
+                        KeyPress::Enter => return Ok(ModeMenuChoice::PlayGame(mode)),
+                        KeyPress::Escape => {
+                            let mut lobby = client.lobby.as_ref().unwrap().lock().unwrap();
+                            lobby.leave_waitlist(client.id, Instant::now());
+                        }
+                        _ => {}
+                    }
+                    continue;
+                }
+                match key {
                     KeyPress::Character('I') | KeyPress::Character('i') => {
                         client.lobby_id_hidden = !client.lobby_id_hidden;
                     }
+                    KeyPress::Character('L') | KeyPress::Character('l') => {
+                        let mut render_data = client.render_data.lock().unwrap();
+                        render_data.buffer.low_bandwidth = !render_data.buffer.low_bandwidth;
+                        // Colors already on the terminal don't go away otherwise
+                        render_data.force_redraw = true;
+                    }
+                    KeyPress::Character(ch @ '1'..='6') => {
+                        let mut lobby = client.lobby.as_ref().unwrap().lock().unwrap();
+                        if lobby.can_change_settings(client.id) {
+                            lobby.cycle_handicap((ch as usize) - ('1' as usize));
+                        }
+                    }
+                    KeyPress::Character('D') | KeyPress::Character('d') => {
+                        let mut lobby = client.lobby.as_ref().unwrap().lock().unwrap();
+                        if lobby.can_change_settings(client.id) {
+                            lobby.toggle_two_bottle_basins();
+                        }
+                    }
+                    KeyPress::Character('P') | KeyPress::Character('p') => {
+                        let mut lobby = client.lobby.as_ref().unwrap().lock().unwrap();
+                        if lobby.can_change_settings(client.id) {
+                            lobby.toggle_allow_parallel_games();
+                        }
+                    }
+                    KeyPress::Character('O') | KeyPress::Character('o') => {
+                        let mut lobby = client.lobby.as_ref().unwrap().lock().unwrap();
+                        if lobby.can_change_settings(client.id) {
+                            lobby.toggle_one_player_per_ip();
+                        }
+                    }
+                    KeyPress::Character('C') | KeyPress::Character('c') => {
+                        let mut lobby = client.lobby.as_ref().unwrap().lock().unwrap();
+                        if lobby.can_change_settings(client.id) {
+                            lobby.cycle_cursed_blocks();
+                        }
+                    }
+                    // B would be bottle game, and x is for 2x size
+                    KeyPress::Character('X') | KeyPress::Character('x') => {
+                        let mut lobby = client.lobby.as_ref().unwrap().lock().unwrap();
+                        if lobby.can_change_settings(client.id) {
+                            lobby.toggle_big_blocks();
+                        }
+                    }
+                    KeyPress::Character('M') | KeyPress::Character('m') => {
+                        return Ok(leave_waitlist_unless_joining(client, ModeMenuChoice::LobbySettings));
+                    }
+                    KeyPress::Character('U') | KeyPress::Character('u') => {
+                        let mut lobby = client.lobby.as_ref().unwrap().lock().unwrap();
+                        if lobby.can_change_settings(client.id) {
+                            lobby.toggle_unlisted_for_name_search();
+                        }
+                    }
+                    KeyPress::Character('E') | KeyPress::Character('e') => {
+                        let lobby = client.lobby.as_ref().unwrap().lock().unwrap();
+                        if lobby.can_change_settings(client.id) {
+                            drop(lobby);
+                            return Ok(leave_waitlist_unless_joining(client, ModeMenuChoice::EnterBlockSeed));
+                        }
+                    }
+                    KeyPress::Character('S') | KeyPress::Character('s') => {
+                        let mut lobby = client.lobby.as_ref().unwrap().lock().unwrap();
+                        if lobby.can_change_settings(client.id) && lobby.block_seed.is_some() {
+                            lobby.toggle_shared_block_sequence();
+                        }
+                    }
                     key => {
                         if menu.handle_key_press(key) {
                             *selected_index = menu.selected_index;
-                            return match menu.selected_text() {
-                                "Gameplay tips" => Ok(ModeMenuChoice::GameplayTips),
-                                "High scores" => Ok(ModeMenuChoice::ShowAllHighScores),
-                                "Quit" => Err(io::Error::new(
+                            let choice = match menu.selected_text() {
+                                t if t == lookup("mode_menu.tips") => ModeMenuChoice::GameplayTips,
+                                t if t == lookup("mode_menu.high_scores") => ModeMenuChoice::ShowAllHighScores,
+                                t if t == lookup("mode_menu.report") => ModeMenuChoice::ReportProblem,
+                                t if t == lookup("mode_menu.add_local_player") => ModeMenuChoice::AddLocalPlayer,
+                                t if t == lookup("mode_menu.remove_local_player") => {
+                                    client.remove_local_player();
+                                    continue;
+                                }
+                                t if t == lookup("menu.quit") => return Err(io::Error::new(
                                     ErrorKind::ConnectionAborted,
                                     "user selected \"Quit\" in menu",
                                 )),
-                                _ => Ok(ModeMenuChoice::PlayGame(Mode::ALL_MODES[menu.selected_index])),
+                                _ => ModeMenuChoice::PlayGame(Mode::ALL_MODES[menu.selected_index]),
                             };
+                            return Ok(leave_waitlist_unless_joining(client, choice));
                         }
                     }
                 }
             }
             res = changed_receiver.changed() => {
-                // It errors if the sender no longer exists.
-                // But the sender is in the lobby which exists as long as there are clients.
-                // So this should never fail.
-                res.unwrap();
+                // It errors if the sender no longer exists, i.e. the lobby is gone.
+                // There's nothing to show in the menu without a lobby.
+                if res.is_err() {
+                    return Err(io::Error::new(
+                        ErrorKind::ConnectionAborted,
+                        "lobby no longer exists",
+                    ));
+                }
             }
         }
     }
 }
 
-const GAMEPLAY_TIPS: &[&str] = &[
-    "Keys:",
-    "  [Ctrl+C], [Ctrl+D] or [Ctrl+Q]: quit",
-    "  [Ctrl+R]: redraw the whole screen (may be needed after resizing the window)",
-    "  [W]/[A]/[S]/[D] or [↑]/[←]/[↓]/[→]: move and rotate (don't hold down [S] or [↓])",
-    "  [H]: hold (aka save) block for later, switch to previously held block if any",
-    "  [R]: change rotating direction",
-    "  [P]: pause/unpause (affects all players)",
-    "  [F]: flip the game upside down (only available in ring mode with 1 player)",
-    "",
-    "There's only one score. {You play together}, not against other players. Try to",
-    "work together and make good use of everyone's blocks.",
-    "",
-    "With multiple players, when your playing area fills all the way to the top,",
-    "you need to wait 30 seconds before you can continue playing. The game ends",
-    "when all players are simultaneously on their 30 seconds waiting time. This",
-    "means that if other players are doing well, you can {intentionally fill your",
-    "playing area} to do your waiting time before others mess up.",
+fn lobby_setting_lines(lobby: &Lobby) -> Vec<(String, char)> {
+    let on_off =
+        |value: bool, on: &str, off: &str| lookup(if value { on } else { off }).to_string();
+    vec![
+        (
+            on_off(
+                lobby.ownership_tint,
+                "lobby.ownership_tint_on",
+                "lobby.ownership_tint_off",
+            ),
+            'W',
+        ),
+        (
+            on_off(
+                lobby.pause_requires_majority,
+                "lobby.pause_vote_on",
+                "lobby.pause_vote_off",
+            ),
+            'V',
+        ),
+        (
+            on_off(
+                lobby.reinforced_squares,
+                "lobby.reinforced_on",
+                "lobby.reinforced_off",
+            ),
+            'N',
+        ),
+        (
+            on_off(
+                lobby.stall_decay,
+                "lobby.stall_decay_on",
+                "lobby.stall_decay_off",
+            ),
+            'F',
+        ),
+        (
+            lookup("lobby.bottle")
+                .replace("{name}", lobby.bottle_necks.name())
+                .replace(
+                    "{rows}",
+                    &lobby.bottle_necks.personal_space_height().to_string(),
+                ),
+            'K',
+        ),
+        (
+            on_off(
+                lobby.spawn_delay,
+                "lobby.spawn_delay_on",
+                "lobby.spawn_delay_off",
+            ),
+            'S',
+        ),
+    ]
+}
+
+// Settings that don't fit next to the list of players in the lobby
+pub async fn show_lobby_settings(client: &mut Client) -> Result<(), io::Error> {
+    let mut menu = Menu {
+        items: vec![Some(lookup("menu.back").to_string())],
+        selected_index: 0,
+    };
+    let mut changed_receiver = client
+        .lobby
+        .as_ref()
+        .unwrap()
+        .lock()
+        .unwrap()
+        .changed_receiver
+        .clone();
+
+    loop {
+        {
+            let mut render_data = client.render_data.lock().unwrap();
+            render_data.clear(80, 24);
+            let lobby = client.lobby.as_ref().unwrap().lock().unwrap();
+            let can_change_settings = lobby.can_change_settings(client.id);
+            for (i, (text, key)) in lobby_setting_lines(&lobby).iter().enumerate() {
+                let y = 5 + 2 * i;
+                let x = render_data.buffer.add_text(25, y, text);
+                if can_change_settings {
+                    render_data.buffer.add_text_with_color(
+                        x,
+                        y,
+                        &format!(" ({})", key),
+                        Color::GRAY_FOREGROUND,
+                    );
+                }
+            }
+            menu.render(&mut render_data.buffer, 17);
+            render_data.mark_changed();
+        }
+
+        tokio::select! {
+            key_or_error = client.receive_key_press() => {
+                let key = key_or_error?;
+                let mut lobby = client.lobby.as_ref().unwrap().lock().unwrap();
+                if lobby.can_change_settings(client.id) {
+                    match key {
+                        // W for who landed each square
+                        KeyPress::Character('W' | 'w') => lobby.toggle_ownership_tint(),
+                        // V for vote, P already toggles parallel games
+                        KeyPress::Character('V' | 'v') => lobby.toggle_pause_requires_majority(),
+                        // R would be ring game, N for reiNforced
+                        KeyPress::Character('N' | 'n') => lobby.toggle_reinforced_squares(),
+                        // F for ring mode blocks that fall forever
+                        KeyPress::Character('F' | 'f') => lobby.toggle_stall_decay(),
+                        // K for necK, B would be bottle game
+                        KeyPress::Character('K' | 'k') => lobby.cycle_bottle_necks(),
+                        // S for spawn
+                        KeyPress::Character('S' | 's') => lobby.toggle_spawn_delay(),
+                        _ => {}
+                    }
+                }
+                drop(lobby);
+                if key == KeyPress::Escape || menu.handle_key_press(key) {
+                    return Ok(());
+                }
+            }
+            res = changed_receiver.changed() => {
+                if res.is_err() {
+                    return Err(io::Error::new(
+                        ErrorKind::ConnectionAborted,
+                        "lobby no longer exists",
+                    ));
+                }
+            }
+        }
+    }
+}
+
+// Keys of texts in strings.rs
+const GAMEPLAY_KEYS: &[&str] = &[
+    "keys.quit",
+    "keys.redraw",
+    "keys.move",
+    "keys.hold",
+    "keys.rotate_direction",
+    "keys.cancel_soft_drop",
+    "keys.bell",
+    "keys.auto_shift",
+    "keys.reduced_flashing",
+    "keys.pause",
+    "keys.flip",
+    "keys.help",
 ];
 
+// Used in gameplay tips and the in-game help, so that they show the same keys
+fn get_key_lines(websocket: bool, include_flip: bool) -> Vec<&'static str> {
+    GAMEPLAY_KEYS
+        .iter()
+        .copied()
+        .filter(|key| !(websocket && ["keys.quit", "keys.redraw"].contains(key)))
+        .filter(|key| include_flip || *key != "keys.flip")
+        .map(lookup)
+        .collect()
+}
+
+const TIPS_COLUMN_WIDTH: usize = 37;
+
+// The text flows from the bottom of the left column to the top of the right column
+fn split_into_columns(lines: &[String]) -> (&[String], &[String]) {
+    let (left, right) = lines.split_at(lines.len().div_ceil(2));
+    match right.split_first() {
+        Some((first, rest)) if first.is_empty() => (left, rest),
+        _ => (left, right),
+    }
+}
+
 pub async fn show_gameplay_tips(client: &mut Client) -> Result<(), io::Error> {
     let mut menu = Menu {
-        items: vec![Some("Back to menu".to_string())],
+        items: vec![Some(lookup("menu.back").to_string())],
         selected_index: 0,
     };
 
@@ -520,83 +1473,374 @@ pub async fn show_gameplay_tips(client: &mut Client) -> Result<(), io::Error> {
         let mut render_data = client.render_data.lock().unwrap();
         render_data.clear(80, 24);
 
-        let mut color = Color::DEFAULT;
-        let mut y = 0;
+        let mut key_lines = vec![lookup("tips.keys")];
+        key_lines.extend(get_key_lines(client.is_connected_with_websocket(), true));
+        let mut open_markers = vec![];
+        for (y, line) in (1..).zip(&key_lines) {
+            render_data
+                .buffer
+                .add_styled_text(2, y, line, &mut open_markers);
+        }
 
-        for line_ref in GAMEPLAY_TIPS {
-            let mut line = *line_ref;
-            if line.contains("Ctrl+") && client.is_connected_with_websocket() {
-                continue;
+        // Short lines are easier to read, and two columns fit above the menu
+        let tips = render::wrap_styled_text(lookup("tips"), TIPS_COLUMN_WIDTH);
+        let (left, right) = split_into_columns(&tips);
+        let top_y = key_lines.len() + 2;
+        for (x, column) in [(2, left), (2 + TIPS_COLUMN_WIDTH + 2, right)] {
+            for (y, line) in (top_y..).zip(column) {
+                render_data
+                    .buffer
+                    .add_styled_text(x, y, line, &mut open_markers);
             }
+        }
 
-            let mut x = 2;
-            y += 1;
+        menu.render(&mut render_data.buffer, 23);
+        render_data.mark_changed();
+    }
 
-            loop {
-                match line.chars().next() {
-                    Some('[') => {
-                        color = Color::MAGENTA_FOREGROUND;
-                        line = &line[1..];
-                    }
-                    Some('{') => {
-                        color = Color::CYAN_FOREGROUND;
-                        line = &line[1..];
-                    }
-                    Some(']') | Some('}') => {
-                        color = Color::DEFAULT;
-                        line = &line[1..];
-                    }
-                    Some(_) => {
-                        let i = line.find(|c| "[]{}".contains(c)).unwrap_or(line.len());
-                        x = render_data
-                            .buffer
-                            .add_text_with_color(x, y, &line[..i], color);
-                        line = &line[i..];
-                    }
-                    None => break,
-                }
+    loop {
+        let key = client.receive_key_press().await?;
+        if key == KeyPress::Escape || menu.handle_key_press(key) {
+            return Ok(());
+        }
+        // Clear the key that user typed, although no need to re-render
+        client.render_data.lock().unwrap().mark_changed();
+    }
+}
+
+const PAUSE_SCREEN_WIDTH: usize = 62;
+const PAUSE_SCREEN_HEIGHT: usize = 20;
+
+// Shown below the pause menu, so that players don't need to unpause to e.g. tell the lobby ID to a friend
+struct PauseInfo<'a> {
+    game: &'a Game,
+    clock: GameClock,
+    lobby_id: Option<&'a str>, // None for games that aren't in a lobby
+    lobby_id_hidden: bool,
+}
+
+fn render_pause_info(buffer: &mut RenderBuffer, top_y: usize, info: &PauseInfo) {
+    let left_x = (buffer.width - PAUSE_SCREEN_WIDTH) / 2 + 4;
+    let right_x = left_x + PAUSE_SCREEN_WIDTH / 2;
+    let right_edge = left_x + PAUSE_SCREEN_WIDTH - 8;
+
+    match info.lobby_id {
+        Some(_) if info.lobby_id_hidden => {
+            let x = buffer.add_text(
+                left_x,
+                top_y,
+                &lookup("side.lobby_id").replace("{id}", "******"),
+            );
+            buffer.add_text_with_color(
+                x,
+                top_y,
+                lookup("lobby.press_i_to_show"),
+                Color::GRAY_FOREGROUND,
+            );
+        }
+        Some(id) => {
+            let x = buffer.add_text(left_x, top_y, &lookup("side.lobby_id").replace("{id}", id));
+            buffer.add_text_with_color(
+                x,
+                top_y,
+                lookup("lobby.press_i_to_hide"),
+                Color::GRAY_FOREGROUND,
+            );
+        }
+        None => {}
+    }
+
+    let seconds = info.clock.elapsed.as_secs();
+    let time = format!("{:02}:{:02}", seconds / 60, seconds % 60);
+    buffer.add_text(left_x, top_y + 1, info.game.mode.name());
+    buffer.add_text(
+        left_x,
+        top_y + 2,
+        &lookup("side.score").replace("{score}", &format_score(info.game.get_score())),
+    );
+    buffer.add_text(
+        right_x,
+        top_y + 2,
+        &lookup("side.time").replace("{time}", &time),
+    );
+
+    let players: Vec<(String, u8)> = info
+        .game
+        .players
+        .iter()
+        .map(|p| (p.borrow().name.clone(), p.borrow().color))
+        .collect();
+    let x = buffer.add_text(left_x, top_y + 4, lookup("pause.players"));
+    add_player_names(buffer, x, top_y + 4, right_edge - x, &players);
+}
+
+fn render_pause_screen(buffer: &mut RenderBuffer, menu: &Menu, info: &PauseInfo) {
+    let top_y = (buffer.height - PAUSE_SCREEN_HEIGHT) / 2;
+    let green_line = |buffer: &mut RenderBuffer, y: usize, left: char, middle: char| {
+        let middle = middle.to_string().repeat(PAUSE_SCREEN_WIDTH - 2);
+        let text = format!("{}{}{}", left, middle, left);
+        buffer.add_centered_text_with_color(y, &text, Color::GREEN_FOREGROUND);
+    };
+
+    green_line(buffer, top_y, 'o', '=');
+    for y in (top_y + 1)..(top_y + PAUSE_SCREEN_HEIGHT - 1) {
+        green_line(buffer, y, '|', ' ');
+    }
+    green_line(buffer, top_y + PAUSE_SCREEN_HEIGHT - 1, 'o', '=');
+
+    let title = lookup("pause.title");
+    let underline = "^".repeat(title.chars().count() + 2);
+    buffer.add_centered_text_with_color(top_y + 2, title, Color::GREEN_FOREGROUND);
+    buffer.add_centered_text_with_color(top_y + 3, &underline, Color::GREEN_FOREGROUND);
+    menu.render(buffer, top_y + 5);
+    for (i, line) in lookup("pause.idle_warning").lines().enumerate() {
+        buffer.add_centered_text_with_color(top_y + 8 + i, line, Color::GREEN_FOREGROUND);
+    }
+    render_pause_info(buffer, top_y + 11, info);
+}
+
+// Drawn over the game like the pause screen, but only for one client
+fn render_keys_overlay(buffer: &mut RenderBuffer, key_lines: &[&str]) {
+    let height = key_lines.len() + 6;
+    let top_y = (buffer.height - height) / 2;
+    let green_line = |buffer: &mut RenderBuffer, y: usize, left: char, middle: char| {
+        let text = format!("{}{}{}", left, middle.to_string().repeat(78), left);
+        buffer.add_centered_text_with_color(y, &text, Color::GREEN_FOREGROUND);
+    };
+
+    green_line(buffer, top_y, 'o', '=');
+    for y in (top_y + 1)..(top_y + height - 1) {
+        green_line(buffer, y, '|', ' ');
+    }
+    green_line(buffer, top_y + height - 1, 'o', '=');
+
+    buffer.add_centered_text_with_color(top_y + 1, lookup("keys.title"), Color::GREEN_FOREGROUND);
+    let mut open_markers = vec![];
+    for (i, line) in key_lines.iter().enumerate() {
+        buffer.add_styled_text(2, top_y + 3 + i, line, &mut open_markers);
+    }
+    buffer.add_centered_text(top_y + height - 2, lookup("keys.continue"));
+}
+
+// Returns the key press that should go to the game, if any
+fn handle_help_key(showing_help: &mut bool, key: KeyPress) -> Option<KeyPress> {
+    if *showing_help {
+        *showing_help = false;
+        None
+    } else if key == KeyPress::Character('?') {
+        *showing_help = true;
+        None
+    } else {
+        Some(key)
+    }
+}
+
+// Keys that change the client's settings work the same in games and in the tutorial.
+// Returns false if the key isn't one of them.
+fn toggle_client_setting(client: &mut Client, key: &KeyPress, paused: bool) -> bool {
+    let setting = match key {
+        KeyPress::Character('R' | 'r') => &mut client.prefer_rotating_counter_clockwise,
+        KeyPress::Character('C' | 'c') => &mut client.other_keys_cancel_soft_drop,
+        KeyPress::Character('B' | 'b') => &mut client.bell_enabled,
+        KeyPress::Character('M' | 'm') => &mut client.auto_shift_enabled,
+        KeyPress::Character('G' | 'g') => &mut client.reduced_flashing,
+        KeyPress::Character('I' | 'i') if paused => &mut client.lobby_id_hidden,
+        _ => return false,
+    };
+    *setting = !*setting;
+    true
+}
+
+// Tutorial isn't in a lobby, so no other players need a different color
+const TUTORIAL_COLOR: u8 = 32;
+
+// Operators can set this with --debug-dump-key to log the game state when pressing the key
+pub static DEBUG_DUMP_KEY: OnceLock<char> = OnceLock::new();
+
+// With a local player, arrow keys control the local player and everything else controls the client
+fn choose_player_for_key(client_id: u64, local_player_id: Option<u64>, key: &KeyPress) -> u64 {
+    match (local_player_id, key) {
+        (Some(id), KeyPress::Up | KeyPress::Down | KeyPress::Left | KeyPress::Right) => id,
+        _ => client_id,
+    }
+}
+
+// Sound events are sent with the next frame, if the client wants them
+fn take_sound_events(
+    client: &Client,
+    render_data: &mut render::RenderData,
+    receiver: &mut broadcast::Receiver<SoundEvent>,
+) {
+    loop {
+        match receiver.try_recv() {
+            Ok(event) if client.sound_events_enabled => {
+                render_data.pending_sound_events.push(event)
             }
+            Ok(_) | Err(broadcast::error::TryRecvError::Lagged(_)) => {}
+            Err(_) => break,
         }
+    }
+}
 
-        menu.render(&mut render_data.buffer, 19);
-        render_data.changed.notify_one();
+const CRASH_MESSAGE_DURATION: Duration = Duration::from_secs(3);
+
+// Shown when a game has crashed (see game_wrapper.rs), before going back to the menu
+async fn show_game_crashed_message(client: &mut Client) -> Result<(), io::Error> {
+    {
+        let mut render_data = client.render_data.lock().unwrap();
+        render_data.clear(80, 24);
+        render_data.buffer.add_centered_text_with_color(
+            10,
+            lookup("crash.message"),
+            Color::RED_FOREGROUND,
+        );
+        render_data.mark_changed();
+    }
+    // Pressing any key goes back to the menu right away
+    tokio::select! {
+        _ = tokio::time::sleep(CRASH_MESSAGE_DURATION) => Ok(()),
+        key = client.receive_key_press() => key.map(|_| ()),
     }
+}
 
-    while !menu.handle_key_press(client.receive_key_press().await?) {
-        // Clear the key that user typed, although no need to re-render
-        client.render_data.lock().unwrap().changed.notify_one();
+// Blocks fall faster over time, so joining an old game can be a miserable first experience
+const OLD_GAME_DURATION: Duration = Duration::from_secs(10 * 60);
+const OLD_GAME_SCORE: usize = 10_000;
+
+fn old_game_warning(elapsed: Duration, score: usize) -> Option<String> {
+    if elapsed >= OLD_GAME_DURATION {
+        Some(
+            lookup("old_game.duration").replace("{minutes}", &(elapsed.as_secs() / 60).to_string()),
+        )
+    } else if score >= OLD_GAME_SCORE {
+        Some(lookup("old_game.score").replace("{score}", &format_score(score)))
+    } else {
+        None
     }
-    Ok(())
 }
 
-const PAUSE_SCREEN: &[&str] = &[
-    "o============================================================o",
-    "|                                                            |",
-    "|                                                            |",
-    "|                        Game paused                         |",
-    "|                       ^^^^^^^^^^^^^                        |",
-    "|                                                            |",
-    "|                                                            |",
-    "|                                                            |",
-    "|                                                            |",
-    "|                                                            |",
-    "|                                                            |",
-    "|         You will be disconnected automatically if          |",
-    "|          you don't press any keys for 10 minutes.          |",
-    "|                                                            |",
-    "|                                                            |",
-    "o============================================================o",
-];
+// Returns false if the client doesn't want to join after all
+async fn confirm_joining_old_game(client: &mut Client, mode: Mode) -> Result<bool, io::Error> {
+    let wrapper = client
+        .lobby
+        .as_ref()
+        .unwrap()
+        .lock()
+        .unwrap()
+        .get_game_wrapper(mode);
+    let warning = match wrapper {
+        Some(wrapper) => {
+            let elapsed = wrapper.get_clock().elapsed;
+            let score = wrapper.lock_game().get_score();
+            old_game_warning(elapsed, score)
+        }
+        None => None,
+    };
+    let warning = match warning {
+        Some(text) => text,
+        None => return Ok(true),
+    };
+
+    let mut menu = Menu {
+        items: vec![
+            Some(lookup("menu.yes").to_string()),
+            Some(lookup("menu.no").to_string()),
+        ],
+        selected_index: 0,
+    };
+    loop {
+        {
+            let mut render_data = client.render_data.lock().unwrap();
+            render_data.clear(80, 24);
+            render_data.buffer.add_centered_text(8, &warning);
+            render_data
+                .buffer
+                .add_centered_text(9, lookup("old_game.question"));
+            menu.render(&mut render_data.buffer, 11);
+            render_data.mark_changed();
+        }
 
-fn render_pause_screen(buffer: &mut RenderBuffer, menu: &Menu) {
-    let top_y = (buffer.height - PAUSE_SCREEN.len()) / 2;
-    for (i, text) in PAUSE_SCREEN.iter().enumerate() {
-        buffer.add_centered_text_with_color(top_y + i, text, Color::GREEN_FOREGROUND);
+        match client.receive_key_press().await? {
+            KeyPress::Character('Y') | KeyPress::Character('y') => return Ok(true),
+            KeyPress::Character('N') | KeyPress::Character('n') => return Ok(false),
+            key => {
+                if menu.handle_key_press(key) {
+                    return Ok(menu.selected_text() == lookup("menu.yes"));
+                }
+            }
+        }
+    }
+}
+
+fn join_error_text(error: JoinError) -> String {
+    match error {
+        JoinError::Full => lookup("join_error.full").to_string(),
+        JoinError::IpRestricted => lookup("join_error.ip_restricted").to_string(),
+        JoinError::NotAllowed(other) => {
+            lookup("join_error.not_allowed").replace("{mode}", other.name())
+        }
+    }
+}
+
+// The game can fill up or change while the client is in the mode menu
+async fn show_join_error(
+    client: &mut Client,
+    mode: Mode,
+    error: JoinError,
+) -> Result<(), io::Error> {
+    let mut items = vec![];
+    if error == JoinError::Full {
+        items.push(Some(lookup("waitlist.join").to_string()));
+    }
+    items.push(Some(lookup("menu.back").to_string()));
+    let mut menu = Menu {
+        items,
+        selected_index: 0,
+    };
+    loop {
+        {
+            let mut render_data = client.render_data.lock().unwrap();
+            render_data.clear(80, 24);
+            render_data.buffer.add_centered_text_with_color(
+                8,
+                &join_error_text(error),
+                Color::RED_FOREGROUND,
+            );
+            menu.render(&mut render_data.buffer, 11);
+            render_data.mark_changed();
+        }
+        if menu.handle_key_press(client.receive_key_press().await?) {
+            if menu.selected_text() == lookup("waitlist.join") {
+                let mut lobby = client.lobby.as_ref().unwrap().lock().unwrap();
+                lobby.join_waitlist(client.id, mode, Instant::now());
+            }
+            return Ok(());
+        }
     }
-    menu.render(buffer, top_y + 7);
 }
 
 pub async fn play_game(client: &mut Client, mode: Mode) -> Result<(), io::Error> {
+    let prefs_before = Prefs::from_client(client);
+    // Loops when players choose "Play again" after a game
+    let result = async {
+        while play_one_game(client, mode).await? {}
+        Ok(())
+    }
+    .await;
+
+    // Settings are changed with keys while playing. Save them even if the client disconnected.
+    let prefs_after = Prefs::from_client(client);
+    if let (Some(passphrase), true) = (&client.settings_passphrase, prefs_after != prefs_before) {
+        let name = client.get_name().unwrap();
+        if let Err(e) = prefs::store(name, passphrase, prefs_after).await {
+            client.logger.log(&format!("Saving settings failed: {}", e));
+        }
+    }
+    result
+}
+
+// Returns true if the client wants to play again
+async fn play_one_game(client: &mut Client, mode: Mode) -> Result<bool, io::Error> {
     /*
     Grab lobby ID before we lock the game.
 
@@ -604,84 +1848,277 @@ pub async fn play_game(client: &mut Client, mode: Mode) -> Result<(), io::Error>
     there's lots of other code that locks the game while keeping the lobby
     locked.
     */
-    let lobby_id = client.lobby.as_ref().unwrap().lock().unwrap().id.clone();
+    let (lobby_id, local_player_id) = {
+        let lobby = client.lobby.as_ref().unwrap().lock().unwrap();
+        let local_player_id = lobby.get_local_player(client.id).map(|info| info.client_id);
+        (lobby.id.clone(), local_player_id)
+    };
 
     let mut pause_menu = Menu {
         items: vec![
-            Some("Continue playing".to_string()),
-            Some("Quit game".to_string()),
+            Some(lookup("pause.continue").to_string()),
+            Some(lookup("pause.quit_game").to_string()),
         ],
         selected_index: 0,
     };
 
-    let (game_wrapper, auto_leave_token) = {
-        if let Some(result) =
-            join_game_in_a_lobby(client.lobby.as_ref().unwrap().clone(), client.id, mode)
-        {
-            result
-        } else {
-            // game full
-            return Ok(());
-        }
-    };
+    if !confirm_joining_old_game(client, mode).await? {
+        return Ok(false);
+    }
+    let (game_wrapper, mut auto_leave_token) =
+        match join_game_in_a_lobby(client.lobby.as_ref().unwrap().clone(), client.id, mode) {
+            Ok(result) => result,
+            Err(error) => {
+                show_join_error(client, mode, error).await?;
+                return Ok(false);
+            }
+        };
+    client
+        .logger
+        .log_event(EventKind::GameStart, &format!("Playing {:?} game", mode));
 
     let mut receiver = game_wrapper.status_receiver.clone();
-    let mut paused = false;
+    let mut sound_receiver = game_wrapper.subscribe_sound_events();
+    let mut paused = matches!(*receiver.borrow(), GameStatus::Paused(_));
+    let mut showing_help = false;
+    let mut cues = ingame_ui::PlayerCues::default();
 
     loop {
         {
             let mut render_data = client.render_data.lock().unwrap();
             render_data.clear(80, 24);
-            let game = game_wrapper.game.lock().unwrap();
-            ingame_ui::render(&*game, &mut *render_data, client, &lobby_id);
+            take_sound_events(client, &mut render_data, &mut sound_receiver);
+            let clock = game_wrapper.get_clock();
+            let game = game_wrapper.lock_game();
+            ingame_ui::render(
+                &game,
+                &mut render_data,
+                client,
+                Some(&lobby_id),
+                &mut cues,
+                clock,
+            );
+            // Expired votes disappear, because the clock on the side is redrawn every second
+            if let Some(vote) = game_wrapper.get_pause_vote(Instant::now()) {
+                ingame_ui::render_pause_vote(&mut render_data, &game, &vote);
+            }
             if paused {
-                render_pause_screen(&mut render_data.buffer, &pause_menu);
+                let info = PauseInfo {
+                    game: &game,
+                    clock,
+                    lobby_id: Some(&lobby_id),
+                    lobby_id_hidden: client.lobby_id_hidden,
+                };
+                render_pause_screen(&mut render_data.buffer, &pause_menu, &info);
             } else {
                 pause_menu.selected_index = 0;
+                if showing_help {
+                    let can_flip = game.can_flip_view();
+                    let key_lines = get_key_lines(client.is_connected_with_websocket(), can_flip);
+                    render_keys_overlay(&mut render_data.buffer, &key_lines);
+                }
             }
-            render_data.changed.notify_one();
+            render_data.mark_changed();
         }
 
+        let redraw_time = cues.next_redraw_time();
+        let wait_for_redraw_time = async {
+            match redraw_time {
+                Some(time) => tokio::time::sleep_until(tokio::time::Instant::from_std(time)).await,
+                None => std::future::pending().await,
+            }
+        };
+
         tokio::select! {
+            _ = wait_for_redraw_time => {}
             result = receiver.changed() => {
-                result.unwrap(); // shouldn't fail, because game wrapper still has the sender
+                if result.is_err() {
+                    client.logger.log("Game status channel closed, going back to menu");
+                    return Ok(false);
+                }
+                if matches!(*receiver.borrow(), GameStatus::Crashed) {
+                    auto_leave_token.set_quit();
+                    drop(auto_leave_token);
+                    client.lobby.as_ref().unwrap().lock().unwrap().mark_changed();
+                    show_game_crashed_message(client).await?;
+                    return Ok(false);
+                }
                 let game_over = match *receiver.borrow() {
                     GameStatus::Playing => { paused = false; false }
                     GameStatus::Paused(_) => { paused = true; false }
                     _ => true,
                 };
                 if game_over {
-                    drop(auto_leave_token);
+                    {
+                        let mut render_data = client.render_data.lock().unwrap();
+                        take_sound_events(client, &mut render_data, &mut sound_receiver);
+                    }
+                    let (block_counts_text, client_ids) = {
+                        let game = game_wrapper.lock_game();
+                        let client_ids: Vec<u64> = game
+                            .players
+                            .iter()
+                            .map(|p| p.borrow().client_id)
+                            .filter(|id| !is_local_player_id(*id))
+                            .collect();
+                        (game.format_block_counts(), client_ids)
+                    };
                     // Locking the lobby here is fine, because we're not locking the game.
+                    // Must happen before leaving the game, so that nobody sees a partial list of players.
+                    client.lobby.as_ref().unwrap().lock().unwrap().offer_rematch(mode, &game_wrapper, client_ids);
+                    auto_leave_token.set_quit();
+                    drop(auto_leave_token);
                     client.lobby.as_ref().unwrap().lock().unwrap().mark_changed();
-                    return show_high_scores_after_game(client, receiver).await;
+                    return show_high_scores_after_game(client, receiver, &block_counts_text, Some(mode)).await;
                 }
             }
             key = client.receive_key_press() => {
-                match key? {
-                    KeyPress::Character('P') | KeyPress::Character('p') => {
-                        game_wrapper.set_paused(None);
+                let key = if paused { Some(key?) } else { handle_help_key(&mut showing_help, key?) };
+                match key {
+                    // help was opened or closed
+                    None => {}
+                    Some(KeyPress::Character(ch)) if DEBUG_DUMP_KEY.get() == Some(&ch) => {
+                        let game = game_wrapper.lock_game();
+                        client.logger.log("Debug dump:");
+                        for line in game.debug_state() {
+                            client.logger.log(&format!("  {}", line));
+                        }
                     }
-                    KeyPress::Character('R') | KeyPress::Character('r') => {
-                        client.prefer_rotating_counter_clockwise = !client.prefer_rotating_counter_clockwise;
+                    Some(KeyPress::Character('P') | KeyPress::Character('p')) => {
+                        game_wrapper.press_pause(client.id, None, Instant::now());
                     }
-                    k => {
+                    Some(k) if toggle_client_setting(client, &k, paused) => {}
+                    Some(k) => {
                         if paused {
                             if pause_menu.handle_key_press(k) {
                                 match pause_menu.selected_text() {
-                                    "Continue playing" => game_wrapper.set_paused(Some(false)),
-                                    "Quit game" => {
+                                    // Same as pressing P, may need a vote
+                                    t if t == lookup("pause.continue") => game_wrapper.press_pause(client.id, Some(false), Instant::now()),
+                                    t if t == lookup("pause.quit_game") => {
+                                        // Other players see that this wasn't a connection problem
+                                        auto_leave_token.set_quit();
                                         // Locking the lobby here is fine, because we're not locking the game.
                                         // We only have access to the immutable GameWrapper.
                                         client.lobby.as_ref().unwrap().lock().unwrap().mark_changed();
-                                        return Ok(());
+                                        return Ok(false);
                                     }
                                     _ => panic!(),
                                 }
                             }
                         } else {
-                            let did_something = game_wrapper.game.lock().unwrap().handle_key_press(
-                                client.id, client.prefer_rotating_counter_clockwise, k
+                            let player_id = choose_player_for_key(client.id, local_player_id, &k);
+                            let did_something = game_wrapper.handle_key_press(
+                                player_id,
+                                client.prefer_rotating_counter_clockwise,
+                                client.other_keys_cancel_soft_drop,
+                                client.auto_shift_enabled,
+                                k,
+                            );
+                            if did_something {
+                                game_wrapper.mark_changed();
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Single player game that isn't in a lobby, with instructions next to it
+pub async fn play_tutorial(client: &mut Client) -> Result<(), io::Error> {
+    let game = tutorial::create_game(client.id, client.get_name().unwrap(), TUTORIAL_COLOR);
+    let mut tutorial = Tutorial::new(&game);
+    let game_wrapper = Arc::new(GameWrapper::new_without_saving_results(game));
+    game_wrapper::start_tasks(game_wrapper.clone());
+
+    let mut pause_menu = Menu {
+        items: vec![
+            Some(lookup("pause.continue").to_string()),
+            Some(lookup("pause.quit_tutorial").to_string()),
+        ],
+        selected_index: 0,
+    };
+
+    let mut receiver = game_wrapper.status_receiver.clone();
+    let mut sound_receiver = game_wrapper.subscribe_sound_events();
+    let mut paused = false;
+    let mut showing_help = false;
+    let mut cues = ingame_ui::PlayerCues::default();
+
+    loop {
+        {
+            let mut render_data = client.render_data.lock().unwrap();
+            render_data.clear(80, 24);
+            take_sound_events(client, &mut render_data, &mut sound_receiver);
+            let clock = game_wrapper.get_clock();
+            let game = game_wrapper.lock_game();
+            ingame_ui::render(&game, &mut render_data, client, None, &mut cues, clock);
+            ingame_ui::render_tutorial_prompt(&mut render_data, tutorial.step.prompt());
+            if paused {
+                let info = PauseInfo {
+                    game: &game,
+                    clock,
+                    lobby_id: None,
+                    lobby_id_hidden: client.lobby_id_hidden,
+                };
+                render_pause_screen(&mut render_data.buffer, &pause_menu, &info);
+            } else {
+                pause_menu.selected_index = 0;
+                if showing_help {
+                    let key_lines = get_key_lines(client.is_connected_with_websocket(), false);
+                    render_keys_overlay(&mut render_data.buffer, &key_lines);
+                }
+            }
+            render_data.mark_changed();
+        }
+
+        let redraw_time = cues.next_redraw_time();
+        let wait_for_redraw_time = async {
+            match redraw_time {
+                Some(time) => tokio::time::sleep_until(tokio::time::Instant::from_std(time)).await,
+                None => std::future::pending().await,
+            }
+        };
+
+        tokio::select! {
+            _ = wait_for_redraw_time => {}
+            result = receiver.changed() => {
+                if result.is_err() {
+                    client.logger.log("Game status channel closed, leaving tutorial");
+                    return Ok(());
+                }
+                if matches!(*receiver.borrow(), GameStatus::Crashed) {
+                    return show_game_crashed_message(client).await;
+                }
+                paused = matches!(*receiver.borrow(), GameStatus::Paused(_));
+            }
+            key = client.receive_key_press() => {
+                let key = if paused { Some(key?) } else { handle_help_key(&mut showing_help, key?) };
+                match key {
+                    // help was opened or closed
+                    None => {}
+                    Some(KeyPress::Enter) if tutorial.step == tutorial::Step::Done => return Ok(()),
+                    Some(KeyPress::Character('P') | KeyPress::Character('p')) => {
+                        game_wrapper.set_paused(None);
+                    }
+                    Some(k) if toggle_client_setting(client, &k, paused) => {}
+                    Some(k) => {
+                        if paused {
+                            if pause_menu.handle_key_press(k) {
+                                match pause_menu.selected_text() {
+                                    t if t == lookup("pause.continue") => game_wrapper.set_paused(Some(false)),
+                                    t if t == lookup("pause.quit_tutorial") => return Ok(()),
+                                    _ => panic!(),
+                                }
+                            }
+                        } else {
+                            let did_something = game_wrapper.handle_key_press(
+                                client.id,
+                                client.prefer_rotating_counter_clockwise,
+                                client.other_keys_cancel_soft_drop,
+                                client.auto_shift_enabled,
+                                k,
                             );
                             if did_something {
                                 game_wrapper.mark_changed();
@@ -691,95 +2128,170 @@ pub async fn play_game(client: &mut Client, mode: Mode) -> Result<(), io::Error>
                 }
             }
         }
+
+        // Falling blocks and bombs change the game without key presses
+        if tutorial.update(&mut game_wrapper.lock_game()) {
+            game_wrapper.mark_changed();
+        }
     }
 }
 
 fn format_game_duration(duration: Duration) -> String {
     let seconds = duration.as_secs();
     if seconds < 60 {
-        format!("{}sec", seconds)
+        lookup("duration.seconds").replace("{n}", &seconds.to_string())
     } else {
-        format!("{}min", seconds / 60)
+        lookup("duration.minutes").replace("{n}", &(seconds / 60).to_string())
     }
 }
 
 // longest possible return value looks like "42 seconds ago" (14 characters)
 fn format_how_long_ago(timestamp: chrono::DateTime<Utc>) -> String {
-    let diff = Utc::now() - timestamp;
-    let (amount, unit) = if diff.num_seconds() == 0 {
-        return "now".to_string();
+    format_time_ago(Utc::now() - timestamp)
+}
+
+fn format_time_ago(diff: chrono::Duration) -> String {
+    // Negative if the timestamp is in the future, e.g. the server's clock was set back
+    let (amount, one_key, many_key) = if diff.num_seconds() <= 0 {
+        return lookup("ago.now").to_string();
     } else if diff.num_minutes() == 0 {
-        (diff.num_seconds(), "second")
+        (diff.num_seconds(), "ago.second", "ago.seconds")
     } else if diff.num_hours() == 0 {
-        (diff.num_minutes(), "minute")
+        (diff.num_minutes(), "ago.minute", "ago.minutes")
     } else if diff.num_days() == 0 {
-        (diff.num_hours(), "hour")
+        (diff.num_hours(), "ago.hour", "ago.hours")
     } else if diff.num_weeks() == 0 {
-        (diff.num_days(), "day")
+        (diff.num_days(), "ago.day", "ago.days")
         // there's no num_months() or num_years()
     } else if diff.num_days() <= 30 {
-        (diff.num_weeks(), "week")
+        (diff.num_weeks(), "ago.week", "ago.weeks")
     } else if diff.num_days() <= 365 {
-        (((diff.num_days() as f32) / 365.25 * 12.0) as i64, "month")
+        (
+            ((diff.num_days() as f32) / 365.25 * 12.0) as i64,
+            "ago.month",
+            "ago.months",
+        )
     } else {
-        (((diff.num_days() as f32) / 365.25) as i64, "year")
+        (
+            ((diff.num_days() as f32) / 365.25) as i64,
+            "ago.year",
+            "ago.years",
+        )
     };
 
     if amount == 1 {
-        format!("1 {} ago", unit)
+        lookup(one_key).to_string()
     } else {
-        format!("{} {}s ago", amount, unit)
+        lookup(many_key).replace("{n}", &amount.to_string())
     }
 }
 
 fn render_game_over_message(buffer: &mut RenderBuffer, game_result: &GameResult, smile: bool) {
     if smile {
-        buffer.add_centered_text(2, "Game over :)");
+        buffer.add_centered_text(2, lookup("game_over.high_score"));
     } else {
-        buffer.add_centered_text(2, "Game over :(");
+        buffer.add_centered_text(2, lookup("game_over.no_high_score"));
     }
 
-    let duration_text = format_game_duration(game_result.duration);
-    let score_text = format!("{}", game_result.score);
+    // Long pauses would make the game look much longer than it was
+    let mut duration_text = format_game_duration(game_result.duration);
+    if game_result.paused_duration >= Duration::from_secs(1) {
+        duration_text += &lookup("game_over.paused").replace(
+            "{duration}",
+            &format_game_duration(game_result.paused_duration),
+        );
+    }
+    let score_text = format_score(game_result.score);
+
+    // The score is drawn again in color, wherever the translation puts it
+    let summary = lookup("game_over.summary").replace("{duration}", &duration_text);
+    let before_score = summary.split("{score}").next().unwrap();
+    let (left, _) = buffer.add_centered_text(3, &summary.replace("{score}", &score_text));
+    if summary.contains("{score}") {
+        buffer.add_text_with_color(
+            left + before_score.chars().count(),
+            3,
+            &score_text,
+            ingame_ui::SCORE_TEXT_COLOR,
+        );
+    }
 
-    let (_, right) = buffer.add_centered_text(
-        3,
-        &format!(
-            "The game lasted {} and it ended with score {}.",
-            &duration_text, &score_text
-        ),
-    );
-    buffer.add_text_with_color(
-        right - ".".len() - score_text.len(),
-        3,
-        &score_text,
-        ingame_ui::SCORE_TEXT_COLOR,
-    );
+    if let Some(seed) = game_result.seed {
+        buffer.add_centered_text_with_color(
+            5,
+            &lookup("game_over.seed").replace("{seed}", &seed.to_string()),
+            Color::GRAY_FOREGROUND,
+        );
+    }
+
+    let player_count = game_result.players.len();
+    if player_count >= 2 {
+        buffer.add_centered_text_with_color(
+            18,
+            &lookup("game_over.multiplier")
+                .replace("{multiplier}", &score_multiplier(player_count).to_string())
+                .replace("{count}", &player_count.to_string()),
+            Color::GRAY_FOREGROUND,
+        );
+    }
+
+    if !game_result.inactive_players.is_empty() {
+        let names: Vec<String> = game_result
+            .inactive_players
+            .iter()
+            .map(|name| lookup("game_over.inactive").replace("{name}", name))
+            .collect();
+        buffer.add_centered_text_with_color(
+            17,
+            &format_player_names(&names, buffer.width - 2),
+            Color::GRAY_FOREGROUND,
+        );
+    }
 }
 
-fn format_player_names(full_names: &Vec<String>, maxlen: usize) -> String {
+fn format_player_names(full_names: &[String], maxlen: usize) -> String {
+    let (names, others) = fit_player_names(full_names, maxlen);
+    names.join(", ") + &others
+}
+
+// Returns the names to show, possibly shortened, and e.g. " +5 others" if not all names fit
+fn fit_player_names(full_names: &[String], maxlen: usize) -> (Vec<String>, String) {
     let mut limit = full_names.iter().map(|n| n.chars().count()).max().unwrap();
     loop {
-        let mut result = "".to_string();
-        for name in full_names {
-            if !result.is_empty() {
-                result.push_str(", ");
-            }
-            if name.chars().count() > limit {
-                for ch in name.chars().take(limit - 3) {
-                    result.push(ch);
+        let names: Vec<String> = full_names
+            .iter()
+            .map(|name| {
+                if name.chars().count() > limit {
+                    name.chars().take(limit - 3).collect::<String>() + "..."
+                } else {
+                    name.clone()
                 }
-                result.push_str("...");
-            } else {
-                result.push_str(name);
-            }
-        }
+            })
+            .collect();
 
-        if result.chars().count() <= maxlen {
-            return result;
+        let length: usize = names.iter().map(|n| n.chars().count()).sum();
+        if length + 2 * (names.len() - 1) <= maxlen {
+            return (names, "".to_string());
+        }
+        // Names like "Ali..." are still readable, but "A..." is not
+        if limit <= 6 {
+            break;
         }
         limit -= 1;
     }
+
+    // Too many names, e.g. "Alice +5 others"
+    let others = match full_names.len() - 1 {
+        1 => lookup("names.one_other").to_string(),
+        n => lookup("names.others").replace("{n}", &n.to_string()),
+    };
+    let first_name_maxlen = maxlen.saturating_sub(others.chars().count());
+    let first_name: String = full_names[0].chars().take(first_name_maxlen).collect();
+    let others = others
+        .chars()
+        .take(maxlen - first_name.chars().count())
+        .collect();
+    (vec![first_name], others)
 }
 
 fn render_table_row(buffer: &mut RenderBuffer, y: usize, text_places: &[usize], texts: &[&str]) {
@@ -796,27 +2308,37 @@ fn render_high_scores_table(
     top_results: &[GameResult],
     this_game_index: Option<usize>,
 ) {
-    let header = format!(
-        " HIGH SCORES: {} with {} ",
-        mode.name(),
-        if multiplayer {
-            "multiplayer"
-        } else {
-            "single player"
-        }
-    );
+    let header = lookup("high_scores.header")
+        .replace("{mode}", mode.name())
+        .replace(
+            "{players}",
+            if multiplayer {
+                lookup("high_scores.multiplayer")
+            } else {
+                lookup("high_scores.single_player")
+            },
+        );
     buffer.fill_row_with_char(header_y, '=');
     buffer.add_centered_text(header_y, &header);
     buffer.set_row_color(header_y, Color::BLUE_FOREGROUND);
 
-    let last_title = if multiplayer { "Players" } else { "Player" };
-    let titles = ["Score", "Duration", "When", last_title];
+    let last_title = if multiplayer {
+        lookup("high_scores.players")
+    } else {
+        lookup("high_scores.player")
+    };
+    let titles = [
+        lookup("high_scores.score"),
+        lookup("high_scores.duration"),
+        lookup("high_scores.when"),
+        last_title,
+    ];
 
     let mut rows: Vec<Vec<String>> = top_results
         .iter()
         .map(|result| {
             vec![
-                format!("{}", result.score),
+                format_score(result.score),
                 format_game_duration(result.duration),
                 result
                     .timestamp
@@ -831,8 +2353,8 @@ fn render_high_scores_table(
     for column in 0..3 {
         let width = rows
             .iter()
-            .map(|row| row[column].len())
-            .chain([titles[column].len()])
+            .map(|row| row[column].chars().count())
+            .chain([titles[column].chars().count()])
             .max()
             .unwrap();
         let last_separator = separator_places.last().unwrap();
@@ -864,7 +2386,7 @@ fn render_high_scores_table(
             buffer,
             first_result_row_y + i,
             &text_places,
-            &row.iter().map(|s| -> &str { &*s }).collect::<Vec<_>>(),
+            &row.iter().map(|s| -> &str { s }).collect::<Vec<_>>(),
         );
     }
     if let Some(i) = this_game_index {
@@ -872,27 +2394,115 @@ fn render_high_scores_table(
     }
 }
 
+fn render_sprint_records_table(
+    buffer: &mut RenderBuffer,
+    header_y: usize,
+    records: &[SprintRecord],
+) {
+    let header =
+        lookup("high_scores.sprint_header").replace("{rows}", &SPRINT_ROW_COUNT.to_string());
+    buffer.fill_row_with_char(header_y, '=');
+    buffer.add_centered_text(header_y, &header);
+    buffer.set_row_color(header_y, Color::BLUE_FOREGROUND);
+
+    let title_y: usize = header_y + 2;
+    let horizontal_line_y: usize = header_y + 3;
+    let first_result_row_y: usize = header_y + 4;
+
+    // "42 seconds ago" is the longest possible "When" text
+    let separator_places = [0, 13, 30];
+    let text_places: Vec<usize> = separator_places.iter().map(|x| x + 2).collect();
+
+    buffer.fill_row_with_char(horizontal_line_y, '-');
+    for x in separator_places {
+        for y in title_y..(first_result_row_y + records.len()) {
+            buffer.set_char(x, y, '|');
+        }
+    }
+
+    let titles = [
+        lookup("high_scores.time"),
+        lookup("high_scores.when"),
+        lookup("high_scores.player"),
+    ];
+    render_table_row(buffer, title_y, &text_places, &titles);
+    for (i, record) in records.iter().enumerate() {
+        render_table_row(
+            buffer,
+            first_result_row_y + i,
+            &text_places,
+            &[
+                &lookup("duration.seconds")
+                    .replace("{n}", &format!("{:.2}", record.duration.as_secs_f64())),
+                &format_how_long_ago(record.timestamp),
+                &record.player,
+            ],
+        );
+    }
+
+    if records.is_empty() {
+        buffer.add_centered_text(
+            first_result_row_y + 1,
+            &lookup("high_scores.no_sprint_records")
+                .replace("{rows}", &SPRINT_ROW_COUNT.to_string()),
+        );
+    }
+}
+
 fn render_exceptional_high_scores_status<T>(
     buffer: &mut RenderBuffer,
     status: &HighScoresStatus<T>,
 ) {
     match status {
         HighScoresStatus::Loading => {
-            buffer.add_centered_text(9, "Loading...");
+            buffer.add_centered_text(9, lookup("high_scores.loading"));
         }
         HighScoresStatus::Error => {
             // hopefully nobody ever sees this...
-            buffer.add_centered_text_with_color(9, "High Scores Error", Color::RED_FOREGROUND);
+            buffer.add_centered_text_with_color(
+                9,
+                lookup("high_scores.error"),
+                Color::RED_FOREGROUND,
+            );
         }
         HighScoresStatus::Loaded(_) => panic!(),
     }
 }
 
+fn get_rematch_status(client: &Client, mode: Option<Mode>) -> RematchStatus {
+    match (mode, &client.lobby) {
+        (Some(mode), Some(lobby)) => lobby.lock().unwrap().get_rematch_status(mode, client.id),
+        _ => RematchStatus::Unavailable,
+    }
+}
+
+// Returns true if the client chose to play again and the new game should start.
+// Playing again is offered only if rematch_mode is given.
 async fn show_high_scores_after_game(
     client: &mut Client,
     mut receiver: watch::Receiver<GameStatus>,
-) -> Result<(), io::Error> {
+    block_counts_text: &str,
+    rematch_mode: Option<Mode>,
+) -> Result<bool, io::Error> {
+    let mut menu = Menu {
+        items: vec![
+            Some(lookup("rematch.continue").to_string()),
+            Some(lookup("rematch.play_again").to_string()),
+        ],
+        selected_index: 0,
+    };
+    let mut waiting = false;
+    let mut lobby_receiver = client
+        .lobby
+        .as_ref()
+        .map(|lobby| lobby.lock().unwrap().changed_receiver.clone());
+
     loop {
+        let rematch_status = get_rematch_status(client, rematch_mode);
+        if waiting && rematch_status == RematchStatus::Start {
+            return Ok(true);
+        }
+
         {
             let mut render_data = client.render_data.lock().unwrap();
             render_data.clear(80, 24);
@@ -903,6 +2513,7 @@ async fn show_high_scores_after_game(
                         &info.this_game_result,
                         info.this_game_index.is_some(),
                     );
+                    render_data.buffer.add_centered_text(4, block_counts_text);
                     render_high_scores_table(
                         &mut render_data.buffer,
                         6,
@@ -915,40 +2526,119 @@ async fn show_high_scores_after_game(
                 GameStatus::GameOver(status) => {
                     render_exceptional_high_scores_status(&mut render_data.buffer, status)
                 }
-                GameStatus::Playing | GameStatus::Paused(_) => panic!(),
+                GameStatus::Playing | GameStatus::Paused(_) | GameStatus::Crashed => panic!(),
             }
 
             render_data
                 .buffer
-                .add_centered_text(19, "High scores older than 90 days are not shown.");
-            render_data
-                .buffer
-                .add_centered_text(20, "Press Enter to continue...");
-            render_data.changed.notify_one();
+                .add_centered_text(19, lookup("high_scores.old"));
+            match rematch_status {
+                RematchStatus::Open {
+                    accepted, total, ..
+                } if waiting => {
+                    render_data.buffer.add_centered_text(
+                        20,
+                        &lookup("rematch.waiting")
+                            .replace("{accepted}", &accepted.to_string())
+                            .replace("{total}", &total.to_string()),
+                    );
+                    render_data
+                        .buffer
+                        .add_centered_text(21, lookup("rematch.stop_waiting"));
+                }
+                RematchStatus::Open { .. } => menu.render(&mut render_data.buffer, 20),
+                _ => {
+                    render_data
+                        .buffer
+                        .add_centered_text(20, lookup("high_scores.continue"));
+                }
+            }
+            render_data.mark_changed();
         }
 
+        let deadline = match rematch_status {
+            RematchStatus::Open { deadline, .. } => Some(deadline),
+            _ => None,
+        };
+        let wait_for_deadline = async {
+            match deadline {
+                Some(time) => tokio::time::sleep_until(tokio::time::Instant::from_std(time)).await,
+                None => std::future::pending().await,
+            }
+        };
+        let wait_for_lobby_change = async {
+            match &mut lobby_receiver {
+                Some(lobby_receiver) => lobby_receiver.changed().await,
+                None => std::future::pending().await,
+            }
+        };
+
         tokio::select! {
             result = receiver.changed() => {
-                // TODO: why does this never fail?
-                // According to docs it means that the sender isn't dropped.
-                result.unwrap();
+                if result.is_err() {
+                    client.logger.log("Game status channel closed, going back to menu");
+                    return Ok(false);
+                }
+            }
+            _ = wait_for_deadline => {}
+            result = wait_for_lobby_change => {
+                if result.is_err() {
+                    client.logger.log("Lobby no longer exists, going back to menu");
+                    return Ok(false);
+                }
             }
             key = client.receive_key_press() => {
-                if key? == KeyPress::Enter {
-                    return Ok(());
+                let key = key?;
+                let lobby = client.lobby.as_ref();
+                match rematch_status {
+                    RematchStatus::Open { .. } if waiting => {
+                        if key == KeyPress::Enter {
+                            lobby.unwrap().lock().unwrap().answer_rematch(rematch_mode.unwrap(), client.id, false);
+                            return Ok(false);
+                        }
+                    }
+                    RematchStatus::Open { .. } => {
+                        if menu.handle_key_press(key) {
+                            let accept = menu.selected_text() == lookup("rematch.play_again");
+                            lobby.unwrap().lock().unwrap().answer_rematch(rematch_mode.unwrap(), client.id, accept);
+                            if !accept {
+                                return Ok(false);
+                            }
+                            waiting = true;
+                        }
+                    }
+                    _ => {
+                        if key == KeyPress::Enter {
+                            return Ok(false);
+                        }
+                    }
                 }
             }
         }
     }
 }
 
-fn switch_mode(mode: Mode, delta: i8) -> Option<Mode> {
+// Pages are the modes in order, then sprint records (None)
+fn switch_page(page: Option<Mode>, delta: i8) -> Option<Option<Mode>> {
     assert!(delta == -1 || delta == 1);
-    let i = Mode::ALL_MODES.iter().position(|m| *m == mode).unwrap() as i8 + delta;
-    if i < 0 || i >= (Mode::ALL_MODES.len() as i8) {
+    let pages: Vec<Option<Mode>> = Mode::ALL_MODES
+        .iter()
+        .filter(|m| m.has_high_scores())
+        .map(|m| Some(*m))
+        .chain([None])
+        .collect();
+    let i = pages.iter().position(|p| *p == page).unwrap() as i8 + delta;
+    if i < 0 || i >= (pages.len() as i8) {
         None
     } else {
-        Some(Mode::ALL_MODES[i as usize])
+        Some(pages[i as usize])
+    }
+}
+
+fn page_name(page: Option<Mode>) -> &'static str {
+    match page {
+        Some(mode) => mode.name(),
+        None => lookup("high_scores.sprint_records"),
     }
 }
 
@@ -957,7 +2647,7 @@ pub async fn show_all_high_scores(client: &mut Client) -> Result<(), io::Error>
     tokio::spawn(game_wrapper::handle_loading_all_high_scores(sender));
 
     let bottom_text_y = 22;
-    let mut mode = Mode::ALL_MODES[0];
+    let mut page = Some(Mode::ALL_MODES[0]);
     let mut loading_task_done = false;
 
     loop {
@@ -967,33 +2657,41 @@ pub async fn show_all_high_scores(client: &mut Client) -> Result<(), io::Error>
 
             match &*receiver.borrow() {
                 HighScoresStatus::Loaded(results) => {
-                    render_high_scores_table(
-                        &mut render_data.buffer,
-                        0,
-                        mode,
-                        false,
-                        &results[&mode].single_player_results,
-                        None,
-                    );
-                    render_high_scores_table(
-                        &mut render_data.buffer,
-                        11,
-                        mode,
-                        true,
-                        &results[&mode].multiplayer_results,
-                        None,
-                    );
+                    if let Some(mode) = page {
+                        render_high_scores_table(
+                            &mut render_data.buffer,
+                            0,
+                            mode,
+                            false,
+                            &results.by_mode[&mode].single_player_results,
+                            None,
+                        );
+                        render_high_scores_table(
+                            &mut render_data.buffer,
+                            11,
+                            mode,
+                            true,
+                            &results.by_mode[&mode].multiplayer_results,
+                            None,
+                        );
+                    } else {
+                        render_sprint_records_table(
+                            &mut render_data.buffer,
+                            0,
+                            &results.sprint_records,
+                        );
+                    }
 
-                    if let Some(prev) = switch_mode(mode, -1) {
+                    if let Some(prev) = switch_page(page, -1) {
                         render_data.buffer.add_text_with_color(
                             0,
                             bottom_text_y,
-                            &format!(" <-- {} ", prev.name()),
+                            &format!(" <-- {} ", page_name(prev)),
                             Color::YELLOW_FOREGROUND,
                         );
                     }
-                    if let Some(next) = switch_mode(mode, 1) {
-                        let text = format!(" {} --> ", next.name());
+                    if let Some(next) = switch_page(page, 1) {
+                        let text = format!(" {} --> ", page_name(next));
                         render_data.buffer.add_text_with_color(
                             80 - text.len(),
                             bottom_text_y,
@@ -1007,13 +2705,13 @@ pub async fn show_all_high_scores(client: &mut Client) -> Result<(), io::Error>
 
             render_data
                 .buffer
-                .add_centered_text(bottom_text_y-1, "High scores older than 90 days are not shown.");
+                .add_centered_text(bottom_text_y - 1, lookup("high_scores.old"));
 
             render_data
                 .buffer
-                .add_centered_text(bottom_text_y, "Press Enter to continue...");
+                .add_centered_text(bottom_text_y, lookup("high_scores.continue"));
 
-            render_data.changed.notify_one();
+            render_data.mark_changed();
         }
 
         tokio::select! {
@@ -1024,9 +2722,9 @@ pub async fn show_all_high_scores(client: &mut Client) -> Result<(), io::Error>
             }
             key = client.receive_key_press() => {
                 match key? {
-                    KeyPress::Enter => return Ok(()),
-                    KeyPress::Left => mode = switch_mode(mode, -1).unwrap_or(mode),
-                    KeyPress::Right => mode = switch_mode(mode, 1).unwrap_or(mode),
+                    KeyPress::Enter | KeyPress::Escape => return Ok(()),
+                    KeyPress::Left => page = switch_page(page, -1).unwrap_or(page),
+                    KeyPress::Right => page = switch_page(page, 1).unwrap_or(page),
                     _ => {}
                 }
             }
@@ -1039,10 +2737,75 @@ mod test {
     use super::*;
     use crate::connection::Receiver;
     use crate::escapes::TerminalType;
+    use crate::game_logic::game::Game;
     use crate::high_scores::HighScoresForGame;
+    use crate::ip_tracker::MAX_FAILED_LOBBY_ATTEMPTS;
+    use crate::logging::test::strip_timestamp;
+    use crate::logging::ClientLogger;
+    use crate::logging::LogFormat;
+    use crate::name_metrics::NameCounts;
+    use crate::render::SharedRenderData;
+    use std::net::IpAddr;
     use std::path::PathBuf;
     use weak_table::WeakValueHashMap;
 
+    // For tests that don't care about names in use or name metrics
+    async fn ask_name_with_fresh_state(client: &mut Client) -> Result<(), io::Error> {
+        ask_name(
+            client,
+            Arc::new(Mutex::new(HashSet::new())),
+            Arc::new(Mutex::new(NameMetrics::new())),
+        )
+        .await
+    }
+
+    fn sample_menu() -> Menu {
+        Menu {
+            items: vec![
+                None,
+                Some("Red".to_string()),
+                Some("Green".to_string()),
+                None,
+                Some("Rose".to_string()),
+                Some("Blue".to_string()),
+                None,
+            ],
+            selected_index: 1,
+        }
+    }
+
+    #[test]
+    fn test_menu_wrap_around() {
+        let mut menu = sample_menu();
+        menu.handle_key_press(KeyPress::Up);
+        assert_eq!(menu.selected_text(), "Blue");
+        menu.handle_key_press(KeyPress::Down);
+        assert_eq!(menu.selected_text(), "Red");
+        menu.handle_key_press(KeyPress::Down);
+        menu.handle_key_press(KeyPress::Down);
+        assert_eq!(menu.selected_text(), "Rose");
+
+        menu.handle_key_press(KeyPress::Home);
+        assert_eq!(menu.selected_text(), "Red");
+        menu.handle_key_press(KeyPress::End);
+        assert_eq!(menu.selected_text(), "Blue");
+    }
+
+    #[test]
+    fn test_menu_letter_cycling() {
+        let mut menu = sample_menu();
+        menu.handle_key_press(KeyPress::Character('r'));
+        assert_eq!(menu.selected_text(), "Rose");
+        menu.handle_key_press(KeyPress::Character('R'));
+        assert_eq!(menu.selected_text(), "Red");
+        menu.handle_key_press(KeyPress::Character('b'));
+        assert_eq!(menu.selected_text(), "Blue");
+        menu.handle_key_press(KeyPress::Character('b'));
+        assert_eq!(menu.selected_text(), "Blue");
+        menu.handle_key_press(KeyPress::Character('x'));
+        assert_eq!(menu.selected_text(), "Blue");
+    }
+
     #[tokio::test]
     async fn test_name_entering_on_windows_cmd_exe() {
         let mut client = Client::new(
@@ -1050,9 +2813,7 @@ mod test {
             Receiver::Test("WindowsUsesCRLF\r\n".to_string()),
             TerminalType::Ansi,
         );
-        ask_name(&mut client, Arc::new(Mutex::new(HashSet::new())))
-            .await
-            .unwrap();
+        ask_name_with_fresh_state(&mut client).await.unwrap();
         assert_eq!(client.get_name(), Some("WindowsUsesCRLF"));
     }
 
@@ -1063,7 +2824,7 @@ mod test {
             Receiver::Test("Oops\n".to_string()),
             TerminalType::Ansi,
         );
-        let result = ask_name(&mut client, Arc::new(Mutex::new(HashSet::new()))).await;
+        let result = ask_name_with_fresh_state(&mut client).await;
         assert!(result.is_err());
         assert_eq!(client.get_name(), None);
         assert!(client
@@ -1078,9 +2839,7 @@ mod test {
             Receiver::Test("linux_usr\r".to_string()),
             TerminalType::Ansi,
         );
-        ask_name(&mut client, Arc::new(Mutex::new(HashSet::new())))
-            .await
-            .unwrap();
+        ask_name_with_fresh_state(&mut client).await.unwrap();
         assert_eq!(client.get_name(), Some("linux_usr"));
     }
 
@@ -1091,24 +2850,71 @@ mod test {
             Receiver::Test("VeryVeryLongNameGoesHere\r".to_string()),
             TerminalType::Ansi,
         );
-        ask_name(&mut client, Arc::new(Mutex::new(HashSet::new())))
-            .await
-            .unwrap();
+        let metrics = Arc::new(Mutex::new(NameMetrics::new()));
+        ask_name(
+            &mut client,
+            Arc::new(Mutex::new(HashSet::new())),
+            metrics.clone(),
+        )
+        .await
+        .unwrap();
         assert_eq!(client.get_name(), Some("VeryVeryLongNam"));
+        assert_eq!(
+            metrics.lock().unwrap().raw_tcp,
+            NameCounts {
+                too_long: 1,
+                registered: 1,
+                ..Default::default()
+            }
+        );
 
         // Name should show up as truncated to the user entering it
         assert!(client.text().contains("Name: VeryVeryLongNam"));
         assert!(!client.text().contains("Name: VeryVeryLongName"));
     }
 
+    #[tokio::test]
+    async fn test_long_name_counted_once() {
+        let mut client = Client::new(
+            123,
+            Receiver::Test("VeryVeryLongNameGoesHere\r\r".to_string()),
+            TerminalType::Ansi,
+        );
+        let metrics = Arc::new(Mutex::new(NameMetrics::new()));
+        let used_names = HashSet::from(["veryverylongnam".to_string()]);
+        let result = ask_name(
+            &mut client,
+            Arc::new(Mutex::new(used_names)),
+            metrics.clone(),
+        )
+        .await;
+        assert!(result.is_err());
+        assert_eq!(
+            metrics.lock().unwrap().raw_tcp,
+            NameCounts {
+                too_long: 1,
+                in_use: 2,
+                ..Default::default()
+            }
+        );
+    }
+
     #[tokio::test]
     async fn test_empty_name() {
         for input in ["\r", "    \r"] {
             let mut client =
                 Client::new(123, Receiver::Test(input.to_string()), TerminalType::Ansi);
-            let result = ask_name(&mut client, Arc::new(Mutex::new(HashSet::new()))).await;
+            let metrics = Arc::new(Mutex::new(NameMetrics::new()));
+            let result = ask_name(
+                &mut client,
+                Arc::new(Mutex::new(HashSet::new())),
+                metrics.clone(),
+            )
+            .await;
             assert!(result.is_err());
             assert_eq!(client.get_name(), None);
+            assert_eq!(metrics.lock().unwrap().raw_tcp.empty, 1);
+            assert_eq!(metrics.lock().unwrap().websocket, NameCounts::default());
             assert!(client
                 .text()
                 .contains("Please write a name before pressing Enter"));
@@ -1118,24 +2924,69 @@ mod test {
     #[tokio::test]
     async fn test_invalid_character_in_name() {
         let mut client = Client::new(123, Receiver::Test(":]\r".to_string()), TerminalType::Ansi);
-        let result = ask_name(&mut client, Arc::new(Mutex::new(HashSet::new()))).await;
+        let metrics = Arc::new(Mutex::new(NameMetrics::new()));
+        let result = ask_name(
+            &mut client,
+            Arc::new(Mutex::new(HashSet::new())),
+            metrics.clone(),
+        )
+        .await;
         assert!(result.is_err());
         assert_eq!(client.get_name(), None);
+        assert_eq!(
+            metrics.lock().unwrap().raw_tcp,
+            NameCounts {
+                invalid_char: 1,
+                ..Default::default()
+            }
+        );
         assert!(client
             .text()
             .contains("The name can't contain a ']' character."));
     }
 
+    #[tokio::test]
+    async fn test_control_characters_in_name() {
+        // Escape and bell are ignored, \x7f is backspace
+        let mut client = Client::new(
+            123,
+            Receiver::Test("x\x1b[1m\r\x7f\x7f\x7f\x07y\r".to_string()),
+            TerminalType::Ansi,
+        );
+        let metrics = Arc::new(Mutex::new(NameMetrics::new()));
+        ask_name(
+            &mut client,
+            Arc::new(Mutex::new(HashSet::new())),
+            metrics.clone(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(client.get_name(), Some("xy"));
+        assert_eq!(
+            metrics.lock().unwrap().raw_tcp,
+            NameCounts {
+                invalid_char: 1,
+                registered: 1,
+                ..Default::default()
+            }
+        );
+        assert!(!client
+            .text()
+            .chars()
+            .any(|ch| ch.is_control() && ch != '\n'));
+    }
+
     #[tokio::test]
     async fn test_name_in_use() {
         let names = Arc::new(Mutex::new(HashSet::new()));
+        let metrics = Arc::new(Mutex::new(NameMetrics::new()));
 
         let mut alice = Client::new(
             1,
             Receiver::Test("my NAME\r".to_string()),
             TerminalType::Ansi,
         );
-        let result = ask_name(&mut alice, names.clone()).await;
+        let result = ask_name(&mut alice, names.clone(), metrics.clone()).await;
         assert!(result.is_ok());
         assert_eq!(alice.get_name(), Some("my NAME"));
 
@@ -1145,12 +2996,20 @@ mod test {
             Receiver::Test("MY name\r".to_string()),
             TerminalType::Ansi,
         );
-        let result = ask_name(&mut bob, names.clone()).await;
+        let result = ask_name(&mut bob, names.clone(), metrics.clone()).await;
         assert!(result.is_err());
         assert_eq!(bob.get_name(), None);
         assert!(bob
             .text()
             .contains("This name is in use. Try a different name."));
+        assert_eq!(
+            metrics.lock().unwrap().raw_tcp,
+            NameCounts {
+                in_use: 1,
+                registered: 1,
+                ..Default::default()
+            }
+        );
 
         drop(alice);
         bob = Client::new(
@@ -1158,9 +3017,10 @@ mod test {
             Receiver::Test("MY name\r".to_string()),
             TerminalType::Ansi,
         );
-        let result = ask_name(&mut bob, names.clone()).await;
+        let result = ask_name(&mut bob, names.clone(), metrics.clone()).await;
         assert!(result.is_ok());
         assert_eq!(bob.get_name(), Some("MY name"));
+        assert_eq!(metrics.lock().unwrap().raw_tcp.registered, 2);
     }
 
     struct CdToTemporaryDir {
@@ -1184,6 +3044,64 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_form_navigation() {
+        let mut form = Form::new(vec![
+            FormField::new("A: ", 3),
+            FormField::new("B: ", 3),
+            FormField::new("C: ", 3),
+        ]);
+        for ch in "xy".chars() {
+            assert!(!form.handle_key_press(KeyPress::Character(ch)));
+        }
+        assert!(!form.handle_key_press(KeyPress::Character('\t')));
+        assert!(!form.handle_key_press(KeyPress::Down));
+        assert_eq!(form.focused_index, 2);
+        form.handle_key_press(KeyPress::Down);
+        assert_eq!(form.focused_index, 0);
+        form.handle_key_press(KeyPress::Up);
+        assert_eq!(form.focused_index, 2);
+        for ch in "abcd".chars() {
+            form.handle_key_press(KeyPress::Character(ch));
+        }
+        form.handle_key_press(KeyPress::Up);
+        form.handle_key_press(KeyPress::BackSpace);
+        assert!(form.handle_key_press(KeyPress::Enter));
+
+        let texts: Vec<&str> = form.fields.iter().map(|f| f.text.as_str()).collect();
+        assert_eq!(texts, ["xy", "", "abc"]);
+        assert!(form.fields[2].truncated);
+        assert!(!form.fields[0].truncated);
+    }
+
+    #[tokio::test]
+    async fn test_report_problem_form() {
+        // Enter with empty description moves focus back to it
+        let mut client = Client::new(
+            123,
+            Receiver::Test("\tcontact\r".to_string()),
+            TerminalType::Ansi,
+        );
+        assert!(ask_problem_report(&mut client).await.is_err());
+        assert!(client
+            .text()
+            .contains("Please describe the problem before pressing Enter."));
+        assert_eq!(
+            client.render_data.lock().unwrap().cursor_pos,
+            Some((40, 10))
+        );
+
+        let mut client = Client::new(
+            123,
+            Receiver::Test("\tcontact\rLag\r".to_string()),
+            TerminalType::Ansi,
+        );
+        let report = ask_problem_report(&mut client).await.unwrap();
+        assert_eq!(report.description, "Lag");
+        assert_eq!(report.contact, "contact");
+        assert_eq!(report.lobby_id, None);
+    }
+
     #[tokio::test]
     async fn test_motd() {
         let _temp_cd_handle = CdToTemporaryDir::new(); // don't touch user's catris_motd.txt
@@ -1195,11 +3113,9 @@ mod test {
         tokio::fs::write("catris_motd.txt", "Hello World\nSecond line of text\n")
             .await
             .unwrap();
-        ask_name(&mut client, Arc::new(Mutex::new(HashSet::new())))
-            .await
-            .unwrap();
+        ask_name_with_fresh_state(&mut client).await.unwrap();
 
-        assert!(ask_if_new_lobby(&mut client).await.is_err());
+        assert!(ask_lobby_choice(&mut client).await.is_err());
         assert!(client.text().contains("   Hello World   "));
         assert!(client.text().contains("   Second line of text   "));
     }
@@ -1210,21 +3126,22 @@ mod test {
             123,
             Receiver::Test(
                 concat!(
-                    "John\r",         // name
-                    "\r",             // new lobby
-                    "\r",             // select traditional game (first item in list)
-                    "g\r",            // select gameplay tips
-                    "\x1b[A\x1b[A\r", // arrow up twice to select bottle game
-                    "\x1b[B\r",       // arrow down to select ring game
+                    "John\r",               // name
+                    "\r",                   // new lobby
+                    "\r",                   // select traditional game (first item in list)
+                    "g\r",                  // select gameplay tips
+                    "\x1b[A\x1b[A\x1b[A\r", // arrow up 3 times to select bottle game
+                    "\x1b[B\r",             // arrow down to select ring game
+                    "\x1b[B\r",             // arrow down to select zen game
                 )
                 .to_string(),
             ),
             TerminalType::Ansi,
         );
-        let result = ask_name(&mut client, Arc::new(Mutex::new(HashSet::new()))).await;
+        let result = ask_name_with_fresh_state(&mut client).await;
         assert!(result.is_ok());
-        let result = ask_if_new_lobby(&mut client).await;
-        assert!(result.unwrap());
+        let result = ask_lobby_choice(&mut client).await;
+        assert_eq!(result.unwrap(), LobbyChoice::NewLobby);
         client.make_lobby(Arc::new(Mutex::new(WeakValueHashMap::new())));
 
         let mut selected_index = 0;
@@ -1236,6 +3153,82 @@ mod test {
         assert_eq!(result.unwrap(), ModeMenuChoice::PlayGame(Mode::Bottle));
         let result = show_mode_menu(&mut client, &mut selected_index).await;
         assert_eq!(result.unwrap(), ModeMenuChoice::PlayGame(Mode::Ring));
+        let result = show_mode_menu(&mut client, &mut selected_index).await;
+        assert_eq!(result.unwrap(), ModeMenuChoice::PlayGame(Mode::Zen));
+        assert!(client.text().contains("Zen game (0/1 players)"));
+    }
+
+    #[tokio::test]
+    async fn test_game_in_progress_blocks_other_modes() {
+        let mut client = Client::new(
+            123,
+            Receiver::Test("John\r\rr".to_string()),
+            TerminalType::Ansi,
+        );
+        ask_name_with_fresh_state(&mut client).await.unwrap();
+        assert_eq!(
+            ask_lobby_choice(&mut client).await.unwrap(),
+            LobbyChoice::NewLobby
+        );
+        client.make_lobby(Arc::new(Mutex::new(WeakValueHashMap::new())));
+
+        let lobby = client.lobby.clone().unwrap();
+        lobby.lock().unwrap().toggle_allow_parallel_games();
+        let _token = join_game_in_a_lobby(lobby, client.id, Mode::Bottle).unwrap();
+
+        // Select ring game by pressing r
+        assert!(show_mode_menu(&mut client, &mut 0).await.is_err());
+        assert!(client
+            .text()
+            .contains("Press P to allow playing different games"));
+        assert!(client
+            .text()
+            .contains("A Bottle game is in progress — join it or wait"));
+    }
+
+    #[tokio::test]
+    async fn test_json_logs_know_name_and_lobby() {
+        let mut client = Client::new(
+            123,
+            Receiver::Test("John\r\r".to_string()),
+            TerminalType::Ansi,
+        );
+        let (logger, lines) = ClientLogger::capturing(123, LogFormat::Json);
+        client.logger = logger;
+        ask_name_with_fresh_state(&mut client).await.unwrap();
+        ask_lobby_choice(&mut client).await.unwrap();
+        client.make_lobby(Arc::new(Mutex::new(WeakValueHashMap::new())));
+        let lobby_id = client.lobby.as_ref().unwrap().lock().unwrap().id.clone();
+
+        let lines: Vec<String> = lines
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|line| strip_timestamp(line))
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                r#""client_id":123,"lobby_id":null,"name":"John","event":"name-set","message":"Name set: John"}"#.to_string(),
+                format!(
+                    r#""client_id":123,"lobby_id":"{0}","name":"John","event":"lobby-join","message":"Created lobby: {0}"}}"#,
+                    lobby_id
+                ),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reduced_flashing_menu_item() {
+        let mut client = Client::new(
+            1,
+            Receiver::Test("Alice\rr\r".to_string()),
+            TerminalType::Ansi,
+        );
+        ask_name_with_fresh_state(&mut client).await.unwrap();
+        assert!(ask_lobby_choice(&mut client).await.is_err());
+        assert!(client.reduced_flashing);
+        assert!(client.text().contains("Reduced flashing: on"));
     }
 
     #[tokio::test]
@@ -1246,9 +3239,9 @@ mod test {
             Receiver::Test("Alice\rq\r".to_string()),
             TerminalType::Ansi,
         );
-        let result = ask_name(&mut client, Arc::new(Mutex::new(HashSet::new()))).await;
+        let result = ask_name_with_fresh_state(&mut client).await;
         assert!(result.is_ok());
-        let result = ask_if_new_lobby(&mut client).await;
+        let result = ask_lobby_choice(&mut client).await;
         assert_eq!(
             result.unwrap_err().to_string(),
             "user selected \"Quit\" in menu"
@@ -1261,10 +3254,10 @@ mod test {
             Receiver::Test("Bob\r\rq\r".to_string()),
             TerminalType::Ansi,
         );
-        let result = ask_name(&mut client, Arc::new(Mutex::new(HashSet::new()))).await;
+        let result = ask_name_with_fresh_state(&mut client).await;
         assert!(result.is_ok());
-        let result = ask_if_new_lobby(&mut client).await;
-        assert!(result.unwrap());
+        let result = ask_lobby_choice(&mut client).await;
+        assert_eq!(result.unwrap(), LobbyChoice::NewLobby);
         client.make_lobby(Arc::new(Mutex::new(WeakValueHashMap::new())));
         let result = show_mode_menu(&mut client, &mut 0).await;
         assert_eq!(
@@ -1273,6 +3266,41 @@ mod test {
         );
     }
 
+    #[tokio::test]
+    async fn test_tutorial() {
+        let mut client = Client::new(
+            1,
+            Receiver::Test("Alice\rt\ra".to_string()),
+            TerminalType::Ansi,
+        );
+        let result = ask_name_with_fresh_state(&mut client).await;
+        assert!(result.is_ok());
+        let result = ask_lobby_choice(&mut client).await;
+        assert_eq!(result.unwrap(), LobbyChoice::Tutorial);
+
+        // Moving the block shows the next instructions, then we run out of input
+        assert!(play_tutorial(&mut client).await.is_err());
+        assert!(client.text().contains("Nice! Now rotate the block"));
+        assert!(!client.text().contains("Welcome to catris!"));
+        assert!(!client.text().contains("Lobby ID"));
+    }
+
+    #[tokio::test]
+    async fn test_setting_keys_in_tutorial() {
+        let mut client = Client::new(
+            1,
+            Receiver::Test("Alice\rmgb".to_string()),
+            TerminalType::Ansi,
+        );
+        ask_name_with_fresh_state(&mut client).await.unwrap();
+        let auto_shift = client.auto_shift_enabled;
+        let bell = client.bell_enabled;
+        assert!(play_tutorial(&mut client).await.is_err());
+        assert_eq!(client.auto_shift_enabled, !auto_shift);
+        assert_eq!(client.bell_enabled, !bell);
+        assert!(client.reduced_flashing);
+    }
+
     async fn make_client_and_enter_lobby_id(
         name: &str,
         id_to_enter: &str,
@@ -1284,21 +3312,35 @@ mod test {
             Receiver::Test(format!("{}\r{}\r", name, id_to_enter)),
             TerminalType::Ansi,
         );
-        let result = ask_name(&mut client, Arc::new(Mutex::new(HashSet::new()))).await;
+        let result = ask_name_with_fresh_state(&mut client).await;
         assert!(result.is_ok());
-        _ = ask_lobby_id_and_join_lobby(&mut client, lobbies).await;
+        _ = ask_lobby_id_and_join_lobby(
+            &mut client,
+            lobbies,
+            Arc::new(Mutex::new(IpTracker::new())),
+        )
+        .await;
         client
     }
 
+    // Alice makes a new lobby, then presses the given keys
+    async fn make_lobby_as_alice(client_id: u64, keys: &str, lobbies: Lobbies) -> Client {
+        let mut alice = Client::new(
+            client_id,
+            Receiver::Test(format!("Alice\r{}", keys)),
+            TerminalType::Ansi,
+        );
+        ask_name_with_fresh_state(&mut alice).await.unwrap();
+        alice.make_lobby(lobbies);
+        alice
+    }
+
     #[tokio::test]
     async fn test_joining_existing_lobby() {
         let lobbies = Arc::new(Mutex::new(WeakValueHashMap::new()));
 
         // Alice makes a new lobby
-        let mut alice = Client::new(1, Receiver::Test("Alice\r".to_string()), TerminalType::Ansi);
-        let result = ask_name(&mut alice, Arc::new(Mutex::new(HashSet::new()))).await;
-        assert!(result.is_ok());
-        alice.make_lobby(lobbies.clone());
+        let alice = make_lobby_as_alice(1, "", lobbies.clone()).await;
 
         let lobby_id = alice.lobby.as_ref().unwrap().lock().unwrap().id.clone();
         assert_eq!(lobby_id.len(), 6);
@@ -1333,19 +3375,184 @@ mod test {
         assert!(bob.text().contains("1. Bob (you)"));
         drop(bob);
 
-        // Lobby stops existing once everyone quits
-        let charlie = make_client_and_enter_lobby_id("Charlie", &lobby_id, lobbies).await;
-        assert!(charlie.text().contains("There is no lobby with ID '"));
+        // Lobby stops existing once everyone quits
+        let charlie = make_client_and_enter_lobby_id("Charlie", &lobby_id, lobbies).await;
+        assert!(charlie.text().contains("There is no lobby with ID '"));
+    }
+
+    async fn make_client_and_enter_player_name(
+        name: &str,
+        name_to_enter: &str,
+        lobbies: Lobbies,
+    ) -> Client {
+        let client_id = name.chars().map(|c| u32::from(c) as u64).sum();
+        let mut client = Client::new(
+            client_id,
+            Receiver::Test(format!("{}\r{}\r", name, name_to_enter)),
+            TerminalType::Ansi,
+        );
+        let result = ask_name_with_fresh_state(&mut client).await;
+        assert!(result.is_ok());
+        _ = ask_player_name_and_join_lobby(
+            &mut client,
+            lobbies,
+            Arc::new(Mutex::new(IpTracker::new())),
+        )
+        .await;
+        client
+    }
+
+    #[tokio::test]
+    async fn test_joining_by_player_name() {
+        let lobbies = Arc::new(Mutex::new(WeakValueHashMap::new()));
+        let alice = make_lobby_as_alice(1, "", lobbies.clone()).await;
+        let lobby = alice.lobby.clone().unwrap();
+
+        // Not found: only exact names work
+        let bob = make_client_and_enter_player_name("Bob", "Ali", lobbies.clone()).await;
+        assert!(bob.lobby.is_none());
+        assert!(bob.text().contains("There is no player named 'Ali'."));
+
+        // Found, case insensitive
+        let bob = make_client_and_enter_player_name("Bob", "aLiCe", lobbies.clone()).await;
+        assert!(Arc::ptr_eq(bob.lobby.as_ref().unwrap(), &lobby));
+        drop(bob);
+
+        // Full
+        let mut others = vec![];
+        for i in 1..MAX_CLIENTS_PER_LOBBY {
+            let name = format!("Bob {}", i);
+            others.push(make_client_and_enter_player_name(&name, "Alice", lobbies.clone()).await);
+        }
+        let charlie = make_client_and_enter_player_name("Charlie", "Bob 3", lobbies.clone()).await;
+        assert!(charlie.lobby.is_none());
+        assert!(charlie
+            .text()
+            .contains("The lobby of 'Bob 3' is full. It already has 6 players."));
+        others.clear();
+
+        // Opted out, looks the same as not found
+        lobby.lock().unwrap().toggle_unlisted_for_name_search();
+        let charlie = make_client_and_enter_player_name("Charlie", "Alice", lobbies.clone()).await;
+        assert!(charlie.lobby.is_none());
+        assert!(charlie.text().contains("There is no player named 'Alice'."));
+    }
+
+    #[tokio::test]
+    async fn test_joining_by_player_name_counts_failed_attempts() {
+        let lobbies = Arc::new(Mutex::new(WeakValueHashMap::new()));
+        let alice = make_lobby_as_alice(1, "", lobbies.clone()).await;
+
+        let ip = IpAddr::from([10, 0, 0, 1]);
+        let ip_tracker = Arc::new(Mutex::new(IpTracker::new()));
+        for _ in 0..MAX_FAILED_LOBBY_ATTEMPTS - 1 {
+            ip_tracker
+                .lock()
+                .unwrap()
+                .record_failed_lobby_attempt(ip, Instant::now());
+        }
+
+        // The wrong name uses up the budget, then even the correct name fails
+        for name_to_enter in ["Bob", "Alice"] {
+            let text = format!("{}\r", name_to_enter);
+            let mut client = Client::new(2, Receiver::Test(text), TerminalType::Ansi);
+            client.ip = Some(ip);
+            _ = ask_player_name_and_join_lobby(&mut client, lobbies.clone(), ip_tracker.clone())
+                .await;
+            assert!(client.lobby.is_none());
+            if name_to_enter == "Bob" {
+                assert!(client.text().contains("There is no player named 'Bob'."));
+            } else {
+                assert!(client
+                    .text()
+                    .contains("Too many wrong lobby IDs or player names. Please try again later."));
+            }
+        }
+        assert_eq!(
+            alice.lobby.as_ref().unwrap().lock().unwrap().clients.len(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_joining_lobby_with_confusable_characters() {
+        let lobbies: Lobbies = Arc::new(Mutex::new(WeakValueHashMap::new()));
+        let lobby = Arc::new(Mutex::new(Lobby::new("LLDDKK")));
+        lobbies
+            .lock()
+            .unwrap()
+            .insert("LLDDKK".to_string(), lobby.clone());
+
+        // "1" and "I" look like "L", "0" and "O" look like "D"
+        let bob = make_client_and_enter_lobby_id("Bob", "1iO0kk", lobbies.clone()).await;
+        assert!(Arc::ptr_eq(bob.lobby.as_ref().unwrap(), &lobby));
+        assert_eq!(lobby.lock().unwrap().clients.len(), 1);
+
+        let alice = make_client_and_enter_lobby_id("Alice", "110KKK", lobbies.clone()).await;
+        assert!(alice.lobby.is_none());
+        assert!(alice.text().contains(
+            "There is no lobby with ID '110KKK'. Maybe you mixed up similar characters?"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_too_many_wrong_lobby_ids() {
+        let lobbies: Lobbies = Arc::new(Mutex::new(WeakValueHashMap::new()));
+        let lobby = Arc::new(Mutex::new(Lobby::new("LLDDKK")));
+        lobbies
+            .lock()
+            .unwrap()
+            .insert("LLDDKK".to_string(), lobby.clone());
+
+        let ip = IpAddr::from([10, 0, 0, 1]);
+        let ip_tracker = Arc::new(Mutex::new(IpTracker::new()));
+        let enter_lobby_id = |id: &str| {
+            let mut client =
+                Client::new(1, Receiver::Test(format!("{}\r", id)), TerminalType::Ansi);
+            client.ip = Some(ip);
+            let lobbies = lobbies.clone();
+            let ip_tracker = ip_tracker.clone();
+            async move {
+                let start = Instant::now();
+                _ = ask_lobby_id_and_join_lobby(&mut client, lobbies, ip_tracker).await;
+                (client, start.elapsed())
+            }
+        };
+
+        // Errors take the same time, whether the lobby exists or not
+        let (client, elapsed) = enter_lobby_id("XXXXXX").await;
+        assert!(client
+            .text()
+            .contains("There is no lobby with ID 'XXXXXX'."));
+        assert!(elapsed >= RATE_LIMITED_PROMPT_ERROR_DELAY);
+
+        // Another connection from the same IP uses up the budget
+        for _ in 0..MAX_FAILED_LOBBY_ATTEMPTS - 2 {
+            ip_tracker
+                .lock()
+                .unwrap()
+                .record_failed_lobby_attempt(ip, Instant::now());
+        }
+        let (client, _) = enter_lobby_id("XXXXXX").await;
+        assert!(client
+            .text()
+            .contains("There is no lobby with ID 'XXXXXX'."));
+
+        // Now even the correct ID doesn't work
+        let (client, elapsed) = enter_lobby_id("LLDDKK").await;
+        assert!(client.lobby.is_none());
+        assert!(client
+            .text()
+            .contains("Too many wrong lobby IDs or player names. Please try again later."));
+        assert!(elapsed >= RATE_LIMITED_PROMPT_ERROR_DELAY);
+        assert!(lobby.lock().unwrap().clients.is_empty());
     }
 
     #[tokio::test]
     async fn test_lobby_full() {
         let lobbies = Arc::new(Mutex::new(WeakValueHashMap::new()));
 
-        let mut alice = Client::new(1, Receiver::Test("Alice\r".to_string()), TerminalType::Ansi);
-        let result = ask_name(&mut alice, Arc::new(Mutex::new(HashSet::new()))).await;
-        assert!(result.is_ok());
-        alice.make_lobby(lobbies.clone());
+        let alice = make_lobby_as_alice(1, "", lobbies.clone()).await;
         let lobby_id = alice.lobby.as_ref().unwrap().lock().unwrap().id.clone();
 
         let mut bobs = vec![];
@@ -1385,17 +3592,19 @@ mod test {
             };
             let mut client = Client::new(i, Receiver::Test(text), TerminalType::Ansi);
 
-            ask_name(&mut client, Arc::new(Mutex::new(HashSet::new())))
-                .await
-                .unwrap();
+            ask_name_with_fresh_state(&mut client).await.unwrap();
 
             if i == 0 {
                 client.make_lobby(lobbies.clone());
                 lobby_id = Some(client.lobby.as_ref().unwrap().lock().unwrap().id.clone());
             } else {
-                ask_lobby_id_and_join_lobby(&mut client, lobbies.clone())
-                    .await
-                    .unwrap();
+                ask_lobby_id_and_join_lobby(
+                    &mut client,
+                    lobbies.clone(),
+                    Arc::new(Mutex::new(IpTracker::new())),
+                )
+                .await
+                .unwrap();
             }
 
             if i == 4 {
@@ -1415,6 +3624,554 @@ mod test {
         assert!(client.text().contains("This game is full."));
     }
 
+    #[tokio::test]
+    async fn test_waitlist() {
+        let lobby = Arc::new(Mutex::new(Lobby::new("LLDDKK")));
+        let mut tokens = vec![];
+        for i in 0..4 {
+            lobby
+                .lock()
+                .unwrap()
+                .add_client(i, &format!("Player {}", i), None);
+            tokens.push(
+                join_game_in_a_lobby(lobby.clone(), i, Mode::Ring)
+                    .unwrap()
+                    .1,
+            );
+        }
+
+        // Both choose "Join waitlist" when they see that the game is full
+        let used_names = Arc::new(Mutex::new(HashSet::new()));
+        let mut waiting_clients = vec![];
+        for (id, keys) in [(4, "\rh\x1b"), (5, "\r\r")] {
+            let mut client = Client::new(id, Receiver::Test(keys.to_string()), TerminalType::Ansi);
+            assert!(client.set_name(&format!("Waiter {}", id), used_names.clone()));
+            assert!(client.join_lobby(lobby.clone()));
+            show_join_error(&mut client, Mode::Ring, JoinError::Full)
+                .await
+                .unwrap();
+            waiting_clients.push(client);
+        }
+        let mut second = waiting_clients.pop().unwrap();
+        let mut first = waiting_clients.pop().unwrap();
+
+        // First in line ignores the h key and declines with Esc
+        drop(tokens.pop());
+        assert!(show_mode_menu(&mut first, &mut 0).await.is_err());
+        assert!(!first.text().contains("A slot opened"));
+        assert_eq!(lobby.lock().unwrap().get_waitlist_position(4), None);
+
+        let choice = show_mode_menu(&mut second, &mut 0).await.unwrap();
+        assert_eq!(choice, ModeMenuChoice::PlayGame(Mode::Ring));
+        assert!(second
+            .text()
+            .contains("A slot opened in Ring game — press Enter to join within 15 seconds"));
+        assert!(second
+            .text()
+            .contains("Waiting for a slot in Ring game, you are number 1 in line."));
+    }
+
+    #[tokio::test]
+    async fn test_join_error_messages() {
+        for (error, message) in [
+            (JoinError::Full, "This game is full."),
+            (
+                JoinError::IpRestricted,
+                "Someone from your IP address is already playing this game.",
+            ),
+            (
+                JoinError::NotAllowed(Mode::Bottle),
+                "A Bottle game is in progress — join it or wait",
+            ),
+        ] {
+            let mut client = Client::new(0, Receiver::Test("b\r".to_string()), TerminalType::Ansi);
+            show_join_error(&mut client, Mode::Ring, error)
+                .await
+                .unwrap();
+            assert!(client.text().contains(message));
+            assert!(client.text().contains("Back to menu"));
+            assert_eq!(
+                client.text().contains("Join waitlist"),
+                error == JoinError::Full
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_join_error_shown_when_playing() {
+        let lobbies = Arc::new(Mutex::new(WeakValueHashMap::new()));
+        let mut alice = make_lobby_as_alice(0, "BLOCK", lobbies.clone()).await;
+        let lobby = alice.lobby.clone().unwrap();
+        let lobby_id = lobby.lock().unwrap().id.clone();
+        lobby.lock().unwrap().toggle_allow_parallel_games();
+        tokio::spawn(async move {
+            _ = play_game(&mut alice, Mode::Bottle).await;
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let text = format!("Bob\r{}\r\r", lobby_id);
+        let mut bob = Client::new(1, Receiver::Test(text), TerminalType::Ansi);
+        ask_name_with_fresh_state(&mut bob).await.unwrap();
+        ask_lobby_id_and_join_lobby(
+            &mut bob,
+            lobbies.clone(),
+            Arc::new(Mutex::new(IpTracker::new())),
+        )
+        .await
+        .unwrap();
+
+        // Enter on "Back to menu" returns to the mode menu
+        play_game(&mut bob, Mode::Ring).await.unwrap();
+        assert!(bob
+            .text()
+            .contains("A Bottle game is in progress — join it or wait"));
+        assert_eq!(lobby.lock().unwrap().get_player_count(Mode::Ring), 0);
+    }
+
+    #[tokio::test]
+    async fn test_joining_old_game() {
+        let lobbies = Arc::new(Mutex::new(WeakValueHashMap::new()));
+        let mut alice = make_lobby_as_alice(0, "BLOCK", lobbies.clone()).await;
+        let lobby = alice.lobby.clone().unwrap();
+        let lobby_id = lobby.lock().unwrap().id.clone();
+        tokio::spawn(async move {
+            _ = play_game(&mut alice, Mode::Traditional).await;
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let wrapper = lobby.lock().unwrap().get_game_wrapper(Mode::Traditional);
+        wrapper
+            .unwrap()
+            .set_start_time(Instant::now() - Duration::from_secs(11 * 60));
+
+        for (client_id, answer) in [(1, "n"), (2, "yBLOCK")] {
+            let text = format!("Player {}\r{}\r{}", client_id, lobby_id, answer);
+            let mut client = Client::new(client_id, Receiver::Test(text), TerminalType::Ansi);
+            ask_name_with_fresh_state(&mut client).await.unwrap();
+            ask_lobby_id_and_join_lobby(
+                &mut client,
+                lobbies.clone(),
+                Arc::new(Mutex::new(IpTracker::new())),
+            )
+            .await
+            .unwrap();
+
+            if answer == "n" {
+                // Declining goes back to the mode menu without joining
+                play_game(&mut client, Mode::Traditional).await.unwrap();
+                assert!(client
+                    .text()
+                    .contains("This game has been running for 11 minutes and is quite fast."));
+                assert_eq!(lobby.lock().unwrap().get_player_count(Mode::Traditional), 1);
+            } else {
+                tokio::spawn(async move {
+                    _ = play_game(&mut client, Mode::Traditional).await;
+                });
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                assert_eq!(lobby.lock().unwrap().get_player_count(Mode::Traditional), 2);
+            }
+        }
+    }
+
+    #[test]
+    fn test_old_game_warning() {
+        let minutes = |n: u64| Duration::from_secs(n * 60);
+        assert_eq!(old_game_warning(minutes(9), 9999), None);
+        assert!(old_game_warning(minutes(37), 0)
+            .unwrap()
+            .contains("running for 37 minutes"));
+        assert!(old_game_warning(minutes(1), 12345)
+            .unwrap()
+            .contains("12345 points"));
+    }
+
+    #[tokio::test]
+    async fn test_mode_menu_shows_who_is_playing() {
+        let lobbies = Arc::new(Mutex::new(WeakValueHashMap::new()));
+        let mut alice = make_lobby_as_alice(1, "BLOCK", lobbies.clone()).await;
+        let lobby_id = alice.lobby.as_ref().unwrap().lock().unwrap().id.clone();
+
+        let mut bob = Client::new(
+            2,
+            Receiver::Test(format!("Bob\r{}\rBLOCK", lobby_id)),
+            TerminalType::Ansi,
+        );
+        ask_name_with_fresh_state(&mut bob).await.unwrap();
+        ask_lobby_id_and_join_lobby(
+            &mut bob,
+            lobbies.clone(),
+            Arc::new(Mutex::new(IpTracker::new())),
+        )
+        .await
+        .unwrap();
+        let bob_render_data = bob.render_data.clone();
+        let bob_screen_row = |y: usize| -> String {
+            let render_data = bob_render_data.lock().unwrap();
+            (0..80).map(|x| render_data.buffer.get_char(x, y)).collect()
+        };
+        tokio::spawn(async move {
+            _ = show_mode_menu(&mut bob, &mut 0).await;
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let bottle_y = 13
+            + Mode::ALL_MODES
+                .iter()
+                .position(|m| *m == Mode::Bottle)
+                .unwrap();
+        assert!(bob_screen_row(bottle_y).contains("Bottle game (0/6 players)"));
+        assert!(!bob_screen_row(bottle_y).contains("Alice"));
+
+        let alice_playing = tokio::spawn(async move {
+            _ = play_game(&mut alice, Mode::Bottle).await;
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(bob_screen_row(bottle_y).contains("Bottle game (1/6 players)"));
+        assert!(bob_screen_row(bottle_y).ends_with("Alice              "));
+        {
+            let render_data = bob_render_data.lock().unwrap();
+            let alice_color = render_data.buffer.get_color(PLAYER_NAMES_X, bottle_y);
+            assert_eq!(alice_color.fg, 31);
+        }
+
+        // Alice disconnects
+        alice_playing.abort();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(bob_screen_row(bottle_y).contains("Bottle game (0/6 players)"));
+        assert!(!bob_screen_row(bottle_y).contains("Alice"));
+    }
+
+    // Waits for renders instead of sleeping, so it doesn't depend on how fast the test runs
+    async fn wait_for_text(
+        render_data: &SharedRenderData,
+        condition: impl Fn(&str) -> bool,
+    ) -> String {
+        let mut receiver = render_data.subscribe();
+        let wait = async {
+            loop {
+                let text = {
+                    let render_data = render_data.lock().unwrap();
+                    let mut result = "".to_string();
+                    for y in 0..render_data.buffer.height {
+                        for x in 0..render_data.buffer.width {
+                            result.push(render_data.buffer.get_char(x, y));
+                        }
+                        result.push('\n');
+                    }
+                    result
+                };
+                if condition(&text) {
+                    return text;
+                }
+                receiver.changed().await.unwrap();
+            }
+        };
+        // Fail instead of hanging forever if the text never shows up
+        tokio::time::timeout(Duration::from_secs(10), wait)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_pause_screen_shows_lobby_info() {
+        let lobbies = Arc::new(Mutex::new(WeakValueHashMap::new()));
+        let mut alice = make_lobby_as_alice(1, "pBLOCK", lobbies.clone()).await;
+        alice.lobby_id_hidden = true;
+        let lobby = alice.lobby.clone().unwrap();
+        let lobby_id = lobby.lock().unwrap().id.clone();
+        let alice_render_data = alice.render_data.clone();
+
+        // Alice pauses
+        tokio::spawn(async move {
+            _ = play_game(&mut alice, Mode::Traditional).await;
+        });
+        let text = wait_for_text(&alice_render_data, |text| text.contains("Game paused")).await;
+        assert!(text.contains("Lobby ID: ****** (press i to show)"));
+        assert!(!text.contains(&lobby_id));
+        assert!(text.contains("Traditional game"));
+        assert!(text.contains("Score: 0"));
+        assert!(text.contains("Time: 00:00"));
+        assert!(text.contains("Players: Alice"));
+
+        // Bob joins the paused game and presses i
+        let mut bob = Client::new(
+            2,
+            Receiver::Test(format!("Bob\r{}\ri", lobby_id)),
+            TerminalType::Ansi,
+        );
+        ask_name_with_fresh_state(&mut bob).await.unwrap();
+        ask_lobby_id_and_join_lobby(
+            &mut bob,
+            lobbies.clone(),
+            Arc::new(Mutex::new(IpTracker::new())),
+        )
+        .await
+        .unwrap();
+        bob.lobby_id_hidden = true;
+        _ = play_game(&mut bob, Mode::Traditional).await;
+        assert!(!bob.lobby_id_hidden);
+        let text = bob.text();
+        assert!(text.contains("Game paused"));
+        assert!(text.contains(&format!("Lobby ID: {} (press i to hide)", lobby_id)));
+        assert!(text.contains("Players: Alice, Bob"));
+
+        // Unpausing hides the pause screen
+        let wrapper = lobby.lock().unwrap().get_game_wrapper(Mode::Traditional);
+        wrapper.unwrap().set_paused(Some(false));
+        wait_for_text(&alice_render_data, |text| !text.contains("Game paused")).await;
+    }
+
+    #[test]
+    fn test_game_over_message_shows_pauses() {
+        let mut result = GameResult {
+            duration: Duration::from_secs(12 * 60 + 5),
+            mode: Mode::Traditional,
+            score: 500,
+            players: vec!["Foo".to_string()],
+            inactive_players: vec![],
+            timestamp: Some(Utc::now()),
+            seed: None,
+            time_limit_reached: false,
+            paused_duration: Duration::from_secs(62 * 60),
+            pause_count: 3,
+        };
+        let row = |result: &GameResult| -> String {
+            let mut buffer = RenderBuffer::new(TerminalType::Ansi);
+            buffer.resize(80, 24);
+            render_game_over_message(&mut buffer, result, true);
+            (0..80).map(|x| buffer.get_char(x, 3)).collect()
+        };
+        assert_eq!(
+            row(&result).trim(),
+            "The game lasted 12min (paused 62min) and it ended with score 500."
+        );
+
+        result.paused_duration = Duration::from_millis(300);
+        assert_eq!(
+            row(&result).trim(),
+            "The game lasted 12min and it ended with score 500."
+        );
+    }
+
+    #[test]
+    fn test_player_names_beside_mode_menu() {
+        let mut buffer = RenderBuffer::new(TerminalType::Ansi);
+        buffer.resize(80, 24);
+        let row = |buffer: &RenderBuffer| -> String {
+            (PLAYER_NAMES_X..80)
+                .map(|x| buffer.get_char(x, 0))
+                .collect()
+        };
+
+        add_player_names(
+            &mut buffer,
+            PLAYER_NAMES_X,
+            0,
+            80 - PLAYER_NAMES_X,
+            &[("Alice".to_string(), 31), ("Bob".to_string(), 32)],
+        );
+        assert_eq!(row(&buffer), "Alice, Bob         ");
+        assert_eq!(buffer.get_color(PLAYER_NAMES_X, 0).fg, 31);
+        assert_eq!(buffer.get_color(PLAYER_NAMES_X + 5, 0), Color::DEFAULT);
+        assert_eq!(buffer.get_color(PLAYER_NAMES_X + 7, 0).fg, 32);
+
+        let players: Vec<(String, u8)> =
+            (1..=6).map(|i| (format!("Player {}", i), 30 + i)).collect();
+        add_player_names(
+            &mut buffer,
+            PLAYER_NAMES_X,
+            1,
+            80 - PLAYER_NAMES_X,
+            &players,
+        );
+        let text: String = (PLAYER_NAMES_X..80)
+            .map(|x| buffer.get_char(x, 1))
+            .collect();
+        assert_eq!(text, "Player 1 +5 others ");
+        assert_eq!(buffer.get_color(PLAYER_NAMES_X, 1).fg, 31);
+        assert_eq!(
+            buffer.get_color(PLAYER_NAMES_X + 9, 1),
+            Color::GRAY_FOREGROUND
+        );
+
+        // The comma inside the first name must not shift colors
+        add_player_names(
+            &mut buffer,
+            PLAYER_NAMES_X,
+            2,
+            80 - PLAYER_NAMES_X,
+            &[("Doe, Jane".to_string(), 31), ("Bob".to_string(), 32)],
+        );
+        assert_eq!(buffer.get_color(PLAYER_NAMES_X + 5, 2).fg, 31);
+        assert_eq!(buffer.get_color(PLAYER_NAMES_X + 11, 2).fg, 32);
+    }
+
+    #[tokio::test]
+    async fn test_escape_leaves_gameplay_tips() {
+        let receiver = Receiver::Test("xy\x1b".to_string());
+        let mut client = Client::new(123, receiver, TerminalType::Ansi);
+        show_gameplay_tips(&mut client).await.unwrap();
+        assert!(client.text().contains("Back to menu"));
+
+        // F1 doesn't leave, because it's ignored
+        let receiver = Receiver::Test("\x1bOP".to_string());
+        let mut client = Client::new(123, receiver, TerminalType::Ansi);
+        assert!(show_gameplay_tips(&mut client).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_gameplay_tips_columns() {
+        let receiver = Receiver::Test("\r".to_string());
+        let mut client = Client::new(123, receiver, TerminalType::Ansi);
+        show_gameplay_tips(&mut client).await.unwrap();
+        let text = client.text();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(
+            lines[15].trim_end(),
+            "  There's only one score. You play       before you can continue playing. The"
+        );
+        assert_eq!(
+            lines[22].trim_end(),
+            "  top, you need to wait 30 seconds       mess up."
+        );
+        // Colors continue from the left column to the right column
+        assert_eq!(
+            client.text_with_color(Color::CYAN_FOREGROUND),
+            "You playtogetherintentionally fill your playing area"
+        );
+    }
+
+    #[test]
+    fn test_keys_overlay_content() {
+        let websocket = get_key_lines(true, false);
+        let raw_tcp = get_key_lines(false, false);
+        assert!(websocket.iter().all(|line| !line.contains("Ctrl+")));
+        assert!(raw_tcp.iter().any(|line| line.contains("Ctrl+C")));
+        assert!(raw_tcp.iter().all(|line| !line.contains("[F]")));
+        assert!(get_key_lines(false, true)
+            .iter()
+            .any(|line| line.contains("[F]")));
+        assert_eq!(raw_tcp.len(), websocket.len() + 2);
+
+        let client = Client::new(123, Receiver::Test("".to_string()), TerminalType::Ansi);
+        {
+            let mut render_data = client.render_data.lock().unwrap();
+            render_data.clear(80, 24);
+            render_keys_overlay(&mut render_data.buffer, &raw_tcp);
+        }
+        let text = client.text();
+        assert!(text.contains("|   Ctrl+R: redraw the whole screen"));
+        assert!(text.contains("Press any key to continue playing."));
+        assert!(client
+            .text_with_color(Color::MAGENTA_FOREGROUND)
+            .starts_with("Ctrl+CCtrl+DCtrl+Q"));
+    }
+
+    #[test]
+    fn test_dismissing_help_key_not_forwarded() {
+        let mut showing_help = false;
+        assert_eq!(
+            handle_help_key(&mut showing_help, KeyPress::Left),
+            Some(KeyPress::Left)
+        );
+        assert_eq!(
+            handle_help_key(&mut showing_help, KeyPress::Character('?')),
+            None
+        );
+        assert!(showing_help);
+        assert_eq!(handle_help_key(&mut showing_help, KeyPress::Left), None);
+        assert!(!showing_help);
+        assert_eq!(
+            handle_help_key(&mut showing_help, KeyPress::Character('d')),
+            Some(KeyPress::Character('d'))
+        );
+    }
+
+    #[test]
+    fn test_format_time_ago() {
+        assert_eq!(
+            format_time_ago(chrono::Duration::seconds(1)),
+            "1 second ago"
+        );
+        assert_eq!(
+            format_time_ago(chrono::Duration::minutes(3)),
+            "3 minutes ago"
+        );
+        assert_eq!(format_time_ago(chrono::Duration::days(400)), "1 year ago");
+
+        // Clock was adjusted backwards after saving
+        assert_eq!(format_time_ago(chrono::Duration::zero()), "now");
+        assert_eq!(format_time_ago(chrono::Duration::seconds(-5)), "now");
+        assert_eq!(format_time_ago(chrono::Duration::minutes(-3)), "now");
+        assert_eq!(format_time_ago(chrono::Duration::days(-400)), "now");
+    }
+
+    #[test]
+    fn test_format_player_names() {
+        let names: Vec<String> = ["Alice", "Bob", "Charlie"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        assert_eq!(format_player_names(&names, 100), "Alice, Bob, Charlie");
+        assert_eq!(format_player_names(&names, 19), "Alice, Bob, Charlie");
+        assert_eq!(format_player_names(&names, 18), "Alice, Bob, Cha...");
+        assert_eq!(format_player_names(&names, 17), "Alice +2 others");
+        assert_eq!(format_player_names(&names, 12), "Al +2 others");
+        assert_eq!(format_player_names(&names, 5), " +2 o");
+    }
+
+    #[test]
+    fn test_high_scores_with_many_long_names() {
+        let result = GameResult {
+            duration: Duration::from_secs(12345),
+            mode: Mode::Bottle,
+            score: 123456789,
+            players: (1..=6).map(|i| format!("VeryLongName{:03}", i)).collect(),
+            inactive_players: vec![],
+            timestamp: Some(Utc::now() - chrono::Duration::seconds(42)),
+            seed: None,
+            time_limit_reached: false,
+            ..Default::default()
+        };
+        assert!(result.players.iter().all(|name| name.len() == 15));
+
+        let mut buffer = RenderBuffer::new(TerminalType::Ansi);
+        buffer.resize(80, 24);
+        render_high_scores_table(&mut buffer, 0, Mode::Bottle, true, &[result], Some(0));
+
+        let row: String = (0..80).map(|x| buffer.get_char(x, 4)).collect();
+        assert_eq!(
+            row.trim_end(),
+            "| 123456789 | 205min   | 42 seconds ago | VeryLongName001 +5 others"
+        );
+
+        // Text that doesn't fit is clipped instead of panicking
+        assert_eq!(buffer.add_text(75, 4, "Hello World"), 80);
+        assert_eq!(buffer.get_char(79, 4), 'o');
+    }
+
+    #[test]
+    fn test_game_over_message_with_inactive_player() {
+        let result = GameResult {
+            duration: Duration::from_secs(60),
+            mode: Mode::Traditional,
+            score: 100,
+            players: vec!["Alice".to_string()],
+            inactive_players: vec!["Bob".to_string()],
+            timestamp: Some(Utc::now()),
+            seed: None,
+            time_limit_reached: false,
+            ..Default::default()
+        };
+        let mut buffer = RenderBuffer::new(TerminalType::Ansi);
+        buffer.resize(80, 24);
+        render_game_over_message(&mut buffer, &result, false);
+
+        let row = |y| -> String { (0..80).map(|x| buffer.get_char(x, y)).collect() };
+        assert_eq!(row(17).trim(), "Bob (inactive, not counted)");
+        // Only one player counts, so nothing was multiplied
+        assert_eq!(row(18).trim(), "");
+    }
+
     #[tokio::test]
     async fn test_show_high_scores_after_game() {
         let this_game_result = GameResult {
@@ -1422,7 +4179,11 @@ mod test {
             mode: Mode::Traditional,
             score: 500,
             players: vec!["Foo".to_string(), "Bar".to_string()],
+            inactive_players: vec![],
             timestamp: Some(Utc::now()),
+            seed: None,
+            time_limit_reached: false,
+            ..Default::default()
         };
 
         let top_results = vec![
@@ -1431,7 +4192,11 @@ mod test {
                 mode: Mode::Traditional,
                 score: 1000,
                 players: vec!["Alice".to_string(), "Bob".to_string()],
+                inactive_players: vec![],
                 timestamp: None,
+                seed: None,
+                time_limit_reached: false,
+                ..Default::default()
             },
             this_game_result.clone(),
             GameResult {
@@ -1444,14 +4209,22 @@ mod test {
                     "Long long name".to_string(),
                     "short name".to_string(),
                 ],
+                inactive_players: vec![],
                 timestamp: Some(Utc::now() - chrono::Duration::days(3)),
+                seed: None,
+                time_limit_reached: false,
+                ..Default::default()
             },
             GameResult {
                 duration: Duration::from_secs(4),
                 mode: Mode::Traditional,
                 score: 10,
                 players: vec!["Asdf".to_string(), "Lol Wat".to_string()],
+                inactive_players: vec![],
                 timestamp: Some(Utc::now() - chrono::Duration::days(10)),
+                seed: None,
+                time_limit_reached: false,
+                ..Default::default()
             },
         ];
 
@@ -1463,8 +4236,11 @@ mod test {
             this_game_index: Some(1),
         }));
         let (_status_sender, status_receiver) = watch::channel(status);
-        let result = show_high_scores_after_game(&mut client, status_receiver).await;
-        assert!(result.is_ok());
+        let block_counts_text = "L:12 I:9 J:10 O:11 T:8 S:9 Z:10 cursed:2 drill:1 bomb:3";
+        let result =
+            show_high_scores_after_game(&mut client, status_receiver, block_counts_text, None)
+                .await;
+        assert!(!result.unwrap());
 
         assert_eq!(
             client.text(),
@@ -1473,7 +4249,7 @@ mod test {
                 "                                                                                \n",
                 "                                  Game over :)                                  \n",
                 "                The game lasted 2min and it ended with score 500.               \n",
-                "                                                                                \n",
+                "             L:12 I:9 J:10 O:11 T:8 S:9 Z:10 cursed:2 drill:1 bomb:3            \n",
                 "                                                                                \n",
                 "================ HIGH SCORES: Traditional game with multiplayer ================\n",
                 "                                                                                \n",
@@ -1487,12 +4263,12 @@ mod test {
                 "                                                                                \n",
                 "                                                                                \n",
                 "                                                                                \n",
-                "                                                                                \n",
+                "            Points were multiplied by 2 because there were 2 players.           \n",
+                "                  High scores older than 90 days are not shown.                 \n",
                 "                           Press Enter to continue...                           \n",
                 "                                                                                \n",
                 "                                                                                \n",
                 "                                                                                \n",
-                "                                                                                \n",
             )
         );
 
@@ -1504,4 +4280,257 @@ mod test {
         // score of current game (in "The game lasted ...") should be highlighted
         assert_eq!(client.text_with_color(Color::CYAN_FOREGROUND), "500");
     }
+
+    fn screen_text(render_data: &SharedRenderData) -> String {
+        let render_data = render_data.lock().unwrap();
+        let mut result = "".to_string();
+        for y in 0..render_data.buffer.height {
+            for x in 0..render_data.buffer.width {
+                result.push(render_data.buffer.get_char(x, y));
+            }
+            result.push('\n');
+        }
+        result
+    }
+
+    type RematchClient = (
+        Arc<SharedRenderData>,
+        tokio::task::JoinHandle<Result<bool, io::Error>>,
+    );
+
+    // Three clients in a lobby see the high scores of a game that they played together
+    fn start_rematch_test(scripts: [&str; 3]) -> Vec<RematchClient> {
+        let lobby = Arc::new(Mutex::new(Lobby::new("ABCDEF")));
+        let used_names = Arc::new(Mutex::new(HashSet::new()));
+        let ended_game = Arc::new(GameWrapper::new(Game::new(Mode::Traditional)));
+
+        let mut clients = vec![];
+        for (i, script) in scripts.iter().enumerate() {
+            let id = (i + 1) as u64;
+            let receiver = Receiver::Test(script.to_string());
+            let mut client = Client::new(id, receiver, TerminalType::Ansi);
+            assert!(client.set_name(&format!("Client {}", id), used_names.clone()));
+            assert!(client.join_lobby(lobby.clone()));
+            clients.push(client);
+        }
+        {
+            let mut lobby = lobby.lock().unwrap();
+            lobby.rematch_timeout = Duration::from_millis(500);
+            lobby.offer_rematch(Mode::Traditional, &ended_game, vec![1, 2, 3]);
+        }
+
+        clients
+            .into_iter()
+            .map(|mut client| {
+                let render_data = client.render_data.clone();
+                let handle = tokio::spawn(async move {
+                    let status = GameStatus::GameOver(HighScoresStatus::Loading);
+                    let (_status_sender, status_receiver) = watch::channel(status);
+                    show_high_scores_after_game(
+                        &mut client,
+                        status_receiver,
+                        "",
+                        Some(Mode::Traditional),
+                    )
+                    .await
+                });
+                (render_data, handle)
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_everyone_plays_again() {
+        let start = Instant::now();
+        for (_, handle) in start_rematch_test(["p\rBLOCK", "p\rBLOCK", "p\rBLOCK"]) {
+            assert!(handle.await.unwrap().unwrap());
+        }
+        // Nobody waited for the timeout
+        assert!(start.elapsed() < Duration::from_millis(300));
+    }
+
+    #[tokio::test]
+    async fn test_some_play_again() {
+        let start = Instant::now();
+        let mut results = vec![];
+        for (_, handle) in start_rematch_test(["p\rBLOCK", "c\rBLOCK", "\x1b[B\rBLOCK"]) {
+            results.push(handle.await.unwrap().unwrap());
+        }
+        assert_eq!(results, [true, false, true]);
+        assert!(start.elapsed() < Duration::from_millis(300));
+    }
+
+    #[tokio::test]
+    async fn test_play_again_timeout() {
+        let start = Instant::now();
+        let mut clients = start_rematch_test(["p\rBLOCK", "BLOCK", "BLOCK"]);
+        let (waiting_render_data, waiting_handle) = clients.remove(0);
+        let (undecided_render_data, undecided_handle) = clients.remove(0);
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(screen_text(&waiting_render_data).contains("Waiting for others: 1/3"));
+        let undecided_text = screen_text(&undecided_render_data);
+        assert!(undecided_text.contains("Continue"));
+        assert!(undecided_text.contains("Play again"));
+
+        // Starts without the others when time runs out
+        assert!(waiting_handle.await.unwrap().unwrap());
+        assert!(start.elapsed() >= Duration::from_millis(500));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let undecided_text = screen_text(&undecided_render_data);
+        assert!(undecided_text.contains("Press Enter to continue..."));
+        assert!(!undecided_text.contains("Play again"));
+        undecided_handle.abort();
+        for (_, handle) in clients {
+            handle.abort();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_game_crash() {
+        let lobby = Arc::new(Mutex::new(Lobby::new("ABCDEF")));
+        let used_names = Arc::new(Mutex::new(HashSet::new()));
+        let mut clients = vec![];
+        for id in 1..=2 {
+            let receiver = Receiver::Test("BLOCK".to_string());
+            let mut client = Client::new(id, receiver, TerminalType::Ansi);
+            assert!(client.set_name(&format!("Client {}", id), used_names.clone()));
+            assert!(client.join_lobby(lobby.clone()));
+            let render_data = client.render_data.clone();
+            let handle = tokio::spawn(async move {
+                play_game(&mut client, Mode::Traditional).await?;
+                show_mode_menu(&mut client, &mut 0).await
+            });
+            clients.push((render_data, handle));
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // Same as a bug in the game logic
+        let wrapper = lobby
+            .lock()
+            .unwrap()
+            .get_game_wrapper(Mode::Traditional)
+            .unwrap();
+        let panicked = std::thread::spawn(move || {
+            let _game = wrapper.game.lock().unwrap();
+            panic!("oops");
+        })
+        .join();
+        assert!(panicked.is_err());
+
+        // Blocks fall every 25ms, and that notices the crash
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        for (render_data, _) in &clients {
+            assert!(screen_text(render_data).contains("The game crashed, sorry"));
+        }
+
+        // Skip to the end of the crash message without really waiting
+        tokio::time::pause();
+        tokio::time::sleep(CRASH_MESSAGE_DURATION + Duration::from_millis(50)).await;
+        for (render_data, handle) in clients {
+            assert!(screen_text(&render_data).contains("Traditional game"));
+            assert!(!handle.is_finished());
+            handle.abort();
+        }
+        assert!(lobby
+            .lock()
+            .unwrap()
+            .get_game_wrapper(Mode::Traditional)
+            .is_none());
+    }
+
+    #[test]
+    fn test_local_player_key_routing() {
+        let local = Some(12345);
+        assert_eq!(choose_player_for_key(1, local, &KeyPress::Up), 12345);
+        assert_eq!(choose_player_for_key(1, local, &KeyPress::Left), 12345);
+        assert_eq!(
+            choose_player_for_key(1, local, &KeyPress::Character('w')),
+            1
+        );
+        assert_eq!(
+            choose_player_for_key(1, local, &KeyPress::Character('A')),
+            1
+        );
+        assert_eq!(
+            choose_player_for_key(1, local, &KeyPress::Character('h')),
+            1
+        );
+        assert_eq!(choose_player_for_key(1, None, &KeyPress::Up), 1);
+    }
+
+    #[tokio::test]
+    async fn test_lobby_settings_screen() {
+        let mut client = Client::new(
+            123,
+            Receiver::Test("mwks\r".to_string()),
+            TerminalType::Ansi,
+        );
+        assert!(client.set_name("Alice", Arc::new(Mutex::new(HashSet::new()))));
+        client.make_lobby(Arc::new(Mutex::new(WeakValueHashMap::new())));
+        let lobby = client.lobby.clone().unwrap();
+        let tint = lobby.lock().unwrap().ownership_tint;
+        let necks = lobby.lock().unwrap().bottle_necks;
+
+        let mut selected_index = 0;
+        let result = show_mode_menu(&mut client, &mut selected_index).await;
+        assert_eq!(result.unwrap(), ModeMenuChoice::LobbySettings);
+        assert!(client.text().contains("Press M for more settings"));
+        show_lobby_settings(&mut client).await.unwrap();
+        assert_eq!(lobby.lock().unwrap().ownership_tint, !tint);
+        assert_ne!(lobby.lock().unwrap().bottle_necks, necks);
+        assert!(client
+            .text()
+            .contains("Bottle: deep necks (12 personal rows)"));
+        assert!(lobby.lock().unwrap().spawn_delay);
+        assert!(client.text().contains("Spawn delay: one tick (S)"));
+        assert!(client.text().contains("(W)"));
+    }
+
+    #[tokio::test]
+    async fn test_mode_menu_when_lobby_channel_closes() {
+        let mut client = Client::new(123, Receiver::Test("BLOCK".to_string()), TerminalType::Ansi);
+        assert!(client.set_name("Alice", Arc::new(Mutex::new(HashSet::new()))));
+        client.make_lobby(Arc::new(Mutex::new(WeakValueHashMap::new())));
+        let lobby = client.lobby.clone().unwrap();
+
+        let close_later = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            lobby.lock().unwrap().close_changed_channel();
+        };
+        let mut selected_index = 0;
+        let (result, ()) = tokio::join!(
+            show_mode_menu(&mut client, &mut selected_index),
+            close_later
+        );
+
+        let error = result.unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::ConnectionAborted);
+        assert_eq!(error.to_string(), "lobby no longer exists");
+    }
+
+    #[tokio::test]
+    async fn test_high_scores_when_status_channel_closes() {
+        let mut client = Client::new(123, Receiver::Test("BLOCK".to_string()), TerminalType::Ansi);
+        let (logger, lines) = ClientLogger::capturing(123, LogFormat::Text);
+        client.logger = logger;
+
+        let status = GameStatus::GameOver(HighScoresStatus::Loading);
+        let (status_sender, status_receiver) = watch::channel(status);
+        let close_later = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            drop(status_sender);
+        };
+        let (result, ()) = tokio::join!(
+            show_high_scores_after_game(&mut client, status_receiver, "", None),
+            close_later
+        );
+
+        // Back to menu instead of panicking
+        assert!(!result.unwrap());
+        assert!(client.text().contains("Loading..."));
+        let lines = lines.lock().unwrap();
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].ends_with("Game status channel closed, going back to menu"));
+    }
 }
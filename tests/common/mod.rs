@@ -0,0 +1,66 @@
+/*
+Starts a real server for tests. The server code is in the catris binary, not
+in the library, so it runs as a separate process with ports chosen by the
+operating system.
+*/
+// Each test file is compiled separately and doesn't use everything here
+#![allow(dead_code)]
+
+use std::io::BufRead;
+use std::io::BufReader;
+use std::process::Child;
+use std::process::Command;
+use std::process::Stdio;
+
+struct KillOnDrop(Child);
+impl Drop for KillOnDrop {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+pub struct Server {
+    _process: KillOnDrop,
+    pub port: u16,
+    pub websocket_port: u16,
+    pub admin_port: u16,
+}
+
+pub fn start_server(dir: &std::path::Path) -> Server {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_catris"))
+        .args(["--port", "0", "--websocket-port", "0", "--admin-port", "0"])
+        .current_dir(dir)
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut lines = BufReader::new(child.stdout.take().unwrap()).lines();
+    let process = KillOnDrop(child);
+
+    let mut port = None;
+    let mut websocket_port = None;
+    let mut admin_port = None;
+    while port.is_none() || websocket_port.is_none() || admin_port.is_none() {
+        let line = lines.next().unwrap().unwrap();
+        let last_number = || {
+            let number = line.trim_end_matches("...").rsplit(' ').next().unwrap();
+            Some(number.parse().unwrap())
+        };
+        if line.starts_with("Listening for raw TCP connections") {
+            port = last_number();
+        } else if line.starts_with("Listening for websocket connections") {
+            websocket_port = last_number();
+        } else if line.starts_with("Listening for admin connections") {
+            admin_port = last_number();
+        }
+    }
+
+    // The server would get stuck if nobody reads what it prints
+    std::thread::spawn(move || for _ in lines {});
+    Server {
+        _process: process,
+        port: port.unwrap(),
+        websocket_port: websocket_port.unwrap(),
+        admin_port: admin_port.unwrap(),
+    }
+}
@@ -0,0 +1,185 @@
+/*
+Randomized tests for parsing what terminals send us.
+
+parse_key_press() gets its input straight from the network, so it must not
+panic and it must not make the connection wait forever for bytes that will
+never complete a key press. The inputs are random but seeded, so a failure
+can be reproduced by running the tests again. To fuzz longer, set the
+CATRIS_FUZZ_ITERATIONS environment variable, e.g. to 1000000.
+*/
+use catris::escapes::parse_key_press;
+use catris::escapes::KeyPress;
+use rand::rngs::StdRng;
+use rand::Rng;
+use rand::SeedableRng;
+
+const DEFAULT_ITERATIONS: usize = 2000;
+
+// Cursor position reports are the longest things that can be incomplete
+const MAX_INCOMPLETE_LEN: usize = 21;
+
+const VALID_SEQUENCES: &[&[u8]] = &[
+    b"\x1b[A",
+    b"\x1b[B",
+    b"\x1b[C",
+    b"\x1b[D",
+    b"\x1bA",
+    b"\x1bB",
+    b"\x1bC",
+    b"\x1bD",
+    b"\x1bOA",
+    b"\x1bOB",
+    b"\x1bOC",
+    b"\x1bOD",
+    b"\x1bOx",
+    b"\x1bOr",
+    b"\x1bOt",
+    b"\x1bOv",
+    b"\x1b[5~",
+    b"\x1b[6~",
+    b"\x1bOP",
+    b"\x1bOS",
+    b"\x1b[15~",
+    b"\x1b[24~",
+    b"\x1b[24;80R",
+    b"\r",
+    b"\x7f",
+    b"\x08",
+    b"\x12",
+    b"a",
+    b"Q",
+    "ö".as_bytes(),
+    "€".as_bytes(),
+    "🐱".as_bytes(),
+];
+
+fn iterations() -> usize {
+    match std::env::var("CATRIS_FUZZ_ITERATIONS") {
+        Ok(s) => s.parse().unwrap(),
+        Err(_) => DEFAULT_ITERATIONS,
+    }
+}
+
+// Quitting keys would disconnect the client, they are tested elsewhere
+fn random_garbage_byte(rng: &mut StdRng) -> u8 {
+    loop {
+        let byte: u8 = rng.gen();
+        if ![b'\x03', b'\x04', b'\x11'].contains(&byte) {
+            return byte;
+        }
+    }
+}
+
+fn random_stream(rng: &mut StdRng) -> Vec<u8> {
+    let mut result = vec![];
+    for _ in 0..rng.gen_range(0..20) {
+        match rng.gen_range(0..5) {
+            0 => {
+                for _ in 0..rng.gen_range(1..10) {
+                    result.push(random_garbage_byte(rng));
+                }
+            }
+            1 | 2 => {
+                result.extend(VALID_SEQUENCES[rng.gen_range(0..VALID_SEQUENCES.len())]);
+            }
+            3 => {
+                // truncated sequence followed by something else
+                let seq = VALID_SEQUENCES[rng.gen_range(0..VALID_SEQUENCES.len())];
+                result.extend(&seq[..rng.gen_range(0..seq.len())]);
+            }
+            _ => {
+                // overlong CSI parameter list, maybe with a terminator
+                result.extend(b"\x1b[");
+                for _ in 0..rng.gen_range(0..100) {
+                    result.push(if rng.gen_bool(0.8) { b'1' } else { b';' });
+                }
+                if rng.gen_bool(0.5) {
+                    result.push([b'R', b'~', b'A'][rng.gen_range(0..3)]);
+                }
+            }
+        }
+    }
+    result
+}
+
+// Like what the connection does: parse whatever has arrived so far
+fn parse_in_chunks(stream: &[u8], chunk_ends: &[usize]) -> (Vec<KeyPress>, Vec<u8>) {
+    let mut buffer = vec![];
+    let mut keys = vec![];
+    let mut start = 0;
+    for end in chunk_ends.iter().copied().chain([stream.len()]) {
+        buffer.extend(&stream[start..end]);
+        start = end;
+        while let Some((key, len)) = parse_key_press(&buffer) {
+            assert!(len >= 1 && len <= buffer.len(), "{:?} {}", buffer, len);
+            keys.push(key);
+            buffer.drain(..len);
+        }
+        assert!(buffer.len() <= MAX_INCOMPLETE_LEN, "{:?}", buffer);
+    }
+    (keys, buffer)
+}
+
+#[test]
+fn test_random_streams_parse_the_same_in_any_chunks() {
+    let mut rng = StdRng::seed_from_u64(1903);
+    for _ in 0..iterations() {
+        let stream = random_stream(&mut rng);
+        let all_at_once = parse_in_chunks(&stream, &[]);
+
+        let mut chunk_ends: Vec<usize> = (0..rng.gen_range(0..10))
+            .map(|_| rng.gen_range(0..=stream.len()))
+            .collect();
+        chunk_ends.sort();
+        assert_eq!(
+            parse_in_chunks(&stream, &chunk_ends),
+            all_at_once,
+            "{:?}",
+            stream
+        );
+
+        let byte_at_a_time: Vec<usize> = (0..stream.len()).collect();
+        assert_eq!(
+            parse_in_chunks(&stream, &byte_at_a_time),
+            all_at_once,
+            "{:?}",
+            stream
+        );
+    }
+}
+
+#[test]
+fn test_valid_sequences_in_garbage() {
+    let mut rng = StdRng::seed_from_u64(1904);
+    for _ in 0..iterations() {
+        let seq = VALID_SEQUENCES[rng.gen_range(0..VALID_SEQUENCES.len())];
+        let expected = parse_key_press(seq).unwrap();
+        assert_eq!(expected.1, seq.len());
+
+        // Whatever comes after a complete sequence doesn't matter
+        let mut data = seq.to_vec();
+        for _ in 0..rng.gen_range(0..10) {
+            data.push(random_garbage_byte(&mut rng));
+        }
+        assert_eq!(parse_key_press(&data), Some(expected));
+    }
+}
+
+#[test]
+fn test_every_short_input() {
+    // All 1 and 2 byte inputs, and 3 byte inputs that start with escape
+    for a in 0..=255u8 {
+        check_short_input(&[a]);
+        for b in 0..=255u8 {
+            check_short_input(&[a, b]);
+            check_short_input(&[b'\x1b', a, b]);
+        }
+    }
+}
+
+fn check_short_input(data: &[u8]) {
+    match parse_key_press(data) {
+        Some((_, len)) => assert!(len >= 1 && len <= data.len(), "{:?}", data),
+        None => assert!(data.len() <= MAX_INCOMPLETE_LEN, "{:?}", data),
+    }
+}
@@ -0,0 +1,38 @@
+// Runs the load tester against a real server
+use std::io::Read;
+use std::io::Write;
+use std::net::TcpStream;
+use std::process::Command;
+
+mod common;
+
+fn admin_command(admin_port: u16, command: &str) -> String {
+    let mut socket = TcpStream::connect(("127.0.0.1", admin_port)).unwrap();
+    socket.write_all(command.as_bytes()).unwrap();
+    socket.write_all(b"\n").unwrap();
+    socket.shutdown(std::net::Shutdown::Write).unwrap();
+    let mut response = String::new();
+    socket.read_to_string(&mut response).unwrap();
+    response
+}
+
+#[test]
+fn test_load_test_with_three_clients() {
+    let dir = tempfile::tempdir().unwrap();
+    let server = common::start_server(dir.path());
+
+    let output = Command::new(env!("CARGO_BIN_EXE_loadtest"))
+        .args(["--host", &format!("127.0.0.1:{}", server.port)])
+        .args(["--clients", "3", "--group-size", "2", "--seconds", "1"])
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(output.status.success(), "{}", stdout);
+    assert!(stdout.contains("connect failures: 0\n"), "{}", stdout);
+    assert!(stdout.contains("disconnects: 0\n"), "{}", stdout);
+    assert!(!stdout.contains("bytes received: 0\n"), "{}", stdout);
+
+    let stats = admin_command(server.admin_port, "stats");
+    assert!(stats.starts_with("accepted=3 "), "{}", stats);
+    assert!(!stats.contains("frames_sent=0"), "{}", stats);
+}
@@ -0,0 +1,86 @@
+// Plays a game through a websocket connection to a real server, like the browser client does
+use futures_util::SinkExt;
+use futures_util::StreamExt;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::MaybeTlsStream;
+use tokio_tungstenite::WebSocketStream;
+
+mod common;
+
+type WebSocket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+struct TestClient {
+    ws: WebSocket,
+    output: String,      // what the server has drawn since the last wait_for_text()
+    sounds: Vec<String>, // text messages, e.g. "sound block_landed"
+}
+
+impl TestClient {
+    async fn press(&mut self, keys: &str) {
+        let message = Message::Binary(keys.as_bytes().to_vec());
+        self.ws.send(message).await.unwrap();
+    }
+
+    async fn receive(&mut self) {
+        match self.ws.next().await.unwrap().unwrap() {
+            Message::Binary(bytes) => self.output.push_str(&String::from_utf8_lossy(&bytes)),
+            Message::Text(text) => self.sounds.push(text),
+            _ => {}
+        }
+    }
+
+    async fn wait_for_text(&mut self, text: &str) {
+        while !self.output.contains(text) {
+            self.receive().await;
+        }
+        self.output.clear();
+    }
+}
+
+#[tokio::test]
+async fn test_scripted_game_with_sound_events() {
+    let dir = tempfile::tempdir().unwrap();
+    let server = common::start_server(dir.path());
+    let url = format!("ws://127.0.0.1:{}/?sound-events", server.websocket_port);
+    let (ws, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+    let mut client = TestClient {
+        ws,
+        output: String::new(),
+        sounds: vec![],
+    };
+
+    timeout(Duration::from_secs(30), async {
+        // Same as what an ANSI terminal responds to the cursor position query
+        client
+            .wait_for_text("Detecting the type of your terminal")
+            .await;
+        client.press("\x1b[24;80R").await;
+        client.wait_for_text("Name: ").await;
+        client.press("Alice\r").await;
+        client.wait_for_text("New lobby").await;
+        client.press("\r").await;
+        client.wait_for_text("Traditional game (0/").await;
+        client.press("\r").await;
+        client.wait_for_text("Score: 0").await;
+
+        // Move left, then fall fast until the block lands.
+        // Falling fast doesn't work during the countdown, so keep trying.
+        client.press("\x1b[D").await;
+        let mut down_interval = tokio::time::interval(Duration::from_millis(500));
+        while client.sounds.is_empty() {
+            let press_down = tokio::select! {
+                _ = down_interval.tick() => true,
+                _ = client.receive() => false,
+            };
+            if press_down {
+                client.press("\x1b[B").await;
+            }
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(client.sounds[0], "sound block_landed");
+}